@@ -0,0 +1,174 @@
+//! Benchmarks of the work done for every proxied request: route lookup, cache key computation, and
+//! origin selection.
+//!
+//! Run with `cargo bench`.  Criterion compares each run against the previous one, so run the
+//! benchmarks on the base branch first to see how a change affects them.
+
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+use pingora::cache::key::CacheHashKey;
+use pingora::cache::CacheKey;
+use pingora::http::RequestHeader;
+use serde_json::json;
+use std::collections::HashSet;
+use std::sync::Arc;
+
+use granite::path_match::RoutePath;
+use granite::route_config::{
+    IncomingScheme, Origin, OriginGroup, RouteConfig, RouteHolder, RouteRequest,
+};
+use granite::route_store::{Route, RouteState, RouteStore};
+use granite::signed_url::SignedUrlConfig;
+
+/// The number of paths configured per host.
+const PATHS_PER_HOST: usize = 10;
+
+fn origin(host: &str, weight: u16) -> Arc<Origin> {
+    Arc::new(serde_json::from_value(json!({"host": host, "weight": weight})).unwrap())
+}
+
+fn origin_group(origins: usize) -> OriginGroup {
+    OriginGroup {
+        origins: (0..origins)
+            .map(|i| origin(&format!("origin{i}.example.net"), 10 + i as u16))
+            .collect(),
+    }
+}
+
+/// A store with `hosts` exact hosts and as many wildcard hosts, each with a few paths.
+fn route_store(hosts: usize) -> RouteStore {
+    let store = RouteStore::new();
+    for i in 0..hosts {
+        for host in [
+            format!("host{i}.example.com"),
+            format!("*.wild{i}.example.com"),
+        ] {
+            store.add_route(RouteConfig {
+                name: host.clone(),
+                incoming_schemes: HashSet::from([IncomingScheme::Http, IncomingScheme::Https]),
+                paths: (0..PATHS_PER_HOST)
+                    .map(|p| RoutePath::from(format!("/api/v{p}/resource").as_str()))
+                    .chain([RoutePath::from("/")])
+                    .collect(),
+                hosts: vec![host],
+                origin_group: origin_group(2),
+                ..Default::default()
+            });
+        }
+    }
+    store
+}
+
+fn route_lookup(c: &mut Criterion) {
+    let mut group = c.benchmark_group("route_lookup");
+    for hosts in [10, 1000] {
+        let store = route_store(hosts);
+        let host = format!("host{}.example.com", hosts / 2);
+        let wildcard = format!("www.wild{}.example.com", hosts / 2);
+        let path = "/api/v5/resource/items/42";
+        group.bench_with_input(BenchmarkId::new("exact", hosts), &hosts, |b, _| {
+            b.iter(|| {
+                store.get_route(
+                    IncomingScheme::Https,
+                    black_box(&RouteRequest {
+                        method: "GET",
+                        host: &host,
+                        path,
+                        ..Default::default()
+                    }),
+                )
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("wildcard", hosts), &hosts, |b, _| {
+            b.iter(|| {
+                store.get_route(
+                    IncomingScheme::Https,
+                    black_box(&RouteRequest {
+                        method: "GET",
+                        host: &wildcard,
+                        path,
+                        ..Default::default()
+                    }),
+                )
+            })
+        });
+        group.bench_with_input(BenchmarkId::new("miss", hosts), &hosts, |b, _| {
+            b.iter(|| {
+                store.get_route(
+                    IncomingScheme::Https,
+                    black_box(&RouteRequest {
+                        method: "GET",
+                        host: "unknown.example.org",
+                        path,
+                        ..Default::default()
+                    }),
+                )
+            })
+        });
+    }
+    group.finish();
+}
+
+fn cache_key(c: &mut Criterion) {
+    let mut group = c.benchmark_group("cache_key");
+    let mut req = RequestHeader::build(
+        "GET",
+        b"/api/v1/resource/items/42?expires=1700000000&signature=abcdef&format=json",
+        None,
+    )
+    .unwrap();
+    req.insert_header("host", "host1.example.com").unwrap();
+
+    group.bench_function("default", |b| {
+        b.iter(|| {
+            let mut key = CacheKey::default(black_box(&req));
+            key.user_tag = "route".to_string();
+            key.primary_bin()
+        })
+    });
+
+    let signed_url: SignedUrlConfig =
+        serde_json::from_value(json!({"secret": "bench-secret"})).unwrap();
+    group.bench_function("signed_url", |b| {
+        b.iter(|| {
+            let mut key = CacheKey::new("", signed_url.strip_signature(&black_box(&req).uri), "");
+            key.user_tag = "route".to_string();
+            key.primary_bin()
+        })
+    });
+    group.finish();
+}
+
+fn origin_selection(c: &mut Criterion) {
+    let mut group = c.benchmark_group("origin_selection");
+    for origins in [2, 16] {
+        let route = Route {
+            state: RouteState::new(&origin_group(origins).origins).into(),
+            config: RouteConfig {
+                origin_group: origin_group(origins),
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        group.bench_with_input(BenchmarkId::new("sample", origins), &origins, |b, _| {
+            b.iter(|| {
+                let state = route.state.read().unwrap();
+                state.selection.as_ref().unwrap().sample()
+            })
+        });
+        // What follows an origin being marked down or back up.
+        group.bench_with_input(BenchmarkId::new("rebuild", origins), &origins, |b, _| {
+            b.iter(|| {
+                let mut state = route.state.write().unwrap();
+                state.selection = None;
+                state
+                    .selection(&route.config.origin_group.origins)
+                    .unwrap()
+                    .sample()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, route_lookup, cache_key, origin_selection);
+criterion_main!(benches);
@@ -0,0 +1,302 @@
+//! A load-test harness for a running granite proxy.
+//!
+//! It starts a mock origin that answers every request with a fixed response, adds a route for it
+//! through the config API, and then sends requests through the proxy from many concurrent
+//! keep-alive connections, reporting throughput and latency percentiles at the end.
+//!
+//! Start granite (e.g., with `examples/conf.yaml`) and then run:
+//!
+//! ```text
+//! cargo run --release --example load_test -- --concurrency 64 --duration 30
+//! ```
+//!
+//! Options (with their defaults):
+//! - `--proxy 127.0.0.1:8080`: The proxy's HTTP listener.
+//! - `--api 127.0.0.1:5000`: The config API.  Set it to `none` to skip adding the route.
+//! - `--origin 127.0.0.1:9090`: Where the mock origin listens.
+//! - `--host load-test`: The host header sent (and routed on).
+//! - `--path /`: The request path.
+//! - `--cache false`: Whether the route caches responses.
+//! - `--body-size 1024`: The size of the mock origin's response body (in bytes).
+//! - `--concurrency 32`: The number of connections.
+//! - `--duration 10`: How long to send requests for (in seconds).
+
+use serde_json::json;
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+
+struct Options {
+    proxy: String,
+    api: Option<String>,
+    origin: String,
+    host: String,
+    path: String,
+    cache: bool,
+    body_size: usize,
+    concurrency: usize,
+    duration: Duration,
+}
+
+impl Options {
+    fn parse() -> Result<Self, String> {
+        let args: Vec<String> = std::env::args().skip(1).collect();
+        if !args.len().is_multiple_of(2) {
+            return Err("Options must be given as `--name value` pairs".to_string());
+        }
+        let mut values: HashMap<&str, &str> = HashMap::new();
+        for pair in args.chunks(2) {
+            let name = pair[0]
+                .strip_prefix("--")
+                .ok_or_else(|| format!("Unexpected argument: {}", pair[0]))?;
+            values.insert(name, &pair[1]);
+        }
+        let mut get =
+            |name: &str, default: &str| values.remove(name).unwrap_or(default).to_string();
+        let number = |name: &str, value: String| {
+            value
+                .parse::<usize>()
+                .map_err(|e| format!("Invalid --{name}: {e}"))
+        };
+
+        let options = Options {
+            proxy: get("proxy", "127.0.0.1:8080"),
+            api: Some(get("api", "127.0.0.1:5000")).filter(|api| api != "none"),
+            origin: get("origin", "127.0.0.1:9090"),
+            host: get("host", "load-test"),
+            path: get("path", "/"),
+            cache: get("cache", "false") == "true",
+            body_size: number("body-size", get("body-size", "1024"))?,
+            concurrency: number("concurrency", get("concurrency", "32"))?,
+            duration: Duration::from_secs(number("duration", get("duration", "10"))? as u64),
+        };
+        if let Some(name) = values.keys().next() {
+            return Err(format!("Unknown option: --{name}"));
+        }
+        Ok(options)
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let options = Options::parse().unwrap_or_else(|e| {
+        eprintln!("{e}");
+        std::process::exit(1);
+    });
+
+    let listener = TcpListener::bind(&options.origin)
+        .await
+        .expect("Unable to start the mock origin");
+    tokio::spawn(mock_origin(listener, options.body_size));
+
+    if let Some(api) = &options.api {
+        add_route(api, &options).await.unwrap_or_else(|e| {
+            eprintln!("Unable to add the load-test route through {api}: {e}");
+            std::process::exit(1);
+        });
+    }
+
+    println!(
+        "Sending requests to {} (host: {}) from {} connections for {}s",
+        options.proxy,
+        options.host,
+        options.concurrency,
+        options.duration.as_secs()
+    );
+    let request = Arc::new(format!(
+        "GET {} HTTP/1.1\r\nHost: {}\r\nUser-Agent: granite-load-test\r\n\r\n",
+        options.path, options.host
+    ));
+    let deadline = Instant::now() + options.duration;
+    let started = Instant::now();
+    let clients: Vec<_> = (0..options.concurrency)
+        .map(|_| tokio::spawn(client(options.proxy.clone(), request.clone(), deadline)))
+        .collect();
+
+    let mut report = Report::default();
+    for client in clients {
+        report.merge(client.await.unwrap());
+    }
+    report.print(started.elapsed());
+}
+
+/// Answer every request on every connection with the same response.
+async fn mock_origin(listener: TcpListener, body_size: usize) {
+    let response: Arc<[u8]> = [
+        format!(
+            "HTTP/1.1 200 OK\r\nContent-Type: application/octet-stream\r\n\
+             Cache-Control: max-age=60\r\nContent-Length: {body_size}\r\n\r\n"
+        )
+        .into_bytes(),
+        vec![b'x'; body_size],
+    ]
+    .concat()
+    .into();
+
+    loop {
+        let Ok((stream, _)) = listener.accept().await else {
+            continue;
+        };
+        let response = response.clone();
+        tokio::spawn(async move {
+            let mut stream = BufReader::new(stream);
+            // Requests from the proxy are all GETs without a body, so each ends at a blank line.
+            while read_head(&mut stream)
+                .await
+                .is_ok_and(|head| !head.is_empty())
+            {
+                if stream.get_mut().write_all(&response).await.is_err() {
+                    break;
+                }
+            }
+        });
+    }
+}
+
+/// Add a route that sends the load-test host to the mock origin.
+async fn add_route(api: &str, options: &Options) -> io::Result<()> {
+    let (origin_host, origin_port) = options
+        .origin
+        .rsplit_once(':')
+        .ok_or_else(|| io::Error::other("The origin address must be host:port"))?;
+    let route = json!({
+        "name": "load-test",
+        "customer": "load-test",
+        "hosts": [options.host],
+        "paths": ["/"],
+        "incoming_schemes": ["Http"],
+        "cache": options.cache,
+        "origin_group": {
+            "origins": [{"host": origin_host, "http_port": origin_port.parse::<u16>().ok()}]
+        }
+    })
+    .to_string();
+
+    let mut stream = BufReader::new(TcpStream::connect(api).await?);
+    let request = format!(
+        "POST /route/add HTTP/1.1\r\nHost: {api}\r\nContent-Type: application/json\r\n\
+         Content-Length: {}\r\nConnection: close\r\n\r\n{route}",
+        route.len()
+    );
+    stream.get_mut().write_all(request.as_bytes()).await?;
+    let head = read_head(&mut stream).await?;
+    match head.split(' ').nth(1) {
+        Some(status) if status.starts_with('2') => Ok(()),
+        _ => Err(io::Error::other(
+            head.lines().next().unwrap_or_default().to_string(),
+        )),
+    }
+}
+
+/// What one client (or all of them) observed.
+#[derive(Default)]
+struct Report {
+    /// The latency of each successful request.
+    latencies: Vec<Duration>,
+    /// The number of responses by status code.
+    statuses: HashMap<u16, u64>,
+    /// The number of connection or protocol errors.
+    errors: u64,
+}
+
+impl Report {
+    fn merge(&mut self, other: Report) {
+        self.latencies.extend(other.latencies);
+        for (status, count) in other.statuses {
+            *self.statuses.entry(status).or_default() += count;
+        }
+        self.errors += other.errors;
+    }
+
+    fn print(mut self, elapsed: Duration) {
+        self.latencies.sort();
+        let requests = self.latencies.len();
+        println!(
+            "{requests} requests in {:.1}s ({:.0} requests/s), {} errors",
+            elapsed.as_secs_f64(),
+            requests as f64 / elapsed.as_secs_f64(),
+            self.errors
+        );
+        let mut statuses: Vec<_> = self.statuses.into_iter().collect();
+        statuses.sort();
+        for (status, count) in statuses {
+            println!("  status {status}: {count}");
+        }
+        if requests == 0 {
+            return;
+        }
+        for percentile in [50.0, 90.0, 99.0, 99.9] {
+            let index = ((requests as f64 * percentile / 100.0) as usize).min(requests - 1);
+            println!("  p{percentile}: {:?}", self.latencies[index]);
+        }
+        println!("  max: {:?}", self.latencies[requests - 1]);
+    }
+}
+
+/// Send requests over a keep-alive connection (reconnecting after errors) until the deadline.
+async fn client(proxy: String, request: Arc<String>, deadline: Instant) -> Report {
+    let mut report = Report::default();
+    let mut connection: Option<BufReader<TcpStream>> = None;
+    while Instant::now() < deadline {
+        let stream = match &mut connection {
+            Some(stream) => stream,
+            None => match TcpStream::connect(&proxy).await {
+                Ok(stream) => connection.insert(BufReader::new(stream)),
+                Err(_) => {
+                    report.errors += 1;
+                    tokio::time::sleep(Duration::from_millis(100)).await;
+                    continue;
+                }
+            },
+        };
+
+        let start = Instant::now();
+        match send(stream, request.as_bytes()).await {
+            Ok(status) => {
+                report.latencies.push(start.elapsed());
+                *report.statuses.entry(status).or_default() += 1;
+            }
+            Err(_) => {
+                report.errors += 1;
+                connection = None;
+            }
+        }
+    }
+    report
+}
+
+/// Send a request and read the whole response, returning its status code.
+async fn send(stream: &mut BufReader<TcpStream>, request: &[u8]) -> io::Result<u16> {
+    stream.get_mut().write_all(request).await?;
+    let head = read_head(stream).await?;
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, "Invalid response");
+    let status = head
+        .split(' ')
+        .nth(1)
+        .and_then(|s| s.parse().ok())
+        .ok_or_else(invalid)?;
+    let content_length = head
+        .lines()
+        .filter_map(|line| line.split_once(':'))
+        .find(|(name, _)| name.eq_ignore_ascii_case("content-length"))
+        .map(|(_, value)| value.trim().parse::<u64>().map_err(|_| invalid()))
+        .transpose()?
+        .ok_or_else(invalid)?;
+    tokio::io::copy(&mut stream.take(content_length), &mut tokio::io::sink()).await?;
+    Ok(status)
+}
+
+/// Read a message head (the start line and headers), up to the blank line that ends it.  An empty
+/// string means the connection was closed.
+async fn read_head(stream: &mut BufReader<TcpStream>) -> io::Result<String> {
+    let mut head = String::new();
+    loop {
+        let read = stream.read_line(&mut head).await?;
+        if read == 0 || head.ends_with("\r\n\r\n") {
+            return Ok(head);
+        }
+    }
+}
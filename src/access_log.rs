@@ -0,0 +1,298 @@
+//! An access log written to a file, with built-in rotation so long-running proxies don't fill their
+//! disks or depend on an external logrotate arrangement.
+//!
+//! Lines are handed to a dedicated writer thread, so request processing never blocks on file I/O.
+//! If the writer falls behind, lines are dropped (and counted) rather than buffered without bound.
+//!
+//! When the log is rotated, `access.log` becomes `access.log.1` (optionally gzipped to
+//! `access.log.1.gz`), the previous `access.log.1` becomes `access.log.2`, and so on.  Files beyond
+//! the retention limit are deleted.
+
+use chrono::Utc;
+use flate2::write::GzEncoder;
+use flate2::Compression;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+/// The number of lines that can be queued for the writer thread.
+const QUEUE_SIZE: usize = 8192;
+
+/// Access log settings.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct AccessLogConfig {
+    /// The file to write the access log to.  If not set, no access log is written.
+    pub path: Option<String>,
+
+    /// Rotate the log once it reaches this size (in bytes).
+    pub max_size: Option<u64>,
+
+    /// Rotate the log once it has been written to for this long (in seconds).
+    pub rotate_interval: Option<u64>,
+
+    /// The number of rotated files to keep.
+    pub max_files: usize,
+
+    /// Whether to gzip rotated files.
+    pub compress: bool,
+
+    /// The directory the file sinks of routes are written to.  If not set, routes can't log to
+    /// files.
+    pub sink_dir: Option<String>,
+}
+
+impl Default for AccessLogConfig {
+    /// By default, no access log is written.  When it is, 5 rotated files are kept (uncompressed).
+    fn default() -> Self {
+        AccessLogConfig {
+            path: None,
+            max_size: None,
+            rotate_interval: None,
+            max_files: 5,
+            compress: false,
+            sink_dir: None,
+        }
+    }
+}
+
+/// What is logged about a finished request.  It is formatted like the Combined Log Format followed
+/// by granite-specific fields.
+pub struct AccessLogEntry<'a> {
+    pub client_ip: Option<String>,
+    pub method: &'a str,
+    pub uri: &'a str,
+    pub version: &'a str,
+    pub status: u16,
+    pub response_bytes: u64,
+    pub referer: Option<&'a str>,
+    pub user_agent: Option<&'a str>,
+    pub duration: Duration,
+    pub route: Option<&'a str>,
+    pub cache_status: Option<&'static str>,
+    pub request_id: &'a str,
+    /// The host of the origin the request was sent to (if any).
+    pub origin: Option<&'a str>,
+    /// The ID of the instance that served the request.
+    pub instance: &'a str,
+    /// The client's TLS fingerprint (if fingerprinting is enabled).
+    pub tls_fingerprint: Option<&'a str>,
+}
+
+impl fmt::Display for AccessLogEntry<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} - - [{}] \"{} {} {}\" {} {} \"{}\" \"{}\" {:.3} {} {} {} {} {} {}",
+            self.client_ip.as_deref().unwrap_or("-"),
+            Utc::now().format("%d/%b/%Y:%H:%M:%S %z"),
+            self.method,
+            self.uri,
+            self.version,
+            self.status,
+            self.response_bytes,
+            self.referer.unwrap_or("-"),
+            self.user_agent.unwrap_or("-"),
+            self.duration.as_secs_f64(),
+            self.route.unwrap_or("-"),
+            self.cache_status.unwrap_or("-"),
+            self.request_id,
+            self.origin.unwrap_or("-"),
+            self.instance,
+            self.tls_fingerprint.unwrap_or("-"),
+        )
+    }
+}
+
+/// The access log (a no-op if not configured).
+pub struct AccessLog {
+    sender: Option<SyncSender<String>>,
+    dropped: AtomicU64,
+}
+
+impl AccessLog {
+    /// Open the access log (if configured) and start its writer thread.
+    pub fn new(config: &AccessLogConfig) -> Self {
+        let sender = config.path.as_ref().and_then(|path| {
+            let file = match RotatingFile::open(path.clone(), config) {
+                Ok(file) => file,
+                Err(e) => {
+                    error!("Unable to open access log {path}: {e}");
+                    return None;
+                }
+            };
+            info!("Writing access log to {path}");
+            let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+            thread::spawn(move || write_lines(file, receiver));
+            Some(sender)
+        });
+        AccessLog {
+            sender,
+            dropped: AtomicU64::new(0),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.sender.is_some()
+    }
+
+    pub fn log(&self, entry: &AccessLogEntry) {
+        let Some(sender) = &self.sender else {
+            return;
+        };
+        if let Err(TrySendError::Full(_)) = sender.try_send(entry.to_string()) {
+            let dropped = self.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped.is_power_of_two() {
+                warn!("Access log writer is falling behind; {dropped} lines dropped so far");
+            }
+        }
+    }
+}
+
+/// The writer thread's loop: write lines as they arrive, flushing whenever the queue is drained.
+pub(crate) fn write_lines(mut file: RotatingFile, receiver: Receiver<String>) {
+    while let Ok(line) = receiver.recv() {
+        let mut result = file.write_line(&line);
+        while let (Ok(()), Ok(line)) = (&result, receiver.try_recv()) {
+            result = file.write_line(&line);
+        }
+        if let Err(e) = result.and_then(|_| file.flush()) {
+            error!("Unable to write access log {}: {e}", file.path);
+        }
+    }
+}
+
+/// A log file that rotates itself by size and/or age.
+pub(crate) struct RotatingFile {
+    path: String,
+    max_size: Option<u64>,
+    rotate_interval: Option<Duration>,
+    max_files: usize,
+    compress: bool,
+    writer: BufWriter<File>,
+    size: u64,
+    opened: Instant,
+}
+
+impl RotatingFile {
+    pub(crate) fn open(path: String, config: &AccessLogConfig) -> io::Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(RotatingFile {
+            path,
+            max_size: config.max_size,
+            rotate_interval: config.rotate_interval.map(Duration::from_secs),
+            max_files: config.max_files,
+            compress: config.compress,
+            writer: BufWriter::new(file),
+            size,
+            opened: Instant::now(),
+        })
+    }
+
+    fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let too_big = self
+            .max_size
+            .is_some_and(|max| self.size > 0 && self.size >= max);
+        let too_old = self
+            .rotate_interval
+            .is_some_and(|interval| self.opened.elapsed() >= interval);
+        if too_big || too_old {
+            self.rotate()?;
+        }
+        writeln!(self.writer, "{line}")?;
+        self.size += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+
+    /// The name of the nth rotated file.
+    fn rotated_path(&self, n: usize) -> String {
+        if self.compress {
+            format!("{}.{n}.gz", self.path)
+        } else {
+            format!("{}.{n}", self.path)
+        }
+    }
+
+    /// Move the current file aside (shifting older files and deleting those beyond the retention
+    /// limit) and start a new one.
+    fn rotate(&mut self) -> io::Result<()> {
+        self.writer.flush()?;
+        if self.max_files == 0 {
+            fs::remove_file(&self.path)?;
+        } else {
+            let _ = fs::remove_file(self.rotated_path(self.max_files));
+            for n in (1..self.max_files).rev() {
+                let _ = fs::rename(self.rotated_path(n), self.rotated_path(n + 1));
+            }
+            if self.compress {
+                let mut encoder =
+                    GzEncoder::new(File::create(self.rotated_path(1))?, Compression::default());
+                io::copy(&mut File::open(&self.path)?, &mut encoder)?;
+                encoder.finish()?;
+                fs::remove_file(&self.path)?;
+            } else {
+                fs::rename(&self.path, self.rotated_path(1))?;
+            }
+        }
+
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.writer = BufWriter::new(file);
+        self.size = 0;
+        self.opened = Instant::now();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use flate2::read::GzDecoder;
+    use std::io::Read;
+
+    #[test]
+    fn rotate_by_size() {
+        let dir =
+            std::env::temp_dir().join(format!("granite-access-log-{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("access.log").to_string_lossy().to_string();
+        let config = AccessLogConfig {
+            path: Some(path.clone()),
+            max_size: Some(10),
+            max_files: 2,
+            compress: true,
+            ..Default::default()
+        };
+
+        let mut file = RotatingFile::open(path.clone(), &config).unwrap();
+        for line in ["line one", "line two", "line three", "line four"] {
+            file.write_line(line).unwrap();
+        }
+        file.flush().unwrap();
+
+        assert_eq!(fs::read_to_string(&path).unwrap(), "line four\n");
+        let mut rotated = String::new();
+        GzDecoder::new(File::open(format!("{path}.1.gz")).unwrap())
+            .read_to_string(&mut rotated)
+            .unwrap();
+        assert_eq!(rotated, "line three\n");
+        assert!(fs::metadata(format!("{path}.2.gz")).is_ok());
+        // Only two rotated files are kept.
+        assert!(fs::metadata(format!("{path}.3.gz")).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
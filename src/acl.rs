@@ -0,0 +1,276 @@
+//! A dynamic deny list of client IP addresses, CIDR blocks, and autonomous systems (e.g., hosting
+//! providers known for scraping), managed through the Config API and consulted on every request.
+//! The list can optionally be persisted to a file so it survives restarts.
+//!
+//! Autonomous systems are written `AS<number>` (e.g., `AS64496`).  Clients' autonomous systems are
+//! looked up in the GeoIP databases, so blocking them requires one that has them (e.g., GeoLite2
+//! ASN).
+
+use ipnet::IpNet;
+use log::{error, info, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::fmt;
+use std::fs;
+use std::net::IpAddr;
+use std::sync::RwLock;
+
+/// An interface for blocking and unblocking clients.
+pub trait AclHolder: Send + Sync {
+    fn block(&self, entry: AclEntry);
+    fn unblock(&self, entry: &AclEntry);
+    fn blocked(&self) -> Vec<AclEntry>;
+}
+
+/// An entry of the deny list.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum AclEntry {
+    /// An address or CIDR block.
+    Net(IpNet),
+
+    /// An autonomous system number.
+    Asn(u32),
+}
+
+impl fmt::Display for AclEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AclEntry::Net(net) => write!(f, "{net}"),
+            AclEntry::Asn(asn) => write!(f, "AS{asn}"),
+        }
+    }
+}
+
+/// Deny list settings.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+#[serde(default)]
+pub struct AclConfig {
+    /// An optional file the deny list is persisted to (one address or CIDR block per line).  The
+    /// list is loaded from the file at startup and rewritten on every change.
+    pub persist_file: Option<String>,
+}
+
+/// A deny list of client addresses.
+pub struct DenyList {
+    inner: RwLock<InnerList>,
+    persist_file: Option<String>,
+}
+
+/// The inner protected part of the DenyList.  Single addresses and autonomous systems are kept in
+/// sets for fast lookups; CIDR blocks are scanned.
+struct InnerList {
+    addrs: HashSet<IpAddr>,
+    nets: Vec<IpNet>,
+    asns: HashSet<u32>,
+}
+
+impl DenyList {
+    pub fn new(config: &AclConfig) -> Self {
+        let deny_list = DenyList {
+            inner: RwLock::new(InnerList {
+                addrs: HashSet::new(),
+                nets: Vec::new(),
+                asns: HashSet::new(),
+            }),
+            persist_file: config.persist_file.clone(),
+        };
+        deny_list.load();
+        deny_list
+    }
+
+    /// Whether the client address is denied.
+    pub fn is_blocked(&self, addr: &IpAddr) -> bool {
+        let inner = self.inner.read().unwrap();
+        inner.addrs.contains(addr) || inner.nets.iter().any(|net| net.contains(addr))
+    }
+
+    /// Whether the autonomous system is denied.
+    pub fn is_asn_blocked(&self, asn: u32) -> bool {
+        self.inner.read().unwrap().asns.contains(&asn)
+    }
+
+    /// Whether any autonomous systems are denied (so clients' autonomous systems need to be looked
+    /// up).
+    pub fn has_asns(&self) -> bool {
+        !self.inner.read().unwrap().asns.is_empty()
+    }
+
+    /// Load the persisted deny list (if any).
+    fn load(&self) {
+        let Some(path) = self.persist_file.as_ref() else {
+            return;
+        };
+        let contents = match fs::read_to_string(path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                warn!("Unable to read deny list file {path}: {e}");
+                return;
+            }
+        };
+
+        let mut inner = self.inner.write().unwrap();
+        for line in contents.lines().map(str::trim).filter(|l| !l.is_empty()) {
+            match parse_entry(line) {
+                Some(entry) => insert(&mut inner, entry),
+                None => warn!("Skipping invalid deny list entry '{line}'"),
+            }
+        }
+        info!(
+            "Loaded {} deny list entries from {path}",
+            inner.addrs.len() + inner.nets.len() + inner.asns.len()
+        );
+    }
+
+    /// Write the deny list to the persistence file (if any).
+    fn persist(&self, inner: &InnerList) {
+        let Some(path) = self.persist_file.as_ref() else {
+            return;
+        };
+        let contents: String = entries(inner)
+            .iter()
+            .map(|entry| format!("{entry}\n"))
+            .collect();
+        if let Err(e) = fs::write(path, contents) {
+            error!("Unable to write deny list file {path}: {e}");
+        }
+    }
+}
+
+impl AclHolder for DenyList {
+    /// Add an address, CIDR block, or autonomous system to the deny list.
+    fn block(&self, entry: AclEntry) {
+        let mut inner = self.inner.write().unwrap();
+        insert(&mut inner, entry);
+        self.persist(&inner);
+    }
+
+    /// Remove an address, CIDR block, or autonomous system from the deny list (if present).
+    fn unblock(&self, entry: &AclEntry) {
+        let mut inner = self.inner.write().unwrap();
+        let removed = match entry {
+            AclEntry::Net(net) if is_single_addr(net) => inner.addrs.remove(&net.addr()),
+            AclEntry::Net(net) => {
+                let len = inner.nets.len();
+                inner.nets.retain(|n| n != net);
+                inner.nets.len() != len
+            }
+            AclEntry::Asn(asn) => inner.asns.remove(asn),
+        };
+        if !removed {
+            warn!("Attempted to unblock an entry that isn't blocked entry={entry}");
+            return;
+        }
+        self.persist(&inner);
+    }
+
+    /// List the deny list entries.
+    fn blocked(&self) -> Vec<AclEntry> {
+        entries(&self.inner.read().unwrap())
+    }
+}
+
+/// An address or CIDR block in a route's configuration (see `RouteConfig::client_ip_match`).
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy)]
+#[serde(try_from = "String", into = "String")]
+pub struct ClientNet(pub IpNet);
+
+impl TryFrom<String> for ClientNet {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        parse_net(&s)
+            .map(ClientNet)
+            .ok_or_else(|| format!("Invalid address or CIDR block {s}"))
+    }
+}
+
+impl From<ClientNet> for String {
+    fn from(net: ClientNet) -> Self {
+        net.0.to_string()
+    }
+}
+
+impl ClientNet {
+    /// Whether the client is in any of the networks.
+    pub fn any_contains(nets: &[ClientNet], client: Option<IpAddr>) -> bool {
+        client.is_some_and(|ip| nets.iter().any(|net| net.0.contains(&ip)))
+    }
+}
+
+/// Parse an address (e.g., `192.0.2.1`) or a CIDR block (e.g., `192.0.2.0/24`).
+pub fn parse_net(s: &str) -> Option<IpNet> {
+    let s = s.trim();
+    s.parse::<IpNet>()
+        .ok()
+        .or_else(|| s.parse::<IpAddr>().ok().map(IpNet::from))
+}
+
+/// Parse a deny list entry: an address, a CIDR block, or an autonomous system (e.g., `AS64496`).
+pub fn parse_entry(s: &str) -> Option<AclEntry> {
+    let s = s.trim();
+    match s.get(..2) {
+        Some(prefix) if prefix.eq_ignore_ascii_case("AS") => s[2..].parse().ok().map(AclEntry::Asn),
+        _ => parse_net(s).map(AclEntry::Net),
+    }
+}
+
+fn is_single_addr(net: &IpNet) -> bool {
+    net.prefix_len() == net.max_prefix_len()
+}
+
+fn insert(inner: &mut InnerList, entry: AclEntry) {
+    match entry {
+        AclEntry::Net(net) if is_single_addr(&net) => {
+            inner.addrs.insert(net.addr());
+        }
+        AclEntry::Net(net) => {
+            if !inner.nets.contains(&net) {
+                inner.nets.push(net);
+            }
+        }
+        AclEntry::Asn(asn) => {
+            inner.asns.insert(asn);
+        }
+    }
+}
+
+fn entries(inner: &InnerList) -> Vec<AclEntry> {
+    inner
+        .addrs
+        .iter()
+        .map(|addr| AclEntry::Net(IpNet::from(*addr)))
+        .chain(inner.nets.iter().map(|net| AclEntry::Net(*net)))
+        .chain(inner.asns.iter().map(|asn| AclEntry::Asn(*asn)))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn block_and_unblock() {
+        let deny_list = DenyList::new(&AclConfig::default());
+        let addr: IpAddr = "192.0.2.1".parse().unwrap();
+        let other: IpAddr = "198.51.100.7".parse().unwrap();
+
+        deny_list.block(parse_entry("192.0.2.1").unwrap());
+        deny_list.block(parse_entry("198.51.100.0/24").unwrap());
+        deny_list.block(parse_entry("as64496").unwrap());
+        assert!(deny_list.is_blocked(&addr));
+        assert!(deny_list.is_blocked(&other));
+        assert!(deny_list.is_asn_blocked(64496));
+        assert!(!deny_list.is_asn_blocked(64497));
+        assert_eq!(deny_list.blocked().len(), 3);
+        assert!(deny_list.blocked().contains(&AclEntry::Asn(64496)));
+        assert_eq!(AclEntry::Asn(64496).to_string(), "AS64496");
+        assert_eq!(parse_entry("AS"), None);
+
+        deny_list.unblock(&parse_entry("192.0.2.1").unwrap());
+        deny_list.unblock(&parse_entry("198.51.100.0/24").unwrap());
+        deny_list.unblock(&parse_entry("AS64496").unwrap());
+        assert!(!deny_list.is_blocked(&addr));
+        assert!(!deny_list.is_blocked(&other));
+        assert!(!deny_list.has_asns());
+    }
+}
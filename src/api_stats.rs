@@ -0,0 +1,117 @@
+//! The Config API's own version and statistics, so orchestration tooling can check it's talking
+//! to a compatible build and monitor the control plane itself.
+
+use serde::Serialize;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+/// The version of this build.
+pub const VERSION: &str = env!("CARGO_PKG_VERSION");
+
+/// The commit this build was made from, if given at build time (in `GRANITE_COMMIT`).
+pub const COMMIT: Option<&str> = option_env!("GRANITE_COMMIT");
+
+/// The build's version.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct VersionInfo {
+    pub version: &'static str,
+    pub commit: Option<&'static str>,
+}
+
+impl VersionInfo {
+    pub fn current() -> Self {
+        VersionInfo {
+            version: VERSION,
+            commit: COMMIT,
+        }
+    }
+}
+
+/// The Config API's statistics.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct ApiStats {
+    #[serde(flatten)]
+    pub version: VersionInfo,
+    /// Time (in seconds) since the Config API started.
+    pub uptime: u64,
+    /// The configured routes, certificate bindings, and customers.
+    pub routes: usize,
+    pub certs: usize,
+    pub customers: usize,
+    /// The requests the Config API answered.
+    pub requests: u64,
+    /// The requests answered with a 4xx status.
+    pub client_errors: u64,
+    /// The requests answered with a 5xx status.
+    pub server_errors: u64,
+}
+
+/// Counts the Config API's requests.
+pub struct ApiCounters {
+    start: Instant,
+    requests: AtomicU64,
+    client_errors: AtomicU64,
+    server_errors: AtomicU64,
+}
+
+impl ApiCounters {
+    pub fn new() -> Self {
+        ApiCounters {
+            start: Instant::now(),
+            requests: AtomicU64::new(0),
+            client_errors: AtomicU64::new(0),
+            server_errors: AtomicU64::new(0),
+        }
+    }
+
+    /// Count a request answered with the status.
+    pub fn record(&self, status: u16) {
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        match status {
+            400..=499 => self.client_errors.fetch_add(1, Ordering::Relaxed),
+            500..=599 => self.server_errors.fetch_add(1, Ordering::Relaxed),
+            _ => 0,
+        };
+    }
+
+    /// The statistics, given the counts of configured items.
+    pub fn stats(&self, routes: usize, certs: usize, customers: usize) -> ApiStats {
+        ApiStats {
+            version: VersionInfo::current(),
+            uptime: self.start.elapsed().as_secs(),
+            routes,
+            certs,
+            customers,
+            requests: self.requests.load(Ordering::Relaxed),
+            client_errors: self.client_errors.load(Ordering::Relaxed),
+            server_errors: self.server_errors.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Default for ApiCounters {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn counters() {
+        let counters = ApiCounters::new();
+        for status in [200, 404, 409, 503] {
+            counters.record(status);
+        }
+        let stats = counters.stats(3, 2, 1);
+        assert_eq!((stats.routes, stats.certs, stats.customers), (3, 2, 1));
+        assert_eq!(stats.requests, 4);
+        assert_eq!(stats.client_errors, 2);
+        assert_eq!(stats.server_errors, 1);
+
+        let json = serde_json::to_value(&stats).unwrap();
+        assert_eq!(json["version"], VERSION);
+    }
+}
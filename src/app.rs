@@ -0,0 +1,378 @@
+//! Assembly of the granite server: the stores, the proxy and Config API services, and the optional
+//! services (replication, metrics), wired together on a Pingora server.
+//!
+//! The `granite` binary is a thin wrapper around `GraniteBuilder`.  Other binaries can embed the
+//! proxy the same way, attach their own services, and reach the stores directly.
+
+use log::info;
+use pingora::listeners::TlsSettings;
+use pingora::prelude::{http_proxy_service, Opt};
+use pingora::server::configuration::ServerConf;
+use pingora::server::Server;
+use pingora::services::background::GenBackgroundService;
+use pingora::services::{listening::Service as ListeningService, Service};
+use pingora::tls::ssl::SslVerifyMode;
+use pingora::{Error, ErrorType::*, OrErr, Result};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+
+use crate::acl::DenyList;
+use crate::app_config::{ApiConfig, AppConfig};
+use crate::basic_auth::CredentialStore;
+use crate::cert::{cert_provider::CertProvider, cert_store::CertStore, session_cache};
+use crate::config_api::ConfigApi;
+use crate::customer::CustomerStore;
+use crate::declarative::Reconciler;
+use crate::drain::Drainer;
+use crate::events;
+use crate::expiry::Sweeper;
+use crate::fault::FaultInjector;
+use crate::geoip::GeoIp;
+use crate::instance::Instance;
+use crate::listeners;
+use crate::memory::MemoryTracker;
+use crate::plugin::{Plugin, PluginRegistry};
+use crate::prewarm::Prewarmer;
+use crate::protocols;
+use crate::proxy::{self, Proxy};
+use crate::quota::QuotaTracker;
+use crate::replication::{Follower, Replicator};
+use crate::route_store::RouteStore;
+use crate::status::StatusReporter;
+use crate::tap::RequestTap;
+use crate::tls_fingerprint;
+use crate::usage::UsageTracker;
+use crate::wasm::WasmStore;
+
+/// Assembles a granite server from its configuration.
+pub struct GraniteBuilder {
+    conf: AppConfig,
+    server_conf: ServerConf,
+    opt: Opt,
+    services: Vec<Box<dyn Service>>,
+    plugins: PluginRegistry,
+}
+
+impl GraniteBuilder {
+    /// Start from the application configuration, with the default Pingora configuration and no
+    /// command-line options (the process's arguments aren't parsed; see `options`).
+    pub fn new(conf: AppConfig) -> Self {
+        GraniteBuilder {
+            conf,
+            server_conf: ServerConf::new().unwrap_or_default(),
+            opt: Opt {
+                upgrade: false,
+                daemon: false,
+                nocapture: false,
+                test: false,
+                conf: None,
+            },
+            services: Vec::new(),
+            plugins: PluginRegistry::new(),
+        }
+    }
+
+    /// Start from a configuration file, which holds both the application configuration and (at the
+    /// top level) the Pingora configuration.
+    pub fn from_file(path: &str) -> Result<Self> {
+        let conf = AppConfig::load_from_yaml(path)?;
+        let server_conf = ServerConf::load_from_yaml(path)?;
+        Ok(GraniteBuilder::new(conf).server_conf(server_conf))
+    }
+
+    /// Set the Pingora configuration.  (The `server` section of the application configuration
+    /// still overrides it.)
+    pub fn server_conf(mut self, server_conf: ServerConf) -> Self {
+        self.server_conf = server_conf;
+        self
+    }
+
+    /// Set the command-line options.  (The `server` section of the application configuration still
+    /// overrides them, and the configuration file option is ignored.)
+    pub fn options(mut self, opt: Opt) -> Self {
+        self.opt = opt;
+        self
+    }
+
+    /// Attach another service to run alongside granite's own.
+    pub fn service(mut self, service: Box<dyn Service>) -> Self {
+        self.services.push(service);
+        self
+    }
+
+    /// Register a plugin that routes can enable by name.
+    pub fn plugin(mut self, name: &str, plugin: Arc<dyn Plugin>) -> Self {
+        self.plugins.register(name, plugin);
+        self
+    }
+
+    /// Set up the server: take over (or open) the listening sockets, and create the stores and
+    /// services.
+    pub fn build(self) -> Result<Granite> {
+        let GraniteBuilder {
+            conf,
+            mut server_conf,
+            mut opt,
+            services: extra_services,
+            plugins,
+        } = self;
+        opt.conf = None;
+        conf.server.apply(&mut server_conf, &mut opt);
+
+        // Sockets passed by systemd or bound early are handed to the server the way a running
+        // instance hands them over in a graceful upgrade.  (In an actual upgrade, they come from
+        // that instance.)
+        let mut hand_over: Option<JoinHandle<()>> = None;
+        if !opt.upgrade {
+            let fds = listeners::open(&conf.listener_addrs(), conf.server.bind_early)
+                .or_err(BindError, "Failed to bind listeners")?;
+            if let Some(fds) = fds {
+                opt.upgrade = true;
+                hand_over = Some(listeners::hand_over(fds, server_conf.upgrade_sock.clone()));
+            }
+        }
+
+        let mut server = Server::new_with_opt_and_conf(opt, server_conf);
+        server.bootstrap();
+        if let Some(hand_over) = hand_over {
+            let _ = hand_over.join();
+        }
+
+        let route_store = Arc::new(RouteStore::new());
+        let cert_store = Arc::new(CertStore::new());
+        let customer_store = Arc::new(CustomerStore::new());
+        let credential_store = Arc::new(CredentialStore::new());
+        let wasm_store = Arc::new(WasmStore::new());
+        let quota_tracker = Arc::new(QuotaTracker::new(&conf.quota));
+        let deny_list = Arc::new(DenyList::new(&conf.acl));
+        let status_reporter = Arc::new(StatusReporter::new(
+            route_store.clone(),
+            conf.cache.max_size,
+        ));
+        let request_tap = Arc::new(RequestTap::new());
+        let memory_tracker = Arc::new(MemoryTracker::new(
+            &conf.memory,
+            route_store.clone(),
+            cert_store.clone(),
+        ));
+        let drainer = Arc::new(Drainer::new(&conf.drain));
+
+        let replicator = Arc::new(Replicator::new(&conf.replication));
+        let fault_injector = Arc::new(FaultInjector::new());
+        let geoip = Arc::new(GeoIp::new(&conf.geoip)?);
+        events::init(&conf.events, &Instance::new(&conf.instance))
+            .or_err(ReadError, "Unable to open the event sink")?;
+        let usage_tracker = Arc::new(UsageTracker::new(&conf.usage));
+        let prewarmer = Arc::new(Prewarmer::new(&conf.proxy, route_store.clone()));
+
+        let config_api = Arc::new(ConfigApi::new(
+            customer_store.clone(),
+            conf.api.require_customers,
+            route_store.clone(),
+            cert_store.clone(),
+            credential_store.clone(),
+            wasm_store.clone(),
+            quota_tracker.clone(),
+            deny_list.clone(),
+            status_reporter,
+            request_tap.clone(),
+            drainer.clone(),
+            replicator.clone(),
+            fault_injector.clone(),
+            &conf.freeze,
+            usage_tracker.clone(),
+            &conf.declarative,
+        ));
+        let config_api_service = create_config_api(&conf.api, config_api.clone())?;
+
+        let proxy = Proxy::new(
+            &conf.proxy,
+            &conf.cache,
+            route_store.clone(),
+            customer_store,
+            credential_store,
+            wasm_store,
+            quota_tracker,
+            &conf.throttle,
+            deny_list,
+            &conf.metrics,
+            &conf.access_log,
+            &conf.dns,
+            &conf.cluster,
+            request_tap,
+            memory_tracker,
+            drainer,
+            fault_injector,
+            &conf.instance,
+            &conf.debug_headers,
+            geoip.clone(),
+            &conf.tls_fingerprint,
+            &conf.secrets,
+            Arc::new(plugins),
+            usage_tracker.clone(),
+            prewarmer.clone(),
+        );
+        let mut proxy_service = http_proxy_service(&server.configuration, proxy);
+        for addr in &conf.proxy.http_bind_addrs {
+            info!("Adding proxy HTTP listener on {addr}");
+            proxy_service.add_tcp(addr);
+        }
+        for addr in &conf.proxy.https_bind_addrs {
+            let cert_provider = CertProvider::new(cert_store.clone());
+            let mut tls_settings = TlsSettings::with_callbacks(cert_provider)?;
+            session_cache::install(cert_store.clone(), &mut tls_settings);
+            let http2 = !conf.proxy.http1_only_bind_addrs.contains(addr)
+                && !conf.tls_fingerprint.forces_http1();
+            tls_settings
+                .set_alpn_select_callback(protocols::alpn_callback(route_store.clone(), http2));
+            if conf.tls_fingerprint.enabled {
+                tls_settings.set_client_hello_callback(tls_fingerprint::client_hello_callback);
+            }
+            info!("Adding proxy HTTPS listener on {addr}");
+            proxy_service.add_tls_with_settings(addr, None, tls_settings);
+        }
+
+        let mut services: Vec<Box<dyn Service>> = vec![config_api_service, Box::new(proxy_service)];
+
+        if conf.declarative.file.is_some() {
+            let reconciler =
+                Reconciler::new(&conf.declarative, replicator.clone(), config_api.clone());
+            reconciler
+                .reconcile()
+                .map_err(|e| Error::explain(ReadError, e))?;
+            let reconciler_service = GenBackgroundService::new(
+                "Declarative configuration".to_string(),
+                Arc::new(reconciler),
+            );
+            services.push(Box::new(reconciler_service));
+        }
+
+        if conf.replication.leader.is_some() {
+            let follower = Follower::new(&conf.replication, replicator, config_api.clone())?;
+            let follower_service =
+                GenBackgroundService::new("Replication follower".to_string(), Arc::new(follower));
+            services.push(Box::new(follower_service));
+        } else {
+            let sweeper = Sweeper::new(replicator, config_api.clone());
+            let sweeper_service =
+                GenBackgroundService::new("Expiry sweeper".to_string(), Arc::new(sweeper));
+            services.push(Box::new(sweeper_service));
+        }
+
+        if conf.usage.persist_file.is_some() {
+            let usage_service =
+                GenBackgroundService::new("Usage persistence".to_string(), usage_tracker);
+            services.push(Box::new(usage_service));
+        }
+
+        if let Some(persister) = proxy::cache_persister(&conf.cache) {
+            let persister_service =
+                GenBackgroundService::new("Cache persistence".to_string(), Arc::new(persister));
+            services.push(Box::new(persister_service));
+        }
+
+        if prewarmer.is_enabled() {
+            let prewarm_service =
+                GenBackgroundService::new("Connection prewarming".to_string(), prewarmer);
+            services.push(Box::new(prewarm_service));
+        }
+
+        if geoip.is_enabled() {
+            let geoip_service =
+                GenBackgroundService::new("GeoIP database reloader".to_string(), geoip);
+            services.push(Box::new(geoip_service));
+        }
+
+        if let Some(addr) = conf.metrics.bind_addr.as_ref() {
+            let mut prometheus_service = ListeningService::prometheus_http_service();
+            info!("Adding metrics exporter on {addr}");
+            prometheus_service.add_tcp(addr);
+            services.push(Box::new(prometheus_service));
+        }
+
+        services.extend(extra_services);
+        server.add_services(services);
+
+        Ok(Granite {
+            server,
+            route_store,
+            cert_store,
+            config_api,
+        })
+    }
+}
+
+/// A granite server, ready to run.
+pub struct Granite {
+    server: Server,
+    route_store: Arc<RouteStore>,
+    cert_store: Arc<CertStore>,
+    config_api: Arc<ConfigApi>,
+}
+
+impl Granite {
+    /// The routes the proxy serves.
+    pub fn route_store(&self) -> &Arc<RouteStore> {
+        &self.route_store
+    }
+
+    /// The certificates the proxy serves over HTTPS.
+    pub fn cert_store(&self) -> &Arc<CertStore> {
+        &self.cert_store
+    }
+
+    /// The Config API, which can also apply configuration changes directly (see
+    /// `ConfigApi::apply`).
+    pub fn config_api(&self) -> &Arc<ConfigApi> {
+        &self.config_api
+    }
+
+    /// Attach another service to run alongside granite's own.
+    pub fn add_service(&mut self, service: impl Service + 'static) {
+        self.server.add_service(service);
+    }
+
+    /// Run the server until it exits.  See `Server::run_forever`.
+    pub fn run_forever(self) -> ! {
+        self.server.run_forever()
+    }
+}
+
+/// Create a config API service to apply dynamic configuration changes.
+/// It can run over HTTP or HTTPS and can also authenticate the caller using mutual TLS, depending
+/// on the configuration.
+fn create_config_api(config: &ApiConfig, config_api: Arc<ConfigApi>) -> Result<Box<dyn Service>> {
+    let mut config_api_service =
+        ListeningService::new("Config API service".to_string(), config_api);
+
+    if config.tls {
+        let (Some(cert_file), Some(key_file)) = (config.cert.as_ref(), config.key.as_ref()) else {
+            return Err(Error::explain(ReadError, "API: cert and key are required"));
+        };
+
+        let mut tls_settings = TlsSettings::intermediate(cert_file, key_file)?;
+        tls_settings.enable_h2();
+
+        if config.mutual_tls {
+            let Some(client_cert_file) = config.client_cert.as_ref() else {
+                return Err(Error::explain(ReadError, "API: client cert is required"));
+            };
+            tls_settings
+                .set_ca_file(client_cert_file)
+                .or_err(ReadError, "Invalid API client cert file")?;
+            tls_settings.set_verify(SslVerifyMode::PEER | SslVerifyMode::FAIL_IF_NO_PEER_CERT);
+        }
+
+        config_api_service.add_tls_with_settings(config.bind_addr.as_str(), None, tls_settings);
+    } else {
+        config_api_service.add_tcp(config.bind_addr.as_str());
+    }
+    info!(
+        "Adding Config API on {} TLS: {} mTLS: {}",
+        config.bind_addr.as_str(),
+        config.tls,
+        config.mutual_tls
+    );
+
+    Ok(Box::new(config_api_service))
+}
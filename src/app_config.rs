@@ -2,18 +2,163 @@
 
 use log::debug;
 use pingora::prelude::*;
+use pingora::server::configuration::ServerConf;
 use pingora::{Error, OrErr, Result};
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::fs;
 
+use crate::access_log::AccessLogConfig;
+use crate::acl::AclConfig;
+use crate::cache_persistence::CachePersistConfig;
+use crate::cache_stats::ShardBy;
+use crate::cluster::ClusterConfig;
+use crate::debug_headers::DebugHeadersConfig;
+use crate::declarative::DeclarativeConfig;
+use crate::dns::DnsConfig;
+use crate::drain::DrainConfig;
+use crate::error_pages::ErrorPages;
+use crate::events::EventsConfig;
+use crate::freeze::FreezeConfig;
+use crate::geoip::GeoIpConfig;
+use crate::instance::InstanceConfig;
+use crate::memory::MemoryConfig;
+use crate::metrics::MetricsConfig;
+use crate::quota::QuotaConfig;
+use crate::replication::ReplicationConfig;
+use crate::retry_budget::RetryBudgetConfig;
+use crate::secrets::SecretsConfig;
+use crate::throttle::ThrottleConfig;
+use crate::tls_fingerprint::TlsFingerprintConfig;
+use crate::usage::UsageConfig;
+
 /// The top-level configuration for the application.  The configuration is further broken down into
-/// `proxy`, `cache`, and `api` sections.
+/// `server`, `instance`, `proxy`, `cache`, `api`, `quota`, `throttle`, `acl`, `metrics`,
+/// `access_log`, `dns`, `memory`, `drain`, `replication`, `cluster`, `freeze`, `secrets`,
+/// `debug_headers`, `geoip`, `tls_fingerprint`, `usage`, `declarative`, and `events` sections.
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
 #[serde(default)]
 pub struct AppConfig {
+    pub server: ServerConfig,
+    pub instance: InstanceConfig,
     pub proxy: ProxyConfig,
     pub cache: CacheConfig,
     pub api: ApiConfig,
+    pub quota: QuotaConfig,
+    pub throttle: ThrottleConfig,
+    pub acl: AclConfig,
+    pub metrics: MetricsConfig,
+    pub access_log: AccessLogConfig,
+    pub dns: DnsConfig,
+    pub memory: MemoryConfig,
+    pub drain: DrainConfig,
+    pub replication: ReplicationConfig,
+    pub cluster: ClusterConfig,
+    pub freeze: FreezeConfig,
+    pub secrets: SecretsConfig,
+    pub debug_headers: DebugHeadersConfig,
+    pub geoip: GeoIpConfig,
+    pub tls_fingerprint: TlsFingerprintConfig,
+    pub usage: UsageConfig,
+    pub declarative: DeclarativeConfig,
+    pub events: EventsConfig,
+}
+
+/// Server (process and runtime) settings.  These override the corresponding top-level Pingora
+/// options and command-line flags, so a deployment can be described entirely by the configuration
+/// file.  Unset options are left to Pingora.  (`bind_early` is granite's own.)
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+#[serde(default)]
+pub struct ServerConfig {
+    /// The number of worker threads per service.
+    pub threads: Option<usize>,
+
+    /// Whether to let idle worker threads steal work from busy ones.
+    pub work_stealing: Option<bool>,
+
+    /// Whether to run in the background.
+    pub daemon: Option<bool>,
+
+    /// Whether to take over the listening sockets of a running instance (for a graceful upgrade).
+    pub upgrade: Option<bool>,
+
+    /// The path to the PID file.
+    pub pid_file: Option<String>,
+
+    /// The path to the socket used to hand over listening sockets during a graceful upgrade.
+    pub upgrade_sock: Option<String>,
+
+    /// The path to the error log (when running in the background).
+    pub error_log: Option<String>,
+
+    /// The user to run as after daemonizing.
+    pub user: Option<String>,
+
+    /// The group to run as after daemonizing.
+    pub group: Option<String>,
+
+    /// The path to the root CA file used to verify origins.
+    pub ca_file: Option<String>,
+
+    /// How long (in seconds) to keep serving after a graceful shutdown starts (before the
+    /// services are stopped).
+    pub grace_period_seconds: Option<u64>,
+
+    /// How long (in seconds) the services are given to stop once the grace period ends.
+    pub graceful_shutdown_timeout_seconds: Option<u64>,
+
+    /// The number of idle connections to origins kept for reuse.
+    pub upstream_keepalive_pool_size: Option<usize>,
+
+    /// Whether to bind the listeners at startup, before switching to `user` and `group` (so that
+    /// privileged ports can be used), rather than once the services start.
+    pub bind_early: bool,
+}
+
+impl ServerConfig {
+    /// Apply the settings that are set to the Pingora configuration and command-line options.
+    pub fn apply(&self, conf: &mut ServerConf, opt: &mut Opt) {
+        if let Some(threads) = self.threads {
+            conf.threads = threads;
+        }
+        if let Some(work_stealing) = self.work_stealing {
+            conf.work_stealing = work_stealing;
+        }
+        if let Some(daemon) = self.daemon {
+            conf.daemon = daemon;
+            opt.daemon = daemon;
+        }
+        if let Some(upgrade) = self.upgrade {
+            opt.upgrade = upgrade;
+        }
+        if let Some(pid_file) = &self.pid_file {
+            conf.pid_file = pid_file.clone();
+        }
+        if let Some(upgrade_sock) = &self.upgrade_sock {
+            conf.upgrade_sock = upgrade_sock.clone();
+        }
+        if self.error_log.is_some() {
+            conf.error_log = self.error_log.clone();
+        }
+        if self.user.is_some() {
+            conf.user = self.user.clone();
+        }
+        if self.group.is_some() {
+            conf.group = self.group.clone();
+        }
+        if self.ca_file.is_some() {
+            conf.ca_file = self.ca_file.clone();
+        }
+        if self.grace_period_seconds.is_some() {
+            conf.grace_period_seconds = self.grace_period_seconds;
+        }
+        if self.graceful_shutdown_timeout_seconds.is_some() {
+            conf.graceful_shutdown_timeout_seconds = self.graceful_shutdown_timeout_seconds;
+        }
+        if let Some(size) = self.upstream_keepalive_pool_size {
+            conf.upstream_keepalive_pool_size = size;
+        }
+    }
 }
 
 /// Proxy settings.
@@ -33,6 +178,29 @@ pub struct ProxyConfig {
 
     /// The maximum number of times to retry connecting to an origin.
     pub connection_retry_limit: u16,
+
+    /// An optional budget for the retries of all routes.  Once it's exhausted, failed connections
+    /// aren't retried.
+    pub retry_budget: Option<RetryBudgetConfig>,
+
+    /// Requests that take at least this long (in milliseconds) are logged with a breakdown of
+    /// where the time went and counted in the metrics.  If not set, slow requests aren't logged.
+    pub slow_request_threshold: Option<u64>,
+
+    /// Custom pages (keyed by status code) for errors generated by the proxy.  Routes can override
+    /// them with their own pages.
+    pub error_pages: ErrorPages,
+
+    /// Additional headers (besides Authorization, Proxy-Authorization, Cookie, and Set-Cookie)
+    /// whose values are redacted from logs and the request tap.
+    pub redact_headers: Vec<String>,
+
+    /// Labels of the listeners (keyed by bind address), which routes can be restricted to.
+    pub listener_labels: HashMap<String, Vec<String>>,
+
+    /// HTTPS bind addresses (among `https_bind_addrs`) that only offer HTTP/1.1 to clients.  The
+    /// others offer HTTP/2 as well.
+    pub http1_only_bind_addrs: Vec<String>,
 }
 
 /// Cache settings.
@@ -42,6 +210,16 @@ pub struct CacheConfig {
     /// The maximum size (in bytes) the cache is allowed to grow to.  If it gets larger, the least
     /// recently used items will be evicted.
     pub max_size: usize,
+
+    /// The number of shards the cache is split into for eviction.  Each shard gets an equal share
+    /// of `max_size` and its own LRU list.
+    pub eviction_shards: usize,
+
+    /// How cache entries are assigned to shards.
+    pub shard_by: ShardBy,
+
+    /// Optional persistence of the cache across restarts.
+    pub persist: Option<CachePersistConfig>,
 }
 
 /// Settings for the config API service.
@@ -66,6 +244,9 @@ pub struct ApiConfig {
     /// If mutual TLS is enabled, the path to the client certificate file.
     /// Only clients presenting this certificate will be allowed to connect.
     pub client_cert: Option<String>,
+
+    /// Whether routes must belong to a customer defined through the API.
+    pub require_customers: bool,
 }
 
 impl AppConfig {
@@ -90,7 +271,99 @@ impl AppConfig {
     }
 
     /// Validate the configuration.
+    /// The addresses of all the listeners (proxy, Config API, and metrics).
+    pub fn listener_addrs(&self) -> Vec<&str> {
+        let proxy = self
+            .proxy
+            .http_bind_addrs
+            .iter()
+            .chain(&self.proxy.https_bind_addrs);
+        proxy
+            .chain(Some(&self.api.bind_addr))
+            .chain(&self.metrics.bind_addr)
+            .map(String::as_str)
+            .collect()
+    }
+
     pub fn validate(self) -> Result<Self> {
+        if self.server.threads == Some(0) {
+            return Err(Error::new_str("Server: threads must be at least 1"));
+        }
+        if let Some(addr) = self.proxy.listener_labels.keys().find(|addr| {
+            addr.parse::<std::net::SocketAddr>().is_err()
+                || !self.proxy.http_bind_addrs.contains(addr)
+                    && !self.proxy.https_bind_addrs.contains(addr)
+        }) {
+            return Err(Error::explain(
+                ReadError,
+                format!("Proxy: {addr} in listener_labels is not a proxy bind address"),
+            ));
+        }
+        if let Some(addr) = self
+            .proxy
+            .http1_only_bind_addrs
+            .iter()
+            .find(|addr| !self.proxy.https_bind_addrs.contains(addr))
+        {
+            return Err(Error::explain(
+                ReadError,
+                format!("Proxy: {addr} in http1_only_bind_addrs is not an HTTPS bind address"),
+            ));
+        }
+        if let Some(name) = [&self.instance.served_by_header, &self.instance.pop_header]
+            .into_iter()
+            .flatten()
+            .find(|name| http::HeaderName::from_bytes(name.as_bytes()).is_err())
+        {
+            return Err(Error::explain(
+                ReadError,
+                format!("Instance: {name} is not a valid header name"),
+            ));
+        }
+        if let Some(name) = self
+            .debug_headers
+            .echo
+            .iter()
+            .find(|name| http::HeaderName::from_bytes(name.as_bytes()).is_err())
+        {
+            return Err(Error::explain(
+                ReadError,
+                format!("Debug headers: {name} is not a valid header name"),
+            ));
+        }
+        if let Some(net) = self
+            .debug_headers
+            .trusted_clients
+            .iter()
+            .find(|net| crate::acl::parse_net(net).is_none())
+        {
+            return Err(Error::explain(
+                ReadError,
+                format!("Debug headers: {net} is not an IP address or CIDR block"),
+            ));
+        }
+        if let Some(name) = self
+            .tls_fingerprint
+            .upstream_header
+            .as_ref()
+            .filter(|name| http::HeaderName::from_bytes(name.as_bytes()).is_err())
+        {
+            return Err(Error::explain(
+                ReadError,
+                format!("TLS fingerprint: {name} is not a valid header name"),
+            ));
+        }
+        if let Some(hash) = self
+            .tls_fingerprint
+            .deny
+            .iter()
+            .find(|hash| hash.len() != 32 || !hash.chars().all(|c| c.is_ascii_hexdigit()))
+        {
+            return Err(Error::explain(
+                ReadError,
+                format!("TLS fingerprint: {hash} is not a JA3 hash"),
+            ));
+        }
         if self.api.tls {
             if self.api.cert.is_none() {
                 return Err(Error::new_str("API: cert is required when tls is enabled"));
@@ -111,6 +384,54 @@ impl AppConfig {
                 ));
             }
         }
+        if let Some(leader) = &self.replication.leader {
+            let valid = leader
+                .parse::<http::Uri>()
+                .is_ok_and(|uri| uri.scheme().is_some() && uri.host().is_some());
+            if !valid {
+                return Err(Error::new_str(
+                    "Replication: leader must be a URL like http://host:port",
+                ));
+            }
+        }
+        if self.declarative.file.is_some() && self.replication.leader.is_some() {
+            return Err(Error::new_str(
+                "Declarative: a follower's configuration comes from its leader, not a file",
+            ));
+        }
+        if self.replication.client_cert.is_some() != self.replication.client_key.is_some() {
+            return Err(Error::new_str(
+                "Replication: client_cert and client_key must be set together",
+            ));
+        }
+        if let Some(self_addr) = &self.cluster.self_addr {
+            if !self.cluster.peers.contains(self_addr) {
+                return Err(Error::new_str(
+                    "Cluster: self_addr must be one of the peers",
+                ));
+            }
+        }
+        if let Some(peer) = self
+            .cluster
+            .peers
+            .iter()
+            .find(|peer| peer.parse::<std::net::SocketAddr>().is_err())
+        {
+            return Err(Error::explain(
+                ReadError,
+                format!("Cluster: peer {peer} is not an ip:port address"),
+            ));
+        }
+        if self
+            .freeze
+            .break_glass_tokens
+            .iter()
+            .any(|digest| digest.len() != 64 || hex::decode(digest).is_err())
+        {
+            return Err(Error::new_str(
+                "Freeze: break_glass_tokens must be SHA-256 digests in hex",
+            ));
+        }
         Ok(self)
     }
 }
@@ -123,15 +444,24 @@ impl Default for ProxyConfig {
             https_bind_addrs: vec!["0.0.0.0:4433".to_string()],
             origin_down_time: 10,
             connection_retry_limit: 1,
+            slow_request_threshold: None,
+            error_pages: ErrorPages::new(),
+            redact_headers: Vec::new(),
+            listener_labels: HashMap::new(),
+            retry_budget: None,
+            http1_only_bind_addrs: Vec::new(),
         }
     }
 }
 
 impl Default for CacheConfig {
-    /// The default maximum cache size is 100 MB.
+    /// The default maximum cache size is 100 MB, split into 16 shards by cache key.
     fn default() -> Self {
         CacheConfig {
             max_size: 100 * 1024 * 1024,
+            eviction_shards: 16,
+            shard_by: ShardBy::Key,
+            persist: None,
         }
     }
 }
@@ -146,6 +476,7 @@ impl Default for ApiConfig {
             key: None,
             mutual_tls: false,
             client_cert: None,
+            require_customers: false,
         }
     }
 }
@@ -184,8 +515,12 @@ mod tests {
                     https_bind_addrs: vec!["0.0.0.0:443".to_string()],
                     origin_down_time: 5,
                     connection_retry_limit: 2,
+                    ..Default::default()
+                },
+                cache: CacheConfig {
+                    max_size: 5000000,
+                    ..Default::default()
                 },
-                cache: CacheConfig { max_size: 5000000 },
                 api: ApiConfig {
                     bind_addr: "127.0.1.5:6000".to_string(),
                     tls: true,
@@ -193,11 +528,93 @@ mod tests {
                     key: Some("/path/to/api.key".to_string()),
                     mutual_tls: true,
                     client_cert: Some("/path/to/client.crt".to_string()),
-                }
+                    ..Default::default()
+                },
+                ..Default::default()
             }
         );
     }
 
+    #[test]
+    fn server_overrides() {
+        let yaml = r#"
+            threads: 2
+            pid_file: /run/pingora.pid
+            server:
+              threads: 8
+              daemon: true
+              grace_period_seconds: 10
+        "#;
+        let conf = AppConfig::from_yaml(yaml).unwrap();
+        let mut server_conf = ServerConf::from_yaml(yaml).unwrap();
+        let mut opt = Opt {
+            upgrade: false,
+            daemon: false,
+            nocapture: false,
+            test: false,
+            conf: None,
+        };
+        conf.server.apply(&mut server_conf, &mut opt);
+        assert_eq!(server_conf.threads, 8);
+        assert!(server_conf.daemon && opt.daemon);
+        assert_eq!(server_conf.grace_period_seconds, Some(10));
+        // Options not set in the `server` section are left alone.
+        assert_eq!(server_conf.pid_file, "/run/pingora.pid");
+        assert!(!opt.upgrade);
+    }
+
+    #[test]
+    fn quota_from_yaml() {
+        let yaml = r#"
+            quota:
+              window: 60
+              global:
+                max_bytes: 1000000
+              customers:
+                customer1:
+                  max_requests: 100
+        "#;
+        let conf = AppConfig::from_yaml(yaml).unwrap();
+        assert_eq!(conf.quota.window, 60);
+        assert_eq!(conf.quota.global.max_bytes, Some(1000000));
+        assert_eq!(conf.quota.global.max_requests, None);
+        assert_eq!(conf.quota.customers["customer1"].max_requests, Some(100));
+    }
+
+    #[test]
+    fn invalid_leader() {
+        let yaml = r#"
+            replication:
+              leader: 10.0.0.1
+        "#;
+        assert!(AppConfig::from_yaml(yaml).is_err());
+
+        let yaml = r#"
+            replication:
+              leader: http://10.0.0.1:5000
+              poll_interval: 2
+        "#;
+        let conf = AppConfig::from_yaml(yaml).unwrap();
+        assert_eq!(conf.replication.poll_interval, 2);
+    }
+
+    #[test]
+    fn invalid_cluster() {
+        let yaml = r#"
+            cluster:
+              peers: [10.0.0.1:8080, 10.0.0.2:8080]
+              self_addr: 10.0.0.3:8080
+        "#;
+        assert!(AppConfig::from_yaml(yaml).is_err());
+
+        let yaml = r#"
+            cluster:
+              peers: [10.0.0.1:8080, proxy2:8080]
+              self_addr: 10.0.0.1:8080
+        "#;
+        assert!(AppConfig::from_yaml(yaml).is_err());
+    }
+
     #[test]
     fn missing_cert() {
         let yaml = r#"
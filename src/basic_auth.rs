@@ -0,0 +1,208 @@
+//! HTTP Basic authentication for routes.
+//!
+//! Credentials are managed as named, htpasswd-style credential lists pushed through the Config
+//! API.  A route protected by basic auth references a credential list by name.
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine;
+use http::StatusCode;
+use log::{debug, warn};
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::tls::hash::{hash, MessageDigest};
+use pingora::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use subtle::ConstantTimeEq;
+
+/// An interface for adding and deleting credential lists.
+pub trait CredentialHolder: Send + Sync {
+    fn add_credential_list(&self, list: CredentialList);
+    fn delete_credential_list(&self, name: &str);
+}
+
+/// Basic auth settings for a route.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct BasicAuthConfig {
+    /// The realm presented to the client in the `WWW-Authenticate` challenge.
+    #[serde(default = "default_realm")]
+    pub realm: String,
+
+    /// The name of the credential list to authenticate against.
+    pub credential_list: String,
+}
+
+fn default_realm() -> String {
+    "granite".to_string()
+}
+
+/// A named list of credentials in htpasswd format (`user:hash`).
+/// Supported hash formats are bcrypt (`$2a$`, `$2b$`, `$2y$`) and SHA-1 (`{SHA}`).
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct CredentialList {
+    /// A name for the credential list.  Must be unique among all credential lists.
+    pub name: String,
+
+    /// The htpasswd entries (one `user:hash` pair per entry).
+    pub entries: Vec<String>,
+}
+
+/// A store of credential lists indexed by name.
+pub struct CredentialStore {
+    // Key: credential list name.  Value: user name to password hash.
+    inner: RwLock<HashMap<String, Arc<HashMap<String, String>>>>,
+}
+
+impl CredentialStore {
+    pub fn new() -> Self {
+        CredentialStore {
+            inner: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Check the credentials in the request's `Authorization` header against the named list.
+    /// Return `false` if the header is missing or malformed, the list doesn't exist, or the
+    /// credentials don't match.
+    pub async fn authenticate(&self, list_name: &str, req: &RequestHeader) -> bool {
+        let Some((user, password)) = parse_authorization(req) else {
+            return false;
+        };
+
+        let users = {
+            let inner = self.inner.read().unwrap();
+            let Some(users) = inner.get(list_name) else {
+                warn!("Credential list '{list_name}' not found");
+                return false;
+            };
+            users.clone()
+        };
+
+        let Some(stored_hash) = users.get(&user) else {
+            debug!("Unknown user '{user}' in credential list '{list_name}'");
+            return false;
+        };
+        verify_password(password, stored_hash.clone()).await
+    }
+}
+
+impl CredentialHolder for CredentialStore {
+    /// Add or replace a credential list.  Malformed entries are skipped.
+    fn add_credential_list(&self, list: CredentialList) {
+        let mut users = HashMap::new();
+        for entry in &list.entries {
+            match entry.split_once(':') {
+                Some((user, hash)) => {
+                    users.insert(user.to_string(), hash.to_string());
+                }
+                None => warn!(
+                    "Skipping malformed entry in credential list '{}'",
+                    list.name
+                ),
+            }
+        }
+
+        let mut inner = self.inner.write().unwrap();
+        inner.insert(list.name, Arc::new(users));
+    }
+
+    /// Delete a credential list (if it exists).
+    fn delete_credential_list(&self, name: &str) {
+        let mut inner = self.inner.write().unwrap();
+        if inner.remove(name).is_none() {
+            warn!("Attempted to delete a credential list that doesn't exist name={name}");
+        }
+    }
+}
+
+/// Build the 401 response that challenges the client to authenticate.
+pub fn challenge_response(config: &BasicAuthConfig) -> Result<ResponseHeader> {
+    let mut resp = ResponseHeader::build(StatusCode::UNAUTHORIZED, Some(2))?;
+    resp.insert_header(
+        http::header::WWW_AUTHENTICATE,
+        format!("Basic realm=\"{}\"", config.realm),
+    )?;
+    resp.insert_header(http::header::CONTENT_LENGTH, 0)?;
+    Ok(resp)
+}
+
+/// Extract the user name and password from a `Basic` `Authorization` header.
+fn parse_authorization(req: &RequestHeader) -> Option<(String, String)> {
+    let value = req
+        .headers
+        .get(http::header::AUTHORIZATION)?
+        .to_str()
+        .ok()?;
+    let (scheme, encoded) = value.trim().split_once(' ')?;
+    if !scheme.eq_ignore_ascii_case("basic") {
+        return None;
+    }
+    let decoded = BASE64.decode(encoded.trim()).ok()?;
+    let decoded = String::from_utf8(decoded).ok()?;
+    let (user, password) = decoded.split_once(':')?;
+    Some((user.to_string(), password.to_string()))
+}
+
+/// Verify a plaintext password against an htpasswd hash.  bcrypt is slow by design, so it runs on
+/// the blocking thread pool rather than stalling the other requests of the worker thread.
+async fn verify_password(password: String, stored_hash: String) -> bool {
+    if let Some(sha) = stored_hash.strip_prefix("{SHA}") {
+        let Ok(digest) = hash(MessageDigest::sha1(), password.as_bytes()) else {
+            return false;
+        };
+        return BASE64
+            .encode(digest)
+            .as_bytes()
+            .ct_eq(sha.as_bytes())
+            .into();
+    }
+    if stored_hash.starts_with("$2") {
+        return tokio::task::spawn_blocking(move || {
+            bcrypt::verify(password, &stored_hash).unwrap_or(false)
+        })
+        .await
+        .unwrap_or(false);
+    }
+    warn!("Unsupported password hash format");
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request_with_credentials(user: &str, password: &str) -> RequestHeader {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        let encoded = BASE64.encode(format!("{user}:{password}"));
+        req.insert_header("authorization", format!("Basic {encoded}"))
+            .unwrap();
+        req
+    }
+
+    async fn check(store: &CredentialStore, list: &str, user: &str, password: &str) -> bool {
+        store
+            .authenticate(list, &request_with_credentials(user, password))
+            .await
+    }
+
+    #[tokio::test]
+    async fn authenticate() {
+        let store = CredentialStore::new();
+        store.add_credential_list(CredentialList {
+            name: "staging".to_string(),
+            entries: vec![
+                // "password" hashed with `htpasswd -s`.
+                "alice:{SHA}W6ph5Mm5Pz8GgiULbPgzG37mj9g=".to_string(),
+                format!("bob:{}", bcrypt::hash("secret", 4).unwrap()),
+            ],
+        });
+
+        assert!(check(&store, "staging", "alice", "password").await);
+        assert!(check(&store, "staging", "bob", "secret").await);
+        assert!(!check(&store, "staging", "alice", "wrong").await);
+        assert!(!check(&store, "staging", "carol", "password").await);
+        assert!(!check(&store, "other", "alice", "password").await);
+
+        store.delete_credential_list("staging");
+        assert!(!check(&store, "staging", "alice", "password").await);
+    }
+}
@@ -0,0 +1,256 @@
+//! Response body rewriting.  A route can substitute text in the bodies of responses with selected
+//! content types, e.g., to rewrite the origin's absolute URLs to the public hostname.
+//!
+//! Bodies are rewritten as they stream from the origin, before they are cached, so cache hits serve
+//! the rewritten body.  To find matches that straddle chunks, the last `max_match` bytes of each
+//! chunk are held back until the next one arrives; a match must fit within that window.  Rewritten
+//! responses lose their `Content-Length` (they are sent chunked), and compressed or partial
+//! responses are left alone.
+
+use bytes::Bytes;
+use pingora::http::ResponseHeader;
+use regex::bytes::Regex;
+use serde::{Deserialize, Serialize};
+
+/// A body rewriting policy for a route.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct BodyRewritePolicy {
+    /// The content types (e.g., `text/html`) of the responses to rewrite.  A type ending in `/`
+    /// (e.g., `text/`) matches all of its subtypes.
+    #[serde(default = "default_content_types")]
+    pub content_types: Vec<String>,
+
+    /// The substitutions, applied in one pass (at each position, the first rule that matches
+    /// wins).
+    pub rules: Vec<RewriteRule>,
+
+    /// The longest match (in bytes) the rules can make.
+    #[serde(default = "default_max_match")]
+    pub max_match: usize,
+}
+
+fn default_content_types() -> Vec<String> {
+    vec![
+        "text/html".to_string(),
+        "text/css".to_string(),
+        "text/javascript".to_string(),
+        "application/javascript".to_string(),
+        "application/json".to_string(),
+    ]
+}
+
+fn default_max_match() -> usize {
+    1024
+}
+
+/// A substitution, as configured.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct RewriteRuleConfig {
+    /// The text to replace (a regular expression if `regex` is set).
+    pub find: String,
+
+    /// The replacement (which can refer to the regular expression's groups, e.g. `$1`).
+    pub replace: String,
+
+    /// Whether `find` is a regular expression.
+    #[serde(default)]
+    pub regex: bool,
+}
+
+/// A substitution, compiled when the route is parsed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(try_from = "RewriteRuleConfig", into = "RewriteRuleConfig")]
+pub struct RewriteRule {
+    config: RewriteRuleConfig,
+    find: Regex,
+}
+
+impl TryFrom<RewriteRuleConfig> for RewriteRule {
+    type Error = regex::Error;
+
+    fn try_from(config: RewriteRuleConfig) -> Result<Self, Self::Error> {
+        let find = if config.regex {
+            Regex::new(&config.find)?
+        } else {
+            Regex::new(&regex::escape(&config.find))?
+        };
+        Ok(RewriteRule { config, find })
+    }
+}
+
+impl From<RewriteRule> for RewriteRuleConfig {
+    fn from(rule: RewriteRule) -> Self {
+        rule.config
+    }
+}
+
+impl PartialEq for RewriteRule {
+    fn eq(&self, other: &Self) -> bool {
+        self.config == other.config
+    }
+}
+
+impl Eq for RewriteRule {}
+
+impl BodyRewritePolicy {
+    /// Whether the policy applies to a response from the origin.
+    pub fn applies_to(&self, resp: &ResponseHeader) -> bool {
+        if resp.status == http::StatusCode::PARTIAL_CONTENT
+            || resp.status == http::StatusCode::NOT_MODIFIED
+        {
+            return false;
+        }
+        let compressed = resp
+            .headers
+            .get(http::header::CONTENT_ENCODING)
+            .is_some_and(|encoding| encoding != "identity");
+        if compressed {
+            return false;
+        }
+        let Some(content_type) = resp
+            .headers
+            .get(http::header::CONTENT_TYPE)
+            .and_then(|v| v.to_str().ok())
+        else {
+            return false;
+        };
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        self.content_types.iter().any(|pattern| {
+            if pattern.ends_with('/') {
+                media_type
+                    .get(..pattern.len())
+                    .is_some_and(|prefix| prefix.eq_ignore_ascii_case(pattern))
+            } else {
+                media_type.eq_ignore_ascii_case(pattern)
+            }
+        })
+    }
+}
+
+/// Rewrites a response body as it streams.
+#[derive(Debug)]
+pub struct BodyRewriter {
+    rules: Vec<RewriteRule>,
+    max_match: usize,
+    /// The bytes held back from the previous chunks.
+    pending: Vec<u8>,
+}
+
+impl BodyRewriter {
+    pub fn new(policy: &BodyRewritePolicy) -> Self {
+        BodyRewriter {
+            rules: policy.rules.clone(),
+            max_match: policy.max_match,
+            pending: Vec::new(),
+        }
+    }
+
+    /// Rewrite the next chunk of the body, returning the rewritten bytes that are ready (`None` if
+    /// they are all held back).
+    pub fn rewrite(&mut self, chunk: Option<&[u8]>, end_of_stream: bool) -> Option<Bytes> {
+        if let Some(chunk) = chunk {
+            self.pending.extend_from_slice(chunk);
+        }
+        // Matches must start before the window (unless the body is complete); the rest of the
+        // bytes are held back.
+        let limit = if end_of_stream {
+            self.pending.len()
+        } else {
+            self.pending.len().saturating_sub(self.max_match)
+        };
+
+        let mut out = Vec::with_capacity(self.pending.len());
+        let mut pos = 0;
+        while pos < limit {
+            let found = self
+                .rules
+                .iter()
+                .filter_map(|rule| rule.find.captures_at(&self.pending, pos).map(|c| (rule, c)))
+                .filter(|(_, c)| c.get(0).is_some_and(|m| m.start() < limit))
+                .min_by_key(|(_, c)| c.get(0).map_or(usize::MAX, |m| m.start()));
+            let Some((rule, captures)) = found else {
+                break;
+            };
+            let m = captures.get(0).unwrap();
+            out.extend_from_slice(&self.pending[pos..m.start()]);
+            captures.expand(rule.config.replace.as_bytes(), &mut out);
+            // An empty match moves on by one byte so that the loop ends.
+            pos = if m.is_empty() {
+                if let Some(&b) = self.pending.get(m.end()) {
+                    out.push(b);
+                }
+                m.end() + 1
+            } else {
+                m.end()
+            };
+        }
+        let cut = pos.max(limit).min(self.pending.len());
+        out.extend_from_slice(&self.pending[pos.min(cut)..cut]);
+        self.pending.drain(..cut);
+
+        if out.is_empty() && !end_of_stream {
+            None
+        } else {
+            Some(Bytes::from(out))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy(rules: serde_json::Value, max_match: usize) -> BodyRewritePolicy {
+        serde_json::from_value(serde_json::json!({"rules": rules, "max_match": max_match})).unwrap()
+    }
+
+    #[test]
+    fn rewrite_across_chunks() {
+        let policy = policy(
+            serde_json::json!([
+                {"find": "http://origin.internal", "replace": "https://www.example.com"},
+                {"find": "v(\\d+)\\.js", "replace": "v$1.min.js", "regex": true}
+            ]),
+            32,
+        );
+        let body =
+            "<a href=\"http://origin.internal/a\">x</a><script src=\"/v12.js\"></script>".repeat(3);
+        let expected = body
+            .replace("http://origin.internal", "https://www.example.com")
+            .replace("v12.js", "v12.min.js");
+
+        for chunk_size in [1, 5, 17, 64, body.len()] {
+            let mut rewriter = BodyRewriter::new(&policy);
+            let mut out = Vec::new();
+            let chunks: Vec<_> = body.as_bytes().chunks(chunk_size).collect();
+            for (i, chunk) in chunks.iter().enumerate() {
+                let end = i + 1 == chunks.len();
+                if let Some(bytes) = rewriter.rewrite(Some(chunk), end) {
+                    out.extend_from_slice(&bytes);
+                }
+            }
+            assert_eq!(String::from_utf8(out).unwrap(), expected, "{chunk_size}");
+        }
+    }
+
+    #[test]
+    fn applies_to() {
+        let policy = policy(serde_json::json!([]), 16);
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header("content-type", "text/html; charset=utf-8")
+            .unwrap();
+        assert!(policy.applies_to(&resp));
+
+        resp.insert_header("content-encoding", "gzip").unwrap();
+        assert!(!policy.applies_to(&resp));
+
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header("content-type", "image/png").unwrap();
+        assert!(!policy.applies_to(&resp));
+
+        assert!(serde_json::from_value::<BodyRewritePolicy>(
+            serde_json::json!({"rules": [{"find": "(", "replace": "", "regex": true}]})
+        )
+        .is_err());
+    }
+}
@@ -0,0 +1,298 @@
+//! Bot and crawler handling.  Requests are classified by their `User-Agent`: search engine bots
+//! (verified by reverse DNS), other crawlers and automated clients, and clients that don't identify
+//! themselves.  A route's bot policy then allows, blocks, or throttles each class, or serves it
+//! only from the cache, so crawl traffic doesn't load the origins.
+//!
+//! A search bot is verified the way the search engines document: the client's address must point
+//! (PTR) to a hostname in the engine's domain, which must resolve back to the address.  Clients
+//! that claim to be a search bot but fail the check are treated as crawlers.  Verdicts are cached.
+
+use log::debug;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use crate::dns::DnsResolver;
+use crate::rate_limit::{RateLimitKey, RateLimitPeriod, RateLimitPolicy};
+
+/// Search engine bots (by a token in their `User-Agent`) and the domains their hosts are in.
+const SEARCH_BOTS: &[(&str, &[&str])] = &[
+    ("googlebot", &["googlebot.com", "google.com"]),
+    ("google-inspectiontool", &["googlebot.com", "google.com"]),
+    ("bingbot", &["search.msn.com"]),
+    ("duckduckbot", &["duckduckgo.com"]),
+    ("yandexbot", &["yandex.ru", "yandex.net", "yandex.com"]),
+    ("baiduspider", &["baidu.com", "baidu.jp"]),
+    ("applebot", &["applebot.apple.com"]),
+];
+
+/// Tokens in the `User-Agent` of crawlers and other automated clients.
+const CRAWLER_TOKENS: &[&str] = &[
+    "bot",
+    "crawl",
+    "spider",
+    "slurp",
+    "scrapy",
+    "curl/",
+    "wget/",
+    "python-requests",
+    "python-urllib",
+    "go-http-client",
+    "java/",
+    "okhttp",
+    "headlesschrome",
+    "phantomjs",
+];
+
+/// How long a search bot verification is trusted.
+const VERIFICATION_TTL: Duration = Duration::from_secs(3600);
+
+/// When the number of cached verifications exceeds this, expired ones are pruned.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// The kind of client a request comes from.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum BotClass {
+    /// A search engine bot (verified, unless verification is turned off).
+    SearchBot,
+
+    /// Another crawler or automated client.
+    Crawler,
+
+    /// A client without a `User-Agent`.
+    Unknown,
+
+    /// Anything else (e.g., a browser).
+    Human,
+}
+
+impl BotClass {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            BotClass::SearchBot => "search-bot",
+            BotClass::Crawler => "crawler",
+            BotClass::Unknown => "unknown",
+            BotClass::Human => "human",
+        }
+    }
+}
+
+/// What happens to the requests of a class of bots.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+pub enum BotAction {
+    /// Handle them like any other request.
+    #[default]
+    Allow,
+
+    /// Reject them with a 403.
+    Block,
+
+    /// Limit the rate of the requests of all the class's clients together.  Requests over the limit
+    /// are rejected with a 429.
+    Throttle {
+        requests: u32,
+        #[serde(default)]
+        period: RateLimitPeriod,
+    },
+
+    /// Serve them from the cache only.  Cache misses are answered with a 503 instead of going to
+    /// the origin.
+    CachedOnly,
+}
+
+/// A route's bot policy.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct BotPolicy {
+    pub search_bots: BotAction,
+    pub crawlers: BotAction,
+    pub unknown: BotAction,
+
+    /// Whether to verify search bots by reverse DNS.  If not, any client claiming to be one is
+    /// treated as one.
+    pub verify_search_bots: bool,
+}
+
+impl Default for BotPolicy {
+    /// By default, every class is allowed and search bots are verified.
+    fn default() -> Self {
+        BotPolicy {
+            search_bots: BotAction::Allow,
+            crawlers: BotAction::Allow,
+            unknown: BotAction::Allow,
+            verify_search_bots: true,
+        }
+    }
+}
+
+impl BotPolicy {
+    /// The action for a class of clients (humans are always allowed).
+    pub fn action(&self, class: BotClass) -> &BotAction {
+        match class {
+            BotClass::SearchBot => &self.search_bots,
+            BotClass::Crawler => &self.crawlers,
+            BotClass::Unknown => &self.unknown,
+            BotClass::Human => &BotAction::Allow,
+        }
+    }
+
+    /// Whether a verification would make a difference to the action taken.
+    fn needs_verification(&self) -> bool {
+        self.verify_search_bots && self.search_bots != self.crawlers
+    }
+}
+
+impl BotAction {
+    /// The rate limit of a throttled class.
+    pub fn rate_limit(&self) -> Option<RateLimitPolicy> {
+        match self {
+            BotAction::Throttle { requests, period } => Some(RateLimitPolicy {
+                requests: *requests,
+                period: period.clone(),
+                burst: 0,
+                key: RateLimitKey::ClientIp,
+            }),
+            _ => None,
+        }
+    }
+}
+
+/// Classify a client by its `User-Agent` alone.  Return the domains to verify a search bot with.
+pub fn classify_user_agent(user_agent: Option<&str>) -> (BotClass, &'static [&'static str]) {
+    let Some(user_agent) = user_agent.filter(|ua| !ua.trim().is_empty()) else {
+        return (BotClass::Unknown, &[]);
+    };
+    let user_agent = user_agent.to_ascii_lowercase();
+    if let Some((_, domains)) = SEARCH_BOTS
+        .iter()
+        .find(|(token, _)| user_agent.contains(token))
+    {
+        return (BotClass::SearchBot, domains);
+    }
+    if CRAWLER_TOKENS
+        .iter()
+        .any(|token| user_agent.contains(token))
+    {
+        return (BotClass::Crawler, &[]);
+    }
+    (BotClass::Human, &[])
+}
+
+/// Classifies clients, verifying search bots.
+pub struct BotClassifier {
+    /// Verification verdicts (and when they were reached) by client address.
+    verified: Mutex<HashMap<IpAddr, (bool, Instant)>>,
+}
+
+impl BotClassifier {
+    pub fn new() -> Self {
+        BotClassifier {
+            verified: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Classify a client for a route's policy.
+    pub async fn classify(
+        &self,
+        policy: &BotPolicy,
+        user_agent: Option<&str>,
+        client: Option<IpAddr>,
+        resolver: &DnsResolver,
+    ) -> BotClass {
+        let (class, domains) = classify_user_agent(user_agent);
+        if class != BotClass::SearchBot || !policy.needs_verification() {
+            return class;
+        }
+        let Some(ip) = client else {
+            return BotClass::Crawler;
+        };
+        if self.verify(ip, domains, resolver).await {
+            BotClass::SearchBot
+        } else {
+            BotClass::Crawler
+        }
+    }
+
+    /// Check that the address points to a host in one of the domains, which points back to it.
+    async fn verify(&self, ip: IpAddr, domains: &[&str], resolver: &DnsResolver) -> bool {
+        let now = Instant::now();
+        if let Some((verdict, at)) = self.verified.lock().unwrap().get(&ip) {
+            if now.duration_since(*at) < VERIFICATION_TTL {
+                return *verdict;
+            }
+        }
+
+        let mut verdict = false;
+        for host in resolver.reverse_lookup(ip).await.unwrap_or_default() {
+            let host = host.to_ascii_lowercase();
+            let in_domain = domains.iter().any(|domain| {
+                host.strip_suffix(domain)
+                    .is_some_and(|prefix| prefix.ends_with('.'))
+            });
+            if in_domain
+                && resolver
+                    .resolve_all(&host)
+                    .await
+                    .unwrap_or_default()
+                    .contains(&ip)
+            {
+                verdict = true;
+                break;
+            }
+        }
+        debug!("Search bot verification for {ip}: {verdict}");
+
+        let mut verified = self.verified.lock().unwrap();
+        if verified.len() > PRUNE_THRESHOLD {
+            verified.retain(|_, (_, at)| now.duration_since(*at) < VERIFICATION_TTL);
+        }
+        verified.insert(ip, (verdict, now));
+        verdict
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn classify() {
+        let class = |ua| classify_user_agent(ua).0;
+        assert_eq!(
+            class(Some(
+                "Mozilla/5.0 (compatible; Googlebot/2.1; +http://www.google.com/bot.html)"
+            )),
+            BotClass::SearchBot
+        );
+        assert_eq!(
+            classify_user_agent(Some("bingbot/2.0")).1,
+            ["search.msn.com"]
+        );
+        assert_eq!(class(Some("AhrefsBot/7.0")), BotClass::Crawler);
+        assert_eq!(class(Some("curl/8.5.0")), BotClass::Crawler);
+        assert_eq!(class(Some("")), BotClass::Unknown);
+        assert_eq!(class(None), BotClass::Unknown);
+        assert_eq!(
+            class(Some("Mozilla/5.0 (X11; Linux x86_64) Firefox/125.0")),
+            BotClass::Human
+        );
+
+        let policy: BotPolicy = serde_json::from_str(
+            r#"{"crawlers": {"Throttle": {"requests": 10, "period": "Minute"}}, "unknown": "Block"}"#,
+        )
+        .unwrap();
+        assert_eq!(policy.action(BotClass::SearchBot), &BotAction::Allow);
+        assert_eq!(policy.action(BotClass::Unknown), &BotAction::Block);
+        assert_eq!(
+            policy
+                .action(BotClass::Crawler)
+                .rate_limit()
+                .unwrap()
+                .requests,
+            10
+        );
+        assert!(policy.needs_verification());
+    }
+}
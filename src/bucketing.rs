@@ -0,0 +1,175 @@
+//! Per-route request bucketing (e.g., for A/B tests).
+//!
+//! A route with a bucketing policy hashes a key identifying the client (its IP address, a cookie,
+//! or a request header) into one of the policy's weighted buckets, so a client stays in the same
+//! bucket across requests and instances.  The bucket's name is passed to the origin in a request
+//! header, a bucket can send its requests to an origin of its own, and each bucket gets its own
+//! cache entries.
+
+use pingora::http::RequestHeader;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::net::IpAddr;
+
+/// What identifies a client for bucketing purposes.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+pub enum BucketKey {
+    /// The client's IP address.
+    #[default]
+    ClientIp,
+
+    /// The value of the given cookie (e.g., a visitor ID).  Requests without the cookie fall back
+    /// to the client's IP address.
+    Cookie(String),
+
+    /// The value of the given request header (e.g., a user ID).  Requests without the header fall
+    /// back to the client's IP address.
+    Header(String),
+}
+
+/// A bucket requests can be assigned to.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct Bucket {
+    /// The bucket's name (passed to the origin).
+    pub name: String,
+
+    /// The bucket's share of the clients, relative to the other buckets' weights.
+    #[serde(default = "default_weight")]
+    pub weight: u32,
+
+    /// The host of the origin (in the route's origin group) that serves the bucket's requests.  By
+    /// default, the origin is selected as usual.
+    pub origin: Option<String>,
+}
+
+fn default_weight() -> u32 {
+    1
+}
+
+/// A bucketing policy for a route.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct BucketingPolicy {
+    /// What identifies a client.
+    #[serde(default)]
+    pub key: BucketKey,
+
+    /// The buckets.
+    pub buckets: Vec<Bucket>,
+
+    /// Mixed into the hash of the key, so that experiments using different salts bucket clients
+    /// independently.
+    #[serde(default)]
+    pub salt: String,
+
+    /// The request header that passes the bucket's name to the origin (replacing any sent by the
+    /// client).
+    #[serde(default = "default_header")]
+    pub header: String,
+}
+
+fn default_header() -> String {
+    "x-bucket".to_string()
+}
+
+impl BucketingPolicy {
+    /// The key identifying the client of a request.
+    pub fn client_key(&self, req: &RequestHeader, client_ip: Option<IpAddr>) -> String {
+        let key = match &self.key {
+            BucketKey::ClientIp => None,
+            BucketKey::Cookie(name) => req
+                .headers
+                .get_all(http::header::COOKIE)
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .flat_map(|v| v.split(';'))
+                .filter_map(|c| c.trim().split_once('='))
+                .find(|(n, _)| n.trim() == name)
+                .map(|(_, v)| v.trim()),
+            BucketKey::Header(name) => req.headers.get(name.as_str()).and_then(|v| v.to_str().ok()),
+        };
+        match key {
+            Some(key) => key.to_string(),
+            None => client_ip.map_or_else(String::new, |ip| ip.to_string()),
+        }
+    }
+
+    /// The bucket for a client key (`None` if the buckets have no weight).
+    pub fn select(&self, key: &str) -> Option<&Bucket> {
+        let total: u64 = self.buckets.iter().map(|b| u64::from(b.weight)).sum();
+        if total == 0 {
+            return None;
+        }
+        let mut hasher = Sha256::new();
+        hasher.update(self.salt.as_bytes());
+        hasher.update([0]);
+        hasher.update(key.as_bytes());
+        let hash = hasher.finalize();
+        let mut point = u64::from_be_bytes(hash[..8].try_into().unwrap()) % total;
+        self.buckets.iter().find(|bucket| {
+            let weight = u64::from(bucket.weight);
+            if point < weight {
+                return true;
+            }
+            point -= weight;
+            false
+        })
+    }
+
+    /// The bucket with the name.
+    pub fn bucket(&self, name: &str) -> Option<&Bucket> {
+        self.buckets.iter().find(|bucket| bucket.name == name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn select() {
+        let policy: BucketingPolicy = serde_json::from_str(
+            r#"{
+                "key": {"Cookie": "uid"},
+                "buckets": [{"name": "a", "weight": 9}, {"name": "b", "origin": "beta.example.com"}],
+                "salt": "exp-1"
+            }"#,
+        )
+        .unwrap();
+        assert_eq!(policy.header, "x-bucket");
+
+        let mut counts = [0; 2];
+        for i in 0..10_000 {
+            let key = format!("client-{i}");
+            let bucket = policy.select(&key).unwrap();
+            assert_eq!(policy.select(&key), Some(bucket));
+            counts[usize::from(bucket.name == "b")] += 1;
+        }
+        assert!((8_700..9_300).contains(&counts[0]), "{counts:?}");
+
+        let other = BucketingPolicy {
+            salt: "exp-2".to_string(),
+            ..policy.clone()
+        };
+        let moved = (0..1_000)
+            .map(|i| format!("client-{i}"))
+            .filter(|key| policy.select(key) != other.select(key))
+            .count();
+        assert!(moved > 50, "{moved}");
+
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.append_header("cookie", "lang=en; uid=42").unwrap();
+        let ip = Some("192.0.2.1".parse().unwrap());
+        assert_eq!(policy.client_key(&req, ip), "42");
+        let by_ip = BucketingPolicy {
+            key: BucketKey::Header("x-user".to_string()),
+            ..policy.clone()
+        };
+        assert_eq!(by_ip.client_key(&req, ip), "192.0.2.1");
+
+        let empty = BucketingPolicy {
+            buckets: vec![],
+            ..policy
+        };
+        assert_eq!(empty.select("client"), None);
+    }
+}
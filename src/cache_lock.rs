@@ -0,0 +1,106 @@
+//! Tuning of the cache lock, which protects origins from cache stampedes: when many requests miss
+//! the same cache entry (or find it expired), only one of them goes to the origin while the others
+//! wait for it to fill the entry.
+//!
+//! If the entry isn't filled within the lock timeout (e.g., because the origin is slow), the
+//! waiting requests give up.  By default they all go to the origin, turning the stampede into
+//! origin load; a route's cache lock policy can instead have them served the stale entry (if the
+//! cache still has one) or sent a `504 Gateway Timeout`.
+//!
+//! Pingora's cache lock has a fixed timeout, so there's a lock for each distinct timeout.  Requests
+//! using different locks don't wait for each other, which only matters for routes with different
+//! timeouts that serve the same cache entries.
+
+use once_cell::sync::Lazy;
+use pingora::cache::lock::CacheLock;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::Duration;
+
+/// The cache locks, by timeout (in milliseconds).  They're never dropped, but there are only as
+/// many as there are distinct timeouts in the route configuration.
+static LOCKS: Lazy<Mutex<HashMap<u64, &'static CacheLock>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// A route's cache lock policy.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct CacheLockPolicy {
+    /// The time (in milliseconds) requests wait for another request to fill a cache entry.
+    pub timeout: u64,
+
+    /// What requests do when they give up waiting.
+    pub on_timeout: LockTimeoutAction,
+}
+
+impl Default for CacheLockPolicy {
+    /// By default, requests wait up to 2 seconds, then go to the origin.
+    fn default() -> Self {
+        CacheLockPolicy {
+            timeout: 2000,
+            on_timeout: LockTimeoutAction::Origin,
+        }
+    }
+}
+
+/// What a request does when it gives up waiting for a cache lock.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum LockTimeoutAction {
+    /// Go to the origin (without caching the response).
+    #[default]
+    Origin,
+
+    /// Serve the stale cache entry, if there is one, else go to the origin.
+    Stale,
+
+    /// Respond with a 504.
+    Error,
+}
+
+impl LockTimeoutAction {
+    /// The action's metric label.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            LockTimeoutAction::Origin => "origin",
+            LockTimeoutAction::Stale => "stale",
+            LockTimeoutAction::Error => "error",
+        }
+    }
+}
+
+impl CacheLockPolicy {
+    /// The cache lock with the policy's timeout.
+    pub fn lock(&self) -> &'static CacheLock {
+        LOCKS
+            .lock()
+            .unwrap()
+            .entry(self.timeout)
+            .or_insert_with(|| {
+                Box::leak(Box::new(CacheLock::new(Duration::from_millis(
+                    self.timeout,
+                ))))
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn lock() {
+        let policy: CacheLockPolicy = serde_json::from_str(r#"{"on_timeout": "Stale"}"#).unwrap();
+        assert_eq!(policy.timeout, 2000);
+        assert_eq!(policy.on_timeout, LockTimeoutAction::Stale);
+        assert!(std::ptr::eq(
+            policy.lock(),
+            CacheLockPolicy::default().lock()
+        ));
+        let shorter = CacheLockPolicy {
+            timeout: 500,
+            ..Default::default()
+        };
+        assert!(!std::ptr::eq(shorter.lock(), policy.lock()));
+    }
+}
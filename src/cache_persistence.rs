@@ -0,0 +1,600 @@
+//! Persistence of the (in-memory) cache across restarts, so a deploy doesn't reset the hit ratio
+//! to zero.
+//!
+//! The cache entries are written to a spill file at shutdown (and optionally every interval), and
+//! loaded back into the cache at startup.  The most frequently looked up entries are written
+//! first, so if the file's size is limited, it holds the hottest entries.  Only fresh entries are
+//! written and loaded; entries still being written by a request are skipped.
+//!
+//! During a graceful upgrade, the new instance starts before the old one shuts down, so it loads
+//! the last file written by the old instance's interval (if any).
+//!
+//! The file is a sequence of length-prefixed fields: a header, then, for each entry, its cache
+//! key, metadata, and body.  Entries are written one at a time, as they're read from the cache.
+//!
+//! The storage keeps the keys of its complete entries: a key is added when its entry is complete
+//! (or refreshed), and removed when the entry is purged or evicted (the eviction manager's evicted
+//! entries are purged), or found gone or expired.
+
+use async_trait::async_trait;
+use bytes::Bytes;
+use log::{error, info, warn};
+use pingora::cache::key::{CacheHashKey, CompactCacheKey, HashBinary};
+use pingora::cache::storage::HandleMiss;
+use pingora::cache::trace::{Span, SpanHandle};
+use pingora::cache::{
+    eviction::EvictionManager, CacheKey, CacheMeta, HitHandler, MemCache, MissHandler, Storage,
+};
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use pingora::Result;
+use serde::{Deserialize, Serialize};
+use std::any::Any;
+use std::cmp::Reverse;
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+use crate::cache_stats::{HotKeyTracker, RouteEvictionManager};
+
+/// The start of a spill file (including the format's version).
+const HEADER: &[u8] = b"granite-cache\x01";
+
+/// Cache persistence settings.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct CachePersistConfig {
+    /// The spill file the cache is written to and loaded from.
+    pub file: String,
+
+    /// How often (in seconds) to write the file, besides at shutdown.  If not set, it's only
+    /// written at shutdown.
+    #[serde(default)]
+    pub interval: Option<u64>,
+
+    /// The maximum size (in bytes) of the bodies written to the file.  If not set, the whole cache
+    /// is written.
+    #[serde(default)]
+    pub max_size: Option<usize>,
+}
+
+/// The keys of stored entries by their hash (as stored).
+type Keys = Arc<Mutex<HashMap<String, CacheKey>>>;
+
+/// The in-memory cache storage, which also keeps the keys of its entries so they can be written
+/// to the spill file.
+pub struct TrackedStorage {
+    inner: MemCache,
+    keys: Keys,
+}
+
+impl TrackedStorage {
+    pub fn new() -> Self {
+        TrackedStorage {
+            inner: MemCache::new(),
+            keys: Arc::default(),
+        }
+    }
+
+    /// The keys of the stored entries.
+    fn keys(&self) -> Vec<CacheKey> {
+        self.keys.lock().unwrap().values().cloned().collect()
+    }
+
+    /// Stop keeping the key of an entry that's gone or expired.
+    fn forget(&self, key: &CacheKey) {
+        self.keys.lock().unwrap().remove(&key.combined());
+    }
+}
+
+/// Keeps the key of an entry once it's complete.
+struct TrackedMissHandler {
+    inner: MissHandler,
+    key: CacheKey,
+    keys: Keys,
+}
+
+#[async_trait]
+impl HandleMiss for TrackedMissHandler {
+    async fn write_body(&mut self, data: Bytes, eof: bool) -> Result<()> {
+        self.inner.write_body(data, eof).await
+    }
+
+    async fn finish(self: Box<Self>) -> Result<usize> {
+        let size = self.inner.finish().await?;
+        self.keys
+            .lock()
+            .unwrap()
+            .insert(self.key.combined(), self.key);
+        Ok(size)
+    }
+}
+
+impl Default for TrackedStorage {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[async_trait]
+impl Storage for TrackedStorage {
+    async fn lookup(
+        &'static self,
+        key: &CacheKey,
+        trace: &SpanHandle,
+    ) -> Result<Option<(CacheMeta, HitHandler)>> {
+        let found = self.inner.lookup(key, trace).await?;
+        if found.is_none() {
+            self.forget(key);
+        }
+        Ok(found)
+    }
+
+    async fn get_miss_handler(
+        &'static self,
+        key: &CacheKey,
+        meta: &CacheMeta,
+        trace: &SpanHandle,
+    ) -> Result<MissHandler> {
+        let inner = self.inner.get_miss_handler(key, meta, trace).await?;
+        Ok(Box::new(TrackedMissHandler {
+            inner,
+            key: key.clone(),
+            keys: self.keys.clone(),
+        }))
+    }
+
+    async fn purge(&'static self, key: &CompactCacheKey, trace: &SpanHandle) -> Result<bool> {
+        self.keys.lock().unwrap().remove(&key.combined());
+        self.inner.purge(key, trace).await
+    }
+
+    async fn update_meta(
+        &'static self,
+        key: &CacheKey,
+        meta: &CacheMeta,
+        trace: &SpanHandle,
+    ) -> Result<bool> {
+        let updated = self.inner.update_meta(key, meta, trace).await?;
+        if updated {
+            self.keys
+                .lock()
+                .unwrap()
+                .insert(key.combined(), key.clone());
+        }
+        Ok(updated)
+    }
+
+    fn support_streaming_partial_write(&self) -> bool {
+        self.inner.support_streaming_partial_write()
+    }
+
+    fn as_any(&self) -> &(dyn Any + Send + Sync + 'static) {
+        self
+    }
+}
+
+/// A cache entry, as written to the spill file.
+#[derive(Debug, PartialEq, Eq)]
+struct SpilledEntry {
+    namespace: String,
+    primary: String,
+    user_tag: String,
+    variance: Option<HashBinary>,
+    /// The serialized metadata (internal and header parts).
+    meta: (Vec<u8>, Vec<u8>),
+    body: Bytes,
+}
+
+/// Writes the cache to the spill file and loads it back.
+pub struct CachePersister {
+    config: CachePersistConfig,
+    storage: &'static TrackedStorage,
+    eviction: &'static RouteEvictionManager,
+    hot_keys: &'static HotKeyTracker,
+}
+
+impl CachePersister {
+    pub fn new(
+        config: &CachePersistConfig,
+        storage: &'static TrackedStorage,
+        eviction: &'static RouteEvictionManager,
+        hot_keys: &'static HotKeyTracker,
+    ) -> Self {
+        CachePersister {
+            config: config.clone(),
+            storage,
+            eviction,
+            hot_keys,
+        }
+    }
+
+    /// Write the fresh cache entries (hottest first, up to the maximum size) to the spill file.
+    /// Return the number of entries written.
+    pub async fn save(&self) -> io::Result<usize> {
+        let span = Span::inactive();
+        let now = SystemTime::now();
+        let mut keys = self.storage.keys();
+        keys.sort_by_cached_key(|key| {
+            Reverse(self.hot_keys.lookups(&key.user_tag, key.primary_key()))
+        });
+
+        // The entries are handed to the writer one at a time, so the cache isn't copied in memory.
+        let (sender, mut receiver) = tokio::sync::mpsc::channel(1);
+        let path = self.config.file.clone();
+        let writer = tokio::task::spawn_blocking(move || {
+            write_file(&path, std::iter::from_fn(|| receiver.blocking_recv()))
+        });
+        let mut size = 0;
+        for key in keys {
+            let remaining = self.config.max_size.map(|max_size| max_size - size);
+            if remaining == Some(0) {
+                break;
+            }
+            let Ok(Some((meta, mut hit))) = self.storage.lookup(&key, &span.handle()).await else {
+                continue;
+            };
+            if !meta.is_fresh(now) {
+                self.storage.forget(&key);
+                continue;
+            }
+            // Entries still being written can't seek.
+            if !hit.can_seek() {
+                continue;
+            }
+            let Some(body) = read_body(&mut hit, remaining).await else {
+                continue;
+            };
+            let Ok(meta) = meta.serialize() else {
+                continue;
+            };
+            size += body.len();
+            let entry = SpilledEntry {
+                namespace: key.namespace().to_string(),
+                primary: key.primary_key().to_string(),
+                user_tag: key.user_tag.clone(),
+                variance: key.get_variance_key().copied(),
+                meta,
+                body,
+            };
+            // The writer only stops early if it failed, which it reports.
+            if sender.send(entry).await.is_err() {
+                break;
+            }
+        }
+        drop(sender);
+        writer.await.map_err(io::Error::other)?
+    }
+
+    /// Load the fresh entries of the spill file (if it exists) into the cache.  Entries that were
+    /// cached in the meantime are kept.  Return the number of entries loaded.
+    pub async fn load(&self) -> io::Result<usize> {
+        let path = self.config.file.clone();
+        let entries = match tokio::task::spawn_blocking(move || read_file(&path))
+            .await
+            .map_err(io::Error::other)?
+        {
+            Ok(entries) => entries,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(e) => return Err(e),
+        };
+
+        let span = Span::inactive();
+        let now = SystemTime::now();
+        let mut count = 0;
+        for entry in entries {
+            let Ok(meta) = CacheMeta::deserialize(&entry.meta.0, &entry.meta.1) else {
+                continue;
+            };
+            if !meta.is_fresh(now) {
+                continue;
+            }
+            let mut key = CacheKey::new(entry.namespace, entry.primary, entry.user_tag);
+            if let Some(variance) = entry.variance {
+                key.set_variance_key(variance);
+            }
+            if let Ok(Some(_)) = self.storage.lookup(&key, &span.handle()).await {
+                continue;
+            }
+            let Ok(size) = self.insert(&key, &meta, entry.body, &span.handle()).await else {
+                continue;
+            };
+            let evicted = self
+                .eviction
+                .admit(key.to_compact(), size, meta.fresh_until());
+            for evicted in evicted {
+                let _ = self.storage.purge(&evicted, &span.handle()).await;
+            }
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    /// Store an entry, and return its size.
+    async fn insert(
+        &self,
+        key: &CacheKey,
+        meta: &CacheMeta,
+        body: Bytes,
+        trace: &SpanHandle,
+    ) -> Result<usize> {
+        let mut miss = self.storage.get_miss_handler(key, meta, trace).await?;
+        miss.write_body(body, true).await?;
+        miss.finish().await
+    }
+
+    /// Write the spill file, and log the outcome.
+    async fn persist(&self) {
+        match self.save().await {
+            Ok(count) => info!("Wrote {count} cache entries to {}", self.config.file),
+            Err(e) => error!("Unable to write cache file {}: {e}", self.config.file),
+        }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for CachePersister {
+    /// Load the spill file, then write it every interval (if set), and once more at shutdown.
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        match self.load().await {
+            Ok(count) => info!("Loaded {count} cache entries from {}", self.config.file),
+            Err(e) => warn!("Unable to load cache file {}: {e}", self.config.file),
+        }
+        loop {
+            let interval = async {
+                match self.config.interval {
+                    Some(interval) => {
+                        tokio::time::sleep(Duration::from_secs(interval.max(1))).await
+                    }
+                    None => std::future::pending().await,
+                }
+            };
+            tokio::select! {
+                _ = interval => self.persist().await,
+                _ = shutdown.changed() => break,
+            }
+        }
+        self.persist().await;
+    }
+}
+
+/// Read the body of an entry, or `None` if it's larger than the maximum size (if any) or can't be
+/// read.  The memory cache hands out its body without copying it.
+async fn read_body(hit: &mut HitHandler, max_size: Option<usize>) -> Option<Bytes> {
+    let mut chunks = Vec::new();
+    let mut len = 0;
+    loop {
+        match hit.read_body().await {
+            Ok(Some(chunk)) => {
+                len += chunk.len();
+                if max_size.is_some_and(|max_size| len > max_size) {
+                    return None;
+                }
+                chunks.push(chunk);
+            }
+            Ok(None) => break,
+            Err(_) => return None,
+        }
+    }
+    match chunks.len() {
+        0 => Some(Bytes::new()),
+        1 => chunks.pop(),
+        _ => Some(chunks.concat().into()),
+    }
+}
+
+/// Write the entries to the file (through a temporary file, so a crash never leaves a partial
+/// one).  Return the number of entries written.
+fn write_file(path: &str, entries: impl Iterator<Item = SpilledEntry>) -> io::Result<usize> {
+    let temp = format!("{path}.tmp");
+    let mut writer = BufWriter::new(File::create(&temp)?);
+    writer.write_all(HEADER)?;
+    let mut count = 0;
+    for entry in entries {
+        write_field(&mut writer, entry.namespace.as_bytes())?;
+        write_field(&mut writer, entry.primary.as_bytes())?;
+        write_field(&mut writer, entry.user_tag.as_bytes())?;
+        write_field(&mut writer, entry.variance.as_ref().map_or(&[], |v| &v[..]))?;
+        write_field(&mut writer, &entry.meta.0)?;
+        write_field(&mut writer, &entry.meta.1)?;
+        write_field(&mut writer, &entry.body)?;
+        count += 1;
+    }
+    writer.into_inner()?.sync_all()?;
+    fs::rename(&temp, path)?;
+    Ok(count)
+}
+
+/// Read the entries of the file.
+fn read_file(path: &str) -> io::Result<Vec<SpilledEntry>> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut header = [0; HEADER.len()];
+    reader.read_exact(&mut header)?;
+    if header != HEADER {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Not a cache file (or an unsupported version)",
+        ));
+    }
+    let mut entries = Vec::new();
+    while let Some(namespace) = read_field(&mut reader)? {
+        let mut next = || {
+            read_field(&mut reader)?.ok_or_else(|| io::Error::from(io::ErrorKind::UnexpectedEof))
+        };
+        let string = |bytes: Vec<u8>| {
+            String::from_utf8(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+        };
+        let primary = string(next()?)?;
+        let user_tag = string(next()?)?;
+        let variance = next()?;
+        entries.push(SpilledEntry {
+            namespace: string(namespace)?,
+            primary,
+            user_tag,
+            variance: variance.try_into().ok(),
+            meta: (next()?, next()?),
+            body: next()?.into(),
+        });
+    }
+    Ok(entries)
+}
+
+fn write_field(writer: &mut impl Write, field: &[u8]) -> io::Result<()> {
+    writer.write_all(&(field.len() as u64).to_le_bytes())?;
+    writer.write_all(field)
+}
+
+/// Read a field, or `None` at the end of the file.
+fn read_field(reader: &mut impl Read) -> io::Result<Option<Vec<u8>>> {
+    let mut len = [0; 8];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(e) => return Err(e),
+    }
+    let len = u64::from_le_bytes(len);
+    let mut field = Vec::new();
+    reader.take(len).read_to_end(&mut field)?;
+    if field.len() as u64 != len {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(Some(field))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cache_stats::ShardBy;
+    use pingora::http::ResponseHeader;
+
+    fn leak<T>(value: T) -> &'static T {
+        Box::leak(Box::new(value))
+    }
+
+    #[tokio::test]
+    async fn save_and_load() {
+        let path = std::env::temp_dir().join(format!("granite-cache-{}", rand::random::<u64>()));
+        let config = CachePersistConfig {
+            file: path.to_string_lossy().to_string(),
+            interval: None,
+            max_size: Some(10),
+        };
+        let hot_keys = leak(HotKeyTracker::default());
+        let persister = || {
+            CachePersister::new(
+                &config,
+                leak(TrackedStorage::new()),
+                leak(RouteEvictionManager::new(1000, 1, ShardBy::Key)),
+                hot_keys,
+            )
+        };
+        let now = SystemTime::now();
+        let meta = CacheMeta::new(
+            now + Duration::from_secs(60),
+            now,
+            0,
+            0,
+            ResponseHeader::build(200, None).unwrap(),
+        );
+        let span = Span::inactive();
+
+        // Two entries fit in the file: the hot one is kept.
+        let before = persister();
+        let cold = CacheKey::new("", "/cold", "r1");
+        let hot = CacheKey::new("", "/hot", "r1");
+        for (key, body) in [(&cold, "cold body"), (&hot, "hot body")] {
+            let body = Bytes::from_static(body.as_bytes());
+            before
+                .insert(key, &meta, body, &span.handle())
+                .await
+                .unwrap();
+        }
+        hot_keys.record("r1", "/hot", true);
+        assert_eq!(before.save().await.unwrap(), 1);
+
+        let after = persister();
+        assert_eq!(after.load().await.unwrap(), 1);
+        assert!(after
+            .storage
+            .lookup(&cold, &span.handle())
+            .await
+            .unwrap()
+            .is_none());
+        let (meta, mut hit) = after
+            .storage
+            .lookup(&hot, &span.handle())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(meta.is_fresh(now));
+        assert_eq!(&hit.read_body().await.unwrap().unwrap()[..], b"hot body");
+        assert_eq!(after.eviction.route_stats()["r1"].items, 1);
+
+        fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn tracked_keys() {
+        let storage = leak(TrackedStorage::new());
+        let span = Span::inactive();
+        let now = SystemTime::now();
+        let meta = |fresh_until| {
+            CacheMeta::new(
+                fresh_until,
+                now,
+                0,
+                0,
+                ResponseHeader::build(200, None).unwrap(),
+            )
+        };
+        let write = |key: CacheKey, meta: CacheMeta, eof| {
+            let span = &span;
+            async move {
+                let mut miss = storage
+                    .get_miss_handler(&key, &meta, &span.handle())
+                    .await
+                    .unwrap();
+                miss.write_body(Bytes::from_static(b"body"), eof)
+                    .await
+                    .unwrap();
+                if eof {
+                    miss.finish().await.unwrap();
+                }
+            }
+        };
+
+        // Only complete entries are kept.
+        let fresh = CacheKey::new("", "/fresh", "r1");
+        let partial = CacheKey::new("", "/partial", "r1");
+        write(fresh.clone(), meta(now + Duration::from_secs(60)), true).await;
+        write(partial.clone(), meta(now + Duration::from_secs(60)), false).await;
+        assert_eq!(storage.keys().len(), 1);
+
+        // Purged (and so evicted) entries are forgotten.
+        let compact = fresh.to_compact();
+        storage.purge(&compact, &span.handle()).await.unwrap();
+        assert!(storage.keys().is_empty());
+
+        // So are expired ones, once they're found expired.
+        let expired = CacheKey::new("", "/expired", "r1");
+        write(expired.clone(), meta(now - Duration::from_secs(1)), true).await;
+        assert_eq!(storage.keys().len(), 1);
+        let persister = CachePersister::new(
+            &CachePersistConfig {
+                file: std::env::temp_dir()
+                    .join(format!("granite-cache-{}", rand::random::<u64>()))
+                    .to_string_lossy()
+                    .to_string(),
+                interval: None,
+                max_size: None,
+            },
+            storage,
+            leak(RouteEvictionManager::new(1000, 1, ShardBy::Key)),
+            leak(HotKeyTracker::default()),
+        );
+        assert_eq!(persister.save().await.unwrap(), 0);
+        assert!(storage.keys().is_empty());
+        fs::remove_file(&persister.config.file).unwrap();
+    }
+}
@@ -0,0 +1,433 @@
+//! Cache statistics broken down by route: occupancy, evictions, and cache lock contention, plus a
+//! report of the hottest cache keys.  They help operators spot keys that deserve longer TTLs and
+//! routes that use more than their share of the cache.
+//!
+//! Cache keys are tagged with the name of their route (see `CacheKey::user_tag`), which is how the
+//! eviction manager attributes cache entries to routes.
+
+use async_trait::async_trait;
+use pingora::cache::eviction::{simple_lru, EvictionManager};
+use pingora::cache::key::CompactCacheKey;
+use pingora::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+/// The maximum number of distinct cache keys whose lookups are counted.  When the limit is
+/// reached, all counts are halved and keys that drop to zero are forgotten, so the report favors
+/// keys that are both popular and recent.
+const MAX_TRACKED_KEYS: usize = 10_000;
+
+/// Cache usage of a route.
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct RouteCacheStats {
+    /// The bytes currently cached for the route.
+    pub bytes: usize,
+    /// The number of entries currently cached for the route.
+    pub items: usize,
+    pub evicted_bytes: usize,
+    pub evicted_items: usize,
+    /// The number of requests that waited for another request to fill a cache entry.
+    pub lock_waits: u64,
+    /// The total time (in milliseconds) spent waiting for cache locks.
+    pub lock_wait_ms: u64,
+    /// The number of requests that gave up waiting for a cache lock.
+    pub lock_timeouts: u64,
+}
+
+/// Lookup counts of a cache key.
+#[derive(Serialize, Debug, Default, Clone, PartialEq, Eq)]
+pub struct HotKey {
+    pub route: String,
+    pub key: String,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+/// The cache statistics reported through the Config API.
+#[derive(Serialize, Debug)]
+pub struct CacheReport {
+    pub routes: HashMap<String, RouteCacheStats>,
+    /// The most frequently looked up keys (most first).
+    pub hot_keys: Vec<HotKey>,
+}
+
+/// How cache entries are assigned to eviction shards.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum ShardBy {
+    /// By cache key hash: spreads entries evenly, minimizing lock contention.
+    #[default]
+    Key,
+    /// By route: a route only evicts entries of routes sharing its shard, isolating tenants.
+    Route,
+}
+
+/// An LRU eviction manager that also keeps track of how much of the cache each route occupies.
+/// It is split into shards, each with its own LRU list and an equal share of the cache size, so
+/// that concurrent admissions don't all contend for one lock.
+pub struct RouteEvictionManager {
+    shards: Vec<Shard>,
+    shard_by: ShardBy,
+}
+
+/// One shard of the eviction manager.
+struct Shard {
+    lru: simple_lru::Manager,
+    limit: usize,
+    /// The size of each cached entry (needed to attribute evictions to routes).
+    sizes: Mutex<HashMap<CompactCacheKey, usize>>,
+    routes: Mutex<HashMap<String, RouteCacheStats>>,
+}
+
+impl RouteEvictionManager {
+    /// Split `limit` bytes among `shards` shards (at least one).
+    pub fn new(limit: usize, shards: usize, shard_by: ShardBy) -> Self {
+        let count = shards.max(1);
+        let shards = (0..count)
+            .map(|_| Shard {
+                lru: simple_lru::Manager::new(limit / count),
+                limit: limit / count,
+                sizes: Mutex::new(HashMap::new()),
+                routes: Mutex::new(HashMap::new()),
+            })
+            .collect();
+        RouteEvictionManager { shards, shard_by }
+    }
+
+    /// The shard of a route (used for route sharding and for statistics not tied to a key).
+    fn route_shard(&self, route: &str) -> &Shard {
+        let mut hasher = DefaultHasher::new();
+        route.hash(&mut hasher);
+        &self.shards[hasher.finish() as usize % self.shards.len()]
+    }
+
+    /// The shard an entry belongs to.
+    fn shard(&self, item: &CompactCacheKey) -> &Shard {
+        match self.shard_by {
+            ShardBy::Key => {
+                let mut hash = [0; 8];
+                hash.copy_from_slice(&item.primary[..8]);
+                &self.shards[u64::from_le_bytes(hash) as usize % self.shards.len()]
+            }
+            ShardBy::Route => self.route_shard(&item.user_tag),
+        }
+    }
+
+    /// Record that a request for the route waited `duration` for a cache lock (and whether it
+    /// `timed_out`).
+    pub fn record_lock_wait(&self, route: &str, duration: Duration, timed_out: bool) {
+        let mut routes = self.route_shard(route).routes.lock().unwrap();
+        let stats = routes.entry(route.to_string()).or_default();
+        stats.lock_waits += 1;
+        stats.lock_wait_ms += duration.as_millis() as u64;
+        if timed_out {
+            stats.lock_timeouts += 1;
+        }
+    }
+
+    /// Evict at least `bytes` bytes of the least recently used entries (taking from each shard in
+    /// proportion to its usage) and return the evicted keys, which must be purged from storage.
+    pub fn trim(&self, bytes: usize) -> Vec<CompactCacheKey> {
+        let total = self.total_size();
+        if total == 0 {
+            return Vec::new();
+        }
+        self.shards
+            .iter()
+            .flat_map(|shard| {
+                let share =
+                    (bytes as u128 * shard.lru.total_size() as u128).div_ceil(total as u128);
+                shard.trim(share as usize)
+            })
+            .collect()
+    }
+
+    /// The cache usage of every route that has used the cache.
+    pub fn route_stats(&self) -> HashMap<String, RouteCacheStats> {
+        let mut merged: HashMap<String, RouteCacheStats> = HashMap::new();
+        for shard in &self.shards {
+            for (route, stats) in shard.routes.lock().unwrap().iter() {
+                let total = merged.entry(route.clone()).or_default();
+                total.bytes += stats.bytes;
+                total.items += stats.items;
+                total.evicted_bytes += stats.evicted_bytes;
+                total.evicted_items += stats.evicted_items;
+                total.lock_waits += stats.lock_waits;
+                total.lock_wait_ms += stats.lock_wait_ms;
+                total.lock_timeouts += stats.lock_timeouts;
+            }
+        }
+        merged
+    }
+}
+
+impl Shard {
+    /// Start tracking an entry (or update its size).
+    fn track(&self, item: &CompactCacheKey, size: usize) {
+        let old_size = self.sizes.lock().unwrap().insert(item.clone(), size);
+        let mut routes = self.routes.lock().unwrap();
+        let stats = routes.entry(item.user_tag.to_string()).or_default();
+        stats.bytes = stats.bytes + size - old_size.unwrap_or(0);
+        if old_size.is_none() {
+            stats.items += 1;
+        }
+    }
+
+    /// Evict at least `bytes` bytes of the least recently used entries.  `simple_lru` can't shrink
+    /// on demand, so a placeholder entry is admitted with a size that puts the shard `bytes` over
+    /// its limit (evicting entries to make room for it) and is then removed.
+    fn trim(&self, bytes: usize) -> Vec<CompactCacheKey> {
+        if bytes == 0 {
+            return Vec::new();
+        }
+        // Real keys are hashes, so they won't collide with an all-zero key.
+        let placeholder = CompactCacheKey {
+            primary: [0; 16],
+            variance: None,
+            user_tag: "".into(),
+        };
+        let size = self.limit.saturating_sub(self.lru.total_size()) + bytes;
+        let mut evicted = self.lru.admit(placeholder.clone(), size, SystemTime::now());
+        self.lru.remove(&placeholder);
+        evicted.retain(|key| key != &placeholder);
+        for key in &evicted {
+            self.untrack(key, true);
+        }
+        evicted
+    }
+
+    /// Stop tracking an entry.
+    fn untrack(&self, item: &CompactCacheKey, evicted: bool) {
+        let Some(size) = self.sizes.lock().unwrap().remove(item) else {
+            return;
+        };
+        let mut routes = self.routes.lock().unwrap();
+        let stats = routes.entry(item.user_tag.to_string()).or_default();
+        stats.bytes -= size;
+        stats.items -= 1;
+        if evicted {
+            stats.evicted_bytes += size;
+            stats.evicted_items += 1;
+        }
+    }
+}
+
+#[async_trait]
+impl EvictionManager for RouteEvictionManager {
+    fn total_size(&self) -> usize {
+        self.shards.iter().map(|s| s.lru.total_size()).sum()
+    }
+
+    fn total_items(&self) -> usize {
+        self.shards.iter().map(|s| s.lru.total_items()).sum()
+    }
+
+    fn evicted_size(&self) -> usize {
+        self.shards.iter().map(|s| s.lru.evicted_size()).sum()
+    }
+
+    fn evicted_items(&self) -> usize {
+        self.shards.iter().map(|s| s.lru.evicted_items()).sum()
+    }
+
+    fn admit(
+        &self,
+        item: CompactCacheKey,
+        size: usize,
+        fresh_until: SystemTime,
+    ) -> Vec<CompactCacheKey> {
+        let shard = self.shard(&item);
+        shard.track(&item, size);
+        let evicted = shard.lru.admit(item, size, fresh_until);
+        for key in &evicted {
+            shard.untrack(key, true);
+        }
+        evicted
+    }
+
+    fn remove(&self, item: &CompactCacheKey) {
+        let shard = self.shard(item);
+        shard.untrack(item, false);
+        shard.lru.remove(item);
+    }
+
+    fn access(&self, item: &CompactCacheKey, size: usize, fresh_until: SystemTime) -> bool {
+        let shard = self.shard(item);
+        let tracked = shard.lru.access(item, size, fresh_until);
+        if !tracked {
+            shard.track(item, size);
+        }
+        tracked
+    }
+
+    fn peek(&self, item: &CompactCacheKey) -> bool {
+        self.shard(item).lru.peek(item)
+    }
+
+    /// Each shard is saved in its own subdirectory.
+    async fn save(&self, dir_path: &str) -> Result<()> {
+        for (index, shard) in self.shards.iter().enumerate() {
+            shard.lru.save(&format!("{dir_path}/{index}")).await?;
+        }
+        Ok(())
+    }
+
+    async fn load(&self, dir_path: &str) -> Result<()> {
+        for (index, shard) in self.shards.iter().enumerate() {
+            shard.lru.load(&format!("{dir_path}/{index}")).await?;
+        }
+        Ok(())
+    }
+}
+
+/// Counts lookups per cache key (bounded to `MAX_TRACKED_KEYS` keys).
+#[derive(Default)]
+pub struct HotKeyTracker {
+    keys: Mutex<HashMap<(String, String), (u64, u64)>>,
+}
+
+impl HotKeyTracker {
+    /// Count a lookup of the route's cache key.
+    pub fn record(&self, route: &str, key: &str, hit: bool) {
+        let mut keys = self.keys.lock().unwrap();
+        let id = (route.to_string(), key.to_string());
+        if keys.len() >= MAX_TRACKED_KEYS && !keys.contains_key(&id) {
+            keys.retain(|_, (hits, misses)| {
+                *hits /= 2;
+                *misses /= 2;
+                *hits + *misses > 0
+            });
+        }
+        let (hits, misses) = keys.entry(id).or_default();
+        if hit {
+            *hits += 1;
+        } else {
+            *misses += 1;
+        }
+    }
+
+    /// The number of lookups of the route's cache key (as far as they're counted).
+    pub fn lookups(&self, route: &str, key: &str) -> u64 {
+        let id = (route.to_string(), key.to_string());
+        self.keys
+            .lock()
+            .unwrap()
+            .get(&id)
+            .map_or(0, |(hits, misses)| hits + misses)
+    }
+
+    /// The `count` most frequently looked up keys (most first).
+    pub fn top(&self, count: usize) -> Vec<HotKey> {
+        let mut keys: Vec<HotKey> = self
+            .keys
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|((route, key), (hits, misses))| HotKey {
+                route: route.clone(),
+                key: key.clone(),
+                hits: *hits,
+                misses: *misses,
+            })
+            .collect();
+        keys.sort_by_key(|k| std::cmp::Reverse(k.hits + k.misses));
+        keys.truncate(count);
+        keys
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pingora::cache::key::CacheKey;
+
+    #[test]
+    fn route_occupancy() {
+        let manager = RouteEvictionManager::new(100, 1, ShardBy::Key);
+        let key = |route: &str, path: &str| CacheKey::new("", path, route).to_compact();
+        let until = SystemTime::now() + Duration::from_secs(60);
+
+        manager.admit(key("r1", "/a"), 40, until);
+        manager.admit(key("r2", "/b"), 30, until);
+        assert_eq!(manager.route_stats()["r1"].bytes, 40);
+        assert_eq!(manager.route_stats()["r2"].items, 1);
+
+        // Exceeding the limit evicts the least recently used entry (r1's).
+        manager.admit(key("r2", "/c"), 50, until);
+        let stats = manager.route_stats();
+        assert_eq!(stats["r1"].bytes, 0);
+        assert_eq!(stats["r1"].evicted_bytes, 40);
+        assert_eq!(stats["r1"].evicted_items, 1);
+        assert_eq!(stats["r2"].bytes, 80);
+        assert_eq!(stats["r2"].items, 2);
+
+        manager.remove(&key("r2", "/b"));
+        let stats = manager.route_stats();
+        assert_eq!(stats["r2"].bytes, 50);
+        assert_eq!(stats["r2"].evicted_items, 0);
+    }
+
+    #[test]
+    fn trim() {
+        let manager = RouteEvictionManager::new(100, 1, ShardBy::Key);
+        let key = |path: &str| CacheKey::new("", path, "r1").to_compact();
+        let until = SystemTime::now() + Duration::from_secs(60);
+        for path in ["/a", "/b", "/c", "/d"] {
+            manager.admit(key(path), 20, until);
+        }
+        manager.access(&key("/a"), 20, until);
+
+        // The least recently used entries go first.
+        assert_eq!(manager.trim(30), vec![key("/b"), key("/c")]);
+        assert_eq!(manager.total_size(), 40);
+        assert_eq!(manager.total_items(), 2);
+        assert_eq!(manager.route_stats()["r1"].evicted_bytes, 40);
+    }
+
+    #[test]
+    fn route_sharding() {
+        let manager = RouteEvictionManager::new(100, 2, ShardBy::Route);
+        let key = |route: &str, path: &str| CacheKey::new("", path, route).to_compact();
+        let until = SystemTime::now() + Duration::from_secs(60);
+        // Find two routes in different shards.
+        let shard = |route: &str| manager.route_shard(route) as *const Shard;
+        let r1 = "r0";
+        let r2 = (1..)
+            .map(|i| format!("r{i}"))
+            .find(|r| shard(r) != shard(r1))
+            .unwrap();
+
+        manager.admit(key(&r2, "/a"), 40, until);
+        // r1 filling its shard (50 bytes) doesn't evict r2's entry.
+        for path in ["/b", "/c", "/d"] {
+            manager.admit(key(r1, path), 20, until);
+        }
+        let stats = manager.route_stats();
+        assert_eq!(stats[&r2].bytes, 40);
+        assert_eq!(stats[r1].evicted_items, 1);
+        assert_eq!(manager.total_size(), 80);
+    }
+
+    #[test]
+    fn hot_keys() {
+        let tracker = HotKeyTracker::default();
+        tracker.record("r1", "/a", false);
+        for _ in 0..3 {
+            tracker.record("r1", "/a", true);
+            tracker.record("r2", "/b", true);
+        }
+        tracker.record("r1", "/c", false);
+
+        let top = tracker.top(2);
+        assert_eq!(top.len(), 2);
+        assert_eq!(
+            (top[0].key.as_str(), top[0].hits, top[0].misses),
+            ("/a", 3, 1)
+        );
+        assert_eq!(top[1].key, "/b");
+    }
+}
@@ -4,7 +4,13 @@ use serde::{Deserialize, Serialize};
 
 /// An interface to add and delete certificates and their bindings.
 pub trait CertHolder: Send + Sync {
-    fn add_cert(&self, host: &str, cert: X509, key: PKey<Private>);
+    fn add_cert(
+        &self,
+        host: &str,
+        cert: X509,
+        key: PKey<Private>,
+        invalidate_sessions: bool,
+    ) -> CertSwap;
     fn delete_cert(&self, host: &str);
 }
 
@@ -13,6 +19,11 @@ pub trait CertHolder: Send + Sync {
 /// and the proxy selects the appropriate certificate and key by searching for the matching binding.
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct CertBinding {
+    /// The schema version of the payload the binding was defined with (see `schema`).  It's set to
+    /// the current version once the binding is parsed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
+
     /// The hostname/SNI associated with the certificate and key.
     pub host: String,
 
@@ -21,4 +32,28 @@ pub struct CertBinding {
 
     /// The corresponding private key in a string in PEM format.
     pub key: String,
+
+    /// How long (in seconds) the binding lives once added.  It's replaced with `expires_at` when
+    /// the binding is added.
+    pub ttl: Option<u64>,
+
+    /// When (in seconds since the Unix epoch) the binding expires and is deleted.
+    pub expires_at: Option<u64>,
+
+    /// Whether to delete the TLS sessions established with the host's previous certificate, so
+    /// their clients can't resume them and get the new certificate.  Otherwise, they keep using
+    /// the previous certificate until they make a full handshake.
+    #[serde(default)]
+    pub invalidate_sessions: bool,
+}
+
+/// The outcome of adding a certificate binding.
+#[derive(Serialize, Debug, PartialEq, Eq, Clone, Default)]
+pub struct CertSwap {
+    /// The number of hosts whose certificate was replaced (0 if the host had no binding, or the
+    /// same certificate).
+    pub hosts_rebound: usize,
+
+    /// The number of TLS sessions deleted.
+    pub sessions_invalidated: usize,
 }
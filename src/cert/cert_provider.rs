@@ -5,6 +5,8 @@ use pingora::tls::ssl::{NameType, SslRef};
 use std::sync::Arc;
 
 use crate::cert::cert_store::CertStore;
+use crate::sni;
+use crate::tls_fingerprint;
 
 /// Implementation of the interface with Pingora to provide certificates for TLS connections.
 /// It uses a CertStore to look up certificates based on the SNI in the Client Hello.
@@ -21,8 +23,12 @@ impl CertProvider {
 #[async_trait]
 impl TlsAccept for CertProvider {
     /// Function that Pingora calls during the TLS handshake to provide the certificate and
-    /// private key.
+    /// private key.  It also hands the client's TLS fingerprint (if any) and SNI over to the
+    /// request handling.
     async fn certificate_callback(&self, ssl: &mut SslRef) {
+        tls_fingerprint::register(ssl).await;
+        sni::register(ssl).await;
+
         let Some(sni) = ssl.servername(NameType::HOST_NAME) else {
             error!("Unable to extract SNI from CLIENT HELLO");
             return;
@@ -1,68 +1,133 @@
+use arc_swap::ArcSwap;
 use log::warn;
 use pingora::tls::pkey::{PKey, Private};
 use pingora::tls::x509::X509;
-use std::sync::RwLock;
 use std::{collections::HashMap, sync::Arc};
 
-use crate::cert::cert_config::CertHolder;
+use crate::cert::cert_config::{CertHolder, CertSwap};
+use crate::cert::session_cache::SessionCache;
 
 pub type CertAndKey = Arc<(X509, PKey<Private>)>;
 
 /// A store of certificates and keys, indexed by hostname/SNI.
 pub struct CertStore {
-    // Protect the internal data structure(s) that enables fast route lookups, additions,
-    // and deletions with a read-writer lock.  Reads are frequent (for every TLS connection),
-    // but writes are infrequent (only when the config API service is used to update a cert binding).
-    inner: RwLock<InnerStore>,
+    // Like the route store, the certificates are an immutable snapshot that is copied, modified,
+    // and atomically swapped in by writes.  Reads are frequent (for every TLS connection) and never
+    // wait, while writes are infrequent (only when the config API service is used to update a cert
+    // binding).  Certificates and keys are parsed before a write starts, and they are shared
+    // between snapshots, so copying a snapshot is cheap.
+    inner: ArcSwap<InnerStore>,
+    /// The TLS sessions established with the certificates.
+    sessions: SessionCache,
 }
 
-/// The inner protected part of the CertStore.
+/// A snapshot of the certificates in the CertStore.
+#[derive(Clone)]
 struct InnerStore {
     host_to_cert: HashMap<String, CertAndKey>,
+    /// The estimated memory used by the certificates and keys, in bytes.
+    bytes: usize,
 }
 
 impl InnerStore {
     fn new() -> Self {
         InnerStore {
             host_to_cert: HashMap::new(),
+            bytes: 0,
         }
     }
+
+    fn insert(&mut self, host: &str, cert_and_key: CertAndKey) {
+        self.bytes += estimated_size(&cert_and_key);
+        if let Some(old) = self.host_to_cert.insert(host.to_string(), cert_and_key) {
+            self.bytes -= estimated_size(&old);
+        }
+    }
+
+    fn remove(&mut self, host: &str) {
+        if let Some(old) = self.host_to_cert.remove(host) {
+            self.bytes -= estimated_size(&old);
+        }
+    }
+}
+
+/// Estimate the memory used by a certificate and key (sized as their DER encodings).
+fn estimated_size(cert_and_key: &CertAndKey) -> usize {
+    let (cert, key) = cert_and_key.as_ref();
+    cert.to_der().map_or(0, |der| der.len()) + key.private_key_to_der().map_or(0, |der| der.len())
 }
 
 impl CertStore {
     pub fn new() -> Self {
         CertStore {
-            inner: RwLock::new(InnerStore::new()),
+            inner: ArcSwap::from_pointee(InnerStore::new()),
+            sessions: SessionCache::default(),
         }
     }
 
+    /// Apply a change to a copy of the current snapshot and publish the copy.  The change may be
+    /// applied more than once (to a newer snapshot) if another change is published concurrently.
+    fn update(&self, change: impl Fn(&mut InnerStore)) {
+        self.inner.rcu(|current| {
+            let mut inner = InnerStore::clone(current);
+            change(&mut inner);
+            inner
+        });
+    }
+
     /// Find a certificate and key pair for the given hostname/SNI.
     pub fn get_cert(&self, host: &str) -> Option<CertAndKey> {
-        let inner = self.inner.read().unwrap();
+        let inner = self.inner.load();
 
         let cert_and_key = inner.host_to_cert.get(host)?;
         Some(cert_and_key.clone())
     }
+
+    /// The estimated memory used by the certificates and keys, in bytes.
+    pub fn memory_usage(&self) -> usize {
+        self.inner.load().bytes
+    }
+
+    /// The TLS sessions established with the certificates.
+    pub fn sessions(&self) -> &SessionCache {
+        &self.sessions
+    }
 }
 
 impl CertHolder for CertStore {
-    /// Add a certificate binding (hostname/SNI, certificate, and key).
-    fn add_cert(&self, host: &str, cert: X509, key: PKey<Private>) {
-        let mut inner = self.inner.write().unwrap();
-
-        inner
-            .host_to_cert
-            .insert(host.to_string(), Arc::new((cert, key)));
+    /// Add a certificate binding (hostname/SNI, certificate, and key).  New handshakes get the new
+    /// certificate right away, and the host's TLS sessions are deleted if `invalidate_sessions` is
+    /// set.
+    fn add_cert(
+        &self,
+        host: &str,
+        cert: X509,
+        key: PKey<Private>,
+        invalidate_sessions: bool,
+    ) -> CertSwap {
+        let rebound = self.get_cert(host).is_some_and(|old| old.0 != cert);
+        let cert_and_key = Arc::new((cert, key));
+        self.update(|inner| inner.insert(host, cert_and_key.clone()));
+        // Handshakes finishing with the old certificate from now on don't keep their sessions (see
+        // `session_cache`), so deleting the current ones is enough.
+        let sessions_invalidated = if invalidate_sessions {
+            self.sessions.invalidate(host)
+        } else {
+            0
+        };
+        CertSwap {
+            hosts_rebound: usize::from(rebound),
+            sessions_invalidated,
+        }
     }
 
-    /// Delete a certificate binding for the given hostname/SNI.
+    /// Delete a certificate binding for the given hostname/SNI, and its TLS sessions.
     fn delete_cert(&self, host: &str) {
-        let mut inner = self.inner.write().unwrap();
-
-        let cert_and_key = inner.host_to_cert.remove(host);
-
-        if cert_and_key.is_some() {
+        if !self.inner.load().host_to_cert.contains_key(host) {
             warn!("Attempted to delete a cert that doesn't exist host={host}");
+            return;
         }
+        self.update(|inner| inner.remove(host));
+        self.sessions.invalidate(host);
     }
 }
@@ -1,3 +1,4 @@
-pub(crate) mod cert_config;
-pub(crate) mod cert_provider;
-pub(crate) mod cert_store;
+pub mod cert_config;
+pub mod cert_provider;
+pub mod cert_store;
+pub mod session_cache;
@@ -0,0 +1,138 @@
+//! A server-side cache of the TLS sessions established by the proxy's HTTPS listeners, so a
+//! certificate binding can be replaced without letting clients resume the sessions established
+//! with the old certificate (when asked to).
+//!
+//! With session tickets, the session state is encrypted and held by the clients, so the proxy
+//! can't tell which certificate a ticket was issued under.  Instead, the listeners issue session
+//! IDs (and, in TLS 1.3, stateful tickets) referring to sessions kept here, along with the host
+//! they were established for.  When a binding is replaced, the host's sessions are kept by default
+//! (their clients keep using the certificate they verified until they make a full handshake), or
+//! deleted, which forces the clients to make a full handshake, with the new certificate.
+//!
+//! New handshakes always get the certificate bound at the time.
+
+use pingora::tls::ssl::{
+    NameType, SslAcceptorBuilder, SslOptions, SslRef, SslSession, SslSessionCacheMode,
+};
+use std::collections::{HashMap, VecDeque};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::cert::cert_store::CertStore;
+
+/// The maximum number of sessions kept (OpenSSL's default size for its own cache).
+const MAX_SESSIONS: usize = 20 * 1024;
+
+/// A session's listener and ID.  A session can only be resumed on the listener it was established
+/// on.
+type SessionKey = (usize, Vec<u8>);
+
+struct CachedSession {
+    session: SslSession,
+    /// The hostname/SNI the session was established for.
+    host: String,
+    expires: Instant,
+}
+
+#[derive(Default)]
+struct Sessions {
+    entries: HashMap<SessionKey, CachedSession>,
+    /// The keys in the order the sessions were added (including those of some deleted sessions),
+    /// so the oldest are evicted first.
+    order: VecDeque<SessionKey>,
+}
+
+/// The TLS sessions of the HTTPS listeners.
+#[derive(Default)]
+pub struct SessionCache {
+    sessions: Mutex<Sessions>,
+    /// The number of listeners using the cache.
+    listeners: AtomicUsize,
+}
+
+impl SessionCache {
+    /// Add a session established for the host.
+    fn insert(&self, listener: usize, host: &str, session: SslSession) {
+        let now = Instant::now();
+        let key = (listener, session.id().to_vec());
+        let expires = now + Duration::from_secs(session.timeout().max(0) as u64);
+        let mut guard = self.sessions.lock().unwrap();
+        let sessions = &mut *guard;
+        sessions.order.push_back(key.clone());
+        sessions.entries.insert(
+            key,
+            CachedSession {
+                session,
+                host: host.to_string(),
+                expires,
+            },
+        );
+
+        // Evict the oldest sessions beyond the maximum, and the expired ones.
+        while let Some(oldest) = sessions.order.front() {
+            let keep = sessions
+                .entries
+                .get(oldest)
+                .is_some_and(|entry| entry.expires > now && sessions.entries.len() <= MAX_SESSIONS);
+            if keep {
+                break;
+            }
+            if let Some(oldest) = sessions.order.pop_front() {
+                sessions.entries.remove(&oldest);
+            }
+        }
+    }
+
+    /// Find a session a client wants to resume.
+    fn get(&self, listener: usize, id: &[u8]) -> Option<SslSession> {
+        let sessions = self.sessions.lock().unwrap();
+        let entry = sessions.entries.get(&(listener, id.to_vec()))?;
+        (entry.expires > Instant::now()).then(|| entry.session.clone())
+    }
+
+    /// Delete the sessions established for the host.  Return the number of sessions deleted.
+    pub fn invalidate(&self, host: &str) -> usize {
+        let mut sessions = self.sessions.lock().unwrap();
+        let before = sessions.entries.len();
+        sessions.entries.retain(|_, entry| entry.host != host);
+        before - sessions.entries.len()
+    }
+}
+
+/// Make a listener keep its sessions in the cert store's session cache instead of issuing session
+/// tickets.
+pub fn install(cert_store: Arc<CertStore>, builder: &mut SslAcceptorBuilder) {
+    let listener = cert_store
+        .sessions()
+        .listeners
+        .fetch_add(1, Ordering::Relaxed);
+    builder.set_options(SslOptions::NO_TICKET);
+    builder.set_session_cache_mode(SslSessionCacheMode::SERVER | SslSessionCacheMode::NO_INTERNAL);
+
+    let store = cert_store.clone();
+    builder.set_new_session_callback(move |ssl, session| {
+        if let Some(host) = resumable_host(&store, ssl) {
+            store.sessions().insert(listener, &host, session);
+        }
+    });
+    // SAFETY: the sessions returned were established on this listener, so with its context.
+    unsafe {
+        builder.set_get_session_callback(move |_, id| cert_store.sessions().get(listener, id));
+    }
+}
+
+/// The host a new session was established for, unless it shouldn't be resumed: the connection has
+/// no SNI, or its certificate was replaced during the handshake (so the session wouldn't be
+/// deleted with the old certificate's sessions).  A session established by resuming another one
+/// has no certificate of its own, and is resumable like the one it comes from.
+fn resumable_host(cert_store: &CertStore, ssl: &SslRef) -> Option<String> {
+    let host = ssl.servername(NameType::HOST_NAME)?.to_string();
+    if let Some(cert) = ssl.certificate() {
+        let bound = cert_store.get_cert(&host)?;
+        if *bound.0 != *cert {
+            return None;
+        }
+    }
+    Some(host)
+}
@@ -0,0 +1,204 @@
+//! A cache tier shared by a cluster of proxies.
+//!
+//! The cache key space is spread across the cluster's instances with consistent hashing, so each
+//! key is owned by one instance.  When a request for a key owned by another instance misses the
+//! local cache, it is forwarded to the owner (instead of the origin), which serves it from its
+//! cache or fetches it from the origin and caches it.  The response isn't cached again locally, so
+//! the cluster's effective cache size is the sum of its instances' cache sizes.
+//!
+//! Forwarded requests carry the `x-granite-peer` header, and the owner always goes to the origin
+//! for them, so requests aren't forwarded more than once.  They also carry the client's address in
+//! the `x-granite-client` header, so the owner matches them to the same route as the peer did.  If
+//! the owner can't be reached, it is avoided for a while and the request goes to the origin.
+
+use log::{info, warn};
+use pingora::lb::selection::consistent::KetamaHashing;
+use pingora::lb::selection::{BackendIter, BackendSelection};
+use pingora::lb::Backend;
+use pingora::protocols::l4::socket::SocketAddr as BackendAddr;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeSet, HashMap};
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+/// The header marking a request forwarded by a peer.
+pub const PEER_HEADER: &str = "x-granite-peer";
+
+/// The header carrying the address of the client a request forwarded by a peer came from.
+pub const CLIENT_HEADER: &str = "x-granite-client";
+
+/// Cluster cache settings.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct ClusterConfig {
+    /// The proxy HTTP listener addresses (`ip:port`) of all the instances in the cluster,
+    /// including this one.
+    pub peers: Vec<String>,
+
+    /// This instance's address in `peers`.  The cluster cache is enabled only if this is set.
+    pub self_addr: Option<String>,
+
+    /// How long (in milliseconds) to wait for a connection to a peer.
+    pub connection_timeout: u64,
+
+    /// How long (in seconds) a peer that couldn't be reached is avoided.
+    pub down_time: u64,
+}
+
+impl Default for ClusterConfig {
+    /// By default, the cluster cache is disabled.
+    fn default() -> Self {
+        ClusterConfig {
+            peers: Vec::new(),
+            self_addr: None,
+            connection_timeout: 500,
+            down_time: 10,
+        }
+    }
+}
+
+impl ClusterConfig {
+    /// Whether the cluster cache is enabled (with at least one other instance).
+    pub fn is_enabled(&self) -> bool {
+        self.self_addr.is_some() && self.peers.len() > 1
+    }
+}
+
+/// Decides which instance of the cluster owns each cache key.
+pub struct Cluster {
+    self_addr: SocketAddr,
+    ring: Arc<KetamaHashing>,
+    /// The addresses peers connect from (trusted to forward requests).
+    peer_ips: Vec<IpAddr>,
+    connection_timeout: Duration,
+    down_time: Duration,
+    /// Peers that couldn't be reached and when.
+    down: RwLock<HashMap<SocketAddr, Instant>>,
+}
+
+impl Cluster {
+    /// Create the cluster if enabled.  The addresses must have been validated (see `AppConfig`).
+    pub fn new(config: &ClusterConfig) -> Option<Self> {
+        if !config.is_enabled() {
+            return None;
+        }
+        let self_addr = config.self_addr.as_ref()?.parse().ok()?;
+        let peers: BTreeSet<Backend> = config
+            .peers
+            .iter()
+            .filter_map(|peer| Backend::new(peer).ok())
+            .collect();
+        let peer_ips = peers
+            .iter()
+            .filter_map(|peer| peer.addr.as_inet().map(|addr| addr.ip()))
+            .collect();
+        info!(
+            "Sharing the cache with {} peers as {self_addr}",
+            peers.len() - 1
+        );
+        Some(Cluster {
+            self_addr,
+            ring: Arc::new(KetamaHashing::build(&peers)),
+            peer_ips,
+            connection_timeout: Duration::from_millis(config.connection_timeout),
+            down_time: Duration::from_secs(config.down_time),
+            down: RwLock::new(HashMap::new()),
+        })
+    }
+
+    /// The peer owning the cache key, or `None` if this instance owns it.  Keys owned by a peer
+    /// that is down are owned by the next instance on the ring.
+    pub fn owner(&self, key: &[u8]) -> Option<SocketAddr> {
+        let mut nodes = self.ring.iter(key);
+        while let Some(node) = nodes.next() {
+            let BackendAddr::Inet(addr) = node.addr else {
+                continue;
+            };
+            if addr == self.self_addr {
+                return None;
+            }
+            if !self.is_down(&addr) {
+                return Some(addr);
+            }
+        }
+        None
+    }
+
+    /// Whether the request came from a peer (and should therefore go to the origin).
+    pub fn is_peer_request(&self, client_ip: Option<IpAddr>, has_peer_header: bool) -> bool {
+        has_peer_header && client_ip.is_some_and(|ip| self.peer_ips.contains(&ip))
+    }
+
+    pub fn self_addr(&self) -> SocketAddr {
+        self.self_addr
+    }
+
+    pub fn connection_timeout(&self) -> Duration {
+        self.connection_timeout
+    }
+
+    /// Avoid a peer that couldn't be reached for a while.
+    pub fn mark_down(&self, peer: SocketAddr) {
+        let mut down = self.down.write().unwrap();
+        if down.insert(peer, Instant::now()).is_none() {
+            warn!("Marking cache peer {peer} down");
+        }
+    }
+
+    fn is_down(&self, peer: &SocketAddr) -> bool {
+        let down = self.down.read().unwrap();
+        down.get(peer)
+            .is_some_and(|since| since.elapsed() < self.down_time)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_ownership() {
+        let peers = vec![
+            "10.0.0.1:8080".to_string(),
+            "10.0.0.2:8080".to_string(),
+            "10.0.0.3:8080".to_string(),
+        ];
+        let clusters: Vec<Cluster> = peers
+            .iter()
+            .map(|addr| {
+                Cluster::new(&ClusterConfig {
+                    peers: peers.clone(),
+                    self_addr: Some(addr.clone()),
+                    ..Default::default()
+                })
+                .unwrap()
+            })
+            .collect();
+
+        // Every key is owned by exactly one instance, and the others agree on which one.
+        let mut owned = [0; 3];
+        for i in 0..300 {
+            let key = format!("key{i}");
+            let owners: Vec<_> = clusters.iter().map(|c| c.owner(key.as_bytes())).collect();
+            let local: Vec<_> = (0..3).filter(|&n| owners[n].is_none()).collect();
+            assert_eq!(local.len(), 1);
+            owned[local[0]] += 1;
+            for owner in owners.iter().flatten() {
+                assert_eq!(*owner, clusters[local[0]].self_addr());
+            }
+        }
+        assert!(owned.iter().all(|&n| n > 50));
+
+        // Keys owned by a peer that is down are owned by another instance.
+        let first = &clusters[0];
+        let peer: SocketAddr = peers[1].parse().unwrap();
+        first.mark_down(peer);
+        assert!((0..300).all(|i| first.owner(format!("key{i}").as_bytes()) != Some(peer)));
+
+        let peer_ip = Some(peer.ip());
+        assert!(first.is_peer_request(peer_ip, true));
+        assert!(!first.is_peer_request(peer_ip, false));
+        assert!(!first.is_peer_request(Some("192.0.2.1".parse().unwrap()), true));
+    }
+}
@@ -0,0 +1,268 @@
+//! Request collapsing for routes that don't cache.  (Caching routes already collapse cache misses
+//! with the cache lock.)
+//!
+//! When a route collapses requests, the first GET request for a URL fetches the response from the
+//! origin while identical requests that arrive in the meantime wait for it, and they are all sent
+//! the same response.  The response is buffered for the waiting requests, so responses larger than
+//! the policy's limit aren't shared, nor are responses that set cookies; the waiting requests then
+//! go to the origin themselves, as they do if the first request fails or takes too long.
+//!
+//! Requests with credentials (`Authorization` or `Cookie` headers) aren't collapsed, since their
+//! responses may be personalized, nor are conditional and range requests.
+
+use bytes::{Bytes, BytesMut};
+use pingora::http::{RequestHeader, ResponseHeader};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::watch;
+
+/// A request collapsing policy for a route.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct CollapsePolicy {
+    /// The request headers that must also be identical (besides the host and URI) for requests to
+    /// be collapsed.
+    #[serde(default = "default_vary")]
+    pub vary: Vec<String>,
+
+    /// The size (in bytes) of the largest response body shared with waiting requests.
+    #[serde(default = "default_max_body_size")]
+    pub max_body_size: usize,
+
+    /// The time (in milliseconds) a request waits for an identical request's response before
+    /// going to the origin itself.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+fn default_vary() -> Vec<String> {
+    vec!["accept-encoding".to_string()]
+}
+
+fn default_max_body_size() -> usize {
+    1024 * 1024
+}
+
+fn default_timeout() -> u64 {
+    5000
+}
+
+impl CollapsePolicy {
+    /// The key identifying identical requests (`None` if the request can't be collapsed).
+    pub fn key(&self, route: &str, req: &RequestHeader, bucket: Option<&str>) -> Option<String> {
+        if req.method != http::Method::GET
+            || req.headers.contains_key(http::header::AUTHORIZATION)
+            || req.headers.contains_key(http::header::COOKIE)
+            || req.headers.contains_key(http::header::IF_NONE_MATCH)
+            || req.headers.contains_key(http::header::IF_MODIFIED_SINCE)
+            || req.headers.contains_key(http::header::RANGE)
+        {
+            return None;
+        }
+        let header = |name: &str| {
+            req.headers
+                .get(name)
+                .map(|v| String::from_utf8_lossy(v.as_bytes()).into_owned())
+                .unwrap_or_default()
+        };
+        let mut key = format!(
+            "{route}\n{}\n{}\n{}",
+            bucket.unwrap_or_default(),
+            header("host"),
+            req.uri
+        );
+        for name in &self.vary {
+            key.push('\n');
+            key.push_str(&header(name));
+        }
+        Some(key)
+    }
+}
+
+/// A response shared with waiting requests.
+#[derive(Debug)]
+pub struct CollapsedResponse {
+    pub header: ResponseHeader,
+    pub body: Bytes,
+}
+
+type Shared = Option<Arc<CollapsedResponse>>;
+
+/// The requests being fetched from origins, by key.
+#[derive(Default)]
+pub struct Collapser {
+    in_flight: Mutex<HashMap<String, (u64, watch::Receiver<Shared>)>>,
+    next_id: Mutex<u64>,
+}
+
+/// How a request joined the requests being fetched.
+pub enum Joined {
+    /// The request fetches the response.
+    Leader(Box<CollapseLeader>),
+
+    /// The request waits for an identical request's response.
+    Follower(watch::Receiver<Shared>),
+}
+
+impl Collapser {
+    /// Join the identical requests being fetched (or start fetching).
+    pub fn join(self: &Arc<Self>, key: String, max_body_size: usize) -> Joined {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if let Some((_, receiver)) = in_flight.get(&key) {
+            return Joined::Follower(receiver.clone());
+        }
+        let id = {
+            let mut next_id = self.next_id.lock().unwrap();
+            *next_id += 1;
+            *next_id
+        };
+        let (sender, receiver) = watch::channel(None);
+        in_flight.insert(key.clone(), (id, receiver));
+        Joined::Leader(Box::new(CollapseLeader {
+            collapser: self.clone(),
+            key,
+            id,
+            sender,
+            header: None,
+            body: BytesMut::new(),
+            max_body_size,
+        }))
+    }
+
+    /// Wait for the response fetched by an identical request (`None` if it isn't shared in time).
+    pub async fn wait(mut receiver: watch::Receiver<Shared>, timeout: Duration) -> Shared {
+        let wait = async {
+            loop {
+                if let Some(resp) = receiver.borrow_and_update().clone() {
+                    return Some(resp);
+                }
+                if receiver.changed().await.is_err() {
+                    return receiver.borrow().clone();
+                }
+            }
+        };
+        tokio::time::timeout(timeout, wait).await.ok().flatten()
+    }
+
+    fn remove(&self, key: &str, id: u64) {
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight
+            .get(key)
+            .is_some_and(|(current, _)| *current == id)
+        {
+            in_flight.remove(key);
+        }
+    }
+}
+
+/// Collects the response of the request that fetches it, to share it with the waiting requests.
+/// If it's dropped before the response is complete, the waiting requests go to the origin.
+pub struct CollapseLeader {
+    collapser: Arc<Collapser>,
+    key: String,
+    id: u64,
+    sender: watch::Sender<Shared>,
+    header: Option<ResponseHeader>,
+    body: BytesMut,
+    max_body_size: usize,
+}
+
+impl std::fmt::Debug for CollapseLeader {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("CollapseLeader")
+            .field("key", &self.key)
+            .finish()
+    }
+}
+
+impl CollapseLeader {
+    /// Record the response header.  Returns whether the response can be shared.
+    pub fn response_header(&mut self, header: &ResponseHeader) -> bool {
+        if header.headers.contains_key(http::header::SET_COOKIE) {
+            return false;
+        }
+        self.header = Some(header.clone());
+        true
+    }
+
+    /// Record the next chunk of the response body, sharing the response once it's complete.
+    /// Returns whether the response can still be shared.
+    pub fn response_body(&mut self, chunk: Option<&[u8]>, end_of_stream: bool) -> bool {
+        if let Some(chunk) = chunk {
+            if self.body.len() + chunk.len() > self.max_body_size {
+                return false;
+            }
+            self.body.extend_from_slice(chunk);
+        }
+        if end_of_stream {
+            let Some(mut header) = self.header.take() else {
+                return false;
+            };
+            header.remove_header(&http::header::TRANSFER_ENCODING);
+            if header
+                .insert_header(http::header::CONTENT_LENGTH, self.body.len())
+                .is_err()
+            {
+                return false;
+            }
+            let body = std::mem::take(&mut self.body).freeze();
+            self.sender
+                .send_replace(Some(Arc::new(CollapsedResponse { header, body })));
+            self.collapser.remove(&self.key, self.id);
+        }
+        true
+    }
+}
+
+impl Drop for CollapseLeader {
+    fn drop(&mut self) {
+        self.collapser.remove(&self.key, self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn collapse() {
+        let policy: CollapsePolicy = serde_json::from_str("{}").unwrap();
+        let mut req = RequestHeader::build("GET", b"/a", None).unwrap();
+        req.insert_header("host", "example.com").unwrap();
+        let key = policy.key("r", &req, None).unwrap();
+        req.insert_header("accept-encoding", "gzip").unwrap();
+        assert_ne!(policy.key("r", &req, None).unwrap(), key);
+        req.insert_header("cookie", "session=1").unwrap();
+        assert_eq!(policy.key("r", &req, None), None);
+
+        let collapser = Arc::new(Collapser::default());
+        let Joined::Leader(mut leader) = collapser.join(key.clone(), 16) else {
+            panic!("expected to lead");
+        };
+        let Joined::Follower(follower) = collapser.join(key.clone(), 16) else {
+            panic!("expected to follow");
+        };
+        let waiting = tokio::spawn(Collapser::wait(follower, Duration::from_secs(5)));
+        assert!(leader.response_header(&ResponseHeader::build(200, None).unwrap()));
+        assert!(leader.response_body(Some(b"hello "), false));
+        assert!(leader.response_body(Some(b"world"), true));
+        let resp = waiting.await.unwrap().unwrap();
+        assert_eq!(resp.body, "hello world");
+        assert_eq!(resp.header.headers["content-length"], "11");
+
+        // The next request starts over, and a response that's too large isn't shared.
+        let Joined::Leader(mut leader) = collapser.join(key.clone(), 16) else {
+            panic!("expected to lead");
+        };
+        let Joined::Follower(follower) = collapser.join(key.clone(), 16) else {
+            panic!("expected to follow");
+        };
+        assert!(leader.response_header(&ResponseHeader::build(200, None).unwrap()));
+        assert!(!leader.response_body(Some(&[0; 17]), false));
+        drop(leader);
+        assert!(Collapser::wait(follower, Duration::from_secs(5))
+            .await
+            .is_none());
+    }
+}
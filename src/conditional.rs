@@ -0,0 +1,73 @@
+//! Conditional requests on caching routes.  Client validators (`If-None-Match`,
+//! `If-Modified-Since`) are never forwarded to the origin on a cache miss, so the full response is
+//! cached; the proxy then answers conditional requests from the cache, with a `304 Not Modified`
+//! when the validators match the cached response, according to the route's policy.
+//!
+//! (On routes that don't cache, conditional requests go to the origin as is.)
+
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::protocols::http::conditional_filter::not_modified_filter;
+use serde::{Deserialize, Serialize};
+
+/// How a caching route answers conditional requests from the cache.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum ConditionalPolicy {
+    /// Answer with a 304 if `If-None-Match` matches the cached response's `ETag` or, without
+    /// `If-None-Match`, if the response wasn't modified since `If-Modified-Since`.
+    #[default]
+    Validators,
+
+    /// Only honor `If-None-Match` (for origins whose `Last-Modified` isn't reliable).
+    ETagOnly,
+
+    /// Always send the full response.
+    Never,
+}
+
+impl ConditionalPolicy {
+    /// Whether to answer the request with a 304 instead of the cached response.
+    pub fn not_modified(&self, req: &RequestHeader, resp: &ResponseHeader) -> bool {
+        match self {
+            ConditionalPolicy::Validators => not_modified_filter(req, resp),
+            ConditionalPolicy::ETagOnly => {
+                req.headers.contains_key(http::header::IF_NONE_MATCH)
+                    && not_modified_filter(req, resp)
+            }
+            ConditionalPolicy::Never => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn not_modified() {
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header("etag", "\"v2\"").unwrap();
+        resp.insert_header("last-modified", "Wed, 01 May 2024 00:00:00 GMT")
+            .unwrap();
+        let request = |name: &str, value: &str| {
+            let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+            req.insert_header(name.to_string(), value).unwrap();
+            req
+        };
+        let etag_match = request("if-none-match", "\"v1\", W/\"v2\"");
+        let etag_mismatch = request("if-none-match", "\"v1\"");
+        let not_modified_since = request("if-modified-since", "Thu, 02 May 2024 00:00:00 GMT");
+        let modified_since = request("if-modified-since", "Tue, 30 Apr 2024 00:00:00 GMT");
+
+        let policy = ConditionalPolicy::Validators;
+        assert!(policy.not_modified(&etag_match, &resp));
+        assert!(!policy.not_modified(&etag_mismatch, &resp));
+        assert!(policy.not_modified(&not_modified_since, &resp));
+        assert!(!policy.not_modified(&modified_since, &resp));
+
+        let policy = ConditionalPolicy::ETagOnly;
+        assert!(policy.not_modified(&etag_match, &resp));
+        assert!(!policy.not_modified(&not_modified_since, &resp));
+
+        assert!(!ConditionalPolicy::Never.not_modified(&etag_match, &resp));
+    }
+}
@@ -1,59 +1,461 @@
 //! The Config API service allows for dynamic configuration changes to the proxy through a REST API.
-//! It supports route and certificate management.
+//! It supports route and certificate management.  It also serves a live stream of request
+//! summaries (the request tap) for debugging.
 
 use async_trait::async_trait;
+use bytes::Bytes;
 use http::{Response, StatusCode};
-use log::{error, info};
-use pingora::apps::http_app::ServeHttp;
+use log::{debug, error, info, warn};
+use pingora::apps::HttpServerApp;
+use pingora::http::{RequestHeader, ResponseHeader};
 use pingora::protocols::http::ServerSession;
+use pingora::protocols::Stream;
+use pingora::server::ShutdownWatch;
 use pingora::tls::pkey::PKey;
 use pingora::tls::x509::X509;
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::broadcast::error::RecvError;
 
-use crate::cert::cert_config::{CertBinding, CertHolder};
-use crate::route_config::{RouteConfig, RouteHolder};
+use crate::acl::{self, AclHolder};
+use crate::api_stats::{ApiCounters, VersionInfo};
+use crate::basic_auth::{CredentialHolder, CredentialList};
+use crate::cert::cert_config::{CertHolder, CertSwap};
+use crate::customer::{CustomerConfig, CustomerHolder, CustomerStore};
+use crate::declarative::DeclarativeConfig;
+use crate::drain::Drainer;
+use crate::expiry;
+use crate::fault::{FaultConfig, FaultInjector};
+use crate::freeze::{self, FreezeConfig, FreezeWindow, Freezer};
+use crate::logging;
+use crate::path_match;
+use crate::proxy;
+use crate::quota::QuotaTracker;
+use crate::replication::{self, ConfigItem, ItemKind, Replicator};
+use crate::route_config::RouteHolder;
+use crate::route_import::{self, ImportOptions};
+use crate::schema;
+use crate::status::StatusReporter;
+use crate::tap::{RequestTap, TapFilter};
+use crate::usage::UsageTracker;
+use crate::utils;
+use crate::wasm::{WasmHolder, WasmModule};
+
+/// How often a comment is sent to idle tap subscribers (to detect disconnected clients).
+const TAP_KEEPALIVE_INTERVAL: Duration = Duration::from_secs(15);
+
+/// The number of hottest cache keys included in the stats.
+const HOT_KEYS_REPORTED: usize = 20;
+
+/// The largest request body read in full (e.g., a WebAssembly filter).
+const MAX_BODY_SIZE: usize = 64 * 1024 * 1024;
 
 pub struct ConfigApi {
+    /// A means to add, delete, and look up customers
+    customer_store: Arc<CustomerStore>,
+    /// Whether routes must belong to a defined customer
+    require_customers: bool,
     /// A means to add and delete routes
     route_holder: Arc<dyn RouteHolder>,
     /// A means to add and delete certificates
     cert_holder: Arc<dyn CertHolder>,
+    /// A means to add and delete basic auth credential lists
+    credential_holder: Arc<dyn CredentialHolder>,
+    /// A means to add and delete WebAssembly filters
+    wasm_holder: Arc<dyn WasmHolder>,
+    /// A means to report quota usage
+    quota_tracker: Arc<QuotaTracker>,
+    /// A means to block and unblock client addresses
+    acl_holder: Arc<dyn AclHolder>,
+    /// A means to report the runtime status
+    status_reporter: Arc<StatusReporter>,
+    /// A means to subscribe to live request summaries
+    request_tap: Arc<RequestTap>,
+    /// A means to drain the server before it exits
+    drainer: Arc<Drainer>,
+    /// A record of the replicated configuration (and the leader, if this instance follows one)
+    replicator: Arc<Replicator>,
+    /// A means to inject faults into routes
+    fault_injector: Arc<FaultInjector>,
+    /// The change freeze windows
+    freezer: Freezer,
+    /// A means to report the usage of customers
+    usage_tracker: Arc<UsageTracker>,
+    /// The Config API's own request counters
+    api_counters: ApiCounters,
+    /// The declarative configuration file (if the configuration is managed by one)
+    declarative_file: Option<String>,
 }
 
 #[async_trait]
-impl ServeHttp for ConfigApi {
-    /// The implementation of the interface between Pingora and the Config API service.  It's
-    /// simple: Pingora receives an HTTP request and calls this method on the ConfigAPI to produce
-    /// a response.  In this case, the response just indicates whether the config change request
-    /// was successfully applied.
+impl HttpServerApp for ConfigApi {
+    /// The implementation of the interface between Pingora and the Config API service.  Pingora
+    /// hands over each new HTTP session.  The request tap (`/tap`) streams its response for as
+    /// long as the client stays connected.  All other requests get a single response produced by
+    /// `response`.
+    async fn process_new_http(
+        self: &Arc<Self>,
+        mut http: ServerSession,
+        shutdown: &ShutdownWatch,
+    ) -> Option<Stream> {
+        match http.read_request().await {
+            Ok(true) => debug!("Received a new request"),
+            Ok(false) => {
+                debug!("Failed to read request header");
+                return None;
+            }
+            Err(e) => {
+                error!("Config API fails to read from downstream: {e}");
+                return None;
+            }
+        }
+
+        if http.req_header().uri.path() == "/tap" {
+            self.tap(&mut http, shutdown).await;
+            // The connection isn't reused after streaming.
+            return None;
+        }
+
+        if *shutdown.borrow() {
+            http.set_keepalive(None);
+        } else {
+            http.set_keepalive(Some(60));
+        }
+        let response = self.response(&mut http).await;
+        self.api_counters.record(response.status().as_u16());
+        if let Err(e) = write_response(&mut http, response).await {
+            error!("Config API fails to write to downstream: {e}");
+            return None;
+        }
+        match http.finish().await {
+            Ok(stream) => stream,
+            Err(e) => {
+                error!("Config API fails to finish the request: {e}");
+                None
+            }
+        }
+    }
+}
+
+impl ConfigApi {
+    /// Produce the response to a (non-streaming) request.  In most cases, the response just
+    /// indicates whether the config change request was successfully applied.
     /// The requested action is determined by the path of the request:
+    /// - /customer/add: Add or update a customer
+    /// - /customer/delete: Delete a customer (that no route belongs to)
     /// - /route/add: Add or update a route
     /// - /route/delete: Delete a route
+    /// - /route/import: Convert an nginx or Caddy configuration into routes (without adding them)
     /// - /cert/add: Add a certificate
     /// - /cert/delete: Delete a certificate
+    /// - /credentials/add: Add or update a basic auth credential list
+    /// - /credentials/delete: Delete a basic auth credential list
+    /// - /wasm/add: Add or update a WebAssembly filter
+    /// - /wasm/delete: Delete a WebAssembly filter
+    /// - /acl/block: Add an IP address, CIDR block, or autonomous system to the deny list
+    /// - /acl/unblock: Remove an IP address, CIDR block, or autonomous system from the deny list
+    /// - /acl/list: List the deny list entries
+    /// - /stats: Report usage statistics
+    /// - /usage: Report the traffic of customers (for billing)
+    /// - /version: Report the build version (and commit)
+    /// - /api/stats: Report this API's own statistics (version, uptime, item counts, requests)
+    /// - /status: Report the runtime status (routes, origin state, cache utilization, uptime)
+    /// - /log/level: Report (GET) or change (POST) the log level
+    /// - /ready: Report whether the server is ready for traffic (i.e., not draining)
+    /// - /admin/drain: Drain the server and then exit
+    /// - /replication/snapshot: Report the replicated configuration (for followers to poll)
+    /// - /fault/set: Set (or replace) the faults injected into a route
+    /// - /fault/clear: Stop injecting faults into a route
+    /// - /fault/list: List the faults injected into routes
+    /// - /freeze/add: Add (or replace) a change freeze window
+    /// - /freeze/delete: Delete a change freeze window
+    /// - /freeze/list: List the change freeze windows
+    ///
+    /// (/tap is handled separately since its response is streamed.)
+    ///
+    /// If this instance follows a leader, configuration changes are rejected: they must be made on
+    /// the leader.  Likewise, if the configuration is managed by a declarative file, changes to the
+    /// items it manages (all but blocked addresses) are rejected.  During a change freeze, they are
+    /// rejected unless they carry a break-glass token.
     async fn response(&self, http_stream: &mut ServerSession) -> Response<Vec<u8>> {
         let path = http_stream.req_header().uri.path();
+        if let Some(leader) = self.replicator.leader() {
+            if is_config_change(path) {
+                warn!("Rejecting configuration change on a follower: {path}");
+                let body = format!("This instance follows {leader}; make changes there\n");
+                return build_response(StatusCode::FORBIDDEN, &body);
+            }
+        }
+        if let Some(file) = &self.declarative_file {
+            if is_config_change(path) && !path.starts_with("/acl/") {
+                warn!("Rejecting configuration change managed by {file}: {path}");
+                let body = format!("The configuration is managed by {file}; make changes there\n");
+                return build_response(StatusCode::FORBIDDEN, &body);
+            }
+        }
+        if is_config_change(path) || is_freezable(path) {
+            if let Some(response) = self.check_freeze(http_stream.req_header()) {
+                return response;
+            }
+        }
         match path {
+            "/customer/add" => self.add_customer(http_stream).await,
+            "/customer/delete" => self.delete_customer(http_stream).await,
             "/route/add" => self.add_route(http_stream).await,
             "/route/delete" => self.delete_route(http_stream).await,
+            "/route/import" => self.import_routes(http_stream).await,
             "/cert/add" => self.add_cert(http_stream).await,
             "/cert/delete" => self.delete_cert(http_stream).await,
+            "/credentials/add" => self.add_credential_list(http_stream).await,
+            "/credentials/delete" => self.delete_credential_list(http_stream).await,
+            "/wasm/add" => self.add_wasm_module(http_stream).await,
+            "/wasm/delete" => self.delete_wasm_module(http_stream).await,
+            "/acl/block" => self.block(http_stream).await,
+            "/acl/unblock" => self.unblock(http_stream).await,
+            "/acl/list" => self.list_blocked(http_stream),
+            "/stats" => self.stats(http_stream),
+            "/usage" => self.usage(http_stream),
+            "/version" => self.version(http_stream),
+            "/api/stats" => self.api_stats(http_stream),
+            "/status" => self.status(http_stream),
+            "/log/level" => self.log_level(http_stream).await,
+            "/ready" => self.ready(http_stream),
+            "/admin/drain" => self.drain(http_stream),
+            replication::SNAPSHOT_PATH => self.snapshot(http_stream),
+            "/fault/set" => self.set_fault(http_stream).await,
+            "/fault/clear" => self.clear_fault(http_stream).await,
+            "/fault/list" => self.list_faults(http_stream),
+            "/freeze/add" => self.add_freeze(http_stream).await,
+            "/freeze/delete" => self.delete_freeze(http_stream).await,
+            "/freeze/list" => self.list_freezes(http_stream),
             _ => {
                 error!("Unhandled path: {path}");
                 build_response(StatusCode::NOT_FOUND, "")
             }
         }
     }
-}
 
-impl ConfigApi {
-    pub fn new(route_holder: Arc<dyn RouteHolder>, cert_holder: Arc<dyn CertHolder>) -> Self {
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        customer_store: Arc<CustomerStore>,
+        require_customers: bool,
+        route_holder: Arc<dyn RouteHolder>,
+        cert_holder: Arc<dyn CertHolder>,
+        credential_holder: Arc<dyn CredentialHolder>,
+        wasm_holder: Arc<dyn WasmHolder>,
+        quota_tracker: Arc<QuotaTracker>,
+        acl_holder: Arc<dyn AclHolder>,
+        status_reporter: Arc<StatusReporter>,
+        request_tap: Arc<RequestTap>,
+        drainer: Arc<Drainer>,
+        replicator: Arc<Replicator>,
+        fault_injector: Arc<FaultInjector>,
+        freeze_config: &FreezeConfig,
+        usage_tracker: Arc<UsageTracker>,
+        declarative_config: &DeclarativeConfig,
+    ) -> Self {
+        // Entries loaded from the deny list file are part of the replicated configuration.
+        for entry in acl_holder.blocked() {
+            replicator.record(ConfigItem::Block(entry.to_string()));
+        }
         ConfigApi {
+            customer_store,
+            require_customers,
             route_holder,
             cert_holder,
+            credential_holder,
+            wasm_holder,
+            quota_tracker,
+            acl_holder,
+            status_reporter,
+            request_tap,
+            drainer,
+            replicator,
+            fault_injector,
+            freezer: Freezer::new(freeze_config),
+            usage_tracker,
+            api_counters: ApiCounters::new(),
+            declarative_file: declarative_config.file.clone(),
         }
     }
 
+    /// Reject a change during a freeze window (unless it carries a break-glass token).  Return the
+    /// response if rejected.
+    fn check_freeze(&self, req: &RequestHeader) -> Option<Response<Vec<u8>>> {
+        let window = self.freezer.active()?;
+        let path = req.uri.path();
+        let token = req.headers.get(freeze::BREAK_GLASS_HEADER);
+        if token.is_some_and(|token| self.freezer.is_break_glass(token.as_bytes())) {
+            warn!(
+                "Break-glass change during freeze '{}': {path}",
+                &window.name
+            );
+            return None;
+        }
+        warn!("Rejecting change during freeze '{}': {path}", &window.name);
+        let mut body = format!("Changes are frozen ({})", &window.name);
+        if !window.reason.is_empty() {
+            body = format!("{body}: {}", &window.reason);
+        }
+        Some(build_response(StatusCode::LOCKED, &format!("{body}\n")))
+    }
+
+    /// Apply a configuration item (adding or replacing it) and record it for replication.
+    /// Return an error if the item is invalid.
+    pub fn apply(&self, item: ConfigItem) -> Result<(), String> {
+        self.apply_and_report(item).map(|_| ())
+    }
+
+    /// Like `apply`, but also return the outcome of adding a certificate binding (`None` for
+    /// other items).
+    fn apply_and_report(&self, mut item: ConfigItem) -> Result<Option<CertSwap>, String> {
+        item.resolve_ttl(expiry::unix_time());
+        let mut swap = None;
+        match &item {
+            ConfigItem::Customer(customer) => {
+                info!("Adding customer '{}'", &customer.name);
+                self.quota_tracker
+                    .set_customer_limit(&customer.name, customer.quota.clone());
+                self.customer_store.add_customer(customer.as_ref().clone());
+            }
+            ConfigItem::Route(route) => {
+                self.customer_store
+                    .check_route(route, self.require_customers)?;
+                path_match::check_patterns(&route.paths)?;
+                info!(
+                    "Adding route '{}' for customer '{}'",
+                    &route.name, &route.customer
+                );
+                self.route_holder.add_route(route.as_ref().clone());
+            }
+            ConfigItem::Cert(binding) => {
+                let cert = X509::from_pem(binding.cert.as_bytes())
+                    .map_err(|_| "Failed to parse certificate".to_string())?;
+                let key = PKey::private_key_from_pem(binding.key.as_bytes())
+                    .map_err(|_| "Failed to parse private key".to_string())?;
+                info!("Adding cert for {}", &binding.host);
+                swap = Some(self.cert_holder.add_cert(
+                    &binding.host,
+                    cert,
+                    key,
+                    binding.invalidate_sessions,
+                ));
+            }
+            ConfigItem::Credentials(list) => {
+                info!("Adding credential list '{}'", &list.name);
+                self.credential_holder.add_credential_list(list.clone());
+            }
+            ConfigItem::Block(entry) => {
+                let entry = acl::parse_entry(entry).ok_or_else(|| {
+                    format!("Invalid IP address, CIDR block, or autonomous system: {entry}")
+                })?;
+                info!("Blocking {entry}");
+                self.acl_holder.block(entry);
+            }
+            ConfigItem::Wasm(module) => {
+                info!("Adding WASM filter '{}'", &module.name);
+                self.wasm_holder.add_module(module)?;
+            }
+        }
+        self.replicator.record(item);
+        Ok(swap)
+    }
+
+    /// Delete a configuration item and record the deletion for replication.
+    pub fn remove(&self, kind: ItemKind, id: &str) {
+        match kind {
+            ItemKind::Customer => {
+                info!("Deleting customer '{id}'");
+                self.quota_tracker.set_customer_limit(id, None);
+                self.customer_store.delete_customer(id);
+            }
+            ItemKind::Route => {
+                info!("Deleting route '{id}'");
+                self.route_holder.delete_route(id);
+            }
+            ItemKind::Cert => {
+                info!("Deleting cert for host {id}");
+                self.cert_holder.delete_cert(id);
+            }
+            ItemKind::Credentials => {
+                info!("Deleting credential list '{id}'");
+                self.credential_holder.delete_credential_list(id);
+            }
+            ItemKind::Block => match acl::parse_entry(id) {
+                Some(entry) => {
+                    info!("Unblocking {entry}");
+                    self.acl_holder.unblock(&entry);
+                }
+                None => error!("Invalid IP address, CIDR block, or autonomous system: {id}"),
+            },
+            ItemKind::Wasm => {
+                info!("Deleting WASM filter '{id}'");
+                self.wasm_holder.delete_module(id);
+            }
+        }
+        self.replicator.forget(kind, id);
+    }
+
+    /// Add or update (i.e., replace) a customer.
+    /// The request body should be a JSON object representing a CustomerConfig.
+    /// The request method should be POST.
+    async fn add_customer(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let method = &session.req_header().as_ref().method;
+        if method != http::Method::POST {
+            error!("Received unsupported method {method:?}");
+            return build_response(StatusCode::METHOD_NOT_ALLOWED, "");
+        }
+
+        let request_body = session.read_request_body().await.ok().flatten();
+        let Some(request_body) = request_body else {
+            error!("Unable to read request body");
+            return build_response(StatusCode::BAD_REQUEST, "");
+        };
+
+        let customer = serde_json::from_slice::<CustomerConfig>(&request_body);
+        let Ok(customer) = customer else {
+            error!("Failed to parse request body as CustomerConfig");
+            return build_response(StatusCode::BAD_REQUEST, "");
+        };
+
+        self.apply_item(ConfigItem::Customer(Box::new(customer)))
+    }
+
+    /// Delete a customer.  A customer can't be deleted while routes belong to it.
+    /// The request body should be the name of the customer to delete.
+    /// The request method should be POST.
+    async fn delete_customer(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let method = &session.req_header().as_ref().method;
+        if method != http::Method::POST {
+            error!("Received unsupported method {method:?}");
+            return build_response(StatusCode::METHOD_NOT_ALLOWED, "");
+        }
+
+        let request_body = session.read_request_body().await.ok().flatten();
+        let Some(request_body) = request_body else {
+            error!("Unable to read request body");
+            return build_response(StatusCode::BAD_REQUEST, "");
+        };
+
+        let Ok(name) = String::from_utf8(request_body.to_vec()) else {
+            error!("customer name not UTF-8");
+            return build_response(StatusCode::BAD_REQUEST, "");
+        };
+
+        let has_routes = self
+            .replicator
+            .snapshot()
+            .items
+            .iter()
+            .any(|item| matches!(item, ConfigItem::Route(route) if route.customer == name));
+        if has_routes {
+            error!("Customer '{name}' still has routes");
+            return build_response(StatusCode::CONFLICT, "Customer still has routes\n");
+        }
+
+        self.remove_item(ItemKind::Customer, &name)
+    }
+
     /// Add or update (i.e., replace) a route.
     /// The request body should be a JSON object representing a RouteConfig.
     /// The request method should be POST.
@@ -70,18 +472,31 @@ impl ConfigApi {
             return build_response(StatusCode::BAD_REQUEST, "");
         };
 
-        let route = serde_json::from_slice::<RouteConfig>(&request_body);
-        let Ok(route) = route else {
-            error!("Failed to parse request body as Route");
-            return build_response(StatusCode::BAD_REQUEST, "");
+        let route = match schema::parse_route(&request_body) {
+            Ok(route) => route,
+            Err(e) => {
+                error!("Failed to parse request body as Route: {e}");
+                return build_response(StatusCode::BAD_REQUEST, "");
+            }
         };
 
-        info!(
-            "Adding route '{}' for customer '{}'",
-            &route.name, &route.customer
-        );
-        self.route_holder.add_route(route);
+        self.apply_item(ConfigItem::Route(Box::new(route)))
+    }
+
+    /// Apply a configuration item received through the API and build the response.
+    fn apply_item(&self, item: ConfigItem) -> Response<Vec<u8>> {
+        match self.apply(item) {
+            Ok(()) => build_response(StatusCode::OK, "Success\n"),
+            Err(e) => {
+                error!("{e}");
+                build_response(StatusCode::BAD_REQUEST, "")
+            }
+        }
+    }
 
+    /// Delete a configuration item received through the API and build the response.
+    fn remove_item(&self, kind: ItemKind, id: &str) -> Response<Vec<u8>> {
+        self.remove(kind, id);
         build_response(StatusCode::OK, "Success\n")
     }
 
@@ -106,10 +521,51 @@ impl ConfigApi {
             return build_response(StatusCode::BAD_REQUEST, "");
         };
 
-        info!("Deleting route '{}'", &route_name);
-        self.route_holder.delete_route(&route_name);
+        self.remove_item(ItemKind::Route, &route_name)
+    }
 
-        build_response(StatusCode::OK, "Success\n")
+    /// Convert the configuration of another reverse proxy into routes, which are returned (as a
+    /// JSON array) rather than added, so they can be reviewed first.
+    /// The query string should have the `format` (`nginx` or `caddy`) and the `customer` the
+    /// routes are for.  The request body should be the configuration.
+    /// The request method should be POST.
+    async fn import_routes(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let method = &session.req_header().as_ref().method;
+        if method != http::Method::POST {
+            error!("Received unsupported method {method:?}");
+            return build_response(StatusCode::METHOD_NOT_ALLOWED, "");
+        }
+
+        let query = session.req_header().uri.query().unwrap_or_default();
+        let options = match ImportOptions::from_query(query) {
+            Ok(options) => options,
+            Err(e) => {
+                error!("Invalid import options: {e}");
+                return build_response(StatusCode::BAD_REQUEST, &format!("{e}\n"));
+            }
+        };
+
+        let Some(request_body) = read_whole_body(session).await else {
+            error!("Unable to read request body");
+            return build_response(StatusCode::BAD_REQUEST, "");
+        };
+        let Ok(config) = String::from_utf8(request_body) else {
+            error!("configuration not UTF-8");
+            return build_response(StatusCode::BAD_REQUEST, "");
+        };
+
+        let routes = match route_import::import(&config, &options) {
+            Ok(routes) => routes,
+            Err(e) => {
+                error!("Unable to import routes: {e}");
+                return build_response(StatusCode::BAD_REQUEST, &format!("{e}\n"));
+            }
+        };
+        let Ok(body) = serde_json::to_string_pretty(&routes) else {
+            error!("Failed to serialize imported routes");
+            return build_response(StatusCode::INTERNAL_SERVER_ERROR, "");
+        };
+        build_json_response(StatusCode::OK, &body)
     }
 
     /// Add a certificate.
@@ -128,33 +584,419 @@ impl ConfigApi {
             return build_response(StatusCode::BAD_REQUEST, "");
         };
 
-        let cert_binding = serde_json::from_slice::<CertBinding>(&request_body);
-        let Ok(cert_binding) = cert_binding else {
-            error!("Failed to parse request body as CertBinding");
+        let cert_binding = match schema::parse_cert_binding(&request_body) {
+            Ok(cert_binding) => cert_binding,
+            Err(e) => {
+                error!("Failed to parse request body as CertBinding: {e}");
+                return build_response(StatusCode::BAD_REQUEST, "");
+            }
+        };
+
+        // Report how the binding was swapped, so certificate rotations can be checked.
+        match self.apply_and_report(ConfigItem::Cert(cert_binding)) {
+            Ok(swap) => {
+                let body = serde_json::to_string(&swap.unwrap_or_default()).unwrap_or_default();
+                build_json_response(StatusCode::OK, &body)
+            }
+            Err(e) => {
+                error!("{e}");
+                build_response(StatusCode::BAD_REQUEST, "")
+            }
+        }
+    }
+
+    /// Delete a certificate.
+    /// The request body should be the hostname of the certificate to delete.
+    async fn delete_cert(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let method = &session.req_header().as_ref().method;
+        if method != http::Method::POST {
+            error!("Received unsupported method {method:?}");
+            return build_response(StatusCode::METHOD_NOT_ALLOWED, "");
+        }
+
+        let request_body = session.read_request_body().await.ok().flatten();
+        let Some(request_body) = request_body else {
+            error!("Unable to read request body");
+            return build_response(StatusCode::BAD_REQUEST, "");
+        };
+
+        let Ok(host) = String::from_utf8(request_body.to_vec()) else {
+            error!("hostname not UTF-8");
+            return build_response(StatusCode::BAD_REQUEST, "");
+        };
+
+        self.remove_item(ItemKind::Cert, &host)
+    }
+
+    /// Add or update (i.e., replace) a basic auth credential list.
+    /// The request body should be a JSON object representing a CredentialList.
+    /// The request method should be POST.
+    async fn add_credential_list(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let method = &session.req_header().as_ref().method;
+        if method != http::Method::POST {
+            error!("Received unsupported method {method:?}");
+            return build_response(StatusCode::METHOD_NOT_ALLOWED, "");
+        }
+
+        let request_body = session.read_request_body().await.ok().flatten();
+        let Some(request_body) = request_body else {
+            error!("Unable to read request body");
+            return build_response(StatusCode::BAD_REQUEST, "");
+        };
+
+        let list = serde_json::from_slice::<CredentialList>(&request_body);
+        let Ok(list) = list else {
+            error!("Failed to parse request body as CredentialList");
+            return build_response(StatusCode::BAD_REQUEST, "");
+        };
+
+        self.apply_item(ConfigItem::Credentials(list))
+    }
+
+    /// Delete a basic auth credential list.
+    /// The request body should be the name of the credential list to delete.
+    /// The request method should be POST.
+    async fn delete_credential_list(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let method = &session.req_header().as_ref().method;
+        if method != http::Method::POST {
+            error!("Received unsupported method {method:?}");
+            return build_response(StatusCode::METHOD_NOT_ALLOWED, "");
+        }
+
+        let request_body = session.read_request_body().await.ok().flatten();
+        let Some(request_body) = request_body else {
+            error!("Unable to read request body");
+            return build_response(StatusCode::BAD_REQUEST, "");
+        };
+
+        let Ok(name) = String::from_utf8(request_body.to_vec()) else {
+            error!("credential list name not UTF-8");
+            return build_response(StatusCode::BAD_REQUEST, "");
+        };
+
+        self.remove_item(ItemKind::Credentials, &name)
+    }
+
+    /// Add or update (i.e., replace) a WebAssembly filter.
+    /// The request body should be a JSON object representing a WasmModule.
+    /// The request method should be POST.
+    async fn add_wasm_module(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let method = &session.req_header().as_ref().method;
+        if method != http::Method::POST {
+            error!("Received unsupported method {method:?}");
+            return build_response(StatusCode::METHOD_NOT_ALLOWED, "");
+        }
+
+        // Modules are large, so their body usually arrives in several parts.
+        let Some(request_body) = read_whole_body(session).await else {
+            error!("Unable to read request body");
+            return build_response(StatusCode::BAD_REQUEST, "");
+        };
+
+        let module = serde_json::from_slice::<WasmModule>(&request_body);
+        let Ok(module) = module else {
+            error!("Failed to parse request body as WasmModule");
+            return build_response(StatusCode::BAD_REQUEST, "");
+        };
+
+        self.apply_item(ConfigItem::Wasm(module))
+    }
+
+    /// Delete a WebAssembly filter.
+    /// The request body should be the name of the filter to delete.
+    /// The request method should be POST.
+    async fn delete_wasm_module(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let method = &session.req_header().as_ref().method;
+        if method != http::Method::POST {
+            error!("Received unsupported method {method:?}");
+            return build_response(StatusCode::METHOD_NOT_ALLOWED, "");
+        }
+
+        let request_body = session.read_request_body().await.ok().flatten();
+        let Some(request_body) = request_body else {
+            error!("Unable to read request body");
+            return build_response(StatusCode::BAD_REQUEST, "");
+        };
+
+        let Ok(name) = String::from_utf8(request_body.to_vec()) else {
+            error!("WASM filter name not UTF-8");
             return build_response(StatusCode::BAD_REQUEST, "");
         };
 
-        let host = &cert_binding.host;
+        self.remove_item(ItemKind::Wasm, &name)
+    }
+
+    /// Add an IP address, CIDR block, or autonomous system to the deny list.
+    /// The request body should be the address (e.g., `192.0.2.1`), CIDR block (e.g.,
+    /// `192.0.2.0/24`), or autonomous system number (e.g., `AS64496`) to block.
+    /// The request method should be POST.
+    async fn block(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let method = &session.req_header().as_ref().method;
+        if method != http::Method::POST {
+            error!("Received unsupported method {method:?}");
+            return build_response(StatusCode::METHOD_NOT_ALLOWED, "");
+        }
 
-        let Ok(cert) = X509::from_pem(&cert_binding.cert.into_bytes()) else {
-            error!("Failed to parse certificate");
+        let request_body = session.read_request_body().await.ok().flatten();
+        let Some(request_body) = request_body else {
+            error!("Unable to read request body");
             return build_response(StatusCode::BAD_REQUEST, "");
         };
 
-        let Ok(key) = PKey::private_key_from_pem(&cert_binding.key.into_bytes()) else {
-            error!("Failed to parse private key");
+        let entry = std::str::from_utf8(&request_body)
+            .ok()
+            .and_then(acl::parse_entry);
+        let Some(entry) = entry else {
+            error!("Failed to parse request body as a deny list entry");
             return build_response(StatusCode::BAD_REQUEST, "");
         };
 
-        info!("Adding cert for {}", &cert_binding.host);
-        self.cert_holder.add_cert(host, cert, key);
+        self.apply_item(ConfigItem::Block(entry.to_string()))
+    }
 
+    /// Remove an IP address, CIDR block, or autonomous system from the deny list.
+    /// The request body should be the address, CIDR block, or autonomous system to unblock (exactly
+    /// as it was blocked).
+    /// The request method should be POST.
+    async fn unblock(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let method = &session.req_header().as_ref().method;
+        if method != http::Method::POST {
+            error!("Received unsupported method {method:?}");
+            return build_response(StatusCode::METHOD_NOT_ALLOWED, "");
+        }
+
+        let request_body = session.read_request_body().await.ok().flatten();
+        let Some(request_body) = request_body else {
+            error!("Unable to read request body");
+            return build_response(StatusCode::BAD_REQUEST, "");
+        };
+
+        let entry = std::str::from_utf8(&request_body)
+            .ok()
+            .and_then(acl::parse_entry);
+        let Some(entry) = entry else {
+            error!("Failed to parse request body as a deny list entry");
+            return build_response(StatusCode::BAD_REQUEST, "");
+        };
+
+        self.remove_item(ItemKind::Block, &entry.to_string())
+    }
+
+    /// List the deny list entries as a JSON array.
+    /// The request method should be GET.
+    fn list_blocked(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let method = &session.req_header().as_ref().method;
+        if method != http::Method::GET {
+            error!("Received unsupported method {method:?}");
+            return build_response(StatusCode::METHOD_NOT_ALLOWED, "");
+        }
+
+        let blocked: Vec<String> = self
+            .acl_holder
+            .blocked()
+            .iter()
+            .map(|entry| entry.to_string())
+            .collect();
+        build_json_response(StatusCode::OK, &serde_json::json!(blocked).to_string())
+    }
+
+    /// Report usage statistics (the quota counters and per-route cache statistics) as a JSON
+    /// object.
+    /// The request method should be GET.
+    fn stats(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let method = &session.req_header().as_ref().method;
+        if method != http::Method::GET {
+            error!("Received unsupported method {method:?}");
+            return build_response(StatusCode::METHOD_NOT_ALLOWED, "");
+        }
+
+        let stats = serde_json::json!({
+            "quotas": self.quota_tracker.stats(),
+            "cache": proxy::cache_report(HOT_KEYS_REPORTED),
+        });
+        build_json_response(StatusCode::OK, &stats.to_string())
+    }
+
+    /// Report the build version as a JSON VersionInfo object.
+    /// The request method should be GET.
+    fn version(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let method = &session.req_header().as_ref().method;
+        if method != http::Method::GET {
+            error!("Received unsupported method {method:?}");
+            return build_response(StatusCode::METHOD_NOT_ALLOWED, "");
+        }
+
+        let Ok(body) = serde_json::to_string(&VersionInfo::current()) else {
+            error!("Failed to serialize version");
+            return build_response(StatusCode::INTERNAL_SERVER_ERROR, "");
+        };
+        build_json_response(StatusCode::OK, &body)
+    }
+
+    /// Report the Config API's own statistics as a JSON ApiStats object.
+    /// The request method should be GET.
+    fn api_stats(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let method = &session.req_header().as_ref().method;
+        if method != http::Method::GET {
+            error!("Received unsupported method {method:?}");
+            return build_response(StatusCode::METHOD_NOT_ALLOWED, "");
+        }
+
+        let stats = self.api_counters.stats(
+            self.replicator.count(ItemKind::Route),
+            self.replicator.count(ItemKind::Cert),
+            self.replicator.count(ItemKind::Customer),
+        );
+        let Ok(body) = serde_json::to_string(&stats) else {
+            error!("Failed to serialize API stats");
+            return build_response(StatusCode::INTERNAL_SERVER_ERROR, "");
+        };
+        build_json_response(StatusCode::OK, &body)
+    }
+
+    /// Report the traffic of customers as a JSON UsageReport object.  The query string can select
+    /// a single `customer`.
+    /// The request method should be GET.
+    fn usage(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let method = &session.req_header().as_ref().method;
+        if method != http::Method::GET {
+            error!("Received unsupported method {method:?}");
+            return build_response(StatusCode::METHOD_NOT_ALLOWED, "");
+        }
+
+        let query = session.req_header().uri.query().unwrap_or_default();
+        let customer = query
+            .split('&')
+            .find_map(|param| param.strip_prefix("customer="))
+            .map(|value| String::from_utf8_lossy(&utils::percent_decode(value)).to_string());
+        let report = self.usage_tracker.report(customer.as_deref());
+        let Ok(body) = serde_json::to_string(&report) else {
+            error!("Failed to serialize usage");
+            return build_response(StatusCode::INTERNAL_SERVER_ERROR, "");
+        };
+        build_json_response(StatusCode::OK, &body)
+    }
+
+    /// Report the runtime status as a JSON object.
+    /// The request method should be GET.
+    fn status(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let method = &session.req_header().as_ref().method;
+        if method != http::Method::GET {
+            error!("Received unsupported method {method:?}");
+            return build_response(StatusCode::METHOD_NOT_ALLOWED, "");
+        }
+
+        let Ok(status) = serde_json::to_string(&self.status_reporter.status()) else {
+            error!("Failed to serialize status");
+            return build_response(StatusCode::INTERNAL_SERVER_ERROR, "");
+        };
+        build_json_response(StatusCode::OK, &status)
+    }
+
+    /// Report whether the server is ready for traffic: 200 if so, or 503 once draining has started.
+    /// Meant for load balancer health checks.
+    /// The request method should be GET.
+    fn ready(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let method = &session.req_header().as_ref().method;
+        if method != http::Method::GET {
+            error!("Received unsupported method {method:?}");
+            return build_response(StatusCode::METHOD_NOT_ALLOWED, "");
+        }
+
+        if self.drainer.is_draining() {
+            build_response(StatusCode::SERVICE_UNAVAILABLE, "Draining\n")
+        } else {
+            build_response(StatusCode::OK, "Ready\n")
+        }
+    }
+
+    /// Start draining: report not ready, stop accepting connections, let the requests in flight
+    /// finish (within the configured deadline), and exit.  The response is sent right away.
+    /// The request method should be POST.
+    fn drain(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let method = &session.req_header().as_ref().method;
+        if method != http::Method::POST {
+            error!("Received unsupported method {method:?}");
+            return build_response(StatusCode::METHOD_NOT_ALLOWED, "");
+        }
+
+        if self.drainer.start() {
+            build_response(StatusCode::ACCEPTED, "Draining\n")
+        } else {
+            build_response(StatusCode::OK, "Already draining\n")
+        }
+    }
+
+    /// Report the replicated configuration (customers, routes, certificates, credential lists, and
+    /// deny list entries) as a versioned JSON snapshot.  The version is also sent as the `ETag`,
+    /// and if it matches `If-None-Match`, a 304 is returned without the snapshot.
+    /// The request method should be GET.
+    fn snapshot(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let method = &session.req_header().as_ref().method;
+        if method != http::Method::GET {
+            error!("Received unsupported method {method:?}");
+            return build_response(StatusCode::METHOD_NOT_ALLOWED, "");
+        }
+
+        let snapshot = self.replicator.snapshot();
+        let if_none_match = session
+            .req_header()
+            .headers
+            .get(http::header::IF_NONE_MATCH);
+        let mut response =
+            if if_none_match.is_some_and(|v| v.as_bytes() == snapshot.version.as_bytes()) {
+                build_response(StatusCode::NOT_MODIFIED, "")
+            } else {
+                let Ok(body) = serde_json::to_string(&snapshot) else {
+                    error!("Failed to serialize the configuration snapshot");
+                    return build_response(StatusCode::INTERNAL_SERVER_ERROR, "");
+                };
+                build_json_response(StatusCode::OK, &body)
+            };
+        if let Ok(version) = http::HeaderValue::from_str(&snapshot.version) {
+            response.headers_mut().insert(http::header::ETAG, version);
+        }
+        response
+    }
+
+    /// Set (or replace) the faults injected into a route.  Faults aren't replicated: they apply to
+    /// this instance only.
+    /// The request body should be a JSON object representing a FaultConfig.
+    /// The request method should be POST.
+    async fn set_fault(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let method = &session.req_header().as_ref().method;
+        if method != http::Method::POST {
+            error!("Received unsupported method {method:?}");
+            return build_response(StatusCode::METHOD_NOT_ALLOWED, "");
+        }
+
+        let request_body = session.read_request_body().await.ok().flatten();
+        let Some(request_body) = request_body else {
+            error!("Unable to read request body");
+            return build_response(StatusCode::BAD_REQUEST, "");
+        };
+
+        let fault = serde_json::from_slice::<FaultConfig>(&request_body);
+        let Ok(fault) = fault else {
+            error!("Failed to parse request body as FaultConfig");
+            return build_response(StatusCode::BAD_REQUEST, "");
+        };
+        if let Some(status) = fault.error.as_ref().map(|error| error.status) {
+            if !(400..600).contains(&status) {
+                error!("Invalid fault status {status}");
+                return build_response(StatusCode::BAD_REQUEST, "");
+            }
+        }
+
+        warn!("Injecting faults into route '{}'", &fault.route);
+        self.fault_injector.set(fault);
         build_response(StatusCode::OK, "Success\n")
     }
 
-    /// Delete a certificate.
-    /// The request body should be the hostname of the certificate to delete.
-    async fn delete_cert(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+    /// Stop injecting faults into a route.
+    /// The request body should be the name of the route.
+    /// The request method should be POST.
+    async fn clear_fault(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
         let method = &session.req_header().as_ref().method;
         if method != http::Method::POST {
             error!("Received unsupported method {method:?}");
@@ -167,18 +1009,277 @@ impl ConfigApi {
             return build_response(StatusCode::BAD_REQUEST, "");
         };
 
-        let Ok(host) = String::from_utf8(request_body.to_vec()) else {
-            error!("hostname not UTF-8");
+        let Ok(route_name) = String::from_utf8(request_body.to_vec()) else {
+            error!("route name not UTF-8");
+            return build_response(StatusCode::BAD_REQUEST, "");
+        };
+
+        if self.fault_injector.clear(&route_name) {
+            warn!("Stopped injecting faults into route '{route_name}'");
+        }
+        build_response(StatusCode::OK, "Success\n")
+    }
+
+    /// List the faults injected into routes as a JSON array of FaultConfig objects.
+    /// The request method should be GET.
+    fn list_faults(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let method = &session.req_header().as_ref().method;
+        if method != http::Method::GET {
+            error!("Received unsupported method {method:?}");
+            return build_response(StatusCode::METHOD_NOT_ALLOWED, "");
+        }
+
+        let Ok(faults) = serde_json::to_string(&self.fault_injector.list()) else {
+            error!("Failed to serialize faults");
+            return build_response(StatusCode::INTERNAL_SERVER_ERROR, "");
+        };
+        build_json_response(StatusCode::OK, &faults)
+    }
+
+    /// Add (or replace) a change freeze window.  Windows aren't replicated: they protect the
+    /// changes made on this instance.
+    /// The request body should be a JSON object representing a FreezeWindow.
+    /// The request method should be POST.
+    async fn add_freeze(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let method = &session.req_header().as_ref().method;
+        if method != http::Method::POST {
+            error!("Received unsupported method {method:?}");
+            return build_response(StatusCode::METHOD_NOT_ALLOWED, "");
+        }
+
+        let request_body = session.read_request_body().await.ok().flatten();
+        let Some(request_body) = request_body else {
+            error!("Unable to read request body");
+            return build_response(StatusCode::BAD_REQUEST, "");
+        };
+
+        let window = serde_json::from_slice::<FreezeWindow>(&request_body);
+        let Ok(window) = window else {
+            error!("Failed to parse request body as FreezeWindow");
+            return build_response(StatusCode::BAD_REQUEST, "");
+        };
+
+        warn!("Adding change freeze window '{}'", &window.name);
+        self.freezer.add(window);
+        build_response(StatusCode::OK, "Success\n")
+    }
+
+    /// Delete a change freeze window.  (During a freeze, this takes a break-glass token like any
+    /// other change.)
+    /// The request body should be the name of the window.
+    /// The request method should be POST.
+    async fn delete_freeze(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let method = &session.req_header().as_ref().method;
+        if method != http::Method::POST {
+            error!("Received unsupported method {method:?}");
+            return build_response(StatusCode::METHOD_NOT_ALLOWED, "");
+        }
+
+        let request_body = session.read_request_body().await.ok().flatten();
+        let Some(request_body) = request_body else {
+            error!("Unable to read request body");
+            return build_response(StatusCode::BAD_REQUEST, "");
+        };
+
+        let Ok(name) = String::from_utf8(request_body.to_vec()) else {
+            error!("freeze window name not UTF-8");
+            return build_response(StatusCode::BAD_REQUEST, "");
+        };
+
+        if self.freezer.delete(&name) {
+            warn!("Deleted change freeze window '{name}'");
+        }
+        build_response(StatusCode::OK, "Success\n")
+    }
+
+    /// List the change freeze windows as a JSON object with the `windows` and the name of the one
+    /// `active` now (if any).
+    /// The request method should be GET.
+    fn list_freezes(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let method = &session.req_header().as_ref().method;
+        if method != http::Method::GET {
+            error!("Received unsupported method {method:?}");
+            return build_response(StatusCode::METHOD_NOT_ALLOWED, "");
+        }
+
+        let freezes = serde_json::json!({
+            "windows": self.freezer.list(),
+            "active": self.freezer.active().map(|window| window.name),
+        });
+        build_json_response(StatusCode::OK, &freezes.to_string())
+    }
+
+    /// Report the log level as a JSON object (GET), or change it (POST).
+    /// To change it, the request body should be the level for all modules (`off`, `error`, `warn`,
+    /// `info`, `debug`, or `trace`), or `reset` to go back to the `RUST_LOG` filter.
+    async fn log_level(&self, session: &mut ServerSession) -> Response<Vec<u8>> {
+        let method = &session.req_header().as_ref().method;
+        if method == http::Method::GET {
+            let level = serde_json::json!({
+                "level": log::max_level().to_string().to_lowercase(),
+                "overridden": logging::level_override().is_some(),
+            });
+            return build_json_response(StatusCode::OK, &level.to_string());
+        }
+        if method != http::Method::POST {
+            error!("Received unsupported method {method:?}");
+            return build_response(StatusCode::METHOD_NOT_ALLOWED, "");
+        }
+
+        let request_body = session.read_request_body().await.ok().flatten();
+        let Some(request_body) = request_body else {
+            error!("Unable to read request body");
             return build_response(StatusCode::BAD_REQUEST, "");
         };
 
-        info!("Deleting cert for host {}", &host);
-        self.cert_holder.delete_cert(&host);
+        let level = std::str::from_utf8(&request_body)
+            .map_err(|e| e.to_string())
+            .and_then(logging::parse_level);
+        let level = match level {
+            Ok(level) => level,
+            Err(e) => {
+                error!("Failed to parse request body as a log level: {e}");
+                return build_response(StatusCode::BAD_REQUEST, &format!("{e}\n"));
+            }
+        };
+
+        if let Err(e) = logging::set_level(level) {
+            error!("Unable to set the log level: {e}");
+            return build_response(StatusCode::INTERNAL_SERVER_ERROR, "");
+        }
+        match level {
+            Some(level) => warn!("Log level set to {level}"),
+            None => warn!("Log level reset to RUST_LOG"),
+        }
 
         build_response(StatusCode::OK, "Success\n")
     }
 }
 
+impl ConfigApi {
+    /// Stream summaries of finished requests to the client as server-sent events (one JSON object
+    /// per event) until the client disconnects or the server shuts down.
+    /// The query string can select requests by `route`, `status`, and `path` (a regular
+    /// expression) and set a `sample` rate (0 to 1).
+    /// The request method should be GET.
+    async fn tap(&self, session: &mut ServerSession, shutdown: &ShutdownWatch) {
+        session.set_keepalive(None);
+        let method = &session.req_header().as_ref().method;
+        if method != http::Method::GET {
+            error!("Received unsupported method {method:?}");
+            let response = build_response(StatusCode::METHOD_NOT_ALLOWED, "");
+            let _ = write_response(session, response).await;
+            return;
+        }
+        let query = session.req_header().uri.query().unwrap_or_default();
+        let filter = match TapFilter::from_query(query) {
+            Ok(filter) => filter,
+            Err(e) => {
+                error!("Invalid tap filter: {e}");
+                let response = build_response(StatusCode::BAD_REQUEST, &format!("{e}\n"));
+                let _ = write_response(session, response).await;
+                return;
+            }
+        };
+
+        info!("Starting request tap with filter {filter:?}");
+        let mut receiver = self.request_tap.subscribe();
+        let mut header = ResponseHeader::build(StatusCode::OK, Some(2)).unwrap();
+        header
+            .insert_header(http::header::CONTENT_TYPE, "text/event-stream")
+            .unwrap();
+        header
+            .insert_header(http::header::CACHE_CONTROL, "no-cache")
+            .unwrap();
+        if session
+            .write_response_header(Box::new(header))
+            .await
+            .is_err()
+        {
+            return;
+        }
+
+        let mut shutdown = shutdown.clone();
+        loop {
+            let event = tokio::select! {
+                summary = receiver.recv() => match summary {
+                    Ok(summary) if filter.matches(&summary) => {
+                        let Ok(json) = serde_json::to_string(&summary) else {
+                            continue;
+                        };
+                        format!("data: {json}\n\n")
+                    }
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(skipped)) => format!(": skipped {skipped}\n\n"),
+                    Err(RecvError::Closed) => break,
+                },
+                _ = tokio::time::sleep(TAP_KEEPALIVE_INTERVAL) => ": keepalive\n\n".to_string(),
+                _ = shutdown.changed() => break,
+            };
+            if session
+                .write_response_body(Bytes::from(event))
+                .await
+                .is_err()
+            {
+                break;
+            }
+        }
+        info!("Request tap ended");
+    }
+}
+
+/// Write a complete response to the client.
+async fn write_response(
+    session: &mut ServerSession,
+    response: Response<Vec<u8>>,
+) -> pingora::Result<()> {
+    let (parts, body) = response.into_parts();
+    let header: ResponseHeader = parts.into();
+    session.write_response_header(Box::new(header)).await?;
+    if !body.is_empty() {
+        session.write_response_body(body.into()).await?;
+    }
+    Ok(())
+}
+
+/// Whether the path is that of a configuration change (which is replicated from the leader).
+fn is_config_change(path: &str) -> bool {
+    matches!(
+        path,
+        "/route/add"
+            | "/route/delete"
+            | "/cert/add"
+            | "/cert/delete"
+            | "/customer/add"
+            | "/customer/delete"
+            | "/credentials/add"
+            | "/credentials/delete"
+            | "/wasm/add"
+            | "/wasm/delete"
+            | "/acl/block"
+            | "/acl/unblock"
+    )
+}
+
+/// Whether the request changes the proxy's behavior locally (without being a replicated
+/// configuration change), and is therefore subject to change freezes too.
+fn is_freezable(path: &str) -> bool {
+    matches!(path, "/fault/set" | "/fault/clear" | "/freeze/delete")
+}
+
+/// Read the whole request body (up to `MAX_BODY_SIZE`).  Return `None` if it couldn't be read, is
+/// empty, or is too large.
+async fn read_whole_body(session: &mut ServerSession) -> Option<Vec<u8>> {
+    let mut body = Vec::new();
+    while let Some(part) = session.read_request_body().await.ok()? {
+        body.extend_from_slice(&part);
+        if body.len() > MAX_BODY_SIZE {
+            return None;
+        }
+    }
+    (!body.is_empty()).then_some(body)
+}
+
 /// Utility function to construct a response byte array given a status code and body.
 fn build_response(status: StatusCode, body: &str) -> Response<Vec<u8>> {
     let body = body.as_bytes().to_vec();
@@ -189,3 +1290,14 @@ fn build_response(status: StatusCode, body: &str) -> Response<Vec<u8>> {
         .body(body)
         .unwrap()
 }
+
+/// Utility function to construct a JSON response byte array given a status code and body.
+fn build_json_response(status: StatusCode, body: &str) -> Response<Vec<u8>> {
+    let body = body.as_bytes().to_vec();
+    Response::builder()
+        .status(status)
+        .header(http::header::CONTENT_TYPE, "application/json")
+        .header(http::header::CONTENT_LENGTH, body.len())
+        .body(body)
+        .unwrap()
+}
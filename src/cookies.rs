@@ -0,0 +1,84 @@
+//! Cookie filtering.  A route can limit the cookies forwarded to the origin and strip `Set-Cookie`
+//! from responses, so caching can be enabled safely in front of applications that set session
+//! cookies on every response.
+
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::Result;
+use serde::{Deserialize, Serialize};
+
+/// A cookie policy for a route.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+#[serde(default)]
+pub struct CookiePolicy {
+    /// If not empty, only these cookies are forwarded to the origin.
+    pub allow: Vec<String>,
+
+    /// Cookies that are never forwarded to the origin.
+    pub strip: Vec<String>,
+
+    /// Whether to remove `Set-Cookie` headers from origin responses on caching routes (before the
+    /// response is cached).
+    pub strip_set_cookie: bool,
+}
+
+impl CookiePolicy {
+    fn forwards(&self, name: &str) -> bool {
+        (self.allow.is_empty() || self.allow.iter().any(|c| c == name))
+            && !self.strip.iter().any(|c| c == name)
+    }
+
+    /// Remove the cookies the origin shouldn't see from the upstream request.  All `Cookie`
+    /// headers are merged into one.
+    pub fn filter_request(&self, req: &mut RequestHeader) -> Result<()> {
+        let cookies: Vec<String> = req
+            .headers
+            .get_all(http::header::COOKIE)
+            .iter()
+            .filter_map(|v| v.to_str().ok())
+            .flat_map(|v| v.split(';'))
+            .map(str::trim)
+            .filter(|c| !c.is_empty())
+            .filter(|c| self.forwards(c.split_once('=').map_or(*c, |(name, _)| name).trim()))
+            .map(str::to_string)
+            .collect();
+
+        req.remove_header(&http::header::COOKIE);
+        if !cookies.is_empty() {
+            req.insert_header(http::header::COOKIE, cookies.join("; "))?;
+        }
+        Ok(())
+    }
+
+    /// Remove `Set-Cookie` from the response if the policy says so.
+    pub fn filter_response(&self, resp: &mut ResponseHeader) {
+        if self.strip_set_cookie {
+            resp.remove_header(&http::header::SET_COOKIE);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_request() {
+        let policy = CookiePolicy {
+            allow: vec!["session".to_string(), "lang".to_string()],
+            strip: vec!["lang".to_string()],
+            ..Default::default()
+        };
+
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.append_header("cookie", "session=abc; _ga=GA1.2")
+            .unwrap();
+        req.append_header("cookie", "lang=en").unwrap();
+        policy.filter_request(&mut req).unwrap();
+        assert_eq!(req.headers["cookie"], "session=abc");
+
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.append_header("cookie", "_ga=GA1.2").unwrap();
+        policy.filter_request(&mut req).unwrap();
+        assert!(!req.headers.contains_key("cookie"));
+    }
+}
@@ -0,0 +1,265 @@
+//! Cross-Origin Resource Sharing (CORS) policy enforcement.
+//!
+//! A route may carry a CORS policy.  Preflight requests (`OPTIONS` with an `Origin` and an
+//! `Access-Control-Request-Method` header) are answered by the proxy without contacting the origin,
+//! and the CORS response headers are added to all other responses for allowed origins.
+
+use http::{Method, StatusCode};
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::Result;
+use serde::{Deserialize, Serialize};
+
+/// A CORS policy attached to a route.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+#[serde(default)]
+pub struct CorsPolicy {
+    /// The origins (e.g., `https://app.example.com`) allowed to make cross-origin requests.
+    /// The special value `*` allows any origin.
+    pub allowed_origins: Vec<String>,
+
+    /// The methods allowed in cross-origin requests.  If empty, only the "simple" methods (GET,
+    /// HEAD, and POST) are allowed.
+    pub allowed_methods: Vec<String>,
+
+    /// The request headers allowed in cross-origin requests.  The special value `*` allows any
+    /// header.
+    pub allowed_headers: Vec<String>,
+
+    /// The response headers the browser is allowed to expose to the calling script.
+    pub exposed_headers: Vec<String>,
+
+    /// How long (in seconds) the browser may cache the result of a preflight request.
+    pub max_age: Option<u64>,
+
+    /// Whether the browser may send credentials (cookies, HTTP auth) with cross-origin requests.
+    pub allow_credentials: bool,
+}
+
+impl CorsPolicy {
+    /// Whether the given origin is allowed by this policy.
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|o| o == "*" || o.eq_ignore_ascii_case(origin))
+    }
+
+    /// Whether the given method is allowed by this policy.
+    pub fn allows_method(&self, method: &str) -> bool {
+        if self.allowed_methods.is_empty() {
+            return matches!(method, "GET" | "HEAD" | "POST");
+        }
+        self.allowed_methods
+            .iter()
+            .any(|m| m.eq_ignore_ascii_case(method))
+    }
+
+    /// Whether all the given (comma-separated) request headers are allowed by this policy.
+    pub fn allows_headers(&self, headers: &str) -> bool {
+        if self.allowed_headers.iter().any(|h| h == "*") {
+            return true;
+        }
+        headers
+            .split(',')
+            .map(str::trim)
+            .filter(|h| !h.is_empty())
+            .all(|h| {
+                self.allowed_headers
+                    .iter()
+                    .any(|a| a.eq_ignore_ascii_case(h))
+            })
+    }
+
+    /// The value to send in `Access-Control-Allow-Origin` for an allowed origin.
+    /// A wildcard is only echoed back as `*` when credentials aren't allowed (browsers reject a
+    /// wildcard with credentials).
+    fn allow_origin_value<'a>(&self, origin: &'a str) -> &'a str {
+        let wildcard = self.allowed_origins.iter().any(|o| o == "*");
+        if wildcard && !self.allow_credentials {
+            "*"
+        } else {
+            origin
+        }
+    }
+
+    /// Build the response to a preflight request.  If the origin, method, or headers aren't
+    /// allowed, a 403 response without any CORS headers is returned.
+    pub fn preflight_response(&self, req: &RequestHeader) -> Result<ResponseHeader> {
+        let origin = header_str(req, http::header::ORIGIN).unwrap_or_default();
+        let method =
+            header_str(req, http::header::ACCESS_CONTROL_REQUEST_METHOD).unwrap_or_default();
+        let headers =
+            header_str(req, http::header::ACCESS_CONTROL_REQUEST_HEADERS).unwrap_or_default();
+
+        if !self.allows_origin(origin)
+            || !self.allows_method(method)
+            || !self.allows_headers(headers)
+        {
+            return rejected_preflight_response();
+        }
+
+        let mut resp = ResponseHeader::build(StatusCode::NO_CONTENT, Some(8))?;
+        self.insert_common_headers(&mut resp, origin)?;
+
+        let methods = if self.allowed_methods.is_empty() {
+            "GET, HEAD, POST".to_string()
+        } else {
+            self.allowed_methods.join(", ")
+        };
+        resp.insert_header(http::header::ACCESS_CONTROL_ALLOW_METHODS, methods)?;
+
+        if !headers.is_empty() {
+            // Either everything is allowed or every requested header was checked above, so echo
+            // back the requested headers.
+            resp.insert_header(http::header::ACCESS_CONTROL_ALLOW_HEADERS, headers)?;
+        }
+        if let Some(max_age) = self.max_age {
+            resp.insert_header(http::header::ACCESS_CONTROL_MAX_AGE, max_age)?;
+        }
+        resp.insert_header(http::header::CONTENT_LENGTH, 0)?;
+        Ok(resp)
+    }
+
+    /// Add the CORS headers to an actual (non-preflight) response, if the request came from an
+    /// allowed origin.
+    pub fn apply_response_headers(
+        &self,
+        req: &RequestHeader,
+        resp: &mut ResponseHeader,
+    ) -> Result<()> {
+        let Some(origin) = header_str(req, http::header::ORIGIN) else {
+            return Ok(());
+        };
+        if !self.allows_origin(origin) {
+            return Ok(());
+        }
+
+        self.insert_common_headers(resp, origin)?;
+        if !self.exposed_headers.is_empty() {
+            resp.insert_header(
+                http::header::ACCESS_CONTROL_EXPOSE_HEADERS,
+                self.exposed_headers.join(", "),
+            )?;
+        }
+        Ok(())
+    }
+
+    fn insert_common_headers(&self, resp: &mut ResponseHeader, origin: &str) -> Result<()> {
+        let allow_origin = self.allow_origin_value(origin);
+        resp.insert_header(
+            http::header::ACCESS_CONTROL_ALLOW_ORIGIN,
+            allow_origin.to_string(),
+        )?;
+        if allow_origin != "*" {
+            // The response differs by origin, so caches must key on it.
+            resp.append_header(http::header::VARY, "Origin")?;
+        }
+        if self.allow_credentials {
+            resp.insert_header(http::header::ACCESS_CONTROL_ALLOW_CREDENTIALS, "true")?;
+        }
+        Ok(())
+    }
+}
+
+/// The response to a preflight request that isn't allowed: a 403 without any CORS headers.
+pub fn rejected_preflight_response() -> Result<ResponseHeader> {
+    let mut resp = ResponseHeader::build(StatusCode::FORBIDDEN, Some(2))?;
+    resp.insert_header(http::header::CONTENT_LENGTH, 0)?;
+    Ok(resp)
+}
+
+/// Whether the request is a CORS preflight request.
+pub fn is_preflight(req: &RequestHeader) -> bool {
+    req.method == Method::OPTIONS
+        && req.headers.contains_key(http::header::ORIGIN)
+        && req
+            .headers
+            .contains_key(http::header::ACCESS_CONTROL_REQUEST_METHOD)
+}
+
+fn header_str(req: &RequestHeader, name: http::header::HeaderName) -> Option<&str> {
+    req.headers.get(name).and_then(|v| v.to_str().ok())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn policy() -> CorsPolicy {
+        CorsPolicy {
+            allowed_origins: vec!["https://app.example.com".to_string()],
+            allowed_methods: vec!["GET".to_string(), "PUT".to_string()],
+            allowed_headers: vec!["Content-Type".to_string()],
+            max_age: Some(600),
+            ..Default::default()
+        }
+    }
+
+    fn preflight(origin: &str, method: &str, headers: &str) -> RequestHeader {
+        let mut req = RequestHeader::build(Method::OPTIONS, b"/", None).unwrap();
+        req.insert_header("origin", origin.to_string()).unwrap();
+        req.insert_header("access-control-request-method", method.to_string())
+            .unwrap();
+        if !headers.is_empty() {
+            req.insert_header("access-control-request-headers", headers.to_string())
+                .unwrap();
+        }
+        req
+    }
+
+    #[test]
+    fn preflight_allowed() {
+        let req = preflight("https://app.example.com", "PUT", "content-type");
+        assert!(is_preflight(&req));
+        let resp = policy().preflight_response(&req).unwrap();
+        assert_eq!(resp.status, StatusCode::NO_CONTENT);
+        assert_eq!(
+            resp.headers.get("access-control-allow-origin").unwrap(),
+            "https://app.example.com"
+        );
+        assert_eq!(resp.headers.get("access-control-max-age").unwrap(), "600");
+    }
+
+    #[test]
+    fn preflight_rejected() {
+        let policy = policy();
+        let req = preflight("https://evil.example.com", "PUT", "");
+        assert_eq!(
+            policy.preflight_response(&req).unwrap().status,
+            StatusCode::FORBIDDEN
+        );
+        let req = preflight("https://app.example.com", "DELETE", "");
+        assert_eq!(
+            policy.preflight_response(&req).unwrap().status,
+            StatusCode::FORBIDDEN
+        );
+        let req = preflight("https://app.example.com", "GET", "x-secret");
+        assert_eq!(
+            policy.preflight_response(&req).unwrap().status,
+            StatusCode::FORBIDDEN
+        );
+    }
+
+    #[test]
+    fn wildcard_with_credentials_echoes_origin() {
+        let policy = CorsPolicy {
+            allowed_origins: vec!["*".to_string()],
+            allow_credentials: true,
+            ..Default::default()
+        };
+        let mut req = RequestHeader::build(Method::GET, b"/", None).unwrap();
+        req.insert_header("origin", "https://a.example.com")
+            .unwrap();
+        let mut resp = ResponseHeader::build(StatusCode::OK, None).unwrap();
+        policy.apply_response_headers(&req, &mut resp).unwrap();
+        assert_eq!(
+            resp.headers.get("access-control-allow-origin").unwrap(),
+            "https://a.example.com"
+        );
+        assert_eq!(
+            resp.headers
+                .get("access-control-allow-credentials")
+                .unwrap(),
+            "true"
+        );
+    }
+}
@@ -0,0 +1,175 @@
+//! Customers: the tenants that routes belong to.
+//!
+//! A customer is managed through the Config API and referenced by the `customer` field of its
+//! routes.  It limits the hosts its routes may serve, can carry a quota (replacing any configured
+//! for it in the app config), and provides defaults for settings its routes leave unset.
+//!
+//! Routes of customers that aren't defined are accepted as before, unless the Config API is
+//! configured to require customers.
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use crate::cookies::CookiePolicy;
+use crate::log_sinks::LogSinkConfig;
+use crate::quota::QuotaLimit;
+use crate::rate_limit::RateLimitPolicy;
+use crate::route_config::RouteConfig;
+use crate::security_headers::SecurityHeadersPolicy;
+
+/// An interface for adding and deleting customers.
+pub trait CustomerHolder: Send + Sync {
+    fn add_customer(&self, customer: CustomerConfig);
+    fn delete_customer(&self, name: &str);
+}
+
+/// A customer, as pushed through the Config API.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct CustomerConfig {
+    /// The customer's name, as used in the `customer` field of its routes.
+    pub name: String,
+
+    /// The hosts the customer's routes may serve.  Each is an exact host or a wildcard (e.g.,
+    /// `*.example.com`), which also allows wildcards under it.  If empty, any host is allowed.
+    #[serde(default)]
+    pub allowed_hosts: Vec<String>,
+
+    /// Limits on the customer's requests and response bytes per quota window.
+    pub quota: Option<QuotaLimit>,
+
+    /// Settings for the customer's routes that don't define their own.
+    #[serde(default)]
+    pub defaults: RouteDefaults,
+}
+
+/// Route settings that a customer provides to its routes.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+#[serde(default)]
+pub struct RouteDefaults {
+    pub rate_limit: Option<RateLimitPolicy>,
+    pub security_headers: Option<SecurityHeadersPolicy>,
+    pub cookies: Option<CookiePolicy>,
+    pub log_sink: Option<LogSinkConfig>,
+}
+
+impl CustomerConfig {
+    /// Whether the customer's routes may serve the host.
+    pub fn allows_host(&self, host: &str) -> bool {
+        if self.allowed_hosts.is_empty() {
+            return true;
+        }
+        let host = host.to_ascii_lowercase();
+        self.allowed_hosts.iter().any(|allowed| {
+            let allowed = allowed.to_ascii_lowercase();
+            match allowed.strip_prefix("*.") {
+                Some(domain) => host
+                    .strip_suffix(domain)
+                    .is_some_and(|prefix| prefix.ends_with('.')),
+                None => host == allowed,
+            }
+        })
+    }
+}
+
+/// A store of customers indexed by name.
+pub struct CustomerStore {
+    inner: RwLock<HashMap<String, Arc<CustomerConfig>>>,
+}
+
+impl CustomerStore {
+    pub fn new() -> Self {
+        CustomerStore {
+            inner: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Get a customer by name.
+    pub fn get(&self, name: &str) -> Option<Arc<CustomerConfig>> {
+        let inner = self.inner.read().unwrap();
+        inner.get(name).cloned()
+    }
+
+    /// Check that a route may be added: its hosts must be allowed by its customer, and, if
+    /// `required`, the customer must be defined.
+    pub fn check_route(&self, route: &RouteConfig, required: bool) -> Result<(), String> {
+        let Some(customer) = self.get(&route.customer) else {
+            if required {
+                return Err(format!(
+                    "Route '{}' belongs to undefined customer '{}'",
+                    &route.name, &route.customer
+                ));
+            }
+            return Ok(());
+        };
+        match route.hosts.iter().find(|host| !customer.allows_host(host)) {
+            Some(host) => Err(format!(
+                "Customer '{}' isn't allowed host {host} (route '{}')",
+                &customer.name, &route.name
+            )),
+            None => Ok(()),
+        }
+    }
+}
+
+impl CustomerHolder for CustomerStore {
+    /// Add or replace a customer.
+    fn add_customer(&self, customer: CustomerConfig) {
+        let mut inner = self.inner.write().unwrap();
+        inner.insert(customer.name.clone(), Arc::new(customer));
+    }
+
+    /// Delete a customer (if it exists).
+    fn delete_customer(&self, name: &str) {
+        let mut inner = self.inner.write().unwrap();
+        if inner.remove(name).is_none() {
+            warn!("Attempted to delete a customer that doesn't exist name={name}");
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_route() {
+        let store = CustomerStore::new();
+        store.add_customer(
+            serde_json::from_value(serde_json::json!({
+                "name": "acme",
+                "allowed_hosts": ["acme.com", "*.acme.net"]
+            }))
+            .unwrap(),
+        );
+        let route = |customer: &str, host: &str| -> RouteConfig {
+            serde_json::from_value(serde_json::json!({
+                "name": "r",
+                "customer": customer,
+                "hosts": [host],
+                "paths": ["/"],
+                "incoming_schemes": ["Https"],
+                "outgoing_scheme": "Https",
+                "origin_group": {"origins": [{"host": "origin.com"}]}
+            }))
+            .unwrap()
+        };
+
+        assert!(store.check_route(&route("acme", "acme.com"), true).is_ok());
+        assert!(store
+            .check_route(&route("acme", "www.acme.net"), true)
+            .is_ok());
+        assert!(store
+            .check_route(&route("acme", "*.eu.acme.net"), true)
+            .is_ok());
+        assert!(store.check_route(&route("acme", "acme.net"), true).is_err());
+        assert!(store.check_route(&route("acme", "evil.com"), true).is_err());
+        assert!(store
+            .check_route(&route("other", "evil.com"), false)
+            .is_ok());
+        assert!(store
+            .check_route(&route("other", "evil.com"), true)
+            .is_err());
+    }
+}
@@ -0,0 +1,122 @@
+//! Debug response headers.  They tell a client how long the origin took to respond, which origin
+//! served the request, and how many times connecting to an origin was retried, so customers can
+//! tell the proxy's latency from the origin's without access to the server logs.  Since they reveal
+//! the origins, they're only added for trusted clients (or for every client in debug mode).
+//!
+//! Tracing headers sent by clients (e.g., `x-request-id`) can also be echoed in the response, for
+//! every client.
+
+use http::header::HeaderName;
+use ipnet::IpNet;
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::Result;
+use serde::{Deserialize, Serialize};
+use std::net::IpAddr;
+use std::time::Duration;
+
+use crate::acl;
+use crate::route_config::Origin;
+
+/// Debug header settings.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+#[serde(default)]
+pub struct DebugHeadersConfig {
+    /// Whether to add the debug headers to every response (e.g., in a staging environment).
+    pub always: bool,
+
+    /// The client addresses (IP addresses or CIDR blocks) the debug headers are added for.
+    pub trusted_clients: Vec<String>,
+
+    /// Request headers echoed in the response (e.g., `x-request-id` or `traceparent`).
+    pub echo: Vec<String>,
+}
+
+/// Adds the debug and echoed headers to responses.
+#[derive(Debug)]
+pub struct DebugHeaders {
+    always: bool,
+    trusted_clients: Vec<IpNet>,
+    echo: Vec<HeaderName>,
+}
+
+impl DebugHeaders {
+    /// The addresses and header names must have been validated (see `AppConfig`).
+    pub fn new(config: &DebugHeadersConfig) -> Self {
+        DebugHeaders {
+            always: config.always,
+            trusted_clients: config
+                .trusted_clients
+                .iter()
+                .filter_map(|net| acl::parse_net(net))
+                .collect(),
+            echo: config
+                .echo
+                .iter()
+                .filter_map(|name| HeaderName::from_bytes(name.as_bytes()).ok())
+                .collect(),
+        }
+    }
+
+    /// Whether the debug headers are added for the client.
+    pub fn applies_to(&self, client: Option<IpAddr>) -> bool {
+        self.always
+            || client.is_some_and(|ip| self.trusted_clients.iter().any(|net| net.contains(&ip)))
+    }
+
+    /// Copy the echoed headers from the request to the response.
+    pub fn echo(&self, req: &RequestHeader, resp: &mut ResponseHeader) -> Result<()> {
+        for name in &self.echo {
+            if let Some(value) = req.headers.get(name) {
+                resp.insert_header(name.clone(), value.clone())?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Add the debug headers describing the request to the origin (if one was made).
+    pub fn add(
+        resp: &mut ResponseHeader,
+        origin: Option<&Origin>,
+        tries: u16,
+        upstream_latency: Option<Duration>,
+    ) -> Result<()> {
+        let Some(origin) = origin else {
+            return Ok(());
+        };
+        resp.insert_header("x-origin", origin.host.as_str())?;
+        resp.insert_header("x-origin-retries", tries.saturating_sub(1))?;
+        if let Some(latency) = upstream_latency {
+            resp.insert_header("x-origin-latency", latency.as_millis().to_string())?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn headers() {
+        let debug = DebugHeaders::new(&DebugHeadersConfig {
+            trusted_clients: vec!["10.0.0.0/8".to_string()],
+            echo: vec!["x-request-id".to_string()],
+            ..Default::default()
+        });
+        assert!(debug.applies_to(Some("10.1.2.3".parse().unwrap())));
+        assert!(!debug.applies_to(Some("192.0.2.1".parse().unwrap())));
+        assert!(!debug.applies_to(None));
+
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header("x-request-id", "abc").unwrap();
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        debug.echo(&req, &mut resp).unwrap();
+        assert_eq!(resp.headers["x-request-id"], "abc");
+
+        let origin: Origin = serde_json::from_str(r#"{"host": "origin.example.com"}"#).unwrap();
+        DebugHeaders::add(&mut resp, Some(&origin), 2, Some(Duration::from_millis(42))).unwrap();
+        assert_eq!(resp.headers["x-origin"], "origin.example.com");
+        assert_eq!(resp.headers["x-origin-retries"], "1");
+        assert_eq!(resp.headers["x-origin-latency"], "42");
+    }
+}
@@ -0,0 +1,302 @@
+//! Declarative configuration: the customers, routes, certificates, credential lists, and WASM
+//! filters are described in a file instead of being pushed through the Config API, which suits
+//! small installations managed like any other configuration file.
+//!
+//! The file is loaded at startup and checked every reload interval.  Each time, its items are
+//! compared with the configuration in effect, and only the differences are applied: new and
+//! changed items are added (or replaced), and items no longer in the file are deleted.  While a
+//! file is in use, the Config API rejects changes to these items (blocked addresses are left to
+//! the API and the deny list file).
+//!
+//! Certificates and WASM modules are referenced by path (relative to the file's directory), so
+//! replacing a certificate file is picked up like a change to the file itself.
+
+use async_trait::async_trait;
+use base64::{engine::general_purpose::STANDARD, Engine};
+use log::{info, warn};
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::basic_auth::CredentialList;
+use crate::cert::cert_config::CertBinding;
+use crate::config_api::ConfigApi;
+use crate::customer::CustomerConfig;
+use crate::expiry;
+use crate::replication::{ConfigItem, ItemKind, Replicator, Snapshot};
+use crate::route_config::RouteConfig;
+use crate::schema;
+use crate::wasm::{self, WasmModule};
+
+/// Declarative configuration settings.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct DeclarativeConfig {
+    /// The path to the file (YAML) describing the configuration.  If not set, the configuration is
+    /// managed through the Config API.
+    pub file: Option<String>,
+
+    /// How often (in seconds) the file is checked for changes.
+    pub reload_interval: u64,
+}
+
+impl Default for DeclarativeConfig {
+    /// By default, there is no file, and it would be checked every 10 seconds.
+    fn default() -> Self {
+        DeclarativeConfig {
+            file: None,
+            reload_interval: 10,
+        }
+    }
+}
+
+/// The contents of a declarative configuration file.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+#[serde(default)]
+pub struct DeclarativeFile {
+    pub customers: Vec<CustomerConfig>,
+    #[serde(deserialize_with = "schema::deserialize_routes")]
+    pub routes: Vec<RouteConfig>,
+    pub certs: Vec<CertRef>,
+    pub credentials: Vec<CredentialList>,
+    pub wasm: Vec<WasmRef>,
+}
+
+/// A certificate binding, with the certificate and key in PEM files.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct CertRef {
+    pub host: String,
+    pub cert: String,
+    pub key: String,
+    #[serde(default)]
+    pub expires_at: Option<u64>,
+}
+
+/// A WASM filter, with the module in a binary file.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct WasmRef {
+    pub name: String,
+    pub file: String,
+    #[serde(default = "wasm::default_fuel")]
+    pub fuel: u64,
+    #[serde(default = "wasm::default_max_memory")]
+    pub max_memory: usize,
+}
+
+/// The kinds of items the file manages.
+const MANAGED: [ItemKind; 5] = [
+    ItemKind::Customer,
+    ItemKind::Route,
+    ItemKind::Cert,
+    ItemKind::Credentials,
+    ItemKind::Wasm,
+];
+
+impl DeclarativeFile {
+    /// Read the file and the files it references, and return its items (in the order they must be
+    /// applied).
+    pub fn load(path: &str) -> Result<Vec<ConfigItem>, String> {
+        let contents =
+            fs::read_to_string(path).map_err(|e| format!("Unable to read {path}: {e}"))?;
+        let file: DeclarativeFile =
+            serde_yaml::from_str(&contents).map_err(|e| format!("Unable to parse {path}: {e}"))?;
+        let dir = Path::new(path).parent().unwrap_or(Path::new("."));
+        file.items(dir)
+    }
+
+    /// The file's items, with the files they reference (relative to `dir`) read.
+    fn items(self, dir: &Path) -> Result<Vec<ConfigItem>, String> {
+        let read = |file: &str| -> Result<Vec<u8>, String> {
+            let path: PathBuf = dir.join(file);
+            fs::read(&path).map_err(|e| format!("Unable to read {}: {e}", path.display()))
+        };
+        let text = |file: &str| -> Result<String, String> {
+            String::from_utf8(read(file)?).map_err(|_| format!("{file} isn't a PEM file"))
+        };
+
+        let mut items = Vec::new();
+        items.extend(
+            self.customers
+                .into_iter()
+                .map(|customer| ConfigItem::Customer(Box::new(customer))),
+        );
+        for route in self.routes {
+            if route.ttl.is_some() {
+                return Err(format!(
+                    "Route '{}': ttl isn't supported in a configuration file (use expires_at)",
+                    route.name
+                ));
+            }
+            items.push(ConfigItem::Route(Box::new(route)));
+        }
+        for cert in self.certs {
+            items.push(ConfigItem::Cert(CertBinding {
+                version: Some(schema::CERT_VERSION),
+                cert: text(&cert.cert)?,
+                key: text(&cert.key)?,
+                host: cert.host,
+                ttl: None,
+                expires_at: cert.expires_at,
+                invalidate_sessions: false,
+            }));
+        }
+        items.extend(self.credentials.into_iter().map(ConfigItem::Credentials));
+        for wasm in self.wasm {
+            items.push(ConfigItem::Wasm(WasmModule {
+                module: STANDARD.encode(read(&wasm.file)?),
+                name: wasm.name,
+                fuel: wasm.fuel,
+                max_memory: wasm.max_memory,
+            }));
+        }
+
+        // Expired items would be deleted (and then added back) over and over.
+        let now = expiry::unix_time();
+        items.retain(|item| item.expires_at().is_none_or(|expires_at| expires_at > now));
+        items.sort_by_key(|item| item.key().0);
+        Ok(items)
+    }
+}
+
+/// Applies the declarative configuration file's changes.
+pub struct Reconciler {
+    config: DeclarativeConfig,
+    replicator: Arc<Replicator>,
+    config_api: Arc<ConfigApi>,
+}
+
+impl Reconciler {
+    pub fn new(
+        config: &DeclarativeConfig,
+        replicator: Arc<Replicator>,
+        config_api: Arc<ConfigApi>,
+    ) -> Self {
+        Reconciler {
+            config: config.clone(),
+            replicator,
+            config_api,
+        }
+    }
+
+    /// Load the file and apply its differences with the configuration in effect.  Return an error
+    /// (and leave the configuration as is) if the file can't be loaded.  Items that can't be
+    /// applied are skipped (with a warning).
+    pub fn reconcile(&self) -> Result<(), String> {
+        let Some(path) = &self.config.file else {
+            return Ok(());
+        };
+        let items = DeclarativeFile::load(path)?;
+        let (changed, stale) = self.replicator.diff(Snapshot {
+            version: String::new(),
+            items,
+        });
+        let stale: Vec<_> = stale
+            .into_iter()
+            .filter(|(kind, _)| MANAGED.contains(kind))
+            .collect();
+        if changed.is_empty() && stale.is_empty() {
+            return Ok(());
+        }
+        info!(
+            "Applying {path}: {} added or replaced, {} deleted",
+            changed.len(),
+            stale.len()
+        );
+        for item in changed {
+            if let Err(e) = self.config_api.apply(item) {
+                warn!("Unable to apply configuration from {path}: {e}");
+            }
+        }
+        // Routes go before their customers.
+        for (kind, id) in stale.into_iter().rev() {
+            self.config_api.remove(kind, &id);
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl BackgroundService for Reconciler {
+    /// Check the file for changes every reload interval until shutdown.
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let interval = Duration::from_secs(self.config.reload_interval.max(1));
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = shutdown.changed() => break,
+            }
+            if let Err(e) = self.reconcile() {
+                warn!("{e}; keeping the current configuration");
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn items() {
+        let dir = std::env::temp_dir().join(format!("granite-decl-{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("www.pem"), "CERT").unwrap();
+        fs::write(dir.join("www.key"), "KEY").unwrap();
+        let file: DeclarativeFile = serde_yaml::from_str(
+            r#"
+            routes:
+              - name: r1
+                customer: c1
+                hosts: [www.example.com]
+                paths: [/]
+                incoming_schemes: [Https]
+                outgoing_scheme: Https
+                origin_group: {origins: [{host: origin.example.com}]}
+              - name: expired
+                customer: c1
+                hosts: [old.example.com]
+                paths: [/]
+                incoming_schemes: [Https]
+                outgoing_scheme: Https
+                origin_group: {origins: [{host: origin.example.com}]}
+                expires_at: 1
+            certs:
+              - {host: www.example.com, cert: www.pem, key: www.key}
+            customers:
+              - name: c1
+            "#,
+        )
+        .unwrap();
+
+        let items = file.clone().items(&dir).unwrap();
+        let keys: Vec<_> = items.iter().map(|item| item.key()).collect();
+        assert_eq!(
+            keys,
+            [
+                (ItemKind::Customer, "c1"),
+                (ItemKind::Route, "r1"),
+                (ItemKind::Cert, "www.example.com")
+            ]
+        );
+        let ConfigItem::Cert(binding) = &items[2] else {
+            panic!("Expected a cert binding");
+        };
+        assert_eq!(
+            (binding.cert.as_str(), binding.key.as_str()),
+            ("CERT", "KEY")
+        );
+
+        let mut with_ttl = file.clone();
+        with_ttl.routes[0].ttl = Some(60);
+        assert!(with_ttl.items(&dir).is_err());
+        let mut missing = file;
+        missing.certs[0].key = "missing.key".to_string();
+        assert!(missing.items(&dir).is_err());
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
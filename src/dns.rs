@@ -0,0 +1,189 @@
+//! A shared, caching DNS resolver for origin hostnames.
+//!
+//! Answers are cached (honoring their TTLs), so most requests don't wait for DNS at all.
+//! Concurrent lookups of the same hostname are deduplicated: one query is sent and every waiting
+//! request gets its answer.  If a lookup fails (e.g., the DNS server is slow or down), the last
+//! address successfully resolved for the hostname is used instead, so a DNS outage doesn't take
+//! down origins that are still reachable.
+
+use hickory_resolver::config::{ResolverConfig, ResolverOpts};
+use hickory_resolver::system_conf::read_system_conf;
+use hickory_resolver::TokioAsyncResolver;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::OnceCell;
+
+/// DNS resolver settings.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct DnsConfig {
+    /// How long (in milliseconds) to wait for a DNS server to answer before retrying.
+    pub timeout: u64,
+
+    /// The number of attempts to query DNS servers before a lookup fails.
+    pub attempts: usize,
+
+    /// The maximum number of cached answers.
+    pub cache_size: usize,
+
+    /// Whether to use the last successfully resolved address when a lookup fails.
+    pub stale_fallback: bool,
+}
+
+impl Default for DnsConfig {
+    /// By default, wait 2 seconds per attempt, make 2 attempts, cache 1024 answers, and fall back
+    /// to the last known address.
+    fn default() -> Self {
+        DnsConfig {
+            timeout: 2000,
+            attempts: 2,
+            cache_size: 1024,
+            stale_fallback: true,
+        }
+    }
+}
+
+/// The outcome of a lookup, shared by all the requests waiting for it.
+type SharedLookup = Arc<OnceCell<Result<IpAddr, String>>>;
+
+/// Resolves origin hostnames to addresses.
+pub struct DnsResolver {
+    resolver: TokioAsyncResolver,
+    stale_fallback: bool,
+    /// Lookups in progress, keyed by hostname.
+    in_flight: Mutex<HashMap<String, SharedLookup>>,
+    /// The last address successfully resolved for each hostname.
+    last_known: Mutex<HashMap<String, IpAddr>>,
+}
+
+impl DnsResolver {
+    /// Create a resolver that uses the system's DNS configuration (`/etc/resolv.conf`) or, if it
+    /// can't be read, public DNS servers.
+    pub fn new(config: &DnsConfig) -> Self {
+        let (resolver_config, mut opts) = read_system_conf().unwrap_or_else(|e| {
+            warn!("Unable to read the system DNS configuration ({e}); using defaults");
+            (ResolverConfig::default(), ResolverOpts::default())
+        });
+        opts.timeout = Duration::from_millis(config.timeout);
+        opts.attempts = config.attempts;
+        opts.cache_size = config.cache_size;
+        DnsResolver {
+            resolver: TokioAsyncResolver::tokio(resolver_config, opts),
+            stale_fallback: config.stale_fallback,
+            in_flight: Mutex::new(HashMap::new()),
+            last_known: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Resolve a hostname (or parse an IP address) to an address.
+    pub async fn resolve(&self, host: &str) -> Result<IpAddr, String> {
+        if let Ok(addr) = host.parse::<IpAddr>() {
+            return Ok(addr);
+        }
+
+        let lookup = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .entry(host.to_string())
+            .or_default()
+            .clone();
+        let result = lookup.get_or_init(|| self.lookup(host)).await.clone();
+        // The first request to finish removes the lookup (unless a new one already replaced it).
+        let mut in_flight = self.in_flight.lock().unwrap();
+        if in_flight
+            .get(host)
+            .is_some_and(|current| Arc::ptr_eq(current, &lookup))
+        {
+            in_flight.remove(host);
+        }
+        result
+    }
+
+    /// Find the hostnames an address points to (PTR records).
+    pub async fn reverse_lookup(&self, ip: IpAddr) -> Result<Vec<String>, String> {
+        let names = self
+            .resolver
+            .reverse_lookup(ip)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(names
+            .iter()
+            .map(|name| name.to_utf8().trim_end_matches('.').to_string())
+            .collect())
+    }
+
+    /// Find all the addresses of a hostname (bypassing the stale fallback).
+    pub async fn resolve_all(&self, host: &str) -> Result<Vec<IpAddr>, String> {
+        let ips = self
+            .resolver
+            .lookup_ip(host)
+            .await
+            .map_err(|e| e.to_string())?;
+        Ok(ips.iter().collect())
+    }
+
+    /// Query DNS (or the resolver's cache), falling back to the last known address on failure.
+    async fn lookup(&self, host: &str) -> Result<IpAddr, String> {
+        debug!("Resolving {host}");
+        let answer = self
+            .resolver
+            .lookup_ip(host)
+            .await
+            .map_err(|e| e.to_string())
+            .and_then(|ips| {
+                ips.iter()
+                    .next()
+                    .ok_or_else(|| "No address found".to_string())
+            });
+        match answer {
+            Ok(addr) => {
+                self.last_known
+                    .lock()
+                    .unwrap()
+                    .insert(host.to_string(), addr);
+                Ok(addr)
+            }
+            Err(e) => match self.last_known.lock().unwrap().get(host) {
+                Some(&addr) if self.stale_fallback => {
+                    warn!("Unable to resolve {host} ({e}); using last known address {addr}");
+                    Ok(addr)
+                }
+                _ => Err(e),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn fallback() {
+        let resolver = DnsResolver::new(&DnsConfig {
+            timeout: 100,
+            attempts: 1,
+            ..Default::default()
+        });
+        assert_eq!(
+            resolver.resolve("192.0.2.1").await,
+            Ok("192.0.2.1".parse().unwrap())
+        );
+
+        // The `.invalid` top-level domain never resolves.
+        assert!(resolver.resolve("origin.invalid").await.is_err());
+        let known: IpAddr = "192.0.2.2".parse().unwrap();
+        resolver
+            .last_known
+            .lock()
+            .unwrap()
+            .insert("origin.invalid".to_string(), known);
+        assert_eq!(resolver.resolve("origin.invalid").await, Ok(known));
+        assert!(resolver.in_flight.lock().unwrap().is_empty());
+    }
+}
@@ -0,0 +1,154 @@
+//! Graceful draining for zero-error rolling deploys.
+//!
+//! Draining (started through the config API) proceeds in steps:
+//! 1. Readiness flips to false, so the load balancer stops sending new connections, and responses
+//!    ask clients to close their keep-alive connections.
+//! 2. After `drain.delay` seconds (time for the load balancer to notice), the server stops
+//!    accepting new connections.
+//! 3. Requests in flight (including the cache writes they drive) are given up to `drain.deadline`
+//!    seconds to finish, and then the process exits.
+
+use log::{info, warn};
+use nix::sys::signal::{self, Signal};
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+/// How often to check whether the requests in flight have finished.
+const POLL_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Drain settings.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct DrainConfig {
+    /// How long (in seconds) to keep accepting connections after readiness flips to false.
+    pub delay: u64,
+
+    /// How long (in seconds) requests in flight are given to finish once new connections are no
+    /// longer accepted.
+    pub deadline: u64,
+}
+
+impl Default for DrainConfig {
+    /// By default, wait 5 seconds for the load balancer to notice, and then up to 30 seconds for
+    /// requests in flight to finish.
+    fn default() -> Self {
+        DrainConfig {
+            delay: 5,
+            deadline: 30,
+        }
+    }
+}
+
+/// Tracks requests in flight and runs the drain sequence.
+pub struct Drainer {
+    config: DrainConfig,
+    draining: AtomicBool,
+    in_flight: Arc<AtomicUsize>,
+}
+
+/// Counts a request as in flight until dropped (along with the request's context).
+#[derive(Debug)]
+pub struct InFlight(Arc<AtomicUsize>);
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+impl Drainer {
+    pub fn new(config: &DrainConfig) -> Self {
+        Drainer {
+            config: config.clone(),
+            draining: AtomicBool::new(false),
+            in_flight: Arc::new(AtomicUsize::new(0)),
+        }
+    }
+
+    /// Count a request as in flight until the returned guard is dropped.
+    pub fn track(&self) -> InFlight {
+        self.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(self.in_flight.clone())
+    }
+
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.load(Ordering::Relaxed)
+    }
+
+    pub fn is_draining(&self) -> bool {
+        self.draining.load(Ordering::Relaxed)
+    }
+
+    /// Start draining in the background.  Return `false` if draining had already started.
+    pub fn start(self: &Arc<Self>) -> bool {
+        if self.draining.swap(true, Ordering::Relaxed) {
+            return false;
+        }
+        warn!(
+            "Draining: no longer ready; stopping new connections in {}s",
+            self.config.delay
+        );
+        tokio::spawn(self.clone().drain());
+        true
+    }
+
+    async fn drain(self: Arc<Self>) {
+        tokio::time::sleep(Duration::from_secs(self.config.delay)).await;
+
+        // The server stops accepting connections when it receives SIGTERM.
+        info!(
+            "Draining: stopping new connections; waiting up to {}s for {} requests in flight",
+            self.config.deadline,
+            self.in_flight()
+        );
+        if let Err(e) = signal::raise(Signal::SIGTERM) {
+            warn!("Unable to signal the server to stop accepting connections: {e}");
+        }
+
+        if self.wait_for_requests().await {
+            info!("Draining: all requests finished; exiting");
+        } else {
+            warn!(
+                "Draining: deadline reached with {} requests in flight; exiting",
+                self.in_flight()
+            );
+        }
+        std::process::exit(0);
+    }
+
+    /// Wait until no requests are in flight (returning `true`) or the deadline passes.
+    async fn wait_for_requests(&self) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(self.config.deadline);
+        while self.in_flight() > 0 {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            tokio::time::sleep(POLL_INTERVAL).await;
+        }
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn wait_for_requests() {
+        let drainer = Drainer::new(&DrainConfig {
+            delay: 0,
+            deadline: 0,
+        });
+        let first = drainer.track();
+        let second = drainer.track();
+        assert_eq!(drainer.in_flight(), 2);
+        assert!(!drainer.wait_for_requests().await);
+
+        drop(first);
+        drop(second);
+        assert_eq!(drainer.in_flight(), 0);
+        assert!(drainer.wait_for_requests().await);
+    }
+}
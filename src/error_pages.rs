@@ -0,0 +1,98 @@
+//! Custom error pages for errors generated by the proxy (e.g., 404 when no route matches, 502 when
+//! the origin is unreachable, 429 when a rate limit is exceeded).
+//!
+//! Error pages are keyed by status code and can be set globally (in the proxy configuration) and
+//! per route.  A route's pages take precedence over the global ones.  The page body is a template
+//! in which the following variables are substituted:
+//! - `{status}`: the status code
+//! - `{reason}`: the canonical reason phrase for the status code (e.g., `Not Found`)
+//! - `{request_id}`: the request ID
+//! - `{host}`: the requested host
+//! - `{path}`: the requested path
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// Error pages keyed by status code.
+pub type ErrorPages = HashMap<u16, ErrorPage>;
+
+/// A custom error page.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct ErrorPage {
+    /// The body template.
+    pub body: String,
+
+    /// The content type of the body.
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+}
+
+fn default_content_type() -> String {
+    "text/html; charset=utf-8".to_string()
+}
+
+/// The values substituted into an error page template.
+pub struct ErrorVars<'a> {
+    pub status: u16,
+    pub request_id: &'a str,
+    pub host: &'a str,
+    pub path: &'a str,
+}
+
+impl ErrorPage {
+    /// Render the body template with the given variables.  Substituted values are HTML-escaped if
+    /// the content type is HTML.
+    pub fn render(&self, vars: &ErrorVars) -> String {
+        let html = self.content_type.starts_with("text/html");
+        let escape = |s: &str| if html { html_escape(s) } else { s.to_string() };
+        let reason = http::StatusCode::from_u16(vars.status)
+            .ok()
+            .and_then(|s| s.canonical_reason())
+            .unwrap_or_default();
+
+        self.body
+            .replace("{status}", &vars.status.to_string())
+            .replace("{reason}", reason)
+            .replace("{request_id}", &escape(vars.request_id))
+            .replace("{host}", &escape(vars.host))
+            .replace("{path}", &escape(vars.path))
+    }
+}
+
+fn html_escape(s: &str) -> String {
+    let mut escaped = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            _ => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let pages: ErrorPages = serde_json::from_str(
+            r#"{"404": {"body": "<h1>{status} {reason}</h1><p>{path} ({request_id})</p>"}}"#,
+        )
+        .unwrap();
+        let vars = ErrorVars {
+            status: 404,
+            request_id: "abc123",
+            host: "example.com",
+            path: "/<script>",
+        };
+        assert_eq!(
+            pages[&404].render(&vars),
+            "<h1>404 Not Found</h1><p>/&lt;script&gt; (abc123)</p>"
+        );
+    }
+}
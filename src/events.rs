@@ -0,0 +1,339 @@
+//! Structured events for notable decisions of the proxy (origins marked down or up, requests that
+//! exhausted their retries, requests for hosts without routes), published to an external event
+//! stream so the health of origins can be analyzed across the fleet without scraping logs.
+//!
+//! An event sink is one of:
+//! - an HTTP(S) endpoint the events are POSTed to in batches (newline-delimited JSON), e.g., a
+//!   collector or a Kafka REST proxy,
+//! - a NATS server, with each event published to a subject.
+//!
+//! Each event is a JSON object with its `event` type, the `time` it happened, the `instance` (and
+//! `pop`) that made the decision, and the event's details.  Like log sinks, the sink has a writer
+//! thread, and events are dropped (and counted) rather than buffered without bound if it falls
+//! behind or can't be reached.
+
+use chrono::{SecondsFormat, Utc};
+use http::Uri;
+use log::{error, info, warn};
+use once_cell::sync::OnceCell;
+use pingora::connectors::http::Connector;
+use serde::{Deserialize, Serialize};
+use std::io::{self, ErrorKind, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::thread;
+use std::time::Duration;
+
+use crate::instance::Instance;
+use crate::log_sinks;
+
+/// The number of events that can be queued for the writer thread.
+const QUEUE_SIZE: usize = 8192;
+
+/// How long to wait for the NATS server.
+const NATS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often the NATS writer checks for pings while no events are sent.
+const NATS_IDLE_CHECK: Duration = Duration::from_secs(1);
+
+static SINK: OnceCell<EventSink> = OnceCell::new();
+
+/// Event settings.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+#[serde(default)]
+pub struct EventsConfig {
+    /// Where events are published.  If not set, no events are published.
+    pub sink: Option<EventSinkConfig>,
+}
+
+/// Where events are published.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub enum EventSinkConfig {
+    /// An HTTP(S) endpoint the events are POSTed to.
+    Http {
+        url: String,
+        /// The maximum number of events per request.
+        #[serde(default = "default_batch_size")]
+        batch_size: usize,
+    },
+
+    /// A NATS server (`host:port`) the events are published to.
+    Nats {
+        addr: String,
+        /// The subject the events are published to.
+        #[serde(default = "default_subject")]
+        subject: String,
+    },
+}
+
+fn default_batch_size() -> usize {
+    100
+}
+
+fn default_subject() -> String {
+    "granite.events".to_string()
+}
+
+/// A notable decision.
+#[derive(Serialize, Debug, PartialEq, Eq, Clone)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    /// An origin was marked down after failed connection attempts (`dns` if its hostname couldn't
+    /// be resolved).
+    OriginDown {
+        route: &'a str,
+        origin: &'a str,
+        failures: u32,
+        dns: bool,
+    },
+
+    /// An origin marked down became eligible again.
+    OriginUp { route: &'a str, origin: &'a str },
+
+    /// A request failed to connect to its origin and wasn't retried, because of the retry limit
+    /// or an exhausted retry budget.
+    RetriesExhausted {
+        route: &'a str,
+        origin: &'a str,
+        tries: u16,
+        reason: &'static str,
+    },
+
+    /// No route matched the request.
+    RouteNotFound { host: &'a str, path: &'a str },
+}
+
+/// An event with its context, as published.
+#[derive(Serialize)]
+struct Envelope<'a> {
+    #[serde(flatten)]
+    event: Event<'a>,
+    time: String,
+    instance: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pop: Option<&'a str>,
+}
+
+/// The open event sink.
+struct EventSink {
+    sender: SyncSender<String>,
+    dropped: AtomicU64,
+    instance: String,
+    pop: Option<String>,
+}
+
+/// Open the event sink (if one is configured) and start its writer thread.
+pub fn init(config: &EventsConfig, instance: &Instance) -> io::Result<()> {
+    let Some(sink) = &config.sink else {
+        return Ok(());
+    };
+    let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+    match sink {
+        EventSinkConfig::Http { url, batch_size } => {
+            let url: Uri = url
+                .parse()
+                .map_err(|e| io::Error::new(ErrorKind::InvalidInput, e))?;
+            let runtime = tokio::runtime::Builder::new_current_thread()
+                .enable_all()
+                .build()?;
+            let batch_size = (*batch_size).max(1);
+            info!("Publishing events to {url}");
+            thread::spawn(move || post_events(runtime, url, batch_size, receiver));
+        }
+        EventSinkConfig::Nats { addr, subject } => {
+            info!("Publishing events to NATS subject '{subject}' at {addr}");
+            let (addr, subject) = (addr.clone(), subject.clone());
+            thread::spawn(move || publish_events(&addr, &subject, receiver));
+        }
+    }
+    let _ = SINK.set(EventSink {
+        sender,
+        dropped: AtomicU64::new(0),
+        instance: instance.id().to_string(),
+        pop: instance.pop().map(str::to_string),
+    });
+    Ok(())
+}
+
+/// Publish an event (if an event sink is configured).
+pub fn emit(event: Event) {
+    let Some(sink) = SINK.get() else {
+        return;
+    };
+    let envelope = Envelope {
+        event,
+        time: Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true),
+        instance: &sink.instance,
+        pop: sink.pop.as_deref(),
+    };
+    let Ok(line) = serde_json::to_string(&envelope) else {
+        return;
+    };
+    if let Err(TrySendError::Full(_)) = sink.sender.try_send(line) {
+        let dropped = sink.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+        if dropped.is_power_of_two() {
+            warn!("Event sink is falling behind; {dropped} events dropped so far");
+        }
+    }
+}
+
+/// The HTTP sink's writer thread: POST the events in batches.
+fn post_events(
+    runtime: tokio::runtime::Runtime,
+    url: Uri,
+    batch_size: usize,
+    receiver: Receiver<String>,
+) {
+    let connector = Connector::new(None);
+    while let Ok(event) = receiver.recv() {
+        let mut batch = event;
+        batch.push('\n');
+        for _ in 1..batch_size {
+            let Ok(event) = receiver.try_recv() else {
+                break;
+            };
+            batch.push_str(&event);
+            batch.push('\n');
+        }
+        let post = log_sinks::post(&connector, &url, "application/x-ndjson", batch);
+        if let Err(e) = runtime.block_on(post) {
+            error!("Unable to send events to {url}: {e}");
+        }
+    }
+}
+
+/// The NATS sink's writer thread: publish each event, reconnecting as needed.  Events are dropped
+/// while the server can't be reached.
+fn publish_events(addr: &str, subject: &str, receiver: Receiver<String>) {
+    let mut connection: Option<NatsConnection> = None;
+    loop {
+        let event = match receiver.recv_timeout(NATS_IDLE_CHECK) {
+            Ok(event) => Some(event),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => return,
+        };
+        if connection.is_none() && event.is_some() {
+            connection = match NatsConnection::connect(addr) {
+                Ok(connection) => Some(connection),
+                Err(e) => {
+                    error!("Unable to connect to NATS server {addr}: {e}");
+                    None
+                }
+            };
+        }
+        let Some(conn) = connection.as_mut() else {
+            continue;
+        };
+        let result = conn.answer_pings().and_then(|_| match &event {
+            Some(event) => conn.publish(subject, event),
+            None => Ok(()),
+        });
+        if let Err(e) = result {
+            error!("Unable to publish event to NATS server {addr}: {e}");
+            connection = None;
+        }
+    }
+}
+
+/// A connection to a NATS server, speaking just enough of the protocol to publish.
+struct NatsConnection {
+    stream: TcpStream,
+}
+
+impl NatsConnection {
+    fn connect(addr: &str) -> io::Result<Self> {
+        let addr = addr
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(ErrorKind::NotFound, "No address found"))?;
+        let mut stream = TcpStream::connect_timeout(&addr, NATS_TIMEOUT)?;
+        stream.set_write_timeout(Some(NATS_TIMEOUT))?;
+        stream.write_all(
+            b"CONNECT {\"verbose\":false,\"pedantic\":false,\"name\":\"granite\"}\r\n",
+        )?;
+        Ok(NatsConnection { stream })
+    }
+
+    fn publish(&mut self, subject: &str, payload: &str) -> io::Result<()> {
+        let message = format!("PUB {subject} {}\r\n{payload}\r\n", payload.len());
+        self.stream.write_all(message.as_bytes())
+    }
+
+    /// Answer the server's pings (which it sends to check the connection is alive), skipping
+    /// everything else it sent.
+    fn answer_pings(&mut self) -> io::Result<()> {
+        self.stream.set_nonblocking(true)?;
+        let mut received = Vec::new();
+        let mut buf = [0; 4096];
+        let result = loop {
+            match self.stream.read(&mut buf) {
+                Ok(0) => break Err(io::Error::from(ErrorKind::ConnectionAborted)),
+                Ok(len) => received.extend_from_slice(&buf[..len]),
+                Err(e) if e.kind() == ErrorKind::WouldBlock => break Ok(()),
+                Err(e) => break Err(e),
+            }
+        };
+        self.stream.set_nonblocking(false)?;
+        result?;
+        let pings = received
+            .split(|&b| b == b'\n')
+            .filter(|line| line.starts_with(b"PING"))
+            .count();
+        for _ in 0..pings {
+            self.stream.write_all(b"PONG\r\n")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::{BufRead, BufReader};
+    use std::net::TcpListener;
+
+    #[test]
+    fn envelope() {
+        let envelope = Envelope {
+            event: Event::OriginDown {
+                route: "r1",
+                origin: "origin.example.com",
+                failures: 3,
+                dns: false,
+            },
+            time: "2024-05-01T00:00:00.000Z".to_string(),
+            instance: "i1",
+            pop: None,
+        };
+        assert_eq!(
+            serde_json::to_value(&envelope).unwrap(),
+            serde_json::json!({
+                "event": "origin_down",
+                "route": "r1",
+                "origin": "origin.example.com",
+                "failures": 3,
+                "dns": false,
+                "time": "2024-05-01T00:00:00.000Z",
+                "instance": "i1",
+            })
+        );
+    }
+
+    #[test]
+    fn nats() {
+        let server = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = server.local_addr().unwrap().to_string();
+        let (sender, receiver) = mpsc::sync_channel(1);
+        let publisher = thread::spawn(move || publish_events(&addr, "events", receiver));
+        sender.send(r#"{"event":"origin_up"}"#.to_string()).unwrap();
+
+        let (stream, _) = server.accept().unwrap();
+        let mut lines = BufReader::new(stream).lines();
+        assert!(lines.next().unwrap().unwrap().starts_with("CONNECT "));
+        assert_eq!(lines.next().unwrap().unwrap(), "PUB events 21");
+        assert_eq!(lines.next().unwrap().unwrap(), r#"{"event":"origin_up"}"#);
+        drop(sender);
+        publisher.join().unwrap();
+    }
+}
@@ -0,0 +1,68 @@
+//! Expiry of routes and certificate bindings added with a TTL (e.g., for short-lived preview
+//! environments), so the control plane doesn't have to delete them explicitly.
+//!
+//! A TTL is turned into an expiry time when the item is added, and the expiry time is replicated
+//! along with the item.  The sweeper deletes expired items through the config API (and so from the
+//! followers as well), logging and counting each deletion.  Followers don't sweep: they delete
+//! expired items when the leader does.
+
+use async_trait::async_trait;
+use log::info;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::config_api::ConfigApi;
+use crate::metrics;
+use crate::replication::Replicator;
+
+/// How often expired items are looked for.
+const SWEEP_INTERVAL: Duration = Duration::from_secs(5);
+
+/// The current time, in seconds since the Unix epoch.
+pub fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Deletes expired routes and certificate bindings.
+pub struct Sweeper {
+    replicator: Arc<Replicator>,
+    config_api: Arc<ConfigApi>,
+}
+
+impl Sweeper {
+    pub fn new(replicator: Arc<Replicator>, config_api: Arc<ConfigApi>) -> Self {
+        Sweeper {
+            replicator,
+            config_api,
+        }
+    }
+
+    /// Delete the items that expired by `now`.  Return how many were deleted.
+    pub fn sweep(&self, now: u64) -> usize {
+        let expired = self.replicator.expired(now);
+        for (kind, id) in &expired {
+            info!("The {} '{id}' expired", kind.as_str());
+            metrics::config_item_expired(kind.as_str());
+            self.config_api.remove(*kind, id);
+        }
+        expired.len()
+    }
+}
+
+#[async_trait]
+impl BackgroundService for Sweeper {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(SWEEP_INTERVAL) => {}
+                _ = shutdown.changed() => break,
+            }
+            self.sweep(unix_time());
+        }
+    }
+}
@@ -0,0 +1,140 @@
+//! Static failover responses.  A route can define a last-resort response that is sent instead of
+//! an error when none of its origins can be reached (and there is no stale cache entry to serve),
+//! e.g., a maintenance page.
+//!
+//! The body is either given inline or read from a file when the route is added (so later changes
+//! to the file need the route to be added again).
+
+use bytes::Bytes;
+use pingora::http::ResponseHeader;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+
+/// The largest body file a failover response can use.
+const MAX_FILE_SIZE: u64 = 1024 * 1024;
+
+/// A failover response, as configured.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct FailoverConfig {
+    /// The response's status code.
+    #[serde(default = "default_status")]
+    pub status: u16,
+
+    /// Headers added to the response (e.g., `retry-after`).
+    #[serde(default)]
+    pub headers: BTreeMap<String, String>,
+
+    /// The body.
+    #[serde(default)]
+    pub body: String,
+
+    /// A file to read the body from (instead of `body`).
+    pub file: Option<String>,
+
+    /// The content type of the body.
+    #[serde(default = "default_content_type")]
+    pub content_type: String,
+}
+
+fn default_status() -> u16 {
+    503
+}
+
+fn default_content_type() -> String {
+    "text/html; charset=utf-8".to_string()
+}
+
+/// A failover response, with its body loaded when the route is parsed.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(try_from = "FailoverConfig", into = "FailoverConfig")]
+pub struct FailoverResponse {
+    config: FailoverConfig,
+    body: Bytes,
+}
+
+impl TryFrom<FailoverConfig> for FailoverResponse {
+    type Error = String;
+
+    fn try_from(config: FailoverConfig) -> Result<Self, Self::Error> {
+        if http::StatusCode::from_u16(config.status).is_err() {
+            return Err(format!("Invalid failover status {}", config.status));
+        }
+        let body = match config.file.as_ref() {
+            Some(path) => {
+                let len = fs::metadata(path)
+                    .map_err(|e| format!("Unable to read failover file {path}: {e}"))?
+                    .len();
+                if len > MAX_FILE_SIZE {
+                    return Err(format!("Failover file {path} is larger than 1 MiB"));
+                }
+                fs::read(path)
+                    .map_err(|e| format!("Unable to read failover file {path}: {e}"))?
+                    .into()
+            }
+            None => Bytes::from(config.body.clone()),
+        };
+        Ok(FailoverResponse { config, body })
+    }
+}
+
+impl From<FailoverResponse> for FailoverConfig {
+    fn from(resp: FailoverResponse) -> Self {
+        resp.config
+    }
+}
+
+impl FailoverResponse {
+    /// Whether the failover response replaces an error response with the status.
+    pub fn replaces(status: u16) -> bool {
+        matches!(status, 502..=504)
+    }
+
+    /// The response to send.  It isn't cached by clients or shared caches, unless its headers say
+    /// otherwise.
+    pub fn response(&self) -> pingora::Result<(ResponseHeader, Bytes)> {
+        let mut resp = ResponseHeader::build(self.config.status, None)?;
+        resp.insert_header(http::header::CACHE_CONTROL, "private, no-store")?;
+        resp.insert_header(
+            http::header::CONTENT_TYPE,
+            self.config.content_type.as_str(),
+        )?;
+        for (name, value) in &self.config.headers {
+            resp.insert_header(name.clone(), value.as_str())?;
+        }
+        resp.insert_header(http::header::CONTENT_LENGTH, self.body.len())?;
+        Ok((resp, self.body.clone()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn response() {
+        let path = std::env::temp_dir().join(format!("granite-failover-{}", std::process::id()));
+        fs::write(&path, "<h1>Back soon</h1>").unwrap();
+        let failover: FailoverResponse = serde_json::from_value(serde_json::json!({
+            "file": path.display().to_string(),
+            "headers": {"retry-after": "120"}
+        }))
+        .unwrap();
+        fs::remove_file(&path).unwrap();
+
+        let (resp, body) = failover.response().unwrap();
+        assert_eq!(resp.status, 503);
+        assert_eq!(resp.headers["retry-after"], "120");
+        assert_eq!(resp.headers["content-length"], "18");
+        assert_eq!(body, "<h1>Back soon</h1>");
+
+        assert!(serde_json::from_value::<FailoverResponse>(
+            serde_json::json!({"file": "/nonexistent/granite-failover"})
+        )
+        .is_err());
+        assert!(
+            serde_json::from_value::<FailoverResponse>(serde_json::json!({"status": 1000}))
+                .is_err()
+        );
+    }
+}
@@ -0,0 +1,168 @@
+//! Fault injection for testing the resilience of clients.
+//!
+//! Faults are set per route through the Config API (and can be cleared just as quickly).  Each kind
+//! of fault applies to a percentage of the route's requests, chosen at random: added latency, an
+//! error response generated by the proxy, or an aborted connection (closed without a response).
+//! Faults are local to the instance they are set on.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::RwLock;
+use std::time::Duration;
+
+/// The faults to inject into a route's requests.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct FaultConfig {
+    /// The name of the route.
+    pub route: String,
+
+    /// Add latency to some requests (before they are forwarded).
+    pub delay: Option<DelayFault>,
+
+    /// Respond to some requests with an error instead of forwarding them.
+    pub error: Option<ErrorFault>,
+
+    /// Close the connection of some requests without responding.
+    pub abort: Option<AbortFault>,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct DelayFault {
+    /// The percentage (0-100) of requests delayed.
+    pub percent: f64,
+
+    /// The delay (in milliseconds).
+    pub duration: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct ErrorFault {
+    /// The percentage (0-100) of requests that get an error.
+    pub percent: f64,
+
+    /// The status of the error response.
+    #[serde(default = "default_error_status")]
+    pub status: u16,
+}
+
+fn default_error_status() -> u16 {
+    503
+}
+
+#[derive(Serialize, Deserialize, Debug, PartialEq, Clone)]
+pub struct AbortFault {
+    /// The percentage (0-100) of requests whose connection is closed.
+    pub percent: f64,
+}
+
+/// What happens to a request instead of being forwarded.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum FaultOutcome {
+    /// Respond with an error with the given status.
+    Error(u16),
+    /// Close the connection without responding.
+    Abort,
+}
+
+/// The faults chosen for a request.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Faults {
+    pub delay: Option<Duration>,
+    pub outcome: Option<FaultOutcome>,
+}
+
+/// The faults set on routes, keyed by route name.
+pub struct FaultInjector {
+    faults: RwLock<HashMap<String, FaultConfig>>,
+}
+
+impl FaultInjector {
+    pub fn new() -> Self {
+        FaultInjector {
+            faults: RwLock::new(HashMap::new()),
+        }
+    }
+
+    /// Set (or replace) the faults of a route.
+    pub fn set(&self, config: FaultConfig) {
+        let mut faults = self.faults.write().unwrap();
+        faults.insert(config.route.clone(), config);
+    }
+
+    /// Clear the faults of a route.  Return `false` if it had none.
+    pub fn clear(&self, route: &str) -> bool {
+        let mut faults = self.faults.write().unwrap();
+        faults.remove(route).is_some()
+    }
+
+    pub fn list(&self) -> Vec<FaultConfig> {
+        let faults = self.faults.read().unwrap();
+        faults.values().cloned().collect()
+    }
+
+    /// Choose the faults to inject into a request for the route.
+    pub fn choose(&self, route: &str) -> Faults {
+        self.choose_with(route, || rand::random::<f64>() * 100.0)
+    }
+
+    /// Choose the faults using `roll` to draw a random percentage (in [0, 100)) for each kind of
+    /// fault.
+    fn choose_with(&self, route: &str, mut roll: impl FnMut() -> f64) -> Faults {
+        let faults = self.faults.read().unwrap();
+        let Some(config) = faults.get(route) else {
+            return Faults::default();
+        };
+
+        let delay = config
+            .delay
+            .as_ref()
+            .filter(|fault| roll() < fault.percent)
+            .map(|fault| Duration::from_millis(fault.duration));
+        let outcome = if config.abort.as_ref().is_some_and(|f| roll() < f.percent) {
+            Some(FaultOutcome::Abort)
+        } else {
+            config
+                .error
+                .as_ref()
+                .filter(|fault| roll() < fault.percent)
+                .map(|fault| FaultOutcome::Error(fault.status))
+        };
+        Faults { delay, outcome }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn choose() {
+        let injector = FaultInjector::new();
+        injector.set(FaultConfig {
+            route: "r".to_string(),
+            delay: Some(DelayFault {
+                percent: 50.0,
+                duration: 100,
+            }),
+            error: Some(ErrorFault {
+                percent: 10.0,
+                status: 500,
+            }),
+            abort: None,
+        });
+
+        assert_eq!(
+            injector.choose_with("r", || 5.0),
+            Faults {
+                delay: Some(Duration::from_millis(100)),
+                outcome: Some(FaultOutcome::Error(500)),
+            }
+        );
+        assert_eq!(injector.choose_with("r", || 60.0), Faults::default());
+        assert_eq!(injector.choose_with("other", || 0.0), Faults::default());
+
+        assert!(injector.clear("r"));
+        assert!(!injector.clear("r"));
+        assert_eq!(injector.choose_with("r", || 0.0), Faults::default());
+    }
+}
@@ -0,0 +1,227 @@
+//! Forward authentication: before a request is forwarded to the origin, metadata about the request
+//! is sent to an external auth service (e.g., oauth2-proxy or Authelia).  The request is only
+//! forwarded if the auth service answers with a 2xx status.  Otherwise, the auth service's response
+//! is relayed to the client.
+//!
+//! Subrequests share a connection pool, and the auth service's hostname is resolved through the
+//! shared, caching resolver, so most checks neither resolve nor connect.
+
+use bytes::{Bytes, BytesMut};
+use http::header::{HeaderName, HeaderValue};
+use http::Uri;
+use log::{debug, info};
+use pingora::connectors::http::Connector;
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::prelude::*;
+use pingora::upstreams::peer::HttpPeer;
+use serde::{Deserialize, Serialize};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+use crate::dns::DnsResolver;
+
+/// The maximum size of a denial response body relayed from the auth service to the client.
+const MAX_DENIAL_BODY_SIZE: usize = 64 * 1024;
+
+/// Headers from the auth service's denial response that are relayed to the client.
+const DENIAL_HEADERS: [HeaderName; 4] = [
+    http::header::LOCATION,
+    http::header::WWW_AUTHENTICATE,
+    http::header::SET_COOKIE,
+    http::header::CONTENT_TYPE,
+];
+
+/// Forward auth settings for a route.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct ForwardAuthConfig {
+    /// The URL of the auth service (e.g., `http://auth.internal:4180/oauth2/auth`).
+    pub url: String,
+
+    /// The client request headers to send to the auth service.  If empty, all headers are sent.
+    /// The `X-Forwarded-Method`, `X-Forwarded-Proto`, `X-Forwarded-Host`, and `X-Forwarded-Uri`
+    /// headers are always sent.
+    #[serde(default)]
+    pub request_headers: Vec<String>,
+
+    /// The headers of a successful auth response to copy into the request sent to the origin
+    /// (e.g., `X-Auth-Request-User`).
+    #[serde(default)]
+    pub response_headers: Vec<String>,
+
+    /// The time (in milliseconds) allowed for the auth service to respond.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+fn default_timeout() -> u64 {
+    2000
+}
+
+/// The outcome of a forward auth check.
+pub enum AuthDecision {
+    /// The request is allowed.  The headers should be added to the request sent to the origin.
+    Allow(Vec<(HeaderName, HeaderValue)>),
+
+    /// The request is denied.  The response should be sent to the client.
+    Deny(Box<ResponseHeader>, Bytes),
+}
+
+/// A client for sending auth subrequests to external auth services (over pooled connections).
+pub struct ForwardAuthClient {
+    connector: Connector,
+}
+
+impl ForwardAuthClient {
+    pub fn new() -> Self {
+        ForwardAuthClient {
+            connector: Connector::new(None),
+        }
+    }
+
+    /// Ask the auth service whether the client request is allowed.
+    /// `scheme` is the scheme the client used to connect to the proxy (e.g., `https`).
+    pub async fn check(
+        &self,
+        config: &ForwardAuthConfig,
+        req: &RequestHeader,
+        scheme: &str,
+        host: &str,
+        resolver: &DnsResolver,
+    ) -> Result<AuthDecision> {
+        let url: Uri = config
+            .url
+            .parse()
+            .or_err(HTTPStatus(500), "Invalid forward auth URL")?;
+        let auth_host = url
+            .host()
+            .ok_or_else(|| Error::explain(HTTPStatus(500), "Forward auth URL has no host"))?;
+        let use_tls = url.scheme_str() == Some("https");
+        let port = url.port_u16().unwrap_or(if use_tls { 443 } else { 80 });
+        let path = url.path_and_query().map_or("/", |p| p.as_str());
+
+        let auth_req = build_auth_request(config, req, scheme, host, auth_host, path)?;
+
+        let ip = resolver
+            .resolve(auth_host)
+            .await
+            .or_err(HTTPStatus(502), "Unable to resolve forward auth host")?;
+        let addr = SocketAddr::new(ip, port);
+        let timeout = Duration::from_millis(config.timeout);
+        let mut peer = HttpPeer::new(addr, use_tls, auth_host.to_string());
+        peer.options.connection_timeout = Some(timeout);
+        peer.options.read_timeout = Some(timeout);
+        peer.options.write_timeout = Some(timeout);
+
+        let (mut session, _reused) = self.connector.get_http_session(&peer).await?;
+        session.write_request_header(Box::new(auth_req)).await?;
+        session.finish_request_body().await?;
+        session.read_response_header().await?;
+        let auth_resp = session
+            .response_header()
+            .ok_or_else(|| Error::explain(HTTPStatus(502), "No response from auth service"))?
+            .clone();
+
+        if auth_resp.status.is_success() {
+            debug!(
+                "Forward auth allowed request with status {}",
+                auth_resp.status
+            );
+            let headers = config
+                .response_headers
+                .iter()
+                .filter_map(|name| {
+                    let name = HeaderName::from_bytes(name.as_bytes()).ok()?;
+                    let value = auth_resp.headers.get(&name)?.clone();
+                    Some((name, value))
+                })
+                .collect();
+            // Drain the (normally empty) body so the connection can be reused.
+            while session.read_response_body().await?.is_some() {}
+            self.connector
+                .release_http_session(session, &peer, None)
+                .await;
+            return Ok(AuthDecision::Allow(headers));
+        }
+
+        info!(
+            "Forward auth denied request with status {}",
+            auth_resp.status
+        );
+        let mut body = BytesMut::new();
+        let mut complete = true;
+        while let Some(chunk) = session.read_response_body().await? {
+            if body.len() + chunk.len() > MAX_DENIAL_BODY_SIZE {
+                complete = false;
+                break;
+            }
+            body.extend_from_slice(&chunk);
+        }
+        let body = body.freeze();
+        // The connection can only be reused if the whole body was read.
+        if complete {
+            self.connector
+                .release_http_session(session, &peer, None)
+                .await;
+        }
+
+        let mut resp = ResponseHeader::build(auth_resp.status, Some(DENIAL_HEADERS.len() + 1))?;
+        for name in DENIAL_HEADERS.iter() {
+            for value in auth_resp.headers.get_all(name) {
+                resp.append_header(name, value)?;
+            }
+        }
+        resp.insert_header(http::header::CONTENT_LENGTH, body.len())?;
+        Ok(AuthDecision::Deny(Box::new(resp), body))
+    }
+}
+
+/// Build the subrequest sent to the auth service from the client request.
+fn build_auth_request(
+    config: &ForwardAuthConfig,
+    req: &RequestHeader,
+    scheme: &str,
+    host: &str,
+    auth_host: &str,
+    path: &str,
+) -> Result<RequestHeader> {
+    let mut auth_req = RequestHeader::build("GET", path.as_bytes(), None)?;
+    for (name, value) in req.headers.iter() {
+        if is_hop_by_hop(name) {
+            continue;
+        }
+        let wanted = config.request_headers.is_empty()
+            || config
+                .request_headers
+                .iter()
+                .any(|h| h.eq_ignore_ascii_case(name.as_str()));
+        if wanted {
+            auth_req.append_header(name, value)?;
+        }
+    }
+
+    auth_req.insert_header(http::header::HOST, auth_host.to_string())?;
+    auth_req.insert_header("x-forwarded-method", req.method.as_str().to_string())?;
+    auth_req.insert_header("x-forwarded-proto", scheme.to_string())?;
+    auth_req.insert_header("x-forwarded-host", host.to_string())?;
+    let uri = req.uri.path_and_query().map_or("/", |p| p.as_str());
+    auth_req.insert_header("x-forwarded-uri", uri.to_string())?;
+    auth_req.insert_header(http::header::CONTENT_LENGTH, 0)?;
+    Ok(auth_req)
+}
+
+/// Whether a header only applies to a single connection (and must not be forwarded) or describes
+/// the client request body (which isn't sent to the auth service).
+fn is_hop_by_hop(name: &HeaderName) -> bool {
+    matches!(
+        name.as_str(),
+        "connection"
+            | "keep-alive"
+            | "proxy-connection"
+            | "transfer-encoding"
+            | "te"
+            | "trailer"
+            | "upgrade"
+            | "host"
+            | "content-length"
+    )
+}
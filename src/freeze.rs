@@ -0,0 +1,149 @@
+//! Change freeze windows: periods during which configuration changes through the Config API are
+//! rejected, e.g., to protect a high-traffic event from accidental pushes.
+//!
+//! Windows are declared in the static configuration or added through the Config API.  During a
+//! window, a change is only accepted if the request carries a break-glass token (in the
+//! `x-granite-break-glass` header) whose SHA-256 digest is listed in the configuration.
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::BTreeMap;
+use std::sync::RwLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// The header carrying a break-glass token.
+pub const BREAK_GLASS_HEADER: &str = "x-granite-break-glass";
+
+/// Change freeze settings.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+#[serde(default)]
+pub struct FreezeConfig {
+    /// Windows declared up front.
+    pub windows: Vec<FreezeWindow>,
+
+    /// The SHA-256 digests (in hex) of the tokens allowed to make changes during a freeze.
+    pub break_glass_tokens: Vec<String>,
+}
+
+/// A period during which configuration changes are rejected.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct FreezeWindow {
+    /// A unique name for the window.
+    pub name: String,
+
+    /// When the window starts (seconds since the Unix epoch).  If not set, it has started.
+    pub start: Option<u64>,
+
+    /// When the window ends (seconds since the Unix epoch).  If not set, it lasts until deleted.
+    pub end: Option<u64>,
+
+    /// Why changes are frozen (reported to whoever attempts a change).
+    #[serde(default)]
+    pub reason: String,
+}
+
+impl FreezeWindow {
+    fn contains(&self, now: u64) -> bool {
+        self.start.is_none_or(|start| start <= now) && self.end.is_none_or(|end| now < end)
+    }
+}
+
+/// The freeze windows in effect.
+pub struct Freezer {
+    windows: RwLock<BTreeMap<String, FreezeWindow>>,
+    break_glass_tokens: Vec<String>,
+}
+
+impl Freezer {
+    pub fn new(config: &FreezeConfig) -> Self {
+        let windows = config
+            .windows
+            .iter()
+            .map(|window| (window.name.clone(), window.clone()))
+            .collect();
+        Freezer {
+            windows: RwLock::new(windows),
+            break_glass_tokens: config
+                .break_glass_tokens
+                .iter()
+                .map(|digest| digest.to_lowercase())
+                .collect(),
+        }
+    }
+
+    /// Add (or replace) a window.
+    pub fn add(&self, window: FreezeWindow) {
+        let mut windows = self.windows.write().unwrap();
+        windows.insert(window.name.clone(), window);
+    }
+
+    /// Delete a window.  Return `false` if there was no such window.
+    pub fn delete(&self, name: &str) -> bool {
+        let mut windows = self.windows.write().unwrap();
+        windows.remove(name).is_some()
+    }
+
+    pub fn list(&self) -> Vec<FreezeWindow> {
+        let windows = self.windows.read().unwrap();
+        windows.values().cloned().collect()
+    }
+
+    /// The window in effect now (if any).
+    pub fn active(&self) -> Option<FreezeWindow> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs();
+        self.active_at(now)
+    }
+
+    fn active_at(&self, now: u64) -> Option<FreezeWindow> {
+        let windows = self.windows.read().unwrap();
+        windows
+            .values()
+            .find(|window| window.contains(now))
+            .cloned()
+    }
+
+    /// Whether the token allows changes during a freeze.
+    pub fn is_break_glass(&self, token: &[u8]) -> bool {
+        let digest = hex::encode(Sha256::digest(token));
+        self.break_glass_tokens.contains(&digest)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows() {
+        let freezer = Freezer::new(&FreezeConfig {
+            windows: vec![FreezeWindow {
+                name: "launch".to_string(),
+                start: Some(1000),
+                end: Some(2000),
+                reason: "Product launch".to_string(),
+            }],
+            break_glass_tokens: vec![hex::encode(Sha256::digest(b"oncall"))],
+        });
+
+        assert_eq!(freezer.active_at(999), None);
+        assert_eq!(freezer.active_at(1000).unwrap().reason, "Product launch");
+        assert_eq!(freezer.active_at(2000), None);
+
+        freezer.add(FreezeWindow {
+            name: "now".to_string(),
+            start: None,
+            end: None,
+            reason: String::new(),
+        });
+        assert_eq!(freezer.active_at(5000).unwrap().name, "now");
+        assert!(freezer.delete("now"));
+        assert!(!freezer.delete("now"));
+        assert_eq!(freezer.active_at(5000), None);
+
+        assert!(freezer.is_break_glass(b"oncall"));
+        assert!(!freezer.is_break_glass(b"intern"));
+    }
+}
@@ -0,0 +1,700 @@
+//! GeoIP enrichment: the country and region of clients, looked up in a MaxMind DB (`.mmdb`) file
+//! such as GeoIP2 or GeoLite2 City/Country, and their autonomous system, looked up in an ASN
+//! database such as GeoLite2 ASN.
+//!
+//! A route's geo policy can allow or deny countries and autonomous systems, send the location to
+//! the origins in `X-Geo-Country`, `X-Geo-Region`, and `X-Geo-Asn` headers, and vary the cache key
+//! on the country.  The location is also available to the route's script (`req.country` and
+//! `req.region`), e.g., to choose an origin.
+//!
+//! The database files are checked for changes periodically and reloaded when they're replaced, so
+//! they can be updated (e.g., by `geoipupdate`) without restarting.
+//!
+//! The MaxMind DB format is decoded here rather than with the `maxminddb` crate, which the vendored
+//! dependencies granite is built from don't include yet; lookups only need the search tree and a
+//! handful of data types.  Malformed files are rejected with errors, never panics.
+
+use arc_swap::ArcSwap;
+use async_trait::async_trait;
+use log::{error, info, warn};
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use pingora::{Error, ErrorType::ReadError, Result};
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// The request header carrying the client's country (ISO 3166-1 code) to the origin.
+pub const COUNTRY_HEADER: &str = "x-geo-country";
+
+/// The request header carrying the client's region (ISO 3166-2 subdivision code) to the origin.
+pub const REGION_HEADER: &str = "x-geo-region";
+
+/// The request header carrying the client's autonomous system number to the origin.
+pub const ASN_HEADER: &str = "x-geo-asn";
+
+/// Precedes the metadata at the end of a MaxMind DB file.
+const METADATA_MARKER: &[u8] = b"\xab\xcd\xefMaxMind.com";
+
+/// The deepest nesting of data structures decoded (to stop malformed files from recursing
+/// endlessly).
+const MAX_DEPTH: usize = 32;
+
+/// GeoIP settings.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct GeoIpConfig {
+    /// The path to the MaxMind DB file.  GeoIP lookups are disabled if unset (and there's no ASN
+    /// database).
+    pub database: Option<String>,
+
+    /// The path to a MaxMind DB file of autonomous systems (e.g., GeoLite2 ASN), for databases
+    /// that don't have them.
+    pub asn_database: Option<String>,
+
+    /// How often (in seconds) the files are checked for changes.
+    pub reload_interval: u64,
+}
+
+impl Default for GeoIpConfig {
+    /// By default, there are no databases, and they would be checked every minute.
+    fn default() -> Self {
+        GeoIpConfig {
+            database: None,
+            asn_database: None,
+            reload_interval: 60,
+        }
+    }
+}
+
+/// A route's geo policy.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+#[serde(default)]
+pub struct GeoPolicy {
+    /// The countries (ISO codes) requests are allowed from.  If not empty, requests from other
+    /// countries, or whose country is unknown, are rejected.
+    pub allow_countries: Vec<String>,
+
+    /// The countries requests are rejected from.
+    pub deny_countries: Vec<String>,
+
+    /// The autonomous systems (by number) requests are allowed from.  If not empty, requests from
+    /// other autonomous systems, or whose autonomous system is unknown, are rejected.
+    pub allow_asns: Vec<u32>,
+
+    /// The autonomous systems requests are rejected from.
+    pub deny_asns: Vec<u32>,
+
+    /// Whether to send the client's location to the origin.
+    pub upstream_headers: bool,
+
+    /// Whether to cache responses separately for each country.
+    pub vary_cache: bool,
+}
+
+impl GeoPolicy {
+    /// Whether requests from the location are allowed.
+    pub fn allows(&self, location: &GeoLocation) -> bool {
+        let listed = |countries: &[String]| {
+            location
+                .country
+                .as_ref()
+                .is_some_and(|country| countries.iter().any(|c| c.eq_ignore_ascii_case(country)))
+        };
+        let asn_listed = |asns: &[u32]| location.asn.is_some_and(|asn| asns.contains(&asn));
+        (self.allow_countries.is_empty() || listed(&self.allow_countries))
+            && !listed(&self.deny_countries)
+            && (self.allow_asns.is_empty() || asn_listed(&self.allow_asns))
+            && !asn_listed(&self.deny_asns)
+    }
+}
+
+/// Where a client is (as far as the database knows).
+#[derive(Debug, PartialEq, Eq, Clone, Default)]
+pub struct GeoLocation {
+    /// The ISO 3166-1 country code (e.g., `DE`).
+    pub country: Option<String>,
+
+    /// The ISO 3166-2 code of the country's largest subdivision (e.g., `BY` for Bavaria).
+    pub region: Option<String>,
+
+    /// The number of the autonomous system announcing the address.
+    pub asn: Option<u32>,
+}
+
+/// Looks up client locations in the configured databases, reloading them when they change.
+pub struct GeoIp {
+    config: GeoIpConfig,
+    location_db: Option<DbFile>,
+    asn_db: Option<DbFile>,
+}
+
+impl GeoIp {
+    /// Load the configured databases (if any).  Return an error if one can't be loaded.
+    pub fn new(config: &GeoIpConfig) -> Result<Self> {
+        let open = |path: &Option<String>| path.as_deref().map(DbFile::open).transpose();
+        Ok(GeoIp {
+            config: config.clone(),
+            location_db: open(&config.database)?,
+            asn_db: open(&config.asn_database)?,
+        })
+    }
+
+    /// Whether a database is configured.
+    pub fn is_enabled(&self) -> bool {
+        self.location_db.is_some() || self.asn_db.is_some()
+    }
+
+    /// Look up the location of an address.
+    pub fn locate(&self, ip: IpAddr) -> GeoLocation {
+        let mut location = self
+            .location_db
+            .as_ref()
+            .map(|file| file.db.load().locate(ip))
+            .unwrap_or_default();
+        if location.asn.is_none() {
+            location.asn = self.asn_db.as_ref().and_then(|file| file.db.load().asn(ip));
+        }
+        location
+    }
+
+    /// Look up the autonomous system of an address.
+    pub fn asn(&self, ip: IpAddr) -> Option<u32> {
+        [&self.asn_db, &self.location_db]
+            .into_iter()
+            .flatten()
+            .find_map(|file| file.db.load().asn(ip))
+    }
+
+    /// Reload the databases whose files were modified since they were loaded.
+    fn reload_if_modified(&self) {
+        for file in [&self.location_db, &self.asn_db].into_iter().flatten() {
+            file.reload_if_modified();
+        }
+    }
+}
+
+/// A database file, reloaded when it changes.
+struct DbFile {
+    path: String,
+    db: ArcSwap<GeoIpDb>,
+    /// The modification time of the loaded file.
+    modified: Mutex<Option<SystemTime>>,
+}
+
+impl DbFile {
+    fn open(path: &str) -> Result<Self> {
+        let modified = fs::metadata(path).and_then(|m| m.modified()).ok();
+        let db = GeoIpDb::open(path).map_err(|e| {
+            Error::explain(ReadError, format!("Unable to load GeoIP database: {e}"))
+        })?;
+        info!("Loaded GeoIP database {path}");
+        Ok(DbFile {
+            path: path.to_string(),
+            db: ArcSwap::from_pointee(db),
+            modified: Mutex::new(modified),
+        })
+    }
+
+    /// Reload the database if the file was modified since it was loaded.  The loaded database is
+    /// kept if the new one can't be loaded.
+    fn reload_if_modified(&self) {
+        let path = &self.path;
+        let modified = match fs::metadata(path).and_then(|m| m.modified()) {
+            Ok(modified) => modified,
+            Err(e) => {
+                warn!("Unable to check GeoIP database {path}: {e}");
+                return;
+            }
+        };
+        let mut loaded = self.modified.lock().unwrap();
+        if *loaded == Some(modified) {
+            return;
+        }
+        match GeoIpDb::open(path) {
+            Ok(db) => {
+                info!("Reloaded GeoIP database {path}");
+                self.db.store(Arc::new(db));
+                *loaded = Some(modified);
+            }
+            Err(e) => error!("Unable to reload GeoIP database {path}: {e}"),
+        }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for GeoIp {
+    /// Check the database files for changes every reload interval until shutdown.
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let interval = Duration::from_secs(self.config.reload_interval.max(1));
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = shutdown.changed() => break,
+            }
+            self.reload_if_modified();
+        }
+    }
+}
+
+/// A value decoded from a MaxMind DB.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Value {
+    Map(Vec<(String, Value)>),
+    Array(Vec<Value>),
+    String(String),
+    Bytes(Vec<u8>),
+    Uint(u128),
+    Int(i32),
+    Double(f64),
+    Bool(bool),
+}
+
+impl Value {
+    /// The value of a key of a map.
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Map(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    /// An element of an array.
+    pub fn index(&self, index: usize) -> Option<&Value> {
+        match self {
+            Value::Array(values) => values.get(index),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_uint(&self) -> Option<u128> {
+        match self {
+            Value::Uint(n) => Some(*n),
+            _ => None,
+        }
+    }
+}
+
+/// A MaxMind DB: a binary search tree over the bits of addresses whose leaves point into a data
+/// section, followed by the metadata.
+pub struct GeoIpDb {
+    data: Vec<u8>,
+    node_count: usize,
+    /// The size (in bits) of each of a node's two records.
+    record_size: usize,
+    ip_version: u128,
+    /// The node IPv4 lookups start from (in an IPv6 database, the one reached by 96 zero bits).
+    ipv4_start: usize,
+    /// The size of the search tree (the data section starts 16 bytes after it).
+    tree_size: usize,
+}
+
+impl GeoIpDb {
+    pub fn open(path: &str) -> std::result::Result<Self, String> {
+        let data = fs::read(path).map_err(|e| format!("Unable to read {path}: {e}"))?;
+        GeoIpDb::from_bytes(data)
+    }
+
+    pub fn from_bytes(data: Vec<u8>) -> std::result::Result<Self, String> {
+        let metadata_start = data
+            .windows(METADATA_MARKER.len())
+            .rposition(|w| w == METADATA_MARKER)
+            .ok_or("Not a MaxMind DB file")?
+            + METADATA_MARKER.len();
+        let metadata = Decoder {
+            data: &data,
+            base: metadata_start,
+        }
+        .decode(metadata_start, 0)?
+        .0;
+        let field = |name: &str| {
+            metadata
+                .get(name)
+                .and_then(Value::as_uint)
+                .ok_or_else(|| format!("Missing {name} in the metadata"))
+        };
+        let node_count = field("node_count")? as usize;
+        let record_size = field("record_size")? as usize;
+        let ip_version = field("ip_version")?;
+        if ![24, 28, 32].contains(&record_size) {
+            return Err(format!("Unsupported record size {record_size}"));
+        }
+        let tree_size = node_count
+            .checked_mul(record_size / 4)
+            .filter(|size| size + 16 <= metadata_start)
+            .ok_or("The search tree is larger than the file")?;
+
+        let mut db = GeoIpDb {
+            data,
+            node_count,
+            record_size,
+            ip_version,
+            ipv4_start: 0,
+            tree_size,
+        };
+        if ip_version == 6 {
+            let mut node = 0;
+            for _ in 0..96 {
+                if node >= node_count {
+                    break;
+                }
+                node = db.record(node, 0).ok_or("Truncated search tree")?;
+            }
+            db.ipv4_start = node;
+        }
+        Ok(db)
+    }
+
+    /// One of a node's records (`bit` 0 for the left one).
+    fn record(&self, node: usize, bit: usize) -> Option<usize> {
+        let start = node * self.record_size / 4;
+        let bytes = self.data.get(start..start + self.record_size / 4)?;
+        let be = |b: &[u8]| b.iter().fold(0, |n, &b| (n << 8) | b as usize);
+        Some(match (self.record_size, bit) {
+            (24, 0) => be(&bytes[..3]),
+            (24, _) => be(&bytes[3..]),
+            (28, 0) => ((bytes[3] as usize & 0xf0) << 20) | be(&bytes[..3]),
+            (28, _) => ((bytes[3] as usize & 0x0f) << 24) | be(&bytes[4..]),
+            (_, 0) => be(&bytes[..4]),
+            (_, _) => be(&bytes[4..]),
+        })
+    }
+
+    /// The data recorded for the network containing the address.
+    pub fn lookup(&self, ip: IpAddr) -> Option<Value> {
+        let (addr, bits, mut node) = match ip {
+            IpAddr::V4(v4) => (u32::from(v4) as u128, 32, self.ipv4_start),
+            IpAddr::V6(v6) if self.ip_version == 6 => (u128::from(v6), 128, 0),
+            IpAddr::V6(v6) => (u32::from(v6.to_ipv4_mapped()?) as u128, 32, 0),
+        };
+        for i in (0..bits).rev() {
+            if node >= self.node_count {
+                break;
+            }
+            node = self.record(node, ((addr >> i) & 1) as usize)?;
+        }
+        if node <= self.node_count {
+            // Not found (or the address is longer than the tree is deep).
+            return None;
+        }
+        let decoder = Decoder {
+            data: &self.data,
+            base: self.tree_size + 16,
+        };
+        let offset = self.tree_size + (node - self.node_count);
+        decoder.decode(offset, 0).ok().map(|(value, _)| value)
+    }
+
+    /// The location of an address.  The country is the one the address is in, or else the one
+    /// its network is registered in.
+    pub fn locate(&self, ip: IpAddr) -> GeoLocation {
+        let Some(record) = self.lookup(ip) else {
+            return GeoLocation::default();
+        };
+        let iso_code = |value: Option<&Value>| {
+            value
+                .and_then(|v| v.get("iso_code"))
+                .and_then(Value::as_str)
+                .map(str::to_string)
+        };
+        GeoLocation {
+            country: iso_code(record.get("country"))
+                .or_else(|| iso_code(record.get("registered_country"))),
+            region: iso_code(record.get("subdivisions").and_then(|s| s.index(0))),
+            asn: autonomous_system(&record),
+        }
+    }
+
+    /// The autonomous system of an address.
+    pub fn asn(&self, ip: IpAddr) -> Option<u32> {
+        autonomous_system(&self.lookup(ip)?)
+    }
+}
+
+/// The autonomous system number of a record (of an ASN or ISP database).
+fn autonomous_system(record: &Value) -> Option<u32> {
+    record
+        .get("autonomous_system_number")
+        .and_then(Value::as_uint)
+        .and_then(|asn| u32::try_from(asn).ok())
+}
+
+/// Decodes the values of a section whose pointers are relative to `base`.
+struct Decoder<'a> {
+    data: &'a [u8],
+    base: usize,
+}
+
+impl Decoder<'_> {
+    fn bytes(&self, pos: usize, len: usize) -> std::result::Result<&[u8], String> {
+        pos.checked_add(len)
+            .and_then(|end| self.data.get(pos..end))
+            .ok_or_else(|| "Truncated data".to_string())
+    }
+
+    fn uint(&self, pos: usize, len: usize) -> std::result::Result<u128, String> {
+        Ok(self
+            .bytes(pos, len)?
+            .iter()
+            .fold(0, |n, &b| (n << 8) | b as u128))
+    }
+
+    /// Decode the value at the position, returning it and the position after it.
+    fn decode(&self, pos: usize, depth: usize) -> std::result::Result<(Value, usize), String> {
+        if depth > MAX_DEPTH {
+            return Err("Data nested too deeply".to_string());
+        }
+        let control = self.bytes(pos, 1)?[0];
+        let mut pos = pos + 1;
+        let mut kind = control >> 5;
+
+        if kind == 1 {
+            let high = (control & 0x07) as u128;
+            let (pointer, len) = match (control >> 3) & 0x03 {
+                0 => ((high << 8) | self.uint(pos, 1)?, 1),
+                1 => (((high << 16) | self.uint(pos, 2)?) + 2048, 2),
+                2 => (((high << 24) | self.uint(pos, 3)?) + 526_336, 3),
+                _ => (self.uint(pos, 4)?, 4),
+            };
+            let (value, _) = self.decode(self.base + pointer as usize, depth + 1)?;
+            return Ok((value, pos + len));
+        }
+        if kind == 0 {
+            kind = self.bytes(pos, 1)?[0]
+                .checked_add(7)
+                .ok_or("Invalid extended type")?;
+            pos += 1;
+        }
+        let mut size = (control & 0x1f) as usize;
+        if kind != 14 && size >= 29 {
+            let len = size - 28;
+            size = [29, 285, 65_821][len - 1] + self.uint(pos, len)? as usize;
+            pos += len;
+        }
+
+        let value = match kind {
+            2 => Value::String(
+                String::from_utf8(self.bytes(pos, size)?.to_vec())
+                    .map_err(|_| "Invalid UTF-8 string")?,
+            ),
+            3 => Value::Double(f64::from_bits(self.uint(pos, 8)? as u64)),
+            4 => Value::Bytes(self.bytes(pos, size)?.to_vec()),
+            5 | 6 | 9 | 10 => Value::Uint(self.uint(pos, size.min(16))?),
+            8 => Value::Int(self.uint(pos, size.min(4))? as u32 as i32),
+            15 => Value::Double(f32::from_bits(self.uint(pos, 4)? as u32) as f64),
+            14 => return Ok((Value::Bool(size != 0), pos)),
+            7 => {
+                let mut entries = Vec::with_capacity(size.min(64));
+                for _ in 0..size {
+                    let (key, next) = self.decode(pos, depth + 1)?;
+                    let (value, next) = self.decode(next, depth + 1)?;
+                    let Value::String(key) = key else {
+                        return Err("Map key isn't a string".to_string());
+                    };
+                    entries.push((key, value));
+                    pos = next;
+                }
+                return Ok((Value::Map(entries), pos));
+            }
+            11 => {
+                let mut values = Vec::with_capacity(size.min(64));
+                for _ in 0..size {
+                    let (value, next) = self.decode(pos, depth + 1)?;
+                    values.push(value);
+                    pos = next;
+                }
+                return Ok((Value::Array(values), pos));
+            }
+            _ => return Err(format!("Unsupported data type {kind}")),
+        };
+        let len = match kind {
+            3 => 8,
+            15 => 4,
+            _ => size,
+        };
+        Ok((value, pos + len))
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    /// Encode a value in the MaxMind DB data format (strings, maps, arrays, and 32-bit unsigned
+    /// integers are enough for tests).
+    fn encode(value: &Value, out: &mut Vec<u8>) {
+        match value {
+            Value::String(s) => {
+                out.push((2 << 5) | s.len() as u8);
+                out.extend_from_slice(s.as_bytes());
+            }
+            Value::Uint(n) => {
+                out.push((6 << 5) | 4);
+                out.extend_from_slice(&(*n as u32).to_be_bytes());
+            }
+            Value::Map(entries) => {
+                out.push((7 << 5) | entries.len() as u8);
+                for (key, value) in entries {
+                    encode(&Value::String(key.clone()), out);
+                    encode(value, out);
+                }
+            }
+            Value::Array(values) => {
+                out.extend_from_slice(&[values.len() as u8, 11 - 7]);
+                for value in values {
+                    encode(value, out);
+                }
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    fn map(entries: &[(&str, Value)]) -> Value {
+        Value::Map(
+            entries
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.clone()))
+                .collect(),
+        )
+    }
+
+    /// The record of a country (and region).
+    pub(crate) fn location(country: &str, region: Option<&str>) -> Value {
+        let iso_code = |code: &str| map(&[("iso_code", Value::String(code.to_string()))]);
+        let mut record = vec![("country", iso_code(country))];
+        if let Some(region) = region {
+            record.push(("subdivisions", Value::Array(vec![iso_code(region)])));
+        }
+        map(&record)
+    }
+
+    /// Build an IPv4 database (with 24-bit records) of networks and their records.
+    pub(crate) fn build_db(networks: &[(&str, Value)]) -> Vec<u8> {
+        enum Record {
+            Empty,
+            Node(usize),
+            Data(usize),
+        }
+        let mut nodes = vec![[Record::Empty, Record::Empty]];
+        let mut data = Vec::new();
+        for (net, value) in networks {
+            let net: ipnet::Ipv4Net = net.parse().unwrap();
+            let addr = u32::from(net.network());
+            let offset = data.len();
+            encode(value, &mut data);
+            let mut node = 0;
+            for i in 0..net.prefix_len() {
+                let bit = ((addr >> (31 - i)) & 1) as usize;
+                if i + 1 == net.prefix_len() {
+                    nodes[node][bit] = Record::Data(offset);
+                    break;
+                }
+                node = match nodes[node][bit] {
+                    Record::Node(next) => next,
+                    _ => {
+                        nodes.push([Record::Empty, Record::Empty]);
+                        nodes[node][bit] = Record::Node(nodes.len() - 1);
+                        nodes.len() - 1
+                    }
+                };
+            }
+        }
+
+        let node_count = nodes.len();
+        let mut db = Vec::new();
+        for node in &nodes {
+            for record in node {
+                let value = match record {
+                    Record::Empty => node_count,
+                    Record::Node(next) => *next,
+                    Record::Data(offset) => node_count + 16 + offset,
+                };
+                db.extend_from_slice(&(value as u32).to_be_bytes()[1..]);
+            }
+        }
+        db.extend_from_slice(&[0; 16]);
+        db.extend_from_slice(&data);
+        db.extend_from_slice(METADATA_MARKER);
+        let metadata = map(&[
+            ("node_count", Value::Uint(node_count as u128)),
+            ("record_size", Value::Uint(24)),
+            ("ip_version", Value::Uint(4)),
+        ]);
+        encode(&metadata, &mut db);
+        db
+    }
+
+    #[test]
+    fn lookup() {
+        let db = GeoIpDb::from_bytes(build_db(&[
+            ("192.0.2.0/24", location("US", Some("CA"))),
+            ("198.51.100.0/25", location("DE", None)),
+        ]))
+        .unwrap();
+
+        let locate = |ip: &str| db.locate(ip.parse().unwrap());
+        assert_eq!(
+            locate("192.0.2.200"),
+            GeoLocation {
+                country: Some("US".to_string()),
+                region: Some("CA".to_string()),
+                asn: None,
+            }
+        );
+        assert_eq!(locate("198.51.100.1").country.as_deref(), Some("DE"));
+        assert_eq!(locate("198.51.100.200"), GeoLocation::default());
+        assert_eq!(locate("::ffff:192.0.2.1").country.as_deref(), Some("US"));
+        assert!(GeoIpDb::from_bytes(b"not a database".to_vec()).is_err());
+        // An extended type that doesn't fit in a byte.
+        let decoder = Decoder {
+            data: &[0x00, 0xff],
+            base: 0,
+        };
+        assert!(decoder.decode(0, 0).is_err());
+
+        let policy = GeoPolicy {
+            deny_countries: vec!["de".to_string()],
+            ..Default::default()
+        };
+        assert!(policy.allows(&locate("192.0.2.1")));
+        assert!(!policy.allows(&locate("198.51.100.1")));
+        let policy = GeoPolicy {
+            allow_countries: vec!["US".to_string()],
+            ..Default::default()
+        };
+        assert!(!policy.allows(&GeoLocation::default()));
+
+        let asn_db = GeoIpDb::from_bytes(build_db(&[(
+            "192.0.2.0/24",
+            map(&[("autonomous_system_number", Value::Uint(64496))]),
+        )]))
+        .unwrap();
+        assert_eq!(asn_db.asn("192.0.2.7".parse().unwrap()), Some(64496));
+        assert_eq!(asn_db.asn("198.51.100.1".parse().unwrap()), None);
+        let hosted = GeoLocation {
+            asn: Some(64496),
+            ..Default::default()
+        };
+        let policy = GeoPolicy {
+            deny_asns: vec![64496],
+            ..Default::default()
+        };
+        assert!(!policy.allows(&hosted));
+        assert!(policy.allows(&GeoLocation::default()));
+        let policy = GeoPolicy {
+            allow_asns: vec![64496],
+            ..Default::default()
+        };
+        assert!(policy.allows(&hosted));
+        assert!(!policy.allows(&GeoLocation::default()));
+    }
+}
@@ -0,0 +1,88 @@
+//! Per-route limits on the size and count of client request headers, for routes (e.g., of a
+//! tenant whose origins can't handle large headers) that need stricter limits than the server's
+//! (256 headers and 1 MiB of headers on HTTP/1.1).  Requests exceeding a limit are rejected with a
+//! `431 Request Header Fields Too Large`.
+//!
+//! A header's size is the length of its name plus the length of its value.
+
+use http::StatusCode;
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::Result;
+use serde::{Deserialize, Serialize};
+
+/// A route's request header limits.  Unset limits aren't checked.
+#[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct HeaderLimits {
+    /// The maximum number of headers.
+    pub max_count: Option<usize>,
+
+    /// The maximum size (in bytes) of a single header.
+    pub max_header_size: Option<usize>,
+
+    /// The maximum size (in bytes) of all the headers together.
+    pub max_total_size: Option<usize>,
+}
+
+impl HeaderLimits {
+    /// The limit the request exceeds (if any).
+    pub fn exceeded(&self, req: &RequestHeader) -> Option<&'static str> {
+        if self.max_count.is_some_and(|max| req.headers.len() > max) {
+            return Some("max_count");
+        }
+        let mut total = 0;
+        for (name, value) in &req.headers {
+            let size = name.as_str().len() + value.len();
+            if self.max_header_size.is_some_and(|max| size > max) {
+                return Some("max_header_size");
+            }
+            total += size;
+        }
+        if self.max_total_size.is_some_and(|max| total > max) {
+            return Some("max_total_size");
+        }
+        None
+    }
+
+    /// The response to a rejected request.
+    pub fn reject_response(&self) -> Result<ResponseHeader> {
+        let mut resp = ResponseHeader::build(StatusCode::REQUEST_HEADER_FIELDS_TOO_LARGE, Some(1))?;
+        resp.insert_header(http::header::CONTENT_LENGTH, 0)?;
+        Ok(resp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn exceeded() {
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header("host", "example.com").unwrap();
+        req.append_header("x-a", "1234").unwrap();
+        req.append_header("x-a", "5").unwrap();
+        assert_eq!(HeaderLimits::default().exceeded(&req), None);
+
+        let limits = |max_count, max_header_size, max_total_size| HeaderLimits {
+            max_count,
+            max_header_size,
+            max_total_size,
+        };
+        assert_eq!(limits(Some(3), None, None).exceeded(&req), None);
+        assert_eq!(
+            limits(Some(2), None, None).exceeded(&req),
+            Some("max_count")
+        );
+        assert_eq!(limits(None, Some(15), None).exceeded(&req), None);
+        assert_eq!(
+            limits(None, Some(14), None).exceeded(&req),
+            Some("max_header_size")
+        );
+        assert_eq!(limits(None, None, Some(26)).exceeded(&req), None);
+        assert_eq!(
+            limits(None, None, Some(25)).exceeded(&req),
+            Some("max_total_size")
+        );
+    }
+}
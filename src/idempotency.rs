@@ -0,0 +1,457 @@
+//! Idempotency keys, protecting non-idempotent endpoints (e.g., payments) from duplicate
+//! submissions caused by client retries.
+//!
+//! When a route honors idempotency keys, the response to a request carrying a key (in the
+//! `Idempotency-Key` header, by default) is kept for the policy's TTL, and retries of the request
+//! with the same key are sent that response instead of going to the origin.  A retry that arrives
+//! while the first request is still being handled is answered with a `409 Conflict`, and a request
+//! reusing a key for another method, URI, or body with a `422 Unprocessable Entity`.
+//!
+//! Keys are scoped to the client's credentials (or its address, if it sends none), so a client
+//! can't get another client's response by reusing its key.  The body of a request with a key is
+//! read (and buffered for the origin) to compare it with the first request's, so it's limited to
+//! the size of that buffer.
+//!
+//! Only requests with unsafe methods (e.g., POST, not GET or HEAD) use keys.  Server errors (5xx)
+//! and responses larger than the policy's limit aren't kept, so the request can be retried.
+
+use bytes::{Bytes, BytesMut};
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::proxy::Session;
+use pingora::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, VecDeque};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::post_cache::MAX_BODY_SIZE;
+
+/// An idempotency key policy for a route.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct IdempotencyPolicy {
+    /// The request header holding the key.
+    #[serde(default = "default_header")]
+    pub header: String,
+
+    /// The time (in seconds) a response is kept for retries.
+    #[serde(default = "default_ttl")]
+    pub ttl: u64,
+
+    /// The size (in bytes) of the largest response body kept.
+    #[serde(default = "default_max_body_size")]
+    pub max_body_size: usize,
+}
+
+fn default_header() -> String {
+    "idempotency-key".to_string()
+}
+
+fn default_ttl() -> u64 {
+    300
+}
+
+fn default_max_body_size() -> usize {
+    64 * 1024
+}
+
+impl IdempotencyPolicy {
+    /// The key of the request, scoped to the route, host, and client (`None` if the request has no
+    /// key or a safe method).
+    pub fn key(
+        &self,
+        route: &str,
+        req: &RequestHeader,
+        client_ip: Option<IpAddr>,
+    ) -> Option<String> {
+        if req.method.is_safe() {
+            return None;
+        }
+        let key = req.headers.get(self.header.as_str())?;
+        let host = req
+            .headers
+            .get(http::header::HOST)
+            .map(|v| v.as_bytes())
+            .unwrap_or_default();
+        Some(format!(
+            "{route}\n{}\n{}\n{}",
+            String::from_utf8_lossy(host),
+            client_digest(req, client_ip),
+            String::from_utf8_lossy(key.as_bytes())
+        ))
+    }
+}
+
+/// A digest of the client's credentials (its `Authorization` and `Cookie` headers), or of its
+/// address if it sends none.
+fn client_digest(req: &RequestHeader, client_ip: Option<IpAddr>) -> String {
+    let mut hasher = Sha256::new();
+    let mut has_credentials = false;
+    for name in [http::header::AUTHORIZATION, http::header::COOKIE] {
+        for value in req.headers.get_all(&name) {
+            hasher.update(name.as_str());
+            hasher.update(b": ");
+            hasher.update(value.as_bytes());
+            hasher.update(b"\n");
+            has_credentials = true;
+        }
+    }
+    if !has_credentials {
+        if let Some(ip) = client_ip {
+            hasher.update(ip.to_string());
+        }
+    }
+    hex::encode(hasher.finalize())
+}
+
+/// Read the request's body, returning its hash (`None` if it's larger than `MAX_BODY_SIZE`).  The
+/// body is kept so it can still be sent to the origin.
+pub async fn hash_body(session: &mut Session) -> Result<Option<String>> {
+    let too_large = session
+        .req_header()
+        .headers
+        .get(http::header::CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse::<usize>().ok())
+        .is_some_and(|len| len > MAX_BODY_SIZE);
+    if too_large {
+        return Ok(None);
+    }
+    session.as_mut().enable_retry_buffering();
+    let mut hasher = Sha256::new();
+    let mut size = 0;
+    while let Some(chunk) = session.read_request_body().await? {
+        size += chunk.len();
+        if size > MAX_BODY_SIZE {
+            return Ok(None);
+        }
+        hasher.update(&chunk);
+    }
+    Ok(Some(hex::encode(hasher.finalize())))
+}
+
+/// What a key was used for (the request's method, URI, and body hash), so a key reused for another
+/// request is detected.
+fn fingerprint(req: &RequestHeader, body_hash: &str) -> String {
+    format!("{} {} {body_hash}", req.method, req.uri)
+}
+
+/// A response kept for retries.
+#[derive(Debug)]
+pub struct StoredResponse {
+    pub header: ResponseHeader,
+    pub body: Bytes,
+}
+
+enum Entry {
+    /// The first request with the key is being handled (by the recorder with this ID).
+    InProgress { fingerprint: String, id: u64 },
+
+    /// The response to the first request with the key.
+    Done {
+        fingerprint: String,
+        response: Arc<StoredResponse>,
+        expires: Instant,
+    },
+}
+
+#[derive(Default)]
+struct Keys {
+    entries: HashMap<String, Entry>,
+    /// The keys of the responses kept, in the order they were stored, so expired ones are removed.
+    order: VecDeque<(Instant, String)>,
+    next_id: u64,
+}
+
+/// The idempotency keys in use, by key.
+#[derive(Default)]
+pub struct IdempotencyStore {
+    keys: Mutex<Keys>,
+}
+
+/// What to do with a request carrying an idempotency key.
+pub enum Claim {
+    /// The key is new: forward the request, recording its response.
+    New(Box<IdempotencyRecorder>),
+
+    /// The key was used by an identical request: send its response.
+    Replay(Arc<StoredResponse>),
+
+    /// The key's first request is still being handled.
+    InProgress,
+
+    /// The key was used by a request with another method, URI, or body.
+    Mismatch,
+}
+
+impl IdempotencyStore {
+    /// Claim the request's key (given the hash of the request's body).
+    pub fn claim(
+        self: &Arc<Self>,
+        key: String,
+        req: &RequestHeader,
+        body_hash: &str,
+        policy: &IdempotencyPolicy,
+    ) -> Claim {
+        let now = Instant::now();
+        let mut guard = self.keys.lock().unwrap();
+        let keys = &mut *guard;
+        while let Some((expires, _)) = keys.order.front() {
+            if *expires > now {
+                break;
+            }
+            if let Some((_, expired)) = keys.order.pop_front() {
+                let current = keys.entries.get(&expired);
+                if matches!(current, Some(Entry::Done { expires, .. }) if *expires <= now) {
+                    keys.entries.remove(&expired);
+                }
+            }
+        }
+
+        let fingerprint = fingerprint(req, body_hash);
+        match keys.entries.get(&key) {
+            Some(Entry::InProgress { fingerprint: f, .. }) if *f == fingerprint => {
+                return Claim::InProgress
+            }
+            Some(Entry::Done {
+                fingerprint: f,
+                response,
+                expires,
+            }) if *expires > now => {
+                return if *f == fingerprint {
+                    Claim::Replay(response.clone())
+                } else {
+                    Claim::Mismatch
+                };
+            }
+            Some(Entry::InProgress { .. }) => return Claim::Mismatch,
+            _ => {}
+        }
+
+        keys.next_id += 1;
+        let id = keys.next_id;
+        keys.entries.insert(
+            key.clone(),
+            Entry::InProgress {
+                fingerprint: fingerprint.clone(),
+                id,
+            },
+        );
+        Claim::New(Box::new(IdempotencyRecorder {
+            store: self.clone(),
+            key,
+            id,
+            fingerprint,
+            ttl: Duration::from_secs(policy.ttl),
+            max_body_size: policy.max_body_size,
+            header: None,
+            body: BytesMut::new(),
+        }))
+    }
+
+    /// Keep the response to the key's first request.
+    fn store(
+        &self,
+        key: &str,
+        id: u64,
+        fingerprint: String,
+        response: StoredResponse,
+        ttl: Duration,
+    ) {
+        let mut keys = self.keys.lock().unwrap();
+        if !keys.is_claimed_by(key, id) {
+            return;
+        }
+        let expires = Instant::now() + ttl;
+        keys.order.push_back((expires, key.to_string()));
+        keys.entries.insert(
+            key.to_string(),
+            Entry::Done {
+                fingerprint,
+                response: Arc::new(response),
+                expires,
+            },
+        );
+    }
+
+    /// Release the key of a request whose response isn't kept.
+    fn release(&self, key: &str, id: u64) {
+        let mut keys = self.keys.lock().unwrap();
+        if keys.is_claimed_by(key, id) {
+            keys.entries.remove(key);
+        }
+    }
+}
+
+impl Keys {
+    fn is_claimed_by(&self, key: &str, id: u64) -> bool {
+        match self.entries.get(key) {
+            Some(Entry::InProgress { id: current, .. }) => *current == id,
+            _ => false,
+        }
+    }
+}
+
+/// Records the response to the first request with a key, to keep it for retries.  If it's dropped
+/// before the response is complete, the key is released, so a retry goes to the origin.
+pub struct IdempotencyRecorder {
+    store: Arc<IdempotencyStore>,
+    key: String,
+    id: u64,
+    fingerprint: String,
+    ttl: Duration,
+    max_body_size: usize,
+    header: Option<ResponseHeader>,
+    body: BytesMut,
+}
+
+impl std::fmt::Debug for IdempotencyRecorder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("IdempotencyRecorder")
+            .field("key", &self.key)
+            .finish()
+    }
+}
+
+impl IdempotencyRecorder {
+    /// Record the response header.  Returns whether the response can be kept.
+    pub fn response_header(&mut self, header: &ResponseHeader) -> bool {
+        if header.status.is_server_error() {
+            return false;
+        }
+        self.header = Some(header.clone());
+        true
+    }
+
+    /// Record the next chunk of the response body, keeping the response once it's complete.
+    /// Returns whether the response can still be kept.
+    pub fn response_body(&mut self, chunk: Option<&[u8]>, end_of_stream: bool) -> bool {
+        if let Some(chunk) = chunk {
+            if self.body.len() + chunk.len() > self.max_body_size {
+                return false;
+            }
+            self.body.extend_from_slice(chunk);
+        }
+        if end_of_stream {
+            let Some(mut header) = self.header.take() else {
+                return false;
+            };
+            header.remove_header(&http::header::TRANSFER_ENCODING);
+            if header
+                .insert_header(http::header::CONTENT_LENGTH, self.body.len())
+                .is_err()
+            {
+                return false;
+            }
+            let body = std::mem::take(&mut self.body).freeze();
+            self.store.store(
+                &self.key,
+                self.id,
+                std::mem::take(&mut self.fingerprint),
+                StoredResponse { header, body },
+                self.ttl,
+            );
+        }
+        true
+    }
+}
+
+impl Drop for IdempotencyRecorder {
+    fn drop(&mut self) {
+        self.store.release(&self.key, self.id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn claim() {
+        let policy: IdempotencyPolicy = serde_json::from_str("{}").unwrap();
+        let mut req = RequestHeader::build("POST", b"/pay", None).unwrap();
+        req.insert_header("host", "example.com").unwrap();
+        assert_eq!(policy.key("r", &req, None), None);
+        req.insert_header("idempotency-key", "k1").unwrap();
+        let key = policy.key("r", &req, None).unwrap();
+        let get = RequestHeader::build("GET", b"/pay", None).unwrap();
+        assert_eq!(policy.key("r", &get, None), None);
+
+        let store = Arc::new(IdempotencyStore::default());
+        let Claim::New(mut recorder) = store.claim(key.clone(), &req, "body", &policy) else {
+            panic!("expected a new key");
+        };
+        assert!(matches!(
+            store.claim(key.clone(), &req, "body", &policy),
+            Claim::InProgress
+        ));
+        let other = RequestHeader::build("POST", b"/refund", None).unwrap();
+        assert!(matches!(
+            store.claim(key.clone(), &other, "body", &policy),
+            Claim::Mismatch
+        ));
+
+        assert!(recorder.response_header(&ResponseHeader::build(201, None).unwrap()));
+        assert!(recorder.response_body(Some(b"paid"), true));
+        drop(recorder);
+        let Claim::Replay(resp) = store.claim(key.clone(), &req, "body", &policy) else {
+            panic!("expected a replay");
+        };
+        assert_eq!(resp.header.status, 201);
+        assert_eq!(resp.body, "paid");
+        assert_eq!(resp.header.headers["content-length"], "4");
+        assert!(matches!(
+            store.claim(key.clone(), &other, "body", &policy),
+            Claim::Mismatch
+        ));
+        // A key reused with another body is rejected too.
+        assert!(matches!(
+            store.claim(key, &req, "other body", &policy),
+            Claim::Mismatch
+        ));
+
+        // Server errors aren't kept, so the key is released.
+        req.insert_header("idempotency-key", "k2").unwrap();
+        let key = policy.key("r", &req, None).unwrap();
+        let Claim::New(mut recorder) = store.claim(key.clone(), &req, "body", &policy) else {
+            panic!("expected a new key");
+        };
+        assert!(!recorder.response_header(&ResponseHeader::build(503, None).unwrap()));
+        drop(recorder);
+        assert!(matches!(
+            store.claim(key, &req, "body", &policy),
+            Claim::New(_)
+        ));
+    }
+
+    #[test]
+    fn client_scoped_keys() {
+        let policy: IdempotencyPolicy = serde_json::from_str("{}").unwrap();
+        let request = |credentials: Option<(&str, &str)>| {
+            let mut req = RequestHeader::build("POST", b"/pay", None).unwrap();
+            req.insert_header("idempotency-key", "k1").unwrap();
+            if let Some((name, value)) = credentials {
+                req.insert_header(name.to_string(), value).unwrap();
+            }
+            req
+        };
+        let alice: Option<IpAddr> = Some("10.0.0.1".parse().unwrap());
+        let bob: Option<IpAddr> = Some("10.0.0.2".parse().unwrap());
+
+        // Clients are told apart by their credentials, else by their address.
+        let key = |req: &RequestHeader, ip| policy.key("r", req, ip).unwrap();
+        let token = request(Some(("authorization", "Bearer alice")));
+        assert_eq!(key(&token, alice), key(&token, bob));
+        assert_ne!(
+            key(&token, alice),
+            key(&request(Some(("authorization", "Bearer bob"))), alice)
+        );
+        assert_ne!(
+            key(&token, alice),
+            key(&request(Some(("cookie", "Bearer alice"))), alice)
+        );
+        assert_eq!(key(&request(None), alice), key(&request(None), alice));
+        assert_ne!(key(&request(None), alice), key(&request(None), bob));
+    }
+}
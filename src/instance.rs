@@ -0,0 +1,134 @@
+//! The identity of this instance within a fleet: an instance ID and a POP (point of presence) or
+//! region label.  It is added to responses, the access log, request summaries, and metrics, so
+//! that problems can be traced to the node that served a request.
+
+use http::header::{HeaderName, HeaderValue};
+use log::warn;
+use pingora::http::ResponseHeader;
+use pingora::Result;
+use serde::{Deserialize, Serialize};
+
+/// Instance identification settings.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct InstanceConfig {
+    /// The instance ID.  Defaults to the hostname.
+    pub id: Option<String>,
+
+    /// The POP or region the instance runs in (e.g., `fra1`).
+    pub pop: Option<String>,
+
+    /// The response header carrying the instance ID (not added if unset).
+    pub served_by_header: Option<String>,
+
+    /// The response header carrying the POP (not added if unset, or if the POP is unset).
+    pub pop_header: Option<String>,
+}
+
+impl Default for InstanceConfig {
+    /// By default, the instance is identified by its hostname in `x-served-by`.
+    fn default() -> Self {
+        InstanceConfig {
+            id: None,
+            pop: None,
+            served_by_header: Some("x-served-by".to_string()),
+            pop_header: Some("x-served-pop".to_string()),
+        }
+    }
+}
+
+/// This instance's identity.
+#[derive(Debug)]
+pub struct Instance {
+    id: String,
+    pop: Option<String>,
+    served_by_header: Option<HeaderName>,
+    pop_header: Option<HeaderName>,
+}
+
+impl Instance {
+    /// Resolve the instance's identity.  The header names must have been validated (see
+    /// `AppConfig`).
+    pub fn new(config: &InstanceConfig) -> Self {
+        let header = |name: &Option<String>| {
+            name.as_ref()
+                .and_then(|name| HeaderName::from_bytes(name.as_bytes()).ok())
+        };
+        Instance {
+            id: config.id.clone().unwrap_or_else(hostname),
+            pop: config.pop.clone(),
+            served_by_header: header(&config.served_by_header),
+            pop_header: header(&config.pop_header),
+        }
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    pub fn pop(&self) -> Option<&str> {
+        self.pop.as_deref()
+    }
+
+    /// Add the identity headers to a response.  The instance ID is appended to any set by another
+    /// instance the response went through (e.g., a cluster peer), so the header lists every
+    /// instance on the way.
+    pub fn add_headers(&self, resp: &mut ResponseHeader) -> Result<()> {
+        if let Some(name) = &self.served_by_header {
+            let served_by = match resp.headers.get(name).map(HeaderValue::to_str) {
+                Some(Ok(earlier)) => format!("{earlier}, {}", self.id),
+                _ => self.id.clone(),
+            };
+            resp.insert_header(name.clone(), served_by)?;
+        }
+        if let (Some(name), Some(pop)) = (&self.pop_header, &self.pop) {
+            resp.insert_header(name.clone(), pop)?;
+        }
+        Ok(())
+    }
+}
+
+/// The hostname of the machine, or `granite` if it can't be determined.
+fn hostname() -> String {
+    let mut buf = [0u8; 256];
+    match nix::unistd::gethostname(&mut buf) {
+        Ok(name) => name.to_string_lossy().into_owned(),
+        Err(e) => {
+            warn!("Unable to get the hostname ({e}); set instance.id");
+            "granite".to_string()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn identity_headers() {
+        let instance = Instance::new(&InstanceConfig {
+            id: Some("edge-2".to_string()),
+            pop: Some("fra1".to_string()),
+            ..Default::default()
+        });
+
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        instance.add_headers(&mut resp).unwrap();
+        assert_eq!(resp.headers["x-served-by"], "edge-2");
+        assert_eq!(resp.headers["x-served-pop"], "fra1");
+
+        // A response that went through a peer lists both instances.
+        resp.insert_header("x-served-by", "edge-1").unwrap();
+        instance.add_headers(&mut resp).unwrap();
+        assert_eq!(resp.headers["x-served-by"], "edge-1, edge-2");
+
+        let anonymous = Instance::new(&InstanceConfig {
+            served_by_header: None,
+            ..Default::default()
+        });
+        assert!(!anonymous.id().is_empty());
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        anonymous.add_headers(&mut resp).unwrap();
+        assert!(resp.headers.is_empty());
+    }
+}
@@ -0,0 +1,90 @@
+//! Reuse of connections to origins.  Connections are pooled once a request is answered and reused
+//! by the next requests to the same origin, so only new connections pay for the TCP and TLS
+//! handshakes.  An origin's keepalive policy sets how long idle connections are pooled and whether
+//! TCP keepalive probes keep them from being dropped silently (e.g., by a NAT or load balancer)
+//! while they're idle.
+//!
+//! Pingora's TLS connector doesn't resume TLS sessions, so new connections to HTTPS origins always
+//! make full handshakes; keeping connections pooled is how handshakes are avoided.  The metrics
+//! count new and reused connections and TLS handshakes, so the reuse rate of each origin can be
+//! watched.
+
+use pingora::protocols::l4::ext::TcpKeepalive;
+use pingora::upstreams::peer::PeerOptions;
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+
+/// An origin's connection keepalive policy.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+#[serde(default)]
+pub struct KeepalivePolicy {
+    /// How long (in seconds) idle connections to the origin are pooled.  If unset, they're pooled
+    /// until the origin closes them (or the pool is full).
+    pub idle_timeout: Option<u64>,
+
+    /// TCP keepalive probes on connections to the origin.  If unset, the system's default applies.
+    pub tcp_keepalive: Option<TcpKeepalivePolicy>,
+}
+
+/// TCP keepalive probe settings.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct TcpKeepalivePolicy {
+    /// How long (in seconds) a connection is idle before probes are sent.
+    pub idle: u64,
+
+    /// The time (in seconds) between probes.
+    pub interval: u64,
+
+    /// The number of unanswered probes after which the connection is dropped.
+    pub count: usize,
+}
+
+impl Default for TcpKeepalivePolicy {
+    /// By default, probe after 60 seconds idle, every 10 seconds, up to 3 times.
+    fn default() -> Self {
+        TcpKeepalivePolicy {
+            idle: 60,
+            interval: 10,
+            count: 3,
+        }
+    }
+}
+
+impl KeepalivePolicy {
+    /// Apply the policy to the options of a connection to the origin.
+    pub fn apply(&self, options: &mut PeerOptions) {
+        if let Some(idle_timeout) = self.idle_timeout {
+            options.idle_timeout = Some(Duration::from_secs(idle_timeout));
+        }
+        if let Some(tcp) = self.tcp_keepalive.as_ref() {
+            options.tcp_keepalive = Some(TcpKeepalive {
+                idle: Duration::from_secs(tcp.idle),
+                interval: Duration::from_secs(tcp.interval),
+                count: tcp.count,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply() {
+        let policy: KeepalivePolicy =
+            serde_json::from_str(r#"{"idle_timeout": 30, "tcp_keepalive": {"count": 5}}"#).unwrap();
+        let mut options = PeerOptions::new();
+        policy.apply(&mut options);
+        assert_eq!(options.idle_timeout, Some(Duration::from_secs(30)));
+        let tcp = options.tcp_keepalive.unwrap();
+        assert_eq!(tcp.idle, Duration::from_secs(60));
+        assert_eq!(tcp.count, 5);
+
+        let mut options = PeerOptions::new();
+        KeepalivePolicy::default().apply(&mut options);
+        assert_eq!(options.idle_timeout, None);
+        assert!(options.tcp_keepalive.is_none());
+    }
+}
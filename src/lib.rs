@@ -0,0 +1,89 @@
+//! The building blocks of granite, a dynamically configurable HTTP caching proxy.  `app` wires them
+//! together into a server (which the `granite` binary runs); they are also exposed here for
+//! embedding, benchmarks, and tests.
+
+// Constructors that set up shared state (stores, trackers, clients) are deliberately not `Default`.
+#![allow(clippy::new_without_default)]
+
+pub mod access_log;
+pub mod acl;
+pub mod api_stats;
+pub mod app;
+pub mod app_config;
+pub mod aws_sigv4;
+pub mod basic_auth;
+pub mod body_rewrite;
+pub mod bots;
+pub mod bucketing;
+pub mod cache_lock;
+pub mod cache_persistence;
+pub mod cache_stats;
+pub mod cert;
+pub mod cluster;
+pub mod collapse;
+pub mod conditional;
+pub mod config_api;
+pub mod cookies;
+pub mod cors;
+pub mod customer;
+pub mod debug_headers;
+pub mod declarative;
+pub mod dns;
+pub mod drain;
+pub mod error_pages;
+pub mod events;
+pub mod expiry;
+pub mod failover;
+pub mod fault;
+pub mod forward_auth;
+pub mod freeze;
+pub mod geoip;
+pub mod header_limits;
+pub mod idempotency;
+pub mod instance;
+pub mod keepalive;
+pub mod listeners;
+pub mod log_sinks;
+pub mod logging;
+pub mod memory;
+pub mod methods;
+pub mod metrics;
+pub mod origin_connections;
+pub mod path_match;
+pub mod plugin;
+pub mod post_cache;
+pub mod prewarm;
+pub mod privacy;
+pub mod protocols;
+pub mod proxy;
+pub mod quota;
+pub mod ranges;
+pub mod rate_limit;
+pub mod redaction;
+pub mod redirects;
+pub mod replication;
+pub mod response_headers;
+pub mod retry_budget;
+pub mod rewrite;
+pub mod route_config;
+pub mod route_import;
+pub mod route_store;
+pub mod route_trie;
+pub mod schema;
+pub mod script;
+pub mod secrets;
+pub mod security_headers;
+pub mod signed_url;
+pub mod sni;
+pub mod status;
+pub mod tap;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod throttle;
+pub mod timing;
+pub mod tls_fingerprint;
+pub mod usage;
+pub mod utils;
+pub mod waf;
+pub mod warm_up;
+pub mod wasm;
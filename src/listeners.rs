@@ -0,0 +1,184 @@
+//! Listening sockets opened before the services start: sockets passed by systemd (socket
+//! activation), and sockets bound early, while the process still has the privileges to bind ports
+//! below 1024.  (Pingora binds its listeners after daemonizing, i.e., after switching to the
+//! configured user.)
+//!
+//! Pingora only adopts existing sockets when taking over from a running instance in a graceful
+//! upgrade, so the sockets are handed over the same way: through the upgrade socket, from another
+//! thread of this process, while the server bootstraps.
+//!
+//! Proxy listeners can also carry labels (e.g., `internal` or `external`), which routes can be
+//! restricted to.
+
+use log::{error, info, warn};
+use pingora::server::Fds;
+use std::collections::{BTreeSet, HashMap};
+use std::env;
+use std::io;
+use std::net::{SocketAddr, TcpListener};
+use std::os::fd::{FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::thread::{self, JoinHandle};
+
+/// The first file descriptor passed by systemd.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// Open the listening sockets for the listener addresses (`ip:port`): adopt the sockets passed by
+/// systemd, and if `bind_early`, bind the others.  Return `None` if there are none (then Pingora
+/// binds all of them itself).
+pub fn open(addrs: &[&str], bind_early: bool) -> io::Result<Option<Fds>> {
+    let mut fds = Fds::new();
+    let mut opened = adopt(&mut fds, &systemd_fds(), addrs);
+
+    if bind_early {
+        for &addr in addrs {
+            if fds.get(addr).is_some() {
+                continue;
+            }
+            let listener = TcpListener::bind(addr)?;
+            listener.set_nonblocking(true)?;
+            info!("Bound listener on {addr}");
+            fds.add(addr.to_string(), listener.into_raw_fd());
+            opened += 1;
+        }
+    }
+
+    Ok((opened > 0).then_some(fds))
+}
+
+/// The labels of the proxy's listeners.
+#[derive(Debug, Default)]
+pub struct ListenerLabels(Vec<(SocketAddr, Vec<String>)>);
+
+impl ListenerLabels {
+    /// Index the labels by listener address.  The addresses must have been validated (see
+    /// `AppConfig`).
+    pub fn new(labels: &HashMap<String, Vec<String>>) -> Self {
+        ListenerLabels(
+            labels
+                .iter()
+                .filter_map(|(addr, labels)| Some((addr.parse().ok()?, labels.clone())))
+                .collect(),
+        )
+    }
+
+    /// The labels of the listener that accepted a connection, given the connection's local
+    /// address.  A listener bound to all interfaces (e.g., `0.0.0.0:443`) accepts connections on
+    /// any address with its port, unless another listener is bound to that address.
+    pub fn labels(&self, local: SocketAddr) -> &[String] {
+        let exact = self.0.iter().find(|(addr, _)| *addr == local);
+        let any = || {
+            self.0
+                .iter()
+                .find(|(addr, _)| addr.port() == local.port() && addr.ip().is_unspecified())
+        };
+        exact
+            .or_else(any)
+            .map_or(&[], |(_, labels)| labels.as_slice())
+    }
+}
+
+/// Hand the sockets over to the server through the upgrade socket (at `upgrade_sock`), where
+/// `Server::bootstrap` receives them if the `upgrade` option is set.  Join the returned thread once
+/// the server has bootstrapped.
+pub fn hand_over(fds: Fds, upgrade_sock: String) -> JoinHandle<()> {
+    thread::spawn(move || {
+        if let Err(e) = fds.send_to_sock(upgrade_sock.as_str()) {
+            error!("Unable to hand over the listening sockets: {e}");
+            return;
+        }
+        // The server has its own copies now.
+        let (_, raw_fds) = fds.serialize();
+        for fd in raw_fds.into_iter().collect::<BTreeSet<_>>() {
+            drop(unsafe { OwnedFd::from_raw_fd(fd) });
+        }
+    })
+}
+
+/// The sockets passed by systemd (if it started this process).  The environment variables are
+/// cleared so that child processes don't take them for their own.
+fn systemd_fds() -> Vec<RawFd> {
+    let pid = env::var("LISTEN_PID").ok().and_then(|pid| pid.parse().ok());
+    let count: RawFd = env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse().ok())
+        .unwrap_or(0);
+    env::remove_var("LISTEN_PID");
+    env::remove_var("LISTEN_FDS");
+    env::remove_var("LISTEN_FDNAMES");
+
+    if pid != Some(std::process::id()) {
+        return Vec::new();
+    }
+    (LISTEN_FDS_START..LISTEN_FDS_START + count).collect()
+}
+
+/// Add the inherited sockets bound to the listener addresses to the table, and close the others.
+/// Return the number of addresses with a socket.
+fn adopt(fds: &mut Fds, inherited: &[RawFd], addrs: &[&str]) -> usize {
+    let mut adopted = 0;
+    for &fd in inherited {
+        let listener = unsafe { TcpListener::from_raw_fd(fd) };
+        let local_addr = match listener.local_addr() {
+            Ok(local_addr) => local_addr,
+            Err(e) => {
+                warn!("Closing inherited socket {fd}, which isn't a TCP listener: {e}");
+                continue;
+            }
+        };
+        let matching: Vec<&str> = addrs
+            .iter()
+            .copied()
+            .filter(|addr| addr.parse::<SocketAddr>().ok() == Some(local_addr))
+            .collect();
+        if matching.is_empty() {
+            warn!("Closing inherited socket on {local_addr}, which no listener is configured for");
+            continue;
+        }
+        if let Err(e) = listener.set_nonblocking(true) {
+            warn!("Closing inherited socket on {local_addr}: {e}");
+            continue;
+        }
+        info!("Using inherited socket on {local_addr}");
+        let fd = listener.into_raw_fd();
+        for addr in matching {
+            fds.add(addr.to_string(), fd);
+            adopted += 1;
+        }
+    }
+    adopted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn labels() {
+        let labels = ListenerLabels::new(&HashMap::from([
+            ("0.0.0.0:443".to_string(), vec!["external".to_string()]),
+            ("10.0.0.1:443".to_string(), vec!["internal".to_string()]),
+        ]));
+        let labels_of = |addr: &str| labels.labels(addr.parse().unwrap());
+        assert_eq!(labels_of("10.0.0.1:443"), ["internal"]);
+        assert_eq!(labels_of("192.0.2.1:443"), ["external"]);
+        assert!(labels_of("192.0.2.1:80").is_empty());
+    }
+
+    #[test]
+    fn adopt_inherited() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap().to_string();
+        let other = TcpListener::bind("127.0.0.1:0").unwrap();
+        let inherited = [listener.into_raw_fd(), other.into_raw_fd()];
+
+        let mut fds = Fds::new();
+        let addrs = [addr.as_str(), "127.0.0.1:1"];
+        assert_eq!(adopt(&mut fds, &inherited, &addrs), 1);
+        assert_eq!(fds.get(&addr), Some(&inherited[0]));
+        assert_eq!(fds.get("127.0.0.1:1"), None);
+
+        // The adopted socket is still open (and the other one was closed).
+        let adopted = unsafe { TcpListener::from_raw_fd(inherited[0]) };
+        assert_eq!(adopted.local_addr().unwrap().to_string(), addr);
+    }
+}
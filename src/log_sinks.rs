@@ -0,0 +1,334 @@
+//! Per-route (or per-customer) access log destinations, so operators can deliver each customer's
+//! logs to them without splitting a global log stream.  A route's lines are written to its sink in
+//! addition to the global access log (if any), in the same format.
+//!
+//! A sink is one of:
+//! - a file in `access_log.sink_dir`, rotated like the global access log,
+//! - an HTTP(S) endpoint the lines are POSTed to in batches (newline-delimited), e.g., a log
+//!   collector or a Kafka REST proxy,
+//! - a syslog collector, over UDP (RFC 5424).
+//!
+//! Sinks are opened the first time a request is logged to them, and shared by the routes with the
+//! same sink.  Like the global access log, each sink has a writer thread, and lines are dropped
+//! (and counted) rather than buffered without bound if it falls behind.
+
+use chrono::{SecondsFormat, Utc};
+use http::Uri;
+use log::{error, info, warn};
+use pingora::connectors::http::Connector;
+use pingora::http::RequestHeader;
+use pingora::upstreams::peer::HttpPeer;
+use pingora::{Error, ErrorType::HTTPStatus, OrErr, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::net::UdpSocket;
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, Receiver, SyncSender, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use crate::access_log::{self, AccessLogConfig, RotatingFile};
+
+/// The number of lines that can be queued for each sink's writer thread.
+const QUEUE_SIZE: usize = 8192;
+
+/// How long to wait for an HTTP sink's endpoint.
+const HTTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// The syslog severity of access log lines (informational).
+const SYSLOG_SEVERITY: u8 = 6;
+
+/// Where a route's access log lines are sent.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Hash)]
+pub enum LogSinkConfig {
+    /// A file (relative to `access_log.sink_dir`).
+    File {
+        path: String,
+        /// Rotate the file once it reaches this size (in bytes).
+        #[serde(default)]
+        max_size: Option<u64>,
+        /// The number of rotated files to keep.
+        #[serde(default = "default_max_files")]
+        max_files: usize,
+        /// Whether to gzip rotated files.
+        #[serde(default)]
+        compress: bool,
+    },
+
+    /// An HTTP(S) endpoint the lines are POSTed to.
+    Http {
+        url: String,
+        /// The maximum number of lines per request.
+        #[serde(default = "default_batch_size")]
+        batch_size: usize,
+    },
+
+    /// A syslog collector (`host:port`), over UDP.
+    Syslog {
+        addr: String,
+        /// The syslog facility (default: 16, `local0`).
+        #[serde(default = "default_facility")]
+        facility: u8,
+    },
+}
+
+fn default_max_files() -> usize {
+    5
+}
+
+fn default_batch_size() -> usize {
+    100
+}
+
+fn default_facility() -> u8 {
+    16
+}
+
+/// An open sink.
+struct LogSink {
+    sender: SyncSender<String>,
+    dropped: AtomicU64,
+}
+
+/// The open sinks, by configuration.
+pub struct LogSinks {
+    /// The directory file sinks are written to (if file sinks are allowed).
+    dir: Option<PathBuf>,
+    /// The sinks opened so far (`None` for those that couldn't be opened).
+    sinks: Mutex<HashMap<LogSinkConfig, Option<Arc<LogSink>>>>,
+}
+
+impl LogSinks {
+    pub fn new(config: &AccessLogConfig) -> Self {
+        LogSinks {
+            dir: config.sink_dir.as_ref().map(PathBuf::from),
+            sinks: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Send a line to the sink (opening it if necessary).
+    pub fn log(&self, config: &LogSinkConfig, line: String) {
+        let sink = {
+            let mut sinks = self.sinks.lock().unwrap();
+            let sink = sinks
+                .entry(config.clone())
+                .or_insert_with(|| match self.open(config) {
+                    Ok(sink) => Some(Arc::new(sink)),
+                    Err(e) => {
+                        error!("Unable to open log sink {config:?}: {e}");
+                        None
+                    }
+                });
+            match sink {
+                Some(sink) => sink.clone(),
+                None => return,
+            }
+        };
+        if let Err(TrySendError::Full(_)) = sink.sender.try_send(line) {
+            let dropped = sink.dropped.fetch_add(1, Ordering::Relaxed) + 1;
+            if dropped.is_power_of_two() {
+                warn!("Log sink {config:?} is falling behind; {dropped} lines dropped so far");
+            }
+        }
+    }
+
+    /// Open the sink and start its writer thread.
+    fn open(&self, config: &LogSinkConfig) -> io::Result<LogSink> {
+        let (sender, receiver) = mpsc::sync_channel(QUEUE_SIZE);
+        match config {
+            LogSinkConfig::File {
+                path,
+                max_size,
+                max_files,
+                compress,
+            } => {
+                let path = self.file_path(path)?;
+                let file = RotatingFile::open(
+                    path.to_string_lossy().to_string(),
+                    &AccessLogConfig {
+                        max_size: *max_size,
+                        max_files: *max_files,
+                        compress: *compress,
+                        ..Default::default()
+                    },
+                )?;
+                info!("Writing route access log to {}", path.display());
+                thread::spawn(move || access_log::write_lines(file, receiver));
+            }
+            LogSinkConfig::Http { url, batch_size } => {
+                let url: Uri = url
+                    .parse()
+                    .map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+                let runtime = tokio::runtime::Builder::new_current_thread()
+                    .enable_all()
+                    .build()?;
+                let batch_size = (*batch_size).max(1);
+                thread::spawn(move || post_lines(runtime, url, batch_size, receiver));
+            }
+            LogSinkConfig::Syslog { addr, facility } => {
+                let bind = if addr.starts_with('[') {
+                    "[::]:0"
+                } else {
+                    "0.0.0.0:0"
+                };
+                let socket = UdpSocket::bind(bind)?;
+                socket.connect(addr)?;
+                let priority = facility.saturating_mul(8).saturating_add(SYSLOG_SEVERITY);
+                thread::spawn(move || send_syslog(socket, priority, receiver));
+            }
+        }
+        Ok(LogSink {
+            sender,
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// The path of a file sink, which must stay within the sink directory.
+    fn file_path(&self, path: &str) -> io::Result<PathBuf> {
+        let Some(dir) = &self.dir else {
+            return Err(io::Error::new(
+                io::ErrorKind::PermissionDenied,
+                "File sinks require access_log.sink_dir",
+            ));
+        };
+        let relative = Path::new(path);
+        if !relative
+            .components()
+            .all(|c| matches!(c, Component::Normal(_)))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "File sink paths must be relative, without '..'",
+            ));
+        }
+        Ok(dir.join(relative))
+    }
+}
+
+/// An HTTP sink's writer thread: POST the lines in batches.
+fn post_lines(
+    runtime: tokio::runtime::Runtime,
+    url: Uri,
+    batch_size: usize,
+    receiver: Receiver<String>,
+) {
+    let connector = Connector::new(None);
+    while let Ok(line) = receiver.recv() {
+        let mut batch = line;
+        batch.push('\n');
+        for _ in 1..batch_size {
+            let Ok(line) = receiver.try_recv() else {
+                break;
+            };
+            batch.push_str(&line);
+            batch.push('\n');
+        }
+        if let Err(e) = runtime.block_on(post(&connector, &url, "text/plain", batch)) {
+            error!("Unable to send access log lines to {url}: {e}");
+        }
+    }
+}
+
+/// POST a batch of lines to a sink's endpoint (also used by the event sink).
+pub(crate) async fn post(
+    connector: &Connector,
+    url: &Uri,
+    content_type: &str,
+    body: String,
+) -> Result<()> {
+    let host = url
+        .host()
+        .ok_or_else(|| Error::explain(HTTPStatus(500), "Sink URL has no host"))?;
+    let use_tls = url.scheme_str() == Some("https");
+    let port = url.port_u16().unwrap_or(if use_tls { 443 } else { 80 });
+    let path = url.path_and_query().map_or("/", |p| p.as_str());
+    let addr = tokio::net::lookup_host((host, port))
+        .await
+        .or_err(HTTPStatus(502), "Unable to resolve sink host")?
+        .next()
+        .ok_or_else(|| Error::explain(HTTPStatus(502), "No address found for sink host"))?;
+    let mut peer = HttpPeer::new(addr, use_tls, host.to_string());
+    peer.options.connection_timeout = Some(HTTP_TIMEOUT);
+    peer.options.read_timeout = Some(HTTP_TIMEOUT);
+    peer.options.write_timeout = Some(HTTP_TIMEOUT);
+
+    let mut req = RequestHeader::build("POST", path.as_bytes(), None)?;
+    req.insert_header(http::header::HOST, host.to_string())?;
+    req.insert_header(http::header::CONTENT_TYPE, content_type.to_string())?;
+    req.insert_header(http::header::CONTENT_LENGTH, body.len())?;
+    let (mut session, _reused) = connector.get_http_session(&peer).await?;
+    session.write_request_header(Box::new(req)).await?;
+    session.write_request_body(body.into(), true).await?;
+    session.finish_request_body().await?;
+    session.read_response_header().await?;
+    let status = session
+        .response_header()
+        .map_or(0, |resp| resp.status.as_u16());
+    if !(200..300).contains(&status) {
+        return Error::e_explain(
+            HTTPStatus(502),
+            format!("Sink answered with status {status}"),
+        );
+    }
+    connector.release_http_session(session, &peer, None).await;
+    Ok(())
+}
+
+/// A syslog sink's writer thread: send each line as a datagram.
+fn send_syslog(socket: UdpSocket, priority: u8, receiver: Receiver<String>) {
+    while let Ok(line) = receiver.recv() {
+        if let Err(e) = socket.send(syslog_message(priority, &line).as_bytes()) {
+            error!("Unable to send access log line to syslog: {e}");
+        }
+    }
+}
+
+/// Format a line as an RFC 5424 syslog message.
+fn syslog_message(priority: u8, line: &str) -> String {
+    format!(
+        "<{priority}>1 {} - granite - access - {line}",
+        Utc::now().to_rfc3339_opts(SecondsFormat::Millis, true)
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn sinks() {
+        let dir = std::env::temp_dir().join(format!("granite-sinks-{}", rand::random::<u64>()));
+        fs::create_dir_all(&dir).unwrap();
+        let sinks = LogSinks::new(&AccessLogConfig {
+            sink_dir: Some(dir.to_string_lossy().to_string()),
+            ..Default::default()
+        });
+        assert!(sinks.file_path("../etc/passwd").is_err());
+        assert!(sinks.file_path("/etc/passwd").is_err());
+        assert_eq!(
+            sinks.file_path("c1/access.log").unwrap(),
+            dir.join("c1/access.log")
+        );
+
+        let collector = UdpSocket::bind("127.0.0.1:0").unwrap();
+        collector
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .unwrap();
+        let syslog: LogSinkConfig = serde_json::from_value(serde_json::json!({
+            "Syslog": {"addr": collector.local_addr().unwrap().to_string()}
+        }))
+        .unwrap();
+        sinks.log(&syslog, "a line".to_string());
+        let mut buf = [0; 512];
+        let len = collector.recv(&mut buf).unwrap();
+        let message = String::from_utf8_lossy(&buf[..len]);
+        assert!(message.starts_with("<134>1 "));
+        assert!(message.ends_with(" granite - access - a line"));
+
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
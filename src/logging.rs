@@ -0,0 +1,125 @@
+//! Logging setup, with a log level that can be changed at runtime (through the config API) without
+//! restarting the proxy.
+//!
+//! The initial filter comes from the `RUST_LOG` environment variable, as usual with `env_logger`.
+//! A level set at runtime overrides it for all modules until it is reset.  Either way, the `log`
+//! crate's global maximum level is kept in step, so disabled log statements cost next to nothing.
+
+use arc_swap::ArcSwapOption;
+use log::{LevelFilter, Log, Metadata, Record};
+use once_cell::sync::OnceCell;
+use std::str::FromStr;
+use std::sync::Arc;
+
+static LOGGER: OnceCell<RuntimeLogger> = OnceCell::new();
+
+/// Delegates to the `RUST_LOG` logger or, if a level was set at runtime, to one for that level.
+struct RuntimeLogger {
+    default: env_logger::Logger,
+    level_override: ArcSwapOption<env_logger::Logger>,
+}
+
+impl RuntimeLogger {
+    fn new() -> Self {
+        RuntimeLogger {
+            default: env_logger::Builder::from_default_env().build(),
+            level_override: ArcSwapOption::empty(),
+        }
+    }
+
+    /// Set (or, with `None`, reset) the level and return the new maximum level.
+    fn set_level(&self, level: Option<LevelFilter>) -> LevelFilter {
+        let logger =
+            level.map(|level| Arc::new(env_logger::Builder::new().filter_level(level).build()));
+        self.level_override.store(logger);
+        self.max_level()
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        match &*self.level_override.load() {
+            Some(logger) => logger.filter(),
+            None => self.default.filter(),
+        }
+    }
+}
+
+impl Log for RuntimeLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        match &*self.level_override.load() {
+            Some(logger) => logger.enabled(metadata),
+            None => self.default.enabled(metadata),
+        }
+    }
+
+    fn log(&self, record: &Record) {
+        match &*self.level_override.load() {
+            Some(logger) => logger.log(record),
+            None => self.default.log(record),
+        }
+    }
+
+    fn flush(&self) {
+        self.default.flush();
+    }
+}
+
+/// Install the logger.  Must be called once, before anything is logged.
+pub fn init() {
+    let logger = LOGGER.get_or_init(RuntimeLogger::new);
+    log::set_logger(logger).expect("The logger has already been installed");
+    log::set_max_level(logger.max_level());
+}
+
+/// The level set at runtime, if any.
+pub fn level_override() -> Option<LevelFilter> {
+    let logger = LOGGER.get()?;
+    let level_override = logger.level_override.load();
+    level_override.as_ref().map(|logger| logger.filter())
+}
+
+/// Parse a level name (`off`, `error`, `warn`, `info`, `debug`, or `trace`).  `reset` (or an empty
+/// string) means going back to the `RUST_LOG` filter and is returned as `None`.
+pub fn parse_level(name: &str) -> Result<Option<LevelFilter>, String> {
+    match name.trim() {
+        "" | "reset" => Ok(None),
+        name => LevelFilter::from_str(name)
+            .map(Some)
+            .map_err(|_| format!("Invalid log level: {name}")),
+    }
+}
+
+/// Set the level of all modules (or, with `None`, go back to the `RUST_LOG` filter).
+pub fn set_level(level: Option<LevelFilter>) -> Result<(), String> {
+    let logger = LOGGER
+        .get()
+        .ok_or_else(|| "Logging is not initialized".to_string())?;
+    log::set_max_level(logger.set_level(level));
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use log::Level;
+
+    #[test]
+    fn override_level() {
+        assert_eq!(parse_level("DEBUG"), Ok(Some(LevelFilter::Debug)));
+        assert_eq!(parse_level("reset"), Ok(None));
+        assert!(parse_level("loud").is_err());
+
+        let logger = RuntimeLogger::new();
+        let trace = Metadata::builder()
+            .level(Level::Trace)
+            .target("granite::proxy")
+            .build();
+        assert!(!logger.enabled(&trace));
+        assert_eq!(
+            logger.set_level(Some(LevelFilter::Trace)),
+            LevelFilter::Trace
+        );
+        assert!(logger.enabled(&trace));
+        logger.set_level(None);
+        assert!(!logger.enabled(&trace));
+    }
+}
@@ -0,0 +1,198 @@
+//! Approximate accounting of the memory used by the proxy's main consumers (routes, certificates,
+//! the cache, and request data buffered in flight), with high-water marks that relieve memory
+//! pressure before the process is killed for running out of memory.
+//!
+//! Above the trim threshold, the least recently used cache entries are evicted until usage is back
+//! under it.  Above the shed threshold (meant to be set higher), new requests are rejected with a
+//! 503 until usage drops back below it.
+//!
+//! The figures are estimates (e.g., a route counts as the size of its JSON representation), so the
+//! thresholds should leave headroom below the actual memory limit.
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::cert::cert_store::CertStore;
+use crate::metrics;
+use crate::proxy;
+use crate::route_store::RouteStore;
+
+/// Memory accounting settings.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct MemoryConfig {
+    /// Trim the cache when the tracked memory exceeds this many bytes.
+    pub trim_threshold: Option<usize>,
+
+    /// Reject new requests with a 503 while the tracked memory exceeds this many bytes.
+    pub shed_threshold: Option<usize>,
+
+    /// How often (in milliseconds) to check memory usage against the thresholds.
+    pub check_interval: u64,
+}
+
+impl Default for MemoryConfig {
+    /// By default, memory usage is tracked (and exported) every second, but no action is taken.
+    fn default() -> Self {
+        MemoryConfig {
+            trim_threshold: None,
+            shed_threshold: None,
+            check_interval: 1000,
+        }
+    }
+}
+
+/// The estimated memory used by each consumer, in bytes.
+#[derive(Serialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct MemoryUsage {
+    pub routes: usize,
+    pub certs: usize,
+    pub cache: usize,
+    pub in_flight: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.routes + self.certs + self.cache + self.in_flight
+    }
+}
+
+/// Tracks memory usage and decides when to trim the cache or shed requests.
+pub struct MemoryTracker {
+    config: MemoryConfig,
+    route_store: Arc<RouteStore>,
+    cert_store: Arc<CertStore>,
+    /// Bytes held by requests in progress (headers and response bodies being cached).
+    in_flight: AtomicUsize,
+    /// Whether requests are currently being shed.
+    shedding: AtomicBool,
+    started: Instant,
+    /// When (in milliseconds since `started`) the next check is due.
+    next_check: AtomicU64,
+}
+
+impl MemoryTracker {
+    pub fn new(
+        config: &MemoryConfig,
+        route_store: Arc<RouteStore>,
+        cert_store: Arc<CertStore>,
+    ) -> Self {
+        MemoryTracker {
+            config: config.clone(),
+            route_store,
+            cert_store,
+            in_flight: AtomicUsize::new(0),
+            shedding: AtomicBool::new(false),
+            started: Instant::now(),
+            next_check: AtomicU64::new(0),
+        }
+    }
+
+    /// Account for bytes buffered by a request in progress.
+    pub fn buffer(&self, bytes: usize) {
+        self.in_flight.fetch_add(bytes, Ordering::Relaxed);
+    }
+
+    /// Stop accounting for bytes previously buffered by a request.
+    pub fn release(&self, bytes: usize) {
+        self.in_flight.fetch_sub(bytes, Ordering::Relaxed);
+    }
+
+    pub fn usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            routes: self.route_store.memory_usage(),
+            certs: self.cert_store.memory_usage(),
+            cache: proxy::cache_usage().0,
+            in_flight: self.in_flight.load(Ordering::Relaxed),
+        }
+    }
+
+    /// Whether new requests should be rejected.
+    pub fn shedding(&self) -> bool {
+        self.shedding.load(Ordering::Relaxed)
+    }
+
+    /// Check memory usage if a check is due (at most one caller per interval does so), update the
+    /// metrics and the shedding state, and return the number of cache bytes to trim (if any).
+    pub fn check(&self) -> usize {
+        let now = self.started.elapsed().as_millis() as u64;
+        let due = self.next_check.load(Ordering::Relaxed);
+        if now < due
+            || self
+                .next_check
+                .compare_exchange(
+                    due,
+                    now + self.config.check_interval,
+                    Ordering::Relaxed,
+                    Ordering::Relaxed,
+                )
+                .is_err()
+        {
+            return 0;
+        }
+        self.evaluate(self.usage())
+    }
+
+    fn evaluate(&self, usage: MemoryUsage) -> usize {
+        metrics::memory_used(&usage);
+        let total = usage.total();
+
+        let shed = self
+            .config
+            .shed_threshold
+            .is_some_and(|threshold| total > threshold);
+        if self.shedding.swap(shed, Ordering::Relaxed) != shed {
+            if shed {
+                warn!("Memory usage ({total} bytes) is above the shed threshold; rejecting new requests");
+            } else {
+                warn!("Memory usage ({total} bytes) is below the shed threshold; accepting requests again");
+            }
+        }
+
+        match self.config.trim_threshold {
+            Some(threshold) if total > threshold => (total - threshold).min(usage.cache),
+            _ => 0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn thresholds() {
+        let tracker = MemoryTracker::new(
+            &MemoryConfig {
+                trim_threshold: Some(1000),
+                shed_threshold: Some(2000),
+                ..Default::default()
+            },
+            Arc::new(RouteStore::new()),
+            Arc::new(CertStore::new()),
+        );
+        let usage = |cache, in_flight| MemoryUsage {
+            routes: 100,
+            certs: 100,
+            cache,
+            in_flight,
+        };
+
+        assert_eq!(tracker.evaluate(usage(500, 0)), 0);
+        assert_eq!(tracker.evaluate(usage(900, 100)), 200);
+        assert!(!tracker.shedding());
+        // Only the cache can be trimmed.
+        assert_eq!(tracker.evaluate(usage(300, 2000)), 300);
+        assert!(tracker.shedding());
+        assert_eq!(tracker.evaluate(usage(0, 500)), 0);
+        assert!(!tracker.shedding());
+
+        tracker.buffer(10);
+        tracker.buffer(5);
+        tracker.release(10);
+        assert_eq!(tracker.usage().in_flight, 5);
+    }
+}
@@ -0,0 +1,164 @@
+//! Per-route method policies, for methods the origin shouldn't see.  `TRACE` (and Microsoft's
+//! `TRACK`) echo the request back, which can leak credentials to scripts (cross-site tracing), so
+//! they're rejected.  A route can also be restricted to a list of methods: other methods are
+//! rejected with a `405 Method Not Allowed` listing the allowed ones, without reaching the origin.
+//!
+//! `OPTIONS` requests can be answered by the proxy as well.  CORS preflights are answered by the
+//! route's CORS policy (and fail for methods the route doesn't allow), and other `OPTIONS` requests
+//! with the allowed methods.
+
+use http::{Method, StatusCode};
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::Result;
+use serde::{Deserialize, Serialize};
+
+/// The methods listed in `Allow` when a route doesn't restrict them.
+const DEFAULT_ALLOW: &str = "GET, HEAD, POST, PUT, PATCH, DELETE, OPTIONS";
+
+/// How `OPTIONS` requests (other than CORS preflights) are handled.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum OptionsHandling {
+    /// Answer them with a `204` listing the allowed methods.
+    #[default]
+    Local,
+
+    /// Forward them to the origin (if `OPTIONS` is allowed).
+    Forward,
+}
+
+/// A route's method policy.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct MethodPolicy {
+    /// The methods allowed on the route (`HEAD` is allowed along with `GET`).  If empty, every
+    /// method is allowed (except `TRACE` and `TRACK`, if rejected).
+    pub allowed_methods: Vec<String>,
+
+    /// How `OPTIONS` requests are handled.
+    pub options: OptionsHandling,
+
+    /// Whether to reject `TRACE` and `TRACK` requests.
+    pub reject_trace: bool,
+}
+
+impl Default for MethodPolicy {
+    /// By default, every method but `TRACE` and `TRACK` is allowed, and `OPTIONS` requests are
+    /// answered locally.
+    fn default() -> Self {
+        MethodPolicy {
+            allowed_methods: Vec::new(),
+            options: OptionsHandling::Local,
+            reject_trace: true,
+        }
+    }
+}
+
+/// What to do with a request under a route's method policy.
+#[derive(Debug, PartialEq, Eq)]
+pub enum MethodDecision {
+    /// Handle the request as usual.
+    Allow,
+
+    /// Answer the `OPTIONS` request locally.
+    AnswerOptions,
+
+    /// Reject the request with a 405.
+    Reject,
+}
+
+impl MethodPolicy {
+    /// Whether the method is allowed on the route.
+    pub fn allows(&self, method: &str) -> bool {
+        if self.reject_trace
+            && (method.eq_ignore_ascii_case("TRACE") || method.eq_ignore_ascii_case("TRACK"))
+        {
+            return false;
+        }
+        self.allowed_methods.is_empty()
+            || self.allowed_methods.iter().any(|m| {
+                m.eq_ignore_ascii_case(method)
+                    || (method.eq_ignore_ascii_case("HEAD") && m.eq_ignore_ascii_case("GET"))
+            })
+    }
+
+    /// Decide what to do with a request.  CORS preflights are left to the route's CORS policy
+    /// (see `allows` for the method they ask for).
+    pub fn decide(&self, req: &RequestHeader, preflight: bool) -> MethodDecision {
+        if req.method == Method::OPTIONS && !preflight && self.options == OptionsHandling::Local {
+            return MethodDecision::AnswerOptions;
+        }
+        if preflight || self.allows(req.method.as_str()) {
+            MethodDecision::Allow
+        } else {
+            MethodDecision::Reject
+        }
+    }
+
+    /// The value of the `Allow` header listing the route's methods.
+    pub fn allow_header(&self) -> String {
+        if self.allowed_methods.is_empty() {
+            return DEFAULT_ALLOW.to_string();
+        }
+        let mut methods: Vec<String> = self
+            .allowed_methods
+            .iter()
+            .map(|m| m.to_ascii_uppercase())
+            .collect();
+        if methods.iter().any(|m| m == "GET") && !methods.iter().any(|m| m == "HEAD") {
+            methods.push("HEAD".to_string());
+        }
+        if self.options == OptionsHandling::Local && !methods.iter().any(|m| m == "OPTIONS") {
+            methods.push("OPTIONS".to_string());
+        }
+        methods.join(", ")
+    }
+
+    /// The response to an `OPTIONS` request answered locally.
+    pub fn options_response(&self) -> Result<ResponseHeader> {
+        let mut resp = ResponseHeader::build(StatusCode::NO_CONTENT, Some(2))?;
+        resp.insert_header(http::header::ALLOW, self.allow_header())?;
+        resp.insert_header(http::header::CONTENT_LENGTH, 0)?;
+        Ok(resp)
+    }
+
+    /// The response to a request with a method that isn't allowed.
+    pub fn reject_response(&self) -> Result<ResponseHeader> {
+        let mut resp = ResponseHeader::build(StatusCode::METHOD_NOT_ALLOWED, Some(3))?;
+        resp.insert_header(http::header::ALLOW, self.allow_header())?;
+        resp.insert_header(http::header::CONTENT_LENGTH, 0)?;
+        Ok(resp)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decide() {
+        let req = |method: &str| RequestHeader::build(method, b"/", None).unwrap();
+        let policy = MethodPolicy::default();
+        assert_eq!(policy.decide(&req("GET"), false), MethodDecision::Allow);
+        assert_eq!(policy.decide(&req("TRACE"), false), MethodDecision::Reject);
+        assert_eq!(
+            policy.decide(&req("OPTIONS"), false),
+            MethodDecision::AnswerOptions
+        );
+        assert_eq!(policy.decide(&req("OPTIONS"), true), MethodDecision::Allow);
+
+        let policy: MethodPolicy =
+            serde_json::from_str(r#"{"allowed_methods": ["get", "POST"], "options": "Forward"}"#)
+                .unwrap();
+        assert_eq!(policy.decide(&req("HEAD"), false), MethodDecision::Allow);
+        assert_eq!(policy.decide(&req("DELETE"), false), MethodDecision::Reject);
+        assert_eq!(
+            policy.decide(&req("OPTIONS"), false),
+            MethodDecision::Reject
+        );
+        assert!(!policy.allows("PUT"));
+        assert_eq!(policy.allow_header(), "GET, POST, HEAD");
+        let resp = policy.reject_response().unwrap();
+        assert_eq!(resp.status, StatusCode::METHOD_NOT_ALLOWED);
+        assert_eq!(resp.headers["allow"], "GET, POST, HEAD");
+    }
+}
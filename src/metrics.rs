@@ -0,0 +1,509 @@
+//! Prometheus metrics labeled by route and customer, so operators can build per-tenant dashboards
+//! and billing reports from the proxy's exporter.
+//!
+//! Origin health metrics are labeled by route and origin host instead.  Their cardinality is
+//! bounded by the route configuration itself.
+//!
+//! Every distinct route/customer pair creates a new time series for each metric.  To protect the
+//! exporter (and Prometheus) from unbounded cardinality, only the first `max_label_values` pairs
+//! get their own labels; requests for any other pair are counted under the `other` label.
+
+use once_cell::sync::Lazy;
+use prometheus::{
+    register_histogram_vec, register_int_counter, register_int_counter_vec, register_int_gauge_vec,
+    HistogramVec, IntCounter, IntCounterVec, IntGaugeVec,
+};
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+use std::sync::RwLock;
+use std::time::Duration;
+
+use crate::instance::Instance;
+use crate::memory::MemoryUsage;
+
+/// The label used for requests that didn't match a route.
+const NO_ROUTE_LABEL: &str = "none";
+
+/// The label used for route/customer pairs beyond the cardinality limit.
+const OVERFLOW_LABEL: &str = "other";
+
+static REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "granite_requests_total",
+        "Requests handled, by route, customer, and response status",
+        &["route", "customer", "status"]
+    )
+    .unwrap()
+});
+
+static REQUEST_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "granite_request_duration_seconds",
+        "Time from receiving a request to finishing the response, by route and customer",
+        &["route", "customer"]
+    )
+    .unwrap()
+});
+
+static CACHE_RESULTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "granite_cache_results_total",
+        "Cache lookups, by route, customer, and cache status (hit, miss, etc.)",
+        &["route", "customer", "cache_status"]
+    )
+    .unwrap()
+});
+
+static SLOW_REQUESTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "granite_slow_requests_total",
+        "Requests exceeding the slow request threshold, by route and customer",
+        &["route", "customer"]
+    )
+    .unwrap()
+});
+
+static RESPONSE_BYTES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "granite_response_bytes_total",
+        "Response body bytes sent to clients, by route and customer",
+        &["route", "customer"]
+    )
+    .unwrap()
+});
+
+static ORIGIN_UP: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "granite_origin_up",
+        "Whether the origin is currently in service (1) or marked down (0), by route and origin",
+        &["route", "origin"]
+    )
+    .unwrap()
+});
+
+static ORIGIN_CONSECUTIVE_FAILURES: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "granite_origin_consecutive_failures",
+        "Failed connection attempts (including DNS failures) since the last successful connection, by route and origin",
+        &["route", "origin"]
+    )
+    .unwrap()
+});
+
+static ORIGIN_MARKED_DOWN: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "granite_origin_marked_down_total",
+        "Times the origin was marked down, by route and origin",
+        &["route", "origin"]
+    )
+    .unwrap()
+});
+
+static ORIGIN_DNS_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "granite_origin_dns_failures_total",
+        "Failures to resolve the origin's hostname, by route and origin",
+        &["route", "origin"]
+    )
+    .unwrap()
+});
+
+static ORIGIN_CONNECT_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "granite_origin_connect_duration_seconds",
+        "Time to establish new connections (TCP and TLS) to the origin, by route and origin",
+        &["route", "origin"]
+    )
+    .unwrap()
+});
+
+static ORIGIN_TLS_HANDSHAKES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "granite_origin_tls_handshakes_total",
+        "TLS handshakes made for new connections to the origin, by route and origin",
+        &["route", "origin"]
+    )
+    .unwrap()
+});
+
+static ORIGIN_TLS_HANDSHAKE_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "granite_origin_tls_handshake_duration_seconds",
+        "Time to make TLS handshakes with the origin, by route and origin",
+        &["route", "origin"]
+    )
+    .unwrap()
+});
+
+static ORIGIN_CONNECTIONS_ACTIVE: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "granite_origin_connections_active",
+        "Connections to the origin in use by requests, by route and origin",
+        &["route", "origin"]
+    )
+    .unwrap()
+});
+
+static ORIGIN_CONNECTIONS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "granite_origin_connections_total",
+        "Connections to the origin used by requests, by route, origin, and whether they were reused from the idle pool",
+        &["route", "origin", "reused"]
+    )
+    .unwrap()
+});
+
+static ORIGIN_CONNECTIONS_QUEUED: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "granite_origin_connections_queued",
+        "Requests waiting for a connection to an origin at its connection limit, by route and origin",
+        &["route", "origin"]
+    )
+    .unwrap()
+});
+
+static CACHE_LOCK_WAIT: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "granite_cache_lock_wait_seconds",
+        "Time requests waited for another request to fill a cache entry, by route",
+        &["route"]
+    )
+    .unwrap()
+});
+
+static CACHE_LOCK_TIMEOUTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "granite_cache_lock_timeouts_total",
+        "Requests that gave up waiting for another request to fill a cache entry, by route and the action taken (origin, stale, or error)",
+        &["route", "action"]
+    )
+    .unwrap()
+});
+
+static RETRY_BUDGET_EXHAUSTED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "granite_retry_budget_exhausted_total",
+        "Failed connections that weren't retried because a retry budget was exhausted, by route",
+        &["route"]
+    )
+    .unwrap()
+});
+
+static REDIRECTS_FOLLOWED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "granite_origin_redirects_followed_total",
+        "Redirects from origins followed by the proxy, by route",
+        &["route"]
+    )
+    .unwrap()
+});
+
+static ORIGIN_CONNECTION_QUEUE_TIMEOUTS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "granite_origin_connection_queue_timeouts_total",
+        "Requests that gave up waiting for a connection to an origin at its connection limit, by route and origin",
+        &["route", "origin"]
+    )
+    .unwrap()
+});
+
+static MEMORY_USED: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "granite_memory_bytes",
+        "Estimated memory used, by component (routes, certs, cache, in_flight)",
+        &["component"]
+    )
+    .unwrap()
+});
+
+static CACHE_TRIMMED_BYTES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "granite_cache_trimmed_bytes_total",
+        "Cache bytes evicted to relieve memory pressure"
+    )
+    .unwrap()
+});
+
+static SHED_REQUESTS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "granite_shed_requests_total",
+        "Requests rejected because memory usage was above the shed threshold"
+    )
+    .unwrap()
+});
+
+static TLS_FINGERPRINT_DENIED: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "granite_tls_fingerprint_denied_total",
+        "Requests rejected because of the client's TLS fingerprint"
+    )
+    .unwrap()
+});
+
+static CONFIG_ITEMS_EXPIRED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "granite_config_items_expired_total",
+        "Routes and certificate bindings deleted when their TTL expired, by kind",
+        &["kind"]
+    )
+    .unwrap()
+});
+
+static INSTANCE_INFO: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "granite_instance_info",
+        "The identity of this instance (always 1)",
+        &["instance", "pop"]
+    )
+    .unwrap()
+});
+
+/// Metrics exporter settings.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct MetricsConfig {
+    /// The socket address to serve metrics on (at any path).  Format is `ip:port`.  E.g.,
+    /// `127.0.0.1:9100`.  If not set, metrics aren't exported.
+    pub bind_addr: Option<String>,
+
+    /// The maximum number of distinct route/customer pairs that get their own labels.
+    pub max_label_values: usize,
+}
+
+impl Default for MetricsConfig {
+    /// By default, metrics aren't exported and up to 100 route/customer pairs are labeled.
+    fn default() -> Self {
+        MetricsConfig {
+            bind_addr: None,
+            max_label_values: 100,
+        }
+    }
+}
+
+/// What is recorded about a finished request.
+pub struct RequestRecord<'a> {
+    /// The matched route and its customer (if any).
+    pub route: Option<(&'a str, &'a str)>,
+    pub status: u16,
+    pub duration: Duration,
+    /// The cache status (if the response went through the cache phases).
+    pub cache_status: Option<&'static str>,
+    pub response_bytes: u64,
+    /// Whether the request exceeded the slow request threshold.
+    pub slow: bool,
+}
+
+/// Records request metrics while guarding label cardinality.
+pub struct RequestMetrics {
+    max_label_values: usize,
+    labeled: RwLock<HashSet<(String, String)>>,
+}
+
+impl RequestMetrics {
+    pub fn new(config: &MetricsConfig) -> Self {
+        RequestMetrics {
+            max_label_values: config.max_label_values,
+            labeled: RwLock::new(HashSet::new()),
+        }
+    }
+
+    /// The labels to use for the route/customer pair.
+    fn labels<'a>(&self, route: &'a str, customer: &'a str) -> (&'a str, &'a str) {
+        let key = (route.to_string(), customer.to_string());
+        if self.labeled.read().unwrap().contains(&key) {
+            return (route, customer);
+        }
+        let mut labeled = self.labeled.write().unwrap();
+        if labeled.contains(&key) || labeled.len() < self.max_label_values {
+            labeled.insert(key);
+            (route, customer)
+        } else {
+            (OVERFLOW_LABEL, OVERFLOW_LABEL)
+        }
+    }
+
+    pub fn record(&self, record: &RequestRecord) {
+        let (route, customer) = match record.route {
+            Some((route, customer)) => self.labels(route, customer),
+            None => (NO_ROUTE_LABEL, NO_ROUTE_LABEL),
+        };
+
+        REQUESTS
+            .with_label_values(&[route, customer, &record.status.to_string()])
+            .inc();
+        REQUEST_DURATION
+            .with_label_values(&[route, customer])
+            .observe(record.duration.as_secs_f64());
+        RESPONSE_BYTES
+            .with_label_values(&[route, customer])
+            .inc_by(record.response_bytes);
+        if record.slow {
+            SLOW_REQUESTS.with_label_values(&[route, customer]).inc();
+        }
+        if let Some(cache_status) = record.cache_status {
+            CACHE_RESULTS
+                .with_label_values(&[route, customer, cache_status])
+                .inc();
+        }
+    }
+}
+
+/// Export the identity of this instance (so it can be joined with the other metrics, whichever
+/// labels the scraper adds).
+pub fn instance_info(instance: &Instance) {
+    INSTANCE_INFO
+        .with_label_values(&[instance.id(), instance.pop().unwrap_or_default()])
+        .set(1);
+}
+
+/// Record a successful connection to an origin.  `connect` is how long it took to establish the
+/// connection (`None` if a pooled connection was reused).
+pub fn origin_connected(route: &str, origin: &str, connect: Option<Duration>) {
+    ORIGIN_CONSECUTIVE_FAILURES
+        .with_label_values(&[route, origin])
+        .set(0);
+    if let Some(connect) = connect {
+        ORIGIN_CONNECT_DURATION
+            .with_label_values(&[route, origin])
+            .observe(connect.as_secs_f64());
+    }
+}
+
+/// Record a TLS handshake with an origin (for a new connection).  `duration` is how long it took
+/// (if known).
+pub fn origin_tls_handshake(route: &str, origin: &str, duration: Option<Duration>) {
+    ORIGIN_TLS_HANDSHAKES
+        .with_label_values(&[route, origin])
+        .inc();
+    if let Some(duration) = duration {
+        ORIGIN_TLS_HANDSHAKE_DURATION
+            .with_label_values(&[route, origin])
+            .observe(duration.as_secs_f64());
+    }
+}
+
+/// Record a failed attempt to connect to an origin.
+pub fn origin_failed(route: &str, origin: &str, consecutive_failures: u32, dns: bool) {
+    ORIGIN_CONSECUTIVE_FAILURES
+        .with_label_values(&[route, origin])
+        .set(consecutive_failures.into());
+    if dns {
+        ORIGIN_DNS_FAILURES
+            .with_label_values(&[route, origin])
+            .inc();
+    }
+}
+
+/// Record that an origin was marked down (`down`) or returned to service.
+pub fn origin_state_changed(route: &str, origin: &str, down: bool) {
+    ORIGIN_UP
+        .with_label_values(&[route, origin])
+        .set(if down { 0 } else { 1 });
+    if down {
+        ORIGIN_MARKED_DOWN.with_label_values(&[route, origin]).inc();
+    }
+}
+
+/// Record a connection to an origin (new, or `reused` from the idle pool) taken in use.
+pub fn origin_connection_opened(route: &str, origin: &str, reused: bool) {
+    ORIGIN_CONNECTIONS
+        .with_label_values(&[route, origin, if reused { "true" } else { "false" }])
+        .inc();
+}
+
+/// Adjust the number of connections to an origin in use.
+pub fn origin_connection_active(route: &str, origin: &str, delta: i64) {
+    ORIGIN_CONNECTIONS_ACTIVE
+        .with_label_values(&[route, origin])
+        .add(delta);
+}
+
+/// Adjust the number of requests waiting for a connection to an origin.
+pub fn origin_connection_queued(route: &str, origin: &str, delta: i64) {
+    ORIGIN_CONNECTIONS_QUEUED
+        .with_label_values(&[route, origin])
+        .add(delta);
+}
+
+/// Record a request that gave up waiting for a connection to an origin.
+pub fn origin_connection_queue_timeout(route: &str, origin: &str) {
+    ORIGIN_CONNECTION_QUEUE_TIMEOUTS
+        .with_label_values(&[route, origin])
+        .inc();
+}
+
+/// Record that a request waited for a cache lock.
+pub fn cache_lock_waited(route: &str, wait: Duration) {
+    CACHE_LOCK_WAIT
+        .with_label_values(&[route])
+        .observe(wait.as_secs_f64());
+}
+
+/// Record that a request gave up waiting for a cache lock, and the action it took.
+pub fn cache_lock_timed_out(route: &str, action: &str) {
+    CACHE_LOCK_TIMEOUTS
+        .with_label_values(&[route, action])
+        .inc();
+}
+
+/// Record a failed connection that wasn't retried because a retry budget was exhausted.
+pub fn retry_budget_exhausted(route: &str) {
+    RETRY_BUDGET_EXHAUSTED.with_label_values(&[route]).inc();
+}
+
+/// Record that a redirect from an origin was followed.
+pub fn redirect_followed(route: &str) {
+    REDIRECTS_FOLLOWED.with_label_values(&[route]).inc();
+}
+
+/// Record the estimated memory usage.
+pub fn memory_used(usage: &MemoryUsage) {
+    for (component, bytes) in [
+        ("routes", usage.routes),
+        ("certs", usage.certs),
+        ("cache", usage.cache),
+        ("in_flight", usage.in_flight),
+    ] {
+        MEMORY_USED
+            .with_label_values(&[component])
+            .set(bytes as i64);
+    }
+}
+
+/// Record that cache entries were evicted to relieve memory pressure.
+pub fn cache_trimmed(bytes: usize) {
+    CACHE_TRIMMED_BYTES.inc_by(bytes as u64);
+}
+
+/// Record that a request was rejected because of memory pressure.
+pub fn request_shed() {
+    SHED_REQUESTS.inc();
+}
+
+/// Record that a request was rejected because of the client's TLS fingerprint.
+pub fn tls_fingerprint_denied() {
+    TLS_FINGERPRINT_DENIED.inc();
+}
+
+/// Record that a configuration item of a kind (`route` or `cert`) expired.
+pub fn config_item_expired(kind: &str) {
+    CONFIG_ITEMS_EXPIRED.with_label_values(&[kind]).inc();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn cardinality_guard() {
+        let metrics = RequestMetrics::new(&MetricsConfig {
+            max_label_values: 2,
+            ..Default::default()
+        });
+        assert_eq!(metrics.labels("r1", "c1"), ("r1", "c1"));
+        assert_eq!(metrics.labels("r2", "c1"), ("r2", "c1"));
+        assert_eq!(metrics.labels("r3", "c2"), ("other", "other"));
+        // Pairs that were already labeled keep their labels.
+        assert_eq!(metrics.labels("r1", "c1"), ("r1", "c1"));
+    }
+}
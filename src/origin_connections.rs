@@ -0,0 +1,154 @@
+//! Upstream connections per origin.  The connections in use by requests are counted (and exported
+//! as metrics), and an origin can cap them, so a fragile origin with a small accept queue isn't
+//! flooded: requests over the cap wait in a queue for a connection to be released, and give up with
+//! a 503 if none is released in time.
+//!
+//! Pingora keeps idle connections in its own pool, which isn't observable, so idle connections are
+//! reflected only by how often new connections are opened rather than pooled ones reused.  A
+//! request holds its connection until it finishes, so for HTTP/1.1 origins (one request per
+//! connection) the cap is also the number of open connections.  Requests multiplexed over an HTTP/2
+//! connection are each counted.
+
+use pingora::{Error, ErrorType::HTTPStatus, Result};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::metrics;
+use crate::route_config::Origin;
+
+/// An origin's connection limit.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct ConnectionLimit {
+    /// The maximum number of connections to the origin in use at once (by this instance).
+    pub max_connections: u32,
+
+    /// How long (in milliseconds) a request waits for a connection before it's answered with a 503.
+    #[serde(default = "default_queue_timeout")]
+    pub queue_timeout: u64,
+}
+
+fn default_queue_timeout() -> u64 {
+    5000
+}
+
+/// The connection limits of a route's origins (by index within the origin group).
+#[derive(Debug, Default)]
+pub struct ConnectionLimits(Vec<Option<Arc<Semaphore>>>);
+
+/// Entitles a request to a connection to a limited origin until dropped.
+#[derive(Debug)]
+pub struct ConnectionPermit {
+    _permit: OwnedSemaphorePermit,
+}
+
+/// Counts a connection to an origin as in use until dropped.
+#[derive(Debug)]
+pub struct ActiveConnection {
+    route: String,
+    origin: String,
+}
+
+impl ConnectionLimits {
+    pub fn new(origins: &[Arc<Origin>]) -> Self {
+        ConnectionLimits(
+            origins
+                .iter()
+                .map(|origin| {
+                    let limit = origin.connection_limit.as_ref()?;
+                    Some(Arc::new(Semaphore::new(limit.max_connections as usize)))
+                })
+                .collect(),
+        )
+    }
+
+    /// Wait for a connection to the origin to be available.  Return `Ok(None)` if the origin isn't
+    /// limited, and a 503 error if the wait timed out.
+    pub async fn acquire(
+        &self,
+        route: &str,
+        origin_index: usize,
+        origin: &Origin,
+    ) -> Result<Option<ConnectionPermit>> {
+        let (Some(Some(semaphore)), Some(limit)) =
+            (self.0.get(origin_index), &origin.connection_limit)
+        else {
+            return Ok(None);
+        };
+        if let Ok(permit) = semaphore.clone().try_acquire_owned() {
+            return Ok(Some(ConnectionPermit { _permit: permit }));
+        }
+
+        metrics::origin_connection_queued(route, &origin.host, 1);
+        let timeout = Duration::from_millis(limit.queue_timeout);
+        let permit = tokio::time::timeout(timeout, semaphore.clone().acquire_owned()).await;
+        metrics::origin_connection_queued(route, &origin.host, -1);
+        match permit {
+            Ok(Ok(permit)) => Ok(Some(ConnectionPermit { _permit: permit })),
+            _ => {
+                metrics::origin_connection_queue_timeout(route, &origin.host);
+                Error::e_explain(
+                    HTTPStatus(503),
+                    "Timed out waiting for a connection to the origin",
+                )
+            }
+        }
+    }
+
+    /// The connections to the origin that are free (if it's limited).
+    pub fn available(&self, origin_index: usize) -> Option<usize> {
+        let semaphore = self.0.get(origin_index)?.as_ref()?;
+        Some(semaphore.available_permits())
+    }
+}
+
+impl ActiveConnection {
+    /// Count a connection to the origin (new, or `reused` from the pool) as in use.
+    pub fn new(route: &str, origin: &str, reused: bool) -> Self {
+        metrics::origin_connection_opened(route, origin, reused);
+        metrics::origin_connection_active(route, origin, 1);
+        ActiveConnection {
+            route: route.to_string(),
+            origin: origin.to_string(),
+        }
+    }
+}
+
+impl Drop for ActiveConnection {
+    fn drop(&mut self) {
+        metrics::origin_connection_active(&self.route, &self.origin, -1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn limit() {
+        let origin: Arc<Origin> = serde_json::from_str(
+            r#"{"host": "o1.com", "connection_limit": {"max_connections": 1, "queue_timeout": 50}}"#,
+        )
+        .unwrap();
+        let unlimited: Arc<Origin> = serde_json::from_str(r#"{"host": "o2.com"}"#).unwrap();
+        let limits = ConnectionLimits::new(&[origin.clone(), unlimited.clone()]);
+
+        assert!(limits.acquire("r", 1, &unlimited).await.unwrap().is_none());
+        assert_eq!(limits.available(1), None);
+
+        let permit = limits.acquire("r", 0, &origin).await.unwrap();
+        assert!(permit.is_some());
+        assert_eq!(limits.available(0), Some(0));
+        assert!(limits.acquire("r", 0, &origin).await.is_err());
+
+        // A queued request gets the connection once it's released.
+        let release = async {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+            drop(permit);
+        };
+        let (waited, _) = tokio::join!(limits.acquire("r", 0, &origin), release);
+        assert!(waited.unwrap().is_some());
+        assert_eq!(limits.available(0), Some(1));
+    }
+}
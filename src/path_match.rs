@@ -0,0 +1,288 @@
+//! Matching of request paths against route paths.  A route path is a prefix by default; it can also
+//! be an exact path or a regular expression, for patterns like `^/api/v[0-9]+/users/` that would
+//! otherwise need a prefix per version.
+//!
+//! For a host, exact paths take precedence, then regular expressions (in the order their routes
+//! were added), then the longest matching prefix.  Exact paths are looked up in a map, the regular
+//! expressions of all the host's routes are evaluated in a single pass (as a `RegexSet`), and
+//! prefixes are looked up in a trie, so the number of routes of a host barely affects lookups.
+//! (Should the set of a host's regular expressions exceed the size limit, they're evaluated one by
+//! one instead; routes whose own regular expressions can't form a set are rejected when added.)
+
+use log::warn;
+use regex::{Regex, RegexSet};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::route_store::Route;
+use crate::route_trie::PathTrie;
+
+/// How a route path matches request paths.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum PathMatchType {
+    /// Paths starting with the route path.
+    #[default]
+    Prefix,
+    /// Only the route path itself.
+    Exact,
+    /// Paths matching the route path as a regular expression (unanchored unless it uses `^` and
+    /// `$`).
+    Regex,
+}
+
+/// A route path, as configured: either a string (a prefix), or an object with the `path` and its
+/// `path_match_type`.
+#[derive(Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum RoutePathConfig {
+    Prefix(String),
+    Typed {
+        path: String,
+        #[serde(default)]
+        path_match_type: PathMatchType,
+    },
+}
+
+/// A route path, with its regular expression compiled when the route is parsed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(try_from = "RoutePathConfig", into = "RoutePathConfig")]
+pub struct RoutePath {
+    pub path: String,
+    pub match_type: PathMatchType,
+    regex: Option<Regex>,
+}
+
+impl TryFrom<RoutePathConfig> for RoutePath {
+    type Error = String;
+
+    fn try_from(config: RoutePathConfig) -> Result<Self, Self::Error> {
+        let (path, match_type) = match config {
+            RoutePathConfig::Prefix(path) => (path, PathMatchType::Prefix),
+            RoutePathConfig::Typed {
+                path,
+                path_match_type,
+            } => (path, path_match_type),
+        };
+        let regex = match match_type {
+            PathMatchType::Regex => {
+                Some(Regex::new(&path).map_err(|e| format!("Invalid path regex {path}: {e}"))?)
+            }
+            _ => None,
+        };
+        Ok(RoutePath {
+            path,
+            match_type,
+            regex,
+        })
+    }
+}
+
+impl From<RoutePath> for RoutePathConfig {
+    fn from(path: RoutePath) -> Self {
+        match path.match_type {
+            PathMatchType::Prefix => RoutePathConfig::Prefix(path.path),
+            path_match_type => RoutePathConfig::Typed {
+                path: path.path,
+                path_match_type,
+            },
+        }
+    }
+}
+
+impl From<&str> for RoutePath {
+    /// A prefix.
+    fn from(path: &str) -> Self {
+        RoutePath {
+            path: path.to_string(),
+            match_type: PathMatchType::Prefix,
+            regex: None,
+        }
+    }
+}
+
+impl PartialEq for RoutePath {
+    fn eq(&self, other: &Self) -> bool {
+        self.path == other.path && self.match_type == other.match_type
+    }
+}
+
+impl Eq for RoutePath {}
+
+impl RoutePath {
+    /// An exact path.
+    pub fn exact(path: &str) -> Self {
+        RoutePath {
+            path: path.to_string(),
+            match_type: PathMatchType::Exact,
+            regex: None,
+        }
+    }
+
+    /// Whether the request path matches.
+    pub fn matches(&self, path: &str) -> bool {
+        match (self.match_type, self.regex.as_ref()) {
+            (PathMatchType::Prefix, _) => path.starts_with(&self.path),
+            (PathMatchType::Exact, _) => path == self.path,
+            (PathMatchType::Regex, Some(regex)) => regex.is_match(path),
+            (PathMatchType::Regex, None) => false,
+        }
+    }
+}
+
+/// Check that the regular expressions of a route's paths can be evaluated in a single pass.
+pub fn check_patterns(paths: &[RoutePath]) -> Result<(), String> {
+    let patterns = paths
+        .iter()
+        .filter(|path| path.match_type == PathMatchType::Regex)
+        .map(|path| path.path.as_str());
+    RegexSet::new(patterns)
+        .map(|_| ())
+        .map_err(|e| format!("Unable to compile the path regexes: {e}"))
+}
+
+/// The paths of a host's routes, indexed by match type.
+#[derive(Debug, Clone, Default)]
+pub struct PathIndex {
+    exact: HashMap<String, Vec<Arc<Route>>>,
+    /// The routes with regular expressions (and the index of each regular expression in the
+    /// route's paths), in the order of `pattern_set`.
+    patterns: Vec<(Arc<Route>, usize)>,
+    /// All the regular expressions, unless they exceed the size limit together.
+    pattern_set: Option<RegexSet>,
+    prefixes: PathTrie,
+}
+
+impl PathIndex {
+    /// Index the paths of the routes.
+    pub fn new(routes: &[Arc<Route>]) -> Self {
+        let mut index = PathIndex::default();
+        for route in routes {
+            for (i, path) in route.config.paths.iter().enumerate() {
+                match path.match_type {
+                    PathMatchType::Prefix => index.prefixes.insert(&path.path, route.clone()),
+                    PathMatchType::Exact => index
+                        .exact
+                        .entry(path.path.clone())
+                        .or_default()
+                        .push(route.clone()),
+                    PathMatchType::Regex => index.patterns.push((route.clone(), i)),
+                }
+            }
+        }
+        if !index.patterns.is_empty() {
+            // Each route's regular expressions were checked when it was added, but the set of all
+            // the host's may still exceed the size limit.
+            let patterns = index
+                .patterns
+                .iter()
+                .map(|(route, i)| route.config.paths[*i].path.as_str());
+            match RegexSet::new(patterns) {
+                Ok(set) => index.pattern_set = Some(set),
+                Err(e) => warn!(
+                    "Unable to compile a host's path regexes together ({e}); matching them one by one"
+                ),
+            }
+        }
+        index
+    }
+
+    /// Find the routes with a path matching the path: exact paths first, then regular expressions,
+    /// then prefixes (longest first).  A route may be returned more than once.
+    pub fn find(&self, path: &str) -> Vec<&Arc<Route>> {
+        let mut routes: Vec<&Arc<Route>> = self.exact.get(path).into_iter().flatten().collect();
+        match &self.pattern_set {
+            Some(set) => routes.extend(set.matches(path).into_iter().map(|i| &self.patterns[i].0)),
+            None => routes.extend(
+                self.patterns
+                    .iter()
+                    .filter(|(route, i)| route.config.paths[*i].matches(path))
+                    .map(|(route, _)| route),
+            ),
+        }
+        routes.extend(self.prefixes.find(path));
+        routes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::route_config::RouteConfig;
+
+    #[test]
+    fn parse() {
+        let paths: Vec<RoutePath> = serde_json::from_value(serde_json::json!([
+            "/static",
+            {"path": "/health", "path_match_type": "Exact"},
+            {"path": "^/api/v[0-9]+/", "path_match_type": "Regex"},
+        ]))
+        .unwrap();
+        assert_eq!(paths[0], RoutePath::from("/static"));
+        assert!(paths[0].matches("/static/a.css"));
+        assert!(paths[1].matches("/health"));
+        assert!(!paths[1].matches("/health/db"));
+        assert!(paths[2].matches("/api/v2/users"));
+        assert!(!paths[2].matches("/api/vx/users"));
+
+        // Prefixes are written back as strings.
+        assert_eq!(
+            serde_json::to_value(&paths[..2]).unwrap(),
+            serde_json::json!(["/static", {"path": "/health", "path_match_type": "Exact"}])
+        );
+
+        assert!(serde_json::from_value::<RoutePath>(
+            serde_json::json!({"path": "(", "path_match_type": "Regex"})
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn precedence() {
+        let route = |name: &str, paths: serde_json::Value| {
+            Arc::new(Route {
+                config: RouteConfig {
+                    name: name.to_string(),
+                    paths: serde_json::from_value(paths).unwrap(),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+        };
+        let index = PathIndex::new(&[
+            route("root", serde_json::json!(["/"])),
+            route("api", serde_json::json!(["/api/"])),
+            route(
+                "users",
+                serde_json::json!([{"path": "^/api/v[0-9]+/users/", "path_match_type": "Regex"}]),
+            ),
+            route(
+                "login",
+                serde_json::json!([{"path": "/api/v1/users/login", "path_match_type": "Exact"}]),
+            ),
+        ]);
+        let names = |index: &PathIndex, path| -> Vec<String> {
+            index
+                .find(path)
+                .iter()
+                .map(|r| r.config.name.clone())
+                .collect()
+        };
+        assert_eq!(
+            names(&index, "/api/v1/users/login"),
+            ["login", "users", "api", "root"]
+        );
+        assert_eq!(names(&index, "/api/v12/users/7"), ["users", "api", "root"]);
+        assert_eq!(names(&index, "/api/v1/orders"), ["api", "root"]);
+        assert_eq!(names(&index, "/index.html"), ["root"]);
+
+        // Without the set (when it exceeds the size limit), the regular expressions are evaluated
+        // one by one.
+        let index = PathIndex {
+            pattern_set: None,
+            ..index
+        };
+        assert_eq!(names(&index, "/api/v12/users/7"), ["users", "api", "root"]);
+        assert_eq!(names(&index, "/api/v1/orders"), ["api", "root"]);
+    }
+}
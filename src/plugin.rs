@@ -0,0 +1,161 @@
+//! Plugins: extensions of the proxy's request handling, compiled in or registered by an embedding
+//! binary (see `GraniteBuilder::plugin`), and enabled per route.
+//!
+//! A plugin implements any of the hooks of `Plugin`, which are called at the corresponding phases
+//! of the requests on the routes that enable it (in the order the route lists its plugins), after
+//! the proxy's own handling for the phase.  A route enables a plugin by name and can give it
+//! settings of its own:
+//!
+//! ```json
+//! "plugins": [{"name": "tenant-header", "config": {"header": "x-tenant"}}]
+//! ```
+
+use async_trait::async_trait;
+use http::Extensions;
+use log::debug;
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::proxy::Session;
+use pingora::{Error, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use crate::route_config::RouteConfig;
+
+/// A plugin enabled on a route.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct PluginRef {
+    /// The name the plugin is registered under.
+    pub name: String,
+
+    /// The route's settings for the plugin (interpreted by the plugin).
+    #[serde(default)]
+    pub config: serde_json::Value,
+}
+
+/// What a plugin hook gets to know about the request besides the session.
+pub struct PluginContext<'a> {
+    /// The matched route.
+    pub route: &'a RouteConfig,
+
+    /// The route's settings for the plugin.
+    pub config: &'a serde_json::Value,
+
+    /// State kept for the duration of the request (shared by all the plugins, keyed by type).
+    pub state: &'a mut Extensions,
+}
+
+/// An extension of the proxy's request handling.  Each hook does nothing by default.
+#[async_trait]
+pub trait Plugin: Send + Sync {
+    /// Inspect the request once the route has been matched and the request has passed the route's
+    /// access controls.  Return `true` if the plugin sent a response (ending the request).
+    async fn request_filter(
+        &self,
+        _session: &mut Session,
+        _ctx: &mut PluginContext<'_>,
+    ) -> Result<bool> {
+        Ok(false)
+    }
+
+    /// Modify the request sent to the origin.
+    async fn upstream_request_filter(
+        &self,
+        _session: &mut Session,
+        _upstream_request: &mut RequestHeader,
+        _ctx: &mut PluginContext<'_>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Modify the response headers sent to the client.
+    async fn response_filter(
+        &self,
+        _session: &mut Session,
+        _response: &mut ResponseHeader,
+        _ctx: &mut PluginContext<'_>,
+    ) -> Result<()> {
+        Ok(())
+    }
+
+    /// Observe the finished request (`error` is set if it failed).
+    async fn logging(
+        &self,
+        _session: &mut Session,
+        _error: Option<&Error>,
+        _ctx: &mut PluginContext<'_>,
+    ) {
+    }
+}
+
+/// The plugins available to routes, by name.
+#[derive(Default)]
+pub struct PluginRegistry {
+    plugins: HashMap<String, Arc<dyn Plugin>>,
+}
+
+impl PluginRegistry {
+    pub fn new() -> Self {
+        PluginRegistry::default()
+    }
+
+    /// Register a plugin under a name (replacing any registered under the same name).
+    pub fn register(&mut self, name: &str, plugin: Arc<dyn Plugin>) {
+        self.plugins.insert(name.to_string(), plugin);
+    }
+
+    /// The plugins a route enables, with the route's settings for them, in order.  Plugins that
+    /// aren't registered are skipped.
+    pub fn for_route<'a>(
+        &'a self,
+        route: &'a RouteConfig,
+    ) -> impl Iterator<Item = (&'a Arc<dyn Plugin>, &'a serde_json::Value)> {
+        route.plugins.iter().filter_map(|plugin_ref| {
+            let plugin = self.plugins.get(&plugin_ref.name);
+            if plugin.is_none() {
+                debug!(
+                    "Route '{}' enables unregistered plugin '{}'",
+                    &route.name, &plugin_ref.name
+                );
+            }
+            plugin.map(|plugin| (plugin, &plugin_ref.config))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    struct Noop;
+    impl Plugin for Noop {}
+
+    #[test]
+    fn route_plugins() {
+        let mut registry = PluginRegistry::new();
+        registry.register("a", Arc::new(Noop));
+        registry.register("b", Arc::new(Noop));
+
+        let route: RouteConfig = serde_json::from_str(
+            r#"{
+                "name": "r",
+                "customer": "c",
+                "hosts": ["example.com"],
+                "paths": ["/"],
+                "incoming_schemes": ["Http"],
+                "origin_group": {"origins": []},
+                "plugins": [{"name": "b", "config": {"n": 1}}, {"name": "missing"}, {"name": "a"}]
+            }"#,
+        )
+        .unwrap();
+
+        let configs: Vec<_> = registry
+            .for_route(&route)
+            .map(|(_, config)| config.clone())
+            .collect();
+        assert_eq!(
+            configs,
+            [serde_json::json!({"n": 1}), serde_json::Value::Null]
+        );
+    }
+}
@@ -0,0 +1,82 @@
+//! Caching of POST responses.  Caching routes only cache GET and HEAD requests unless they opt into
+//! caching POST requests (e.g., for search APIs whose queries are sent in the body), which are
+//! then keyed on a hash of their body as well as their URI.
+//!
+//! To compute the hash, the body is read before the request is forwarded (and buffered for the
+//! origin), so only bodies with a `Content-Length` up to the policy's limit are considered; other
+//! POST requests aren't cached.
+
+use pingora::http::RequestHeader;
+use pingora::proxy::Session;
+use pingora::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+/// The largest body that can be hashed (the size of Pingora's buffer for replaying a request body
+/// to the origin).
+pub const MAX_BODY_SIZE: usize = 64 * 1024;
+
+/// A POST caching policy for a route.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct PostCachePolicy {
+    /// The size (in bytes) of the largest body to cache the response for (at most 64 KiB).
+    #[serde(default = "default_max_body_size")]
+    pub max_body_size: usize,
+}
+
+fn default_max_body_size() -> usize {
+    16 * 1024
+}
+
+impl PostCachePolicy {
+    /// Whether the request is a POST with a body small enough to be cached.
+    pub fn applies_to(&self, req: &RequestHeader) -> bool {
+        if req.method != http::Method::POST {
+            return false;
+        }
+        req.headers
+            .get(http::header::CONTENT_LENGTH)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.parse::<usize>().ok())
+            .is_some_and(|len| len <= self.max_body_size.min(MAX_BODY_SIZE))
+    }
+
+    /// Read the request's body, returning its hash.  The body is kept so it can still be sent to
+    /// the origin.
+    pub async fn hash_body(session: &mut Session) -> Result<String> {
+        session.as_mut().enable_retry_buffering();
+        let mut hasher = Sha256::new();
+        while let Some(chunk) = session.read_request_body().await? {
+            hasher.update(&chunk);
+        }
+        Ok(hex::encode(hasher.finalize()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn applies_to() {
+        let policy: PostCachePolicy = serde_json::from_str("{}").unwrap();
+        let request = |method: &str, len: Option<usize>| {
+            let mut req = RequestHeader::build(method, b"/search", None).unwrap();
+            if let Some(len) = len {
+                req.insert_header("content-length", len.to_string())
+                    .unwrap();
+            }
+            req
+        };
+        assert!(policy.applies_to(&request("POST", Some(100))));
+        assert!(policy.applies_to(&request("POST", Some(0))));
+        assert!(!policy.applies_to(&request("POST", Some(20_000))));
+        assert!(!policy.applies_to(&request("POST", None)));
+        assert!(!policy.applies_to(&request("PUT", Some(100))));
+
+        let large = PostCachePolicy {
+            max_body_size: 1 << 20,
+        };
+        assert!(!large.applies_to(&request("POST", Some(MAX_BODY_SIZE + 1))));
+    }
+}
@@ -0,0 +1,310 @@
+//! Prewarming of connections to origins, so the first requests after an idle period (or after an
+//! origin recovers) don't pay for the connection's TCP and TLS handshakes.
+//!
+//! Pingora keeps idle upstream connections in a pool that only its proxy can fill, so connections
+//! are prewarmed by sending requests through the proxy itself: every half of an origin's idle TTL,
+//! the prewarmer sends the policy's number of concurrent `HEAD` requests to the proxy's own HTTP
+//! listener, each carrying a secret token (generated at startup) and the route and origin to
+//! forward it to.  The proxy forwards them to that origin with the same connection settings as
+//! user requests (bypassing the route's filters and cache, and leaving them out of metrics and the
+//! access log), and pools their connections once they're answered.  Pooled connections to the
+//! origin are closed after the idle TTL.
+//!
+//! Prewarm requests also check the origin's health: an origin they can't connect to is marked
+//! down, just like after failed connection attempts by user requests.  Origins that are down are
+//! still prewarmed, so their connections are ready when they recover.
+
+use async_trait::async_trait;
+use log::{debug, info};
+use pingora::connectors::http::Connector;
+use pingora::http::RequestHeader;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use pingora::upstreams::peer::HttpPeer;
+use pingora::Result;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::task::JoinSet;
+
+use crate::app_config::ProxyConfig;
+use crate::route_config::{IncomingScheme, OutgoingScheme, RouteConfig};
+use crate::route_store::RouteStore;
+
+/// The header carrying a prewarm request's token and target.
+pub const PREWARM_HEADER: &str = "x-granite-prewarm";
+
+/// How often the prewarmer checks for origins that are due.
+const TICK: Duration = Duration::from_secs(1);
+
+/// How long to wait for a prewarm request's response.
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An origin's connection prewarming.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct PrewarmPolicy {
+    /// The number of idle connections to keep ready (for each scheme the route forwards with).
+    pub connections: u32,
+
+    /// How long (in seconds) idle connections to the origin are kept.
+    pub idle_ttl: u64,
+
+    /// The path of the prewarm requests.
+    pub path: String,
+}
+
+impl Default for PrewarmPolicy {
+    /// By default, 2 connections, kept for 60 seconds, prewarmed with `HEAD /`.
+    fn default() -> Self {
+        PrewarmPolicy {
+            connections: 2,
+            idle_ttl: 60,
+            path: "/".to_string(),
+        }
+    }
+}
+
+/// The origin a prewarm request is forwarded to.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct PrewarmTarget {
+    pub route: String,
+    pub origin_index: usize,
+    pub use_tls: bool,
+}
+
+/// Sends the prewarm requests, and recognizes them when the proxy receives them.
+pub struct Prewarmer {
+    route_store: Arc<RouteStore>,
+    /// The proxy's own HTTP listener (`None` if it has none, which disables prewarming).
+    addr: Option<SocketAddr>,
+    /// Proves that a request was sent by the prewarmer.
+    token: String,
+    connector: Arc<Connector>,
+    /// When each origin was last prewarmed (by route name and origin index).
+    last_prewarmed: Mutex<HashMap<(String, usize), Instant>>,
+}
+
+impl Prewarmer {
+    pub fn new(proxy_config: &ProxyConfig, route_store: Arc<RouteStore>) -> Self {
+        let addr = proxy_config
+            .http_bind_addrs
+            .iter()
+            .find_map(|addr| addr.parse::<SocketAddr>().ok())
+            .map(|mut addr| {
+                // Reach a wildcard listener on the loopback interface.
+                match addr.ip() {
+                    IpAddr::V4(ip) if ip.is_unspecified() => {
+                        addr.set_ip(Ipv4Addr::LOCALHOST.into())
+                    }
+                    IpAddr::V6(ip) if ip.is_unspecified() => {
+                        addr.set_ip(Ipv6Addr::LOCALHOST.into())
+                    }
+                    _ => {}
+                }
+                addr
+            });
+        Prewarmer {
+            route_store,
+            addr,
+            token: format!("{:032x}", rand::random::<u128>()),
+            connector: Arc::new(Connector::new(None)),
+            last_prewarmed: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Whether connections can be prewarmed (the proxy has an HTTP listener to send the requests
+    /// to).
+    pub fn is_enabled(&self) -> bool {
+        self.addr.is_some()
+    }
+
+    /// The origin a request is to be forwarded to, if it's a prewarm request (carrying the token).
+    pub fn target(&self, req: &RequestHeader) -> Option<PrewarmTarget> {
+        let value = req.headers.get(PREWARM_HEADER)?.to_str().ok()?;
+        let mut parts = value.splitn(4, ' ');
+        if parts.next()? != self.token {
+            return None;
+        }
+        let origin_index = parts.next()?.parse().ok()?;
+        let use_tls = match parts.next()? {
+            "http" => false,
+            "https" => true,
+            _ => return None,
+        };
+        Some(PrewarmTarget {
+            route: parts.next()?.to_string(),
+            origin_index,
+            use_tls,
+        })
+    }
+
+    /// Prewarm the origins that are due.
+    async fn prewarm_due(&self) {
+        let Some(addr) = self.addr else {
+            return;
+        };
+        let routes = self.route_store.routes();
+        let now = Instant::now();
+        let mut due = Vec::new();
+        {
+            let mut last_prewarmed = self.last_prewarmed.lock().unwrap();
+            last_prewarmed.retain(|(name, index), _| {
+                routes.iter().any(|route| {
+                    route.config.name == *name
+                        && route
+                            .config
+                            .origin_group
+                            .origins
+                            .get(*index)
+                            .is_some_and(|origin| origin.prewarm.is_some())
+                })
+            });
+            for route in &routes {
+                for (index, origin) in route.config.origin_group.origins.iter().enumerate() {
+                    let Some(policy) = origin.prewarm.as_ref() else {
+                        continue;
+                    };
+                    let key = (route.config.name.clone(), index);
+                    let interval = Duration::from_secs(policy.idle_ttl) / 2;
+                    if last_prewarmed
+                        .get(&key)
+                        .is_some_and(|last| now.duration_since(*last) < interval)
+                    {
+                        continue;
+                    }
+                    last_prewarmed.insert(key, now);
+                    due.push((route.clone(), index));
+                }
+            }
+        }
+
+        for (route, index) in due {
+            let config = &route.config;
+            let Some(policy) = config.origin_group.origins[index].prewarm.as_ref() else {
+                continue;
+            };
+            let Some(host) = config.hosts.iter().find(|host| !host.starts_with("*.")) else {
+                debug!("Route '{}' has no exact host to prewarm with", config.name);
+                continue;
+            };
+            // The requests are sent at once, so each of them gets a connection of its own.
+            let mut requests = JoinSet::new();
+            for use_tls in schemes(config) {
+                for _ in 0..policy.connections {
+                    let value = format!(
+                        "{} {index} {} {}",
+                        self.token,
+                        if use_tls { "https" } else { "http" },
+                        config.name
+                    );
+                    requests.spawn(send(
+                        self.connector.clone(),
+                        addr,
+                        host.clone(),
+                        policy.path.clone(),
+                        value,
+                    ));
+                }
+            }
+            while let Some(result) = requests.join_next().await {
+                if let Ok(Err(e)) = result {
+                    debug!(
+                        "Prewarm request for origin {index} of route '{}' failed: {e}",
+                        config.name
+                    );
+                }
+            }
+        }
+    }
+}
+
+/// Send a prewarm request to the proxy and wait for the response header.
+async fn send(
+    connector: Arc<Connector>,
+    addr: SocketAddr,
+    host: String,
+    path: String,
+    value: String,
+) -> Result<()> {
+    let mut peer = HttpPeer::new(addr, false, String::new());
+    peer.options.connection_timeout = Some(TIMEOUT);
+    peer.options.read_timeout = Some(TIMEOUT);
+    peer.options.write_timeout = Some(TIMEOUT);
+
+    let mut req = RequestHeader::build("HEAD", path.as_bytes(), None)?;
+    req.insert_header(http::header::HOST, host)?;
+    req.insert_header(http::header::USER_AGENT, "granite-prewarm")?;
+    req.insert_header(PREWARM_HEADER, value)?;
+    let (mut session, _reused) = connector.get_http_session(&peer).await?;
+    session.write_request_header(Box::new(req)).await?;
+    session.finish_request_body().await?;
+    session.read_response_header().await?;
+    Ok(())
+}
+
+/// Whether connections for the route use TLS, for each scheme its requests are forwarded with.
+/// (Port mappings are ignored.)
+fn schemes(route: &RouteConfig) -> Vec<bool> {
+    match route.outgoing_scheme {
+        OutgoingScheme::Http => vec![false],
+        OutgoingScheme::Https => vec![true],
+        OutgoingScheme::MatchIncoming => [IncomingScheme::Http, IncomingScheme::Https]
+            .iter()
+            .filter(|scheme| route.incoming_schemes.contains(scheme))
+            .map(|scheme| *scheme == IncomingScheme::Https)
+            .collect(),
+    }
+}
+
+#[async_trait]
+impl BackgroundService for Prewarmer {
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let Some(addr) = self.addr else {
+            return;
+        };
+        info!("Prewarming origin connections through {addr}");
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(TICK) => {}
+                _ = shutdown.changed() => break,
+            }
+            self.prewarm_due().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn target() {
+        let prewarmer = Prewarmer::new(&ProxyConfig::default(), Arc::new(RouteStore::new()));
+        assert_eq!(
+            prewarmer.addr,
+            Some(SocketAddr::from(([127, 0, 0, 1], 8080)))
+        );
+        let request = |value: &str| {
+            let mut req = RequestHeader::build("HEAD", b"/", None).unwrap();
+            req.insert_header(PREWARM_HEADER, value).unwrap();
+            req
+        };
+        let value = format!("{} 1 https my route", prewarmer.token);
+        assert_eq!(
+            prewarmer.target(&request(&value)),
+            Some(PrewarmTarget {
+                route: "my route".to_string(),
+                origin_index: 1,
+                use_tls: true,
+            })
+        );
+        assert_eq!(prewarmer.target(&request("forged 1 https r1")), None);
+        assert_eq!(
+            prewarmer.target(&RequestHeader::build("HEAD", b"/", None).unwrap()),
+            None
+        );
+    }
+}
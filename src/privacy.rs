@@ -0,0 +1,113 @@
+//! Client IP privacy.  A route can have the addresses of its clients truncated or hashed before
+//! they appear in the access log and request summaries, reach its scripts and WebAssembly filters,
+//! or are sent to its origins in forwarding headers (e.g., `X-Forwarded-For`), to meet GDPR-style
+//! requirements while keeping coarse analytics (truncated addresses still locate networks, and
+//! hashed ones still count clients).
+//!
+//! Hashes are keyed with a random secret generated at startup, so they can't be reversed by hashing
+//! every address, and are only comparable among the requests handled by the same process.
+
+use once_cell::sync::Lazy;
+use pingora::http::RequestHeader;
+use pingora::Result;
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::net::IpAddr;
+
+/// The secret key of the hashes.
+static HASH_KEY: Lazy<[u8; 32]> = Lazy::new(rand::random);
+
+/// The headers carrying client addresses that are rewritten (`Forwarded` is removed instead, since
+/// it mixes addresses with other parameters).
+const FORWARDING_HEADERS: [&str; 2] = ["x-forwarded-for", "x-real-ip"];
+
+/// How a route's client addresses are recorded.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum ClientIpPrivacy {
+    /// The full address.
+    #[default]
+    Full,
+
+    /// The address of the network: IPv4 addresses are truncated to /24 and IPv6 addresses to /48.
+    Truncate,
+
+    /// A keyed hash of the address.
+    Hash,
+}
+
+impl ClientIpPrivacy {
+    /// The client address as recorded.
+    pub fn anonymize(&self, ip: IpAddr) -> String {
+        match (self, ip) {
+            (ClientIpPrivacy::Full, _) => ip.to_string(),
+            (ClientIpPrivacy::Truncate, IpAddr::V4(v4)) => {
+                let [a, b, c, _] = v4.octets();
+                IpAddr::from([a, b, c, 0]).to_string()
+            }
+            (ClientIpPrivacy::Truncate, IpAddr::V6(v6)) => {
+                let mut octets = v6.octets();
+                octets[6..].fill(0);
+                IpAddr::from(octets).to_string()
+            }
+            (ClientIpPrivacy::Hash, _) => {
+                let mut hasher = Sha256::new();
+                hasher.update(HASH_KEY.as_slice());
+                hasher.update(ip.to_string());
+                hex::encode(&hasher.finalize()[..8])
+            }
+        }
+    }
+
+    /// Anonymize the addresses in the request's forwarding headers.  Entries that aren't addresses
+    /// (e.g., `unknown`) are dropped.
+    pub fn anonymize_headers(&self, req: &mut RequestHeader) -> Result<()> {
+        if *self == ClientIpPrivacy::Full {
+            return Ok(());
+        }
+        for name in FORWARDING_HEADERS {
+            let Some(value) = req.headers.get(name) else {
+                continue;
+            };
+            let anonymized: Vec<_> = String::from_utf8_lossy(value.as_bytes())
+                .split(',')
+                .filter_map(|entry| entry.trim().parse().ok())
+                .map(|ip| self.anonymize(ip))
+                .collect();
+            if anonymized.is_empty() {
+                req.remove_header(name);
+            } else {
+                req.insert_header(name, anonymized.join(", "))?;
+            }
+        }
+        req.remove_header(&http::header::FORWARDED);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn anonymize() {
+        let v4: IpAddr = "192.0.2.77".parse().unwrap();
+        let v6: IpAddr = "2001:db8:1234:5678::1".parse().unwrap();
+        assert_eq!(ClientIpPrivacy::Full.anonymize(v4), "192.0.2.77");
+        assert_eq!(ClientIpPrivacy::Truncate.anonymize(v4), "192.0.2.0");
+        assert_eq!(ClientIpPrivacy::Truncate.anonymize(v6), "2001:db8:1234::");
+        let hash = ClientIpPrivacy::Hash.anonymize(v4);
+        assert_eq!(hash.len(), 16);
+        assert_eq!(ClientIpPrivacy::Hash.anonymize(v4), hash);
+        assert_ne!(ClientIpPrivacy::Hash.anonymize(v6), hash);
+
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header("x-forwarded-for", "198.51.100.7, unknown, 192.0.2.77")
+            .unwrap();
+        req.insert_header("forwarded", "for=198.51.100.7").unwrap();
+        ClientIpPrivacy::Truncate
+            .anonymize_headers(&mut req)
+            .unwrap();
+        assert_eq!(req.headers["x-forwarded-for"], "198.51.100.0, 192.0.2.0");
+        assert!(!req.headers.contains_key("forwarded"));
+    }
+}
@@ -0,0 +1,89 @@
+//! Control over the HTTP version negotiated with clients over HTTPS.
+//!
+//! HTTPS listeners offer HTTP/2 (preferred) and HTTP/1.1 in ALPN, unless configured to offer
+//! HTTP/1.1 only.  Routes with issues over HTTP/2 (e.g., long-lived streaming responses) can be
+//! forced to HTTP/1.1 too, as are routes matched on the SNI (which HTTP/2 requests don't carry, see
+//! `sni`).  ALPN is negotiated before any request is read, so it's decided by the TLS server name:
+//! a connection for a host with such a route gets HTTP/1.1, for all of the host's routes.  A client
+//! that reuses an HTTP/2 connection for another host (connection coalescing) may still send the
+//! route's requests over HTTP/2; they're answered with a `421 Misdirected Request`, which makes the
+//! client retry on a connection of their own.
+//!
+//! Pingora doesn't let the proxy set its HTTP/2 server settings (e.g., the maximum number of
+//! concurrent streams or the flow-control window sizes), so HTTP/2 connections use its defaults.
+
+use http::StatusCode;
+use pingora::http::ResponseHeader;
+use pingora::tls::ssl::{select_next_proto, AlpnError, NameType, SslRef};
+use pingora::Result;
+use std::sync::Arc;
+
+use crate::route_store::RouteStore;
+
+/// The protocols offered to clients, in ALPN wire format, in order of preference.
+const H2_H1: &[u8] = b"\x02h2\x08http/1.1";
+const H1: &[u8] = b"\x08http/1.1";
+
+/// An ALPN selection callback for an HTTPS listener, which offers HTTP/2 (if `http2` is set)
+/// unless the client's server name has an HTTP/1.1-only route.
+pub fn alpn_callback(
+    route_store: Arc<RouteStore>,
+    http2: bool,
+) -> impl for<'a> Fn(&mut SslRef, &'a [u8]) -> Result<&'a [u8], AlpnError> + Send + Sync + 'static {
+    move |ssl, client| {
+        let offered = if http2 && !forces_http1(&route_store, ssl) {
+            H2_H1
+        } else {
+            H1
+        };
+        // Clients that don't speak any of the offered protocols get none (and default to
+        // HTTP/1.1), as with Pingora's own callbacks.
+        select_next_proto(offered, client).ok_or(AlpnError::NOACK)
+    }
+}
+
+/// Whether the connection's server name has an HTTP/1.1-only route.
+fn forces_http1(route_store: &RouteStore, ssl: &SslRef) -> bool {
+    ssl.servername(NameType::HOST_NAME)
+        .is_some_and(|host| route_store.forces_http1(&host.to_ascii_lowercase()))
+}
+
+/// The response to an HTTP/2 request for an HTTP/1.1-only route.
+pub fn misdirected_response() -> Result<ResponseHeader> {
+    let mut resp = ResponseHeader::build(StatusCode::MISDIRECTED_REQUEST, Some(1))?;
+    resp.insert_header(http::header::CONTENT_LENGTH, 0)?;
+    Ok(resp)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::route_config::{RouteConfig, RouteHolder};
+
+    #[test]
+    fn http1_only_hosts() {
+        let route_store = RouteStore::new();
+        let route = |name: &str, host: &str, http1_only: bool| -> RouteConfig {
+            serde_json::from_value(serde_json::json!({
+                "name": name,
+                "customer": "c1",
+                "hosts": [host],
+                "paths": ["/"],
+                "incoming_schemes": ["Https"],
+                "outgoing_scheme": "Https",
+                "origin_group": {"origins": [{"host": "origin.example.com"}]},
+                "http1_only": http1_only,
+            }))
+            .unwrap()
+        };
+        route_store.add_route(route("r1", "stream.example.com", true));
+        route_store.add_route(route("r2", "*.example.net", true));
+        route_store.add_route(route("r3", "www.example.com", false));
+
+        assert!(route_store.forces_http1("stream.example.com"));
+        assert!(route_store.forces_http1("a.example.net"));
+        assert!(!route_store.forces_http1("a.b.example.net"));
+        assert!(!route_store.forces_http1("www.example.com"));
+        assert!(!route_store.forces_http1("other.example.com"));
+    }
+}
@@ -1,121 +1,1704 @@
 //! The caching proxy.
 
 use async_trait::async_trait;
-use log::{info, warn};
+use bytes::{Bytes, BytesMut};
+use http::header::{HeaderName, HeaderValue};
+use http::Extensions;
+use http::StatusCode;
+use log::{debug, info, log_enabled, warn, Level};
 use once_cell::sync::{Lazy, OnceCell};
 use pingora::cache::{
-    cache_control::CacheControl, eviction::simple_lru, filters::resp_cacheable, lock::CacheLock,
-    CacheMetaDefaults, CachePhase, MemCache, NoCacheReason, RespCacheable,
+    cache_control::CacheControl, eviction::EvictionManager, filters::resp_cacheable,
+    key::CacheHashKey, trace::Span, CacheKey, CacheMeta, CacheMetaDefaults, CachePhase,
+    NoCacheReason, RespCacheable, Storage,
 };
 use pingora::http::ResponseHeader;
 use pingora::prelude::*;
+use pingora::protocols::Digest;
 use pingora::proxy::{ProxyHttp, Session};
 use pingora::upstreams::peer::HttpPeer;
-use rand::distributions::{Distribution, WeightedIndex};
 use std::collections::hash_map::Entry;
+use std::net::{IpAddr, SocketAddr};
 use std::sync::Arc;
-use std::time::{Duration, Instant};
-use tokio::net::lookup_host;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
+use crate::access_log::{AccessLog, AccessLogConfig, AccessLogEntry};
+use crate::acl::DenyList;
 use crate::app_config::{CacheConfig, ProxyConfig};
-use crate::route_config::{IncomingScheme, Origin, OutgoingScheme};
+use crate::aws_sigv4::AwsSigner;
+use crate::basic_auth::{self, CredentialStore};
+use crate::body_rewrite::BodyRewriter;
+use crate::bots::{BotAction, BotClassifier};
+use crate::cache_lock::{CacheLockPolicy, LockTimeoutAction};
+use crate::cache_persistence::{CachePersister, TrackedStorage};
+use crate::cache_stats::{CacheReport, HotKeyTracker, RouteEvictionManager};
+use crate::cluster::{self, Cluster, ClusterConfig};
+use crate::collapse::{CollapseLeader, Collapser, Joined};
+use crate::cookies::CookiePolicy;
+use crate::cors;
+use crate::customer::{CustomerConfig, CustomerStore};
+use crate::debug_headers::{DebugHeaders, DebugHeadersConfig};
+use crate::dns::{DnsConfig, DnsResolver};
+use crate::drain::{Drainer, InFlight};
+use crate::error_pages::{ErrorPages, ErrorVars};
+use crate::events::{self, Event};
+use crate::failover::FailoverResponse;
+use crate::fault::{FaultInjector, FaultOutcome};
+use crate::forward_auth::{AuthDecision, ForwardAuthClient};
+use crate::geoip::{self, GeoIp, GeoLocation};
+use crate::idempotency::{self, Claim, IdempotencyRecorder, IdempotencyStore};
+use crate::instance::{Instance, InstanceConfig};
+use crate::listeners::ListenerLabels;
+use crate::log_sinks::{LogSinkConfig, LogSinks};
+use crate::memory::MemoryTracker;
+use crate::methods::MethodDecision;
+use crate::metrics::{self, MetricsConfig, RequestMetrics, RequestRecord};
+use crate::origin_connections::{ActiveConnection, ConnectionPermit};
+use crate::plugin::{PluginContext, PluginRegistry};
+use crate::post_cache::PostCachePolicy;
+use crate::prewarm::{PrewarmTarget, Prewarmer, PREWARM_HEADER};
+use crate::privacy::ClientIpPrivacy;
+use crate::protocols;
+use crate::quota::QuotaTracker;
+use crate::rate_limit::{RateLimitDecision, RateLimitKey, RateLimitPolicy, RateLimiter};
+use crate::redaction::HeaderRedactor;
+use crate::redirects::Redirect;
+use crate::response_headers;
+use crate::retry_budget::RetryBudget;
+use crate::route_config::{
+    self, IncomingScheme, Origin, OutgoingScheme, RouteConfig, RouteRequest,
+};
 use crate::route_store::Route;
 use crate::route_store::RouteStore;
+use crate::script::{ScriptHeaders, ScriptRequest, ScriptResponse};
+use crate::secrets::{SecretStore, SecretsConfig};
+use crate::security_headers::SecurityHeadersPolicy;
+use crate::sni;
+use crate::tap::{RequestSummary, RequestTap};
+use crate::throttle::{self, Pacer, ThrottleConfig, Throttler};
+use crate::timing::RequestTimings;
+use crate::tls_fingerprint::{Fingerprint, TlsFingerprintConfig, TlsFingerprints};
+use crate::usage::{UsageRecord, UsageTracker};
 use crate::utils;
+use crate::wasm::{WasmContext, WasmRequest, WasmStore};
 
-static CACHE_BACKEND: Lazy<MemCache> = Lazy::new(MemCache::new);
+static CACHE_BACKEND: Lazy<TrackedStorage> = Lazy::new(TrackedStorage::new);
 /// By default, cache all responses for 5 minutes.  This can be overridden by the origin's cache
 /// control headers.
 const CACHE_META_DEFAULTS: CacheMetaDefaults = CacheMetaDefaults::new(|_| Some(300), 1, 1);
-static EVICTION_MANAGER: OnceCell<simple_lru::Manager> = OnceCell::new();
-static CACHE_LOCK: Lazy<CacheLock> =
-    Lazy::new(|| CacheLock::new(std::time::Duration::from_secs(2)));
+static EVICTION_MANAGER: OnceCell<RouteEvictionManager> = OnceCell::new();
+static HOT_KEYS: Lazy<HotKeyTracker> = Lazy::new(HotKeyTracker::default);
 
 /// A context that is available throughout the lifecycle of a request.
 #[derive(Debug)]
 pub struct RequestContext {
     /// The route that was matched for the request.
     route: Option<Arc<Route>>,
+    /// The customer the matched route belongs to (if it's defined).
+    customer: Option<Arc<CustomerConfig>>,
+    /// The client's location (if the route has a geo policy or a script).
+    geo: Option<GeoLocation>,
+    /// The client's TLS fingerprint (if fingerprinting is enabled and the request came over
+    /// HTTP/1.1 with TLS).
+    tls_fingerprint: Option<Arc<Fingerprint>>,
+    /// The SNI of the client's TLS connection (if routes are matched on it and the request came
+    /// over HTTP/1.1 with TLS).
+    sni: Option<Arc<str>>,
+    /// Whether the client may only be served from the cache (per the route's bot policy).
+    cached_only: bool,
     /// The origin that was selected for the request.
-    origin: Option<Origin>,
+    origin: Option<Arc<Origin>>,
     /// The index of the origin that was selected for the request.
     origin_index: Option<usize>,
     /// The number of attempts to connect to an origin.
     tries: u16,
+    /// Entitles the request to a connection to the origin (if its connections are limited).
+    connection_permit: Option<ConnectionPermit>,
+    /// Counts the connection to the origin as in use until the request finishes.
+    upstream_connection: Option<ActiveConnection>,
+    /// The response body bytes received from the origin.
+    origin_response_bytes: u64,
+    /// When the request was received.
+    start: Instant,
+    /// The cache status reported to the client (if the response went through the cache phases).
+    cache_status: Option<&'static str>,
+    /// Where the time went while handling the request.
+    timings: RequestTimings,
+    /// An ID for the request (substituted into custom error pages).
+    request_id: String,
+    /// Headers approved by a forward auth service to add to the upstream request.
+    auth_headers: Vec<(HeaderName, HeaderValue)>,
+    /// The result of the rate limit check (if the route is rate limited).
+    rate_limit: Option<RateLimitDecision>,
+    /// The bytes accounted to the memory tracker for this request (released when it finishes).
+    buffered: usize,
+    /// Whether the request was forwarded by a cluster peer (so it must go to the origin).
+    from_peer: bool,
+    /// The origin to forward the request to, if it's a prewarm request.
+    prewarm: Option<PrewarmTarget>,
+    /// The cluster peer the request was forwarded to (on a miss for a key it owns).
+    peer: Option<SocketAddr>,
+    /// Whether the cluster peer couldn't be reached (so the request goes to the origin).
+    peer_failed: bool,
+    /// The cache status reported by the cluster peer.
+    peer_cache_status: Option<&'static str>,
+    /// Paces the response body (if the route limits the bandwidth of each response).
+    pacer: Option<Pacer>,
+    /// The hash of the request body (for POST requests cached by the route).
+    body_hash: Option<String>,
+    /// Collects the response to share it with identical requests (if the request leads them).
+    collapse_leader: Option<Box<CollapseLeader>>,
+    /// Whether the request was sent the response of an identical request.
+    collapsed: bool,
+    /// Records the response to keep it for retries (if the request has a new idempotency key).
+    idempotency_recorder: Option<Box<IdempotencyRecorder>>,
+    /// Whether the request was sent the response kept for its idempotency key.
+    replayed: bool,
+    /// Whether the request gave up waiting for another request to fill its cache entry.
+    cache_lock_timed_out: bool,
+    /// Whether the request was sent a stale cache entry because the cache lock timed out.
+    served_stale: bool,
+    /// Rewrites the response body from the origin (if the route's body rewriting applies to it).
+    body_rewriter: Option<BodyRewriter>,
+    /// State kept by the route's plugins.
+    plugin_state: Extensions,
+    /// The route's WebAssembly filters handling the request (in order).
+    wasm: Vec<WasmContext>,
+    /// The bucket the request was assigned to (if the route buckets its clients).
+    bucket: Option<String>,
+    /// The origin of the request's bucket (by index within the origin group).
+    bucket_origin: Option<usize>,
+    /// The origin the route's script chose (by index within the origin group).
+    script_origin: Option<usize>,
+    /// How long the route's script chose to cache the response for (regardless of its headers).
+    script_cache_ttl: Option<u64>,
+    /// The origin redirect being followed (if the route follows redirects).
+    redirect: Option<Redirect>,
+    /// The number of origin redirects followed.
+    redirects: u8,
+    /// Whether the origin's response is a redirect to follow (so it's neither cached nor sent).
+    following_redirect: bool,
+    /// Counts the request as in flight (for draining) until the context is dropped.
+    _in_flight: InFlight,
 }
 
 impl RequestContext {
-    fn new() -> RequestContext {
+    fn new(in_flight: InFlight) -> RequestContext {
         RequestContext {
             route: None,
+            customer: None,
+            geo: None,
+            tls_fingerprint: None,
+            sni: None,
+            cached_only: false,
             origin: None,
             origin_index: None,
             tries: 0,
+            connection_permit: None,
+            upstream_connection: None,
+            origin_response_bytes: 0,
+            start: Instant::now(),
+            cache_status: None,
+            timings: RequestTimings::default(),
+            request_id: format!("{:016x}", rand::random::<u64>()),
+            auth_headers: Vec::new(),
+            rate_limit: None,
+            buffered: 0,
+            from_peer: false,
+            prewarm: None,
+            peer: None,
+            peer_failed: false,
+            peer_cache_status: None,
+            pacer: None,
+            body_hash: None,
+            collapse_leader: None,
+            collapsed: false,
+            idempotency_recorder: None,
+            replayed: false,
+            cache_lock_timed_out: false,
+            served_stale: false,
+            body_rewriter: None,
+            plugin_state: Extensions::new(),
+            wasm: Vec::new(),
+            bucket: None,
+            bucket_origin: None,
+            script_origin: None,
+            script_cache_ttl: None,
+            redirect: None,
+            redirects: 0,
+            following_redirect: false,
+            _in_flight: in_flight,
         }
     }
+
+    /// The matched route's rate limit (or its customer's default).
+    fn rate_limit_policy(&self) -> Option<&RateLimitPolicy> {
+        let route = self.route.as_ref()?;
+        route.config.rate_limit.as_ref().or_else(|| {
+            let customer = self.customer.as_ref()?;
+            customer.defaults.rate_limit.as_ref()
+        })
+    }
+
+    /// The matched route's security headers (or its customer's default).
+    fn security_headers_policy(&self) -> Option<&SecurityHeadersPolicy> {
+        let route = self.route.as_ref()?;
+        route.config.security_headers.as_ref().or_else(|| {
+            let customer = self.customer.as_ref()?;
+            customer.defaults.security_headers.as_ref()
+        })
+    }
+
+    /// The matched route's cookie policy (or its customer's default).
+    fn cookie_policy(&self) -> Option<&CookiePolicy> {
+        let route = self.route.as_ref()?;
+        route.config.cookies.as_ref().or_else(|| {
+            let customer = self.customer.as_ref()?;
+            customer.defaults.cookies.as_ref()
+        })
+    }
+
+    /// The matched route's log sink (or its customer's default).
+    fn log_sink(&self) -> Option<&LogSinkConfig> {
+        let route = self.route.as_ref()?;
+        route.config.log_sink.as_ref().or_else(|| {
+            let customer = self.customer.as_ref()?;
+            customer.defaults.log_sink.as_ref()
+        })
+    }
 }
 
 pub struct Proxy {
     /// A means to look up routes.
     route_store: Arc<RouteStore>,
 
+    /// A means to look up the customers routes belong to.
+    customer_store: Arc<CustomerStore>,
+
+    /// A means to look up credentials for routes protected by basic auth.
+    credential_store: Arc<CredentialStore>,
+
+    /// The WebAssembly filters routes can enable.
+    wasm_store: Arc<WasmStore>,
+
+    /// A client for sending subrequests to forward auth services.
+    forward_auth_client: ForwardAuthClient,
+
+    /// Signs requests to origins that require AWS SigV4.
+    aws_signer: AwsSigner,
+
+    /// Resolves the secrets referred to by routes' upstream headers.
+    secret_store: SecretStore,
+
+    /// The requests being fetched for routes that collapse identical requests.
+    collapser: Arc<Collapser>,
+
+    /// The idempotency keys used on routes that honor them.
+    idempotency_store: Arc<IdempotencyStore>,
+
+    /// Token buckets for rate-limited routes.
+    rate_limiter: RateLimiter,
+
+    /// Classifies clients for routes' bot policies.
+    bot_classifier: BotClassifier,
+
+    /// Global and per-customer quota enforcement.
+    quota_tracker: Arc<QuotaTracker>,
+
+    /// Per-customer bandwidth limits.
+    throttler: Throttler,
+
+    /// Client addresses that are refused service.
+    deny_list: Arc<DenyList>,
+
+    /// Per-route and per-customer request metrics.
+    metrics: RequestMetrics,
+
+    /// Requests that take at least this long are logged with a latency breakdown.
+    slow_request_threshold: Option<Duration>,
+
+    /// Resolves origin hostnames.
+    resolver: DnsResolver,
+
+    /// Finished requests are logged here (if configured).
+    access_log: AccessLog,
+    /// The access log destinations of routes.
+    log_sinks: LogSinks,
+
+    /// Summaries of finished requests are published here while someone is subscribed.
+    request_tap: Arc<RequestTap>,
+
+    /// Hides sensitive header values in logs and request summaries.
+    header_redactor: HeaderRedactor,
+
+    /// Tracks memory usage and relieves memory pressure.
+    memory: Arc<MemoryTracker>,
+
+    /// Tracks requests in flight and whether the server is draining.
+    drainer: Arc<Drainer>,
+
+    /// The cache peers (if the cache is shared by a cluster of proxies).
+    cluster: Option<Cluster>,
+
+    /// Sends the requests prewarming connections to origins, and recognizes them.
+    prewarmer: Arc<Prewarmer>,
+
+    /// This instance's identity, added to responses and the access log.
+    instance: Instance,
+
+    /// Adds the debug headers (for trusted clients) and echoes tracing headers.
+    debug_headers: DebugHeaders,
+
+    /// Locates clients for routes' geo policies and scripts.
+    geoip: Arc<GeoIp>,
+
+    /// Looks up and filters clients' TLS fingerprints.
+    tls_fingerprints: TlsFingerprints,
+    /// Accounts for the traffic of each customer.
+    usage_tracker: Arc<UsageTracker>,
+
+    /// The plugins routes can enable.
+    plugins: Arc<PluginRegistry>,
+
+    /// Faults injected into routes for resilience testing.
+    fault_injector: Arc<FaultInjector>,
+
+    /// Custom error pages used when the matched route doesn't define one.
+    error_pages: ErrorPages,
+
     /// The ports that are used for HTTPS.
     https_ports: Vec<u16>,
 
+    /// The labels of the listeners, which routes can be restricted to.
+    listener_labels: ListenerLabels,
+
     /// The amount of time (in seconds) an origin is marked down if it fails to connect.
     origin_down_time: u64,
 
     /// The maximum number of times to retry connecting to an origin.
     connection_retry_limit: u16,
+    /// The budget for the retries of all routes (if any).
+    retry_budget: Option<RetryBudget>,
 }
 
 impl Proxy {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         proxy_config: &ProxyConfig,
         cache_config: &CacheConfig,
         route_store: Arc<RouteStore>,
+        customer_store: Arc<CustomerStore>,
+        credential_store: Arc<CredentialStore>,
+        wasm_store: Arc<WasmStore>,
+        quota_tracker: Arc<QuotaTracker>,
+        throttle_config: &ThrottleConfig,
+        deny_list: Arc<DenyList>,
+        metrics_config: &MetricsConfig,
+        access_log_config: &AccessLogConfig,
+        dns_config: &DnsConfig,
+        cluster_config: &ClusterConfig,
+        request_tap: Arc<RequestTap>,
+        memory: Arc<MemoryTracker>,
+        drainer: Arc<Drainer>,
+        fault_injector: Arc<FaultInjector>,
+        instance_config: &InstanceConfig,
+        debug_headers_config: &DebugHeadersConfig,
+        geoip: Arc<GeoIp>,
+        tls_fingerprint_config: &TlsFingerprintConfig,
+        secrets_config: &SecretsConfig,
+        plugins: Arc<PluginRegistry>,
+        usage_tracker: Arc<UsageTracker>,
+        prewarmer: Arc<Prewarmer>,
     ) -> Proxy {
         let https_ports = utils::collect_ports(&proxy_config.https_bind_addrs);
 
-        let eviction_manager = simple_lru::Manager::new(cache_config.max_size);
+        let instance = Instance::new(instance_config);
+        match instance.pop() {
+            Some(pop) => info!("Serving as instance '{}' in POP '{pop}'", instance.id()),
+            None => info!("Serving as instance '{}'", instance.id()),
+        }
+        metrics::instance_info(&instance);
+
+        let eviction_manager = RouteEvictionManager::new(
+            cache_config.max_size,
+            cache_config.eviction_shards,
+            cache_config.shard_by,
+        );
         if EVICTION_MANAGER.set(eviction_manager).is_err() {
             warn!("Eviction manager has already been initialized");
         }
 
         Proxy {
             route_store,
+            customer_store,
+            credential_store,
+            wasm_store,
+            forward_auth_client: ForwardAuthClient::new(),
+            aws_signer: AwsSigner::new(),
+            secret_store: SecretStore::new(secrets_config),
+            collapser: Arc::new(Collapser::default()),
+            idempotency_store: Arc::new(IdempotencyStore::default()),
+            rate_limiter: RateLimiter::new(),
+            bot_classifier: BotClassifier::new(),
+            quota_tracker,
+            throttler: Throttler::new(throttle_config),
+            deny_list,
+            metrics: RequestMetrics::new(metrics_config),
+            resolver: DnsResolver::new(dns_config),
+            access_log: AccessLog::new(access_log_config),
+            log_sinks: LogSinks::new(access_log_config),
+            request_tap,
+            header_redactor: HeaderRedactor::new(&proxy_config.redact_headers),
+            memory,
+            drainer,
+            cluster: Cluster::new(cluster_config),
+            prewarmer,
+            instance,
+            debug_headers: DebugHeaders::new(debug_headers_config),
+            geoip,
+            tls_fingerprints: TlsFingerprints::new(tls_fingerprint_config),
+            plugins,
+            usage_tracker,
+            fault_injector,
+            slow_request_threshold: proxy_config
+                .slow_request_threshold
+                .map(Duration::from_millis),
+            error_pages: proxy_config.error_pages.clone(),
             https_ports,
+            listener_labels: ListenerLabels::new(&proxy_config.listener_labels),
             origin_down_time: proxy_config.origin_down_time,
             connection_retry_limit: proxy_config.connection_retry_limit,
+            retry_budget: proxy_config.retry_budget.as_ref().map(RetryBudget::new),
+        }
+    }
+
+    /// The retry budgets that apply to the route: its own and the global one.
+    fn retry_budgets<'a>(
+        &'a self,
+        route: &'a Route,
+    ) -> impl Iterator<Item = &'a RetryBudget> + Clone {
+        [route.retry_budget.as_ref(), self.retry_budget.as_ref()]
+            .into_iter()
+            .flatten()
+    }
+
+    /// Count a retry against the route's budgets if it fits in all of them.  Return `false` (and
+    /// count the request as failing fast) if a budget is exhausted.
+    fn spend_retry(&self, route: &Route) -> bool {
+        if !self
+            .retry_budgets(route)
+            .all(|budget| budget.allows_retry())
+        {
+            debug!("Retry budget exhausted; not retrying");
+            metrics::retry_budget_exhausted(&route.config.name);
+            return false;
+        }
+        for budget in self.retry_budgets(route) {
+            budget.record_retry();
+        }
+        true
+    }
+
+    /// Whether to retry a failed connection to the origin: only within the connection retry limit,
+    /// and if the retry fits in the route's budgets.  Otherwise, publish that the request exhausted
+    /// its retries.
+    fn should_retry(&self, route: &Route, origin_index: usize, tries: u16) -> bool {
+        let reason = if tries > self.connection_retry_limit {
+            debug!("Connection retry limit exceed");
+            "limit"
+        } else if !self.spend_retry(route) {
+            "budget"
+        } else {
+            return true;
+        };
+        if let Some(origin) = route.config.origin_group.origins.get(origin_index) {
+            events::emit(Event::RetriesExhausted {
+                route: &route.config.name,
+                origin: &origin.host,
+                tries,
+                reason,
+            });
+        }
+        false
+    }
+
+    /// Check memory usage (trimming the cache if necessary) and reject the request with a 503 if
+    /// memory usage is above the shed threshold.  Otherwise, account for the request's headers.
+    /// Return `true` if a response was sent.
+    async fn check_memory(&self, session: &mut Session, ctx: &mut RequestContext) -> Result<bool> {
+        let trim = self.memory.check();
+        if trim > 0 {
+            trim_cache(trim);
+        }
+
+        if self.memory.shedding() {
+            debug!("Shedding request due to memory pressure");
+            metrics::request_shed();
+            let mut resp = ResponseHeader::build(StatusCode::SERVICE_UNAVAILABLE, None)?;
+            resp.insert_header(http::header::RETRY_AFTER, "1")?;
+            self.send_error(session, ctx, resp).await?;
+            return Ok(true);
+        }
+
+        let req = session.req_header();
+        ctx.buffered = req.uri.to_string().len()
+            + req
+                .headers
+                .iter()
+                .map(|(name, value)| name.as_str().len() + value.len())
+                .sum::<usize>();
+        self.memory.buffer(ctx.buffered);
+        Ok(false)
+    }
+
+    /// Reject the request with a 403 if the client address (or its autonomous system) is on the
+    /// deny list.
+    /// Return `true` if a response was sent.
+    async fn check_deny_list(
+        &self,
+        session: &mut Session,
+        ctx: &mut RequestContext,
+    ) -> Result<bool> {
+        let Some(client_ip) = get_client_ip(session) else {
+            return Ok(false);
+        };
+        let blocked = self.deny_list.is_blocked(&client_ip)
+            || (self.deny_list.has_asns()
+                && self
+                    .geoip
+                    .asn(client_ip)
+                    .is_some_and(|asn| self.deny_list.is_asn_blocked(asn)));
+        if !blocked {
+            return Ok(false);
+        }
+
+        debug!("Rejecting request from blocked client {client_ip}");
+        let resp = ResponseHeader::build(StatusCode::FORBIDDEN, None)?;
+        self.send_error(session, ctx, resp).await?;
+        Ok(true)
+    }
+
+    /// Answer HTTP requests for a route that redirects them to HTTPS with a `301` to the HTTPS
+    /// equivalent (the same host, without its port, and path).  Requests forwarded by a cluster
+    /// peer are never redirected (peers are reached over HTTP).  Return `true` if a response was
+    /// sent.
+    async fn check_https_redirect(
+        &self,
+        session: &mut Session,
+        ctx: &mut RequestContext,
+    ) -> Result<bool> {
+        let Some(route) = ctx.route.as_ref() else {
+            return Ok(false);
+        };
+        if !route.config.redirect_to_https
+            || ctx.from_peer
+            || get_incoming_scheme(session, &self.https_ports)? != IncomingScheme::Http
+        {
+            return Ok(false);
+        }
+        let path = session
+            .req_header()
+            .uri
+            .path_and_query()
+            .map_or("/", |p| p.as_str());
+        let location = format!("https://{}{path}", get_host_header(session)?);
+        debug!("Redirecting to {location}");
+        let mut resp = ResponseHeader::build(StatusCode::MOVED_PERMANENTLY, None)?;
+        resp.insert_header(http::header::LOCATION, location)?;
+        resp.insert_header(http::header::CONTENT_LENGTH, 0)?;
+        self.instance.add_headers(&mut resp)?;
+        send_response(session, resp, None).await?;
+        Ok(true)
+    }
+
+    /// Reject the request with a 403 if the matched route doesn't allow the client's address.
+    /// Requests forwarded by a cluster peer were checked there.  Return `true` if a response was
+    /// sent.
+    async fn check_client_ip(
+        &self,
+        session: &mut Session,
+        ctx: &mut RequestContext,
+    ) -> Result<bool> {
+        let Some(route) = ctx.route.clone() else {
+            return Ok(false);
+        };
+        let client_ip = get_client_ip(session);
+        if ctx.from_peer || route.config.allows_client(client_ip) {
+            return Ok(false);
+        }
+
+        debug!(
+            "Rejecting request from client {client_ip:?} not allowed on route '{}'",
+            route.config.name
+        );
+        let resp = ResponseHeader::build(StatusCode::FORBIDDEN, None)?;
+        self.send_error(session, ctx, resp).await?;
+        Ok(true)
+    }
+
+    /// Look up the client's TLS fingerprint (HTTP/2 requests have none, but they're only negotiated
+    /// without a deny list, see `tls_fingerprint`).  If it's denied, a 403 response is sent.
+    /// Return `true` if a response was sent.
+    async fn check_tls_fingerprint(
+        &self,
+        session: &mut Session,
+        ctx: &mut RequestContext,
+    ) -> Result<bool> {
+        if session.is_http2() {
+            return Ok(false);
+        }
+        ctx.tls_fingerprint = self.tls_fingerprints.current().await;
+        let Some(fingerprint) = &ctx.tls_fingerprint else {
+            return Ok(false);
+        };
+        if !self.tls_fingerprints.is_denied(fingerprint) {
+            return Ok(false);
+        }
+
+        debug!(
+            "Rejecting request with denied TLS fingerprint {}",
+            &fingerprint.hash
+        );
+        metrics::tls_fingerprint_denied();
+        let resp = ResponseHeader::build(StatusCode::FORBIDDEN, None)?;
+        self.send_error(session, ctx, resp).await?;
+        Ok(true)
+    }
+
+    /// Determine whether the request was forwarded by a cluster peer.  Only requests from a peer's
+    /// address that carry the peer header are trusted as such.
+    fn check_peer_request(&self, session: &Session, ctx: &mut RequestContext) {
+        if let Some(cluster) = &self.cluster {
+            let has_peer_header = session.get_header(cluster::PEER_HEADER).is_some();
+            ctx.from_peer = cluster.is_peer_request(get_client_ip(session), has_peer_header);
+        }
+    }
+
+    /// Recognize a prewarm request (see `prewarm`), and set it up to be forwarded to its origin.
+    /// Prewarm requests skip the route's filters and the cache.  The prewarm header is removed from
+    /// all requests.
+    /// Return `true` if the request is a prewarm request.
+    fn check_prewarm(&self, session: &mut Session, ctx: &mut RequestContext) -> Result<bool> {
+        if !session.req_header().headers.contains_key(PREWARM_HEADER) {
+            return Ok(false);
+        }
+        let target = self.prewarmer.target(session.req_header());
+        session.req_header_mut().remove_header(PREWARM_HEADER);
+        let Some(target) = target else {
+            return Ok(false);
+        };
+        let route = self
+            .route_store
+            .routes()
+            .into_iter()
+            .find(|route| route.config.name == target.route)
+            .filter(|route| target.origin_index < route.config.origin_group.origins.len())
+            .ok_or_else(|| Error::explain(HTTPStatus(404), "Prewarmed origin not found"))?;
+        ctx.route = Some(route);
+        ctx.prewarm = Some(target);
+        Ok(true)
+    }
+
+    /// If the cache is shared by a cluster and another instance owns the request's cache key,
+    /// return that instance as the peer to fetch the response from (instead of the origin).
+    /// Requests forwarded by a peer, and requests whose peer couldn't be reached, go to the origin.
+    /// Peers are reached over HTTP, so only routes that accept HTTP are shared.
+    fn cache_peer(&self, session: &Session, ctx: &mut RequestContext) -> Option<Box<HttpPeer>> {
+        let cluster = self.cluster.as_ref()?;
+        let route = ctx.route.as_ref()?;
+        if ctx.from_peer
+            || ctx.peer_failed
+            || !session.cache.enabled()
+            || !route
+                .config
+                .incoming_schemes
+                .contains(&IncomingScheme::Http)
+        {
+            return None;
         }
+        let addr = cluster.owner(&session.cache.cache_key().primary_bin())?;
+
+        debug!("Fetching from cache peer {addr}");
+        ctx.peer = Some(addr);
+        let mut peer = Box::new(HttpPeer::new(addr, false, String::new()));
+        peer.options.connection_timeout = Some(cluster.connection_timeout());
+        Some(peer)
     }
 
     /// Find the route that matches the request.
-    /// The scheme and host header must match a route's scheme and host exactly.  The path is a
-    /// longest-prefix match.
-    /// If a matching route is found, it is stored in the context.  Else, a 404 error is returned.
+    /// The scheme and host header (or the SNI, for routes matched on it) must match a route's
+    /// scheme and host exactly, and the route must accept the request (see
+    /// `RouteConfig::accepts`).  The path is matched as the route's paths say (see `path_match`).
+    /// If a matching route is found, it is stored in the context.  Else, a 404 error is returned
+    /// (or a 421 for an HTTP/2 request for a host with routes matched on the SNI).
     fn find_route(&self, session: &mut Session, ctx: &mut RequestContext) -> Result<()> {
-        let host = get_host_header(session)?;
+        let host = get_host_header_with_port(session)?;
         let path = session.req_header().uri.path();
         let protocol = get_incoming_scheme(session, &self.https_ports)?;
-        let route = self
-            .route_store
-            .get_route(protocol, host, path)
-            .ok_or_else(|| Error::explain(HTTPStatus(404), "No route found"))?;
+        let server_port = get_server_port(session)?;
+        let listener = session
+            .server_addr()
+            .and_then(|addr| addr.as_inet())
+            .map_or(&[][..], |addr| self.listener_labels.labels(*addr));
+        let req = RouteRequest {
+            method: session.req_header().method.as_str(),
+            host,
+            path,
+            query: session.req_header().uri.query(),
+            client_ip: get_original_client_ip(session, ctx.from_peer),
+            listener,
+        };
+        // HTTP/2 requests carry no SNI (see `sni`).  Connections for hosts with routes matched on
+        // it negotiate HTTP/1.1, so an HTTP/2 request for one came over a connection for another
+        // host (connection coalescing): a 421 makes the client retry on a connection of its own.
+        if session.is_http2()
+            && protocol == IncomingScheme::Https
+            && self
+                .route_store
+                .has_sni_routes_for(utils::split_host_port(host).0)
+        {
+            return Error::e_explain(
+                HTTPStatus(421),
+                "HTTP/2 request for a host with routes matched on the SNI",
+            );
+        }
+        let by_sni = ctx
+            .sni
+            .as_deref()
+            .and_then(|sni| self.route_store.get_route_by_sni(sni, server_port, &req));
+        let Some(route) = by_sni.or_else(|| self.route_store.get_route(protocol, &req)) else {
+            events::emit(Event::RouteNotFound { host, path });
+            return Error::e_explain(HTTPStatus(404), "No route found");
+        };
 
-        info!(
+        debug!(
             "Matched route '{}' belonging to customer '{}'",
             route.config.name, route.config.customer
         );
+        ctx.customer = self.customer_store.get(&route.config.customer);
+        route
+            .config
+            .client_ip_privacy
+            .anonymize_headers(session.req_header_mut())?;
         ctx.route = Some(route);
 
         Ok(())
     }
 
-    /// Override the host header in the upstream request if the origin configuration has a host
-    /// header override.
+    /// Locate the client if the matched route has a geo policy or a script.  If the policy doesn't
+    /// allow the client's country or autonomous system, a 403 response is sent.  Requests
+    /// forwarded by a cluster peer carry the location of the client in the geo headers (and were
+    /// checked there).
+    /// Return `true` if a response was sent.
+    async fn check_geo(&self, session: &mut Session, ctx: &mut RequestContext) -> Result<bool> {
+        let Some(route) = ctx.route.clone() else {
+            return Ok(false);
+        };
+        if !self.geoip.is_enabled() || (route.config.geo.is_none() && route.config.script.is_none())
+        {
+            return Ok(false);
+        }
+        if ctx.from_peer {
+            let header = |name| {
+                session
+                    .get_header(name)
+                    .and_then(|v| v.to_str().ok())
+                    .map(|v| v.to_string())
+            };
+            ctx.geo = Some(GeoLocation {
+                country: header(geoip::COUNTRY_HEADER),
+                region: header(geoip::REGION_HEADER),
+                asn: header(geoip::ASN_HEADER).and_then(|asn| asn.parse().ok()),
+            });
+            return Ok(false);
+        }
+        let location = get_client_ip(session)
+            .map(|ip| self.geoip.locate(ip))
+            .unwrap_or_default();
+        let allowed = route
+            .config
+            .geo
+            .as_ref()
+            .is_none_or(|policy| policy.allows(&location));
+        ctx.geo = Some(location);
+        if allowed {
+            return Ok(false);
+        }
+
+        debug!(
+            "Request blocked by the geo policy of route '{}'",
+            route.config.name
+        );
+        let resp = ResponseHeader::build(StatusCode::FORBIDDEN, None)?;
+        self.send_error(session, ctx, resp).await?;
+        Ok(true)
+    }
+
+    /// Apply the matched route's method policy (if any): answer `OPTIONS` requests locally or
+    /// reject methods the route doesn't allow with a 405.
+    /// Return `true` if a response was sent.
+    async fn check_methods(&self, session: &mut Session, ctx: &mut RequestContext) -> Result<bool> {
+        let Some(route) = ctx.route.as_ref() else {
+            return Ok(false);
+        };
+        let Some(policy) = route.config.methods.as_ref() else {
+            return Ok(false);
+        };
+        let req = session.req_header();
+        let preflight = route.config.cors.is_some() && cors::is_preflight(req);
+        match policy.decide(req, preflight) {
+            MethodDecision::Allow => Ok(false),
+            MethodDecision::AnswerOptions => {
+                let mut resp = policy.options_response()?;
+                if let Some(cors) = route.config.cors.as_ref() {
+                    cors.apply_response_headers(req, &mut resp)?;
+                }
+                send_response(session, resp, None).await?;
+                Ok(true)
+            }
+            MethodDecision::Reject => {
+                debug!(
+                    "Method {} not allowed on route '{}'",
+                    req.method, route.config.name
+                );
+                let resp = policy.reject_response()?;
+                self.send_error(session, ctx, resp).await?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Answer HTTP/2 requests for an HTTP/1.1-only route with a 421, so the client retries on a
+    /// connection of their own (which negotiates HTTP/1.1).
+    /// Return `true` if a response was sent.
+    async fn check_protocol(
+        &self,
+        session: &mut Session,
+        ctx: &mut RequestContext,
+    ) -> Result<bool> {
+        let Some(route) = ctx.route.as_ref() else {
+            return Ok(false);
+        };
+        if !route.config.http1_only || !session.is_http2() {
+            return Ok(false);
+        }
+        debug!(
+            "HTTP/2 request for HTTP/1.1-only route '{}'",
+            route.config.name
+        );
+        let resp = protocols::misdirected_response()?;
+        self.send_error(session, ctx, resp).await?;
+        Ok(true)
+    }
+
+    /// Apply the matched route's range policy (if any): reject open-ended ranges that would be
+    /// forwarded to the origin with a 416.
+    /// Return `true` if a response was sent.
+    async fn check_ranges(&self, session: &mut Session, ctx: &mut RequestContext) -> Result<bool> {
+        let Some(route) = ctx.route.as_ref() else {
+            return Ok(false);
+        };
+        let Some(policy) = route.config.ranges.as_ref() else {
+            return Ok(false);
+        };
+        if !policy.rejects(session.req_header(), route.config.cache) {
+            return Ok(false);
+        }
+
+        debug!(
+            "Rejecting open-ended range on route '{}'",
+            route.config.name
+        );
+        let resp = policy.reject_response()?;
+        self.send_error(session, ctx, resp).await?;
+        Ok(true)
+    }
+
+    /// Reject requests whose headers exceed the route's limits with a 431.
+    /// Return `true` if a response was sent.
+    async fn check_header_limits(
+        &self,
+        session: &mut Session,
+        ctx: &mut RequestContext,
+    ) -> Result<bool> {
+        let Some(route) = ctx.route.as_ref() else {
+            return Ok(false);
+        };
+        let Some(limits) = route.config.header_limits.as_ref() else {
+            return Ok(false);
+        };
+        let Some(limit) = limits.exceeded(session.req_header()) else {
+            return Ok(false);
+        };
+
+        debug!(
+            "Request headers exceed the {limit} limit of route '{}'",
+            route.config.name
+        );
+        let resp = limits.reject_response()?;
+        self.send_error(session, ctx, resp).await?;
+        Ok(true)
+    }
+
+    /// Evaluate the matched route's WAF rules (if any).  If a rule blocks the request, a 403
+    /// response is sent.
+    /// Return `true` if a response was sent.
+    async fn check_waf(&self, session: &mut Session, ctx: &mut RequestContext) -> Result<bool> {
+        let Some(policy) = ctx.route.as_ref().and_then(|r| r.config.waf.as_ref()) else {
+            return Ok(false);
+        };
+        let body_empty = session.as_mut().is_body_empty();
+        let Some(rule) = policy.check(session.req_header(), body_empty) else {
+            return Ok(false);
+        };
+
+        debug!("Request blocked by WAF rule '{rule}'");
+        let resp = ResponseHeader::build(StatusCode::FORBIDDEN, None)?;
+        self.send_error(session, ctx, resp).await?;
+        Ok(true)
+    }
+
+    /// Apply the matched route's rate limit (if any) to the client.  If the client has exceeded the
+    /// limit, a 429 response is sent.  Requests forwarded by a cluster peer were already limited
+    /// there.
+    /// Return `true` if a response was sent.
+    async fn check_rate_limit(
+        &self,
+        session: &mut Session,
+        ctx: &mut RequestContext,
+    ) -> Result<bool> {
+        if ctx.from_peer {
+            return Ok(false);
+        }
+        let Some(route) = ctx.route.as_ref() else {
+            return Ok(false);
+        };
+        let Some(policy) = ctx.rate_limit_policy() else {
+            return Ok(false);
+        };
+
+        let header_key = match &policy.key {
+            RateLimitKey::ClientIp => None,
+            RateLimitKey::Header(name) => session
+                .get_header(name.as_str())
+                .and_then(|v| v.to_str().ok())
+                .map(|v| v.to_string()),
+        };
+        let client = match header_key {
+            Some(key) => key,
+            None => get_client_ip(session).map_or_else(String::new, |ip| ip.to_string()),
+        };
+
+        let decision = self.rate_limiter.check(&route.config.name, &client, policy);
+        if let RateLimitDecision::Limited { limit, retry_after } = decision {
+            debug!("Rate limit exceeded for route '{}'", route.config.name);
+            let mut resp = ResponseHeader::build(StatusCode::TOO_MANY_REQUESTS, Some(4))?;
+            resp.insert_header(http::header::RETRY_AFTER, retry_after)?;
+            resp.insert_header("x-ratelimit-limit", limit)?;
+            resp.insert_header("x-ratelimit-remaining", 0)?;
+            self.send_error(session, ctx, resp).await?;
+            return Ok(true);
+        }
+        ctx.rate_limit = Some(decision);
+        Ok(false)
+    }
+
+    /// Apply the matched route's bot policy (if any) to the client.  Blocked clients are sent a
+    /// 403, and throttled ones over their class's limit a 429.  Requests forwarded by a cluster
+    /// peer were already checked there.
+    /// Return `true` if a response was sent.
+    async fn check_bots(&self, session: &mut Session, ctx: &mut RequestContext) -> Result<bool> {
+        if ctx.from_peer {
+            return Ok(false);
+        }
+        let Some(route) = ctx.route.clone() else {
+            return Ok(false);
+        };
+        let Some(policy) = route.config.bots.as_ref() else {
+            return Ok(false);
+        };
+        let user_agent = session
+            .get_header(http::header::USER_AGENT)
+            .and_then(|v| v.to_str().ok());
+        let class = self
+            .bot_classifier
+            .classify(policy, user_agent, get_client_ip(session), &self.resolver)
+            .await;
+
+        let resp = match policy.action(class) {
+            BotAction::Allow => return Ok(false),
+            BotAction::CachedOnly => {
+                ctx.cached_only = true;
+                return Ok(false);
+            }
+            BotAction::Block => ResponseHeader::build(StatusCode::FORBIDDEN, None)?,
+            throttle @ BotAction::Throttle { .. } => {
+                let limit = throttle.rate_limit().unwrap();
+                let bucket = format!("{}#bots", route.config.name);
+                let RateLimitDecision::Limited { retry_after, .. } =
+                    self.rate_limiter.check(&bucket, class.as_str(), &limit)
+                else {
+                    return Ok(false);
+                };
+                let mut resp = ResponseHeader::build(StatusCode::TOO_MANY_REQUESTS, Some(2))?;
+                resp.insert_header(http::header::RETRY_AFTER, retry_after)?;
+                resp
+            }
+        };
+        debug!(
+            "Request of {} rejected by the bot policy of route '{}'",
+            class.as_str(),
+            route.config.name
+        );
+        self.send_error(session, ctx, resp).await?;
+        Ok(true)
+    }
+
+    /// Count the request against the global and customer quotas.  If a quota is used up, a 429
+    /// response is sent.  Requests forwarded by a cluster peer were already counted there.
+    /// Return `true` if a response was sent.
+    async fn check_quota(&self, session: &mut Session, ctx: &mut RequestContext) -> Result<bool> {
+        if ctx.from_peer {
+            return Ok(false);
+        }
+        let Some(route) = ctx.route.as_ref() else {
+            return Ok(false);
+        };
+        let Err(retry_after) = self.quota_tracker.start_request(&route.config.customer) else {
+            return Ok(false);
+        };
+
+        debug!("Quota exceeded for customer '{}'", route.config.customer);
+        let mut resp = ResponseHeader::build(StatusCode::TOO_MANY_REQUESTS, Some(2))?;
+        resp.insert_header(http::header::RETRY_AFTER, retry_after)?;
+        self.send_error(session, ctx, resp).await?;
+        Ok(true)
+    }
+
+    /// Answer a CORS preflight request locally if the matched route has a CORS policy.  Methods
+    /// the route's method policy doesn't allow are refused like those the CORS policy doesn't.
+    /// Return `true` if a response was sent.
+    async fn handle_cors_preflight(
+        &self,
+        session: &mut Session,
+        ctx: &mut RequestContext,
+    ) -> Result<bool> {
+        let Some(route) = ctx.route.as_ref() else {
+            return Ok(false);
+        };
+        let Some(policy) = route.config.cors.as_ref() else {
+            return Ok(false);
+        };
+        let req = session.req_header();
+        if !cors::is_preflight(req) {
+            return Ok(false);
+        }
+
+        let requested = req
+            .headers
+            .get(http::header::ACCESS_CONTROL_REQUEST_METHOD)
+            .and_then(|v| v.to_str().ok())
+            .unwrap_or_default();
+        let resp = match &route.config.methods {
+            Some(methods) if !methods.allows(requested) => cors::rejected_preflight_response()?,
+            _ => policy.preflight_response(req)?,
+        };
+        send_response(session, resp, None).await?;
+        Ok(true)
+    }
+
+    /// Challenge the client to authenticate if the matched route requires basic auth and the
+    /// request doesn't carry valid credentials.
+    /// Return `true` if a response was sent.
+    async fn check_basic_auth(
+        &self,
+        session: &mut Session,
+        ctx: &mut RequestContext,
+    ) -> Result<bool> {
+        let Some(config) = ctx
+            .route
+            .as_ref()
+            .and_then(|r| r.config.basic_auth.as_ref())
+        else {
+            return Ok(false);
+        };
+        if self
+            .credential_store
+            .authenticate(&config.credential_list, session.req_header())
+            .await
+        {
+            return Ok(false);
+        }
+
+        debug!(
+            "Basic auth failed for route '{}'",
+            ctx.route.as_ref().unwrap().config.name
+        );
+        let resp = basic_auth::challenge_response(config)?;
+        send_response(session, resp, None).await?;
+        Ok(true)
+    }
+
+    /// Reject the request with a 403 if the matched route requires signed URLs and the request
+    /// URI doesn't carry a valid, unexpired signature.
+    /// Return `true` if a response was sent.
+    async fn check_signed_url(
+        &self,
+        session: &mut Session,
+        ctx: &mut RequestContext,
+    ) -> Result<bool> {
+        let Some(config) = ctx
+            .route
+            .as_ref()
+            .and_then(|r| r.config.signed_url.as_ref())
+        else {
+            return Ok(false);
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        if config.verify(&session.req_header().uri, now) {
+            return Ok(false);
+        }
+
+        debug!("Invalid or expired URL signature");
+        let resp = ResponseHeader::build(StatusCode::FORBIDDEN, None)?;
+        self.send_error(session, ctx, resp).await?;
+        Ok(true)
+    }
+
+    /// Ask the route's forward auth service (if any) whether the request is allowed.  Headers
+    /// approved by the auth service are saved in the context.  If the request is denied, the auth
+    /// service's response is relayed to the client.
+    /// Return `true` if a response was sent.
+    async fn check_forward_auth(
+        &self,
+        session: &mut Session,
+        ctx: &mut RequestContext,
+    ) -> Result<bool> {
+        let Some(route) = ctx.route.clone() else {
+            return Ok(false);
+        };
+        let Some(config) = route.config.forward_auth.as_ref() else {
+            return Ok(false);
+        };
+
+        let scheme = match get_incoming_scheme(session, &self.https_ports)? {
+            IncomingScheme::Http => "http",
+            IncomingScheme::Https => "https",
+        };
+        let host = client_host(session, &route.config)?;
+        let decision = self
+            .forward_auth_client
+            .check(config, session.req_header(), scheme, host, &self.resolver)
+            .await?;
+
+        match decision {
+            AuthDecision::Allow(headers) => {
+                ctx.auth_headers = headers;
+                Ok(false)
+            }
+            AuthDecision::Deny(resp, body) => {
+                send_response(session, *resp, Some(body)).await?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Inject the faults set on the matched route (if any) into the request: delay it, and then
+    /// either send an error response or abort the connection.  Requests forwarded by a cluster
+    /// peer had their faults injected there.
+    /// Return `true` if a response was sent.
+    async fn inject_fault(&self, session: &mut Session, ctx: &mut RequestContext) -> Result<bool> {
+        if ctx.from_peer {
+            return Ok(false);
+        }
+        let Some(route) = ctx.route.as_ref() else {
+            return Ok(false);
+        };
+        let faults = self.fault_injector.choose(&route.config.name);
+
+        if let Some(delay) = faults.delay {
+            debug!("Injecting a delay of {delay:?}");
+            tokio::time::sleep(delay).await;
+        }
+        match faults.outcome {
+            None => Ok(false),
+            Some(FaultOutcome::Error(status)) => {
+                debug!("Injecting a {status} response");
+                let resp = ResponseHeader::build(status, None)?;
+                self.send_error(session, ctx, resp).await?;
+                Ok(true)
+            }
+            Some(FaultOutcome::Abort) => {
+                debug!("Injecting a connection abort");
+                session.set_keepalive(None);
+                // A downstream connection error, so no response is sent (see `fail_to_proxy`).
+                Err(Error::explain(ConnectionClosed, "Fault injected").into_down())
+            }
+        }
+    }
+
+    /// Run the request filters of the route's plugins.  Requests forwarded by a cluster peer went
+    /// through them there.
+    /// Return `true` if a plugin sent a response.
+    async fn run_plugins(&self, session: &mut Session, ctx: &mut RequestContext) -> Result<bool> {
+        if ctx.from_peer {
+            return Ok(false);
+        }
+        let Some(route) = ctx.route.clone() else {
+            return Ok(false);
+        };
+        for (plugin, config) in self.plugins.for_route(&route.config) {
+            let mut plugin_ctx = PluginContext {
+                route: &route.config,
+                config,
+                state: &mut ctx.plugin_state,
+            };
+            if plugin.request_filter(session, &mut plugin_ctx).await? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Assign the request to one of the route's buckets (if it buckets its clients), and pass the
+    /// bucket's name in the request header (for the origin and the later phases).  Requests
+    /// forwarded by a cluster peer keep the bucket the peer assigned.
+    fn assign_bucket(&self, session: &mut Session, ctx: &mut RequestContext) -> Result<()> {
+        let Some(route) = ctx.route.clone() else {
+            return Ok(());
+        };
+        let Some(policy) = route.config.bucketing.as_ref() else {
+            return Ok(());
+        };
+        let forwarded = ctx
+            .from_peer
+            .then(|| session.get_header(policy.header.as_str()))
+            .flatten()
+            .and_then(|v| v.to_str().ok())
+            .and_then(|name| policy.bucket(name));
+        let bucket = match forwarded {
+            Some(bucket) => Some(bucket),
+            None => policy.select(&policy.client_key(session.req_header(), get_client_ip(session))),
+        };
+        let Some(bucket) = bucket else {
+            session
+                .req_header_mut()
+                .remove_header(policy.header.as_str());
+            return Ok(());
+        };
+
+        if let Some(host) = bucket.origin.as_ref() {
+            let origins = &route.config.origin_group.origins;
+            let index = origins
+                .iter()
+                .position(|o| &o.host == host)
+                .ok_or_else(|| {
+                    Error::explain(
+                        HTTPStatus(500),
+                        format!("Bucket '{}' chose unknown origin '{host}'", &bucket.name),
+                    )
+                })?;
+            ctx.bucket_origin = Some(index);
+        }
+        session
+            .req_header_mut()
+            .insert_header(policy.header.clone(), bucket.name.as_str())?;
+        ctx.bucket = Some(bucket.name.clone());
+        Ok(())
+    }
+
+    /// Hash the body of a POST request on a route that caches POST requests (reading the body,
+    /// which is kept for the origin), so the response can be cached.
+    async fn hash_post_body(&self, session: &mut Session, ctx: &mut RequestContext) -> Result<()> {
+        let Some(route) = ctx.route.as_ref() else {
+            return Ok(());
+        };
+        let Some(policy) = route.config.post_cache.as_ref() else {
+            return Ok(());
+        };
+        if !route.config.cache || !policy.applies_to(session.req_header()) {
+            return Ok(());
+        }
+        ctx.body_hash = Some(PostCachePolicy::hash_body(session).await?);
+        Ok(())
+    }
+
+    /// On a route that collapses requests, either lead the identical requests (fetching the
+    /// response for them), or wait for the leader's response and send it.
+    /// Return `true` if a response was sent.
+    async fn collapse_request(
+        &self,
+        session: &mut Session,
+        ctx: &mut RequestContext,
+    ) -> Result<bool> {
+        let Some(route) = ctx.route.clone() else {
+            return Ok(false);
+        };
+        let Some(policy) = route.config.collapse.as_ref() else {
+            return Ok(false);
+        };
+        if route.config.cache {
+            return Ok(false);
+        }
+        let Some(key) = policy.key(
+            &route.config.name,
+            session.req_header(),
+            ctx.bucket.as_deref(),
+        ) else {
+            return Ok(false);
+        };
+
+        let receiver = match self.collapser.join(key, policy.max_body_size) {
+            Joined::Leader(leader) => {
+                ctx.collapse_leader = Some(leader);
+                return Ok(false);
+            }
+            Joined::Follower(receiver) => receiver,
+        };
+        let timeout = Duration::from_millis(policy.timeout);
+        let Some(resp) = Collapser::wait(receiver, timeout).await else {
+            debug!("Collapsed request wasn't answered in time, fetching it from the origin");
+            return Ok(false);
+        };
+        ctx.collapsed = true;
+        let mut header = resp.header.clone();
+        self.response_filter(session, &mut header, ctx).await?;
+        let body = (session.req_header().method != http::Method::HEAD).then(|| resp.body.clone());
+        send_response(session, header, body).await?;
+        Ok(true)
+    }
+
+    /// On a route that honors idempotency keys, send the response kept for the request's key (if
+    /// any), or reject the request if the key is in use by another request (or its body is too
+    /// large to tell).  Otherwise, record the response to keep it for retries.
+    /// Return `true` if a response was sent.
+    async fn check_idempotency_key(
+        &self,
+        session: &mut Session,
+        ctx: &mut RequestContext,
+    ) -> Result<bool> {
+        let Some(route) = ctx.route.clone() else {
+            return Ok(false);
+        };
+        let Some(policy) = route.config.idempotency.as_ref() else {
+            return Ok(false);
+        };
+        let Some(key) = policy.key(
+            &route.config.name,
+            session.req_header(),
+            get_client_ip(session),
+        ) else {
+            return Ok(false);
+        };
+        // The body may already have been read to cache the response (see `hash_post_body`).
+        let body_hash = match ctx.body_hash.clone() {
+            Some(hash) => Some(hash),
+            None => idempotency::hash_body(session).await?,
+        };
+        let Some(body_hash) = body_hash else {
+            debug!("Rejecting a request with an idempotency key and a body too large to compare");
+            let resp = ResponseHeader::build(StatusCode::PAYLOAD_TOO_LARGE, Some(2))?;
+            self.send_error(session, ctx, resp).await?;
+            return Ok(true);
+        };
+
+        let status =
+            match self
+                .idempotency_store
+                .claim(key, session.req_header(), &body_hash, policy)
+            {
+                Claim::New(recorder) => {
+                    ctx.idempotency_recorder = Some(recorder);
+                    return Ok(false);
+                }
+                Claim::Replay(resp) => {
+                    debug!("Replaying the response kept for the request's idempotency key");
+                    ctx.replayed = true;
+                    let mut header = resp.header.clone();
+                    header.insert_header("idempotent-replayed", "true")?;
+                    self.response_filter(session, &mut header, ctx).await?;
+                    send_response(session, header, Some(resp.body.clone())).await?;
+                    return Ok(true);
+                }
+                Claim::InProgress => StatusCode::CONFLICT,
+                Claim::Mismatch => StatusCode::UNPROCESSABLE_ENTITY,
+            };
+        debug!(
+            "Idempotency key in use by another request on route '{}'",
+            route.config.name
+        );
+        let resp = ResponseHeader::build(status, Some(2))?;
+        self.send_error(session, ctx, resp).await?;
+        Ok(true)
+    }
+
+    /// Apply the route's cache lock policy to a request that gave up waiting for another request to
+    /// fill its cache entry: send it the stale entry or a 504 instead of going to the origin, if
+    /// the policy says so.
+    /// Return `true` if a response was sent.
+    async fn handle_lock_timeout(
+        &self,
+        session: &mut Session,
+        ctx: &mut RequestContext,
+    ) -> Result<bool> {
+        ctx.cache_lock_timed_out = true;
+        let Some(route) = ctx.route.clone() else {
+            return Ok(false);
+        };
+        let action = route
+            .config
+            .cache_lock
+            .as_ref()
+            .map_or(LockTimeoutAction::Origin, |policy| policy.on_timeout);
+        debug!(
+            "Cache lock timed out on route '{}' ({})",
+            route.config.name,
+            action.as_str()
+        );
+        let sent = match action {
+            LockTimeoutAction::Origin => false,
+            LockTimeoutAction::Stale => self.serve_stale(session, ctx).await?,
+            LockTimeoutAction::Error => {
+                let resp = ResponseHeader::build(StatusCode::GATEWAY_TIMEOUT, None)?;
+                self.send_error(session, ctx, resp).await?;
+                true
+            }
+        };
+        let taken = if sent {
+            action
+        } else {
+            LockTimeoutAction::Origin
+        };
+        metrics::cache_lock_timed_out(&route.config.name, taken.as_str());
+        Ok(sent)
+    }
+
+    /// Send the cache entry of the request, although it's stale.
+    /// Return `true` if a response was sent (the cache has a complete entry).
+    async fn serve_stale(&self, session: &mut Session, ctx: &mut RequestContext) -> Result<bool> {
+        let key = self.cache_key_callback(session, ctx)?;
+        let span = Span::inactive();
+        let Ok(Some((meta, mut hit))) = CACHE_BACKEND.lookup(&key, &span.handle()).await else {
+            return Ok(false);
+        };
+        // Entries still being written can't seek.
+        if !hit.can_seek() {
+            return Ok(false);
+        }
+        let mut body = BytesMut::new();
+        while let Some(chunk) = hit.read_body().await? {
+            body.extend_from_slice(&chunk);
+        }
+
+        debug!("Serving the stale cache entry");
+        ctx.served_stale = true;
+        let mut header = meta.response_header_copy();
+        header.remove_header(&http::header::TRANSFER_ENCODING);
+        header.insert_header(http::header::CONTENT_LENGTH, body.len())?;
+        header.insert_header(http::header::AGE, meta.age().as_secs())?;
+        self.response_filter(session, &mut header, ctx).await?;
+        send_response(session, header, Some(body.freeze())).await?;
+        Ok(true)
+    }
+
+    /// Run the route's WebAssembly filters on the request headers: apply their header changes, and
+    /// send the response a filter makes instead of forwarding the request.  Requests forwarded by a
+    /// cluster peer went through them there.
+    /// Return `true` if a filter sent a response.
+    async fn run_wasm(&self, session: &mut Session, ctx: &mut RequestContext) -> Result<bool> {
+        if ctx.from_peer {
+            return Ok(false);
+        }
+        let Some(route) = ctx.route.clone() else {
+            return Ok(false);
+        };
+        for filter_ref in &route.config.wasm {
+            let wasm_error = |e| {
+                Error::explain(
+                    HTTPStatus(500),
+                    format!("WASM filter '{}' failed: {e}", &filter_ref.name),
+                )
+            };
+            let filter = self.wasm_store.get(&filter_ref.name).ok_or_else(|| {
+                Error::explain(
+                    HTTPStatus(500),
+                    format!("WASM filter '{}' isn't loaded", &filter_ref.name),
+                )
+            })?;
+            let mut wasm_ctx = filter.start(&filter_ref.config).map_err(wasm_error)?;
+
+            let scheme = match get_incoming_scheme(session, &self.https_ports)? {
+                IncomingScheme::Http => "http",
+                IncomingScheme::Https => "https",
+            };
+            let end_of_stream = session.is_body_empty();
+            let req_header = session.req_header();
+            let req = WasmRequest {
+                route: &route.config.name,
+                method: req_header.method.as_str(),
+                path: req_header.uri.path(),
+                query: req_header.uri.query().unwrap_or_default(),
+                authority: get_host_header(session)?,
+                scheme,
+                client_ip: exposed_client_ip(session, ctx).unwrap_or_default(),
+                headers: &req_header.headers,
+                end_of_stream,
+            };
+            let outcome = wasm_ctx.on_request_headers(&req).map_err(wasm_error)?;
+            ctx.wasm.push(wasm_ctx);
+
+            for edit in &outcome.edits {
+                edit.apply_to_request(session.req_header_mut())?;
+            }
+            if let Some(local) = outcome.response {
+                let mut resp = ResponseHeader::build(local.status, None)?;
+                for (name, value) in local.headers {
+                    resp.append_header(name, value)?;
+                }
+                resp.insert_header(http::header::CONTENT_LENGTH, local.body.len())?;
+                self.instance.add_headers(&mut resp)?;
+                send_response(session, resp, Some(Bytes::from(local.body))).await?;
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
+    /// Run the `on_request` function of the route's script (if any): apply its header changes, and
+    /// note the origin and cache TTL it chose.  Requests forwarded by a cluster peer run it again,
+    /// since this is where the origin is chosen and the response is cached.
+    /// Return `true` if the script sent a response.
+    async fn run_script(&self, session: &mut Session, ctx: &mut RequestContext) -> Result<bool> {
+        let Some(route) = ctx.route.clone() else {
+            return Ok(false);
+        };
+        let Some(script) = route.config.script.as_ref() else {
+            return Ok(false);
+        };
+        let req_header = session.req_header();
+        let req = ScriptRequest {
+            method: req_header.method.to_string(),
+            path: req_header.uri.path().to_string(),
+            query: req_header.uri.query().unwrap_or_default().to_string(),
+            host: get_host_header(session)?.to_string(),
+            client_ip: exposed_client_ip(session, ctx).unwrap_or_default(),
+            country: ctx.geo.as_ref().and_then(|g| g.country.clone()),
+            region: ctx.geo.as_ref().and_then(|g| g.region.clone()),
+            headers: ScriptHeaders::new(req_header.headers.clone()),
+            ..Default::default()
+        };
+        let req = script.on_request(req).map_err(|e| {
+            Error::explain(
+                HTTPStatus(500),
+                format!("Script of route '{}' failed: {e}", route.config.name),
+            )
+        })?;
+
+        for edit in &req.headers.edits {
+            edit.apply_to_request(session.req_header_mut())?;
+        }
+        if let Some(host) = req.origin {
+            let origins = &route.config.origin_group.origins;
+            let index = origins
+                .iter()
+                .position(|origin| origin.host == host)
+                .ok_or_else(|| {
+                    Error::explain(
+                        HTTPStatus(500),
+                        format!("Script chose unknown origin '{host}'"),
+                    )
+                })?;
+            ctx.script_origin = Some(index);
+        }
+        ctx.script_cache_ttl = req.cache_ttl;
+
+        let Some((status, body)) = req.response else {
+            return Ok(false);
+        };
+        let mut resp = ResponseHeader::build(status, None)?;
+        resp.insert_header(http::header::CONTENT_TYPE, "text/plain; charset=utf-8")?;
+        resp.insert_header(http::header::CONTENT_LENGTH, body.len())?;
+        self.instance.add_headers(&mut resp)?;
+        send_response(session, resp, Some(Bytes::from(body))).await?;
+        Ok(true)
+    }
+
+    /// Send an error response generated by the proxy.  If the matched route or the proxy
+    /// configuration has a custom error page for the status, it is used as the body.
+    async fn send_error(
+        &self,
+        session: &mut Session,
+        ctx: &RequestContext,
+        mut resp: ResponseHeader,
+    ) -> Result<()> {
+        let status = resp.status.as_u16();
+        let page = ctx
+            .route
+            .as_ref()
+            .and_then(|r| r.config.error_pages.get(&status))
+            .or_else(|| self.error_pages.get(&status));
+
+        let body = page.map(|page| {
+            let vars = ErrorVars {
+                status,
+                request_id: &ctx.request_id,
+                host: get_host_header(session).unwrap_or_default(),
+                path: session.req_header().uri.path(),
+            };
+            (page.content_type.clone(), Bytes::from(page.render(&vars)))
+        });
+
+        resp.insert_header(http::header::CACHE_CONTROL, "private, no-store")?;
+        self.instance.add_headers(&mut resp)?;
+        let body = match body {
+            Some((content_type, body)) => {
+                resp.insert_header(http::header::CONTENT_TYPE, content_type)?;
+                resp.insert_header(http::header::CONTENT_LENGTH, body.len())?;
+                Some(body)
+            }
+            None => {
+                resp.insert_header(http::header::CONTENT_LENGTH, 0)?;
+                None
+            }
+        };
+        send_response(session, resp, body).await
+    }
+
+    /// Send a route's failover response, returning its status.
+    async fn send_failover(
+        &self,
+        session: &mut Session,
+        failover: &FailoverResponse,
+    ) -> Result<u16> {
+        let (mut resp, body) = failover.response()?;
+        self.instance.add_headers(&mut resp)?;
+        let status = resp.status.as_u16();
+        send_response(session, resp, Some(body)).await?;
+        Ok(status)
+    }
+
+    /// Take `bytes` sent to the client from the response's and the customer's bandwidth limits, and
+    /// return how long to wait before sending them (if at all).  Requests forwarded by a cluster
+    /// peer are throttled there.
+    fn throttle(&self, ctx: &mut RequestContext, bytes: usize) -> Option<Duration> {
+        if ctx.from_peer {
+            return None;
+        }
+        let response = ctx.pacer.as_mut().and_then(|pacer| pacer.delay(bytes));
+        let customer = ctx
+            .route
+            .as_ref()
+            .and_then(|r| self.throttler.customer_delay(&r.config.customer, bytes));
+        response.max(customer)
+    }
+
+    /// Override the host header in the upstream request if the origin configuration (or else the
+    /// route configuration) has a host header override.
     fn override_host_header(
         &self,
+        session: &Session,
         upstream_request: &mut RequestHeader,
         ctx: &mut RequestContext,
     ) -> Result<()> {
@@ -126,8 +1709,9 @@ impl Proxy {
             )
         })?;
 
-        if let Some(ref host_header_override) = origin.host_header_override {
-            upstream_request.insert_header("host", host_header_override)?;
+        let route = ctx.route.as_ref().map(|r| &r.config);
+        if let Some(host) = host_header_override(session, route, origin) {
+            upstream_request.insert_header("host", host)?;
         }
 
         Ok(())
@@ -142,80 +1726,106 @@ impl Proxy {
             return Error::e_explain(HTTPStatus(502), "No origins in origin group");
         }
 
-        let mut down_origins: Vec<usize> = Vec::new();
-
+        // If any origins were marked down more than N seconds ago, unmark them.
+        // First, take a read lock and check if any were marked down more than N seconds ago.
+        // Most of the time, we shouldn't find any that need to be unmarked, and the precomputed
+        // selection can be used as is.
+        let mut found_expired = false;
         {
-            // If any origins were marked down more than N seconds ago, unmark them.
-            // First, take a read lock and check if any were marked down more than N seconds ago.
-            // Most of the time, we shouldn't find any that need to be unmarked.
-            let mut found_expired = false;
-            {
-                let state = route.state.read().unwrap();
-                for (_, &timestamp) in state.down_endpoints.iter() {
-                    if timestamp.elapsed() > Duration::from_secs(self.origin_down_time) {
-                        found_expired = true;
-                        break;
-                    }
+            let state = route.state.read().unwrap();
+            for (_, &timestamp) in state.down_endpoints.iter() {
+                if timestamp.elapsed() > Duration::from_secs(self.origin_down_time) {
+                    found_expired = true;
+                    break;
                 }
             }
-            // In the rare chance that any were found, take a write lock and remove them.
-            if found_expired {
-                info!(
-                    "Unmarking origin(s) that were marked down more than {} seconds ago",
-                    self.origin_down_time
-                );
-                let mut state = route.state.write().unwrap();
-                state
-                    .down_endpoints
-                    .retain(|_, v| v.elapsed() <= Duration::from_secs(self.origin_down_time));
-            }
-
-            // Copy the list of origins still marked down.
-            let state = route.state.read().unwrap();
-            for (&index, _) in state.down_endpoints.iter() {
-                down_origins.push(index);
+            if let (false, Some(selection)) = (found_expired, &state.selection) {
+                if selection.all_down {
+                    debug!("All origins marked down. Picking a down origin");
+                }
+                return Ok(selection.sample());
             }
         }
 
-        // Get a list of eligible origins along with their weights.  The list of eligible origins includes
-        // all the origins that aren't marked down; Or, if all origins are marked down, then all are eligible.
-        // The data structure is a vector of tuples, where the first element is the index of the origin in the
-        // origin group and the second element is the weight of the origin.
-        let mut eligible_origins_and_weights: Vec<(usize, u16)> = Vec::new();
-        if down_origins.len() == origins.len() {
-            info!("All origins marked down. Picking a down origin");
-            for (index, origin) in origins.iter().enumerate() {
-                eligible_origins_and_weights.push((index, origin.weight));
-            }
-        } else {
-            for (index, origin) in origins.iter().enumerate() {
-                if !down_origins.contains(&index) {
-                    eligible_origins_and_weights.push((index, origin.weight));
+        // In the rare chance that any were found (or the selection needs to be rebuilt), take a
+        // write lock.
+        let mut state = route.state.write().unwrap();
+        if found_expired {
+            info!(
+                "Unmarking origin(s) that were marked down more than {} seconds ago",
+                self.origin_down_time
+            );
+            state.down_endpoints.retain(|&index, v| {
+                let down = v.elapsed() <= Duration::from_secs(self.origin_down_time);
+                if !down {
+                    metrics::origin_state_changed(&route.config.name, &origins[index].host, false);
+                    events::emit(Event::OriginUp {
+                        route: &route.config.name,
+                        origin: &origins[index].host,
+                    });
                 }
-            }
+                down
+            });
+            state.selection = None;
         }
 
-        // Select an eligible origin randomly using the weights of all eligible origins.
-        let mut rng = rand::thread_rng();
-        let weights: Vec<_> = eligible_origins_and_weights.iter().map(|e| e.1).collect();
-        let dist = WeightedIndex::new(weights)
+        let selection = state
+            .selection(origins)
             .or_else(|e| Error::e_because(HTTPStatus(500), "Unable to create WeightedIndex", e))?;
-        let index_into_eligible_origins = dist.sample(&mut rng);
-        Ok(eligible_origins_and_weights[index_into_eligible_origins].0)
+        if selection.all_down {
+            debug!("All origins marked down. Picking a down origin");
+        }
+        Ok(selection.sample())
     }
 
-    fn mark_origin_down(route: &Route, origin_index: usize) -> Result<()> {
+    /// Count a failed attempt to connect to the origin (`dns` if its hostname couldn't be
+    /// resolved) and mark it down.
+    pub fn mark_origin_down(route: &Route, origin_index: usize, dns: bool) -> Result<()> {
         let mut state = route.state.write().unwrap();
         let origins = &route.config.origin_group.origins;
         if origins.is_empty() {
             return Err(Error::new_str("No origins in origin group"));
         }
+        let host = &origins[origin_index].host;
+        let failures = {
+            let failures = state.consecutive_failures.entry(origin_index).or_default();
+            *failures += 1;
+            *failures
+        };
+        metrics::origin_failed(&route.config.name, host, failures, dns);
         if let Entry::Vacant(e) = state.down_endpoints.entry(origin_index) {
-            info!("Marking origin '{}' down", &origins[origin_index].host);
+            info!("Marking origin '{}' down", host);
             let _ = e.insert(Instant::now());
+            state.selection = None;
+            metrics::origin_state_changed(&route.config.name, host, true);
+            events::emit(Event::OriginDown {
+                route: &route.config.name,
+                origin: host,
+                failures,
+                dns,
+            });
         }
         Ok(())
     }
+
+    /// Reset the origin's failure count after a successful connection.
+    fn origin_connected(route: &Route, origin_index: usize, connect: Option<Duration>) {
+        let Some(origin) = route.config.origin_group.origins.get(origin_index) else {
+            return;
+        };
+        metrics::origin_connected(&route.config.name, &origin.host, connect);
+        // Most of the time, there were no failures, so only a read lock is needed.
+        if route
+            .state
+            .read()
+            .unwrap()
+            .consecutive_failures
+            .contains_key(&origin_index)
+        {
+            let mut state = route.state.write().unwrap();
+            state.consecutive_failures.remove(&origin_index);
+        }
+    }
 }
 
 /// The implementation of the interface between Pingora and the proxy.
@@ -223,76 +1833,221 @@ impl Proxy {
 impl ProxyHttp for Proxy {
     type CTX = RequestContext;
     fn new_ctx(&self) -> Self::CTX {
-        RequestContext::new()
+        RequestContext::new(self.drainer.track())
     }
 
     /// The first phase in the request lifetime.  This is where we try to find a matching route
     /// which will be saved in the request context.
     async fn request_filter(&self, session: &mut Session, ctx: &mut Self::CTX) -> Result<bool> {
-        self.find_route(session, ctx)?;
+        if self.drainer.is_draining() {
+            // Ask the client to move to another instance after this request.
+            session.set_keepalive(None);
+        }
+        if self.check_memory(session, ctx).await? {
+            return Ok(true);
+        }
+        if self.check_deny_list(session, ctx).await? {
+            return Ok(true);
+        }
+        if self.check_tls_fingerprint(session, ctx).await? {
+            return Ok(true);
+        }
+        self.check_peer_request(session, ctx);
+        if self.check_prewarm(session, ctx)? {
+            return Ok(false);
+        }
+        if self.route_store.has_sni_routes() && !session.is_http2() {
+            ctx.sni = sni::current().await;
+        }
+        let route_match_start = Instant::now();
+        let found = self.find_route(session, ctx);
+        ctx.timings.route_match = Some(route_match_start.elapsed());
+        found?;
+        if self.check_client_ip(session, ctx).await? {
+            return Ok(true);
+        }
+        if self.check_https_redirect(session, ctx).await? {
+            return Ok(true);
+        }
+        if self.check_geo(session, ctx).await? {
+            return Ok(true);
+        }
+        if self.check_protocol(session, ctx).await? {
+            return Ok(true);
+        }
+        if self.check_header_limits(session, ctx).await? {
+            return Ok(true);
+        }
+        if self.check_methods(session, ctx).await? {
+            return Ok(true);
+        }
+        if self.check_ranges(session, ctx).await? {
+            return Ok(true);
+        }
+        if self.check_waf(session, ctx).await? {
+            return Ok(true);
+        }
+        if self.check_rate_limit(session, ctx).await? {
+            return Ok(true);
+        }
+        if self.check_bots(session, ctx).await? {
+            return Ok(true);
+        }
+        if self.check_quota(session, ctx).await? {
+            return Ok(true);
+        }
+        if self.handle_cors_preflight(session, ctx).await? {
+            return Ok(true);
+        }
+        if self.check_signed_url(session, ctx).await? {
+            return Ok(true);
+        }
+        if self.check_basic_auth(session, ctx).await? {
+            return Ok(true);
+        }
+        if self.check_forward_auth(session, ctx).await? {
+            return Ok(true);
+        }
+        if self.inject_fault(session, ctx).await? {
+            return Ok(true);
+        }
+        self.assign_bucket(session, ctx)?;
+        if self.run_plugins(session, ctx).await? {
+            return Ok(true);
+        }
+        if self.run_wasm(session, ctx).await? {
+            return Ok(true);
+        }
+        if self.run_script(session, ctx).await? {
+            return Ok(true);
+        }
+        self.hash_post_body(session, ctx).await?;
+        if self.check_idempotency_key(session, ctx).await? {
+            return Ok(true);
+        }
+        if self.collapse_request(session, ctx).await? {
+            return Ok(true);
+        }
         Ok(false)
     }
 
-    /// Select an origin to forward the request to.
+    /// Select an origin to forward the request to (or, on a cache miss for a key owned by a
+    /// cluster peer, the peer).
     async fn upstream_peer(
         &self,
         session: &mut Session,
         ctx: &mut Self::CTX,
     ) -> Result<Box<HttpPeer>> {
+        // The redirect's response disabled the cache, so enable it again to cache the final
+        // response under the request's key.
+        if ctx.redirect.is_some()
+            && session.cache.phase() == CachePhase::Disabled(NoCacheReason::Custom("redirect"))
+        {
+            let key = self.cache_key_callback(session, ctx)?;
+            session.cache.enable(
+                &*CACHE_BACKEND,
+                Some(EVICTION_MANAGER.get().unwrap()),
+                None,
+                None,
+            );
+            session.cache.set_cache_key(key);
+            session.cache.cache_miss();
+        }
+        if let Some(peer) = self.cache_peer(session, ctx) {
+            ctx.timings.connect_started();
+            return Ok(peer);
+        }
+
         let route = ctx
             .route
             .as_ref()
             .ok_or_else(|| Error::explain(HTTPStatus(500), "Missing expected route"))?;
 
-        let origin_index = self.select_origin(route)?;
+        let prewarm_origin = ctx.prewarm.as_ref().map(|target| target.origin_index);
+        let redirect_origin = ctx.redirect.as_ref().and_then(|r| r.origin_index);
+        let origin_index = match prewarm_origin
+            .or(redirect_origin)
+            .or(ctx.script_origin)
+            .or(ctx.bucket_origin)
+        {
+            Some(index) => index,
+            None => self.select_origin(route)?,
+        };
         let origin = &route.config.origin_group.origins[origin_index];
 
-        // TODO: Save a *reference* to the origin in the context.
         ctx.origin = Some(origin.clone());
         ctx.origin_index = Some(origin_index);
 
+        // Release the connection of a previous attempt, then wait for one to the origin (if its
+        // connections are limited).
+        ctx.upstream_connection = None;
+        ctx.connection_permit = None;
+        ctx.connection_permit = route
+            .connection_limits
+            .acquire(&route.config.name, origin_index, origin)
+            .await?;
+
         // Determine whether to connect to the origin using TLS, what port to use, what SNI to use
         // based on the origin's configuration.
         let incoming_scheme = get_incoming_scheme(session, &self.https_ports)?;
-        let use_tls = match &route.config.outgoing_scheme {
-            OutgoingScheme::Http => false,
-            OutgoingScheme::Https => true,
-            OutgoingScheme::MatchIncoming => match &incoming_scheme {
+        let server_port = get_server_port(session)?;
+        // Prewarm requests arrive over HTTP, and carry the scheme to prewarm.
+        let mapping = match ctx.prewarm {
+            Some(_) => None,
+            None => route
+                .config
+                .port_map
+                .iter()
+                .find(|m| m.incoming_port == server_port),
+        };
+        let outgoing_scheme = mapping
+            .and_then(|m| m.outgoing_scheme.as_ref())
+            .unwrap_or(&route.config.outgoing_scheme);
+        let use_tls = match (outgoing_scheme, &ctx.prewarm) {
+            (OutgoingScheme::Http, _) => false,
+            (OutgoingScheme::Https, _) => true,
+            (OutgoingScheme::MatchIncoming, Some(target)) => target.use_tls,
+            (OutgoingScheme::MatchIncoming, None) => match &incoming_scheme {
                 IncomingScheme::Http => false,
                 IncomingScheme::Https => true,
             },
         };
-        let outgoing_port = if use_tls {
-            origin.https_port
-        } else {
-            origin.http_port
-        };
-        let sni = match origin.sni.as_ref() {
-            Some(sni) => sni.clone(),
-            None => "".to_string(),
+        let outgoing_port = match mapping {
+            Some(mapping) => mapping.outgoing_port,
+            None if use_tls => origin.https_port,
+            None => origin.http_port,
         };
+        let host_header = host_header_override(session, Some(&route.config), origin)
+            .unwrap_or_else(|| get_host_header(session).unwrap_or_default().to_string());
+        let sni = origin.sni(&host_header);
 
-        info!(
+        debug!(
             "Routing request to {}:{}",
             origin.host.as_str(),
             outgoing_port
         );
 
         ctx.tries += 1;
+        if ctx.tries == 1 && ctx.prewarm.is_none() {
+            for budget in self.retry_budgets(route) {
+                budget.record_request();
+            }
+        }
 
-        // Resolve the host to an IP address (asynchronously).
+        // Resolve the host to an IP address (asynchronously, usually from the resolver's cache).
         // Note: `HttpPeer::new` can also do this, but it is blocking.
-        let addr = match lookup_host((origin.host.as_str(), outgoing_port)).await {
-            // For now, we only use the first address found.
-            Ok(mut addrs) => addrs
-                .next()
-                .ok_or_else(|| Error::explain(HTTPStatus(502), "No address found"))?,
+        let dns_start = Instant::now();
+        let resolved = self.resolver.resolve(&origin.host).await;
+        ctx.timings.dns = Some(dns_start.elapsed());
+        let addr = match resolved {
+            Ok(ip) => SocketAddr::new(ip, outgoing_port),
             Err(e) => {
                 // Mark the origin down and return an error.  If the connection attempt should be
                 // retried, Pingora will call `upstream_peer` again
-                Self::mark_origin_down(route, origin_index).expect("Expect at least one origin");
+                Self::mark_origin_down(route, origin_index, true)
+                    .expect("Expect at least one origin");
                 let mut e = Error::because(HTTPStatus(502), "Unable to resolve host", e);
-                if ctx.tries <= self.connection_retry_limit {
+                if self.should_retry(route, origin_index, ctx.tries) {
                     e.set_retry(true);
                 }
                 return Err(e);
@@ -304,8 +2059,16 @@ impl ProxyHttp for Proxy {
         // If using HTTP/2, try HTTP/2 but fall back to HTTP/1.1 if it fails.
         if use_tls {
             peer.options.set_http_version(2, 1);
+            peer.options.alternative_cn = origin.verify_hostname.clone();
+        }
+        if let Some(policy) = origin.keepalive.as_ref() {
+            policy.apply(&mut peer.options);
+        }
+        if let Some(policy) = origin.prewarm.as_ref() {
+            peer.options.idle_timeout = Some(Duration::from_secs(policy.idle_ttl));
         }
 
+        ctx.timings.connect_started();
         Ok(peer)
     }
 
@@ -315,34 +2078,243 @@ impl ProxyHttp for Proxy {
         let Some(route) = &ctx.route else {
             return Ok(());
         };
-        if !route.config.cache {
+        if !route.config.cache || ctx.prewarm.is_some() {
+            return Ok(());
+        }
+        let cacheable_method = match session.req_header().method {
+            http::Method::GET | http::Method::HEAD => true,
+            http::Method::POST => ctx.body_hash.is_some(),
+            _ => false,
+        };
+        if !cacheable_method {
             return Ok(());
         }
+        if let Some(policy) = route.config.ranges.as_ref() {
+            if policy.bypasses_cache(session.req_header()) {
+                debug!("Range request bypasses the cache");
+                return Ok(());
+            }
+        }
 
+        let cache_lock = route
+            .config
+            .cache_lock
+            .as_ref()
+            .map_or_else(|| CacheLockPolicy::default().lock(), CacheLockPolicy::lock);
         session.cache.enable(
             &*CACHE_BACKEND,
             Some(EVICTION_MANAGER.get().unwrap()),
             None,
-            Some(&*CACHE_LOCK),
+            Some(cache_lock),
         );
         Ok(())
     }
 
+    /// Generate the cache key for the request.  For routes with signed URLs, the signature
+    /// parameters are left out of the key so that all signed links to the same content share a
+    /// cache entry.
+    /// Requests assigned to a bucket are cached separately for each bucket (and for each country,
+    /// if the route's geo policy varies the cache), and POST requests are keyed on their body as
+    /// well.
+    /// The key is tagged with the route name so that cache usage can be attributed to routes.
+    fn cache_key_callback(&self, session: &Session, ctx: &mut Self::CTX) -> Result<CacheKey> {
+        let req_header = session.req_header();
+        let Some(route) = &ctx.route else {
+            return Ok(CacheKey::default(req_header));
+        };
+        let primary = match &route.config.signed_url {
+            Some(config) => config.strip_signature(&req_header.uri),
+            None => req_header.uri.to_string(),
+        };
+        let primary = match &ctx.body_hash {
+            Some(hash) => format!("POST {primary} {hash}"),
+            None => primary,
+        };
+        let mut namespace = ctx.bucket.clone().unwrap_or_default();
+        if route.config.geo.as_ref().is_some_and(|g| g.vary_cache) {
+            let country = ctx.geo.as_ref().and_then(|g| g.country.as_deref());
+            namespace = format!("{namespace}/geo:{}", country.unwrap_or("-"));
+        }
+        Ok(CacheKey::new(namespace, primary, route.config.name.clone()))
+    }
+
+    /// Decide whether a request that wasn't served from the cache goes to the origin.  Requests
+    /// that gave up waiting for a cache lock are handled according to the route's cache lock
+    /// policy, and clients the route's bot policy only serves from the cache are sent a 503.
+    async fn proxy_upstream_filter(
+        &self,
+        session: &mut Session,
+        ctx: &mut Self::CTX,
+    ) -> Result<bool> {
+        if session.cache.phase() == CachePhase::Disabled(NoCacheReason::CacheLockTimeout)
+            && self.handle_lock_timeout(session, ctx).await?
+        {
+            return Ok(false);
+        }
+        if !ctx.cached_only {
+            return Ok(true);
+        }
+        debug!("Cache miss for a client served from the cache only");
+        let mut resp = ResponseHeader::build(StatusCode::SERVICE_UNAVAILABLE, Some(2))?;
+        resp.insert_header(http::header::RETRY_AFTER, 3600)?;
+        self.send_error(session, ctx, resp).await?;
+        Ok(false)
+    }
+
     /// Modify the request headers before sending them to the upstream server.
-    /// Override the host header in the upstream request if the origin configuration has a host
-    /// header override.
+    /// Override the host header in the upstream request if the origin or route configuration has a
+    /// host header override, add any headers approved by a forward auth service, filter cookies,
+    /// add the client's location (if the route's geo policy sends it), add the route's upstream
+    /// headers, and let the route's plugins make their changes.
+    /// Requests to a cluster peer are only marked as such (with the client's address and
+    /// location), since the peer makes these changes.
+    /// Requests following an origin redirect are sent to its location; others have their path
+    /// rewritten if the route says so.
     async fn upstream_request_filter(
         &self,
-        _session: &mut Session,
+        session: &mut Session,
         upstream_request: &mut RequestHeader,
         ctx: &mut Self::CTX,
     ) -> Result<()> {
-        self.override_host_header(upstream_request, ctx)
+        if let (Some(cluster), Some(_)) = (&self.cluster, ctx.peer) {
+            upstream_request
+                .insert_header(cluster::PEER_HEADER, cluster.self_addr().to_string())?;
+            upstream_request.remove_header(cluster::CLIENT_HEADER);
+            if let Some(client_ip) = get_client_ip(session) {
+                upstream_request.insert_header(cluster::CLIENT_HEADER, client_ip.to_string())?;
+            }
+            insert_geo_headers(upstream_request, ctx.geo.as_ref())?;
+            ctx.timings.request_sent();
+            return Ok(());
+        }
+        if ctx.from_peer {
+            upstream_request.remove_header(cluster::PEER_HEADER);
+            upstream_request.remove_header(cluster::CLIENT_HEADER);
+        }
+        if let Some(redirect) = &ctx.redirect {
+            upstream_request.set_uri(redirect.uri.clone());
+            if let Some(host) = &redirect.host {
+                upstream_request.insert_header(http::header::HOST, host)?;
+            }
+        } else if let Some(uri) = ctx
+            .route
+            .as_ref()
+            .and_then(|r| r.config.upstream_uri(&upstream_request.uri))
+        {
+            upstream_request.set_uri(uri);
+        }
+        self.override_host_header(session, upstream_request, ctx)?;
+        if let Some(config) = ctx
+            .route
+            .as_ref()
+            .and_then(|r| r.config.forward_auth.as_ref())
+        {
+            // These headers only come from the auth service, so clients can't forge an identity.
+            for name in &config.response_headers {
+                upstream_request.remove_header(name.as_str());
+            }
+        }
+        for (name, value) in &ctx.auth_headers {
+            upstream_request.insert_header(name, value)?;
+        }
+        if let Some(policy) = ctx.cookie_policy() {
+            policy.filter_request(upstream_request)?;
+        }
+        if let Some(name) = self.tls_fingerprints.upstream_header() {
+            // Only the proxy sets the header: a value sent by the client (e.g., over plain HTTP or
+            // HTTP/2, where there's no fingerprint) is removed.
+            upstream_request.remove_header(name);
+            if let Some(fingerprint) = &ctx.tls_fingerprint {
+                upstream_request.insert_header(name.clone(), fingerprint.hash.as_str())?;
+            }
+        }
+        let geo_headers = ctx
+            .route
+            .as_ref()
+            .and_then(|r| r.config.geo.as_ref())
+            .is_some_and(|g| g.upstream_headers);
+        insert_geo_headers(upstream_request, ctx.geo.as_ref().filter(|_| geo_headers))?;
+        if let Some(route) = ctx.route.as_ref() {
+            for header in &route.config.upstream_headers {
+                let value = self.secret_store.render(&header.value).map_err(|e| {
+                    Error::explain(
+                        HTTPStatus(500),
+                        format!("Upstream header '{}': {e}", &header.name),
+                    )
+                })?;
+                upstream_request.insert_header(header.name.clone(), value)?;
+            }
+        }
+        // Compressed bodies can't be rewritten.
+        if ctx
+            .route
+            .as_ref()
+            .is_some_and(|r| r.config.body_rewrite.is_some())
+        {
+            upstream_request.remove_header(&http::header::ACCEPT_ENCODING);
+        }
+        if let Some(route) = ctx.route.clone() {
+            for (plugin, config) in self.plugins.for_route(&route.config) {
+                let mut plugin_ctx = PluginContext {
+                    route: &route.config,
+                    config,
+                    state: &mut ctx.plugin_state,
+                };
+                plugin
+                    .upstream_request_filter(session, upstream_request, &mut plugin_ctx)
+                    .await?;
+            }
+        }
+        // Signing must come last, since it covers the final host header.
+        if let Some(config) = ctx.origin.as_ref().and_then(|o| o.aws_sigv4.as_ref()) {
+            self.aws_signer.sign(config, upstream_request)?;
+        }
+        ctx.timings.request_sent();
+        Ok(())
+    }
+
+    /// Record how long it took to connect to the upstream server, and reset the origin's failure
+    /// count.
+    async fn connected_to_upstream(
+        &self,
+        _session: &mut Session,
+        reused: bool,
+        _peer: &HttpPeer,
+        _fd: std::os::unix::io::RawFd,
+        digest: Option<&Digest>,
+        ctx: &mut Self::CTX,
+    ) -> Result<()>
+    where
+        Self::CTX: Send + Sync,
+    {
+        ctx.timings.connected(reused, digest);
+        if let (Some(route), Some(origin_index)) = (&ctx.route, ctx.origin_index) {
+            let connect = ctx
+                .timings
+                .connect
+                .map(|tcp| tcp + ctx.timings.tls_handshake.unwrap_or_default());
+            Self::origin_connected(route, origin_index, connect);
+            let origin = &route.config.origin_group.origins[origin_index];
+            if !reused && digest.is_some_and(|d| d.ssl_digest.is_some()) {
+                metrics::origin_tls_handshake(
+                    &route.config.name,
+                    &origin.host,
+                    ctx.timings.tls_handshake,
+                );
+            }
+            ctx.upstream_connection = Some(ActiveConnection::new(
+                &route.config.name,
+                &origin.host,
+                reused,
+            ));
+        }
+        Ok(())
     }
 
     /// Handle the case where the connection to the upstream server fails.
     /// Mark the origin down for a while and specify whether the connection attempt should be
-    /// retried (possibly to a different origin).
+    /// retried (possibly to a different origin).  If a cluster peer couldn't be reached, it is
+    /// avoided for a while and the request goes to the origin instead.
     fn fail_to_connect(
         &self,
         _session: &mut Session,
@@ -350,6 +2322,16 @@ impl ProxyHttp for Proxy {
         ctx: &mut Self::CTX,
         mut e: Box<Error>,
     ) -> Box<Error> {
+        if let Some(peer) = ctx.peer.take() {
+            if let Some(cluster) = &self.cluster {
+                cluster.mark_down(peer);
+            }
+            ctx.peer_failed = true;
+            debug!("Cache peer {peer} unreachable; going to the origin");
+            e.set_retry(true);
+            return e;
+        }
+
         let Some(route) = ctx.route.as_ref() else {
             return e;
         };
@@ -361,28 +2343,177 @@ impl ProxyHttp for Proxy {
             return e;
         };
 
-        if Self::mark_origin_down(route, origin_index).is_err() {
+        if Self::mark_origin_down(route, origin_index, false).is_err() {
+            return e;
+        }
+
+        // Prewarm requests are for a specific origin.
+        if ctx.prewarm.is_some() {
             return e;
         }
 
-        // Retry once.
-        if ctx.tries > self.connection_retry_limit {
-            info!("Connection retry limit exceed");
+        if !self.should_retry(route, origin_index, ctx.tries) {
             return e;
         }
-        info!("Retrying connection");
+        debug!("Retrying connection");
         e.set_retry(true);
         e
     }
 
+    /// Modify the response headers received from the upstream server (before they are cached).
+    /// Record the time to the upstream response, note the cache status reported by a cluster peer,
+    /// note a redirect to follow if the route follows them (leaving the response as is), strip
+    /// `Set-Cookie` if the route caches and its cookie policy says so, set up the rewriting of the
+    /// body if the route's body rewriting applies (a cluster peer already rewrote it), and start
+    /// collecting the response if identical requests wait for it.
+    fn upstream_response_filter(
+        &self,
+        session: &mut Session,
+        upstream_response: &mut ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) {
+        ctx.timings.response_received();
+        if ctx.peer.is_some() {
+            let hit = upstream_response
+                .headers
+                .get("x-cache-status")
+                .is_some_and(|status| status == "hit");
+            ctx.peer_cache_status = Some(if hit { "peer-hit" } else { "peer-miss" });
+        }
+        let Some(route) = ctx.route.as_ref() else {
+            return;
+        };
+        if let (None, None, Some(policy)) = (ctx.peer, &ctx.prewarm, &route.config.redirects) {
+            let req = session.req_header();
+            let host = match ctx.redirect.as_ref().and_then(|r| r.host.as_deref()) {
+                Some(host) => host,
+                None => get_host_header(session).unwrap_or_default(),
+            };
+            let uri = ctx.redirect.as_ref().map_or(&req.uri, |r| &r.uri);
+            let path = uri.path_and_query().map_or("/", |p| p.as_str());
+            if let Some(mut redirect) = policy.follow(
+                &route.config,
+                &req.method,
+                ctx.redirects,
+                host,
+                path,
+                upstream_response,
+            ) {
+                debug!("Following redirect from the origin to {}", redirect.uri);
+                metrics::redirect_followed(&route.config.name);
+                redirect.origin_index = redirect.origin_index.or(ctx.origin_index);
+                ctx.redirect = Some(redirect);
+                ctx.redirects += 1;
+                ctx.following_redirect = true;
+                return;
+            }
+        }
+        if let (true, Some(policy)) = (route.config.cache, ctx.cookie_policy()) {
+            policy.filter_response(upstream_response);
+        }
+        if let (None, Some(policy)) = (ctx.peer, route.config.body_rewrite.as_ref()) {
+            if policy.applies_to(upstream_response) {
+                upstream_response.remove_header(&http::header::CONTENT_LENGTH);
+                ctx.body_rewriter = Some(BodyRewriter::new(policy));
+            }
+        }
+        if let Some(leader) = ctx.collapse_leader.as_mut() {
+            if !leader.response_header(upstream_response) {
+                ctx.collapse_leader = None;
+            }
+        }
+        if let Some(recorder) = ctx.idempotency_recorder.as_mut() {
+            if !recorder.response_header(upstream_response) {
+                ctx.idempotency_recorder = None;
+            }
+        }
+    }
+
+    /// Handle a fatal error by sending an error response (using a custom error page if one is
+    /// configured for the status).  The status is determined the same way as Pingora's default
+    /// implementation.  If no origin could be reached (and there was no stale cache entry to serve
+    /// instead), the route's failover response is sent if it has one.
+    async fn fail_to_proxy(&self, session: &mut Session, e: &Error, ctx: &mut Self::CTX) -> u16
+    where
+        Self::CTX: Send + Sync,
+    {
+        let code = match e.etype() {
+            HTTPStatus(code) => *code,
+            _ => match e.esource() {
+                ErrorSource::Upstream => 502,
+                ErrorSource::Downstream => match e.etype() {
+                    // The connection is already dead.
+                    WriteError | ReadError | ConnectionClosed => 0,
+                    _ => 400,
+                },
+                ErrorSource::Internal | ErrorSource::Unset => 500,
+            },
+        };
+        let failover = ctx
+            .route
+            .as_ref()
+            .and_then(|r| r.config.failover.as_ref())
+            .filter(|_| ctx.tries > 0 && FailoverResponse::replaces(code));
+        if let Some(failover) = failover {
+            match self.send_failover(session, failover).await {
+                Ok(status) => return status,
+                Err(e) => warn!("Failed to send failover response: {e}"),
+            }
+        }
+        if code > 0 {
+            let sent = match ResponseHeader::build(code, None) {
+                Ok(resp) => self.send_error(session, ctx, resp).await,
+                Err(e) => Err(e),
+            };
+            if let Err(e) = sent {
+                warn!("Failed to send error response: {e}");
+            }
+        }
+        code
+    }
+
     /// Determine if the response should be cached based on the response headers.
     /// This function is only called if caching was enabled in `request_cache_filter`.
+    /// Responses that set cookies are never cached, since the cookies would be served to every
+    /// client.  Responses from a cluster peer are cached by the peer, not again here.  A cache TTL
+    /// chosen by the route's script overrides the response's cache headers.
     fn response_cache_filter(
         &self,
         _session: &Session,
         resp: &ResponseHeader,
-        _ctx: &mut Self::CTX,
+        ctx: &mut Self::CTX,
     ) -> Result<RespCacheable> {
+        if ctx.following_redirect {
+            return Ok(RespCacheable::Uncacheable(NoCacheReason::Custom(
+                "redirect",
+            )));
+        }
+        if ctx.peer.is_some() {
+            return Ok(RespCacheable::Uncacheable(NoCacheReason::Custom(
+                "cluster-peer",
+            )));
+        }
+        if resp.headers.contains_key(http::header::SET_COOKIE) {
+            return Ok(RespCacheable::Uncacheable(NoCacheReason::Custom(
+                "set-cookie",
+            )));
+        }
+        match ctx.script_cache_ttl {
+            Some(0) => {
+                return Ok(RespCacheable::Uncacheable(NoCacheReason::Custom("script")));
+            }
+            Some(ttl) => {
+                let now = SystemTime::now();
+                return Ok(RespCacheable::Cacheable(CacheMeta::new(
+                    now + Duration::from_secs(ttl),
+                    now,
+                    CACHE_META_DEFAULTS.serve_stale_while_revalidate_sec(),
+                    CACHE_META_DEFAULTS.serve_stale_if_error_sec(),
+                    resp.clone(),
+                )));
+            }
+            None => {}
+        }
         let cc = CacheControl::from_resp_headers(resp);
         Ok(resp_cacheable(
             cc.as_ref(),
@@ -392,18 +2523,85 @@ impl ProxyHttp for Proxy {
         ))
     }
 
+    /// Decide whether to answer a conditional request with a 304 instead of the cached response,
+    /// according to the route's policy.
+    fn cache_not_modified_filter(
+        &self,
+        session: &Session,
+        resp: &ResponseHeader,
+        ctx: &mut Self::CTX,
+    ) -> Result<bool> {
+        let policy = ctx
+            .route
+            .as_ref()
+            .map(|route| route.config.conditional)
+            .unwrap_or_default();
+        Ok(policy.not_modified(session.req_header(), resp))
+    }
+
+    /// Rewrite the response body (if the route's body rewriting applies), and account for response
+    /// body bytes that are buffered while being written to the cache.
+    fn upstream_response_body_filter(
+        &self,
+        session: &mut Session,
+        body: &mut Option<Bytes>,
+        end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) {
+        if let Some(body) = body {
+            ctx.origin_response_bytes += body.len() as u64;
+        }
+        if let Some(rewriter) = ctx.body_rewriter.as_mut() {
+            *body = rewriter.rewrite(body.as_deref(), end_of_stream);
+        }
+        if let Some(leader) = ctx.collapse_leader.as_mut() {
+            if !leader.response_body(body.as_deref(), end_of_stream) {
+                ctx.collapse_leader = None;
+            }
+        }
+        if let Some(recorder) = ctx.idempotency_recorder.as_mut() {
+            if !recorder.response_body(body.as_deref(), end_of_stream) {
+                ctx.idempotency_recorder = None;
+            }
+        }
+        if let (true, Some(body)) = (session.cache.enabled(), body) {
+            ctx.buffered += body.len();
+            self.memory.buffer(body.len());
+        }
+    }
+
     /// Modify the response headers before sending them to the client.
-    /// Insert a header indicating the cache status of the response.
+    /// Strip hop-by-hop headers and the headers the route's response header policy removes.
+    /// Insert headers indicating the cache status of the response and the instance serving it,
+    /// apply the route's CORS policy (if any), report the client's remaining rate limit (if any),
+    /// let the route's plugins, WebAssembly filters, and script make their changes, and set up
+    /// bandwidth throttling.
+    /// A redirect to follow isn't sent: the error returned has Pingora retry the request, which
+    /// then goes to the redirect's location.
     async fn response_filter(
         &self,
         session: &mut Session,
         upstream_response: &mut ResponseHeader,
-        _ctx: &mut Self::CTX,
+        ctx: &mut Self::CTX,
     ) -> Result<()>
     where
         Self::CTX: Send + Sync,
     {
-        let cache_status = if session.cache.enabled() {
+        if ctx.following_redirect {
+            ctx.following_redirect = false;
+            let mut e = Error::explain(Custom("FollowRedirect"), "Following origin redirect");
+            e.set_retry(true);
+            return Err(e);
+        }
+        let cache_status = if let Some(status) = ctx.peer_cache_status {
+            status
+        } else if ctx.collapsed {
+            "collapsed"
+        } else if ctx.replayed {
+            "replayed"
+        } else if ctx.served_stale {
+            "stale"
+        } else if session.cache.enabled() {
             match session.cache.phase() {
                 CachePhase::Hit => "hit",
                 CachePhase::Miss => "miss",
@@ -419,17 +2617,414 @@ impl ProxyHttp for Proxy {
             }
         };
 
-        info!("Cache status: {}", cache_status);
+        debug!("Cache status: {}", cache_status);
+        response_headers::strip_hop_by_hop(upstream_response);
+        if let Some(policy) = ctx
+            .route
+            .as_ref()
+            .and_then(|r| r.config.response_headers.as_ref())
+        {
+            policy.apply(upstream_response);
+        }
+        if let (true, Some(route)) = (session.cache.enabled(), &ctx.route) {
+            HOT_KEYS.record(
+                &route.config.name,
+                session.cache.cache_key().primary_key(),
+                cache_status == "hit",
+            );
+        }
         upstream_response.insert_header("x-cache-status", cache_status)?;
+        ctx.cache_status = Some(cache_status);
+        self.instance.add_headers(upstream_response)?;
+        self.debug_headers
+            .echo(session.req_header(), upstream_response)?;
+        if self.debug_headers.applies_to(get_client_ip(session)) {
+            DebugHeaders::add(
+                upstream_response,
+                ctx.origin.as_deref(),
+                ctx.tries,
+                ctx.timings.upstream_response,
+            )?;
+        }
+
+        if let Some(policy) = ctx.route.as_ref().and_then(|r| r.config.cors.as_ref()) {
+            policy.apply_response_headers(session.req_header(), upstream_response)?;
+        }
+        if let Some(policy) = ctx.security_headers_policy() {
+            let https = matches!(
+                get_incoming_scheme(session, &self.https_ports)?,
+                IncomingScheme::Https
+            );
+            policy.apply(upstream_response, https)?;
+        }
+        if let Some(RateLimitDecision::Allowed { limit, remaining }) = ctx.rate_limit {
+            upstream_response.insert_header("x-ratelimit-limit", limit)?;
+            upstream_response.insert_header("x-ratelimit-remaining", remaining)?;
+        }
+
+        if let (false, Some(route)) = (ctx.from_peer, ctx.route.clone()) {
+            for (plugin, config) in self.plugins.for_route(&route.config) {
+                let mut plugin_ctx = PluginContext {
+                    route: &route.config,
+                    config,
+                    state: &mut ctx.plugin_state,
+                };
+                plugin
+                    .response_filter(session, upstream_response, &mut plugin_ctx)
+                    .await?;
+            }
+        }
+        for wasm_ctx in ctx.wasm.iter_mut() {
+            let edits = wasm_ctx
+                .on_response_headers(
+                    upstream_response.status.as_u16(),
+                    &upstream_response.headers,
+                )
+                .map_err(|e| Error::explain(HTTPStatus(500), format!("WASM filter failed: {e}")))?;
+            for edit in &edits {
+                edit.apply_to_response(upstream_response)?;
+            }
+        }
+        if let Some(route) = ctx.route.as_ref() {
+            if let Some(script) = route.config.script.as_ref() {
+                let resp = ScriptResponse {
+                    status: upstream_response.status.as_u16(),
+                    headers: ScriptHeaders::new(upstream_response.headers.clone()),
+                };
+                let resp = script.on_response(resp).map_err(|e| {
+                    Error::explain(
+                        HTTPStatus(500),
+                        format!("Script of route '{}' failed: {e}", route.config.name),
+                    )
+                })?;
+                for edit in &resp.headers.edits {
+                    edit.apply_to_response(upstream_response)?;
+                }
+            }
+        }
+
+        if let Some(route) = ctx.route.as_ref() {
+            let content_type = upstream_response
+                .headers
+                .get(http::header::CONTENT_TYPE)
+                .and_then(|v| v.to_str().ok());
+            let policy = throttle::select_policy(
+                route.config.throttle.as_ref(),
+                &route.config.pacing,
+                content_type,
+            );
+            ctx.pacer = policy.map(|policy| Pacer::new(policy.bytes_per_second, policy.burst));
+        }
+        if cache_status == "hit" || ctx.collapsed || ctx.replayed || ctx.served_stale {
+            // Cache hits, collapsed, replayed, and stale responses are written in one go (without
+            // going through `response_body_filter`), so hold the whole response for as long as its
+            // body takes at the allowed rate.
+            let bytes = upstream_response
+                .headers
+                .get(http::header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse().ok())
+                .unwrap_or(0);
+            if let Some(delay) = self.throttle(ctx, bytes) {
+                tokio::time::sleep(delay).await;
+            }
+        }
         Ok(())
     }
+
+    /// Pace the response body sent to the client according to the bandwidth limits.
+    fn response_body_filter(
+        &self,
+        _session: &mut Session,
+        body: &mut Option<Bytes>,
+        _end_of_stream: bool,
+        ctx: &mut Self::CTX,
+    ) -> Result<Option<Duration>>
+    where
+        Self::CTX: Send + Sync,
+    {
+        let bytes = body.as_ref().map_or(0, |body| body.len());
+        if bytes == 0 {
+            return Ok(None);
+        }
+        Ok(self.throttle(ctx, bytes))
+    }
+
+    /// The last phase in the request lifetime.  Account the bytes sent to the client against the
+    /// customer's bandwidth quota, log the request if it was slow, record the request metrics,
+    /// write the access log, and publish a summary to the request tap.  Sensitive header values
+    /// are redacted from the debug log and the summary.
+    async fn logging(&self, session: &mut Session, e: Option<&Error>, ctx: &mut Self::CTX)
+    where
+        Self::CTX: Send + Sync,
+    {
+        self.memory.release(ctx.buffered);
+        if ctx.prewarm.is_some() {
+            return;
+        }
+        let response_bytes = session.body_bytes_sent() as u64;
+        if let (false, Some(route)) = (ctx.from_peer, ctx.route.as_ref()) {
+            self.quota_tracker
+                .add_bytes(&route.config.customer, response_bytes);
+        }
+        if let Some(route) = ctx.route.as_ref() {
+            let request_bytes = session.body_bytes_read() as u64;
+            self.usage_tracker.record(
+                &route.config.customer,
+                &UsageRecord {
+                    from_client: !ctx.from_peer,
+                    client_ingress_bytes: request_bytes,
+                    client_egress_bytes: response_bytes,
+                    cache_hit: matches!(
+                        ctx.cache_status,
+                        Some("hit" | "stale" | "revalidated" | "peer-hit")
+                    ),
+                    origin_egress_bytes: if ctx.origin.is_some() {
+                        request_bytes
+                    } else {
+                        0
+                    },
+                    origin_ingress_bytes: ctx.origin_response_bytes,
+                },
+            );
+        }
+
+        let status = session
+            .response_written()
+            .map_or(0, |resp| resp.status.as_u16());
+        let duration = ctx.start.elapsed();
+        ctx.timings.cache_lock = session.cache.lock_duration();
+        if let (Some(wait), Some(route)) = (ctx.timings.cache_lock, &ctx.route) {
+            metrics::cache_lock_waited(&route.config.name, wait);
+            if let Some(manager) = EVICTION_MANAGER.get() {
+                manager.record_lock_wait(&route.config.name, wait, ctx.cache_lock_timed_out);
+            }
+        }
+        if log_enabled!(Level::Debug) {
+            debug!(
+                "Request headers: {:?}",
+                self.header_redactor.redact(&session.req_header().headers)
+            );
+            if let Some(resp) = session.response_written() {
+                debug!(
+                    "Response headers: {:?}",
+                    self.header_redactor.redact(&resp.headers)
+                );
+            }
+        }
+        let slow = self
+            .slow_request_threshold
+            .is_some_and(|threshold| duration >= threshold);
+        if slow {
+            warn!(
+                "Slow request: {} {} route={} status={status} total={:.1}ms {}",
+                session.req_header().method,
+                session.req_header().uri,
+                ctx.route
+                    .as_ref()
+                    .map_or("none", |r| r.config.name.as_str()),
+                duration.as_secs_f64() * 1000.0,
+                ctx.timings
+            );
+        }
+
+        self.metrics.record(&RequestRecord {
+            route: ctx
+                .route
+                .as_ref()
+                .map(|r| (r.config.name.as_str(), r.config.customer.as_str())),
+            status,
+            duration,
+            cache_status: ctx.cache_status,
+            response_bytes,
+            slow,
+        });
+
+        let log_sink = ctx.log_sink();
+        if self.access_log.is_enabled() || log_sink.is_some() {
+            let req = session.req_header();
+            let header = |name| req.headers.get(name).and_then(|v| v.to_str().ok());
+            let entry = AccessLogEntry {
+                client_ip: exposed_client_ip(session, ctx),
+                method: req.method.as_str(),
+                uri: &req.uri.to_string(),
+                version: &format!("{:?}", req.version),
+                status,
+                response_bytes,
+                referer: header(http::header::REFERER),
+                user_agent: header(http::header::USER_AGENT),
+                duration,
+                route: ctx.route.as_ref().map(|r| r.config.name.as_str()),
+                cache_status: ctx.cache_status,
+                request_id: &ctx.request_id,
+                origin: ctx.origin.as_ref().map(|o| o.host.as_str()),
+                instance: self.instance.id(),
+                tls_fingerprint: ctx.tls_fingerprint.as_ref().map(|f| f.hash.as_str()),
+            };
+            self.access_log.log(&entry);
+            if let Some(sink) = log_sink {
+                self.log_sinks.log(sink, entry.to_string());
+            }
+        }
+
+        if self.request_tap.is_active() {
+            let req = session.req_header();
+            self.request_tap.publish(RequestSummary {
+                timestamp: SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .map_or(0, |d| d.as_millis() as u64),
+                request_id: ctx.request_id.clone(),
+                client_ip: exposed_client_ip(session, ctx),
+                route: ctx.route.as_ref().map(|r| r.config.name.clone()),
+                customer: ctx.route.as_ref().map(|r| r.config.customer.clone()),
+                method: req.method.to_string(),
+                host: get_host_header(session).unwrap_or_default().to_string(),
+                path: req.uri.path().to_string(),
+                status,
+                duration_ms: duration.as_secs_f64() * 1000.0,
+                cache_status: ctx.cache_status,
+                response_bytes,
+                request_headers: self.header_redactor.redact(&req.headers),
+                response_headers: session
+                    .response_written()
+                    .map(|resp| self.header_redactor.redact(&resp.headers))
+                    .unwrap_or_default(),
+            });
+        }
+
+        if let (false, Some(route)) = (ctx.from_peer, ctx.route.clone()) {
+            for (plugin, config) in self.plugins.for_route(&route.config) {
+                let mut plugin_ctx = PluginContext {
+                    route: &route.config,
+                    config,
+                    state: &mut ctx.plugin_state,
+                };
+                plugin.logging(session, e, &mut plugin_ctx).await;
+            }
+        }
+    }
+}
+
+/// The cache's utilization: (used bytes, items, evicted bytes, evicted items).
+pub fn cache_usage() -> (usize, usize, usize, usize) {
+    match EVICTION_MANAGER.get() {
+        Some(manager) => (
+            manager.total_size(),
+            manager.total_items(),
+            manager.evicted_size(),
+            manager.evicted_items(),
+        ),
+        None => (0, 0, 0, 0),
+    }
+}
+
+/// Evict the least recently used cache entries to free at least `bytes` bytes.  The evicted entries
+/// are purged from storage in the background.
+fn trim_cache(bytes: usize) {
+    let Some(manager) = EVICTION_MANAGER.get() else {
+        return;
+    };
+    let before = manager.total_size();
+    let evicted = manager.trim(bytes);
+    let trimmed = before.saturating_sub(manager.total_size());
+    warn!(
+        "Memory usage is above the trim threshold; evicted {} cache entries ({trimmed} bytes)",
+        evicted.len()
+    );
+    metrics::cache_trimmed(trimmed);
+    tokio::spawn(async move {
+        let span = Span::inactive();
+        for key in evicted {
+            let _ = CACHE_BACKEND.purge(&key, &span.handle()).await;
+        }
+    });
+}
+
+/// The persister of the cache (if it's persisted).  The proxy must have been created.
+pub fn cache_persister(config: &CacheConfig) -> Option<CachePersister> {
+    let persist = config.persist.as_ref()?;
+    Some(CachePersister::new(
+        persist,
+        &CACHE_BACKEND,
+        EVICTION_MANAGER.get()?,
+        &HOT_KEYS,
+    ))
+}
+
+/// Per-route cache statistics and the `hot_keys` most frequently looked up cache keys.
+pub fn cache_report(hot_keys: usize) -> CacheReport {
+    CacheReport {
+        routes: EVICTION_MANAGER
+            .get()
+            .map(|manager| manager.route_stats())
+            .unwrap_or_default(),
+        hot_keys: HOT_KEYS.top(hot_keys),
+    }
+}
+
+/// Send a response generated by the proxy itself (rather than by an origin) to the client.
+async fn send_response(
+    session: &mut Session,
+    resp: ResponseHeader,
+    body: Option<Bytes>,
+) -> Result<()> {
+    session.write_response_header(Box::new(resp)).await?;
+    if let Some(body) = body {
+        session.write_response_body(body).await?;
+    }
+    Ok(())
+}
+
+/// The host header to send to the origin if the origin (or else the route) overrides it.
+fn host_header_override(
+    session: &Session,
+    route: Option<&RouteConfig>,
+    origin: &Origin,
+) -> Option<String> {
+    let template = origin
+        .host_header_override
+        .as_ref()
+        .or_else(|| route.and_then(|r| r.host_header_override.as_ref()))?;
+    let host = match route {
+        Some(route) => client_host(session, route),
+        None => get_host_header(session),
+    }
+    .unwrap_or_default();
+    Some(route_config::render_host_header(
+        template,
+        host,
+        &origin.host,
+    ))
 }
 
-/// Get the host header from the request.  If HTTP/2 or a missing host header, use the "authority"
-/// header or portion of the URI instead.
+/// Get the host header from the request, without its port.  If HTTP/2 or a missing host header,
+/// use the "authority" header or portion of the URI instead.
 /// Return a 400 status code if no header could be found.
 fn get_host_header(session: &Session) -> Result<&str> {
-    let host = match session.get_header(http::header::HOST) {
+    let host = get_host_header_with_port(session);
+
+    // If the host contains a colon (e.g., "example.com:443"), return the part before the colon.
+    if let Ok(host) = host {
+        if let Some(index) = host.find(':') {
+            return Ok(&host[..index]);
+        }
+    }
+
+    host
+}
+
+/// The host the client requested, with the port of its host header if the route preserves it.
+fn client_host<'a>(session: &'a Session, route: &RouteConfig) -> Result<&'a str> {
+    if route.preserve_host_port {
+        get_host_header_with_port(session)
+    } else {
+        get_host_header(session)
+    }
+}
+
+/// Get the host header from the request, including its port (if it has one).
+fn get_host_header_with_port(session: &Session) -> Result<&str> {
+    match session.get_header(http::header::HOST) {
         Some(host_header) => host_header
             .to_str()
             .map_err(|_| Error::explain(HTTPStatus(400), "Non-ascii host header")),
@@ -439,28 +3034,74 @@ fn get_host_header(session: &Session) -> Result<&str> {
             Some(authority) => Ok(authority.as_str()),
             None => Error::e_explain(HTTPStatus(400), "No host header or authority detected"),
         },
-    };
+    }
+}
 
-    // If the host contains a colon (e.g., "example.com:443"), return the part before the colon.
-    if let Ok(host) = host {
-        if let Some(index) = host.find(':') {
-            return Ok(&host[..index]);
+/// Get the IP address of the client (if the client connected over an inet socket).
+/// The client's address as exposed beyond the proxy: in the access log, request summaries, and to
+/// the route's scripts and WebAssembly filters (anonymized if the route asks for it).
+fn exposed_client_ip(session: &Session, ctx: &RequestContext) -> Option<String> {
+    let ip = get_client_ip(session)?;
+    let privacy = ctx
+        .route
+        .as_ref()
+        .map_or(ClientIpPrivacy::Full, |r| r.config.client_ip_privacy);
+    Some(privacy.anonymize(ip))
+}
+
+/// Replace any geo headers in the request with the client's location, or just remove them (so
+/// clients can't forge them) if it isn't known or isn't sent to the origin.
+fn insert_geo_headers(req: &mut RequestHeader, location: Option<&GeoLocation>) -> Result<()> {
+    let unknown = GeoLocation::default();
+    let location = location.unwrap_or(&unknown);
+    for (name, value) in [
+        (geoip::COUNTRY_HEADER, location.country.clone()),
+        (geoip::REGION_HEADER, location.region.clone()),
+        (geoip::ASN_HEADER, location.asn.map(|asn| asn.to_string())),
+    ] {
+        req.remove_header(name);
+        if let Some(value) = value {
+            req.insert_header(name, value)?;
         }
     }
+    Ok(())
+}
 
-    host
+fn get_client_ip(session: &Session) -> Option<IpAddr> {
+    session
+        .client_addr()
+        .and_then(|addr| addr.as_inet())
+        .map(|addr| addr.ip())
 }
 
-/// Infer the scheme of the incoming request based on the server port (because Pingora doesn't
-/// directly provide the scheme).
-pub fn get_incoming_scheme(session: &Session, https_ports: &[u16]) -> Result<IncomingScheme> {
-    let server_port = session
+/// Get the IP address of the client the request came from: for requests forwarded by a cluster
+/// peer, the one the peer received it from.
+fn get_original_client_ip(session: &Session, from_peer: bool) -> Option<IpAddr> {
+    if !from_peer {
+        return get_client_ip(session);
+    }
+    session
+        .get_header(cluster::CLIENT_HEADER)?
+        .to_str()
+        .ok()?
+        .parse()
+        .ok()
+}
+
+/// Get the port the request was received on.
+fn get_server_port(session: &Session) -> Result<u16> {
+    Ok(session
         .server_addr()
         .ok_or_else(|| Error::explain(HTTPStatus(500), "No server address"))?
         .as_inet()
         .ok_or_else(|| Error::explain(HTTPStatus(500), "Not an inet socket"))?
-        .port();
+        .port())
+}
 
+/// Infer the scheme of the incoming request based on the server port (because Pingora doesn't
+/// directly provide the scheme).
+pub fn get_incoming_scheme(session: &Session, https_ports: &[u16]) -> Result<IncomingScheme> {
+    let server_port = get_server_port(session)?;
     match https_ports.contains(&server_port) {
         true => Ok(IncomingScheme::Https),
         false => Ok(IncomingScheme::Http),
@@ -0,0 +1,275 @@
+//! Request and bandwidth quotas for the proxy as a whole and for individual customers.
+//!
+//! Quotas apply over fixed windows.  Request counts are checked and incremented when a request
+//! arrives; response bytes are added once the response has been sent, so a customer may exceed
+//! the bandwidth quota by at most the size of the in-flight responses before further requests are
+//! rejected.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::{Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+/// Limits that apply within a single quota window.  A missing limit is unlimited.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+#[serde(default)]
+pub struct QuotaLimit {
+    /// The maximum number of requests per window.
+    pub max_requests: Option<u64>,
+
+    /// The maximum number of response body bytes per window.
+    pub max_bytes: Option<u64>,
+}
+
+/// Quota settings.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct QuotaConfig {
+    /// The length (in seconds) of a quota window.
+    pub window: u64,
+
+    /// Limits across all customers.
+    pub global: QuotaLimit,
+
+    /// Limits per customer (keyed by the customer name used in routes).
+    pub customers: HashMap<String, QuotaLimit>,
+}
+
+impl Default for QuotaConfig {
+    /// By default, the window is one hour and there are no limits.
+    fn default() -> Self {
+        QuotaConfig {
+            window: 3600,
+            global: QuotaLimit::default(),
+            customers: HashMap::new(),
+        }
+    }
+}
+
+/// The usage counted within the current window.
+#[derive(Serialize, Debug, Clone, PartialEq, Eq)]
+pub struct QuotaUsage {
+    pub requests: u64,
+    pub bytes: u64,
+    /// Seconds until the current window ends and the counters are reset.
+    pub resets_in: u64,
+}
+
+/// A snapshot of all quota counters.
+#[derive(Serialize, Debug, Clone)]
+pub struct QuotaStats {
+    pub global: QuotaUsage,
+    pub customers: HashMap<String, QuotaUsage>,
+}
+
+#[derive(Debug)]
+struct Counter {
+    window_start: Instant,
+    requests: u64,
+    bytes: u64,
+}
+
+impl Counter {
+    fn new(now: Instant) -> Self {
+        Counter {
+            window_start: now,
+            requests: 0,
+            bytes: 0,
+        }
+    }
+
+    /// Start a new window if the current one has ended.
+    fn roll(&mut self, now: Instant, window: Duration) {
+        if now.duration_since(self.window_start) >= window {
+            *self = Counter::new(now);
+        }
+    }
+
+    fn resets_in(&self, now: Instant, window: Duration) -> u64 {
+        window
+            .saturating_sub(now.duration_since(self.window_start))
+            .as_secs()
+            .max(1)
+    }
+
+    fn exceeds(&self, limit: &QuotaLimit) -> bool {
+        limit.max_requests.is_some_and(|max| self.requests >= max)
+            || limit.max_bytes.is_some_and(|max| self.bytes >= max)
+    }
+
+    fn usage(&self, now: Instant, window: Duration) -> QuotaUsage {
+        QuotaUsage {
+            requests: self.requests,
+            bytes: self.bytes,
+            resets_in: self.resets_in(now, window),
+        }
+    }
+}
+
+struct Counters {
+    global: Counter,
+    customers: HashMap<String, Counter>,
+}
+
+/// Enforces quotas and keeps the usage counters.
+pub struct QuotaTracker {
+    config: QuotaConfig,
+    /// Limits set by customer resources (replacing those in the config).
+    customer_limits: RwLock<HashMap<String, QuotaLimit>>,
+    counters: Mutex<Counters>,
+}
+
+impl QuotaTracker {
+    pub fn new(config: &QuotaConfig) -> Self {
+        QuotaTracker {
+            config: config.clone(),
+            customer_limits: RwLock::new(HashMap::new()),
+            counters: Mutex::new(Counters {
+                global: Counter::new(Instant::now()),
+                customers: HashMap::new(),
+            }),
+        }
+    }
+
+    fn window(&self) -> Duration {
+        Duration::from_secs(self.config.window)
+    }
+
+    /// Set (or, with `None`, clear) the limit of a customer resource, replacing the customer's
+    /// limit in the config.
+    pub fn set_customer_limit(&self, customer: &str, limit: Option<QuotaLimit>) {
+        let mut limits = self.customer_limits.write().unwrap();
+        match limit {
+            Some(limit) => limits.insert(customer.to_string(), limit),
+            None => limits.remove(customer),
+        };
+    }
+
+    /// Count a new request for the customer.  If the global or customer quota is already used up,
+    /// the request isn't counted and the number of seconds until the quota resets is returned as an
+    /// error.
+    pub fn start_request(&self, customer: &str) -> Result<(), u64> {
+        let now = Instant::now();
+        let window = self.window();
+        let mut counters = self.counters.lock().unwrap();
+
+        counters.global.roll(now, window);
+        if counters.global.exceeds(&self.config.global) {
+            return Err(counters.global.resets_in(now, window));
+        }
+
+        let customer_counter = counters
+            .customers
+            .entry(customer.to_string())
+            .or_insert_with(|| Counter::new(now));
+        customer_counter.roll(now, window);
+        let limits = self.customer_limits.read().unwrap();
+        if let Some(limit) = limits
+            .get(customer)
+            .or_else(|| self.config.customers.get(customer))
+        {
+            if customer_counter.exceeds(limit) {
+                return Err(customer_counter.resets_in(now, window));
+            }
+        }
+
+        customer_counter.requests += 1;
+        counters.global.requests += 1;
+        Ok(())
+    }
+
+    /// Add the bytes sent in a response to the customer's usage.
+    pub fn add_bytes(&self, customer: &str, bytes: u64) {
+        let now = Instant::now();
+        let window = self.window();
+        let mut counters = self.counters.lock().unwrap();
+
+        counters.global.roll(now, window);
+        counters.global.bytes += bytes;
+
+        let customer_counter = counters
+            .customers
+            .entry(customer.to_string())
+            .or_insert_with(|| Counter::new(now));
+        customer_counter.roll(now, window);
+        customer_counter.bytes += bytes;
+    }
+
+    /// A snapshot of the usage in the current windows.
+    pub fn stats(&self) -> QuotaStats {
+        let now = Instant::now();
+        let window = self.window();
+        let mut counters = self.counters.lock().unwrap();
+
+        counters.global.roll(now, window);
+        for counter in counters.customers.values_mut() {
+            counter.roll(now, window);
+        }
+        QuotaStats {
+            global: counters.global.usage(now, window),
+            customers: counters
+                .customers
+                .iter()
+                .map(|(customer, counter)| (customer.clone(), counter.usage(now, window)))
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn customer_quota() {
+        let config = QuotaConfig {
+            customers: HashMap::from([(
+                "c1".to_string(),
+                QuotaLimit {
+                    max_requests: Some(2),
+                    max_bytes: Some(1000),
+                },
+            )]),
+            ..Default::default()
+        };
+        let tracker = QuotaTracker::new(&config);
+
+        assert!(tracker.start_request("c1").is_ok());
+        assert!(tracker.start_request("c1").is_ok());
+        assert!(tracker.start_request("c1").is_err());
+        // Other customers are unaffected.
+        assert!(tracker.start_request("c2").is_ok());
+
+        // A customer resource's limit replaces the configured one.
+        tracker.set_customer_limit(
+            "c1",
+            Some(QuotaLimit {
+                max_requests: Some(3),
+                max_bytes: None,
+            }),
+        );
+        assert!(tracker.start_request("c1").is_ok());
+        assert!(tracker.start_request("c1").is_err());
+        tracker.set_customer_limit("c1", None);
+
+        let stats = tracker.stats();
+        assert_eq!(stats.global.requests, 4);
+        assert_eq!(stats.customers["c1"].requests, 3);
+    }
+
+    #[test]
+    fn bandwidth_quota() {
+        let config = QuotaConfig {
+            global: QuotaLimit {
+                max_requests: None,
+                max_bytes: Some(100),
+            },
+            ..Default::default()
+        };
+        let tracker = QuotaTracker::new(&config);
+
+        assert!(tracker.start_request("c1").is_ok());
+        tracker.add_bytes("c1", 100);
+        assert!(tracker.start_request("c2").is_err());
+    }
+}
@@ -0,0 +1,104 @@
+//! Per-route handling of range requests that are forwarded to the origin.
+//!
+//! Routes that don't cache forward the client's `Range` (and `If-Range`) headers unmodified and
+//! stream the origin's `206 Partial Content` responses as they arrive.  On caching routes, the
+//! cache fetches whole objects from the origin and serves ranges from them, which doesn't suit
+//! large objects (e.g., video) or objects the origin marks uncacheable (whose ranges would be
+//! answered with the whole object): a route can let range requests bypass the cache instead.
+//!
+//! Open-ended ranges (`bytes=1000-`) ask the origin for everything to the end of the object, so a
+//! route can reject them (with a `416 Range Not Satisfiable`) to protect its origins.
+
+use http::StatusCode;
+use pingora::http::{RequestHeader, ResponseHeader};
+use pingora::Result;
+use serde::{Deserialize, Serialize};
+
+/// A route's range policy.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct RangePolicy {
+    /// Whether range requests bypass the cache (on caching routes) and are forwarded as is.
+    pub bypass_cache: bool,
+
+    /// Whether to reject open-ended ranges in requests forwarded to the origin.
+    pub reject_open_ended: bool,
+}
+
+impl Default for RangePolicy {
+    /// By default, range requests go through the cache (on caching routes), and open-ended ranges
+    /// aren't forwarded to the origin.
+    fn default() -> Self {
+        RangePolicy {
+            bypass_cache: false,
+            reject_open_ended: true,
+        }
+    }
+}
+
+impl RangePolicy {
+    /// Whether the request bypasses the cache.
+    pub fn bypasses_cache(&self, req: &RequestHeader) -> bool {
+        self.bypass_cache && req.headers.contains_key(http::header::RANGE)
+    }
+
+    /// Whether to reject the request.  `cached` is whether the route caches.
+    pub fn rejects(&self, req: &RequestHeader, cached: bool) -> bool {
+        if !self.reject_open_ended || (cached && !self.bypasses_cache(req)) {
+            return false;
+        }
+        req.headers
+            .get_all(http::header::RANGE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .any(is_open_ended)
+    }
+
+    /// The response to a rejected request.
+    pub fn reject_response(&self) -> Result<ResponseHeader> {
+        let mut resp = ResponseHeader::build(StatusCode::RANGE_NOT_SATISFIABLE, Some(1))?;
+        resp.insert_header(http::header::CONTENT_LENGTH, 0)?;
+        Ok(resp)
+    }
+}
+
+/// Whether a `Range` header value has a byte range without an end (e.g., `bytes=1000-`).  Suffix
+/// ranges (`bytes=-500`, the last 500 bytes) are bounded.
+pub fn is_open_ended(range: &str) -> bool {
+    let Some(specs) = range.trim().strip_prefix("bytes=") else {
+        return false;
+    };
+    specs.split(',').any(|spec| {
+        spec.trim()
+            .split_once('-')
+            .is_some_and(|(start, end)| !start.trim().is_empty() && end.trim().is_empty())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn open_ended() {
+        assert!(is_open_ended("bytes=1000-"));
+        assert!(is_open_ended("bytes=0-99, 200-"));
+        assert!(!is_open_ended("bytes=0-99"));
+        assert!(!is_open_ended("bytes=-500"));
+        assert!(!is_open_ended("items=1-"));
+
+        let mut req = RequestHeader::build("GET", b"/video", None).unwrap();
+        req.insert_header("range", "bytes=100-").unwrap();
+        let policy = RangePolicy::default();
+        assert!(policy.rejects(&req, false));
+        assert!(!policy.rejects(&req, true));
+        assert!(!policy.bypasses_cache(&req));
+
+        let policy = RangePolicy {
+            bypass_cache: true,
+            ..Default::default()
+        };
+        assert!(policy.bypasses_cache(&req));
+        assert!(policy.rejects(&req, true));
+    }
+}
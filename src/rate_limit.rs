@@ -0,0 +1,273 @@
+//! Per-route rate limiting of clients using token buckets.
+//!
+//! Each route with a rate limit policy gets one bucket per client key (the client IP address or
+//! the value of a request header).  A bucket holds up to `requests + burst` tokens and refills at
+//! `requests` tokens per period.  Each request consumes one token; requests arriving at an empty
+//! bucket are rejected with a 429.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// When the number of tracked buckets exceeds this, idle (full) buckets are pruned.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// The minimum time between two prunings (each of which goes through all the buckets).
+const PRUNE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// The period over which the request limit applies.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+pub enum RateLimitPeriod {
+    #[default]
+    Second,
+    Minute,
+}
+
+impl RateLimitPeriod {
+    fn duration(&self) -> Duration {
+        match self {
+            RateLimitPeriod::Second => Duration::from_secs(1),
+            RateLimitPeriod::Minute => Duration::from_secs(60),
+        }
+    }
+}
+
+/// What identifies a client for rate limiting purposes.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+pub enum RateLimitKey {
+    /// The client's IP address.
+    #[default]
+    ClientIp,
+
+    /// The value of the given request header (e.g., an API key).  Requests without the header
+    /// fall back to the client's IP address.
+    Header(String),
+}
+
+/// A rate limit policy for a route.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct RateLimitPolicy {
+    /// The number of requests allowed per period.
+    pub requests: u32,
+
+    /// The period over which `requests` applies.
+    #[serde(default)]
+    pub period: RateLimitPeriod,
+
+    /// The number of requests allowed in excess of `requests` in a short burst.
+    #[serde(default)]
+    pub burst: u32,
+
+    /// What identifies a client.
+    #[serde(default)]
+    pub key: RateLimitKey,
+}
+
+impl RateLimitPolicy {
+    /// The maximum number of tokens in a bucket.
+    fn capacity(&self) -> f64 {
+        f64::from(self.requests) + f64::from(self.burst)
+    }
+
+    /// The number of tokens added to a bucket per second.
+    fn refill_rate(&self) -> f64 {
+        f64::from(self.requests) / self.period.duration().as_secs_f64()
+    }
+}
+
+/// The outcome of a rate limit check.
+#[derive(Debug, PartialEq, Clone)]
+pub enum RateLimitDecision {
+    /// The request is allowed.  `remaining` requests may be made immediately.
+    Allowed { limit: u32, remaining: u32 },
+
+    /// The request is rejected.  The client should retry after the given number of seconds.
+    Limited { limit: u32, retry_after: u64 },
+}
+
+/// A client's bucket, with the refill parameters of its route's policy (so it can be pruned
+/// without the policy at hand).
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    last_refill: Instant,
+    capacity: f64,
+    refill_rate: f64,
+}
+
+impl Bucket {
+    /// Whether the bucket would have refilled completely by now, in which case it carries no state
+    /// worth keeping.
+    fn is_full(&self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last_refill).as_secs_f64();
+        self.tokens + elapsed * self.refill_rate >= self.capacity
+    }
+}
+
+#[derive(Debug)]
+struct Buckets {
+    // Key: (route name, client key).
+    by_key: HashMap<(String, String), Bucket>,
+    last_prune: Instant,
+}
+
+/// Tracks token buckets for all rate-limited routes and clients.
+pub struct RateLimiter {
+    buckets: Mutex<Buckets>,
+}
+
+impl RateLimiter {
+    pub fn new() -> Self {
+        RateLimiter {
+            buckets: Mutex::new(Buckets {
+                by_key: HashMap::new(),
+                last_prune: Instant::now(),
+            }),
+        }
+    }
+
+    /// Consume a token for the given route and client (if one is available).
+    pub fn check(&self, route: &str, client: &str, policy: &RateLimitPolicy) -> RateLimitDecision {
+        self.check_at(route, client, policy, Instant::now())
+    }
+
+    fn check_at(
+        &self,
+        route: &str,
+        client: &str,
+        policy: &RateLimitPolicy,
+        now: Instant,
+    ) -> RateLimitDecision {
+        let capacity = policy.capacity();
+        let refill_rate = policy.refill_rate();
+
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.by_key.len() > PRUNE_THRESHOLD
+            && now.saturating_duration_since(buckets.last_prune) >= PRUNE_INTERVAL
+        {
+            buckets.by_key.retain(|_, b| !b.is_full(now));
+            buckets.last_prune = now;
+        }
+
+        let bucket = buckets
+            .by_key
+            .entry((route.to_string(), client.to_string()))
+            .or_insert_with(|| Bucket {
+                tokens: capacity,
+                last_refill: now,
+                capacity,
+                refill_rate,
+            });
+
+        let elapsed = now.duration_since(bucket.last_refill).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * refill_rate).min(capacity);
+        bucket.last_refill = now;
+        // The route's policy may have changed since the bucket was created.
+        bucket.capacity = capacity;
+        bucket.refill_rate = refill_rate;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            RateLimitDecision::Allowed {
+                limit: policy.requests,
+                remaining: bucket.tokens as u32,
+            }
+        } else {
+            let wait = if refill_rate > 0.0 {
+                ((1.0 - bucket.tokens) / refill_rate).ceil() as u64
+            } else {
+                policy.period.duration().as_secs()
+            };
+            RateLimitDecision::Limited {
+                limit: policy.requests,
+                retry_after: wait.max(1),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket() {
+        let limiter = RateLimiter::new();
+        let policy = RateLimitPolicy {
+            requests: 2,
+            period: RateLimitPeriod::Second,
+            burst: 1,
+            key: RateLimitKey::ClientIp,
+        };
+        let start = Instant::now();
+
+        // The bucket starts full (requests + burst).
+        for remaining in [2, 1, 0] {
+            assert_eq!(
+                limiter.check_at("r", "1.2.3.4", &policy, start),
+                RateLimitDecision::Allowed {
+                    limit: 2,
+                    remaining
+                }
+            );
+        }
+        assert_eq!(
+            limiter.check_at("r", "1.2.3.4", &policy, start),
+            RateLimitDecision::Limited {
+                limit: 2,
+                retry_after: 1
+            }
+        );
+
+        // Other clients have their own buckets.
+        assert!(matches!(
+            limiter.check_at("r", "5.6.7.8", &policy, start),
+            RateLimitDecision::Allowed { .. }
+        ));
+
+        // Half a second later, one token has been refilled.
+        let later = start + Duration::from_millis(500);
+        assert!(matches!(
+            limiter.check_at("r", "1.2.3.4", &policy, later),
+            RateLimitDecision::Allowed { .. }
+        ));
+        assert!(matches!(
+            limiter.check_at("r", "1.2.3.4", &policy, later),
+            RateLimitDecision::Limited { .. }
+        ));
+    }
+    #[test]
+    fn prune() {
+        let limiter = RateLimiter::new();
+        let fast = RateLimitPolicy {
+            requests: 10,
+            period: RateLimitPeriod::Second,
+            burst: 0,
+            key: RateLimitKey::ClientIp,
+        };
+        let slow = RateLimitPolicy {
+            requests: 1,
+            period: RateLimitPeriod::Minute,
+            ..fast.clone()
+        };
+        let start = Instant::now();
+        limiter.check_at("slow", "1.2.3.4", &slow, start);
+        for i in 0..PRUNE_THRESHOLD {
+            limiter.check_at("fast", &i.to_string(), &fast, start);
+        }
+
+        // Each bucket is judged by its own route's policy: the fast route's buckets have refilled,
+        // but not the slow route's.
+        let later = start + PRUNE_INTERVAL;
+        assert!(matches!(
+            limiter.check_at("fast", "new", &fast, later),
+            RateLimitDecision::Allowed { .. }
+        ));
+        assert_eq!(limiter.buckets.lock().unwrap().by_key.len(), 2);
+        assert!(matches!(
+            limiter.check_at("slow", "1.2.3.4", &slow, later),
+            RateLimitDecision::Limited { .. }
+        ));
+    }
+}
@@ -0,0 +1,77 @@
+//! Redaction of sensitive header values (credentials, session cookies, etc.) from logs and the
+//! request tap.  Redacted headers are still listed (with a placeholder value) so that their
+//! presence can be seen while debugging.
+
+use http::HeaderMap;
+use std::collections::{BTreeMap, HashSet};
+
+/// Headers that are always redacted.
+const ALWAYS_REDACTED: [&str; 4] = [
+    "authorization",
+    "proxy-authorization",
+    "cookie",
+    "set-cookie",
+];
+
+/// The value shown in place of a redacted header's value.
+const REDACTED: &str = "[redacted]";
+
+/// Renders headers for logs and debug output with sensitive values redacted.
+#[derive(Debug)]
+pub struct HeaderRedactor {
+    /// Lowercase names of the headers to redact.
+    names: HashSet<String>,
+}
+
+impl HeaderRedactor {
+    /// Redact the built-in sensitive headers and the given additional headers.
+    pub fn new(extra_headers: &[String]) -> Self {
+        let names = ALWAYS_REDACTED
+            .iter()
+            .map(|name| name.to_string())
+            .chain(extra_headers.iter().map(|name| name.to_lowercase()))
+            .collect();
+        HeaderRedactor { names }
+    }
+
+    /// The headers as a name-to-value map.  Repeated headers are joined with commas, and
+    /// non-ASCII values are replaced by a placeholder.
+    pub fn redact(&self, headers: &HeaderMap) -> BTreeMap<String, String> {
+        let mut redacted: BTreeMap<String, String> = BTreeMap::new();
+        for (name, value) in headers {
+            let value = if self.names.contains(name.as_str()) {
+                REDACTED
+            } else {
+                value.to_str().unwrap_or("[non-ascii]")
+            };
+            redacted
+                .entry(name.to_string())
+                .and_modify(|v| {
+                    v.push_str(", ");
+                    v.push_str(value);
+                })
+                .or_insert_with(|| value.to_string());
+        }
+        redacted
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn redact() {
+        let redactor = HeaderRedactor::new(&["X-Api-Key".to_string()]);
+        let mut headers = HeaderMap::new();
+        headers.insert("authorization", "Bearer secret".parse().unwrap());
+        headers.insert("x-api-key", "secret".parse().unwrap());
+        headers.append("accept", "text/html".parse().unwrap());
+        headers.append("accept", "*/*".parse().unwrap());
+
+        let redacted = redactor.redact(&headers);
+        assert_eq!(redacted["authorization"], "[redacted]");
+        assert_eq!(redacted["x-api-key"], "[redacted]");
+        assert_eq!(redacted["accept"], "text/html, */*");
+    }
+}
@@ -0,0 +1,275 @@
+//! Following of redirects from origins.  Legacy origins sometimes redirect internally (e.g., from
+//! an old path to a new one, or to a canonical hostname); a route can have the proxy follow these
+//! redirects itself, so clients get the final response directly and it's cached under the URL
+//! they asked for.
+//!
+//! Only redirects that stay within the route are followed: relative ones, and absolute ones to one
+//! of the route's hosts or origins.  The followed request goes to the origin that redirected (or to
+//! the origin the redirect names), keeping the route's outgoing scheme.  Other redirects, and
+//! redirects past the limit, are passed on to the client.  Only GET and HEAD requests follow
+//! redirects, since other requests can't be sent again safely.
+
+use http::{Method, StatusCode, Uri};
+use pingora::http::ResponseHeader;
+use serde::{Deserialize, Serialize};
+
+use crate::route_config::RouteConfig;
+
+/// A route's redirect following.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct RedirectPolicy {
+    /// The maximum number of redirects followed for a request.
+    pub max_redirects: u8,
+}
+
+impl Default for RedirectPolicy {
+    /// By default, up to 3 redirects are followed.
+    fn default() -> Self {
+        RedirectPolicy { max_redirects: 3 }
+    }
+}
+
+/// A redirect to follow.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Redirect {
+    /// The path and query to request.
+    pub uri: Uri,
+
+    /// The host header to send (if the redirect is to another of the route's hosts).
+    pub host: Option<String>,
+
+    /// The origin to send the request to (by index within the origin group), if the redirect
+    /// names one.  Otherwise, it goes to the origin that redirected.
+    pub origin_index: Option<usize>,
+}
+
+impl RedirectPolicy {
+    /// The redirect to follow, if the response is one the policy follows.  `followed` is the
+    /// number of redirects already followed for the request, `host` and `path` the host header
+    /// and the path (and query) of the request that got the response.
+    pub fn follow(
+        &self,
+        route: &RouteConfig,
+        method: &Method,
+        followed: u8,
+        host: &str,
+        path: &str,
+        resp: &ResponseHeader,
+    ) -> Option<Redirect> {
+        if followed >= self.max_redirects || (method != Method::GET && method != Method::HEAD) {
+            return None;
+        }
+        if !matches!(
+            resp.status,
+            StatusCode::MOVED_PERMANENTLY
+                | StatusCode::FOUND
+                | StatusCode::SEE_OTHER
+                | StatusCode::TEMPORARY_REDIRECT
+                | StatusCode::PERMANENT_REDIRECT
+        ) {
+            return None;
+        }
+        let location = resp.headers.get(http::header::LOCATION)?.to_str().ok()?;
+        resolve(route, host, path, location)
+    }
+}
+
+/// Resolve a `Location` against the request, if it stays within the route.
+fn resolve(route: &RouteConfig, host: &str, path: &str, location: &str) -> Option<Redirect> {
+    if location.starts_with("//") {
+        return resolve(route, host, path, &format!("http:{location}"));
+    }
+    if location.starts_with('/') {
+        return Some(Redirect {
+            uri: location.parse().ok()?,
+            host: None,
+            origin_index: None,
+        });
+    }
+    if !location.contains("://") {
+        // A path relative to the request's directory.
+        let path = path.split('?').next().unwrap_or_default();
+        let dir = &path[..path.rfind('/').map_or(0, |i| i + 1)];
+        return Some(Redirect {
+            uri: format!("/{}{location}", dir.trim_start_matches('/'))
+                .parse()
+                .ok()?,
+            host: None,
+            origin_index: None,
+        });
+    }
+
+    let target: Uri = location.parse().ok()?;
+    if !matches!(target.scheme_str(), Some("http" | "https")) {
+        return None;
+    }
+    let target_host = target.host()?.to_ascii_lowercase();
+    let uri = target
+        .path_and_query()
+        .map_or("/", |p| p.as_str())
+        .parse()
+        .ok()?;
+    if let Some(origin_index) = route
+        .origin_group
+        .origins
+        .iter()
+        .position(|origin| origin.host.eq_ignore_ascii_case(&target_host))
+    {
+        return Some(Redirect {
+            uri,
+            host: None,
+            origin_index: Some(origin_index),
+        });
+    }
+    // The location's port is kept in the host header if the route preserves ports.
+    let target_port = target.port_u16().filter(|_| route.preserve_host_port);
+    let with_port = |host: &str| match target_port {
+        Some(port) => format!("{host}:{port}"),
+        None => host.to_string(),
+    };
+    let host = host.split(':').next().unwrap_or_default();
+    if target_host.eq_ignore_ascii_case(host) {
+        return Some(Redirect {
+            uri,
+            host: target_port.map(|_| with_port(&target_host)),
+            origin_index: None,
+        });
+    }
+    if route
+        .hosts
+        .iter()
+        .any(|route_host| host_matches(route_host, &target_host))
+    {
+        return Some(Redirect {
+            uri,
+            host: Some(with_port(&target_host)),
+            origin_index: None,
+        });
+    }
+    None
+}
+
+/// Whether a host matches a route host (which may be a wildcard for a single label).
+fn host_matches(route_host: &str, host: &str) -> bool {
+    let route_host = route_host.to_ascii_lowercase();
+    match route_host.strip_prefix("*.") {
+        Some(domain) => host.split_once('.').is_some_and(|(_, rest)| rest == domain),
+        None => host == route_host,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn route() -> RouteConfig {
+        serde_json::from_value(serde_json::json!({
+            "name": "r1",
+            "customer": "c1",
+            "hosts": ["www.example.com", "*.example.net"],
+            "paths": ["/"],
+            "incoming_schemes": ["Https"],
+            "outgoing_scheme": "Https",
+            "origin_group": {"origins": [{"host": "a.origin.com"}, {"host": "b.origin.com"}]},
+        }))
+        .unwrap()
+    }
+
+    fn redirect(status: u16, location: &str) -> ResponseHeader {
+        let mut resp = ResponseHeader::build(status, None).unwrap();
+        resp.insert_header(http::header::LOCATION, location)
+            .unwrap();
+        resp
+    }
+
+    #[test]
+    fn follow() {
+        let policy = RedirectPolicy::default();
+        let route = route();
+        let follow = |method: &Method, followed, resp: &ResponseHeader| {
+            policy.follow(
+                &route,
+                method,
+                followed,
+                "www.example.com",
+                "/a/b?x=1",
+                resp,
+            )
+        };
+        let target = |uri: &str, host: Option<&str>, origin_index| Redirect {
+            uri: uri.parse().unwrap(),
+            host: host.map(str::to_string),
+            origin_index,
+        };
+
+        assert_eq!(
+            follow(&Method::GET, 0, &redirect(301, "/new?y=2")),
+            Some(target("/new?y=2", None, None))
+        );
+        assert_eq!(
+            follow(&Method::GET, 0, &redirect(302, "c")),
+            Some(target("/a/c", None, None))
+        );
+        assert_eq!(
+            follow(
+                &Method::HEAD,
+                2,
+                &redirect(307, "https://www.example.com/d")
+            ),
+            Some(target("/d", None, None))
+        );
+        assert_eq!(
+            follow(
+                &Method::GET,
+                0,
+                &redirect(308, "http://b.origin.com:8080/e")
+            ),
+            Some(target("/e", None, Some(1)))
+        );
+        assert_eq!(
+            follow(&Method::GET, 0, &redirect(302, "//cdn.example.net")),
+            Some(target("/", Some("cdn.example.net"), None))
+        );
+        assert_eq!(
+            follow(
+                &Method::GET,
+                0,
+                &redirect(302, "http://www.example.com:8080/f")
+            ),
+            Some(target("/f", None, None))
+        );
+
+        // Redirects out of the route, past the limit, of other methods, or without a location.
+        assert_eq!(
+            follow(&Method::GET, 0, &redirect(302, "https://other.com/")),
+            None
+        );
+        assert_eq!(
+            follow(&Method::GET, 0, &redirect(302, "https://a.b.example.net/")),
+            None
+        );
+        assert_eq!(follow(&Method::GET, 3, &redirect(302, "/new")), None);
+        assert_eq!(follow(&Method::POST, 0, &redirect(302, "/new")), None);
+        assert_eq!(follow(&Method::GET, 0, &redirect(200, "/new")), None);
+        assert_eq!(
+            follow(&Method::GET, 0, &ResponseHeader::build(302, None).unwrap()),
+            None
+        );
+
+        // The location's port is kept if the route preserves ports.
+        let mut route = route;
+        route.preserve_host_port = true;
+        assert_eq!(
+            policy.follow(
+                &route,
+                &Method::GET,
+                0,
+                "www.example.com:8443",
+                "/",
+                &redirect(302, "https://www.example.com:8443/g")
+            ),
+            Some(target("/g", Some("www.example.com:8443"), None))
+        );
+    }
+}
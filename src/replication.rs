@@ -0,0 +1,511 @@
+//! Configuration replication among a set of instances.
+//!
+//! One instance is the leader: the control plane pushes configuration changes (customers, routes,
+//! certificates, credential lists, deny list entries, and WebAssembly filters) to its config API
+//! as usual.  Every other instance is a follower: it periodically fetches a snapshot of the
+//! leader's configuration (`GET /replication/snapshot`) and reconciles its own configuration with
+//! it, adding and replacing what changed and deleting what the leader no longer has.  The config
+//! API of a follower rejects configuration changes, so the instances can't diverge.
+//!
+//! Snapshots are versioned, and a follower sends the version it last applied in `If-None-Match`, so
+//! polling an unchanged leader is cheap.
+
+use async_trait::async_trait;
+use bytes::BytesMut;
+use http::{StatusCode, Uri};
+use log::{debug, error, info, warn};
+use pingora::connectors::http::Connector;
+use pingora::http::RequestHeader;
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use pingora::tls::pkey::PKey;
+use pingora::tls::x509::X509;
+use pingora::upstreams::peer::HttpPeer;
+use pingora::utils::CertKey;
+use pingora::{Error, ErrorType::HTTPStatus, OrErr, Result};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tokio::net::lookup_host;
+
+use crate::basic_auth::CredentialList;
+use crate::cert::cert_config::CertBinding;
+use crate::config_api::ConfigApi;
+use crate::customer::CustomerConfig;
+use crate::route_config::RouteConfig;
+use crate::schema;
+use crate::wasm::WasmModule;
+
+/// The config API path serving the configuration snapshot.
+pub const SNAPSHOT_PATH: &str = "/replication/snapshot";
+
+/// The largest snapshot a follower accepts.
+const MAX_SNAPSHOT_SIZE: usize = 256 * 1024 * 1024;
+
+/// Replication settings.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct ReplicationConfig {
+    /// The base URL of the leader's config API (e.g., `https://10.0.0.1:5000`).  If set, this
+    /// instance is a follower.  Otherwise, it accepts configuration changes itself.
+    pub leader: Option<String>,
+
+    /// How often (in seconds) a follower polls the leader.
+    pub poll_interval: u64,
+
+    /// How long (in milliseconds) a follower waits for the leader to connect and respond.
+    pub timeout: u64,
+
+    /// If the leader's config API requires mutual TLS, the path to the client certificate file.
+    pub client_cert: Option<String>,
+
+    /// If the leader's config API requires mutual TLS, the path to the client private key file.
+    pub client_key: Option<String>,
+
+    /// The path to a CA certificate file for verifying the leader's certificate (instead of the
+    /// system's root certificates).
+    pub ca: Option<String>,
+}
+
+impl Default for ReplicationConfig {
+    /// By default, there is no leader, and followers poll every 5 seconds.
+    fn default() -> Self {
+        ReplicationConfig {
+            leader: None,
+            poll_interval: 5,
+            timeout: 5000,
+            client_cert: None,
+            client_key: None,
+            ca: None,
+        }
+    }
+}
+
+/// The kinds of replicated configuration items.  Snapshots list items in this order, so customers
+/// are applied before their routes.
+#[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy)]
+pub enum ItemKind {
+    Customer,
+    Route,
+    Cert,
+    Credentials,
+    Block,
+    Wasm,
+}
+
+/// A replicated configuration item, as pushed through the config API.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(tag = "kind", content = "item", rename_all = "snake_case")]
+pub enum ConfigItem {
+    Customer(Box<CustomerConfig>),
+    #[serde(deserialize_with = "schema::deserialize_route")]
+    Route(Box<RouteConfig>),
+    #[serde(deserialize_with = "schema::deserialize_cert_binding")]
+    Cert(CertBinding),
+    Credentials(CredentialList),
+    /// A blocked IP address or CIDR block.
+    Block(String),
+    Wasm(WasmModule),
+}
+
+impl ItemKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ItemKind::Customer => "customer",
+            ItemKind::Route => "route",
+            ItemKind::Cert => "cert",
+            ItemKind::Credentials => "credentials",
+            ItemKind::Block => "block",
+            ItemKind::Wasm => "wasm",
+        }
+    }
+}
+
+impl ConfigItem {
+    /// The kind of the item and its identifier (unique among items of the same kind).
+    pub fn key(&self) -> (ItemKind, &str) {
+        match self {
+            ConfigItem::Customer(customer) => (ItemKind::Customer, &customer.name),
+            ConfigItem::Route(route) => (ItemKind::Route, &route.name),
+            ConfigItem::Cert(binding) => (ItemKind::Cert, &binding.host),
+            ConfigItem::Credentials(list) => (ItemKind::Credentials, &list.name),
+            ConfigItem::Block(net) => (ItemKind::Block, net),
+            ConfigItem::Wasm(module) => (ItemKind::Wasm, &module.name),
+        }
+    }
+
+    /// When the item expires (for routes and certificate bindings with a TTL).
+    pub fn expires_at(&self) -> Option<u64> {
+        match self {
+            ConfigItem::Route(route) => route.expires_at,
+            ConfigItem::Cert(binding) => binding.expires_at,
+            _ => None,
+        }
+    }
+
+    /// Replace the item's TTL (if any) with the time it expires, so it expires at the same time on
+    /// every instance it's replicated to.
+    pub fn resolve_ttl(&mut self, now: u64) {
+        let (ttl, expires_at) = match self {
+            ConfigItem::Route(route) => (&mut route.ttl, &mut route.expires_at),
+            ConfigItem::Cert(binding) => (&mut binding.ttl, &mut binding.expires_at),
+            _ => return,
+        };
+        if let Some(ttl) = ttl.take() {
+            *expires_at = Some(now.saturating_add(ttl));
+        }
+    }
+}
+
+/// A versioned copy of the whole replicated configuration.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct Snapshot {
+    pub version: String,
+    pub items: Vec<ConfigItem>,
+}
+
+/// Keeps a copy of the replicated configuration (as applied on this instance) and its version.
+pub struct Replicator {
+    leader: Option<String>,
+    /// Distinguishes the versions of this process from those of earlier ones.
+    epoch: u64,
+    inner: RwLock<InnerRecord>,
+}
+
+#[derive(Default)]
+struct InnerRecord {
+    version: u64,
+    items: BTreeMap<(ItemKind, String), ConfigItem>,
+}
+
+impl Replicator {
+    pub fn new(config: &ReplicationConfig) -> Self {
+        let epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |d| d.as_millis() as u64);
+        Replicator {
+            leader: config.leader.clone(),
+            epoch,
+            inner: RwLock::new(InnerRecord::default()),
+        }
+    }
+
+    /// The leader this instance follows (if it is a follower).
+    pub fn leader(&self) -> Option<&str> {
+        self.leader.as_deref()
+    }
+
+    /// Record an item that was added or replaced.
+    pub fn record(&self, item: ConfigItem) {
+        let (kind, id) = item.key();
+        let key = (kind, id.to_string());
+        let mut inner = self.inner.write().unwrap();
+        inner.items.insert(key, item);
+        inner.version += 1;
+    }
+
+    /// Record that an item was deleted.
+    pub fn forget(&self, kind: ItemKind, id: &str) {
+        let mut inner = self.inner.write().unwrap();
+        if inner.items.remove(&(kind, id.to_string())).is_some() {
+            inner.version += 1;
+        }
+    }
+
+    /// The number of items of the kind.
+    pub fn count(&self, kind: ItemKind) -> usize {
+        let inner = self.inner.read().unwrap();
+        inner.items.keys().filter(|(k, _)| *k == kind).count()
+    }
+
+    /// The current version, as sent in the `ETag` header.
+    pub fn version(&self) -> String {
+        let inner = self.inner.read().unwrap();
+        format!("\"{}-{}\"", self.epoch, inner.version)
+    }
+
+    pub fn snapshot(&self) -> Snapshot {
+        let inner = self.inner.read().unwrap();
+        Snapshot {
+            version: format!("\"{}-{}\"", self.epoch, inner.version),
+            items: inner.items.values().cloned().collect(),
+        }
+    }
+
+    /// The keys of the items that expired by `now`.
+    pub fn expired(&self, now: u64) -> Vec<(ItemKind, String)> {
+        let inner = self.inner.read().unwrap();
+        inner
+            .items
+            .iter()
+            .filter(|(_, item)| item.expires_at().is_some_and(|at| at <= now))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    /// Compare this instance's configuration with a snapshot, returning the items to add or
+    /// replace and the keys of the items to delete.
+    pub fn diff(&self, snapshot: Snapshot) -> (Vec<ConfigItem>, Vec<(ItemKind, String)>) {
+        let inner = self.inner.read().unwrap();
+        let mut stale: BTreeSet<_> = inner.items.keys().cloned().collect();
+        let mut changed = Vec::new();
+        for item in snapshot.items {
+            let (kind, id) = item.key();
+            let key = (kind, id.to_string());
+            stale.remove(&key);
+            if inner.items.get(&key) != Some(&item) {
+                changed.push(item);
+            }
+        }
+        (changed, stale.into_iter().collect())
+    }
+}
+
+/// Polls the leader and applies its configuration to this instance.
+pub struct Follower {
+    config: ReplicationConfig,
+    leader: Uri,
+    replicator: Arc<Replicator>,
+    config_api: Arc<ConfigApi>,
+    connector: Connector,
+    client_cert_key: Option<Arc<CertKey>>,
+    ca: Option<Arc<Box<[X509]>>>,
+    /// The version of the last snapshot applied.
+    applied: Mutex<Option<String>>,
+}
+
+impl Follower {
+    /// Create a follower of the configured leader.  Return an error if the leader URL or the TLS
+    /// files are invalid.
+    pub fn new(
+        config: &ReplicationConfig,
+        replicator: Arc<Replicator>,
+        config_api: Arc<ConfigApi>,
+    ) -> Result<Self> {
+        let leader = config.leader.as_deref().unwrap_or_default();
+        let leader: Uri = leader
+            .parse()
+            .or_err(HTTPStatus(500), "Invalid leader URL")?;
+        if leader.host().is_none() {
+            return Err(Error::explain(HTTPStatus(500), "Leader URL has no host"));
+        }
+
+        let client_cert_key = match (&config.client_cert, &config.client_key) {
+            (Some(cert), Some(key)) => {
+                let cert = X509::from_pem(&read_file(cert)?)
+                    .or_err(HTTPStatus(500), "Invalid client certificate")?;
+                let key = PKey::private_key_from_pem(&read_file(key)?)
+                    .or_err(HTTPStatus(500), "Invalid client key")?;
+                Some(Arc::new(CertKey::new(vec![cert], key)))
+            }
+            (None, None) => None,
+            _ => {
+                return Err(Error::explain(
+                    HTTPStatus(500),
+                    "Both client_cert and client_key are required for mutual TLS",
+                ))
+            }
+        };
+        let ca = match &config.ca {
+            Some(ca) => {
+                let certs =
+                    X509::stack_from_pem(&read_file(ca)?).or_err(HTTPStatus(500), "Invalid CA")?;
+                Some(Arc::new(certs.into_boxed_slice()))
+            }
+            None => None,
+        };
+
+        Ok(Follower {
+            config: config.clone(),
+            leader,
+            replicator,
+            config_api,
+            connector: Connector::new(None),
+            client_cert_key,
+            ca,
+            applied: Mutex::new(None),
+        })
+    }
+
+    /// Fetch the leader's snapshot and apply it, unless it hasn't changed since the last one
+    /// applied.
+    async fn sync(&self) -> Result<()> {
+        let applied = self.applied.lock().unwrap().clone();
+        let Some(snapshot) = self.fetch(applied.as_deref()).await? else {
+            debug!("Leader configuration unchanged");
+            return Ok(());
+        };
+
+        let version = snapshot.version.clone();
+        let (changed, stale) = self.replicator.diff(snapshot);
+        if !changed.is_empty() || !stale.is_empty() {
+            info!(
+                "Applying leader configuration version {version}: {} added or replaced, {} deleted",
+                changed.len(),
+                stale.len()
+            );
+        }
+        for item in changed {
+            if let Err(e) = self.config_api.apply(item) {
+                warn!("Unable to apply replicated configuration: {e}");
+            }
+        }
+        for (kind, id) in stale {
+            self.config_api.remove(kind, &id);
+        }
+        *self.applied.lock().unwrap() = Some(version);
+        Ok(())
+    }
+
+    /// Fetch the leader's snapshot.  Return `None` if its version is still `applied`.
+    async fn fetch(&self, applied: Option<&str>) -> Result<Option<Snapshot>> {
+        let host = self.leader.host().unwrap_or_default();
+        let use_tls = self.leader.scheme_str() == Some("https");
+        let port = self
+            .leader
+            .port_u16()
+            .unwrap_or(if use_tls { 443 } else { 80 });
+
+        let addr = lookup_host((host, port))
+            .await
+            .or_err(HTTPStatus(502), "Unable to resolve leader host")?
+            .next()
+            .ok_or_else(|| Error::explain(HTTPStatus(502), "No address found for leader host"))?;
+        let timeout = Duration::from_millis(self.config.timeout);
+        let mut peer = HttpPeer::new(addr, use_tls, host.to_string());
+        peer.options.connection_timeout = Some(timeout);
+        peer.options.read_timeout = Some(timeout);
+        peer.options.write_timeout = Some(timeout);
+        peer.client_cert_key = self.client_cert_key.clone();
+        peer.options.ca = self.ca.clone();
+
+        let mut req = RequestHeader::build("GET", SNAPSHOT_PATH.as_bytes(), None)?;
+        req.insert_header(http::header::HOST, host.to_string())?;
+        if let Some(applied) = applied {
+            req.insert_header(http::header::IF_NONE_MATCH, applied.to_string())?;
+        }
+
+        let (mut session, _reused) = self.connector.get_http_session(&peer).await?;
+        session.write_request_header(Box::new(req)).await?;
+        session.finish_request_body().await?;
+        session.read_response_header().await?;
+        let status = session
+            .response_header()
+            .ok_or_else(|| Error::explain(HTTPStatus(502), "No response from leader"))?
+            .status;
+
+        let mut body = BytesMut::new();
+        while let Some(chunk) = session.read_response_body().await? {
+            if body.len() + chunk.len() > MAX_SNAPSHOT_SIZE {
+                return Error::e_explain(HTTPStatus(502), "Leader snapshot is too large");
+            }
+            body.extend_from_slice(&chunk);
+        }
+        self.connector
+            .release_http_session(session, &peer, None)
+            .await;
+
+        match status {
+            StatusCode::NOT_MODIFIED => Ok(None),
+            StatusCode::OK => serde_json::from_slice(&body)
+                .map(Some)
+                .or_err(HTTPStatus(502), "Unable to parse leader snapshot"),
+            status => Error::e_explain(
+                HTTPStatus(502),
+                format!("Leader responded with status {status}"),
+            ),
+        }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for Follower {
+    /// Sync with the leader right away, and then every poll interval until shutdown.
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        info!("Following the configuration of {}", self.leader);
+        let interval = Duration::from_secs(self.config.poll_interval);
+        loop {
+            if let Err(e) = self.sync().await {
+                error!("Unable to sync configuration with {}: {e}", self.leader);
+            }
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => {}
+                _ = shutdown.changed() => break,
+            }
+        }
+    }
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>> {
+    fs::read(path).or_err_with(HTTPStatus(500), || format!("Unable to read {path}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list(name: &str, entries: &[&str]) -> ConfigItem {
+        ConfigItem::Credentials(CredentialList {
+            name: name.to_string(),
+            entries: entries.iter().map(|e| e.to_string()).collect(),
+        })
+    }
+
+    #[test]
+    fn record_and_diff() {
+        let replicator = Replicator::new(&ReplicationConfig::default());
+        let initial = replicator.version();
+        replicator.record(list("a", &["u:{SHA}x"]));
+        replicator.record(list("b", &[]));
+        replicator.record(ConfigItem::Block("192.0.2.0/24".to_string()));
+        assert_ne!(replicator.version(), initial);
+
+        // Deleting an unknown item doesn't change the version.
+        let version = replicator.version();
+        replicator.forget(ItemKind::Route, "a");
+        assert_eq!(replicator.version(), version);
+
+        let snapshot = Snapshot {
+            version: "\"1-1\"".to_string(),
+            items: vec![
+                list("a", &["u:{SHA}x"]),
+                list("b", &["v:{SHA}y"]),
+                list("c", &[]),
+            ],
+        };
+        let (changed, stale) = replicator.diff(snapshot);
+        assert_eq!(changed, vec![list("b", &["v:{SHA}y"]), list("c", &[])]);
+        assert_eq!(stale, vec![(ItemKind::Block, "192.0.2.0/24".to_string())]);
+
+        replicator.forget(ItemKind::Block, "192.0.2.0/24");
+        assert_eq!(replicator.snapshot().items.len(), 2);
+    }
+
+    #[test]
+    fn expiry() {
+        let route = |name: &str, ttl| {
+            ConfigItem::Route(Box::new(RouteConfig {
+                name: name.to_string(),
+                ttl,
+                ..Default::default()
+            }))
+        };
+        let mut preview = route("preview", Some(60));
+        preview.resolve_ttl(1000);
+        assert_eq!(preview.expires_at(), Some(1060));
+        // Replicating the item doesn't push its expiry back.
+        preview.resolve_ttl(2000);
+        assert_eq!(preview.expires_at(), Some(1060));
+
+        let replicator = Replicator::new(&ReplicationConfig::default());
+        replicator.record(preview);
+        replicator.record(route("permanent", None));
+        assert!(replicator.expired(1059).is_empty());
+        assert_eq!(
+            replicator.expired(1060),
+            vec![(ItemKind::Route, "preview".to_string())]
+        );
+    }
+}
@@ -0,0 +1,115 @@
+//! Headers removed from origin responses before they're sent to clients: hop-by-hop headers, which
+//! only apply to the connection to the origin, and the headers a route's policy strips (e.g.,
+//! `Server`, `X-Powered-By`, or internal debugging headers the origin leaks).
+//!
+//! Pingora manages the framing and persistence of the client connection itself, so
+//! `Transfer-Encoding` and `Content-Length` are left to it, as are `Connection` and `Upgrade` on
+//! `101 Switching Protocols` responses (which need them to upgrade the connection).
+
+use http::StatusCode;
+use pingora::http::ResponseHeader;
+use serde::{Deserialize, Serialize};
+
+/// Hop-by-hop headers always removed from origin responses (along with the headers `Connection`
+/// names).
+const HOP_BY_HOP: [&str; 5] = ["keep-alive", "proxy-connection", "te", "trailer", "upgrade"];
+
+/// A route's policy on origin response headers.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+#[serde(default)]
+pub struct ResponseHeaderPolicy {
+    /// The headers to remove (case-insensitive).  A trailing `*` matches any suffix (e.g.,
+    /// `x-internal-*`).
+    pub strip: Vec<String>,
+}
+
+impl ResponseHeaderPolicy {
+    /// Whether the policy strips the header.
+    pub fn strips(&self, name: &str) -> bool {
+        self.strip
+            .iter()
+            .any(|pattern| match pattern.strip_suffix('*') {
+                Some(prefix) => name
+                    .get(..prefix.len())
+                    .is_some_and(|start| start.eq_ignore_ascii_case(prefix)),
+                None => pattern.eq_ignore_ascii_case(name),
+            })
+    }
+
+    /// Remove the headers the policy strips from the response.
+    pub fn apply(&self, resp: &mut ResponseHeader) {
+        let names: Vec<String> = resp
+            .headers
+            .keys()
+            .map(|name| name.as_str())
+            .filter(|name| self.strips(name))
+            .map(str::to_string)
+            .collect();
+        for name in names {
+            resp.remove_header(&name);
+        }
+    }
+}
+
+/// Remove the hop-by-hop headers from an origin response.
+pub fn strip_hop_by_hop(resp: &mut ResponseHeader) {
+    if resp.status == StatusCode::SWITCHING_PROTOCOLS {
+        return;
+    }
+    let mut names: Vec<String> = resp
+        .headers
+        .get_all(http::header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|name| name.trim().to_ascii_lowercase())
+        .filter(|name| {
+            !matches!(
+                name.as_str(),
+                "" | "close" | "transfer-encoding" | "content-length"
+            )
+        })
+        .collect();
+    names.extend(HOP_BY_HOP.iter().map(|name| name.to_string()));
+    for name in names {
+        resp.remove_header(&name);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn strip() {
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        for (name, value) in [
+            ("server", "nginx"),
+            ("x-powered-by", "PHP"),
+            ("x-internal-trace", "abc"),
+            ("x-internal-node", "n1"),
+            ("connection", "keep-alive, x-conn-debug"),
+            ("keep-alive", "timeout=5"),
+            ("x-conn-debug", "1"),
+            ("content-type", "text/plain"),
+        ] {
+            resp.append_header(name, value).unwrap();
+        }
+
+        strip_hop_by_hop(&mut resp);
+        let policy: ResponseHeaderPolicy =
+            serde_json::from_str(r#"{"strip": ["Server", "x-powered-by", "X-Internal-*"]}"#)
+                .unwrap();
+        policy.apply(&mut resp);
+
+        let mut names: Vec<&str> = resp.headers.keys().map(|n| n.as_str()).collect();
+        names.sort();
+        assert_eq!(names, ["connection", "content-type"]);
+
+        // Upgrades keep their hop-by-hop headers.
+        let mut resp = ResponseHeader::build(101, None).unwrap();
+        resp.append_header("upgrade", "websocket").unwrap();
+        strip_hop_by_hop(&mut resp);
+        assert_eq!(resp.headers["upgrade"], "websocket");
+    }
+}
@@ -0,0 +1,157 @@
+//! Retry budgets, to keep retries from amplifying the load on origins during an incident.  A fixed
+//! retry limit multiplies the requests to an origin that's failing (and to the others in its
+//! group); a budget instead caps the retries at a fraction of the requests sent to origins over a
+//! sliding window.  Once the budget is exhausted, failed connections aren't retried: the request
+//! fails fast.
+//!
+//! There's a global budget (for all routes) and routes can have their own.  A retry must fit in
+//! both.  A minimum number of retries is always allowed per window, so low traffic can still be
+//! retried.
+
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use std::time::Instant;
+
+/// A retry budget's settings.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct RetryBudgetConfig {
+    /// The maximum retries, as a percentage of the requests sent to origins.
+    pub max_retry_percent: u32,
+
+    /// The length (in seconds) of the sliding window requests and retries are counted over.
+    pub window: u64,
+
+    /// The retries allowed per window regardless of the percentage.
+    pub min_retries: u64,
+}
+
+impl Default for RetryBudgetConfig {
+    /// By default, retries may not exceed 20% of the requests over 10 seconds (but 10 are allowed).
+    fn default() -> Self {
+        RetryBudgetConfig {
+            max_retry_percent: 20,
+            window: 10,
+            min_retries: 10,
+        }
+    }
+}
+
+/// The requests and retries counted during one second of the window.
+#[derive(Debug, Default, Clone, Copy)]
+struct Bucket {
+    /// The second (since the budget was created) counted.
+    second: u64,
+    requests: u64,
+    retries: u64,
+}
+
+/// A retry budget: counts requests and retries over a sliding window.
+#[derive(Debug)]
+pub struct RetryBudget {
+    config: RetryBudgetConfig,
+    start: Instant,
+    buckets: Mutex<Vec<Bucket>>,
+}
+
+impl RetryBudget {
+    pub fn new(config: &RetryBudgetConfig) -> Self {
+        RetryBudget {
+            config: config.clone(),
+            start: Instant::now(),
+            buckets: Mutex::new(vec![Bucket::default(); config.window.max(1) as usize]),
+        }
+    }
+
+    /// Count a request sent to an origin (its first attempt).
+    pub fn record_request(&self) {
+        self.record_request_at(self.now());
+    }
+
+    /// Whether a retry fits in the budget.
+    pub fn allows_retry(&self) -> bool {
+        self.allows_retry_at(self.now())
+    }
+
+    /// Count a retry.
+    pub fn record_retry(&self) {
+        self.record_retry_at(self.now());
+    }
+
+    fn now(&self) -> u64 {
+        self.start.elapsed().as_secs()
+    }
+
+    /// Update the bucket of the second, starting it over if it last counted an earlier second.
+    fn update(&self, second: u64, f: impl FnOnce(&mut Bucket)) {
+        let mut buckets = self.buckets.lock().unwrap();
+        let len = buckets.len() as u64;
+        let bucket = &mut buckets[(second % len) as usize];
+        if bucket.second != second {
+            *bucket = Bucket {
+                second,
+                ..Default::default()
+            };
+        }
+        f(bucket);
+    }
+
+    fn record_request_at(&self, second: u64) {
+        self.update(second, |bucket| bucket.requests += 1);
+    }
+
+    fn record_retry_at(&self, second: u64) {
+        self.update(second, |bucket| bucket.retries += 1);
+    }
+
+    fn allows_retry_at(&self, second: u64) -> bool {
+        let buckets = self.buckets.lock().unwrap();
+        let len = buckets.len() as u64;
+        let (requests, retries) = buckets
+            .iter()
+            .filter(|bucket| bucket.second + len > second)
+            .fold((0, 0), |(requests, retries), bucket| {
+                (requests + bucket.requests, retries + bucket.retries)
+            });
+        let budget = requests * self.config.max_retry_percent as u64 / 100;
+        retries < budget.max(self.config.min_retries)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn budget() {
+        let budget = RetryBudget::new(&RetryBudgetConfig {
+            max_retry_percent: 20,
+            window: 10,
+            min_retries: 1,
+        });
+
+        // The minimum is allowed without any requests.
+        assert!(budget.allows_retry_at(0));
+        budget.record_retry_at(0);
+        assert!(!budget.allows_retry_at(0));
+
+        // 20 requests allow 4 retries (including the one already made).
+        for _ in 0..20 {
+            budget.record_request_at(1);
+        }
+        for _ in 0..3 {
+            assert!(budget.allows_retry_at(2));
+            budget.record_retry_at(2);
+        }
+        assert!(!budget.allows_retry_at(5));
+
+        // The first retry leaves the window, then the requests do.
+        assert!(budget.allows_retry_at(10));
+        assert!(!budget.allows_retry_at(11));
+
+        // A bucket reused for a later second starts over.
+        budget.record_retry_at(12);
+        assert!(!budget.allows_retry_at(21));
+        assert!(budget.allows_retry_at(22));
+    }
+}
@@ -0,0 +1,93 @@
+//! Rewriting of request URIs with regular expressions before they're forwarded to the origins
+//! (e.g., `^/old/(.*)` to `/new/$1`), for origins whose URL layout differs from the one clients
+//! see.  The rules are compiled when the route is parsed, so requests only run them.
+//!
+//! A route's rules are tried in order against the path and query the origins would otherwise get,
+//! and the first one that matches rewrites them; later rules aren't applied.
+
+use regex::Regex;
+use serde::{Deserialize, Serialize};
+
+/// A rewrite rule, as configured.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct RewriteRuleConfig {
+    /// The regular expression the path and query must match.
+    pub pattern: String,
+
+    /// What the match is replaced with.  `$1`, `$2`, ... (or `${name}`) refer to capture groups.
+    pub replacement: String,
+}
+
+/// A rewrite rule, with its regular expression compiled when the route is parsed.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(try_from = "RewriteRuleConfig", into = "RewriteRuleConfig")]
+pub struct RewriteRule {
+    config: RewriteRuleConfig,
+    regex: Regex,
+}
+
+impl TryFrom<RewriteRuleConfig> for RewriteRule {
+    type Error = String;
+
+    fn try_from(config: RewriteRuleConfig) -> Result<Self, Self::Error> {
+        let regex = Regex::new(&config.pattern)
+            .map_err(|e| format!("Invalid rewrite pattern {}: {e}", config.pattern))?;
+        Ok(RewriteRule { config, regex })
+    }
+}
+
+impl From<RewriteRule> for RewriteRuleConfig {
+    fn from(rule: RewriteRule) -> Self {
+        rule.config
+    }
+}
+
+impl PartialEq for RewriteRule {
+    fn eq(&self, other: &Self) -> bool {
+        self.config == other.config
+    }
+}
+
+impl Eq for RewriteRule {}
+
+/// Rewrite a path and query with the first rule that matches it.  Rewrites that don't result in
+/// an absolute path are ignored.
+pub fn apply(rules: &[RewriteRule], path_and_query: &str) -> Option<String> {
+    let rule = rules
+        .iter()
+        .find(|rule| rule.regex.is_match(path_and_query))?;
+    let rewritten = rule
+        .regex
+        .replace(path_and_query, rule.config.replacement.as_str());
+    rewritten.starts_with('/').then(|| rewritten.into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply() {
+        let rules: Vec<RewriteRule> = serde_json::from_value(serde_json::json!([
+            {"pattern": "^/old/(.*)", "replacement": "/new/$1"},
+            {"pattern": "^/users/(?P<id>[0-9]+)$", "replacement": "/profile?id=${id}"},
+            {"pattern": "^/relative", "replacement": "relative"},
+        ]))
+        .unwrap();
+        assert_eq!(
+            super::apply(&rules, "/old/a/b?x=1").as_deref(),
+            Some("/new/a/b?x=1")
+        );
+        assert_eq!(
+            super::apply(&rules, "/users/42").as_deref(),
+            Some("/profile?id=42")
+        );
+        assert_eq!(super::apply(&rules, "/other"), None);
+        assert_eq!(super::apply(&rules, "/relative"), None);
+
+        assert!(serde_json::from_value::<RewriteRule>(
+            serde_json::json!({"pattern": "(", "replacement": ""})
+        )
+        .is_err());
+    }
+}
@@ -1,5 +1,49 @@
 use serde::{Deserialize, Serialize};
 use std::collections::HashSet;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+use crate::acl::ClientNet;
+use crate::aws_sigv4::AwsSigV4Config;
+use crate::basic_auth::BasicAuthConfig;
+use crate::body_rewrite::BodyRewritePolicy;
+use crate::bots::BotPolicy;
+use crate::bucketing::BucketingPolicy;
+use crate::cache_lock::CacheLockPolicy;
+use crate::collapse::CollapsePolicy;
+use crate::conditional::ConditionalPolicy;
+use crate::cookies::CookiePolicy;
+use crate::cors::CorsPolicy;
+use crate::error_pages::ErrorPages;
+use crate::failover::FailoverResponse;
+use crate::forward_auth::ForwardAuthConfig;
+use crate::geoip::GeoPolicy;
+use crate::header_limits::HeaderLimits;
+use crate::idempotency::IdempotencyPolicy;
+use crate::keepalive::KeepalivePolicy;
+use crate::log_sinks::LogSinkConfig;
+use crate::methods::MethodPolicy;
+use crate::origin_connections::ConnectionLimit;
+use crate::path_match::RoutePath;
+use crate::plugin::PluginRef;
+use crate::post_cache::PostCachePolicy;
+use crate::prewarm::PrewarmPolicy;
+use crate::privacy::ClientIpPrivacy;
+use crate::ranges::RangePolicy;
+use crate::rate_limit::RateLimitPolicy;
+use crate::redirects::RedirectPolicy;
+use crate::response_headers::ResponseHeaderPolicy;
+use crate::retry_budget::RetryBudgetConfig;
+use crate::rewrite::{self, RewriteRule};
+use crate::script::ScriptConfig;
+use crate::secrets::UpstreamHeader;
+use crate::security_headers::SecurityHeadersPolicy;
+use crate::signed_url::SignedUrlConfig;
+use crate::throttle::{PacingRule, ThrottlePolicy};
+use crate::utils;
+use crate::waf::WafPolicy;
+use crate::warm_up::WarmUpPolicy;
+use crate::wasm::WasmFilterRef;
 
 /// An interface for adding and deleting routes.
 pub trait RouteHolder: Send + Sync {
@@ -14,6 +58,17 @@ pub enum IncomingScheme {
     Https,
 }
 
+/// What HTTPS requests are matched on against a route's hosts.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum MatchOn {
+    /// The `Host` header.
+    #[default]
+    Host,
+
+    /// The SNI of the TLS connection (the `Host` header for HTTP/2 requests, which carry no SNI).
+    Sni,
+}
+
 /// The scheme to use for requests to the origin.
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Hash, Default)]
 pub enum OutgoingScheme {
@@ -28,6 +83,35 @@ pub enum OutgoingScheme {
     MatchIncoming,
 }
 
+/// How the SNI sent to an origin is chosen when the origin doesn't set one.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Copy, Default)]
+pub enum SniPolicy {
+    /// The origin's host (unless it's an IP address, in which case no SNI is sent).
+    #[default]
+    OriginHost,
+
+    /// The host header sent to the origin (without a port).
+    HostHeader,
+
+    /// Never send an SNI, even if the origin sets one.
+    Disabled,
+}
+
+/// A mapping of the port a request was received on to the port of the origins to forward it to.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct PortMapping {
+    /// The port the proxy received the request on.
+    pub incoming_port: u16,
+
+    /// The origin port to forward the request to (instead of the origin's `http_port` or
+    /// `https_port`).
+    pub outgoing_port: u16,
+
+    /// The scheme to use for the request to the origin (instead of the route's
+    /// `outgoing_scheme`).
+    pub outgoing_scheme: Option<OutgoingScheme>,
+}
+
 /// Information about an origin server.
 #[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
 pub struct Origin {
@@ -42,12 +126,22 @@ pub struct Origin {
     #[serde(default = "default_https_port")]
     pub https_port: u16,
 
-    /// An optional host header to send to the origin server.
+    /// An optional host header to send to the origin server.  See [`render_host_header`] for the
+    /// variables it can refer to.
     pub host_header_override: Option<String>,
 
     /// An optional SNI to send to the origin server.
     pub sni: Option<String>,
 
+    /// How the SNI is chosen if `sni` isn't set.
+    #[serde(default)]
+    pub sni_policy: SniPolicy,
+
+    /// An optional hostname the origin's certificate is also accepted for, when the SNI isn't in it
+    /// (e.g., when connecting by IP address to a shared ingress).  The certificate is only verified
+    /// if an SNI is sent.
+    pub verify_hostname: Option<String>,
+
     /// The weight of this origin server.  The higher the weight, the more likely it is to be
     /// selected.  Weights are relative to the weights of other origins in the same group.
     /// E.g., if one origin has a weight of 10 and another has a weight of 20, the second origin is
@@ -55,6 +149,149 @@ pub struct Origin {
     /// If no weight is specified, the default weight is 10.
     #[serde(default = "default_weight")]
     pub weight: u16,
+
+    /// An optional limit on the connections to the origin in use at once.
+    pub connection_limit: Option<ConnectionLimit>,
+
+    /// Optional AWS SigV4 signing of requests to the origin (e.g., a private S3 bucket).  Set
+    /// `host_header_override` to the bucket's hostname so the signed host matches.
+    pub aws_sigv4: Option<AwsSigV4Config>,
+
+    /// Optional prewarming of idle connections to the origin, which also sets how long they're
+    /// kept.
+    pub prewarm: Option<PrewarmPolicy>,
+
+    /// How connections to the origin are kept alive for reuse.
+    pub keepalive: Option<KeepalivePolicy>,
+}
+
+impl Origin {
+    /// The SNI to send to the origin (empty for none), given the host header sent to it.
+    pub fn sni(&self, host_header: &str) -> String {
+        match (self.sni_policy, self.sni.as_ref()) {
+            (SniPolicy::Disabled, _) => String::new(),
+            (_, Some(sni)) => sni.clone(),
+            (SniPolicy::OriginHost, None) => match self.host.parse::<IpAddr>() {
+                Ok(_) => String::new(),
+                Err(_) => self.host.clone(),
+            },
+            (SniPolicy::HostHeader, None) => host_header
+                .split(':')
+                .next()
+                .unwrap_or_default()
+                .to_string(),
+        }
+    }
+}
+
+impl RouteConfig {
+    /// Whether the route is reachable from a listener with the labels.
+    pub fn reachable_from(&self, listener_labels: &[String]) -> bool {
+        self.listeners.is_empty() || self.listeners.iter().any(|l| listener_labels.contains(l))
+    }
+
+    /// Whether the route matches a host header with the port.
+    pub fn accepts_port(&self, port: u16) -> bool {
+        self.host_ports.is_empty() || self.host_ports.contains(&port)
+    }
+
+    /// Whether the route accepts a request matching its host (with the port) and paths: the path
+    /// isn't excluded, the route is reachable from the listener, and the request meets its
+    /// conditions.
+    pub fn accepts(&self, req: &RouteRequest, port: u16) -> bool {
+        self.accepts_path(req.path)
+            && self.reachable_from(req.listener)
+            && self.accepts_port(port)
+            && self.accepts_method(req.method)
+            && self.accepts_query(req.query)
+            && self.accepts_client(req.client_ip)
+    }
+
+    /// Whether the route has conditions on requests beyond their host and path.
+    pub fn has_conditions(&self) -> bool {
+        !self.match_methods.is_empty()
+            || !self.match_query.is_empty()
+            || !self.client_ip_match.is_empty()
+    }
+
+    /// Whether the route matches requests from the client.
+    pub fn accepts_client(&self, client_ip: Option<IpAddr>) -> bool {
+        self.client_ip_match.is_empty() || ClientNet::any_contains(&self.client_ip_match, client_ip)
+    }
+
+    /// Whether the client is allowed on the route.
+    pub fn allows_client(&self, client_ip: Option<IpAddr>) -> bool {
+        self.client_ip_allow.is_empty() || ClientNet::any_contains(&self.client_ip_allow, client_ip)
+    }
+
+    /// Whether the route matches requests with the query string (all its query conditions hold).
+    pub fn accepts_query(&self, query: Option<&str>) -> bool {
+        self.match_query.iter().all(|condition| {
+            query
+                .unwrap_or_default()
+                .split('&')
+                .filter(|p| !p.is_empty())
+                .any(|param| {
+                    let (name, value) = param.split_once('=').unwrap_or((param, ""));
+                    utils::percent_decode(name) == condition.name.as_bytes()
+                        && condition
+                            .value
+                            .as_ref()
+                            .is_none_or(|v| utils::percent_decode(value) == v.as_bytes())
+                })
+        })
+    }
+
+    /// Whether the path isn't excluded from the route.
+    pub fn accepts_path(&self, path: &str) -> bool {
+        !self
+            .exclude_paths
+            .iter()
+            .any(|excluded| excluded.matches(path))
+    }
+
+    /// The URI to forward a request for the URI to the origins with, if the route rewrites paths
+    /// (see `strip_path_prefix`, `prepend_path_prefix`, and `rewrite`).  Prefixes are changed
+    /// first, then the rewrite rules apply.  The query is kept, unless a rule changes it.
+    pub fn upstream_uri(&self, uri: &http::Uri) -> Option<http::Uri> {
+        if self.strip_path_prefix.is_none()
+            && self.prepend_path_prefix.is_none()
+            && self.rewrite.is_empty()
+        {
+            return None;
+        }
+        let path = uri.path();
+        let path = match self.strip_path_prefix.as_deref() {
+            // Only whole path segments are stripped (`/api/v1` from `/api/v1/foo`, not from
+            // `/api/v10/foo`).
+            Some(prefix) => path
+                .strip_prefix(prefix.trim_end_matches('/'))
+                .filter(|rest| rest.is_empty() || rest.starts_with('/'))
+                .unwrap_or(path),
+            None => path,
+        };
+        let prefix = self
+            .prepend_path_prefix
+            .as_deref()
+            .unwrap_or_default()
+            .trim_end_matches('/');
+        let separator = if path.starts_with('/') { "" } else { "/" };
+        let query = uri.query().map(|q| format!("?{q}")).unwrap_or_default();
+        let path_and_query = format!("{prefix}{separator}{path}{query}");
+        rewrite::apply(&self.rewrite, &path_and_query)
+            .unwrap_or(path_and_query)
+            .parse()
+            .ok()
+    }
+
+    /// Whether the route matches requests with the method.
+    pub fn accepts_method(&self, method: &str) -> bool {
+        self.match_methods.is_empty()
+            || self.match_methods.iter().any(|m| {
+                m.eq_ignore_ascii_case(method)
+                    || (method.eq_ignore_ascii_case("HEAD") && m.eq_ignore_ascii_case("GET"))
+            })
+    }
 }
 
 fn default_http_port() -> u16 {
@@ -69,15 +306,57 @@ fn default_weight() -> u16 {
     10
 }
 
+/// Substitute the variables in a host header override: `${host}` (the host requested by the
+/// client) and `${origin_host}` (the host of the selected origin).
+pub fn render_host_header(template: &str, host: &str, origin_host: &str) -> String {
+    template
+        .replace("${host}", host)
+        .replace("${origin_host}", origin_host)
+}
+
 #[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone)]
 pub struct OriginGroup {
-    pub origins: Vec<Origin>,
+    /// The origins are shared (rather than copied) with the requests they're selected for.
+    pub origins: Vec<Arc<Origin>>,
+}
+
+/// A request, as far as routes are matched on it.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RouteRequest<'a> {
+    pub method: &'a str,
+
+    /// The host header (which may carry a port).
+    pub host: &'a str,
+
+    pub path: &'a str,
+
+    pub query: Option<&'a str>,
+
+    pub client_ip: Option<IpAddr>,
+
+    /// The labels of the listener the request was received on.
+    pub listener: &'a [String],
+}
+
+/// A condition on a query parameter: it must be present, with the value if one is set.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct QueryCondition {
+    /// The parameter's name.
+    pub name: String,
+
+    /// The value the parameter must have.  If not set, it only needs to be present.
+    pub value: Option<String>,
 }
 
 /// A route configuration.  Route matching is based on the combination of the scheme, host, and path
-/// (using longest prefix match).
+/// (exact, regular expression, or longest prefix match).
 #[derive(Serialize, Deserialize, Debug, Default, PartialEq, Eq, Clone)]
 pub struct RouteConfig {
+    /// The schema version of the payload the route was defined with (see `schema`).  It's set to
+    /// the current version once the route is parsed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub version: Option<u32>,
+
     /// A name for the route.  Must be unique among all routes.
     pub name: String,
 
@@ -90,19 +369,233 @@ pub struct RouteConfig {
     /// The hosts this route matches.
     pub hosts: Vec<String>,
 
-    /// The paths this route matches.
-    pub paths: Vec<String>,
+    /// What HTTPS requests are matched on against the hosts.  HTTP requests are always matched on
+    /// their `Host` header.
+    #[serde(default)]
+    pub match_on: MatchOn,
+
+    /// The paths this route matches (prefixes, unless they say otherwise; see `path_match`).
+    pub paths: Vec<RoutePath>,
+
+    /// Paths excluded from the route even though they match its paths (e.g., `/app/admin` for a
+    /// route for `/app`), in the same form.  Requests for them fall back to the next best route.
+    #[serde(default)]
+    pub exclude_paths: Vec<RoutePath>,
+
+    /// The route's priority among the routes matching a request for the same host: the one with the
+    /// highest priority wins, regardless of how well its path matches.  Routes with equal
+    /// priorities are ranked by their paths (the default, since all routes have priority 0).
+    #[serde(default)]
+    pub priority: i32,
+
+    /// The labels of the listeners this route is reachable from (see `proxy.listener_labels`).  If
+    /// empty, it's reachable from every listener.
+    #[serde(default)]
+    pub listeners: Vec<String>,
+
+    /// The request methods this route matches (`HEAD` matches along with `GET`).  Requests with
+    /// other methods fall through to other routes for the same host and path (unlike `methods`,
+    /// which rejects them).  If empty, every method matches.
+    #[serde(default)]
+    pub match_methods: Vec<String>,
+
+    /// Conditions on the query parameters of the requests this route matches (all must hold), e.g.,
+    /// to send `?preview=true` requests to a staging origin.
+    #[serde(default)]
+    pub match_query: Vec<QueryCondition>,
+
+    /// The client addresses (IP addresses or CIDR blocks) this route matches, e.g., for internal
+    /// routes.  Requests from other clients fall through to other routes.  If empty, every client
+    /// matches.
+    #[serde(default)]
+    pub client_ip_match: Vec<ClientNet>,
+
+    /// The client addresses (IP addresses or CIDR blocks) allowed on this route.  Requests from
+    /// other clients are rejected with a 403.  If empty, every client is allowed.
+    #[serde(default)]
+    pub client_ip_allow: Vec<ClientNet>,
+
+    /// Whether HTTP requests are redirected (with a `301`) to HTTPS instead of being proxied.  The
+    /// route must accept HTTP for them to match it.
+    #[serde(default)]
+    pub redirect_to_https: bool,
+
+    /// The ports the client's host header may carry for this route (the port of the incoming
+    /// scheme if it carries none).  If empty, any port matches.
+    #[serde(default)]
+    pub host_ports: Vec<u16>,
+
+    /// Whether to keep the port of the client's host header in the hosts the proxy derives from it:
+    /// `${host}` in host header overrides, the forward auth service's `X-Forwarded-Host`, and the
+    /// host header of followed redirects.  Otherwise, the port is dropped.
+    #[serde(default)]
+    pub preserve_host_port: bool,
+
+    /// Whether clients must use HTTP/1.1 for this route (e.g., if its streaming responses have
+    /// issues over HTTP/2).  It applies to HTTPS connections for the route's hosts (see
+    /// `protocols`).
+    #[serde(default)]
+    pub http1_only: bool,
 
     /// Whether to enable caching for requests that match this route.
     #[serde(default)]
     pub cache: bool,
 
+    /// How conditional requests are answered from the cache (if the route caches).
+    #[serde(default)]
+    pub conditional: ConditionalPolicy,
+
+    /// Cache POST requests too, keyed on their body (if the route caches).  Otherwise, only GET and
+    /// HEAD requests are cached.
+    pub post_cache: Option<PostCachePolicy>,
+
+    /// How long requests wait for another request to fill a cache entry, and what they do when
+    /// they give up (if the route caches).  Otherwise, they wait 2 seconds and go to the origin.
+    pub cache_lock: Option<CacheLockPolicy>,
+
+    /// Collapse concurrent identical GET requests into one request to the origin (if the route
+    /// doesn't cache).
+    pub collapse: Option<CollapsePolicy>,
+
+    /// Keep the responses to requests carrying an idempotency key, sending them to retries with
+    /// the same key instead of forwarding the retries to the origin.
+    pub idempotency: Option<IdempotencyPolicy>,
+
     /// The scheme to use for requests to the origin (HTTP, HTTPS, or match the client's scheme).
     #[serde(default)]
     pub outgoing_scheme: OutgoingScheme,
 
     /// A group of origin servers to select from.
     pub origin_group: OriginGroup,
+
+    /// Origin ports (and schemes) for requests received on specific ports.
+    #[serde(default)]
+    pub port_map: Vec<PortMapping>,
+
+    /// An optional destination the route's access log lines are also sent to (e.g., for delivery
+    /// to the customer).
+    pub log_sink: Option<LogSinkConfig>,
+
+    /// Optional probes sent to the origins when the route is added (or its origins change).
+    pub warm_up: Option<WarmUpPolicy>,
+
+    /// An optional budget for retries to the route's origins (in addition to the global one).
+    pub retry_budget: Option<RetryBudgetConfig>,
+
+    /// Optional following of redirects from the origins, so clients (and the cache) get the final
+    /// response.
+    pub redirects: Option<RedirectPolicy>,
+
+    /// An optional host header to send to the origins that don't override it themselves.  See
+    /// [`render_host_header`] for the variables it can refer to.
+    pub host_header_override: Option<String>,
+
+    /// A prefix removed from the path of requests before they're forwarded to the origins (e.g.,
+    /// `/api/v1` to forward `/api/v1/foo` as `/foo`).  Paths without it as whole segments (e.g.,
+    /// `/api/v10/foo`) are forwarded as they are.
+    pub strip_path_prefix: Option<String>,
+
+    /// A prefix added to the path of requests (after `strip_path_prefix` is removed) before they're
+    /// forwarded to the origins.
+    pub prepend_path_prefix: Option<String>,
+
+    /// Rules rewriting the path and query of requests (after their prefix is changed) before
+    /// they're forwarded to the origins (see `rewrite`).
+    #[serde(default)]
+    pub rewrite: Vec<RewriteRule>,
+
+    /// An optional CORS policy enforced by the proxy on behalf of the origin.
+    pub cors: Option<CorsPolicy>,
+
+    /// An optional policy on the methods allowed on the route and how `OPTIONS` is answered.
+    pub methods: Option<MethodPolicy>,
+
+    /// An optional policy on range requests forwarded to the origin.
+    pub ranges: Option<RangePolicy>,
+
+    /// Limits on the size and count of the request headers, stricter than the server's.
+    pub header_limits: Option<HeaderLimits>,
+
+    /// An optional policy on the origin response headers stripped before responses are sent.
+    pub response_headers: Option<ResponseHeaderPolicy>,
+
+    /// Optional HTTP Basic authentication required to access the route.
+    pub basic_auth: Option<BasicAuthConfig>,
+
+    /// Optional external auth service that must approve each request before it is forwarded.
+    pub forward_auth: Option<ForwardAuthConfig>,
+
+    /// Optional signed URL validation.  Requests without a valid, unexpired signature are
+    /// rejected.
+    pub signed_url: Option<SignedUrlConfig>,
+
+    /// Optional per-client rate limit.
+    pub rate_limit: Option<RateLimitPolicy>,
+
+    /// Optional request filtering rules (e.g., to stop scanners and known exploit paths).
+    pub waf: Option<WafPolicy>,
+
+    /// Optional country restrictions and location headers (requires a GeoIP database).
+    pub geo: Option<GeoPolicy>,
+
+    /// Optional handling of search bots, crawlers, and clients without a `User-Agent`.
+    pub bots: Option<BotPolicy>,
+
+    /// Optional security headers (e.g., HSTS) added to responses.
+    pub security_headers: Option<SecurityHeadersPolicy>,
+
+    /// Optional filtering of the cookies sent to the origin and of `Set-Cookie` on cached
+    /// responses.
+    pub cookies: Option<CookiePolicy>,
+
+    /// Headers added to the requests sent to the origin (e.g., origin credentials, with values
+    /// that refer to secrets by name).
+    #[serde(default)]
+    pub upstream_headers: Vec<UpstreamHeader>,
+
+    /// Optional substitutions in the bodies of responses (before they are cached).
+    pub body_rewrite: Option<BodyRewritePolicy>,
+
+    /// Optional assignment of clients to buckets (e.g., for A/B tests).
+    pub bucketing: Option<BucketingPolicy>,
+
+    /// Custom pages (keyed by status code) for errors generated by the proxy.
+    #[serde(default)]
+    pub error_pages: ErrorPages,
+
+    /// A static response sent instead of an error when none of the origins can be reached.
+    pub failover: Option<FailoverResponse>,
+
+    /// How client addresses are recorded in logs and request summaries, and sent to the origins in
+    /// forwarding headers.
+    #[serde(default)]
+    pub client_ip_privacy: ClientIpPrivacy,
+
+    /// Optional bandwidth limit for each response.
+    pub throttle: Option<ThrottlePolicy>,
+
+    /// Bandwidth limits for each response with a given content type (the first matching rule
+    /// applies instead of `throttle`).
+    #[serde(default)]
+    pub pacing: Vec<PacingRule>,
+
+    /// Plugins (registered with the server) that extend the handling of the route's requests.
+    #[serde(default)]
+    pub plugins: Vec<PluginRef>,
+
+    /// An optional script run at phases of the route's requests.
+    pub script: Option<ScriptConfig>,
+
+    /// WebAssembly filters (loaded through the Config API) run on the route's requests.
+    #[serde(default)]
+    pub wasm: Vec<WasmFilterRef>,
+
+    /// How long (in seconds) the route lives once added (e.g., for a preview environment).  It's
+    /// replaced with `expires_at` when the route is added.
+    pub ttl: Option<u64>,
+
+    /// When (in seconds since the Unix epoch) the route expires and is deleted.
+    pub expires_at: Option<u64>,
 }
 
 #[cfg(test)]
@@ -133,7 +626,8 @@ mod tests {
                         "http_port": 8080,
                         "weight": 10,
                         "host_header_override": "foo.com",
-                        "sni": "foo.com"
+                        "sni": "foo.com",
+                        "verify_hostname": "ingress.internal"
                     },
                     {
                         "host": "origin2.com",
@@ -154,31 +648,129 @@ mod tests {
                 customer: "customer1".to_string(),
                 incoming_schemes: HashSet::from([IncomingScheme::Https, IncomingScheme::Http]),
                 hosts: vec!["example1.com".to_string(), "example2.com".to_string()],
-                paths: vec!["/".to_string()],
+                paths: vec!["/".into()],
                 cache: false,
                 outgoing_scheme: OutgoingScheme::MatchIncoming,
                 origin_group: OriginGroup {
                     origins: vec![
-                        Origin {
+                        Arc::new(Origin {
                             host: "origin1.com".to_string(),
                             http_port: 8080,
                             https_port: 443,
                             weight: 10,
                             host_header_override: Some("foo.com".to_string()),
                             sni: Some("foo.com".to_string()),
-                        },
-                        Origin {
+                            sni_policy: SniPolicy::OriginHost,
+                            verify_hostname: Some("ingress.internal".to_string()),
+                            connection_limit: None,
+                            aws_sigv4: None,
+                            prewarm: None,
+                            keepalive: None,
+                        }),
+                        Arc::new(Origin {
                             host: "origin2.com".to_string(),
                             http_port: 8080,
                             https_port: 4433,
                             weight: 20,
                             host_header_override: None,
                             sni: None,
-                        },
+                            sni_policy: SniPolicy::OriginHost,
+                            verify_hostname: None,
+                            connection_limit: None,
+                            aws_sigv4: None,
+                            prewarm: None,
+                            keepalive: None,
+                        }),
                     ],
                 },
+                ..Default::default()
             },
             route
         );
     }
+
+    #[test]
+    fn upstream_uri() {
+        let route = |strip: Option<&str>, prepend: Option<&str>| RouteConfig {
+            strip_path_prefix: strip.map(str::to_string),
+            prepend_path_prefix: prepend.map(str::to_string),
+            ..Default::default()
+        };
+        let upstream = |route: &RouteConfig, uri: &str| {
+            route
+                .upstream_uri(&uri.parse().unwrap())
+                .map(|uri| uri.to_string())
+        };
+
+        let strip = route(Some("/api/v1"), None);
+        assert_eq!(
+            upstream(&strip, "/api/v1/foo?a=1").as_deref(),
+            Some("/foo?a=1")
+        );
+        assert_eq!(upstream(&strip, "/api/v1").as_deref(), Some("/"));
+        assert_eq!(upstream(&strip, "/other").as_deref(), Some("/other"));
+        assert_eq!(
+            upstream(&strip, "/api/v10/foo").as_deref(),
+            Some("/api/v10/foo")
+        );
+        let trailing_slash = route(Some("/api/"), None);
+        assert_eq!(
+            upstream(&trailing_slash, "/api/foo").as_deref(),
+            Some("/foo")
+        );
+
+        let replace = route(Some("/api/v1"), Some("/internal/"));
+        assert_eq!(
+            upstream(&replace, "/api/v1/foo").as_deref(),
+            Some("/internal/foo")
+        );
+        assert_eq!(upstream(&RouteConfig::default(), "/foo"), None);
+
+        let mut rewrite = route(Some("/api"), None);
+        rewrite.rewrite =
+            serde_json::from_str(r#"[{"pattern": "^/old/(.*)", "replacement": "/new/$1"}]"#)
+                .unwrap();
+        assert_eq!(
+            upstream(&rewrite, "/api/old/a?b=1").as_deref(),
+            Some("/new/a?b=1")
+        );
+    }
+
+    #[test]
+    fn sni() {
+        let origin = |host: &str, sni: Option<&str>, policy| Origin {
+            host: host.to_string(),
+            http_port: 80,
+            https_port: 443,
+            weight: 10,
+            host_header_override: None,
+            sni: sni.map(str::to_string),
+            sni_policy: policy,
+            verify_hostname: None,
+            connection_limit: None,
+            aws_sigv4: None,
+            prewarm: None,
+            keepalive: None,
+        };
+        let host_header = "www.example.com:8443";
+        let sni = |origin: Origin| origin.sni(host_header);
+
+        assert_eq!(
+            sni(origin("origin.com", None, SniPolicy::OriginHost)),
+            "origin.com"
+        );
+        assert_eq!(sni(origin("192.0.2.1", None, SniPolicy::OriginHost)), "");
+        assert_eq!(
+            sni(origin("origin.com", None, SniPolicy::HostHeader)),
+            "www.example.com"
+        );
+        assert_eq!(
+            sni(origin("origin.com", Some("foo.com"), SniPolicy::HostHeader)),
+            "foo.com"
+        );
+        assert_eq!(
+            sni(origin("origin.com", Some("foo.com"), SniPolicy::Disabled)),
+            ""
+        );
+    }
 }
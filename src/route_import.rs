@@ -0,0 +1,711 @@
+//! Conversion of reverse proxy configurations from other servers into routes, to ease migrating
+//! onto granite.
+//!
+//! Only simple configurations are understood:
+//! - nginx: `server` blocks (at the top level or in an `http` block) with `listen`, `server_name`,
+//!   and prefix or exact (`=`) `location` blocks that `proxy_pass` to a URL or to an `upstream`
+//!   block.  The `Host` header set with `proxy_set_header` is kept.
+//! - Caddyfile: site blocks with `reverse_proxy` directives (optionally with a path matcher, or in
+//!   a `handle` block).  The `Host` header set with `header_up` is kept.
+//!
+//! Locations and handlers that don't proxy (e.g., that serve files or redirect) are skipped, and
+//! anything that can't be expressed as a route (e.g., regex locations or rewriting the path) is
+//! reported as an error rather than converted approximately.
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use crate::path_match::{PathMatchType, RoutePath};
+use crate::route_config::{
+    IncomingScheme, Origin, OriginGroup, OutgoingScheme, RouteConfig, SniPolicy,
+};
+
+/// The configuration formats that can be imported.
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum ImportFormat {
+    Nginx,
+    Caddy,
+}
+
+/// The options of an import, as given in the query string of `/route/import`.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct ImportOptions {
+    pub format: ImportFormat,
+    /// The customer the routes are for.
+    pub customer: String,
+}
+
+impl ImportOptions {
+    /// Parse the options from a query string (e.g., `format=nginx&customer=acme`).
+    pub fn from_query(query: &str) -> Result<Self, String> {
+        let mut format = None;
+        let mut customer = None;
+        for param in query.split('&').filter(|p| !p.is_empty()) {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            let value = String::from_utf8(crate::utils::percent_decode(value))
+                .map_err(|_| format!("Invalid value for {name}"))?;
+            match name {
+                "format" => {
+                    format = Some(match value.as_str() {
+                        "nginx" => ImportFormat::Nginx,
+                        "caddy" => ImportFormat::Caddy,
+                        _ => return Err(format!("Unknown format {value}")),
+                    })
+                }
+                "customer" => customer = Some(value),
+                _ => return Err(format!("Unknown parameter {name}")),
+            }
+        }
+        Ok(ImportOptions {
+            format: format.ok_or("Missing format")?,
+            customer: customer.ok_or("Missing customer")?,
+        })
+    }
+}
+
+/// Convert a configuration into routes.
+pub fn import(config: &str, options: &ImportOptions) -> Result<Vec<RouteConfig>, String> {
+    let directives = parse(config, options.format)?;
+    let mut routes = Routes::default();
+    match options.format {
+        ImportFormat::Nginx => import_nginx(&directives, &options.customer, &mut routes)?,
+        ImportFormat::Caddy => import_caddy(&directives, &options.customer, &mut routes)?,
+    }
+    Ok(routes.list)
+}
+
+/// A directive: its name, arguments, and block (if it has one).
+#[derive(Debug, Default)]
+struct Directive {
+    name: String,
+    args: Vec<String>,
+    block: Option<Vec<Directive>>,
+}
+
+impl Directive {
+    fn new(mut words: Vec<String>, block: Option<Vec<Directive>>) -> Self {
+        let name = if words.is_empty() {
+            String::new()
+        } else {
+            words.remove(0)
+        };
+        Directive {
+            name,
+            args: words,
+            block,
+        }
+    }
+
+    fn children(&self) -> &[Directive] {
+        self.block.as_deref().unwrap_or_default()
+    }
+}
+
+/// Parse the configuration into directives.  nginx directives end with `;`, and Caddyfile
+/// directives end with the line.  Both use `{ ... }` blocks and `#` comments.
+fn parse(config: &str, format: ImportFormat) -> Result<Vec<Directive>, String> {
+    let nginx = format == ImportFormat::Nginx;
+    // The directives of the enclosing blocks, with the words of the directive that opened each.
+    let mut stack: Vec<(Vec<String>, Vec<Directive>)> = vec![(Vec::new(), Vec::new())];
+    let mut words = Vec::new();
+    let end = |stack: &mut Vec<(Vec<String>, Vec<Directive>)>, words: &mut Vec<String>| {
+        if !words.is_empty() {
+            let directive = Directive::new(std::mem::take(words), None);
+            stack.last_mut().unwrap().1.push(directive);
+        }
+    };
+
+    let mut chars = config.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '#' => while chars.next_if(|&c| c != '\n').is_some() {},
+            '\n' if !nginx => end(&mut stack, &mut words),
+            ';' if nginx => end(&mut stack, &mut words),
+            c if c.is_whitespace() => {}
+            '"' | '\'' => {
+                let mut word = String::new();
+                loop {
+                    match chars.next() {
+                        Some(q) if q == c => break,
+                        Some('\\') => word.extend(chars.next()),
+                        Some(ch) => word.push(ch),
+                        None => return Err("Unterminated quoted string".to_string()),
+                    }
+                }
+                words.push(word);
+            }
+            _ => {
+                // In a Caddyfile, braces only delimit blocks as separate words (`{host}` is a
+                // placeholder).
+                let separator = |c: char| c.is_whitespace() || (nginx && "{};".contains(c));
+                let mut word = c.to_string();
+                if !(nginx && "{}".contains(c)) {
+                    while let Some(ch) = chars.next_if(|&ch| !separator(ch)) {
+                        word.push(ch);
+                    }
+                }
+                match word.as_str() {
+                    "{" => stack.push((std::mem::take(&mut words), Vec::new())),
+                    "}" => {
+                        end(&mut stack, &mut words);
+                        let (opening, block) = stack.pop().unwrap();
+                        let Some((_, parent)) = stack.last_mut() else {
+                            return Err("Unexpected '}'".to_string());
+                        };
+                        parent.push(Directive::new(opening, Some(block)));
+                    }
+                    _ => words.push(word),
+                }
+            }
+        }
+    }
+    if nginx && !words.is_empty() {
+        return Err(format!("Missing ';' after {}", words.join(" ")));
+    }
+    end(&mut stack, &mut words);
+    if stack.len() > 1 {
+        return Err("Missing '}'".to_string());
+    }
+    Ok(stack.pop().unwrap().1)
+}
+
+/// The routes converted so far.
+#[derive(Default)]
+struct Routes {
+    list: Vec<RouteConfig>,
+}
+
+impl Routes {
+    /// Add a route.  A route that only differs from an earlier one by its incoming schemes (e.g., a
+    /// site served by separate HTTP and HTTPS server blocks) is merged into it.
+    fn add(&mut self, route: RouteConfig) -> Result<(), String> {
+        let Some(existing) = self.list.iter_mut().find(|r| r.name == route.name) else {
+            self.list.push(route);
+            return Ok(());
+        };
+        let schemes = existing.incoming_schemes.clone();
+        existing.incoming_schemes = route.incoming_schemes.clone();
+        if *existing != route {
+            existing.incoming_schemes = schemes;
+            return Err(format!("Conflicting definitions of {}", route.name));
+        }
+        existing.incoming_schemes.extend(schemes);
+        Ok(())
+    }
+}
+
+/// Build a route for the hosts and path.  It's named after the first host and the path (preceded
+/// by `=` if it's exact).
+fn route(
+    customer: &str,
+    hosts: &[String],
+    schemes: &HashSet<IncomingScheme>,
+    path: RoutePath,
+    upstream: Upstream,
+) -> RouteConfig {
+    let name = match path.match_type {
+        PathMatchType::Exact => format!("{}={}", hosts[0], path.path),
+        _ => format!("{}{}", hosts[0], path.path),
+    };
+    RouteConfig {
+        name,
+        customer: customer.to_string(),
+        incoming_schemes: schemes.clone(),
+        hosts: hosts.to_vec(),
+        paths: vec![path],
+        outgoing_scheme: upstream.scheme,
+        origin_group: OriginGroup {
+            origins: upstream.origins.into_iter().map(Arc::new).collect(),
+        },
+        host_header_override: upstream.host_header,
+        ..Default::default()
+    }
+}
+
+/// Where requests are proxied to.
+struct Upstream {
+    scheme: OutgoingScheme,
+    origins: Vec<Origin>,
+    host_header: Option<String>,
+}
+
+/// An origin at the address (`host` or `host:port`).
+fn origin(address: &str, scheme: &OutgoingScheme, weight: u16) -> Result<Origin, String> {
+    let authority: http::uri::Authority = address
+        .parse()
+        .map_err(|_| format!("Invalid upstream address {address}"))?;
+    let port = authority.port_u16();
+    Ok(Origin {
+        host: authority.host().trim_matches(['[', ']']).to_string(),
+        http_port: match scheme {
+            OutgoingScheme::Http => port.unwrap_or(80),
+            _ => 80,
+        },
+        https_port: match scheme {
+            OutgoingScheme::Https => port.unwrap_or(443),
+            _ => 443,
+        },
+        host_header_override: None,
+        sni: None,
+        sni_policy: SniPolicy::default(),
+        verify_hostname: None,
+        weight,
+        connection_limit: None,
+        aws_sigv4: None,
+        prewarm: None,
+        keepalive: None,
+    })
+}
+
+/// Split an upstream URL into its scheme and address (with `default` as the scheme if it has
+/// none).
+fn split_scheme<'a>(url: &'a str, default: &str) -> Result<(OutgoingScheme, &'a str), String> {
+    let (scheme, rest) = url.split_once("://").unwrap_or((default, url));
+    let scheme = match scheme {
+        "http" => OutgoingScheme::Http,
+        "https" => OutgoingScheme::Https,
+        _ => return Err(format!("Unsupported upstream scheme in {url}")),
+    };
+    Ok((scheme, rest))
+}
+
+fn import_nginx(
+    directives: &[Directive],
+    customer: &str,
+    routes: &mut Routes,
+) -> Result<(), String> {
+    let mut upstreams = HashMap::new();
+    let mut servers = Vec::new();
+    collect_nginx(directives, &mut upstreams, &mut servers);
+
+    for server in servers {
+        let mut hosts = Vec::new();
+        let mut schemes = HashSet::new();
+        let mut host_header = None;
+        for directive in server.children() {
+            match directive.name.as_str() {
+                "server_name" => hosts.extend(
+                    directive
+                        .args
+                        .iter()
+                        .filter(|name| !name.is_empty() && *name != "_")
+                        .cloned(),
+                ),
+                "listen" => {
+                    let port = directive.args.first().map_or("80", |addr| {
+                        addr.rsplit_once(':')
+                            .map_or(addr.as_str(), |(_, port)| port)
+                    });
+                    let https = port == "443" || directive.args.iter().any(|arg| arg == "ssl");
+                    schemes.insert(if https {
+                        IncomingScheme::Https
+                    } else {
+                        IncomingScheme::Http
+                    });
+                }
+                "proxy_set_header" => host_header = nginx_host_header(directive)?.or(host_header),
+                _ => {}
+            }
+        }
+        if hosts.is_empty() {
+            return Err("A server block has no server_name".to_string());
+        }
+        if schemes.is_empty() {
+            schemes.insert(IncomingScheme::Http);
+        }
+
+        for location in server.children().iter().filter(|d| d.name == "location") {
+            let (path, exact) = match location.args.as_slice() {
+                [path] => (path, false),
+                [modifier, path] if modifier == "^~" => (path, false),
+                [modifier, path] if modifier == "=" => (path, true),
+                _ => {
+                    return Err(format!(
+                        "Unsupported location {} (only prefix and exact locations are)",
+                        location.args.join(" ")
+                    ))
+                }
+            };
+            let mut proxy_pass = None;
+            let mut location_host_header = None;
+            for directive in location.children() {
+                match directive.name.as_str() {
+                    "proxy_pass" => proxy_pass = directive.args.first(),
+                    "proxy_set_header" => {
+                        location_host_header =
+                            nginx_host_header(directive)?.or(location_host_header)
+                    }
+                    _ => {}
+                }
+            }
+            let Some(proxy_pass) = proxy_pass else {
+                continue;
+            };
+            if proxy_pass.contains('$') {
+                return Err(format!(
+                    "Unsupported proxy_pass with variables: {proxy_pass}"
+                ));
+            }
+
+            let (scheme, rest) = split_scheme(proxy_pass, "")?;
+            let (address, uri) = rest.split_once('/').unwrap_or((rest, ""));
+            if !uri.is_empty() && format!("/{uri}") != *path {
+                return Err(format!(
+                    "Unsupported proxy_pass {proxy_pass} in location {path} (the path can't be \
+                     rewritten)"
+                ));
+            }
+            let origins = match upstreams.get(address) {
+                Some(servers) => servers
+                    .iter()
+                    .map(|(address, weight)| origin(address, &scheme, *weight))
+                    .collect::<Result<_, _>>()?,
+                None => vec![origin(address, &scheme, 10)?],
+            };
+            // nginx sends the upstream's host unless the Host header is set.
+            let host_header = match location_host_header.or(host_header.clone()) {
+                Some(NginxHost::Client) => None,
+                Some(NginxHost::Value(value)) => Some(value),
+                None => Some("${origin_host}".to_string()),
+            };
+            let upstream = Upstream {
+                scheme,
+                origins,
+                host_header,
+            };
+            let path = if exact {
+                RoutePath::exact(path)
+            } else {
+                path.as_str().into()
+            };
+            routes.add(route(customer, &hosts, &schemes, path, upstream))?;
+        }
+    }
+    Ok(())
+}
+
+/// Find the `upstream` (name to servers and weights) and `server` blocks.
+fn collect_nginx<'a>(
+    directives: &'a [Directive],
+    upstreams: &mut HashMap<String, Vec<(String, u16)>>,
+    servers: &mut Vec<&'a Directive>,
+) {
+    for directive in directives {
+        match (directive.name.as_str(), directive.args.first()) {
+            ("http", _) => collect_nginx(directive.children(), upstreams, servers),
+            ("server", _) if directive.block.is_some() => servers.push(directive),
+            ("upstream", Some(name)) => {
+                let members = directive
+                    .children()
+                    .iter()
+                    .filter(|d| d.name == "server" && !d.args.is_empty())
+                    .map(|d| {
+                        let weight = d
+                            .args
+                            .iter()
+                            .find_map(|arg| arg.strip_prefix("weight="))
+                            .and_then(|w| w.parse().ok())
+                            .unwrap_or(1);
+                        (d.args[0].clone(), weight)
+                    })
+                    .collect();
+                upstreams.insert(name.clone(), members);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// The `Host` header sent to the upstream.
+#[derive(Clone)]
+enum NginxHost {
+    /// The client's.
+    Client,
+    Value(String),
+}
+
+/// The `Host` header set by a `proxy_set_header` directive (`None` if it sets another header).
+fn nginx_host_header(directive: &Directive) -> Result<Option<NginxHost>, String> {
+    let [name, value] = directive.args.as_slice() else {
+        return Ok(None);
+    };
+    if !name.eq_ignore_ascii_case("host") {
+        return Ok(None);
+    }
+    let host = match value.as_str() {
+        "$host" | "$http_host" => NginxHost::Client,
+        "$proxy_host" => NginxHost::Value("${origin_host}".to_string()),
+        value if value.contains('$') => {
+            return Err(format!("Unsupported Host header {value}"));
+        }
+        value => NginxHost::Value(value.to_string()),
+    };
+    Ok(Some(host))
+}
+
+fn import_caddy(
+    directives: &[Directive],
+    customer: &str,
+    routes: &mut Routes,
+) -> Result<(), String> {
+    // Site blocks (the global options block has no addresses, and snippets are in parentheses).
+    let sites = directives
+        .iter()
+        .filter(|d| d.block.is_some() && !d.name.is_empty() && !d.name.starts_with('('));
+    for site in sites {
+        let mut hosts = Vec::new();
+        let mut schemes = HashSet::new();
+        let addresses = std::iter::once(&site.name).chain(&site.args);
+        for address in addresses
+            .flat_map(|a| a.split(','))
+            .filter(|a| !a.is_empty())
+        {
+            let (scheme, rest) = match address.split_once("://") {
+                Some((scheme, rest)) => (Some(scheme), rest),
+                None => (None, address),
+            };
+            let (host, port) = match rest.rsplit_once(':') {
+                Some((host, port)) if !host.ends_with(']') || rest.starts_with('[') => {
+                    (host, Some(port))
+                }
+                _ => (rest, None),
+            };
+            if host.is_empty() {
+                return Err(format!(
+                    "Unsupported site address {address} (a host is needed)"
+                ));
+            }
+            // Caddy serves HTTPS (and redirects HTTP) unless told otherwise.
+            schemes.insert(match (scheme, port) {
+                (Some("http"), _) | (None, Some("80")) => IncomingScheme::Http,
+                _ => IncomingScheme::Https,
+            });
+            if !hosts.iter().any(|h| h == host) {
+                hosts.push(host.to_string());
+            }
+        }
+
+        for directive in site.children() {
+            match directive.name.as_str() {
+                "reverse_proxy" => {
+                    let (path, upstream) = caddy_reverse_proxy(directive, None)?;
+                    routes.add(route(
+                        customer,
+                        &hosts,
+                        &schemes,
+                        path.as_str().into(),
+                        upstream,
+                    ))?;
+                }
+                "handle" | "route" => {
+                    let matcher = directive.args.first().map(String::as_str);
+                    for inner in directive.children() {
+                        if inner.name == "reverse_proxy" {
+                            let (path, upstream) = caddy_reverse_proxy(inner, matcher)?;
+                            routes.add(route(
+                                customer,
+                                &hosts,
+                                &schemes,
+                                path.as_str().into(),
+                                upstream,
+                            ))?;
+                        }
+                    }
+                }
+                "handle_path" | "rewrite" | "uri" => {
+                    return Err(format!(
+                        "Unsupported directive {} (the path can't be rewritten)",
+                        directive.name
+                    ))
+                }
+                _ => {}
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Convert a `reverse_proxy` directive (in a block with the path matcher, if any) into the path
+/// it applies to and its upstream.
+fn caddy_reverse_proxy(
+    directive: &Directive,
+    matcher: Option<&str>,
+) -> Result<(String, Upstream), String> {
+    let mut args = directive.args.as_slice();
+    let mut matcher = matcher;
+    if let Some(first) = args.first().filter(|arg| arg.starts_with('/')) {
+        matcher = Some(first);
+        args = &args[1..];
+    }
+    let path = match matcher {
+        None | Some("*") => "/".to_string(),
+        Some(m) if m.starts_with('/') && !m[..m.len() - 1].contains('*') => {
+            m.trim_end_matches('*').to_string()
+        }
+        Some(m) => return Err(format!("Unsupported matcher {m} (only path prefixes are)")),
+    };
+
+    let mut upstreams: Vec<&str> = args.iter().map(String::as_str).collect();
+    let mut host_header = None;
+    for sub in directive.children() {
+        match (sub.name.as_str(), sub.args.as_slice()) {
+            ("to", to) => upstreams.extend(to.iter().map(String::as_str)),
+            ("header_up", [name, value]) if name.eq_ignore_ascii_case("host") => {
+                host_header = match value.as_str() {
+                    "{host}" | "{http.request.host}" | "{hostport}" => None,
+                    "{upstream_hostport}" | "{http.reverse_proxy.upstream.hostport}" => {
+                        Some("${origin_host}".to_string())
+                    }
+                    value if value.contains('{') => {
+                        return Err(format!("Unsupported Host header {value}"))
+                    }
+                    value => Some(value.to_string()),
+                };
+            }
+            _ => {}
+        }
+    }
+    if upstreams.is_empty() {
+        return Err("A reverse_proxy has no upstreams".to_string());
+    }
+
+    let mut scheme = None;
+    let mut origins = Vec::new();
+    for upstream in upstreams {
+        let (upstream_scheme, address) = split_scheme(upstream, "http")?;
+        if scheme.as_ref().is_some_and(|s| *s != upstream_scheme) {
+            return Err("Upstreams with different schemes are unsupported".to_string());
+        }
+        // Caddy's default port for `host` alone is 80 (or 443 with https://).
+        origins.push(origin(address, &upstream_scheme, 10)?);
+        scheme = Some(upstream_scheme);
+    }
+    let upstream = Upstream {
+        scheme: scheme.unwrap_or_default(),
+        origins,
+        host_header,
+    };
+    Ok((path, upstream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(format: ImportFormat) -> ImportOptions {
+        ImportOptions {
+            format,
+            customer: "acme".to_string(),
+        }
+    }
+
+    #[test]
+    fn nginx() {
+        let config = r#"
+            http {
+                upstream app {
+                    server 10.0.0.1:8080 weight=3;
+                    server 10.0.0.2:8080;
+                }
+                server {
+                    listen 80;
+                    listen 443 ssl;
+                    server_name example.com www.example.com;
+                    location / {
+                        proxy_pass http://app;
+                        proxy_set_header Host $host;  # keep the client's host
+                    }
+                    location ^~ /static/ {
+                        proxy_pass https://cdn.internal/static/;
+                    }
+                    location /health { return 200; }
+                }
+            }
+        "#;
+        let routes = import(config, &options(ImportFormat::Nginx)).unwrap();
+        assert_eq!(routes.len(), 2);
+
+        let root = &routes[0];
+        assert_eq!(root.name, "example.com/");
+        assert_eq!(root.customer, "acme");
+        assert_eq!(root.hosts, ["example.com", "www.example.com"]);
+        assert_eq!(
+            root.incoming_schemes,
+            HashSet::from([IncomingScheme::Http, IncomingScheme::Https])
+        );
+        assert_eq!(root.outgoing_scheme, OutgoingScheme::Http);
+        let origins = &root.origin_group.origins;
+        assert_eq!(
+            (origins[0].host.as_str(), origins[0].http_port),
+            ("10.0.0.1", 8080)
+        );
+        assert_eq!((origins[0].weight, origins[1].weight), (3, 1));
+        assert_eq!(root.host_header_override, None);
+
+        let assets = &routes[1];
+        assert_eq!(assets.paths, ["/static/".into()]);
+        assert_eq!(assets.outgoing_scheme, OutgoingScheme::Https);
+        assert_eq!(assets.origin_group.origins[0].https_port, 443);
+        assert_eq!(
+            assets.host_header_override.as_deref(),
+            Some("${origin_host}")
+        );
+
+        // An exact location only matches its path, so `location = /` isn't a catch-all.
+        let exact = "server { server_name a.com; location = / { proxy_pass http://b; } \
+                     location / { proxy_pass http://c; } }";
+        let routes = import(exact, &options(ImportFormat::Nginx)).unwrap();
+        assert_eq!(routes.len(), 2);
+        assert_eq!(routes[0].name, "a.com=/");
+        assert_eq!(routes[0].paths[0].match_type, PathMatchType::Exact);
+        assert!(routes[0].paths[0].matches("/"));
+        assert!(!routes[0].paths[0].matches("/a"));
+        assert_eq!(routes[1].name, "a.com/");
+        assert_eq!(routes[1].paths[0].match_type, PathMatchType::Prefix);
+
+        let regex = "server { server_name a.com; location ~ \\.php$ { proxy_pass http://b; } }";
+        assert!(import(regex, &options(ImportFormat::Nginx)).is_err());
+        let rewrite = "server { server_name a.com; location /a/ { proxy_pass http://b/c/; } }";
+        assert!(import(rewrite, &options(ImportFormat::Nginx)).is_err());
+    }
+
+    #[test]
+    fn caddy() {
+        let config = r#"
+            {
+                email admin@example.com
+            }
+            example.com, http://example.com {
+                reverse_proxy /api/* api1:9000 api2:9000 {
+                    header_up Host {upstream_hostport}
+                }
+                handle /admin* {
+                    reverse_proxy https://admin.internal
+                }
+                reverse_proxy localhost:8080
+            }
+        "#;
+        let routes = import(config, &options(ImportFormat::Caddy)).unwrap();
+        let names: Vec<_> = routes.iter().map(|r| r.name.as_str()).collect();
+        assert_eq!(
+            names,
+            ["example.com/api/", "example.com/admin", "example.com/"]
+        );
+        assert_eq!(routes[0].hosts, ["example.com"]);
+        assert_eq!(
+            routes[0].incoming_schemes,
+            HashSet::from([IncomingScheme::Http, IncomingScheme::Https])
+        );
+        assert_eq!(routes[0].origin_group.origins.len(), 2);
+        assert_eq!(routes[0].origin_group.origins[1].http_port, 9000);
+        assert_eq!(
+            routes[0].host_header_override.as_deref(),
+            Some("${origin_host}")
+        );
+        assert_eq!(routes[1].outgoing_scheme, OutgoingScheme::Https);
+        assert_eq!(routes[2].host_header_override, None);
+
+        let rewrite = "example.com {\n handle_path /a/* {\n reverse_proxy b\n }\n}";
+        assert!(import(rewrite, &options(ImportFormat::Caddy)).is_err());
+    }
+}
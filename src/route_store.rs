@@ -1,9 +1,19 @@
+use arc_swap::ArcSwap;
 use log::{debug, warn};
+use rand::distributions::{Distribution, WeightedError, WeightedIndex};
+use std::cmp::Reverse;
 use std::sync::RwLock;
 use std::time::Instant;
 use std::{collections::HashMap, sync::Arc};
 
-use crate::route_config::{IncomingScheme, RouteConfig, RouteHolder};
+use crate::origin_connections::ConnectionLimits;
+use crate::path_match::PathIndex;
+use crate::retry_budget::RetryBudget;
+use crate::route_config::{
+    IncomingScheme, MatchOn, Origin, RouteConfig, RouteHolder, RouteRequest,
+};
+use crate::utils;
+use crate::warm_up;
 
 /// A route defines how to route HTTP requests to origin servers.  It includes some configuration
 /// (e.g., a group of origin servers to route to) along with some mutable state (e.g., which origin
@@ -12,172 +22,715 @@ use crate::route_config::{IncomingScheme, RouteConfig, RouteHolder};
 pub struct Route {
     pub config: RouteConfig,
     pub state: RwLock<RouteState>,
+    pub connection_limits: ConnectionLimits,
+    pub retry_budget: Option<RetryBudget>,
 }
 
 #[derive(Debug, Default)]
 pub struct RouteState {
     pub down_endpoints: HashMap<usize, Instant>, // Key: index of down origin, Value: time it was marked down.
+    pub consecutive_failures: HashMap<usize, u32>, // Key: index of origin, Value: failed connection attempts since the last success.
+    /// The weighted selection among the eligible origins.  It is reset to `None` whenever
+    /// `down_endpoints` changes and is rebuilt on the next selection.
+    pub selection: Option<OriginSelection>,
+}
+
+impl RouteState {
+    /// Create the state of a route whose origins are all up.
+    pub fn new(origins: &[Arc<Origin>]) -> Self {
+        let mut state = RouteState::default();
+        // A route with invalid weights fails when an origin is selected.
+        let _ = state.selection(origins);
+        state
+    }
+
+    /// The weighted selection among the eligible origins, rebuilt if necessary.
+    pub fn selection(
+        &mut self,
+        origins: &[Arc<Origin>],
+    ) -> Result<&OriginSelection, WeightedError> {
+        if self.selection.is_none() {
+            self.selection = Some(OriginSelection::new(origins, &self.down_endpoints)?);
+        }
+        Ok(self.selection.as_ref().unwrap())
+    }
+}
+
+/// A precomputed weighted random selection among the eligible origins of a route.  Origins that
+/// are marked down aren't eligible, unless all of them are marked down, in which case all are.
+#[derive(Debug, Clone)]
+pub struct OriginSelection {
+    /// The indexes (within the origin group) of the eligible origins.
+    eligible: Vec<usize>,
+    weights: WeightedIndex<u16>,
+    /// Whether all the origins are marked down.
+    pub all_down: bool,
+}
+
+impl OriginSelection {
+    fn new(
+        origins: &[Arc<Origin>],
+        down_endpoints: &HashMap<usize, Instant>,
+    ) -> Result<Self, WeightedError> {
+        let all_down = down_endpoints.len() == origins.len();
+        let eligible: Vec<usize> = (0..origins.len())
+            .filter(|index| all_down || !down_endpoints.contains_key(index))
+            .collect();
+        let weights = WeightedIndex::new(eligible.iter().map(|&index| origins[index].weight))?;
+        Ok(OriginSelection {
+            eligible,
+            weights,
+            all_down,
+        })
+    }
+
+    /// Pick an origin at random and return its index within the origin group.
+    pub fn sample(&self) -> usize {
+        self.eligible[self.weights.sample(&mut rand::thread_rng())]
+    }
 }
 
 /// A store for routes.  Routes are indexed by name, host, and path.  They are added and deleted
 /// through the Config API service.  Routes are looked up by the proxy when processing requests.
 pub struct RouteStore {
-    // Protect the set of inter-related data structures that enable fast route lookups, additions,
-    // and deletions with a read-writer lock.  Reads are frequent (for every request), but writes
-    // are infrequent (only when the config API service is used or when some mutable route state
-    // is changed).
-    inner: RwLock<InnerStore>,
+    // The set of inter-related data structures that enable fast route lookups is an immutable
+    // snapshot.  Reads are frequent (for every request) and must never wait, while writes are
+    // infrequent (only when the config API service is used).  So a write copies the current
+    // snapshot, modifies the copy, and atomically swaps it in; lookups in progress keep using the
+    // snapshot they started with.  No lock is held while the copy is built; if another write swaps
+    // in a snapshot first, the copy is rebuilt from that one so neither change is lost.
+    inner: ArcSwap<InnerStore>,
 }
 
-/// The inner protected part of the RouteStore.
+/// A snapshot of the routes in the RouteStore.
+#[derive(Clone)]
 struct InnerStore {
-    http_host_to_route: HashMap<String, Vec<Arc<Route>>>,
-    https_host_to_route: HashMap<String, Vec<Arc<Route>>>,
+    http_hosts: HostIndex,
+    https_hosts: HostIndex,
+    /// HTTPS routes matched on the SNI rather than the host header.
+    sni_hosts: HostIndex,
     name_to_route: HashMap<String, Arc<Route>>,
+    /// The estimated memory used by the routes, in bytes.
+    bytes: usize,
+}
+
+/// Routes indexed by host.  Hosts like `*.example.com` match any single label in place of the `*`
+/// (e.g., `www.example.com` but not `example.com` or `a.b.example.com`).
+/// The routes of each host are shared between snapshots, so copying a snapshot is cheap and only
+/// the hosts touched by a change are copied and recompiled.
+#[derive(Clone, Default)]
+struct HostIndex {
+    exact: HashMap<String, Arc<HostRoutes>>,
+    /// Keyed by the part after `*.`.
+    wildcard: HashMap<String, Arc<HostRoutes>>,
+}
+
+/// The routes of a host, along with a path index compiled from them.
+#[derive(Clone, Default)]
+struct HostRoutes {
+    routes: Vec<Arc<Route>>,
+    paths: PathIndex,
+}
+
+impl HostRoutes {
+    /// Rebuild the path index after the routes changed.  Routes with request conditions (methods,
+    /// query parameters) take precedence over routes without for the same paths.
+    fn compile(&mut self) {
+        let mut routes = self.routes.clone();
+        routes.sort_by_key(|route| !route.config.has_conditions());
+        self.paths = PathIndex::new(&routes);
+    }
+
+    /// Find the route with the highest priority among the matching routes accepting the request,
+    /// and with the best path among those (the first one found, for equal paths).
+    fn find(&self, path: &str, accepts: impl Fn(&Arc<Route>) -> bool) -> Option<&Arc<Route>> {
+        self.paths
+            .find(path)
+            .into_iter()
+            .filter(|route| accepts(route))
+            .min_by_key(|route| Reverse(route.config.priority))
+    }
+}
+
+impl HostIndex {
+    /// The map that holds the host's routes.
+    fn map_for(&mut self, host: &str) -> &mut HashMap<String, Arc<HostRoutes>> {
+        if host.starts_with("*.") {
+            &mut self.wildcard
+        } else {
+            &mut self.exact
+        }
+    }
+
+    fn key(host: &str) -> &str {
+        host.strip_prefix("*.").unwrap_or(host)
+    }
+
+    fn insert(&mut self, host: &str, route: Arc<Route>) {
+        let host_routes = Arc::make_mut(
+            self.map_for(host)
+                .entry(Self::key(host).to_string())
+                .or_default(),
+        );
+        host_routes.routes.push(route);
+        host_routes.compile();
+    }
+
+    fn remove(&mut self, host: &str, name: &str) {
+        let map = self.map_for(host);
+        let key = Self::key(host);
+        let host_routes = Arc::make_mut(
+            map.get_mut(key)
+                .unwrap_or_else(|| panic!("No routes for {host}. Expected {name}")),
+        );
+        let position = host_routes
+            .routes
+            .iter()
+            .position(|r| r.config.name == name)
+            .unwrap_or_else(|| panic!("Route {name} not found for host {host}"));
+        let _ = host_routes.routes.remove(position);
+        if host_routes.routes.is_empty() {
+            let _ = map.remove(key);
+        } else {
+            host_routes.compile();
+        }
+    }
+
+    /// Find the route for the host (and port) with the highest priority, then the best path
+    /// matching the request's path (see `path_match`), among those accepting the request (see
+    /// `RouteConfig::accepts`).  Routes for the exact host take precedence over wildcard routes.
+    fn find(&self, host: &str, port: u16, req: &RouteRequest) -> Option<&Arc<Route>> {
+        let accepts = |route: &Arc<Route>| route.config.accepts(req, port);
+        if let Some(host_routes) = self.exact.get(host) {
+            debug!(
+                "Found {} routes for host: {}",
+                host_routes.routes.len(),
+                host
+            );
+            if let Some(route) = host_routes.find(req.path, accepts) {
+                return Some(route);
+            }
+        }
+        let (_, parent) = host.split_once('.')?;
+        let host_routes = self.wildcard.get(parent)?;
+        debug!(
+            "Found {} routes for wildcard host: *.{}",
+            host_routes.routes.len(),
+            parent
+        );
+        host_routes.find(req.path, accepts)
+    }
+
+    /// The routes that may serve the host (exact or wildcard).
+    fn routes_for<'a>(&'a self, host: &str) -> impl Iterator<Item = &'a Arc<Route>> {
+        let wildcard = host
+            .split_once('.')
+            .and_then(|(_, parent)| self.wildcard.get(parent));
+        self.exact
+            .get(host)
+            .into_iter()
+            .chain(wildcard)
+            .flat_map(|host_routes| host_routes.routes.iter())
+    }
+
+    /// Whether any of the routes that may serve the host is HTTP/1.1-only.
+    fn forces_http1(&self, host: &str) -> bool {
+        self.routes_for(host).any(|r| r.config.http1_only)
+    }
 }
 
 impl InnerStore {
     fn new() -> Self {
         InnerStore {
-            http_host_to_route: HashMap::new(),
-            https_host_to_route: HashMap::new(),
+            http_hosts: HostIndex::default(),
+            https_hosts: HostIndex::default(),
+            sni_hosts: HostIndex::default(),
             name_to_route: HashMap::new(),
+            bytes: 0,
+        }
+    }
+
+    /// The index of the route's hosts for the protocol.
+    fn hosts(&mut self, protocol: &IncomingScheme, config: &RouteConfig) -> &mut HostIndex {
+        match (protocol, config.match_on) {
+            (IncomingScheme::Http, _) => &mut self.http_hosts,
+            (IncomingScheme::Https, MatchOn::Host) => &mut self.https_hosts,
+            (IncomingScheme::Https, MatchOn::Sni) => &mut self.sni_hosts,
+        }
+    }
+
+    /// Remove a route (if it exists).
+    fn remove(&mut self, name: &str) -> bool {
+        let Some(route) = self.name_to_route.remove(name) else {
+            return false;
+        };
+
+        for protocol in route.config.incoming_schemes.iter() {
+            let hosts = self.hosts(protocol, &route.config);
+            for host in &route.config.hosts {
+                hosts.remove(host, name);
+            }
         }
+        self.bytes -= estimated_size(&route.config);
+        true
     }
+
+    /// Add a route.  A route with the same name must not exist.
+    fn insert(&mut self, route: Arc<Route>) {
+        self.name_to_route
+            .insert(route.config.name.clone(), route.clone());
+
+        for protocol in route.config.incoming_schemes.iter() {
+            let hosts = self.hosts(protocol, &route.config);
+            for host in &route.config.hosts {
+                hosts.insert(host, route.clone());
+            }
+        }
+        self.bytes += estimated_size(&route.config);
+    }
+}
+
+/// Estimate the memory used by a route: its configuration (sized as its JSON representation) plus
+/// an entry in the indexes for each combination of scheme, host, and path.
+fn estimated_size(config: &RouteConfig) -> usize {
+    const INDEX_ENTRY_SIZE: usize = 64;
+    let config_size = serde_json::to_vec(config).map_or(0, |json| json.len());
+    let index_entries =
+        config.incoming_schemes.len() * config.hosts.len() * (config.paths.len() + 1);
+    config_size + index_entries * INDEX_ENTRY_SIZE
 }
 
 impl RouteStore {
     pub fn new() -> Self {
         RouteStore {
-            inner: RwLock::new(InnerStore::new()),
+            inner: ArcSwap::from_pointee(InnerStore::new()),
         }
     }
 
-    /// Get the route that matches the given protocol, host, and path.  The route with the longest
-    /// matching path is returned.  If no route matches, `None` is returned.
-    pub fn get_route(
-        &self,
-        protocol: IncomingScheme,
-        host: &str,
-        path: &str,
-    ) -> Option<Arc<Route>> {
-        let inner = self.inner.read().unwrap();
-
-        // Look up the routes for the given host.
-        let host_to_route = match protocol {
-            IncomingScheme::Http => &inner.http_host_to_route,
-            IncomingScheme::Https => &inner.https_host_to_route,
+    /// Apply a change to a copy of the current snapshot and publish the copy.  The change may be
+    /// applied more than once (to a newer snapshot) if another change is published concurrently.
+    fn update(&self, change: impl Fn(&mut InnerStore)) {
+        self.inner.rcu(|current| {
+            let mut inner = InnerStore::clone(current);
+            change(&mut inner);
+            inner
+        });
+    }
+
+    /// Get the route that matches the request received with the given protocol.  The route with
+    /// the highest priority is returned, then the one with the best matching path (an exact path,
+    /// then a regular expression, then the longest prefix), preferring routes for the exact host
+    /// over wildcard routes, and routes with request conditions over routes without.  If no route
+    /// matches, `None` is returned.  The host may carry a port, which the route must accept (the
+    /// scheme's default port if it carries none).
+    pub fn get_route(&self, protocol: IncomingScheme, req: &RouteRequest) -> Option<Arc<Route>> {
+        let inner = self.inner.load();
+        let (host, port) = utils::split_host_port(req.host);
+        let (hosts, default_port) = match protocol {
+            IncomingScheme::Http => (&inner.http_hosts, 80),
+            IncomingScheme::Https => (&inner.https_hosts, 443),
         };
-        let routes = host_to_route.get(host)?;
-        if routes.is_empty() {
-            return None;
-        }
-        debug!("Found {} routes for host: {}", routes.len(), host);
-
-        // Find the route with the longest matching path.
-        let mut longest_path_length = 0;
-        let mut best_match_route: Option<Arc<Route>> = None;
-        for route in routes {
-            for candidate_path in &route.config.paths {
-                if path.starts_with(candidate_path) && candidate_path.len() > longest_path_length {
-                    longest_path_length = candidate_path.len();
-                    best_match_route = Some(route.clone());
-                }
-            }
-        }
+        hosts.find(host, port.unwrap_or(default_port), req).cloned()
+    }
+
+    /// Get the HTTPS route matched on the SNI that matches the SNI and the request received on the
+    /// given listener port (see `get_route`; the request's host header is ignored).  Routes matched
+    /// on the SNI take precedence over routes matched on the host header.
+    pub fn get_route_by_sni(&self, sni: &str, port: u16, req: &RouteRequest) -> Option<Arc<Route>> {
+        self.inner.load().sni_hosts.find(sni, port, req).cloned()
+    }
 
-        best_match_route
+    /// Whether any HTTPS route is matched on the SNI.
+    pub fn has_sni_routes(&self) -> bool {
+        let inner = self.inner.load();
+        !inner.sni_hosts.exact.is_empty() || !inner.sni_hosts.wildcard.is_empty()
+    }
+
+    /// Whether the host has an HTTPS route matched on the SNI.
+    pub fn has_sni_routes_for(&self, host: &str) -> bool {
+        self.inner
+            .load()
+            .sni_hosts
+            .routes_for(host)
+            .next()
+            .is_some()
+    }
+
+    /// Whether the host has an HTTPS route that's HTTP/1.1-only (see `protocols`), or matched on
+    /// the SNI (which HTTP/2 requests don't carry, see `sni`).
+    pub fn forces_http1(&self, host: &str) -> bool {
+        let inner = self.inner.load();
+        inner.https_hosts.forces_http1(host) || inner.sni_hosts.routes_for(host).next().is_some()
+    }
+
+    /// Get all the routes.
+    pub fn routes(&self) -> Vec<Arc<Route>> {
+        let inner = self.inner.load();
+        inner.name_to_route.values().cloned().collect()
+    }
+
+    /// The estimated memory used by the routes, in bytes.
+    pub fn memory_usage(&self) -> usize {
+        self.inner.load().bytes
     }
 }
 
 impl RouteHolder for RouteStore {
-    /// Add or replace a route.
+    /// Add or replace a route.  Readers see either the old route or the new one, never neither.
     fn add_route(&self, route_config: RouteConfig) {
-        let mut inner = self.inner.write().unwrap();
-
-        // If a route with the same name already exists, delete it first.
-        let name = route_config.name.as_str();
-        if let Some(route) = inner.name_to_route.get(name) {
-            let route = route.clone();
-
-            for protocol in route.config.incoming_schemes.iter() {
-                let host_to_route = match protocol {
-                    IncomingScheme::Http => &mut inner.http_host_to_route,
-                    IncomingScheme::Https => &mut inner.https_host_to_route,
-                };
-                for host in &route.config.hosts {
-                    let routes = host_to_route
-                        .get_mut(host)
-                        .unwrap_or_else(|| panic!("No routes for {host}. Expected {name}"));
-                    let position = routes
-                        .iter()
-                        .position(|r| r.config.name == name)
-                        .unwrap_or_else(|| panic!("Route {name} not found for host {host}"));
-                    let _ = routes.remove(position);
-                    if routes.is_empty() {
-                        let _ = host_to_route.remove(host);
-                    }
-                }
-            }
-
-            let _ = inner.name_to_route.remove(name);
-        }
-
-        // Add the new route while still under the lock (this is important so that no reader
-        // experiences a lookup miss while a route is being changed).
         let route = Arc::new(Route {
+            state: RwLock::new(RouteState::new(&route_config.origin_group.origins)),
+            connection_limits: ConnectionLimits::new(&route_config.origin_group.origins),
+            retry_budget: route_config.retry_budget.as_ref().map(RetryBudget::new),
             config: route_config,
-            state: RwLock::new(RouteState::default()),
         });
-
-        inner
+        let origins_changed = self
+            .inner
+            .load()
             .name_to_route
-            .insert(route.config.name.clone(), route.clone());
-
-        for protocol in route.config.incoming_schemes.iter() {
-            let host_to_route = match protocol {
-                IncomingScheme::Http => &mut inner.http_host_to_route,
-                IncomingScheme::Https => &mut inner.https_host_to_route,
-            };
-            for host in &route.config.hosts {
-                host_to_route
-                    .entry(host.to_string())
-                    .or_insert_with(Vec::new)
-                    .push(route.clone());
-            }
+            .get(&route.config.name)
+            .is_none_or(|old| old.config.origin_group != route.config.origin_group);
+        self.update(|inner| {
+            // If a route with the same name already exists, delete it first.
+            inner.remove(&route.config.name);
+            inner.insert(route.clone());
+        });
+        if origins_changed {
+            warm_up::start(route);
         }
     }
 
     /// Delete a route (if it exists)
     fn delete_route(&self, name: &str) {
-        let mut inner = self.inner.write().unwrap();
+        self.update(|inner| {
+            if !inner.remove(name) {
+                warn!("Attempted to delete a route that doesn't exis name={name}");
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashSet;
 
-        let Some(route) = inner.name_to_route.get(name) else {
-            warn!("Attempted to delete a route that doesn't exis name={name}");
-            return;
+    fn request<'a>(host: &'a str, path: &'a str) -> RouteRequest<'a> {
+        RouteRequest {
+            method: "GET",
+            host,
+            path,
+            ..Default::default()
+        }
+    }
+
+    fn route(name: &str, path: &str) -> RouteConfig {
+        RouteConfig {
+            name: name.to_string(),
+            hosts: vec!["example.com".to_string()],
+            paths: vec![path.into()],
+            incoming_schemes: HashSet::from([IncomingScheme::Http]),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn add_replace_delete() {
+        let store = RouteStore::new();
+        store.add_route(route("r1", "/"));
+        store.add_route(route("r2", "/api"));
+        let lookup = |path| {
+            store
+                .get_route(IncomingScheme::Http, &request("example.com", path))
+                .map(|r| r.config.name.clone())
         };
-        let route = route.clone();
+        assert_eq!(lookup("/api/users").as_deref(), Some("r2"));
+        assert_eq!(lookup("/static").as_deref(), Some("r1"));
 
-        for protocol in route.config.incoming_schemes.iter() {
-            let host_to_route = match protocol {
-                IncomingScheme::Http => &mut inner.http_host_to_route,
-                IncomingScheme::Https => &mut inner.https_host_to_route,
-            };
-            for host in &route.config.hosts {
-                let routes = host_to_route
-                    .get_mut(host)
-                    .unwrap_or_else(|| panic!("No routes for {host}. Expected {name}"));
-                let position = routes
-                    .iter()
-                    .position(|r| r.config.name == name)
-                    .unwrap_or_else(|| panic!("Route {name} not found for host {host}"));
-                let _ = routes.remove(position);
-                if routes.is_empty() {
-                    let _ = host_to_route.remove(host);
-                }
-            }
+        // A route held by an in-flight request outlives its replacement.
+        let old = store
+            .get_route(IncomingScheme::Http, &request("example.com", "/api"))
+            .unwrap();
+        store.add_route(route("r2", "/static"));
+        assert_eq!(old.config.paths, vec!["/api".into()]);
+        assert_eq!(lookup("/static").as_deref(), Some("r2"));
+        assert_eq!(lookup("/api").as_deref(), Some("r1"));
+
+        // Wildcard hosts match a single label, and exact hosts take precedence.
+        let mut wildcard = route("w1", "/");
+        wildcard.hosts = vec!["*.com".to_string(), "*.example.com".to_string()];
+        store.add_route(wildcard);
+        assert_eq!(lookup("/api").as_deref(), Some("r1"));
+        assert_eq!(
+            store
+                .get_route(IncomingScheme::Http, &request("www.example.com", "/"))
+                .map(|r| r.config.name.clone())
+                .as_deref(),
+            Some("w1")
+        );
+        assert!(store
+            .get_route(IncomingScheme::Http, &request("a.b.example.net", "/"))
+            .is_none());
+
+        store.delete_route("r1");
+        // Without a matching route for the exact host, the wildcard route matches.
+        assert_eq!(lookup("/api").as_deref(), Some("w1"));
+        assert_eq!(store.routes().len(), 2);
+
+        store.delete_route("r2");
+        store.delete_route("w1");
+        assert_eq!(store.memory_usage(), 0);
+    }
+
+    #[test]
+    fn wildcard_precedence() {
+        let store = RouteStore::new();
+        let mut wildcard = route("wildcard", "/api");
+        wildcard.hosts = vec!["*.customer1.example.com".to_string()];
+        store.add_route(wildcard);
+        let mut exact = route("exact", "/");
+        exact.hosts = vec!["www.customer1.example.com".to_string()];
+        store.add_route(exact);
+        let lookup = |host: &str| {
+            store
+                .get_route(IncomingScheme::Http, &request(host, "/api/users"))
+                .map(|r| r.config.name.clone())
+        };
+        // The exact host wins even though the wildcard route has a longer path.
+        assert_eq!(
+            lookup("www.customer1.example.com").as_deref(),
+            Some("exact")
+        );
+        assert_eq!(
+            lookup("img.customer1.example.com").as_deref(),
+            Some("wildcard")
+        );
+        assert_eq!(lookup("customer1.example.com"), None);
+        assert_eq!(lookup("a.img.customer1.example.com"), None);
+    }
+
+    #[test]
+    fn listeners() {
+        let store = RouteStore::new();
+        let mut internal = route("internal", "/admin");
+        internal.listeners = vec!["internal".to_string()];
+        store.add_route(internal);
+        store.add_route(route("public", "/"));
+        let lookup = |listener: &[&str]| {
+            let listener: Vec<String> = listener.iter().map(|l| l.to_string()).collect();
+            store
+                .get_route(
+                    IncomingScheme::Http,
+                    &RouteRequest {
+                        listener: &listener,
+                        ..request("example.com", "/admin")
+                    },
+                )
+                .map(|r| r.config.name.clone())
+        };
+        assert_eq!(lookup(&["internal"]).as_deref(), Some("internal"));
+        // The route isn't reachable from other listeners, so the next best match is used.
+        assert_eq!(lookup(&["external"]).as_deref(), Some("public"));
+        assert_eq!(lookup(&[]).as_deref(), Some("public"));
+    }
+
+    #[test]
+    fn host_ports() {
+        let store = RouteStore::new();
+        let mut alt = route("alt", "/");
+        alt.host_ports = vec![8080];
+        store.add_route(alt);
+        let mut default = route("default", "/");
+        default.host_ports = vec![80];
+        store.add_route(default);
+        let lookup = |host: &str| {
+            store
+                .get_route(IncomingScheme::Http, &request(host, "/"))
+                .map(|r| r.config.name.clone())
+        };
+        assert_eq!(lookup("example.com:8080").as_deref(), Some("alt"));
+        assert_eq!(lookup("example.com").as_deref(), Some("default"));
+        assert_eq!(lookup("example.com:80").as_deref(), Some("default"));
+        assert_eq!(lookup("example.com:8081"), None);
+    }
+
+    #[test]
+    fn exclude_paths() {
+        let store = RouteStore::new();
+        store.add_route(route("root", "/"));
+        let mut app = route("app", "/app");
+        app.exclude_paths = vec!["/app/admin".into()];
+        store.add_route(app);
+        let lookup = |path| {
+            store
+                .get_route(IncomingScheme::Http, &request("example.com", path))
+                .map(|r| r.config.name.clone())
+        };
+        assert_eq!(lookup("/app/home").as_deref(), Some("app"));
+        assert_eq!(lookup("/app/admin/users").as_deref(), Some("root"));
+    }
+
+    #[test]
+    fn sni() {
+        let store = RouteStore::new();
+        let mut by_sni = route("sni", "/");
+        by_sni.incoming_schemes = HashSet::from([IncomingScheme::Http, IncomingScheme::Https]);
+        by_sni.match_on = MatchOn::Sni;
+        store.add_route(by_sni);
+        let mut alt = route("alt", "/");
+        alt.hosts = vec!["alt.com".to_string()];
+        alt.incoming_schemes = HashSet::from([IncomingScheme::Https]);
+        alt.match_on = MatchOn::Sni;
+        alt.host_ports = vec![8443];
+        store.add_route(alt);
+        let lookup_sni = |sni, port| {
+            store
+                .get_route_by_sni(sni, port, &request("", "/"))
+                .map(|r| r.config.name.clone())
+        };
+        assert!(store.has_sni_routes());
+        assert!(store.has_sni_routes_for("example.com"));
+        assert!(!store.has_sni_routes_for("other.com"));
+        // HTTP/2 requests carry no SNI, so the hosts' connections are kept to HTTP/1.1.
+        assert!(store.forces_http1("example.com"));
+        assert!(!store.forces_http1("other.com"));
+        assert_eq!(lookup_sni("example.com", 443).as_deref(), Some("sni"));
+        assert_eq!(lookup_sni("other.com", 443), None);
+        // The SNI carries no port, so the routes' ports are matched against the listener's.
+        assert_eq!(lookup_sni("alt.com", 8443).as_deref(), Some("alt"));
+        assert_eq!(lookup_sni("alt.com", 443), None);
+        // HTTPS requests aren't matched on their host header, but HTTP requests are.
+        let lookup = |protocol| {
+            store
+                .get_route(protocol, &request("example.com", "/"))
+                .map(|r| r.config.name.clone())
+        };
+        assert_eq!(lookup(IncomingScheme::Https), None);
+        assert_eq!(lookup(IncomingScheme::Http).as_deref(), Some("sni"));
+
+        store.delete_route("sni");
+        store.delete_route("alt");
+        assert!(!store.has_sni_routes());
+    }
+
+    #[test]
+    fn methods() {
+        let store = RouteStore::new();
+        let mut reads = route("reads", "/api");
+        reads.match_methods = vec!["GET".to_string()];
+        store.add_route(reads);
+        store.add_route(route("writes", "/api"));
+        let lookup = |method: &str| {
+            store
+                .get_route(
+                    IncomingScheme::Http,
+                    &RouteRequest {
+                        method,
+                        ..request("example.com", "/api/users")
+                    },
+                )
+                .map(|r| r.config.name.clone())
+        };
+        assert_eq!(lookup("GET").as_deref(), Some("reads"));
+        assert_eq!(lookup("HEAD").as_deref(), Some("reads"));
+        assert_eq!(lookup("POST").as_deref(), Some("writes"));
+    }
+
+    #[test]
+    fn query() {
+        let store = RouteStore::new();
+        store.add_route(route("live", "/"));
+        let mut preview = route("preview", "/");
+        preview.match_query =
+            serde_json::from_str(r#"[{"name": "preview", "value": "true"}]"#).unwrap();
+        store.add_route(preview);
+        let mut debug = route("debug", "/");
+        debug.match_query = serde_json::from_str(r#"[{"name": "debug"}]"#).unwrap();
+        store.add_route(debug);
+        let lookup = |query| {
+            store
+                .get_route(
+                    IncomingScheme::Http,
+                    &RouteRequest {
+                        query,
+                        ..request("example.com", "/a")
+                    },
+                )
+                .map(|r| r.config.name.clone())
+        };
+        // Routes with conditions take precedence, even if added later.
+        assert_eq!(lookup(Some("x=1&preview=true")).as_deref(), Some("preview"));
+        assert_eq!(lookup(Some("preview=false")).as_deref(), Some("live"));
+        assert_eq!(lookup(Some("debug")).as_deref(), Some("debug"));
+        assert_eq!(lookup(Some("debug=%31")).as_deref(), Some("debug"));
+        assert_eq!(lookup(None).as_deref(), Some("live"));
+    }
+
+    #[test]
+    fn priority() {
+        let store = RouteStore::new();
+        store.add_route(route("api", "/api"));
+        let mut fallback = route("fallback", "/");
+        fallback.priority = 1;
+        store.add_route(fallback);
+        let mut other = route("other", "/");
+        other.priority = 1;
+        store.add_route(other);
+        let lookup = |path| {
+            store
+                .get_route(IncomingScheme::Http, &request("example.com", path))
+                .map(|r| r.config.name.clone())
+        };
+        // A higher priority overrides a longer path, and the first route added wins a tie.
+        assert_eq!(lookup("/api/users").as_deref(), Some("fallback"));
+        store.delete_route("fallback");
+        assert_eq!(lookup("/api/users").as_deref(), Some("other"));
+        store.delete_route("other");
+        assert_eq!(lookup("/api/users").as_deref(), Some("api"));
+    }
+
+    #[test]
+    fn concurrent_updates() {
+        let store = Arc::new(RouteStore::new());
+        store.add_route(route("root", "/"));
+        let writers: Vec<_> = (0..4)
+            .map(|i| {
+                let store = store.clone();
+                std::thread::spawn(move || {
+                    for j in 0..50 {
+                        store.add_route(route(&format!("r{i}-{j}"), &format!("/{i}/{j}")));
+                        let found =
+                            store.get_route(IncomingScheme::Http, &request("example.com", "/x"));
+                        assert_eq!(found.unwrap().config.name, "root");
+                    }
+                })
+            })
+            .collect();
+        for writer in writers {
+            writer.join().unwrap();
         }
+        // No change was lost.
+        assert_eq!(store.routes().len(), 201);
+        let found = store.get_route(IncomingScheme::Http, &request("example.com", "/3/49/x"));
+        assert_eq!(found.unwrap().config.name, "r3-49");
+    }
+
+    #[test]
+    fn origin_selection() {
+        let origin = |host: &str| -> Arc<Origin> {
+            serde_json::from_value(serde_json::json!({ "host": host })).unwrap()
+        };
+        let origins = vec![origin("o1.com"), origin("o2.com")];
+        let mut state = RouteState::new(&origins);
+        assert!(!state.selection(&origins).unwrap().all_down);
+
+        state.down_endpoints.insert(0, Instant::now());
+        state.selection = None;
+        let selection = state.selection(&origins).unwrap();
+        assert!((0..20).all(|_| selection.sample() == 1));
 
-        let _ = inner.name_to_route.remove(name);
+        state.down_endpoints.insert(1, Instant::now());
+        state.selection = None;
+        assert!(state.selection(&origins).unwrap().all_down);
     }
 }
@@ -0,0 +1,122 @@
+//! A radix trie of path prefixes, used to find the routes whose paths match a request path without
+//! scanning every path of every route configured for the host.
+
+use std::sync::Arc;
+
+use crate::route_store::Route;
+
+/// Routes indexed by path prefix.  Prefixes are compared byte by byte (like `str::starts_with`).
+#[derive(Debug, Clone, Default)]
+pub struct PathTrie {
+    root: PathNode,
+}
+
+/// A node of the trie.  Each child's label starts with a different byte.
+#[derive(Debug, Clone, Default)]
+struct PathNode {
+    /// The bytes on the edge leading to this node.
+    label: Vec<u8>,
+    /// The routes with a path prefix ending at this node (in the order they were added).
+    routes: Vec<Arc<Route>>,
+    children: Vec<PathNode>,
+}
+
+impl PathTrie {
+    /// Index a route under one of its path prefixes.
+    pub fn insert(&mut self, prefix: &str, route: Arc<Route>) {
+        let mut node = &mut self.root;
+        let mut rest = prefix.as_bytes();
+        while !rest.is_empty() {
+            let Some(index) = node.children.iter().position(|c| c.label[0] == rest[0]) else {
+                node.children.push(PathNode {
+                    label: rest.to_vec(),
+                    routes: vec![route],
+                    children: Vec::new(),
+                });
+                return;
+            };
+
+            let child = &mut node.children[index];
+            let common = child
+                .label
+                .iter()
+                .zip(rest)
+                .take_while(|(a, b)| a == b)
+                .count();
+            if common < child.label.len() {
+                // Split the edge: the child keeps the common part, and a new node below it takes
+                // over the rest of the label along with the child's routes and children.
+                let tail = PathNode {
+                    label: child.label.split_off(common),
+                    routes: std::mem::take(&mut child.routes),
+                    children: std::mem::take(&mut child.children),
+                };
+                child.children.push(tail);
+            }
+            rest = &rest[common..];
+            node = child;
+        }
+        node.routes.push(route);
+    }
+
+    /// Find the routes with a path prefix matching the path, longest prefix first.  Routes with the
+    /// same prefix are returned in the order they were added.
+    pub fn find(&self, path: &str) -> Vec<&Arc<Route>> {
+        let mut levels: Vec<&[Arc<Route>]> = Vec::new();
+        let mut node = &self.root;
+        let mut rest = path.as_bytes();
+        loop {
+            if !node.routes.is_empty() {
+                levels.push(&node.routes);
+            }
+            match node.children.iter().find(|c| rest.starts_with(&c.label)) {
+                Some(child) => {
+                    rest = &rest[child.label.len()..];
+                    node = child;
+                }
+                None => break,
+            }
+        }
+        levels
+            .iter()
+            .rev()
+            .flat_map(|routes| routes.iter())
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::route_config::RouteConfig;
+
+    #[test]
+    fn longest_prefix_first() {
+        let route = |name: &str| {
+            Arc::new(Route {
+                config: RouteConfig {
+                    name: name.to_string(),
+                    ..Default::default()
+                },
+                ..Default::default()
+            })
+        };
+        let mut trie = PathTrie::default();
+        trie.insert("/api/v1", route("v1"));
+        trie.insert("/", route("root"));
+        trie.insert("/api/v2", route("v2"));
+        trie.insert("/api", route("api"));
+        trie.insert("/api", route("api2"));
+
+        let names = |path| -> Vec<String> {
+            trie.find(path)
+                .iter()
+                .map(|r| r.config.name.clone())
+                .collect()
+        };
+        assert_eq!(names("/api/v1/users"), ["v1", "api", "api2", "root"]);
+        assert_eq!(names("/api/v3"), ["api", "api2", "root"]);
+        assert_eq!(names("/static"), ["root"]);
+        assert!(names("static").is_empty());
+    }
+}
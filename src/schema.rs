@@ -0,0 +1,170 @@
+//! Versioned route and certificate binding payloads, so the proxy fleet can be upgraded without
+//! migrating every stored definition at the same time.
+//!
+//! Payloads carry the `version` of the schema they were written for (a missing version is version
+//! 1).  When the schema changes incompatibly, its version is bumped and a converter is added that
+//! rewrites payloads of the previous version; older payloads go through each converter in turn
+//! until they're current.  Payloads for a newer version than the proxy knows are rejected, since
+//! they may use fields it would silently ignore.
+//!
+//! Payloads are upgraded wherever they're received: the Config API, the declarative configuration
+//! file, and replicated snapshots (so followers can be upgraded before their leader).
+
+use serde::de::{DeserializeOwned, Error};
+use serde::{Deserialize, Deserializer};
+use serde_json::{Map, Value};
+
+use crate::cert::cert_config::CertBinding;
+use crate::route_config::RouteConfig;
+
+/// Rewrites a payload of one version into the next one.
+type Converter = fn(&mut Map<String, Value>) -> Result<(), String>;
+
+/// The route payload converters: the first one upgrades version 1 to version 2, and so on.
+const ROUTE_CONVERTERS: &[Converter] = &[];
+
+/// The certificate binding payload converters.
+const CERT_CONVERTERS: &[Converter] = &[];
+
+/// The current version of route payloads.
+pub const ROUTE_VERSION: u32 = ROUTE_CONVERTERS.len() as u32 + 1;
+
+/// The current version of certificate binding payloads.
+pub const CERT_VERSION: u32 = CERT_CONVERTERS.len() as u32 + 1;
+
+/// Parse a route payload of any supported version.
+pub fn parse_route(body: &[u8]) -> Result<RouteConfig, String> {
+    let value = serde_json::from_slice(body).map_err(|e| e.to_string())?;
+    route(value)
+}
+
+/// Parse a certificate binding payload of any supported version.
+pub fn parse_cert_binding(body: &[u8]) -> Result<CertBinding, String> {
+    let value = serde_json::from_slice(body).map_err(|e| e.to_string())?;
+    cert_binding(value)
+}
+
+/// Upgrade a route payload and deserialize it.
+pub fn route(value: Value) -> Result<RouteConfig, String> {
+    upgrade(value, ROUTE_CONVERTERS)
+}
+
+/// Upgrade a certificate binding payload and deserialize it.
+pub fn cert_binding(value: Value) -> Result<CertBinding, String> {
+    upgrade(value, CERT_CONVERTERS)
+}
+
+/// Deserialize a route payload of any supported version (for `deserialize_with`).
+pub fn deserialize_route<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Box<RouteConfig>, D::Error> {
+    route(Value::deserialize(deserializer)?)
+        .map(Box::new)
+        .map_err(D::Error::custom)
+}
+
+/// Deserialize a list of route payloads of any supported version (for `deserialize_with`).
+pub fn deserialize_routes<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<Vec<RouteConfig>, D::Error> {
+    Vec::<Value>::deserialize(deserializer)?
+        .into_iter()
+        .map(|value| route(value).map_err(D::Error::custom))
+        .collect()
+}
+
+/// Deserialize a certificate binding payload of any supported version (for `deserialize_with`).
+pub fn deserialize_cert_binding<'de, D: Deserializer<'de>>(
+    deserializer: D,
+) -> Result<CertBinding, D::Error> {
+    cert_binding(Value::deserialize(deserializer)?).map_err(D::Error::custom)
+}
+
+/// Bring a payload to the current version (the number of converters plus one), then deserialize
+/// it.
+fn upgrade<T: DeserializeOwned>(value: Value, converters: &[Converter]) -> Result<T, String> {
+    let Value::Object(mut object) = value else {
+        return Err("Payload isn't a JSON object".to_string());
+    };
+    let current = converters.len() as u32 + 1;
+    let version = match object.get("version") {
+        None | Some(Value::Null) => 1,
+        Some(version) => version
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .filter(|version| *version >= 1)
+            .ok_or_else(|| format!("Invalid payload version {version}"))?,
+    };
+    if version > current {
+        return Err(format!(
+            "Payload version {version} is newer than the supported version {current}"
+        ));
+    }
+    for (from, converter) in converters.iter().enumerate().skip(version as usize - 1) {
+        converter(&mut object)
+            .map_err(|e| format!("Unable to upgrade payload from version {}: {e}", from + 1))?;
+    }
+    object.insert("version".to_string(), current.into());
+    serde_json::from_value(Value::Object(object)).map_err(|e| e.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[derive(Deserialize, Debug, PartialEq)]
+    struct Item {
+        version: u32,
+        hosts: Vec<String>,
+    }
+
+    /// Version 2 renamed `host` to `hosts` (a list).
+    fn hosts(object: &mut Map<String, Value>) -> Result<(), String> {
+        let host = object.remove("host").ok_or("Missing host")?;
+        object.insert("hosts".to_string(), json!([host]));
+        Ok(())
+    }
+
+    #[test]
+    fn upgrade() {
+        let converters: &[Converter] = &[hosts];
+        let item = |version, hosts: &[&str]| Item {
+            version,
+            hosts: hosts.iter().map(|host| host.to_string()).collect(),
+        };
+        assert_eq!(
+            super::upgrade::<Item>(json!({"host": "a"}), converters),
+            Ok(item(2, &["a"]))
+        );
+        assert_eq!(
+            super::upgrade::<Item>(json!({"version": 1, "host": "a"}), converters),
+            Ok(item(2, &["a"]))
+        );
+        assert_eq!(
+            super::upgrade::<Item>(json!({"version": 2, "hosts": ["a", "b"]}), converters),
+            Ok(item(2, &["a", "b"]))
+        );
+        assert!(super::upgrade::<Item>(json!({"version": 1}), converters).is_err());
+        assert!(super::upgrade::<Item>(json!({"version": 3, "hosts": []}), converters).is_err());
+        assert!(super::upgrade::<Item>(json!({"version": 0, "hosts": []}), converters).is_err());
+        assert!(super::upgrade::<Item>(json!(["a"]), converters).is_err());
+    }
+
+    #[test]
+    fn current() {
+        let route = parse_route(
+            br#"{"name": "r1", "customer": "c1", "incoming_schemes": ["Https"], "hosts": [],
+                 "paths": ["/"], "outgoing_scheme": "Https", "origin_group": {"origins": []}}"#,
+        )
+        .unwrap();
+        assert_eq!(route.version, Some(ROUTE_VERSION));
+        assert!(parse_route(br#"{"version": 99, "name": "r1"}"#)
+            .unwrap_err()
+            .contains("newer"));
+
+        let binding =
+            parse_cert_binding(br#"{"version": 1, "host": "a", "cert": "C", "key": "K"}"#).unwrap();
+        assert_eq!(binding.version, Some(CERT_VERSION));
+    }
+}
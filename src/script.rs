@@ -0,0 +1,460 @@
+//! Route scripts: small [Rhai](https://rhai.rs) scripts that a route runs at phases of its
+//! requests, for edge logic that doesn't warrant a plugin (see `plugin`) or a new release.
+//!
+//! A script defines a function for each phase it handles:
+//!
+//! ```rhai
+//! fn on_request(req) {
+//!     if req.path.starts_with("/beta/") {
+//!         req.origin = "beta.example.com";
+//!     }
+//!     if req.header("authorization") == () {
+//!         req.respond(401, "Sign in first\n");
+//!     }
+//!     req.set_header("x-client-ip", req.client_ip);
+//!     req.cache_ttl = 60;
+//! }
+//!
+//! fn on_response(resp) {
+//!     resp.remove_header("server");
+//! }
+//! ```
+//!
+//! Scripts are sandboxed (no modules, `eval`, or access to anything but the request and response)
+//! and each call has a budget of operations and time.  A script that fails or runs out of budget
+//! fails the request.
+
+use http::header::{HeaderMap, HeaderName, HeaderValue};
+use log::debug;
+use once_cell::sync::Lazy;
+use pingora::http::{RequestHeader, ResponseHeader};
+use rhai::module_resolvers::DummyModuleResolver;
+use rhai::{CallFnOptions, Dynamic, Engine, EvalAltResult, Scope, AST};
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::cell::Cell;
+use std::fmt;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// The engine running all the scripts.  The budget of the script being run is kept per thread.
+static ENGINE: Lazy<Engine> = Lazy::new(new_engine);
+
+thread_local! {
+    /// The operation limit and deadline of the script being run on this thread.
+    static BUDGET: Cell<Option<(u64, Instant)>> = const { Cell::new(None) };
+}
+
+/// How often (in operations) a running script's deadline is checked.
+const DEADLINE_CHECK_INTERVAL: u64 = 256;
+
+/// A script attached to a route.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct ScriptConfig {
+    /// The script's source.
+    pub source: Script,
+
+    /// The maximum number of operations in each call of the script.
+    #[serde(default = "default_max_operations")]
+    pub max_operations: u64,
+
+    /// The maximum time (in milliseconds) each call of the script may take.
+    #[serde(default = "default_timeout")]
+    pub timeout: u64,
+}
+
+fn default_max_operations() -> u64 {
+    100_000
+}
+
+fn default_timeout() -> u64 {
+    5
+}
+
+/// A compiled script, (de)serialized as its source so that invalid scripts are rejected when a
+/// route is added rather than when it's used.
+#[derive(Clone)]
+pub struct Script {
+    source: String,
+    ast: Arc<AST>,
+}
+
+impl Script {
+    pub fn compile(source: &str) -> Result<Self, String> {
+        let ast = ENGINE.compile(source).map_err(|e| e.to_string())?;
+        Ok(Script {
+            source: source.to_string(),
+            ast: Arc::new(ast),
+        })
+    }
+
+    fn defines(&self, function: &str) -> bool {
+        self.ast.iter_functions().any(|f| f.name == function)
+    }
+}
+
+impl fmt::Debug for Script {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("Script").field(&self.source).finish()
+    }
+}
+
+impl PartialEq for Script {
+    fn eq(&self, other: &Self) -> bool {
+        self.source == other.source
+    }
+}
+
+impl Eq for Script {}
+
+impl Serialize for Script {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.source)
+    }
+}
+
+impl<'de> Deserialize<'de> for Script {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let source = String::deserialize(deserializer)?;
+        Script::compile(&source).map_err(serde::de::Error::custom)
+    }
+}
+
+/// A change a script (or a WebAssembly filter) made to the headers.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum HeaderEdit {
+    Set(HeaderName, HeaderValue),
+    Append(HeaderName, HeaderValue),
+    Remove(HeaderName),
+}
+
+impl HeaderEdit {
+    pub fn apply_to_request(&self, req: &mut RequestHeader) -> pingora::Result<()> {
+        match self {
+            HeaderEdit::Set(name, value) => req.insert_header(name.clone(), value.clone()),
+            HeaderEdit::Append(name, value) => {
+                req.append_header(name.clone(), value.clone())?;
+                Ok(())
+            }
+            HeaderEdit::Remove(name) => {
+                req.remove_header(name);
+                Ok(())
+            }
+        }
+    }
+
+    pub fn apply_to_response(&self, resp: &mut ResponseHeader) -> pingora::Result<()> {
+        match self {
+            HeaderEdit::Set(name, value) => resp.insert_header(name.clone(), value.clone()),
+            HeaderEdit::Append(name, value) => {
+                resp.append_header(name.clone(), value.clone())?;
+                Ok(())
+            }
+            HeaderEdit::Remove(name) => {
+                resp.remove_header(name);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Headers as seen by a script, and the changes it made to them.
+#[derive(Debug, Default, Clone)]
+pub struct ScriptHeaders {
+    headers: HeaderMap,
+    pub edits: Vec<HeaderEdit>,
+}
+
+impl ScriptHeaders {
+    pub fn new(headers: HeaderMap) -> Self {
+        ScriptHeaders {
+            headers,
+            edits: Vec::new(),
+        }
+    }
+
+    fn get(&self, name: &str) -> Dynamic {
+        self.headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map_or(Dynamic::UNIT, |value| value.into())
+    }
+
+    fn set(&mut self, name: &str, value: &str) -> Result<(), Box<EvalAltResult>> {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("Invalid header name: {name}"))?;
+        let value =
+            HeaderValue::from_str(value).map_err(|_| format!("Invalid header value: {value}"))?;
+        self.headers.insert(name.clone(), value.clone());
+        self.edits.push(HeaderEdit::Set(name, value));
+        Ok(())
+    }
+
+    fn remove(&mut self, name: &str) -> Result<(), Box<EvalAltResult>> {
+        let name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| format!("Invalid header name: {name}"))?;
+        self.headers.remove(&name);
+        self.edits.push(HeaderEdit::Remove(name));
+        Ok(())
+    }
+}
+
+/// A request as seen by a script's `on_request`, and what the script decided.
+#[derive(Debug, Default, Clone)]
+pub struct ScriptRequest {
+    pub method: String,
+    pub path: String,
+    pub query: String,
+    pub host: String,
+    pub client_ip: String,
+
+    /// The client's country and region (if the server has a GeoIP database and they're known).
+    pub country: Option<String>,
+    pub region: Option<String>,
+
+    pub headers: ScriptHeaders,
+
+    /// The origin (by host) chosen from the route's origin group.
+    pub origin: Option<String>,
+
+    /// How long (in seconds) to cache the response, regardless of its cache headers.  Zero means
+    /// not at all.
+    pub cache_ttl: Option<u64>,
+
+    /// The response (status, body) to send instead of forwarding the request.
+    pub response: Option<(u16, String)>,
+}
+
+/// A response as seen by a script's `on_response`, and the changes the script made to it.
+#[derive(Debug, Default, Clone)]
+pub struct ScriptResponse {
+    pub status: u16,
+    pub headers: ScriptHeaders,
+}
+
+/// A request or response shared with a running script.
+#[derive(Clone)]
+struct Handle<T>(Arc<Mutex<T>>);
+
+impl<T> Handle<T> {
+    fn lock(&self) -> MutexGuard<'_, T> {
+        self.0.lock().unwrap()
+    }
+}
+
+impl ScriptConfig {
+    /// Run the script's `on_request` (if it has one) on the request.
+    pub fn on_request(&self, req: ScriptRequest) -> Result<ScriptRequest, String> {
+        self.call("on_request", req)
+    }
+
+    /// Run the script's `on_response` (if it has one) on the response.
+    pub fn on_response(&self, resp: ScriptResponse) -> Result<ScriptResponse, String> {
+        self.call("on_response", resp)
+    }
+
+    fn call<T: Clone + Send + Sync + 'static>(&self, function: &str, arg: T) -> Result<T, String> {
+        if !self.source.defines(function) {
+            return Ok(arg);
+        }
+        let handle = Handle(Arc::new(Mutex::new(arg)));
+        let deadline = Instant::now() + Duration::from_millis(self.timeout);
+        BUDGET.with(|budget| budget.set(Some((self.max_operations, deadline))));
+        let result = ENGINE.call_fn_with_options::<Dynamic>(
+            CallFnOptions::new().eval_ast(false),
+            &mut Scope::new(),
+            &self.source.ast,
+            function,
+            (Dynamic::from(handle.clone()),),
+        );
+        BUDGET.with(|budget| budget.set(None));
+
+        if let Err(e) = result {
+            return Err(format!("{function}: {e}"));
+        }
+        let arg = handle.lock().clone();
+        Ok(arg)
+    }
+}
+
+fn new_engine() -> Engine {
+    let mut engine = Engine::new();
+    engine
+        .set_module_resolver(DummyModuleResolver::new())
+        .disable_symbol("eval")
+        .set_max_call_levels(16)
+        .set_max_expr_depths(64, 32)
+        .set_max_string_size(64 * 1024)
+        .set_max_array_size(1024)
+        .set_max_map_size(1024)
+        .on_print(|s| debug!("Script: {s}"))
+        .on_debug(|s, _, _| debug!("Script: {s}"))
+        .on_progress(|operations| {
+            let (max_operations, deadline) = BUDGET.with(Cell::get)?;
+            if operations > max_operations {
+                Some("Operation budget exceeded".into())
+            } else if operations % DEADLINE_CHECK_INTERVAL == 0 && Instant::now() > deadline {
+                Some("Time budget exceeded".into())
+            } else {
+                None
+            }
+        });
+
+    engine
+        .register_type_with_name::<Handle<ScriptRequest>>("Request")
+        .register_get("method", |r: &mut Handle<ScriptRequest>| {
+            r.lock().method.clone()
+        })
+        .register_get("path", |r: &mut Handle<ScriptRequest>| {
+            r.lock().path.clone()
+        })
+        .register_get("query", |r: &mut Handle<ScriptRequest>| {
+            r.lock().query.clone()
+        })
+        .register_get("host", |r: &mut Handle<ScriptRequest>| {
+            r.lock().host.clone()
+        })
+        .register_get("client_ip", |r: &mut Handle<ScriptRequest>| {
+            r.lock().client_ip.clone()
+        })
+        .register_get("country", |r: &mut Handle<ScriptRequest>| {
+            r.lock()
+                .country
+                .clone()
+                .map_or(Dynamic::UNIT, Dynamic::from)
+        })
+        .register_get("region", |r: &mut Handle<ScriptRequest>| {
+            r.lock().region.clone().map_or(Dynamic::UNIT, Dynamic::from)
+        })
+        .register_fn("header", |r: &mut Handle<ScriptRequest>, name: &str| {
+            r.lock().headers.get(name)
+        })
+        .register_fn(
+            "set_header",
+            |r: &mut Handle<ScriptRequest>, name: &str, value: &str| {
+                r.lock().headers.set(name, value)
+            },
+        )
+        .register_fn(
+            "remove_header",
+            |r: &mut Handle<ScriptRequest>, name: &str| r.lock().headers.remove(name),
+        )
+        .register_set("origin", |r: &mut Handle<ScriptRequest>, host: &str| {
+            r.lock().origin = Some(host.to_string());
+        })
+        .register_set(
+            "cache_ttl",
+            |r: &mut Handle<ScriptRequest>, ttl: i64| -> Result<(), Box<EvalAltResult>> {
+                let ttl = u64::try_from(ttl).map_err(|_| format!("Invalid cache TTL: {ttl}"))?;
+                r.lock().cache_ttl = Some(ttl);
+                Ok(())
+            },
+        )
+        .register_fn(
+            "respond",
+            |r: &mut Handle<ScriptRequest>,
+             status: i64,
+             body: &str|
+             -> Result<(), Box<EvalAltResult>> {
+                if !(100..=599).contains(&status) {
+                    return Err(format!("Invalid status: {status}").into());
+                }
+                r.lock().response = Some((status as u16, body.to_string()));
+                Ok(())
+            },
+        );
+
+    engine
+        .register_type_with_name::<Handle<ScriptResponse>>("Response")
+        .register_get("status", |r: &mut Handle<ScriptResponse>| {
+            r.lock().status as i64
+        })
+        .register_fn("header", |r: &mut Handle<ScriptResponse>, name: &str| {
+            r.lock().headers.get(name)
+        })
+        .register_fn(
+            "set_header",
+            |r: &mut Handle<ScriptResponse>, name: &str, value: &str| {
+                r.lock().headers.set(name, value)
+            },
+        )
+        .register_fn(
+            "remove_header",
+            |r: &mut Handle<ScriptResponse>, name: &str| r.lock().headers.remove(name),
+        );
+
+    engine
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn script(source: &str) -> ScriptConfig {
+        ScriptConfig {
+            source: Script::compile(source).unwrap(),
+            max_operations: default_max_operations(),
+            timeout: 1000,
+        }
+    }
+
+    #[test]
+    fn request_changes() {
+        let script = script(
+            r#"
+            fn on_request(req) {
+                if req.path.starts_with("/beta/") {
+                    req.origin = "beta.example.com";
+                }
+                req.set_header("x-method", req.method);
+                req.remove_header("cookie");
+                req.cache_ttl = 60;
+                if req.header("authorization") == () {
+                    req.respond(401, "Sign in first");
+                }
+            }
+            "#,
+        );
+        let mut headers = HeaderMap::new();
+        headers.insert("cookie", HeaderValue::from_static("a=1"));
+        let req = ScriptRequest {
+            method: "GET".to_string(),
+            path: "/beta/index.html".to_string(),
+            headers: ScriptHeaders::new(headers),
+            ..Default::default()
+        };
+
+        let req = script.on_request(req).unwrap();
+        assert_eq!(req.origin.as_deref(), Some("beta.example.com"));
+        assert_eq!(req.cache_ttl, Some(60));
+        assert_eq!(req.response, Some((401, "Sign in first".to_string())));
+        assert_eq!(
+            req.headers.edits,
+            [
+                HeaderEdit::Set(
+                    HeaderName::from_static("x-method"),
+                    HeaderValue::from_static("GET")
+                ),
+                HeaderEdit::Remove(HeaderName::from_static("cookie")),
+            ]
+        );
+
+        // A script without `on_response` leaves the response alone.
+        let resp = script.on_response(ScriptResponse::default()).unwrap();
+        assert!(resp.headers.edits.is_empty());
+    }
+
+    #[test]
+    fn budgets_and_sandbox() {
+        let mut looping = script("fn on_request(req) { loop {} }");
+        assert!(looping.on_request(ScriptRequest::default()).is_err());
+        looping.max_operations = u64::MAX;
+        looping.timeout = 10;
+        let start = Instant::now();
+        assert!(looping.on_request(ScriptRequest::default()).is_err());
+        assert!(start.elapsed() < Duration::from_secs(1));
+
+        let importing = script(r#"fn on_request(req) { import "os" as os; }"#);
+        assert!(importing.on_request(ScriptRequest::default()).is_err());
+        assert!(Script::compile(r#"fn on_request(req) { eval("1") }"#).is_err());
+    }
+}
@@ -0,0 +1,160 @@
+//! Secrets for upstream requests (e.g., origin credentials).  Secrets are defined in the static
+//! configuration by name, with where to read them from, so routes only refer to them by name and
+//! their values never go through the Config API (or its replication and audit trail).
+//!
+//! A route adds headers to its upstream requests with values that can refer to secrets as
+//! `${name}`:
+//!
+//! ```json
+//! "upstream_headers": [{"name": "authorization", "value": "Bearer ${origin-token}"}]
+//! ```
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fs;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// How long a secret read from a file is cached before the file is read again.
+const FILE_REFRESH_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Where to read a secret from, written as `env:<variable>` or `file:<path>`.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(try_from = "String", into = "String")]
+pub enum SecretSource {
+    /// An environment variable.
+    Env(String),
+
+    /// A file (e.g., a mounted Kubernetes or Docker secret).  Surrounding whitespace is trimmed.
+    File(String),
+}
+
+impl TryFrom<String> for SecretSource {
+    type Error = String;
+
+    fn try_from(source: String) -> Result<Self, Self::Error> {
+        match source.split_once(':') {
+            Some(("env", var)) if !var.is_empty() => Ok(SecretSource::Env(var.to_string())),
+            Some(("file", path)) if !path.is_empty() => Ok(SecretSource::File(path.to_string())),
+            _ => Err(format!(
+                "Invalid secret source '{source}' (expected env:<variable> or file:<path>)"
+            )),
+        }
+    }
+}
+
+impl From<SecretSource> for String {
+    fn from(source: SecretSource) -> Self {
+        match source {
+            SecretSource::Env(var) => format!("env:{var}"),
+            SecretSource::File(path) => format!("file:{path}"),
+        }
+    }
+}
+
+/// The secrets available to routes, by name.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+#[serde(transparent)]
+pub struct SecretsConfig(pub HashMap<String, SecretSource>);
+
+/// A header added to the requests sent to the origin.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct UpstreamHeader {
+    /// The header's name.  It replaces any header with the same name.
+    pub name: String,
+
+    /// The header's value, where `${name}` stands for the secret with the name.
+    pub value: String,
+}
+
+/// Resolves secrets, caching the ones read from files.
+pub struct SecretStore {
+    sources: HashMap<String, SecretSource>,
+    files: Mutex<HashMap<String, (String, Instant)>>,
+}
+
+impl SecretStore {
+    pub fn new(config: &SecretsConfig) -> Self {
+        SecretStore {
+            sources: config.0.clone(),
+            files: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// The value of a secret (`None` if it isn't defined or can't be read).
+    pub fn get(&self, name: &str) -> Option<String> {
+        let path = match self.sources.get(name)? {
+            SecretSource::Env(var) => return std::env::var(var).ok(),
+            SecretSource::File(path) => path,
+        };
+
+        let mut cache = self.files.lock().unwrap();
+        if let Some((value, loaded)) = cache.get(name) {
+            if loaded.elapsed() < FILE_REFRESH_INTERVAL {
+                return Some(value.clone());
+            }
+        }
+        match fs::read_to_string(path) {
+            Ok(contents) => {
+                let value = contents.trim().to_string();
+                cache.insert(name.to_string(), (value.clone(), Instant::now()));
+                Some(value)
+            }
+            // Keep using the previously read value (if any) if the file became unreadable.
+            Err(e) => {
+                warn!("Unable to read secret '{name}' from {path}: {e}");
+                cache.get(name).map(|(value, _)| value.clone())
+            }
+        }
+    }
+
+    /// Replace the references to secrets (`${name}`) in a value.
+    pub fn render(&self, value: &str) -> Result<String, String> {
+        let mut rendered = String::with_capacity(value.len());
+        let mut rest = value;
+        while let Some(start) = rest.find("${") {
+            let Some(len) = rest[start + 2..].find('}') else {
+                break;
+            };
+            let name = &rest[start + 2..start + 2 + len];
+            let secret = self
+                .get(name)
+                .ok_or_else(|| format!("Secret '{name}' isn't available"))?;
+            rendered.push_str(&rest[..start]);
+            rendered.push_str(&secret);
+            rest = &rest[start + 3 + len..];
+        }
+        rendered.push_str(rest);
+        Ok(rendered)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn render() {
+        let path = std::env::temp_dir().join(format!("granite-secret-{}", std::process::id()));
+        fs::write(&path, "s3cr3t\n").unwrap();
+        std::env::set_var("GRANITE_TEST_API_KEY", "key-1");
+        let config: SecretsConfig = serde_yaml::from_str(&format!(
+            "token: file:{}\napi-key: env:GRANITE_TEST_API_KEY\nmissing: env:GRANITE_TEST_UNSET",
+            path.display()
+        ))
+        .unwrap();
+        let store = SecretStore::new(&config);
+        assert!(serde_yaml::from_str::<SecretsConfig>("token: vault:x").is_err());
+
+        assert_eq!(store.render("Bearer ${token}").unwrap(), "Bearer s3cr3t");
+        assert_eq!(store.render("${api-key}:${token}").unwrap(), "key-1:s3cr3t");
+        assert_eq!(store.render("plain ${").unwrap(), "plain ${");
+        assert!(store.render("${missing}").is_err());
+        assert!(store.render("${undefined}").is_err());
+
+        // The cached value outlives the file.
+        fs::remove_file(&path).unwrap();
+        assert_eq!(store.get("token").unwrap(), "s3cr3t");
+    }
+}
@@ -0,0 +1,136 @@
+//! Security response headers added by the proxy on behalf of the origin, so customers get a secure
+//! baseline without changing their origins.
+
+use pingora::http::ResponseHeader;
+use pingora::Result;
+use serde::{Deserialize, Serialize};
+
+/// Strict-Transport-Security settings.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct HstsConfig {
+    /// How long (in seconds) browsers should only use HTTPS for the host.
+    pub max_age: u64,
+
+    /// Whether the policy also applies to subdomains.
+    pub include_subdomains: bool,
+
+    /// Whether the host may be added to browsers' HSTS preload lists.
+    pub preload: bool,
+}
+
+impl Default for HstsConfig {
+    /// By default, HSTS applies for one year, to the host only.
+    fn default() -> Self {
+        HstsConfig {
+            max_age: 31_536_000,
+            include_subdomains: false,
+            preload: false,
+        }
+    }
+}
+
+impl HstsConfig {
+    fn header_value(&self) -> String {
+        let mut value = format!("max-age={}", self.max_age);
+        if self.include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        if self.preload {
+            value.push_str("; preload");
+        }
+        value
+    }
+}
+
+/// The security headers to add to responses for a route.  Headers that aren't set are passed
+/// through from the origin unchanged.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+#[serde(default)]
+pub struct SecurityHeadersPolicy {
+    /// Strict-Transport-Security (only sent on HTTPS responses).
+    pub hsts: Option<HstsConfig>,
+
+    /// Whether to send `X-Content-Type-Options: nosniff`.
+    pub content_type_options: bool,
+
+    /// The Referrer-Policy value (e.g., `strict-origin-when-cross-origin`).
+    pub referrer_policy: Option<String>,
+
+    /// The X-Frame-Options value (e.g., `DENY` or `SAMEORIGIN`).
+    pub frame_options: Option<String>,
+
+    /// The Content-Security-Policy value.
+    pub content_security_policy: Option<String>,
+
+    /// Whether to replace headers the origin already sent.  If false, the origin's headers take
+    /// precedence and the policy only fills in missing headers.
+    pub override_origin: bool,
+}
+
+impl SecurityHeadersPolicy {
+    /// Add the policy's headers to the response.  `https` is whether the client connected over
+    /// HTTPS.
+    pub fn apply(&self, resp: &mut ResponseHeader, https: bool) -> Result<()> {
+        if https {
+            if let Some(hsts) = &self.hsts {
+                self.set(resp, "strict-transport-security", hsts.header_value())?;
+            }
+        }
+        if self.content_type_options {
+            self.set(resp, "x-content-type-options", "nosniff".to_string())?;
+        }
+        if let Some(value) = &self.referrer_policy {
+            self.set(resp, "referrer-policy", value.clone())?;
+        }
+        if let Some(value) = &self.frame_options {
+            self.set(resp, "x-frame-options", value.clone())?;
+        }
+        if let Some(value) = &self.content_security_policy {
+            self.set(resp, "content-security-policy", value.clone())?;
+        }
+        Ok(())
+    }
+
+    fn set(&self, resp: &mut ResponseHeader, name: &'static str, value: String) -> Result<()> {
+        if self.override_origin || !resp.headers.contains_key(name) {
+            resp.insert_header(name, value)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply() {
+        let policy = SecurityHeadersPolicy {
+            hsts: Some(HstsConfig {
+                include_subdomains: true,
+                preload: true,
+                ..Default::default()
+            }),
+            content_type_options: true,
+            frame_options: Some("DENY".to_string()),
+            ..Default::default()
+        };
+
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        resp.insert_header("x-frame-options", "SAMEORIGIN").unwrap();
+        policy.apply(&mut resp, true).unwrap();
+        assert_eq!(
+            resp.headers["strict-transport-security"],
+            "max-age=31536000; includeSubDomains; preload"
+        );
+        assert_eq!(resp.headers["x-content-type-options"], "nosniff");
+        // The origin's header is passed through.
+        assert_eq!(resp.headers["x-frame-options"], "SAMEORIGIN");
+
+        // No HSTS over plain HTTP.
+        let mut resp = ResponseHeader::build(200, None).unwrap();
+        policy.apply(&mut resp, false).unwrap();
+        assert!(!resp.headers.contains_key("strict-transport-security"));
+    }
+}
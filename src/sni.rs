@@ -0,0 +1,49 @@
+//! The SNI clients send in their TLS handshake, for routes matched on it rather than on the `Host`
+//! header (some clients send a `Host` header that doesn't match the hostname they connected to).
+//!
+//! Like TLS fingerprints (see `tls_fingerprint`), the SNI is kept with the connection and handed
+//! over to the requests of HTTP/1.1 connections by task.  HTTP/2 requests are handled in tasks of
+//! their own, so the proxy doesn't look their SNI up.  Instead, connections for hosts with routes
+//! matched on the SNI only negotiate HTTP/1.1 (see `protocols`).
+
+use once_cell::sync::Lazy;
+use openssl::ex_data::Index;
+use pingora::tls::ssl::{NameType, Ssl, SslRef};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::tls_fingerprint::task_id;
+
+/// When the number of tracked connections exceeds this, closed ones are pruned.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Where each connection's SNI is kept (it lives as long as the connection).
+static EX_INDEX: Lazy<Index<Ssl, Arc<str>>> =
+    Lazy::new(|| Ssl::new_ex_index().expect("Unable to allocate a TLS ex data index"));
+
+/// The SNI of open connections by the task handling them.
+static BY_TASK: Lazy<Mutex<HashMap<usize, Weak<str>>>> = Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Keep the connection's SNI (if any) and hand it over to the requests handled by the current
+/// task.  Called during the handshake, in the certificate callback.
+pub async fn register(ssl: &mut SslRef) {
+    let Some(sni) = ssl.servername(NameType::HOST_NAME) else {
+        return;
+    };
+    let sni: Arc<str> = Arc::from(sni.to_ascii_lowercase());
+    let weak = Arc::downgrade(&sni);
+    ssl.set_ex_data(*EX_INDEX, sni);
+    let id = task_id().await;
+    let mut by_task = BY_TASK.lock().unwrap();
+    if by_task.len() > PRUNE_THRESHOLD {
+        by_task.retain(|_, sni| sni.strong_count() > 0);
+    }
+    by_task.insert(id, weak);
+}
+
+/// The SNI of the connection handled by the current task (if it's an HTTP/1.1 connection over
+/// TLS).
+pub async fn current() -> Option<Arc<str>> {
+    let id = task_id().await;
+    BY_TASK.lock().unwrap().get(&id)?.upgrade()
+}
@@ -0,0 +1,159 @@
+//! A runtime status snapshot of the proxy (loaded routes, origin health, cache utilization, and
+//! uptime), reported through the Config API.
+
+use serde::Serialize;
+use std::sync::Arc;
+use std::time::Instant;
+
+use crate::proxy;
+use crate::route_store::{Route, RouteStore};
+
+/// The state of an origin.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct OriginStatus {
+    pub host: String,
+    pub up: bool,
+    /// Failed connection attempts since the last successful connection.
+    pub consecutive_failures: u32,
+    /// How long (in seconds) the origin has been marked down (if it is).
+    pub down_for: Option<u64>,
+}
+
+/// The state of a route.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct RouteStatus {
+    pub name: String,
+    pub customer: String,
+    pub origins: Vec<OriginStatus>,
+}
+
+/// The cache's utilization.
+#[derive(Serialize, Debug, PartialEq, Eq)]
+pub struct CacheStatus {
+    pub used_bytes: usize,
+    pub max_bytes: usize,
+    pub items: usize,
+    pub evicted_bytes: usize,
+    pub evicted_items: usize,
+}
+
+/// The full status snapshot.
+#[derive(Serialize, Debug)]
+pub struct Status {
+    /// Time (in seconds) since the proxy started.
+    pub uptime: u64,
+    pub routes: Vec<RouteStatus>,
+    pub cache: CacheStatus,
+}
+
+/// Produces status snapshots.
+pub struct StatusReporter {
+    route_store: Arc<RouteStore>,
+    cache_max_size: usize,
+    start: Instant,
+}
+
+impl StatusReporter {
+    pub fn new(route_store: Arc<RouteStore>, cache_max_size: usize) -> Self {
+        StatusReporter {
+            route_store,
+            cache_max_size,
+            start: Instant::now(),
+        }
+    }
+
+    pub fn status(&self) -> Status {
+        let mut routes: Vec<RouteStatus> = self
+            .route_store
+            .routes()
+            .iter()
+            .map(|route| route_status(route))
+            .collect();
+        routes.sort_by(|a, b| a.name.cmp(&b.name));
+
+        let (used_bytes, items, evicted_bytes, evicted_items) = proxy::cache_usage();
+        Status {
+            uptime: self.start.elapsed().as_secs(),
+            routes,
+            cache: CacheStatus {
+                used_bytes,
+                max_bytes: self.cache_max_size,
+                items,
+                evicted_bytes,
+                evicted_items,
+            },
+        }
+    }
+}
+
+fn route_status(route: &Route) -> RouteStatus {
+    let state = route.state.read().unwrap();
+    let origins = route
+        .config
+        .origin_group
+        .origins
+        .iter()
+        .enumerate()
+        .map(|(index, origin)| {
+            let down_since = state.down_endpoints.get(&index);
+            OriginStatus {
+                host: origin.host.clone(),
+                up: down_since.is_none(),
+                consecutive_failures: state
+                    .consecutive_failures
+                    .get(&index)
+                    .copied()
+                    .unwrap_or_default(),
+                down_for: down_since.map(|t| t.elapsed().as_secs()),
+            }
+        })
+        .collect();
+    RouteStatus {
+        name: route.config.name.clone(),
+        customer: route.config.customer.clone(),
+        origins,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::route_config::{Origin, OriginGroup, RouteConfig};
+
+    #[test]
+    fn origin_state() {
+        let origin = |host: &str| -> Arc<Origin> {
+            serde_json::from_value(serde_json::json!({ "host": host })).unwrap()
+        };
+        let route = Route {
+            config: RouteConfig {
+                name: "r1".to_string(),
+                customer: "c1".to_string(),
+                origin_group: OriginGroup {
+                    origins: vec![origin("o1.com"), origin("o2.com")],
+                },
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        route
+            .state
+            .write()
+            .unwrap()
+            .down_endpoints
+            .insert(1, Instant::now());
+        route
+            .state
+            .write()
+            .unwrap()
+            .consecutive_failures
+            .insert(1, 3);
+
+        let status = route_status(&route);
+        assert!(status.origins[0].up);
+        assert_eq!(status.origins[0].down_for, None);
+        assert!(!status.origins[1].up);
+        assert_eq!(status.origins[1].consecutive_failures, 3);
+        assert_eq!(status.origins[1].down_for, Some(0));
+    }
+}
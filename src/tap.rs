@@ -0,0 +1,156 @@
+//! A live request tap: operators can subscribe (through the Config API) to a filtered, sampled
+//! stream of request summaries to debug production behavior without enabling verbose logging.
+//!
+//! Summaries are only built while at least one subscriber is connected.  Slow subscribers skip
+//! summaries rather than slowing down the proxy.
+
+use regex::Regex;
+use serde::Serialize;
+use std::collections::BTreeMap;
+use tokio::sync::broadcast;
+
+/// The number of summaries buffered for each subscriber.
+const TAP_BUFFER_SIZE: usize = 1024;
+
+/// A summary of a finished request.
+#[derive(Serialize, Debug, Clone)]
+pub struct RequestSummary {
+    /// When the request finished (milliseconds since the Unix epoch).
+    pub timestamp: u64,
+    pub request_id: String,
+    pub client_ip: Option<String>,
+    pub route: Option<String>,
+    pub customer: Option<String>,
+    pub method: String,
+    pub host: String,
+    pub path: String,
+    pub status: u16,
+    pub duration_ms: f64,
+    pub cache_status: Option<&'static str>,
+    pub response_bytes: u64,
+    /// The headers, with sensitive values redacted.
+    pub request_headers: BTreeMap<String, String>,
+    pub response_headers: BTreeMap<String, String>,
+}
+
+/// Criteria a subscriber uses to select summaries.
+#[derive(Debug, Default)]
+pub struct TapFilter {
+    /// Only requests matching this route.
+    pub route: Option<String>,
+    /// Only responses with this status.
+    pub status: Option<u16>,
+    /// Only requests whose path matches this regular expression.
+    pub path: Option<Regex>,
+    /// The fraction (0 to 1) of matching requests to report.
+    pub sample: f64,
+}
+
+impl TapFilter {
+    /// Parse a filter from a query string (e.g., `route=r1&status=502&path=^/api&sample=0.1`).
+    pub fn from_query(query: &str) -> Result<Self, String> {
+        let mut filter = TapFilter {
+            sample: 1.0,
+            ..Default::default()
+        };
+        for param in query.split('&').filter(|p| !p.is_empty()) {
+            let (name, value) = param.split_once('=').unwrap_or((param, ""));
+            let value = String::from_utf8(crate::utils::percent_decode(value))
+                .map_err(|_| format!("Invalid value for {name}"))?;
+            match name {
+                "route" => filter.route = Some(value),
+                "status" => {
+                    filter.status = Some(value.parse().map_err(|_| "Invalid status")?);
+                }
+                "path" => {
+                    filter.path = Some(Regex::new(&value).map_err(|e| e.to_string())?);
+                }
+                "sample" => {
+                    filter.sample = value.parse().map_err(|_| "Invalid sample rate")?;
+                }
+                _ => return Err(format!("Unknown parameter {name}")),
+            }
+        }
+        Ok(filter)
+    }
+
+    /// Whether the summary should be reported to the subscriber.
+    pub fn matches(&self, summary: &RequestSummary) -> bool {
+        if let Some(route) = &self.route {
+            if summary.route.as_ref() != Some(route) {
+                return false;
+            }
+        }
+        if self.status.is_some_and(|s| s != summary.status) {
+            return false;
+        }
+        if let Some(path) = &self.path {
+            if !path.is_match(&summary.path) {
+                return false;
+            }
+        }
+        self.sample >= 1.0 || rand::random::<f64>() < self.sample
+    }
+}
+
+/// Distributes request summaries to subscribers.
+pub struct RequestTap {
+    sender: broadcast::Sender<RequestSummary>,
+}
+
+impl RequestTap {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(TAP_BUFFER_SIZE);
+        RequestTap { sender }
+    }
+
+    /// Whether anyone is subscribed (i.e., whether summaries should be built).
+    pub fn is_active(&self) -> bool {
+        self.sender.receiver_count() > 0
+    }
+
+    pub fn publish(&self, summary: RequestSummary) {
+        // An error only means that the last subscriber just left.
+        let _ = self.sender.send(summary);
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<RequestSummary> {
+        self.sender.subscribe()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(route: &str, status: u16, path: &str) -> RequestSummary {
+        RequestSummary {
+            timestamp: 0,
+            request_id: "id".to_string(),
+            client_ip: None,
+            route: Some(route.to_string()),
+            customer: None,
+            method: "GET".to_string(),
+            host: "example.com".to_string(),
+            path: path.to_string(),
+            status,
+            duration_ms: 1.0,
+            cache_status: None,
+            response_bytes: 0,
+            request_headers: BTreeMap::new(),
+            response_headers: BTreeMap::new(),
+        }
+    }
+
+    #[test]
+    fn filter() {
+        let filter = TapFilter::from_query("route=r1&status=502&path=%5E%2Fapi").unwrap();
+        assert!(filter.matches(&summary("r1", 502, "/api/users")));
+        assert!(!filter.matches(&summary("r2", 502, "/api/users")));
+        assert!(!filter.matches(&summary("r1", 200, "/api/users")));
+        assert!(!filter.matches(&summary("r1", 502, "/static/api")));
+
+        assert!(TapFilter::from_query("path=(").is_err());
+        assert!(TapFilter::from_query("bogus=1").is_err());
+    }
+}
@@ -0,0 +1,1507 @@
+//! A harness for end-to-end tests: a granite server running in this process on ephemeral ports,
+//! mock origin servers, and a small blocking HTTP client for the proxy and the Config API.
+//!
+//! It is compiled for the crate's own tests, and for embedding binaries with the `testing` feature.
+//!
+//! ```ignore
+//! let origin = MockOrigin::start(|_| MockResponse::new(200, "hello"));
+//! let server = TestServer::start(AppConfig::default());
+//! server.add_route(serde_json::json!({ ... "origin_group": {"origins": [origin.origin()]} }));
+//! assert_eq!(server.get("example.com", "/").text(), "hello");
+//! ```
+//!
+//! Servers share the process's cache (whose keys don't include the host), so tests should use
+//! hosts and paths of their own.
+
+use openssl::asn1::Asn1Time;
+use openssl::hash::MessageDigest;
+use openssl::pkey::PKey;
+use openssl::rsa::Rsa;
+use openssl::ssl::{SslConnector, SslMethod, SslVerifyMode};
+use openssl::x509::{X509NameBuilder, X509};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use crate::app::GraniteBuilder;
+use crate::app_config::AppConfig;
+use crate::cert::cert_store::CertStore;
+use crate::route_store::RouteStore;
+
+/// How long to wait for a server to start listening.
+const START_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// An address on the loopback interface that nothing listens on (yet).
+pub fn free_addr() -> SocketAddr {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind an ephemeral port");
+    listener.local_addr().unwrap()
+}
+
+/// Wait for something to listen on an address.
+fn wait_for(addr: SocketAddr) {
+    let start = Instant::now();
+    while TcpStream::connect(addr).is_err() {
+        assert!(
+            start.elapsed() < START_TIMEOUT,
+            "Nothing listening on {addr}"
+        );
+        thread::sleep(Duration::from_millis(20));
+    }
+}
+
+/// A granite server running in this process.  (It runs until the process exits.)
+pub struct TestServer {
+    /// The proxy's HTTP listener.
+    pub http_addr: SocketAddr,
+
+    /// The proxy's HTTPS listener.
+    pub https_addr: SocketAddr,
+
+    /// The Config API's listener.
+    pub api_addr: SocketAddr,
+
+    route_store: Arc<RouteStore>,
+    cert_store: Arc<CertStore>,
+}
+
+impl TestServer {
+    /// Start a server with the configuration, on ephemeral ports (the configured listener
+    /// addresses are replaced, and the metrics exporter is disabled).
+    pub fn start(mut conf: AppConfig) -> Self {
+        let (http_addr, https_addr, api_addr) = (free_addr(), free_addr(), free_addr());
+        conf.proxy.http_bind_addrs = vec![http_addr.to_string()];
+        conf.proxy.https_bind_addrs = vec![https_addr.to_string()];
+        conf.api.bind_addr = api_addr.to_string();
+        conf.metrics.bind_addr = None;
+        conf.server.threads.get_or_insert(1);
+
+        let granite = GraniteBuilder::new(conf)
+            .build()
+            .expect("Failed to set up the server");
+        let route_store = granite.route_store().clone();
+        let cert_store = granite.cert_store().clone();
+        thread::spawn(move || granite.run_forever());
+        for addr in [http_addr, https_addr, api_addr] {
+            wait_for(addr);
+        }
+
+        TestServer {
+            http_addr,
+            https_addr,
+            api_addr,
+            route_store,
+            cert_store,
+        }
+    }
+
+    /// The routes the proxy serves.
+    pub fn route_store(&self) -> &Arc<RouteStore> {
+        &self.route_store
+    }
+
+    /// The certificates the proxy serves over HTTPS.
+    pub fn cert_store(&self) -> &Arc<CertStore> {
+        &self.cert_store
+    }
+
+    /// Send a request to the Config API (a POST if there is a body).
+    pub fn api(&self, path: &str, body: Option<&serde_json::Value>) -> TestResponse {
+        let request = match body {
+            Some(body) => TestRequest::new("POST", "api", path).body(body.to_string()),
+            None => TestRequest::new("GET", "api", path),
+        };
+        request
+            .send(self.api_addr)
+            .expect("Config API request failed")
+    }
+
+    /// Add a route through the Config API, and check it was accepted.
+    pub fn add_route(&self, route: serde_json::Value) {
+        let resp = self.api("/route/add", Some(&route));
+        assert_eq!(resp.status, 200, "Route rejected: {}", resp.text());
+    }
+
+    /// Bind a certificate for the host through the Config API, and check it was accepted.
+    pub fn add_cert(&self, host: &str, cert: &TestCert) {
+        let binding = serde_json::json!({
+            "host": host,
+            "cert": cert.cert_pem,
+            "key": cert.key_pem,
+        });
+        let resp = self.api("/cert/add", Some(&binding));
+        assert_eq!(resp.status, 200, "Certificate rejected: {}", resp.text());
+    }
+
+    /// Send a GET request to the proxy over HTTP.
+    pub fn get(&self, host: &str, path: &str) -> TestResponse {
+        self.send(TestRequest::new("GET", host, path))
+    }
+
+    /// Send a request to the proxy over HTTP.
+    pub fn send(&self, request: TestRequest) -> TestResponse {
+        request.send(self.http_addr).expect("Proxy request failed")
+    }
+
+    /// Send a request to the proxy over HTTPS, with the request's host as the SNI (the server's
+    /// certificate isn't verified).  Return the response and the certificate the proxy presented.
+    pub fn send_tls(&self, request: TestRequest) -> (TestResponse, Option<X509>) {
+        let mut connector = SslConnector::builder(SslMethod::tls_client()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        let tcp = TcpStream::connect(self.https_addr).expect("Failed to connect to the proxy");
+        let mut tls = connector
+            .build()
+            .connect(&request.host, tcp)
+            .expect("TLS handshake failed");
+        let cert = tls.ssl().peer_certificate();
+        let resp = request.send_on(&mut tls).expect("Proxy request failed");
+        (resp, cert)
+    }
+}
+
+/// A request sent by the harness's client.  It asks the server to close the connection after the
+/// response.
+#[derive(Debug, Clone)]
+pub struct TestRequest {
+    pub method: String,
+    pub host: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+    /// Whether the body is sent chunked (rather than with a `Content-Length`).
+    pub chunked: bool,
+}
+
+impl TestRequest {
+    pub fn new(method: &str, host: &str, path: &str) -> Self {
+        TestRequest {
+            method: method.to_string(),
+            host: host.to_string(),
+            path: path.to_string(),
+            headers: Vec::new(),
+            body: Vec::new(),
+            chunked: false,
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    pub fn body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Send the body chunked (in a single chunk).
+    pub fn chunked(mut self) -> Self {
+        self.chunked = true;
+        self
+    }
+
+    /// Send the request to the address over HTTP.
+    pub fn send(&self, addr: SocketAddr) -> io::Result<TestResponse> {
+        let mut stream = TcpStream::connect(addr)?;
+        stream.set_read_timeout(Some(Duration::from_secs(30)))?;
+        self.send_on(&mut stream)
+    }
+
+    /// Send the request on a connection.
+    pub fn send_on<S: Read + Write>(&self, stream: &mut S) -> io::Result<TestResponse> {
+        let mut head = format!(
+            "{} {} HTTP/1.1\r\nHost: {}\r\n",
+            self.method, self.path, self.host
+        );
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        if self.chunked {
+            head.push_str("Transfer-Encoding: chunked\r\n");
+        } else if !self.body.is_empty() {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        head.push_str("Connection: close\r\n\r\n");
+        stream.write_all(head.as_bytes())?;
+        if self.chunked {
+            if !self.body.is_empty() {
+                write!(stream, "{:x}\r\n", self.body.len())?;
+                stream.write_all(&self.body)?;
+                stream.write_all(b"\r\n")?;
+            }
+            stream.write_all(b"0\r\n\r\n")?;
+        } else {
+            stream.write_all(&self.body)?;
+        }
+        stream.flush()?;
+
+        let mut reader = BufReader::new(stream);
+        let mut status_line = String::new();
+        reader.read_line(&mut status_line)?;
+        let status = status_line
+            .split_whitespace()
+            .nth(1)
+            .and_then(|status| status.parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, status_line.clone()))?;
+        let headers = read_headers(&mut reader)?;
+        let resp = TestResponse {
+            status,
+            headers,
+            body: Vec::new(),
+        };
+        let body = if self.method == "HEAD" || status == 204 || status == 304 {
+            Vec::new()
+        } else if resp
+            .header("transfer-encoding")
+            .is_some_and(|te| te.eq_ignore_ascii_case("chunked"))
+        {
+            read_chunked(&mut reader)?
+        } else if let Some(len) = resp.header("content-length").and_then(|l| l.parse().ok()) {
+            let mut body = vec![0; len];
+            reader.read_exact(&mut body)?;
+            body
+        } else {
+            let mut body = Vec::new();
+            reader.read_to_end(&mut body)?;
+            body
+        };
+        Ok(TestResponse { body, ..resp })
+    }
+}
+
+/// A response received by the harness's client.
+#[derive(Debug, Clone)]
+pub struct TestResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl TestResponse {
+    /// The value of the first header with the name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+
+    /// The body as text.
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).into_owned()
+    }
+}
+
+/// A request received by a mock origin.
+#[derive(Debug, Clone)]
+pub struct MockRequest {
+    pub method: String,
+    pub path: String,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl MockRequest {
+    /// The value of the first header with the name.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        find_header(&self.headers, name)
+    }
+}
+
+/// A response sent by a mock origin.  Its `Content-Length` is set automatically.
+#[derive(Debug, Clone)]
+pub struct MockResponse {
+    pub status: u16,
+    pub headers: Vec<(String, String)>,
+    pub body: Vec<u8>,
+}
+
+impl MockResponse {
+    pub fn new(status: u16, body: impl Into<Vec<u8>>) -> Self {
+        MockResponse {
+            status,
+            headers: Vec::new(),
+            body: body.into(),
+        }
+    }
+
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_string(), value.to_string()));
+        self
+    }
+}
+
+type Handler = dyn Fn(&MockRequest) -> MockResponse + Send + Sync;
+
+/// An origin server answering HTTP/1.1 requests (with `Content-Length` bodies) with a handler, and
+/// recording them.
+pub struct MockOrigin {
+    addr: SocketAddr,
+    requests: Arc<Mutex<Vec<MockRequest>>>,
+}
+
+impl MockOrigin {
+    /// Start an origin on an ephemeral port.  (It runs until the process exits.)
+    pub fn start(handler: impl Fn(&MockRequest) -> MockResponse + Send + Sync + 'static) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Failed to bind an ephemeral port");
+        let addr = listener.local_addr().unwrap();
+        let requests = Arc::new(Mutex::new(Vec::new()));
+        let handler: Arc<Handler> = Arc::new(handler);
+
+        let recorded = requests.clone();
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let (handler, recorded) = (handler.clone(), recorded.clone());
+                thread::spawn(move || {
+                    let _ = serve_connection(stream, &*handler, &recorded);
+                });
+            }
+        });
+        MockOrigin { addr, requests }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    /// The origin's definition, to use in a route's origin group.
+    pub fn origin(&self) -> serde_json::Value {
+        serde_json::json!({"host": self.addr.ip().to_string(), "http_port": self.addr.port()})
+    }
+
+    /// The requests received so far.
+    pub fn requests(&self) -> Vec<MockRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    /// The number of requests received so far.
+    pub fn hits(&self) -> usize {
+        self.requests.lock().unwrap().len()
+    }
+}
+
+/// Serve the requests on a (keep-alive) connection.
+fn serve_connection(
+    stream: TcpStream,
+    handler: &Handler,
+    recorded: &Mutex<Vec<MockRequest>>,
+) -> io::Result<()> {
+    let mut writer = stream.try_clone()?;
+    let mut reader = BufReader::new(stream);
+    loop {
+        let mut request_line = String::new();
+        if reader.read_line(&mut request_line)? == 0 {
+            return Ok(());
+        }
+        let mut parts = request_line.split_whitespace();
+        let (Some(method), Some(path)) = (parts.next(), parts.next()) else {
+            return Ok(());
+        };
+        let headers = read_headers(&mut reader)?;
+        let len = find_header(&headers, "content-length")
+            .and_then(|l| l.parse().ok())
+            .unwrap_or(0);
+        let mut body = vec![0; len];
+        reader.read_exact(&mut body)?;
+        let req = MockRequest {
+            method: method.to_string(),
+            path: path.to_string(),
+            headers,
+            body,
+        };
+        recorded.lock().unwrap().push(req.clone());
+
+        let resp = handler(&req);
+        let mut head = format!("HTTP/1.1 {} Mock\r\n", resp.status);
+        for (name, value) in &resp.headers {
+            head.push_str(&format!("{name}: {value}\r\n"));
+        }
+        head.push_str(&format!("Content-Length: {}\r\n\r\n", resp.body.len()));
+        writer.write_all(head.as_bytes())?;
+        if req.method != "HEAD" {
+            writer.write_all(&resp.body)?;
+        }
+        if req
+            .header("connection")
+            .is_some_and(|c| c.eq_ignore_ascii_case("close"))
+        {
+            return Ok(());
+        }
+    }
+}
+
+/// A self-signed certificate and its key.
+pub struct TestCert {
+    pub cert: X509,
+    pub cert_pem: String,
+    pub key_pem: String,
+}
+
+impl TestCert {
+    /// Create a self-signed certificate for the host (as its common name).
+    pub fn new(host: &str) -> Self {
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", host).unwrap();
+        let name = name.build();
+
+        let mut builder = X509::builder().unwrap();
+        builder.set_version(2).unwrap();
+        builder.set_subject_name(&name).unwrap();
+        builder.set_issuer_name(&name).unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::days_from_now(0).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::days_from_now(1).unwrap())
+            .unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        let cert = builder.build();
+
+        let cert_pem = String::from_utf8(cert.to_pem().unwrap()).unwrap();
+        let key_pem = String::from_utf8(key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+        TestCert {
+            cert,
+            cert_pem,
+            key_pem,
+        }
+    }
+}
+
+fn find_header<'a>(headers: &'a [(String, String)], name: &str) -> Option<&'a str> {
+    headers
+        .iter()
+        .find(|(n, _)| n.eq_ignore_ascii_case(name))
+        .map(|(_, v)| v.as_str())
+}
+
+/// Read header lines up to the empty line ending them.
+fn read_headers(reader: &mut impl BufRead) -> io::Result<Vec<(String, String)>> {
+    let mut headers = Vec::new();
+    loop {
+        let mut line = String::new();
+        if reader.read_line(&mut line)? == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        let line = line.trim_end();
+        if line.is_empty() {
+            return Ok(headers);
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            headers.push((name.trim().to_string(), value.trim().to_string()));
+        }
+    }
+}
+
+/// Read a chunked body (ignoring any trailers).
+fn read_chunked(reader: &mut impl BufRead) -> io::Result<Vec<u8>> {
+    let mut body = Vec::new();
+    loop {
+        let mut size_line = String::new();
+        reader.read_line(&mut size_line)?;
+        let size = size_line.trim().split(';').next().unwrap_or_default();
+        let size = usize::from_str_radix(size, 16)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        if size == 0 {
+            read_headers(reader)?;
+            return Ok(body);
+        }
+        let start = body.len();
+        body.resize(start + size, 0);
+        reader.read_exact(&mut body[start..])?;
+        let mut crlf = [0; 2];
+        reader.read_exact(&mut crlf)?;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cluster;
+    use once_cell::sync::Lazy;
+    use openssl::ssl::{SslSession, SslStream};
+
+    /// One server for all the tests (each with routes of its own).
+    static SERVER: Lazy<TestServer> = Lazy::new(|| TestServer::start(AppConfig::default()));
+
+    fn route(name: &str, origins: Vec<serde_json::Value>) -> serde_json::Value {
+        serde_json::json!({
+            "name": name,
+            "customer": "test",
+            "hosts": [format!("{name}.test")],
+            "paths": ["/"],
+            "incoming_schemes": ["Http", "Https"],
+            "outgoing_scheme": "Http",
+            "origin_group": {"origins": origins},
+        })
+    }
+
+    /// The protocol negotiated with ALPN on a TLS connection to the address with the SNI (offering
+    /// HTTP/2 and HTTP/1.1).
+    fn negotiated(addr: SocketAddr, sni: &str) -> Option<Vec<u8>> {
+        let mut connector = SslConnector::builder(SslMethod::tls_client()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        connector.set_alpn_protos(b"\x02h2\x08http/1.1").unwrap();
+        let tcp = TcpStream::connect(addr).unwrap();
+        let tls = connector.build().connect(sni, tcp).unwrap();
+        tls.ssl().selected_alpn_protocol().map(|p| p.to_vec())
+    }
+
+    /// Send the request to the address over HTTP/2, on a TLS connection with the SNI.
+    fn send_h2(addr: SocketAddr, sni: &str, request: &TestRequest) -> TestResponse {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let mut connector = SslConnector::builder(SslMethod::tls_client()).unwrap();
+            connector.set_verify(SslVerifyMode::NONE);
+            connector.set_alpn_protos(b"\x02h2").unwrap();
+            let ssl = connector
+                .build()
+                .configure()
+                .unwrap()
+                .into_ssl(sni)
+                .unwrap();
+            let tcp = tokio::net::TcpStream::connect(addr).await.unwrap();
+            let mut tls = tokio_openssl::SslStream::new(ssl, tcp).unwrap();
+            std::pin::Pin::new(&mut tls).connect().await.unwrap();
+            assert_eq!(tls.ssl().selected_alpn_protocol(), Some(&b"h2"[..]));
+
+            let (client, connection) = h2::client::handshake(tls).await.unwrap();
+            tokio::spawn(connection);
+            let mut req = http::Request::builder()
+                .method(request.method.as_str())
+                .uri(format!("https://{}{}", request.host, request.path));
+            for (name, value) in &request.headers {
+                req = req.header(name, value);
+            }
+            let mut client = client.ready().await.unwrap();
+            let (resp, _) = client.send_request(req.body(()).unwrap(), true).unwrap();
+            let resp = resp.await.unwrap();
+
+            let status = resp.status().as_u16();
+            let headers = resp
+                .headers()
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_str().unwrap().to_string()))
+                .collect();
+            let mut body = Vec::new();
+            let mut stream = resp.into_body();
+            while let Some(chunk) = stream.data().await {
+                let chunk = chunk.unwrap();
+                stream.flow_control().release_capacity(chunk.len()).unwrap();
+                body.extend_from_slice(&chunk);
+            }
+            TestResponse {
+                status,
+                headers,
+                body,
+            }
+        })
+    }
+
+    #[test]
+    fn caching() {
+        let origin = MockOrigin::start(|req| {
+            MockResponse::new(200, format!("body of {}", req.path))
+                .header("cache-control", "max-age=60")
+        });
+        let mut route = route("caching", vec![origin.origin()]);
+        route["cache"] = true.into();
+        SERVER.add_route(route);
+
+        let resp = SERVER.get("caching.test", "/a");
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.text(), "body of /a");
+        assert_eq!(resp.header("x-cache-status"), Some("miss"));
+
+        let resp = SERVER.get("caching.test", "/a");
+        assert_eq!(resp.text(), "body of /a");
+        assert_eq!(resp.header("x-cache-status"), Some("hit"));
+        assert_eq!(origin.hits(), 1);
+        assert_eq!(origin.requests()[0].header("host"), Some("caching.test"));
+    }
+
+    #[test]
+    fn conditional_requests() {
+        let origin = MockOrigin::start(|_| {
+            MockResponse::new(200, "v1")
+                .header("cache-control", "max-age=60")
+                .header("etag", "\"v1\"")
+        });
+        for (name, policy) in [("conditional", "Validators"), ("unconditional", "Never")] {
+            let mut route = route(name, vec![origin.origin()]);
+            route["cache"] = true.into();
+            route["conditional"] = policy.into();
+            SERVER.add_route(route);
+        }
+        let host = |name: &str| format!("{name}.test");
+        let revalidate = |name: &str| {
+            SERVER.send(
+                TestRequest::new("GET", &host(name), &format!("/{name}"))
+                    .header("if-none-match", "\"v1\""),
+            )
+        };
+
+        assert_eq!(
+            SERVER.get(&host("conditional"), "/conditional").text(),
+            "v1"
+        );
+        let resp = revalidate("conditional");
+        assert_eq!(resp.status, 304);
+        assert_eq!(resp.header("x-cache-status"), Some("hit"));
+
+        // On a miss, the origin sends the full response (which is cached).
+        assert_eq!(revalidate("unconditional").status, 200);
+        assert_eq!(origin.requests()[1].header("if-none-match"), None);
+        assert_eq!(revalidate("unconditional").text(), "v1");
+        assert_eq!(origin.hits(), 2);
+    }
+
+    #[test]
+    fn post_caching() {
+        let origin = MockOrigin::start(|req| {
+            let body = format!("results for {}", String::from_utf8_lossy(&req.body));
+            MockResponse::new(200, body).header("cache-control", "max-age=60")
+        });
+        let mut route = route("post-cache", vec![origin.origin()]);
+        route["cache"] = true.into();
+        route["post_cache"] = serde_json::json!({"max_body_size": 16});
+        SERVER.add_route(route);
+        let post = |body: &str| {
+            SERVER.send(TestRequest::new("POST", "post-cache.test", "/search").body(body))
+        };
+
+        // Each body gets its own cache entry.
+        for _ in 0..2 {
+            assert_eq!(post("q=a").text(), "results for q=a");
+            assert_eq!(post("q=b").text(), "results for q=b");
+        }
+        assert_eq!(origin.hits(), 2);
+        assert_eq!(origin.requests()[0].body, b"q=a");
+        assert_eq!(post("q=b").header("x-cache-status"), Some("hit"));
+
+        // Bodies over the limit and other methods aren't cached.
+        let long = "q=".repeat(10);
+        assert_eq!(post(&long).text(), format!("results for {long}"));
+        assert_eq!(post(&long).text(), format!("results for {long}"));
+        let put = TestRequest::new("PUT", "post-cache.test", "/search").body("q=a");
+        assert_eq!(SERVER.send(put.clone()).text(), "results for q=a");
+        assert_eq!(SERVER.send(put).text(), "results for q=a");
+        assert_eq!(origin.hits(), 6);
+    }
+
+    #[test]
+    fn request_collapsing() {
+        let origin = MockOrigin::start(|req| {
+            thread::sleep(Duration::from_millis(500));
+            MockResponse::new(200, format!("body of {}", req.path))
+        });
+        let mut route = route("collapse", vec![origin.origin()]);
+        route["collapse"] = serde_json::json!({});
+        SERVER.add_route(route);
+
+        let requests: Vec<_> = (0..5)
+            .map(|_| thread::spawn(|| SERVER.get("collapse.test", "/collapse")))
+            .collect();
+        let responses: Vec<_> = requests.into_iter().map(|t| t.join().unwrap()).collect();
+        assert!(responses.iter().all(|r| r.text() == "body of /collapse"));
+        let collapsed = responses
+            .iter()
+            .filter(|r| r.header("x-cache-status") == Some("collapsed"))
+            .count();
+        assert_eq!(collapsed, 4);
+        assert_eq!(origin.hits(), 1);
+
+        // Requests that arrive afterwards go to the origin.
+        assert_eq!(
+            SERVER
+                .get("collapse.test", "/collapse")
+                .header("x-cache-status"),
+            Some("no-cache")
+        );
+        assert_eq!(origin.hits(), 2);
+    }
+
+    #[test]
+    fn cache_lock_timeout() {
+        let origin = MockOrigin::start(|req| {
+            thread::sleep(Duration::from_millis(500));
+            MockResponse::new(200, format!("body of {}", req.path))
+                .header("cache-control", "max-age=60")
+        });
+        let mut route = route("cache-lock", vec![origin.origin()]);
+        route["cache"] = true.into();
+        route["cache_lock"] = serde_json::json!({"timeout": 100, "on_timeout": "Error"});
+        SERVER.add_route(route);
+
+        let requests: Vec<_> = (0..3)
+            .map(|i| {
+                thread::sleep(Duration::from_millis(if i == 0 { 0 } else { 50 }));
+                thread::spawn(|| SERVER.get("cache-lock.test", "/slow"))
+            })
+            .collect();
+        let mut statuses: Vec<_> = requests
+            .into_iter()
+            .map(|t| t.join().unwrap().status)
+            .collect();
+        statuses.sort();
+        assert_eq!(statuses, vec![200, 504, 504]);
+        assert_eq!(origin.hits(), 1);
+
+        let resp = SERVER.get("cache-lock.test", "/slow");
+        assert_eq!(resp.header("x-cache-status"), Some("hit"));
+    }
+
+    #[test]
+    fn redirects() {
+        let origin = MockOrigin::start(|req| match req.path.as_str() {
+            "/old" => MockResponse::new(301, "").header("location", "/older"),
+            "/older" => MockResponse::new(302, "").header("location", "http://redirects.test/new"),
+            "/loop" => MockResponse::new(302, "").header("location", "/loop"),
+            "/away" => MockResponse::new(302, "").header("location", "https://other.test/"),
+            path => MockResponse::new(200, format!("body of {path}"))
+                .header("cache-control", "max-age=60"),
+        });
+        let mut route = route("redirects", vec![origin.origin()]);
+        route["cache"] = true.into();
+        route["redirects"] = serde_json::json!({"max_redirects": 2});
+        SERVER.add_route(route);
+
+        // The final response is sent, and cached under the requested URL.
+        let resp = SERVER.get("redirects.test", "/old");
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.text(), "body of /new");
+        assert_eq!(origin.hits(), 3);
+        let resp = SERVER.get("redirects.test", "/old");
+        assert_eq!(resp.text(), "body of /new");
+        assert_eq!(resp.header("x-cache-status"), Some("hit"));
+        assert_eq!(origin.hits(), 3);
+
+        // Redirects past the limit, and out of the route, are passed on.
+        let resp = SERVER.get("redirects.test", "/loop");
+        assert_eq!(resp.status, 302);
+        assert_eq!(origin.hits(), 6);
+        let resp = SERVER.get("redirects.test", "/away");
+        assert_eq!(resp.status, 302);
+        assert_eq!(resp.header("location"), Some("https://other.test/"));
+    }
+
+    #[test]
+    fn regex_paths() {
+        let origin = MockOrigin::start(|_| MockResponse::new(200, "versioned"));
+        let mut versioned = route("regex-paths", vec![origin.origin()]);
+        versioned["paths"] =
+            serde_json::json!([{"path": "^/v[0-9]+/", "path_match_type": "Regex"}]);
+        SERVER.add_route(versioned.clone());
+
+        // Routes with patterns that don't compile are rejected, leaving the others alone.
+        versioned["name"] = "regex-paths-invalid".into();
+        versioned["paths"] = serde_json::json!([{"path": "^/v(", "path_match_type": "Regex"}]);
+        assert_eq!(SERVER.api("/route/add", Some(&versioned)).status, 400);
+        assert_eq!(SERVER.get("regex-paths.test", "/v2/a").text(), "versioned");
+        assert_eq!(SERVER.get("regex-paths.test", "/a").status, 404);
+    }
+
+    #[test]
+    fn https_redirect() {
+        let origin = MockOrigin::start(|_| MockResponse::new(200, "proxied"));
+        let mut route = route("https-redirect", vec![origin.origin()]);
+        route["redirect_to_https"] = true.into();
+        SERVER.add_route(route);
+
+        let resp = SERVER.get("https-redirect.test", "/a?b=1");
+        assert_eq!(resp.status, 301);
+        assert_eq!(
+            resp.header("location"),
+            Some("https://https-redirect.test/a?b=1")
+        );
+        assert_eq!(origin.hits(), 0);
+    }
+
+    #[test]
+    fn idempotency_keys() {
+        let origin = MockOrigin::start(|req| {
+            MockResponse::new(
+                201,
+                format!("charged {}", String::from_utf8_lossy(&req.body)),
+            )
+        });
+        let mut route = route("idempotency", vec![origin.origin()]);
+        route["idempotency"] = serde_json::json!({});
+        SERVER.add_route(route);
+        let post = |path: &str, key: Option<&str>| {
+            let mut req = TestRequest::new("POST", "idempotency.test", path).body("10");
+            if let Some(key) = key {
+                req = req.header("idempotency-key", key);
+            }
+            SERVER.send(req)
+        };
+
+        // Retries with the same key get the first response.
+        let first = post("/pay", Some("k1"));
+        assert_eq!(first.status, 201);
+        assert_eq!(first.header("idempotent-replayed"), None);
+        let retry = post("/pay", Some("k1"));
+        assert_eq!(retry.status, 201);
+        assert_eq!(retry.text(), "charged 10");
+        assert_eq!(retry.header("idempotent-replayed"), Some("true"));
+        assert_eq!(retry.header("x-cache-status"), Some("replayed"));
+        assert_eq!(origin.hits(), 1);
+
+        // A key reused for another request (or with another body) is rejected, and requests without
+        // a key go through.
+        assert_eq!(post("/refund", Some("k1")).status, 422);
+        let other_body = TestRequest::new("POST", "idempotency.test", "/pay")
+            .header("idempotency-key", "k1")
+            .body("1000");
+        assert_eq!(SERVER.send(other_body).status, 422);
+        assert_eq!(post("/pay", Some("k2")).status, 201);
+        assert_eq!(post("/pay", None).status, 201);
+        assert_eq!(post("/pay", None).status, 201);
+        assert_eq!(origin.hits(), 4);
+
+        // Keys are scoped to the client's credentials, so another client reusing a key doesn't get
+        // the first client's response.
+        let send = |authorization: &str| {
+            SERVER.send(
+                TestRequest::new("POST", "idempotency.test", "/pay")
+                    .header("idempotency-key", "k3")
+                    .header("authorization", authorization)
+                    .body("10"),
+            )
+        };
+        assert_eq!(send("Bearer alice").header("idempotent-replayed"), None);
+        assert_eq!(send("Bearer bob").header("idempotent-replayed"), None);
+        assert_eq!(
+            send("Bearer alice").header("idempotent-replayed"),
+            Some("true")
+        );
+        assert_eq!(origin.hits(), 6);
+    }
+
+    #[test]
+    fn waf_body_size() {
+        let origin = MockOrigin::start(|_| MockResponse::new(200, "uploaded"));
+        let mut route = route("waf-body", vec![origin.origin()]);
+        route["waf"] = serde_json::json!({
+            "rules": [{"name": "upload", "methods": ["POST"], "max_body_size": 4}]
+        });
+        SERVER.add_route(route);
+        let post = |body: &str, chunked: bool| {
+            let mut req = TestRequest::new("POST", "waf-body.test", "/").body(body);
+            if chunked {
+                req = req.chunked();
+            }
+            SERVER.send(req).status
+        };
+
+        assert_eq!(post("1234", false), 200);
+        assert_eq!(post("12345", false), 403);
+        // A chunked body's size isn't known up front, whatever it turns out to be.
+        assert_eq!(post("1234", true), 403);
+        assert_eq!(post("12345", true), 403);
+        assert_eq!(origin.hits(), 1);
+    }
+
+    #[test]
+    fn prewarm() {
+        let origin = MockOrigin::start(|_| MockResponse::new(200, ""));
+        let mut prewarmed = origin.origin();
+        prewarmed["prewarm"] = serde_json::json!({"connections": 2, "path": "/health"});
+        SERVER.add_route(route("prewarm", vec![prewarmed]));
+
+        let start = Instant::now();
+        while origin.hits() < 2 {
+            assert!(
+                start.elapsed() < Duration::from_secs(10),
+                "Origin wasn't prewarmed"
+            );
+            thread::sleep(Duration::from_millis(50));
+        }
+        let req = &origin.requests()[0];
+        assert_eq!(req.method, "HEAD");
+        assert_eq!(req.path, "/health");
+        assert_eq!(req.header("user-agent"), Some("granite-prewarm"));
+        assert_eq!(req.header("x-granite-prewarm"), None);
+
+        // Other requests can't pass for prewarm requests, and their prewarm header isn't forwarded.
+        let forged = TestRequest::new("GET", "prewarm.test", "/")
+            .header("x-granite-prewarm", "forged 0 http prewarm");
+        assert_eq!(SERVER.send(forged).status, 200);
+        let req = origin.requests().pop().unwrap();
+        assert_eq!(req.method, "GET");
+        assert_eq!(req.header("x-granite-prewarm"), None);
+    }
+
+    #[test]
+    fn retry_unreachable_origin() {
+        let origin = MockOrigin::start(|_| MockResponse::new(200, "up"));
+        let down = free_addr();
+        let down = serde_json::json!({"host": down.ip().to_string(), "http_port": down.port()});
+        SERVER.add_route(route("retry", vec![down, origin.origin()]));
+
+        for _ in 0..10 {
+            let resp = SERVER.get("retry.test", "/");
+            assert_eq!((resp.status, resp.text().as_str()), (200, "up"));
+        }
+        assert_eq!(origin.hits(), 10);
+    }
+
+    #[test]
+    fn host_header_override() {
+        let origin = MockOrigin::start(|req| MockResponse::new(200, req.header("host").unwrap()));
+        let mut templated = route("host-override", vec![origin.origin()]);
+        templated["host_header_override"] = "${host}.via.${origin_host}".into();
+        SERVER.add_route(templated);
+        let resp = SERVER.get("host-override.test", "/");
+        assert_eq!(resp.text(), "host-override.test.via.127.0.0.1");
+
+        // An origin's own override takes precedence.
+        let mut own = origin.origin();
+        own["host_header_override"] = "origin.example.com".into();
+        let mut other = route("host-override-origin", vec![own]);
+        other["host_header_override"] = "${host}".into();
+        SERVER.add_route(other);
+        let resp = SERVER.get("host-override-origin.test", "/");
+        assert_eq!(resp.text(), "origin.example.com");
+    }
+
+    #[test]
+    fn forward_auth() {
+        let auth = MockOrigin::start(|_| {
+            MockResponse::new(200, "").header("x-auth-request-user", "alice")
+        });
+        let origin = MockOrigin::start(|_| MockResponse::new(200, "ok"));
+        let mut route = route("forward-auth", vec![origin.origin()]);
+        route["forward_auth"] = serde_json::json!({
+            "url": format!("http://{}/auth", auth.addr()),
+            "response_headers": ["X-Auth-Request-User", "X-Auth-Request-Email"],
+        });
+        SERVER.add_route(route);
+
+        // Identity headers sent by the client are replaced with (or removed in favor of) the auth
+        // service's.
+        let resp = SERVER.send(
+            TestRequest::new("GET", "forward-auth.test", "/")
+                .header("x-auth-request-user", "mallory")
+                .header("x-auth-request-email", "mallory@example.com"),
+        );
+        assert_eq!(resp.status, 200);
+        let forwarded = &origin.requests()[0];
+        assert_eq!(forwarded.header("x-auth-request-user"), Some("alice"));
+        assert_eq!(forwarded.header("x-auth-request-email"), None);
+        assert_eq!(auth.hits(), 1);
+    }
+
+    #[test]
+    fn geo_headers() {
+        let origin = MockOrigin::start(|req| {
+            MockResponse::new(200, req.header("x-geo-country").unwrap_or_default())
+        });
+        SERVER.add_route(route("geo-headers", vec![origin.origin()]));
+
+        // Geo headers only come from the proxy's own lookup, never from the client.
+        let resp = SERVER
+            .send(TestRequest::new("GET", "geo-headers.test", "/").header("x-geo-country", "US"));
+        assert_eq!((resp.status, resp.text().as_str()), (200, ""));
+    }
+
+    #[test]
+    fn port_map() {
+        let origin = MockOrigin::start(|_| MockResponse::new(200, "mapped"));
+        let mut unmapped = origin.origin();
+        unmapped["http_port"] = free_addr().port().into();
+        let mut route = route("port-map", vec![unmapped]);
+        route["port_map"] = serde_json::json!([{
+            "incoming_port": SERVER.http_addr.port(),
+            "outgoing_port": origin.addr().port()
+        }]);
+        SERVER.add_route(route);
+
+        let resp = SERVER.get("port-map.test", "/");
+        assert_eq!((resp.status, resp.text().as_str()), (200, "mapped"));
+    }
+
+    #[test]
+    fn customers() {
+        let origin = MockOrigin::start(|_| MockResponse::new(200, "ok"));
+        let customer = serde_json::json!({
+            "name": "acme",
+            "allowed_hosts": ["*.acme.test"],
+            "defaults": {"security_headers": {"frame_options": "DENY"}}
+        });
+        assert_eq!(SERVER.api("/customer/add", Some(&customer)).status, 200);
+
+        let mut outside = route("customer-outside", vec![origin.origin()]);
+        outside["customer"] = "acme".into();
+        assert_eq!(SERVER.api("/route/add", Some(&outside)).status, 400);
+
+        let mut inside = route("customer-inside", vec![origin.origin()]);
+        inside["customer"] = "acme".into();
+        inside["hosts"] = serde_json::json!(["www.acme.test"]);
+        SERVER.add_route(inside);
+        let resp = SERVER.get("www.acme.test", "/");
+        assert_eq!(resp.text(), "ok");
+        assert_eq!(resp.header("x-frame-options"), Some("DENY"));
+
+        // A customer can't be deleted while it has routes.
+        let delete = |path: &str, name: &str| {
+            TestRequest::new("POST", "api", path)
+                .body(name.to_string())
+                .send(SERVER.api_addr)
+                .expect("Config API request failed")
+        };
+        assert_eq!(delete("/customer/delete", "acme").status, 409);
+        assert_eq!(delete("/route/delete", "customer-inside").status, 200);
+        assert_eq!(delete("/customer/delete", "acme").status, 200);
+    }
+
+    #[test]
+    fn route_import() {
+        let origin = MockOrigin::start(|req| MockResponse::new(200, req.header("host").unwrap()));
+        let config = format!(
+            "server {{ server_name imported.test; location / {{ proxy_pass http://{}; }} }}",
+            origin.addr()
+        );
+        let resp = TestRequest::new("POST", "api", "/route/import?format=nginx&customer=test")
+            .body(config)
+            .send(SERVER.api_addr)
+            .expect("Config API request failed");
+        assert_eq!(resp.status, 200, "Import failed: {}", resp.text());
+        let routes: Vec<serde_json::Value> = serde_json::from_str(&resp.text()).unwrap();
+        assert_eq!(routes.len(), 1);
+
+        // The imported route is only added when pushed, and nginx sends the origin's host.
+        assert_eq!(SERVER.get("imported.test", "/").status, 404);
+        SERVER.add_route(routes[0].clone());
+        assert_eq!(SERVER.get("imported.test", "/").text(), "127.0.0.1");
+    }
+
+    #[test]
+    fn client_ip_privacy() {
+        let origin = MockOrigin::start(|req| {
+            MockResponse::new(200, req.header("x-forwarded-for").unwrap_or_default())
+        });
+        let mut route = route("privacy", vec![origin.origin()]);
+        route["client_ip_privacy"] = "Truncate".into();
+        SERVER.add_route(route);
+
+        let resp = SERVER.send(
+            TestRequest::new("GET", "privacy.test", "/")
+                .header("x-forwarded-for", "203.0.113.9, 2001:db8:1:2::3"),
+        );
+        assert_eq!(resp.text(), "203.0.113.0, 2001:db8:1::");
+    }
+
+    #[test]
+    fn bot_policy() {
+        let origin = MockOrigin::start(|req| {
+            MockResponse::new(200, req.path.clone()).header("cache-control", "max-age=60")
+        });
+        let mut route = route("bots", vec![origin.origin()]);
+        route["cache"] = true.into();
+        route["bots"] = serde_json::json!({"crawlers": "CachedOnly", "unknown": "Block"});
+        SERVER.add_route(route);
+        let get = |user_agent: &str| {
+            SERVER.send(
+                TestRequest::new("GET", "bots.test", "/page").header("user-agent", user_agent),
+            )
+        };
+
+        assert_eq!(SERVER.get("bots.test", "/page").status, 403);
+        assert_eq!(get("curl/8.5.0").status, 503);
+        assert_eq!(get("Mozilla/5.0 Firefox/125.0").text(), "/page");
+        let resp = get("curl/8.5.0");
+        assert_eq!(resp.text(), "/page");
+        assert_eq!(resp.header("x-cache-status"), Some("hit"));
+        assert_eq!(origin.hits(), 1);
+    }
+
+    #[test]
+    fn origin_connection_limit() {
+        let origin = MockOrigin::start(|_| {
+            thread::sleep(Duration::from_millis(300));
+            MockResponse::new(200, "slow")
+        });
+        let mut limited = origin.origin();
+        limited["connection_limit"] =
+            serde_json::json!({"max_connections": 1, "queue_timeout": 50});
+        SERVER.add_route(route("connlimit", vec![limited]));
+
+        let requests: Vec<_> = (0..2)
+            .map(|_| thread::spawn(|| SERVER.get("connlimit.test", "/").status))
+            .collect();
+        let mut statuses: Vec<_> = requests.into_iter().map(|r| r.join().unwrap()).collect();
+        statuses.sort();
+        assert_eq!(statuses, [200, 503]);
+        assert_eq!(origin.hits(), 1);
+    }
+
+    #[test]
+    fn method_policy() {
+        let origin = MockOrigin::start(|req| MockResponse::new(200, req.method.clone()));
+        let mut route = route("methods", vec![origin.origin()]);
+        route["methods"] = serde_json::json!({"allowed_methods": ["GET", "POST"]});
+        SERVER.add_route(route);
+        let send = |method: &str| SERVER.send(TestRequest::new(method, "methods.test", "/"));
+
+        assert_eq!(send("POST").text(), "POST");
+        let resp = send("DELETE");
+        assert_eq!(resp.status, 405);
+        assert_eq!(resp.header("allow"), Some("GET, POST, HEAD, OPTIONS"));
+        assert_eq!(send("TRACE").status, 405);
+        let resp = send("OPTIONS");
+        assert_eq!(resp.status, 204);
+        assert_eq!(resp.header("allow"), Some("GET, POST, HEAD, OPTIONS"));
+        assert_eq!(origin.hits(), 1);
+    }
+
+    #[test]
+    fn response_header_policy() {
+        let origin = MockOrigin::start(|_| {
+            MockResponse::new(200, "ok")
+                .header("server", "origin/1.0")
+                .header("x-internal-node", "n1")
+                .header("keep-alive", "timeout=5")
+                .header("x-kept", "1")
+        });
+        let mut route = route("response-headers", vec![origin.origin()]);
+        route["response_headers"] = serde_json::json!({"strip": ["Server", "x-internal-*"]});
+        SERVER.add_route(route);
+
+        let resp = SERVER.get("response-headers.test", "/");
+        assert_eq!(resp.text(), "ok");
+        assert_eq!(resp.header("server"), None);
+        assert_eq!(resp.header("x-internal-node"), None);
+        assert_eq!(resp.header("keep-alive"), None);
+        assert_eq!(resp.header("x-kept"), Some("1"));
+    }
+
+    #[test]
+    fn range_pass_through() {
+        let origin = MockOrigin::start(|req| {
+            let range = req.header("range").unwrap_or_default().to_string();
+            MockResponse::new(206, "abc")
+                .header("content-range", "bytes 0-2/10")
+                .header("x-range", &range)
+        });
+        let mut route = route("ranges", vec![origin.origin()]);
+        route["ranges"] = serde_json::json!({});
+        SERVER.add_route(route);
+        let send = |range: &str| {
+            SERVER.send(TestRequest::new("GET", "ranges.test", "/").header("range", range))
+        };
+
+        let resp = send("bytes=0-2");
+        assert_eq!(resp.status, 206);
+        assert_eq!(resp.text(), "abc");
+        assert_eq!(resp.header("x-range"), Some("bytes=0-2"));
+        assert_eq!(send("bytes=3-").status, 416);
+        assert_eq!(origin.hits(), 1);
+    }
+
+    #[test]
+    fn api_stats() {
+        let resp = SERVER.api("/version", None);
+        let version: serde_json::Value = serde_json::from_str(&resp.text()).unwrap();
+        assert_eq!(version["version"], env!("CARGO_PKG_VERSION"));
+
+        assert_eq!(SERVER.api("/unknown", None).status, 404);
+        let resp = SERVER.api("/api/stats", None);
+        let stats: serde_json::Value = serde_json::from_str(&resp.text()).unwrap();
+        assert!(stats["requests"].as_u64().unwrap() >= 2);
+        assert!(stats["client_errors"].as_u64().unwrap() >= 1);
+    }
+
+    #[test]
+    fn failover_response() {
+        let down = free_addr();
+        let down = serde_json::json!({"host": down.ip().to_string(), "http_port": down.port()});
+        let mut route = route("failover", vec![down]);
+        route["failover"] = serde_json::json!({
+            "body": "Back soon",
+            "content_type": "text/plain",
+            "headers": {"retry-after": "60"}
+        });
+        SERVER.add_route(route);
+
+        let resp = SERVER.get("failover.test", "/");
+        assert_eq!(resp.status, 503);
+        assert_eq!(resp.header("retry-after"), Some("60"));
+        assert_eq!(resp.text(), "Back soon");
+    }
+
+    #[test]
+    fn bucketing() {
+        let echo = |name: &'static str| {
+            move |req: &MockRequest| {
+                let bucket = req.header("x-bucket").unwrap_or_default();
+                MockResponse::new(200, format!("{name} {bucket}"))
+            }
+        };
+        let (a, b) = (MockOrigin::start(echo("a")), MockOrigin::start(echo("b")));
+        let mut route = route("bucketing", vec![a.origin(), b.origin()]);
+        route["bucketing"] = serde_json::json!({
+            "key": {"Header": "x-user"},
+            "buckets": [{"name": "control"}, {"name": "beta", "origin": "localhost"}],
+        });
+        route["origin_group"]["origins"][1]["host"] = "localhost".into();
+        SERVER.add_route(route);
+
+        let mut seen = Vec::new();
+        for user in 0..20 {
+            let request = TestRequest::new("GET", "bucketing.test", "/")
+                .header("x-user", &user.to_string())
+                .header("x-bucket", "spoofed");
+            let first = SERVER.send(request).text();
+            let again = SERVER.send(
+                TestRequest::new("GET", "bucketing.test", "/").header("x-user", &user.to_string()),
+            );
+            // Only the beta bucket is pinned to an origin.
+            let bucket = |body: &str| body.split_once(' ').unwrap().1.to_string();
+            assert_eq!(bucket(&again.text()), bucket(&first));
+            seen.push(first);
+        }
+        assert!(seen.iter().any(|body| body == "b beta"));
+        assert!(seen
+            .iter()
+            .all(|body| body == "b beta" || body.ends_with(" control")));
+    }
+
+    #[test]
+    fn cert_serving() {
+        let origin = MockOrigin::start(|_| MockResponse::new(200, "secure"));
+        SERVER.add_route(route("tls", vec![origin.origin()]));
+        let cert = TestCert::new("tls.test");
+        SERVER.add_cert("tls.test", &cert);
+
+        let (resp, served) = SERVER.send_tls(TestRequest::new("GET", "tls.test", "/"));
+        assert_eq!((resp.status, resp.text().as_str()), (200, "secure"));
+        assert_eq!(
+            served.unwrap().digest(MessageDigest::sha256()).unwrap()[..],
+            cert.cert.digest(MessageDigest::sha256()).unwrap()[..]
+        );
+    }
+
+    #[test]
+    fn cert_swap_sessions() {
+        let origin = MockOrigin::start(|_| MockResponse::new(200, "ok"));
+        SERVER.add_route(route("swap", vec![origin.origin()]));
+        let host = "swap.test";
+        SERVER.add_cert(host, &TestCert::new(host));
+        let mut connector = SslConnector::builder(SslMethod::tls_client()).unwrap();
+        connector.set_verify(SslVerifyMode::NONE);
+        let connector = connector.build();
+        // Connect (resuming the session, if any), and return whether the session was resumed, the
+        // session to resume next, and the certificate served.
+        let connect = |session: Option<&SslSession>| {
+            let mut ssl = connector.configure().unwrap().into_ssl(host).unwrap();
+            if let Some(session) = session {
+                unsafe { ssl.set_session(session).unwrap() };
+            }
+            let tcp = TcpStream::connect(SERVER.https_addr).unwrap();
+            let mut tls = SslStream::new(ssl, tcp).unwrap();
+            tls.connect().unwrap();
+            // TLS 1.3 sessions are received after the handshake.
+            let resp = TestRequest::new("GET", host, "/")
+                .send_on(&mut tls)
+                .unwrap();
+            assert_eq!(resp.status, 200);
+            // Sessions of connections dropped without a shutdown can't be resumed.
+            let _ = tls.shutdown();
+            let ssl = tls.ssl();
+            let digest = ssl
+                .peer_certificate()
+                .unwrap()
+                .digest(MessageDigest::sha256())
+                .unwrap()
+                .to_vec();
+            (
+                ssl.session_reused(),
+                ssl.session().unwrap().to_owned(),
+                digest,
+            )
+        };
+        let swap = |cert: &TestCert, invalidate_sessions: bool| {
+            let binding = serde_json::json!({
+                "host": host,
+                "cert": cert.cert_pem,
+                "key": cert.key_pem,
+                "invalidate_sessions": invalidate_sessions,
+            });
+            let resp = SERVER.api("/cert/add", Some(&binding));
+            assert_eq!(resp.status, 200);
+            serde_json::from_slice::<serde_json::Value>(&resp.body).unwrap()
+        };
+
+        let (resumed, session, old) = connect(None);
+        assert!(!resumed);
+        assert!(connect(Some(&session)).0);
+
+        // New handshakes get the new certificate, while sessions are still resumed.
+        let cert = TestCert::new(host);
+        let new = cert.cert.digest(MessageDigest::sha256()).unwrap().to_vec();
+        let report = swap(&cert, false);
+        assert_eq!(report["hosts_rebound"], 1);
+        assert_eq!(report["sessions_invalidated"], 0);
+        assert_eq!(connect(None).2, new);
+        let (resumed, session, served) = connect(Some(&session));
+        assert!(resumed);
+        assert_eq!(served, old);
+
+        // Unless they're invalidated.
+        let report = swap(&TestCert::new(host), true);
+        assert_eq!(report["hosts_rebound"], 1);
+        assert!(report["sessions_invalidated"].as_u64().unwrap() > 0);
+        assert!(!connect(Some(&session)).0);
+    }
+
+    #[test]
+    fn http1_only() {
+        let origin = MockOrigin::start(|_| MockResponse::new(200, "ok"));
+        let mut streaming = route("h1-streaming", vec![origin.origin()]);
+        streaming["http1_only"] = true.into();
+        SERVER.add_route(streaming);
+        SERVER.add_route(route("h1-default", vec![origin.origin()]));
+        let negotiated = |host: &str| {
+            SERVER.add_cert(host, &TestCert::new(host));
+            negotiated(SERVER.https_addr, host)
+        };
+
+        assert_eq!(negotiated("h1-default.test").unwrap(), b"h2");
+        assert_eq!(negotiated("h1-streaming.test").unwrap(), b"http/1.1");
+        let (resp, _) = SERVER.send_tls(TestRequest::new("GET", "h1-streaming.test", "/"));
+        assert_eq!(resp.status, 200);
+    }
+
+    #[test]
+    fn tls_fingerprint() {
+        // Fingerprinting is off by default, so these tests get servers of their own.
+        let server = |deny: Vec<String>| {
+            let mut conf = AppConfig::default();
+            conf.tls_fingerprint.enabled = true;
+            conf.tls_fingerprint.upstream_header = Some("x-ja3".to_string());
+            conf.tls_fingerprint.deny = deny;
+            let server = TestServer::start(conf);
+            let origin = MockOrigin::start(|req| {
+                MockResponse::new(200, req.header("x-ja3").unwrap_or_default())
+            });
+            server.add_route(route("ja3", vec![origin.origin()]));
+            server.add_cert("ja3.test", &TestCert::new("ja3.test"));
+            (server, origin)
+        };
+
+        let (open, _origin) = server(Vec::new());
+        let (resp, _) = open.send_tls(TestRequest::new("GET", "ja3.test", "/"));
+        let fingerprint = resp.text();
+        assert_eq!(fingerprint.len(), 32);
+        let (resp, _) = open.send_tls(TestRequest::new("GET", "ja3.test", "/"));
+        assert_eq!(resp.text(), fingerprint);
+        // Without TLS, there's no fingerprint, and the client can't supply one.
+        let resp = open.send(TestRequest::new("GET", "ja3.test", "/").header("x-ja3", "forged"));
+        assert_eq!(resp.text(), "");
+        // Neither over HTTP/2, whose requests are handled apart from the TLS handshake.
+        let forged = TestRequest::new("GET", "ja3.test", "/").header("x-ja3", "forged");
+        let resp = send_h2(open.https_addr, "ja3.test", &forged);
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.text(), "");
+
+        let (closed, _origin) = server(vec![fingerprint]);
+        let (resp, _) = closed.send_tls(TestRequest::new("GET", "ja3.test", "/"));
+        assert_eq!(resp.status, 403);
+        // Clients can't get past the deny list with HTTP/2, which isn't offered.
+        assert_eq!(
+            negotiated(closed.https_addr, "ja3.test").unwrap(),
+            b"http/1.1"
+        );
+        assert_eq!(negotiated(open.https_addr, "ja3.test").unwrap(), b"h2");
+    }
+
+    #[test]
+    fn peer_client_ip() {
+        // The test's client is the cluster peer, so this test gets a server of its own.
+        let mut conf = AppConfig::default();
+        let peers = [free_addr().to_string(), free_addr().to_string()];
+        conf.cluster.peers = peers.to_vec();
+        conf.cluster.self_addr = Some(peers[0].clone());
+        let server = TestServer::start(conf);
+        let internal_origin = MockOrigin::start(|_| MockResponse::new(200, "internal"));
+        let public_origin = MockOrigin::start(|_| MockResponse::new(200, "public"));
+        let mut internal = route("peer-client", vec![internal_origin.origin()]);
+        internal["name"] = "peer-client-internal".into();
+        internal["client_ip_match"] = serde_json::json!(["10.0.0.0/8"]);
+        server.add_route(internal);
+        server.add_route(route("peer-client", vec![public_origin.origin()]));
+        let send = |peer: bool, client: Option<&str>| {
+            let mut req = TestRequest::new("GET", "peer-client.test", "/");
+            if peer {
+                req = req.header(cluster::PEER_HEADER, &peers[1]);
+            }
+            if let Some(client) = client {
+                req = req.header(cluster::CLIENT_HEADER, client);
+            }
+            server.send(req).text()
+        };
+
+        // Requests forwarded by a peer are matched on the address of the client the peer received
+        // them from, not the peer's.
+        assert_eq!(send(true, Some("10.1.2.3")), "internal");
+        assert_eq!(send(true, Some("192.0.2.1")), "public");
+        assert_eq!(send(true, None), "public");
+        assert_eq!(
+            internal_origin.requests()[0].header(cluster::CLIENT_HEADER),
+            None
+        );
+        // Other clients can't pass for another.
+        assert_eq!(send(false, Some("10.1.2.3")), "public");
+    }
+
+    #[test]
+    fn sni_routing() {
+        let sni_origin = MockOrigin::start(|_| MockResponse::new(200, "by sni"));
+        let host_origin = MockOrigin::start(|_| MockResponse::new(200, "by host"));
+        let mut by_sni = route("sni-routing", vec![sni_origin.origin()]);
+        by_sni["match_on"] = "Sni".into();
+        SERVER.add_route(by_sni);
+        SERVER.add_route(route("host-routing", vec![host_origin.origin()]));
+        SERVER.add_cert("sni-routing.test", &TestCert::new("sni-routing.test"));
+        SERVER.add_cert("host-routing.test", &TestCert::new("host-routing.test"));
+        let send_tls = |sni: &str, host: &str| {
+            let mut connector = SslConnector::builder(SslMethod::tls_client()).unwrap();
+            connector.set_verify(SslVerifyMode::NONE);
+            let tcp = TcpStream::connect(SERVER.https_addr).unwrap();
+            let mut tls = connector.build().connect(sni, tcp).unwrap();
+            TestRequest::new("GET", host, "/")
+                .send_on(&mut tls)
+                .unwrap()
+        };
+
+        // Over HTTP/1.1, the route is matched on the SNI, whatever the host header.
+        let resp = send_tls("sni-routing.test", "host-routing.test");
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.text(), "by sni");
+        let resp = send_tls("host-routing.test", "sni-routing.test");
+        assert_eq!(resp.status, 404);
+
+        // HTTP/2 requests carry no SNI, so the route's host only gets HTTP/1.1, and HTTP/2 requests
+        // for it on another host's connection are misdirected.
+        assert_eq!(
+            negotiated(SERVER.https_addr, "sni-routing.test").unwrap(),
+            b"http/1.1"
+        );
+        let resp = send_h2(
+            SERVER.https_addr,
+            "host-routing.test",
+            &TestRequest::new("GET", "sni-routing.test", "/"),
+        );
+        assert_eq!(resp.status, 421);
+        let resp = send_h2(
+            SERVER.https_addr,
+            "host-routing.test",
+            &TestRequest::new("GET", "host-routing.test", "/"),
+        );
+        assert_eq!(resp.status, 200);
+        assert_eq!(resp.text(), "by host");
+    }
+}
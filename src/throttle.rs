@@ -0,0 +1,204 @@
+//! Egress bandwidth throttling, per response (set on routes) and in aggregate per customer (set in
+//! the static configuration).
+//!
+//! Response bodies are paced as they are streamed to the client: each chunk draws its size from a
+//! token bucket, and if the bucket goes into debt, the chunk is held until the debt is paid off.
+//! A customer's bucket is shared by all of the customer's responses in progress, so together they
+//! can't exceed the customer's rate.
+//!
+//! Routes can also pace responses by content type (e.g., video sent in an initial burst and then at
+//! a little over its bitrate), so that less is sent for nothing when clients abandon playback.
+//!
+//! Cache hits are written to the client in one go, so they are paced as a whole: the response is
+//! held for as long as its body would take at the allowed rate before it is sent.
+
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Aggregate bandwidth limits.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+#[serde(default)]
+pub struct ThrottleConfig {
+    /// The maximum rate (in bytes per second) of response bodies sent to clients, per customer
+    /// (keyed by the customer name used in routes).
+    pub customers: HashMap<String, u64>,
+}
+
+/// A bandwidth limit for each response on a route.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct ThrottlePolicy {
+    /// The maximum rate (in bytes per second) at which a response body is sent.
+    pub bytes_per_second: u64,
+
+    /// The number of bytes at the start of a response that are sent without delay.
+    #[serde(default)]
+    pub burst: u64,
+}
+
+/// A bandwidth limit for each response on a route with a matching content type.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct PacingRule {
+    /// The content types (e.g., `video/mp4`) the rule applies to.  A type ending in `/` (e.g.,
+    /// `video/`) matches all of its subtypes.
+    pub content_types: Vec<String>,
+
+    #[serde(flatten)]
+    pub policy: ThrottlePolicy,
+}
+
+impl PacingRule {
+    /// Whether the rule applies to a response with the `Content-Type` header value.
+    pub fn matches(&self, content_type: &str) -> bool {
+        let media_type = content_type.split(';').next().unwrap_or_default().trim();
+        self.content_types.iter().any(|pattern| {
+            if pattern.ends_with('/') {
+                media_type
+                    .get(..pattern.len())
+                    .is_some_and(|prefix| prefix.eq_ignore_ascii_case(pattern))
+            } else {
+                media_type.eq_ignore_ascii_case(pattern)
+            }
+        })
+    }
+}
+
+/// Choose the limit for a response: the first pacing rule matching its content type, or else the
+/// route's throttle policy.
+pub fn select_policy<'a>(
+    throttle: Option<&'a ThrottlePolicy>,
+    pacing: &'a [PacingRule],
+    content_type: Option<&str>,
+) -> Option<&'a ThrottlePolicy> {
+    content_type
+        .and_then(|content_type| pacing.iter().find(|rule| rule.matches(content_type)))
+        .map(|rule| &rule.policy)
+        .or(throttle)
+}
+
+/// A token bucket that goes into debt rather than rejecting, so it can tell how long to wait.
+#[derive(Debug)]
+pub struct Pacer {
+    rate: f64,
+    capacity: f64,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl Pacer {
+    /// Create a pacer allowing `rate` bytes per second, with `burst` bytes available right away.
+    pub fn new(rate: u64, burst: u64) -> Self {
+        Pacer {
+            rate: rate.max(1) as f64,
+            capacity: burst as f64,
+            tokens: burst as f64,
+            last_refill: Instant::now(),
+        }
+    }
+
+    /// Take `bytes` from the bucket, and return how long to wait before sending them (if at all).
+    pub fn delay(&mut self, bytes: usize) -> Option<Duration> {
+        self.delay_at(bytes, Instant::now())
+    }
+
+    fn delay_at(&mut self, bytes: usize, now: Instant) -> Option<Duration> {
+        let elapsed = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate).min(self.capacity);
+        self.last_refill = now;
+
+        self.tokens -= bytes as f64;
+        (self.tokens < 0.0).then(|| Duration::from_secs_f64(-self.tokens / self.rate))
+    }
+}
+
+/// Paces responses against the aggregate limits of their customers.
+pub struct Throttler {
+    customers: HashMap<String, Mutex<Pacer>>,
+}
+
+impl Throttler {
+    pub fn new(config: &ThrottleConfig) -> Self {
+        Throttler {
+            customers: config
+                .customers
+                .iter()
+                .map(|(customer, &rate)| (customer.clone(), Mutex::new(Pacer::new(rate, 0))))
+                .collect(),
+        }
+    }
+
+    /// Take `bytes` sent to one of the customer's clients, and return how long to wait before
+    /// sending them (if at all).
+    pub fn customer_delay(&self, customer: &str, bytes: usize) -> Option<Duration> {
+        let pacer = self.customers.get(customer)?;
+        pacer.lock().unwrap().delay(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pacing() {
+        let start = Instant::now();
+        let mut pacer = Pacer::new(1000, 500);
+        pacer.last_refill = start;
+
+        // The burst goes through right away, and the rest waits for its share of the rate.
+        assert_eq!(pacer.delay_at(500, start), None);
+        assert_eq!(pacer.delay_at(250, start), Some(Duration::from_millis(250)));
+        assert_eq!(pacer.delay_at(250, start), Some(Duration::from_millis(500)));
+
+        // Once the debt is paid off, unused time refills the burst.
+        let later = start + Duration::from_millis(1500);
+        assert_eq!(pacer.delay_at(400, later), None);
+
+        let throttler = Throttler::new(&ThrottleConfig {
+            customers: HashMap::from([("c".to_string(), 100)]),
+        });
+        assert!(throttler.customer_delay("c", 50).is_some());
+        assert_eq!(throttler.customer_delay("other", 50), None);
+    }
+
+    #[test]
+    fn content_type_pacing() {
+        let throttle = ThrottlePolicy {
+            bytes_per_second: 1000,
+            burst: 0,
+        };
+        let pacing = [PacingRule {
+            content_types: vec![
+                "video/".to_string(),
+                "application/vnd.apple.mpegurl".to_string(),
+            ],
+            policy: ThrottlePolicy {
+                bytes_per_second: 500_000,
+                burst: 2_000_000,
+            },
+        }];
+
+        let video = Some(&pacing[0].policy);
+        assert_eq!(
+            select_policy(Some(&throttle), &pacing, Some("video/mp4")),
+            video
+        );
+        assert_eq!(
+            select_policy(
+                None,
+                &pacing,
+                Some("Application/vnd.apple.mpegURL; charset=utf-8")
+            ),
+            video
+        );
+        assert_eq!(
+            select_policy(Some(&throttle), &pacing, Some("text/html")),
+            Some(&throttle)
+        );
+        assert_eq!(select_policy(None, &pacing, Some("videos")), None);
+        assert_eq!(select_policy(None, &pacing, None), None);
+    }
+}
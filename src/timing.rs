@@ -0,0 +1,122 @@
+//! A breakdown of where the time went while handling a request, used to explain slow requests.
+
+use pingora::protocols::Digest;
+use std::fmt;
+use std::time::{Duration, Instant, SystemTime};
+
+/// The durations of the phases of a request.  A phase that didn't happen (e.g., connecting when a
+/// pooled connection was reused) is `None`.
+#[derive(Debug, Default)]
+pub struct RequestTimings {
+    /// Looking up the route.
+    pub route_match: Option<Duration>,
+    /// Waiting for another request to fill the cache entry.
+    pub cache_lock: Option<Duration>,
+    /// Resolving the origin's hostname.
+    pub dns: Option<Duration>,
+    /// Establishing the TCP connection to the origin.
+    pub connect: Option<Duration>,
+    /// The TLS handshake with the origin.
+    pub tls_handshake: Option<Duration>,
+    /// From sending the request to the origin until the response header is received.
+    pub upstream_response: Option<Duration>,
+
+    /// When the connection to the origin started to be established.
+    connect_start: Option<SystemTime>,
+    /// When the request was sent to the origin.
+    request_sent: Option<Instant>,
+}
+
+impl RequestTimings {
+    /// Note that the proxy is about to connect to the origin.
+    pub fn connect_started(&mut self) {
+        self.connect_start = Some(SystemTime::now());
+    }
+
+    /// Derive the connect and TLS handshake times from the connection digest.
+    pub fn connected(&mut self, reused: bool, digest: Option<&Digest>) {
+        let (Some(start), Some(digest), false) = (self.connect_start, digest, reused) else {
+            return;
+        };
+        // The first layer is TCP, the second (if any) is TLS.
+        let mut layers = digest.timing_digest.iter().map(|t| t.as_ref());
+        let tcp = layers.next().flatten().map(|t| t.established_ts);
+        let tls = layers.next().flatten().map(|t| t.established_ts);
+
+        self.connect = tcp.and_then(|tcp| tcp.duration_since(start).ok());
+        if let (Some(tcp), Some(tls)) = (tcp, tls) {
+            self.tls_handshake = tls.duration_since(tcp).ok();
+        }
+    }
+
+    /// Note that the request was sent to the origin.
+    pub fn request_sent(&mut self) {
+        self.request_sent = Some(Instant::now());
+    }
+
+    /// Note that the origin's response header was received.
+    pub fn response_received(&mut self) {
+        if let Some(sent) = self.request_sent {
+            self.upstream_response = Some(sent.elapsed());
+        }
+    }
+}
+
+impl fmt::Display for RequestTimings {
+    /// Format the phases that happened as `name=<milliseconds>ms`.
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let phases = [
+            ("route_match", self.route_match),
+            ("cache_lock", self.cache_lock),
+            ("dns", self.dns),
+            ("connect", self.connect),
+            ("tls_handshake", self.tls_handshake),
+            ("upstream_response", self.upstream_response),
+        ];
+        let mut first = true;
+        for (name, duration) in phases {
+            let Some(duration) = duration else {
+                continue;
+            };
+            if !first {
+                write!(f, " ")?;
+            }
+            write!(f, "{name}={:.1}ms", duration.as_secs_f64() * 1000.0)?;
+            first = false;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use pingora::protocols::TimingDigest;
+
+    #[test]
+    fn connected() {
+        let start = SystemTime::now();
+        let mut timings = RequestTimings {
+            connect_start: Some(start),
+            ..Default::default()
+        };
+        let digest = Digest {
+            ssl_digest: None,
+            timing_digest: vec![
+                Some(TimingDigest {
+                    established_ts: start + Duration::from_millis(10),
+                }),
+                Some(TimingDigest {
+                    established_ts: start + Duration::from_millis(25),
+                }),
+            ],
+            proxy_digest: None,
+            socket_digest: None,
+        };
+
+        timings.connected(false, Some(&digest));
+        assert_eq!(timings.connect, Some(Duration::from_millis(10)));
+        assert_eq!(timings.tls_handshake, Some(Duration::from_millis(15)));
+        assert_eq!(timings.to_string(), "connect=10.0ms tls_handshake=15.0ms");
+    }
+}
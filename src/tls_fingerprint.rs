@@ -0,0 +1,274 @@
+//! TLS client fingerprints ([JA3](https://github.com/salesforce/ja3)): a hash of the version,
+//! cipher suites, extensions, elliptic curves, and point formats a client offers in its
+//! ClientHello.  Clients built on the same TLS stack share a fingerprint regardless of the
+//! `User-Agent` they claim, which makes it a common signal against abusive clients.
+//!
+//! The fingerprint is computed in a ClientHello callback and kept with the connection.  Pingora
+//! doesn't expose the TLS connection to the request handling, but an HTTP/1.1 connection's requests
+//! are handled in the task that did its handshake, so the fingerprint is handed over by task (as
+//! identified by its waker).  HTTP/2 requests are handled in tasks of their own, so the proxy
+//! doesn't look their fingerprint up: they carry none.  With a deny list, HTTPS listeners only
+//! offer HTTP/1.1, so it can't be bypassed by negotiating HTTP/2.
+
+use http::header::HeaderName;
+use log::debug;
+use once_cell::sync::Lazy;
+use openssl::ex_data::Index;
+use pingora::tls::hash::{hash, MessageDigest};
+use pingora::tls::ssl::{ClientHelloResponse, Ssl, SslAlert, SslRef};
+use pingora::tls::{error::ErrorStack, ssl_sys};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::future::poll_fn;
+use std::sync::{Arc, Mutex, Weak};
+use std::task::Poll;
+
+/// The extension listing the elliptic curves (supported groups) the client offers.
+const SUPPORTED_GROUPS: u32 = 10;
+
+/// The extension listing the elliptic curve point formats the client offers.
+const EC_POINT_FORMATS: u32 = 11;
+
+/// When the number of tracked connections exceeds this, closed ones are pruned.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+/// Where each connection's fingerprint is kept (it lives as long as the connection).
+static EX_INDEX: Lazy<Index<Ssl, Arc<Fingerprint>>> =
+    Lazy::new(|| Ssl::new_ex_index().expect("Unable to allocate a TLS ex data index"));
+
+/// The fingerprints of open connections by the task handling them.  (A task's ID may be reused
+/// once it's done, but by then its connection, and so the fingerprint, is gone.)
+static BY_TASK: Lazy<Mutex<HashMap<usize, Weak<Fingerprint>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// TLS fingerprinting settings.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+#[serde(default)]
+pub struct TlsFingerprintConfig {
+    /// Whether to fingerprint clients connecting over HTTPS.
+    pub enabled: bool,
+
+    /// A request header the fingerprint is sent to the origins in (e.g., `x-ja3`).
+    pub upstream_header: Option<String>,
+
+    /// The fingerprints (JA3 hashes) of clients to reject with a 403.
+    pub deny: Vec<String>,
+}
+
+impl TlsFingerprintConfig {
+    /// Whether HTTPS listeners must only offer HTTP/1.1, so the deny list applies to all requests
+    /// (HTTP/2 requests carry no fingerprint).
+    pub fn forces_http1(&self) -> bool {
+        self.enabled && !self.deny.is_empty()
+    }
+}
+
+/// A client's JA3 fingerprint.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Fingerprint {
+    /// The fields the hash is computed from.
+    pub ja3: String,
+
+    /// The MD5 hash of the fields (in hex).
+    pub hash: String,
+}
+
+impl Fingerprint {
+    /// Compute the fingerprint of a ClientHello from its version, its cipher suites (as sent), the
+    /// types of its extensions (in order), and the contents of its supported groups and point
+    /// formats extensions (if present).  GREASE values are left out.
+    pub fn new(
+        version: u16,
+        ciphers: &[u8],
+        extensions: &[u16],
+        groups: Option<&[u8]>,
+        point_formats: Option<&[u8]>,
+    ) -> Self {
+        let join = |values: &mut dyn Iterator<Item = u16>| {
+            values
+                .filter(|&v| !is_grease(v))
+                .map(|v| v.to_string())
+                .collect::<Vec<_>>()
+                .join("-")
+        };
+        let pairs = |bytes: &[u8]| {
+            bytes
+                .chunks_exact(2)
+                .map(|c| u16::from_be_bytes([c[0], c[1]]))
+                .collect::<Vec<_>>()
+        };
+        // Both extensions start with the length of their list (2 and 1 bytes).
+        let groups = groups.map(|g| pairs(g.get(2..).unwrap_or_default()));
+        let point_formats = point_formats.and_then(|p| p.get(1..)).unwrap_or_default();
+
+        let ja3 = format!(
+            "{version},{},{},{},{}",
+            join(&mut pairs(ciphers).into_iter()),
+            join(&mut extensions.iter().copied()),
+            join(&mut groups.unwrap_or_default().into_iter()),
+            join(&mut point_formats.iter().map(|&p| p as u16)),
+        );
+        let hash = hash(MessageDigest::md5(), ja3.as_bytes())
+            .map(|digest| hex::encode(&*digest))
+            .unwrap_or_default();
+        Fingerprint { ja3, hash }
+    }
+}
+
+/// Whether a value is one of the reserved GREASE values clients add to keep servers tolerant of
+/// unknown ones (`0x0a0a`, `0x1a1a`, ..., `0xfafa`).
+fn is_grease(value: u16) -> bool {
+    value & 0x0f0f == 0x0a0a && value >> 8 == value & 0xff
+}
+
+/// An ID for the current task: the data of its waker, which tokio points at the task.
+pub(crate) async fn task_id() -> usize {
+    poll_fn(|cx| Poll::Ready(cx.waker().data() as usize)).await
+}
+
+/// The ClientHello callback: fingerprint the client and keep the fingerprint with the connection.
+pub fn client_hello_callback(
+    ssl: &mut SslRef,
+    _alert: &mut SslAlert,
+) -> Result<ClientHelloResponse, ErrorStack> {
+    let fingerprint = fingerprint_client_hello(ssl);
+    debug!("TLS client fingerprint {}", &fingerprint.hash);
+    ssl.set_ex_data(*EX_INDEX, Arc::new(fingerprint));
+    Ok(ClientHelloResponse::SUCCESS)
+}
+
+/// Hand the connection's fingerprint (if any) over to the requests handled by the current task.
+/// Called later in the handshake, in an async callback (the ClientHello callback isn't async).
+pub async fn register(ssl: &SslRef) {
+    let Some(fingerprint) = ssl.ex_data(*EX_INDEX) else {
+        return;
+    };
+    let id = task_id().await;
+    let mut by_task = BY_TASK.lock().unwrap();
+    if by_task.len() > PRUNE_THRESHOLD {
+        by_task.retain(|_, fingerprint| fingerprint.strong_count() > 0);
+    }
+    by_task.insert(id, Arc::downgrade(fingerprint));
+}
+
+/// The fingerprint of the connection handled by the current task (if it's an HTTP/1.1 connection
+/// over TLS and fingerprinting is enabled).
+pub async fn current() -> Option<Arc<Fingerprint>> {
+    let id = task_id().await;
+    BY_TASK.lock().unwrap().get(&id)?.upgrade()
+}
+
+fn fingerprint_client_hello(ssl: &mut SslRef) -> Fingerprint {
+    // `SslRef` is an opaque reference to the `SSL` structure.
+    let ptr = ssl as *mut SslRef as *mut ssl_sys::SSL;
+    let extension = |kind| {
+        let mut data = std::ptr::null();
+        let mut len = 0;
+        // SAFETY: the data belongs to the ClientHello being processed, which outlives this call.
+        unsafe {
+            (ssl_sys::SSL_client_hello_get0_ext(ptr, kind, &mut data, &mut len) == 1)
+                .then(|| std::slice::from_raw_parts(data, len).to_vec())
+        }
+    };
+    let groups = extension(SUPPORTED_GROUPS);
+    let point_formats = extension(EC_POINT_FORMATS);
+
+    let mut extensions = Vec::new();
+    // SAFETY: OpenSSL allocates the array of extension types (in the order they were received),
+    // which is copied and then freed.
+    unsafe {
+        let mut present = std::ptr::null_mut();
+        let mut len = 0;
+        if ssl_sys::SSL_client_hello_get1_extensions_present(ptr, &mut present, &mut len) == 1 {
+            extensions = std::slice::from_raw_parts(present, len)
+                .iter()
+                .map(|&kind| kind as u16)
+                .collect();
+            ssl_sys::OPENSSL_free(present as *mut _);
+        }
+    }
+    // SAFETY: reads a field of the ClientHello being processed.
+    let version = unsafe { ssl_sys::SSL_client_hello_get0_legacy_version(ptr) } as u16;
+
+    Fingerprint::new(
+        version,
+        ssl.client_hello_ciphers().unwrap_or_default(),
+        &extensions,
+        groups.as_deref(),
+        point_formats.as_deref(),
+    )
+}
+
+/// Applies the fingerprinting settings to requests.
+pub struct TlsFingerprints {
+    enabled: bool,
+    upstream_header: Option<HeaderName>,
+    deny: HashSet<String>,
+}
+
+impl TlsFingerprints {
+    /// The header name must have been validated (see `AppConfig`).
+    pub fn new(config: &TlsFingerprintConfig) -> Self {
+        TlsFingerprints {
+            enabled: config.enabled,
+            upstream_header: config
+                .upstream_header
+                .as_ref()
+                .and_then(|name| HeaderName::from_bytes(name.as_bytes()).ok()),
+            deny: config.deny.iter().map(|h| h.to_ascii_lowercase()).collect(),
+        }
+    }
+
+    /// The fingerprint of the current request's connection (if fingerprinting is enabled).
+    pub async fn current(&self) -> Option<Arc<Fingerprint>> {
+        if !self.enabled {
+            return None;
+        }
+        current().await
+    }
+
+    /// Whether clients with the fingerprint are rejected.
+    pub fn is_denied(&self, fingerprint: &Fingerprint) -> bool {
+        self.deny.contains(&fingerprint.hash)
+    }
+
+    /// The header the fingerprint is sent to the origins in.
+    pub fn upstream_header(&self) -> Option<&HeaderName> {
+        self.upstream_header.as_ref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ja3() {
+        let ciphers: Vec<u8> = [
+            0x0a0a, 47, 53, 5, 10, 49161, 49162, 49171, 49172, 50, 56, 19, 4,
+        ]
+        .iter()
+        .flat_map(|c: &u16| c.to_be_bytes())
+        .collect();
+        let fingerprint = Fingerprint::new(
+            769,
+            &ciphers,
+            &[0, 0x2a2a, 10, 11],
+            Some(&[0, 6, 0, 23, 0, 24, 0, 25]),
+            Some(&[1, 0]),
+        );
+        assert_eq!(
+            fingerprint.ja3,
+            "769,47-53-5-10-49161-49162-49171-49172-50-56-19-4,0-10-11,23-24-25,0"
+        );
+        assert_eq!(fingerprint.hash, "ada70206e40642a3e4461f35503241d5");
+        assert!(is_grease(0xfafa));
+        assert!(!is_grease(0x0a1a));
+
+        let fingerprints = TlsFingerprints::new(&TlsFingerprintConfig {
+            deny: vec!["ADA70206E40642A3E4461F35503241D5".to_string()],
+            ..Default::default()
+        });
+        assert!(fingerprints.is_denied(&fingerprint));
+    }
+}
@@ -0,0 +1,269 @@
+//! Usage accounting for billing.  The bytes each customer's requests move are counted on both sides
+//! of the proxy: received from and sent to clients, and sent to and received from origins.  Bytes
+//! sent to clients are also split by whether the response came from the cache, so cache hits can be
+//! billed differently.  Only body bytes are counted.
+//!
+//! The counters are cumulative.  They can be persisted to a file periodically (and at shutdown),
+//! and are loaded from it at startup, so they survive restarts.  Requests forwarded by a cluster
+//! peer only count their origin traffic (the peer counts the client traffic).
+
+use async_trait::async_trait;
+use log::{error, info, warn};
+use pingora::server::ShutdownWatch;
+use pingora::services::background::BackgroundService;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::sync::Mutex;
+use std::time::Duration;
+
+use crate::expiry;
+
+/// Usage accounting settings.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct UsageConfig {
+    /// An optional file the counters are persisted to and loaded from at startup.
+    pub persist_file: Option<String>,
+
+    /// How often (in seconds) the counters are persisted.
+    pub persist_interval: u64,
+}
+
+impl Default for UsageConfig {
+    /// By default, the counters aren't persisted (and would be every minute).
+    fn default() -> Self {
+        UsageConfig {
+            persist_file: None,
+            persist_interval: 60,
+        }
+    }
+}
+
+/// A customer's usage.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+#[serde(default)]
+pub struct CustomerUsage {
+    /// The requests received from clients.
+    pub requests: u64,
+
+    /// Request body bytes received from clients.
+    pub client_ingress_bytes: u64,
+
+    /// Response body bytes sent to clients.
+    pub client_egress_bytes: u64,
+
+    /// The part of `client_egress_bytes` served from the cache.
+    pub cache_hit_bytes: u64,
+
+    /// The part of `client_egress_bytes` that wasn't served from the cache.
+    pub cache_miss_bytes: u64,
+
+    /// Request body bytes sent to origins.
+    pub origin_egress_bytes: u64,
+
+    /// Response body bytes received from origins.
+    pub origin_ingress_bytes: u64,
+}
+
+/// The traffic of a finished request.
+#[derive(Debug, Default)]
+pub struct UsageRecord {
+    /// Whether the request was received from a client (rather than forwarded by a cluster peer).
+    pub from_client: bool,
+    pub client_ingress_bytes: u64,
+    pub client_egress_bytes: u64,
+    /// Whether the response was served from the cache.
+    pub cache_hit: bool,
+    pub origin_egress_bytes: u64,
+    pub origin_ingress_bytes: u64,
+}
+
+/// The usage of every customer since counting started.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+pub struct UsageReport {
+    /// When counting started (in seconds since the Unix epoch).
+    pub since: u64,
+    pub customers: BTreeMap<String, CustomerUsage>,
+}
+
+/// Counts the usage of each customer.
+pub struct UsageTracker {
+    config: UsageConfig,
+    report: Mutex<UsageReport>,
+}
+
+impl CustomerUsage {
+    fn add(&mut self, record: &UsageRecord) {
+        if record.from_client {
+            self.requests += 1;
+            self.client_ingress_bytes += record.client_ingress_bytes;
+            self.client_egress_bytes += record.client_egress_bytes;
+            if record.cache_hit {
+                self.cache_hit_bytes += record.client_egress_bytes;
+            } else {
+                self.cache_miss_bytes += record.client_egress_bytes;
+            }
+        }
+        self.origin_egress_bytes += record.origin_egress_bytes;
+        self.origin_ingress_bytes += record.origin_ingress_bytes;
+    }
+}
+
+impl UsageTracker {
+    pub fn new(config: &UsageConfig) -> Self {
+        let tracker = UsageTracker {
+            config: config.clone(),
+            report: Mutex::new(UsageReport {
+                since: expiry::unix_time(),
+                customers: BTreeMap::new(),
+            }),
+        };
+        tracker.load();
+        tracker
+    }
+
+    /// Add a finished request to its customer's usage.
+    pub fn record(&self, customer: &str, record: &UsageRecord) {
+        let mut report = self.report.lock().unwrap();
+        match report.customers.get_mut(customer) {
+            Some(usage) => usage.add(record),
+            None => {
+                let mut usage = CustomerUsage::default();
+                usage.add(record);
+                report.customers.insert(customer.to_string(), usage);
+            }
+        }
+    }
+
+    /// The usage of every customer (or only of `customer`).
+    pub fn report(&self, customer: Option<&str>) -> UsageReport {
+        let report = self.report.lock().unwrap();
+        match customer {
+            Some(customer) => UsageReport {
+                since: report.since,
+                customers: report
+                    .customers
+                    .get_key_value(customer)
+                    .map(|(name, usage)| (name.clone(), usage.clone()))
+                    .into_iter()
+                    .collect(),
+            },
+            None => report.clone(),
+        }
+    }
+
+    /// Load the persisted counters (if any).
+    fn load(&self) {
+        let Some(path) = self.config.persist_file.as_ref() else {
+            return;
+        };
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) => {
+                warn!("Unable to read usage file {path}: {e}");
+                return;
+            }
+        };
+        match serde_json::from_slice::<UsageReport>(&contents) {
+            Ok(report) => {
+                info!(
+                    "Loaded the usage of {} customers from {path}",
+                    report.customers.len()
+                );
+                *self.report.lock().unwrap() = report;
+            }
+            Err(e) => warn!("Unable to parse usage file {path}: {e}"),
+        }
+    }
+
+    /// Write the counters to the persistence file (if any).  The file is replaced atomically, so
+    /// it's never left partially written.
+    pub fn persist(&self) {
+        let Some(path) = self.config.persist_file.as_ref() else {
+            return;
+        };
+        let contents = match serde_json::to_vec(&self.report(None)) {
+            Ok(contents) => contents,
+            Err(e) => {
+                error!("Unable to serialize usage: {e}");
+                return;
+            }
+        };
+        let temp = format!("{path}.tmp");
+        if let Err(e) = fs::write(&temp, contents).and_then(|_| fs::rename(&temp, path)) {
+            error!("Unable to write usage file {path}: {e}");
+        }
+    }
+}
+
+#[async_trait]
+impl BackgroundService for UsageTracker {
+    /// Persist the counters every interval, and once more at shutdown.
+    async fn start(&self, mut shutdown: ShutdownWatch) {
+        let interval = Duration::from_secs(self.config.persist_interval.max(1));
+        loop {
+            tokio::select! {
+                _ = tokio::time::sleep(interval) => self.persist(),
+                _ = shutdown.changed() => break,
+            }
+        }
+        self.persist();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn record_and_persist() {
+        let path = std::env::temp_dir().join(format!("granite-usage-{}.json", std::process::id()));
+        let config = UsageConfig {
+            persist_file: Some(path.to_string_lossy().to_string()),
+            ..Default::default()
+        };
+        let tracker = UsageTracker::new(&config);
+        let miss = UsageRecord {
+            from_client: true,
+            client_ingress_bytes: 10,
+            client_egress_bytes: 100,
+            origin_egress_bytes: 10,
+            origin_ingress_bytes: 100,
+            ..Default::default()
+        };
+        let hit = UsageRecord {
+            from_client: true,
+            client_egress_bytes: 100,
+            cache_hit: true,
+            ..Default::default()
+        };
+        let from_peer = UsageRecord {
+            origin_ingress_bytes: 50,
+            ..Default::default()
+        };
+        tracker.record("c1", &miss);
+        tracker.record("c1", &hit);
+        tracker.record("c1", &from_peer);
+        tracker.record("c2", &hit);
+
+        let expected = CustomerUsage {
+            requests: 2,
+            client_ingress_bytes: 10,
+            client_egress_bytes: 200,
+            cache_hit_bytes: 100,
+            cache_miss_bytes: 100,
+            origin_egress_bytes: 10,
+            origin_ingress_bytes: 150,
+        };
+        let report = tracker.report(Some("c1"));
+        assert_eq!(report.customers.len(), 1);
+        assert_eq!(report.customers["c1"], expected);
+
+        // The counters survive a restart.
+        tracker.persist();
+        let restarted = UsageTracker::new(&config);
+        assert_eq!(restarted.report(None), tracker.report(None));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,8 +1,80 @@
+use regex::Regex;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
 /// Parse a list of socket addresses given as "ip:port" strings (e.g., "0.0.0.0:80") into a list of
 /// ports.
 pub fn collect_ports(addrs: &[String]) -> Vec<u16> {
     addrs
         .iter()
-        .map(|addr| addr.split(':').last().unwrap().parse().unwrap())
+        .map(|addr| addr.rsplit(':').next().unwrap().parse().unwrap())
         .collect()
 }
+
+/// Split a host header (e.g., "example.com:8443") into the host and the port, if it has one.
+pub fn split_host_port(host: &str) -> (&str, Option<u16>) {
+    match host.rsplit_once(':') {
+        Some((name, port)) if !name.is_empty() => match port.parse() {
+            Ok(port) => (name, Some(port)),
+            Err(_) => (host, None),
+        },
+        _ => (host, None),
+    }
+}
+
+/// Decode percent-encoded bytes.  Malformed escapes are kept as they are.
+pub fn percent_decode(s: &str) -> Vec<u8> {
+    let bytes = s.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            let hex_digits = (hex_value(bytes[i + 1]), hex_value(bytes[i + 2]));
+            if let (Some(high), Some(low)) = hex_digits {
+                decoded.push(high << 4 | low);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    decoded
+}
+
+fn hex_value(b: u8) -> Option<u8> {
+    (b as char).to_digit(16).map(|d| d as u8)
+}
+
+/// A regular expression that is (de)serialized as its source string, so that invalid patterns are
+/// rejected when a configuration is parsed rather than when it's used.
+#[derive(Debug, Clone)]
+pub struct Pattern(pub Regex);
+
+impl Pattern {
+    pub fn is_match(&self, haystack: &str) -> bool {
+        self.0.is_match(haystack)
+    }
+}
+
+impl PartialEq for Pattern {
+    fn eq(&self, other: &Self) -> bool {
+        self.0.as_str() == other.0.as_str()
+    }
+}
+
+impl Eq for Pattern {}
+
+impl Serialize for Pattern {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.0.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Pattern {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let s = String::deserialize(deserializer)?;
+        Regex::new(&s)
+            .map(Pattern)
+            .map_err(serde::de::Error::custom)
+    }
+}
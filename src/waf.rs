@@ -0,0 +1,189 @@
+//! Basic WAF-style request filtering.  A route can have a list of rules that match requests by
+//! method, path, headers, query string, and body size.  A matching rule either blocks the request
+//! (with a 403) or flags it (logs it and lets it through).
+//!
+//! All conditions of a rule must match for the rule to match.  Rules are evaluated in order and
+//! the first blocking rule wins.
+//!
+//! Rules are evaluated before the request body is read, so a body whose size isn't announced in a
+//! `Content-Length` (e.g., a chunked body) is taken to exceed any `max_body_size`.
+
+use log::warn;
+use pingora::http::RequestHeader;
+use serde::{Deserialize, Serialize};
+
+use crate::utils::Pattern;
+
+/// What to do with a request that matches a rule.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+pub enum WafAction {
+    /// Reject the request with a 403.
+    #[default]
+    Block,
+
+    /// Log the request and let it through.
+    Flag,
+}
+
+/// A condition on a request header.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct HeaderCondition {
+    /// The header name (case-insensitive).
+    pub name: String,
+
+    /// A regular expression the header value must match.  A missing header doesn't match.
+    pub pattern: Pattern,
+}
+
+/// A filtering rule.  Conditions that aren't given match every request.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct WafRule {
+    /// A name for the rule (used in logs).
+    pub name: String,
+
+    /// What to do with a matching request.
+    #[serde(default)]
+    pub action: WafAction,
+
+    /// The request methods the rule applies to (e.g., `TRACE`).
+    #[serde(default)]
+    pub methods: Vec<String>,
+
+    /// A regular expression the request path must match (e.g., `\.(php|asp)$`).
+    pub path: Option<Pattern>,
+
+    /// Conditions on request headers.
+    #[serde(default)]
+    pub headers: Vec<HeaderCondition>,
+
+    /// A regular expression the raw query string must match (e.g., `(?i)union.+select`).
+    pub query: Option<Pattern>,
+
+    /// The rule matches requests with a `Content-Length` larger than this, or a body without one.
+    pub max_body_size: Option<u64>,
+}
+
+/// A set of filtering rules for a route.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone, Default)]
+pub struct WafPolicy {
+    pub rules: Vec<WafRule>,
+}
+
+impl WafRule {
+    /// Whether the request (whose body is empty if `body_empty` is set) matches all of the rule's
+    /// conditions.
+    fn matches(&self, req: &RequestHeader, body_empty: bool) -> bool {
+        if !self.methods.is_empty()
+            && !self
+                .methods
+                .iter()
+                .any(|m| m.eq_ignore_ascii_case(req.method.as_str()))
+        {
+            return false;
+        }
+        if let Some(path) = &self.path {
+            if !path.is_match(req.uri.path()) {
+                return false;
+            }
+        }
+        if let Some(query) = &self.query {
+            if !query.is_match(req.uri.query().unwrap_or_default()) {
+                return false;
+            }
+        }
+        for condition in &self.headers {
+            let matched = req
+                .headers
+                .get_all(condition.name.as_str())
+                .iter()
+                .filter_map(|v| v.to_str().ok())
+                .any(|v| condition.pattern.is_match(v));
+            if !matched {
+                return false;
+            }
+        }
+        if let Some(max_body_size) = self.max_body_size {
+            let content_length = req
+                .headers
+                .get(http::header::CONTENT_LENGTH)
+                .and_then(|v| v.to_str().ok())
+                .and_then(|v| v.parse::<u64>().ok());
+            let too_large = match content_length {
+                Some(len) => len > max_body_size,
+                None => !body_empty,
+            };
+            if !too_large {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+impl WafPolicy {
+    /// Evaluate the rules against the request (whose body is empty if `body_empty` is set).
+    /// Flagged requests are logged.
+    /// Return the name of the rule that blocks the request (if any).
+    pub fn check(&self, req: &RequestHeader, body_empty: bool) -> Option<&str> {
+        for rule in self.rules.iter().filter(|r| r.matches(req, body_empty)) {
+            match rule.action {
+                WafAction::Block => return Some(&rule.name),
+                WafAction::Flag => warn!(
+                    "WAF rule '{}' flagged request {} {}",
+                    rule.name, req.method, req.uri
+                ),
+            }
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check() {
+        let policy: WafPolicy = serde_json::from_str(
+            r#"{
+                "rules": [
+                    {"name": "scanner", "action": "Flag",
+                     "headers": [{"name": "user-agent", "pattern": "(?i)sqlmap"}]},
+                    {"name": "php", "path": "\\.php$"},
+                    {"name": "sqli", "query": "(?i)union.+select"},
+                    {"name": "upload", "methods": ["POST"], "max_body_size": 1024}
+                ]
+            }"#,
+        )
+        .unwrap();
+
+        let req = RequestHeader::build("GET", b"/index.html", None).unwrap();
+        assert_eq!(policy.check(&req, true), None);
+
+        let req = RequestHeader::build("GET", b"/wp-login.php", None).unwrap();
+        assert_eq!(policy.check(&req, true), Some("php"));
+
+        let req = RequestHeader::build("GET", b"/?id=1%20UNION%20SELECT", None).unwrap();
+        assert_eq!(policy.check(&req, true), Some("sqli"));
+
+        let mut req = RequestHeader::build("POST", b"/upload", None).unwrap();
+        req.insert_header("content-length", "2048").unwrap();
+        assert_eq!(policy.check(&req, false), Some("upload"));
+
+        // A body without a Content-Length (e.g., chunked) may be of any size.
+        let req = RequestHeader::build("POST", b"/upload", None).unwrap();
+        assert_eq!(policy.check(&req, false), Some("upload"));
+        assert_eq!(policy.check(&req, true), None);
+
+        // Flagged requests are let through.
+        let mut req = RequestHeader::build("GET", b"/", None).unwrap();
+        req.insert_header("user-agent", "sqlmap/1.7").unwrap();
+        assert_eq!(policy.check(&req, true), None);
+    }
+
+    #[test]
+    fn invalid_pattern() {
+        let result = serde_json::from_str::<WafRule>(r#"{"name": "bad", "path": "("}"#);
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,192 @@
+//! Warm-up probes sent to a route's origins when the route is added (or its origin group changes),
+//! so unreachable origins are marked down right away instead of failing the first user requests
+//! sent to them.
+//!
+//! Each origin gets a number of probe requests, one after the other.  An origin that doesn't
+//! answer any of them (because its hostname doesn't resolve, it refuses the connection, or it
+//! times out) is marked down, just like after failed connection attempts by user requests.  Any
+//! response counts as an answer: the probes check reachability, not the origin's content.
+
+use log::{debug, info};
+use pingora::connectors::http::Connector;
+use pingora::http::RequestHeader;
+use pingora::upstreams::peer::HttpPeer;
+use pingora::Result;
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::net::lookup_host;
+
+use crate::proxy::Proxy;
+use crate::route_config::{self, IncomingScheme, Origin, OutgoingScheme, RouteConfig};
+use crate::route_store::Route;
+
+/// A route's warm-up probes.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+#[serde(default)]
+pub struct WarmUpPolicy {
+    /// The number of probe requests sent to each origin.
+    pub requests: u32,
+
+    /// The path the probe requests are sent to.
+    pub path: String,
+
+    /// How long (in milliseconds) to wait for each probe's response.
+    pub timeout: u64,
+}
+
+impl Default for WarmUpPolicy {
+    /// By default, a single `HEAD /` probe with a 2 second timeout.
+    fn default() -> Self {
+        WarmUpPolicy {
+            requests: 1,
+            path: "/".to_string(),
+            timeout: 2000,
+        }
+    }
+}
+
+/// Probe the route's origins in the background (if it has a warm-up policy and there's a runtime
+/// to run the probes in).
+pub fn start(route: Arc<Route>) {
+    if route.config.warm_up.is_none() {
+        return;
+    }
+    let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+        return;
+    };
+    runtime.spawn(warm_up(route));
+}
+
+/// Probe each of the route's origins, and mark the ones that don't answer down.
+async fn warm_up(route: Arc<Route>) {
+    let Some(policy) = route.config.warm_up.as_ref() else {
+        return;
+    };
+    let config = &route.config;
+    let use_tls = match config.outgoing_scheme {
+        OutgoingScheme::Http => false,
+        OutgoingScheme::Https => true,
+        OutgoingScheme::MatchIncoming => !config.incoming_schemes.contains(&IncomingScheme::Http),
+    };
+    let connector = Connector::new(None);
+    for (index, origin) in config.origin_group.origins.iter().enumerate() {
+        let port = if use_tls {
+            origin.https_port
+        } else {
+            origin.http_port
+        };
+        let host_header = probe_host_header(config, origin);
+        let mut dns_failed = false;
+        let mut answered = false;
+        for _ in 0..policy.requests {
+            let addr = match lookup_host((origin.host.as_str(), port)).await {
+                Ok(mut addrs) => addrs.next(),
+                Err(_) => None,
+            };
+            let Some(addr) = addr else {
+                debug!("Warm-up probe unable to resolve origin '{}'", origin.host);
+                dns_failed = true;
+                continue;
+            };
+            dns_failed = false;
+            let mut peer = HttpPeer::new(addr, use_tls, origin.sni(&host_header));
+            peer.options.alternative_cn = origin.verify_hostname.clone();
+            match probe(&connector, peer, &host_header, policy).await {
+                Ok(()) => {
+                    answered = true;
+                    break;
+                }
+                Err(e) => debug!("Warm-up probe to origin '{}' failed: {e}", origin.host),
+            }
+        }
+        if !answered && policy.requests > 0 {
+            info!(
+                "Origin '{}' of route '{}' didn't answer its warm-up probes",
+                origin.host, route.config.name
+            );
+            let _ = Proxy::mark_origin_down(&route, index, dns_failed);
+        }
+    }
+}
+
+/// Send a probe request to the origin and wait for the response header.
+async fn probe(
+    connector: &Connector,
+    mut peer: HttpPeer,
+    host_header: &str,
+    policy: &WarmUpPolicy,
+) -> Result<()> {
+    let timeout = Duration::from_millis(policy.timeout);
+    peer.options.connection_timeout = Some(timeout);
+    peer.options.read_timeout = Some(timeout);
+    peer.options.write_timeout = Some(timeout);
+
+    let mut req = RequestHeader::build("HEAD", policy.path.as_bytes(), None)?;
+    req.insert_header(http::header::HOST, host_header.to_string())?;
+    req.insert_header(http::header::USER_AGENT, "granite-warm-up")?;
+    let (mut session, _reused) = connector.get_http_session(&peer).await?;
+    session.write_request_header(Box::new(req)).await?;
+    session.finish_request_body().await?;
+    session.read_response_header().await?;
+    Ok(())
+}
+
+/// The host header sent with probes: the one user requests to the route's first host would get.
+fn probe_host_header(route: &RouteConfig, origin: &Origin) -> String {
+    let host = route
+        .hosts
+        .iter()
+        .find(|host| !host.starts_with("*."))
+        .unwrap_or(&origin.host);
+    match origin
+        .host_header_override
+        .as_ref()
+        .or(route.host_header_override.as_ref())
+    {
+        Some(template) => route_config::render_host_header(template, host, &origin.host),
+        None => host.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::route_store::RouteState;
+    use std::net::TcpListener;
+    use std::sync::RwLock;
+
+    #[tokio::test]
+    async fn mark_down() {
+        // A port nothing listens on.
+        let closed = TcpListener::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        let config: RouteConfig = serde_json::from_value(serde_json::json!({
+            "name": "r1",
+            "customer": "c1",
+            "hosts": ["example.com"],
+            "paths": ["/"],
+            "incoming_schemes": ["Http"],
+            "outgoing_scheme": "Http",
+            "origin_group": {"origins": [{"host": "127.0.0.1", "http_port": closed.port()}]},
+            "warm_up": {"requests": 2, "timeout": 500},
+        }))
+        .unwrap();
+        assert_eq!(
+            probe_host_header(&config, &config.origin_group.origins[0]),
+            "example.com"
+        );
+        let route = Arc::new(Route {
+            state: RwLock::new(RouteState::new(&config.origin_group.origins)),
+            config,
+            ..Default::default()
+        });
+
+        warm_up(route.clone()).await;
+        let state = route.state.read().unwrap();
+        assert!(state.down_endpoints.contains_key(&0));
+        assert_eq!(state.consecutive_failures[&0], 1);
+    }
+}
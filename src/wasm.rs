@@ -0,0 +1,1071 @@
+//! WebAssembly filters: sandboxed request and response filters that customers ship as WebAssembly
+//! modules, loaded through the Config API and enabled per route.
+//!
+//! Filters are written against the [proxy-wasm](https://github.com/proxy-wasm/spec) ABI (v0.2), so
+//! filters built with the proxy-wasm SDKs run as long as they stick to what granite supports:
+//! - the plugin configuration (the route's settings for the filter),
+//! - reading and changing the request and response headers (the pseudo-headers `:method`, `:path`,
+//!   `:authority`, `:scheme`, and `:status` are read-only),
+//! - sending a local response instead of forwarding the request (from the request headers),
+//! - the properties `request.path`, `request.url_path`, `request.query`, `request.method`,
+//!   `request.host`, `request.scheme`, `source.address`, and `route_name`,
+//! - logging and the current time.
+//!
+//! Other calls (e.g., HTTP calls, body access, shared data, timers) return `Unimplemented`, and a
+//! filter that pauses a request without responding fails it.
+//!
+//! Filters run in an interpreter, each request in its own instance of the filter (instances are
+//! pooled and reused), with limits on the instance's memory and on the fuel (i.e., the number of
+//! instructions) spent in each call.  A filter that fails or runs out of fuel fails the request.
+
+use base64::engine::general_purpose::STANDARD as BASE64;
+use base64::Engine as _;
+use http::header::{HeaderMap, HeaderName, HeaderValue};
+use log::{debug, info, log, Level};
+use once_cell::sync::Lazy;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
+use wasmi::core::ValType;
+use wasmi::{
+    AsContextMut, Caller, Config, Engine, Error, Extern, Instance, Linker, Memory, Module, Store,
+    StoreLimits, StoreLimitsBuilder, Val, WasmParams, WasmResults,
+};
+
+use crate::script::HeaderEdit;
+
+/// The engine running all the filters.
+static ENGINE: Lazy<Engine> = Lazy::new(|| {
+    let mut config = Config::default();
+    config.consume_fuel(true);
+    Engine::new(&config)
+});
+
+/// The most idle instances kept for each filter and configuration.
+const MAX_IDLE_INSTANCES: usize = 64;
+
+/// The ID of the root context of each instance (the contexts of requests follow).
+const ROOT_CONTEXT_ID: i32 = 1;
+
+// proxy-wasm status codes.
+const OK: i32 = 0;
+const NOT_FOUND: i32 = 1;
+const BAD_ARGUMENT: i32 = 2;
+const UNIMPLEMENTED: i32 = 12;
+
+// proxy-wasm header map types.
+const REQUEST_HEADERS: i32 = 0;
+const RESPONSE_HEADERS: i32 = 2;
+
+// proxy-wasm buffer types.
+const PLUGIN_CONFIGURATION: i32 = 7;
+
+// proxy-wasm filter actions.
+const ACTION_CONTINUE: i32 = 0;
+
+/// The WASI error returned by unsupported system calls.
+const WASI_ENOSYS: i32 = 52;
+
+/// An interface for adding and deleting filter modules.
+pub trait WasmHolder: Send + Sync {
+    fn add_module(&self, module: &WasmModule) -> Result<(), String>;
+    fn delete_module(&self, name: &str);
+}
+
+/// A filter module, as loaded through the Config API.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct WasmModule {
+    /// The name routes enable the filter by.
+    pub name: String,
+
+    /// The module's binary, base64-encoded.
+    pub module: String,
+
+    /// The fuel (roughly, the number of instructions) each call of the filter may spend.
+    #[serde(default = "default_fuel")]
+    pub fuel: u64,
+
+    /// The maximum memory (in bytes) of each instance of the filter.
+    #[serde(default = "default_max_memory")]
+    pub max_memory: usize,
+}
+
+pub(crate) fn default_fuel() -> u64 {
+    10_000_000
+}
+
+pub(crate) fn default_max_memory() -> usize {
+    16 * 1024 * 1024
+}
+
+/// A filter enabled on a route.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, Clone)]
+pub struct WasmFilterRef {
+    /// The name the filter module was loaded under.
+    pub name: String,
+
+    /// The route's settings for the filter (its plugin configuration: a string is passed as is,
+    /// and other values as JSON).
+    #[serde(default)]
+    pub config: serde_json::Value,
+}
+
+/// The loaded filters, by name.
+#[derive(Default)]
+pub struct WasmStore {
+    filters: RwLock<HashMap<String, Arc<WasmFilter>>>,
+}
+
+impl WasmStore {
+    pub fn new() -> Self {
+        WasmStore::default()
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<WasmFilter>> {
+        self.filters.read().unwrap().get(name).cloned()
+    }
+}
+
+impl WasmHolder for WasmStore {
+    /// Compile and add (or replace) a filter.  Requests in flight keep the instances they have.
+    fn add_module(&self, module: &WasmModule) -> Result<(), String> {
+        let filter = WasmFilter::new(module)?;
+        let mut filters = self.filters.write().unwrap();
+        filters.insert(module.name.clone(), Arc::new(filter));
+        Ok(())
+    }
+
+    fn delete_module(&self, name: &str) {
+        let mut filters = self.filters.write().unwrap();
+        filters.remove(name);
+    }
+}
+
+/// A compiled filter, with its idle instances.
+pub struct WasmFilter {
+    name: String,
+    module: Module,
+    linker: Linker<Host>,
+    fuel: u64,
+    max_memory: usize,
+    /// Idle instances, by plugin configuration.
+    idle: Mutex<HashMap<Vec<u8>, Vec<Vm>>>,
+}
+
+impl WasmFilter {
+    pub fn new(config: &WasmModule) -> Result<Self, String> {
+        let binary = BASE64
+            .decode(&config.module)
+            .map_err(|e| format!("Invalid base64 module: {e}"))?;
+        let module =
+            Module::new(&ENGINE, &binary[..]).map_err(|e| format!("Invalid module: {e}"))?;
+        let abi = ["proxy_abi_version_0_2_0", "proxy_abi_version_0_2_1"];
+        if !abi.iter().any(|name| module.get_export(name).is_some()) {
+            return Err("The module doesn't implement the proxy-wasm ABI v0.2".to_string());
+        }
+        let linker = new_linker(&module).map_err(|e| e.to_string())?;
+        info!(
+            "Compiled WASM filter '{}' ({} bytes)",
+            &config.name,
+            binary.len()
+        );
+        Ok(WasmFilter {
+            name: config.name.clone(),
+            module,
+            linker,
+            fuel: config.fuel,
+            max_memory: config.max_memory,
+            idle: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Start the filter's handling of a request with the route's settings for the filter.
+    pub fn start(self: &Arc<Self>, config: &serde_json::Value) -> Result<WasmContext, String> {
+        let config = match config {
+            serde_json::Value::Null => Vec::new(),
+            serde_json::Value::String(s) => s.as_bytes().to_vec(),
+            value => value.to_string().into_bytes(),
+        };
+        let idle = self
+            .idle
+            .lock()
+            .unwrap()
+            .get_mut(&config)
+            .and_then(Vec::pop);
+        let mut vm = match idle {
+            Some(vm) => vm,
+            None => self.new_vm(&config)?,
+        };
+
+        let id = vm.next_context_id;
+        vm.next_context_id = id.checked_add(1).unwrap_or(ROOT_CONTEXT_ID + 1);
+        vm.call::<(i32, i32), ()>(self.fuel, "proxy_on_context_create", (id, ROOT_CONTEXT_ID))?;
+        Ok(WasmContext {
+            filter: self.clone(),
+            config,
+            vm: Some(vm),
+            id,
+        })
+    }
+
+    /// Create and configure an instance of the filter.
+    fn new_vm(&self, config: &[u8]) -> Result<Vm, String> {
+        let host = Host {
+            filter: self.name.clone(),
+            limits: StoreLimitsBuilder::new()
+                .memory_size(self.max_memory)
+                .build(),
+            plugin_config: config.to_vec(),
+            ..Default::default()
+        };
+        let mut store = Store::new(&ENGINE, host);
+        store.limiter(|host| &mut host.limits);
+        store.set_fuel(self.fuel).map_err(|e| e.to_string())?;
+        let instance = self
+            .linker
+            .instantiate(&mut store, &self.module)
+            .and_then(|pre| pre.start(&mut store))
+            .map_err(|e| format!("Failed to instantiate: {e}"))?;
+        let mut vm = Vm {
+            store,
+            instance,
+            next_context_id: ROOT_CONTEXT_ID + 1,
+        };
+
+        // WASI reactors initialize themselves in `_initialize`, commands in `_start`.
+        if vm.call::<(), ()>(self.fuel, "_initialize", ())?.is_none() {
+            vm.call::<(), ()>(self.fuel, "_start", ())?;
+        }
+        let started =
+            vm.call::<(i32, i32), i32>(self.fuel, "proxy_on_vm_start", (ROOT_CONTEXT_ID, 0))?;
+        if started == Some(0) {
+            return Err("The filter failed to start".to_string());
+        }
+        vm.call::<(i32, i32), ()>(self.fuel, "proxy_on_context_create", (ROOT_CONTEXT_ID, 0))?;
+        let configured = vm.call::<(i32, i32), i32>(
+            self.fuel,
+            "proxy_on_configure",
+            (ROOT_CONTEXT_ID, config.len() as i32),
+        )?;
+        if configured == Some(0) {
+            return Err("The filter rejected its configuration".to_string());
+        }
+        Ok(vm)
+    }
+}
+
+/// An instance of a filter.
+struct Vm {
+    store: Store<Host>,
+    instance: Instance,
+    next_context_id: i32,
+}
+
+impl Vm {
+    /// Call an export of the filter (with a fresh allowance of fuel).  Return `None` if the filter
+    /// doesn't export the function.
+    fn call<Params: WasmParams, Results: WasmResults>(
+        &mut self,
+        fuel: u64,
+        name: &str,
+        params: Params,
+    ) -> Result<Option<Results>, String> {
+        let Some(func) = self.instance.get_func(&self.store, name) else {
+            return Ok(None);
+        };
+        let func = func
+            .typed::<Params, Results>(&self.store)
+            .map_err(|e| format!("{name}: {e}"))?;
+        self.store.set_fuel(fuel).map_err(|e| e.to_string())?;
+        func.call(&mut self.store, params)
+            .map(Some)
+            .map_err(|e| format!("{name}: {e}"))
+    }
+}
+
+/// A request as seen by a filter.
+pub struct WasmRequest<'a> {
+    pub route: &'a str,
+    pub method: &'a str,
+    pub path: &'a str,
+    pub query: &'a str,
+    pub authority: &'a str,
+    pub scheme: &'a str,
+    pub client_ip: String,
+    pub headers: &'a HeaderMap,
+    /// Whether the request has no body.
+    pub end_of_stream: bool,
+}
+
+/// A response sent by a filter instead of forwarding the request.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct LocalResponse {
+    pub status: u16,
+    pub headers: Vec<(HeaderName, HeaderValue)>,
+    pub body: Vec<u8>,
+}
+
+/// What a filter did with the request headers.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct RequestOutcome {
+    pub edits: Vec<HeaderEdit>,
+    pub response: Option<LocalResponse>,
+}
+
+/// A filter's handling of a request.  It holds an instance of the filter until it's dropped (when
+/// the request finishes).
+pub struct WasmContext {
+    filter: Arc<WasmFilter>,
+    config: Vec<u8>,
+    /// The instance (dropped if it failed, since its state can't be trusted).
+    vm: Option<Vm>,
+    id: i32,
+}
+
+impl WasmContext {
+    /// Run the filter on the request headers.
+    pub fn on_request_headers(&mut self, req: &WasmRequest) -> Result<RequestOutcome, String> {
+        let mut headers = vec![
+            (":method".to_string(), req.method.as_bytes().to_vec()),
+            (":path".to_string(), path_and_query(req).into_bytes()),
+            (":authority".to_string(), req.authority.as_bytes().to_vec()),
+            (":scheme".to_string(), req.scheme.as_bytes().to_vec()),
+        ];
+        headers.extend(header_pairs(req.headers));
+        let properties = HashMap::from([
+            ("request.path".to_string(), path_and_query(req).into_bytes()),
+            ("request.url_path".to_string(), req.path.as_bytes().to_vec()),
+            ("request.query".to_string(), req.query.as_bytes().to_vec()),
+            ("request.method".to_string(), req.method.as_bytes().to_vec()),
+            (
+                "request.host".to_string(),
+                req.authority.as_bytes().to_vec(),
+            ),
+            ("request.scheme".to_string(), req.scheme.as_bytes().to_vec()),
+            (
+                "source.address".to_string(),
+                req.client_ip.as_bytes().to_vec(),
+            ),
+            ("route_name".to_string(), req.route.as_bytes().to_vec()),
+        ]);
+
+        let id = self.id;
+        let end_of_stream = req.end_of_stream as i32;
+        self.run(|vm, fuel| {
+            let host = vm.store.data_mut();
+            host.view = Some(HeaderView::new(REQUEST_HEADERS, headers));
+            host.properties = properties;
+            let num_headers = host.view.as_ref().map_or(0, |view| view.headers.len()) as i32;
+            let action = vm.call::<(i32, i32, i32), i32>(
+                fuel,
+                "proxy_on_request_headers",
+                (id, num_headers, end_of_stream),
+            )?;
+
+            let host = vm.store.data_mut();
+            let edits = host.view.take().map(|view| view.edits).unwrap_or_default();
+            let response = host.local_response.take();
+            if action.is_some_and(|action| action != ACTION_CONTINUE) && response.is_none() {
+                return Err("The filter paused the request, which isn't supported".to_string());
+            }
+            Ok(RequestOutcome { edits, response })
+        })
+    }
+
+    /// Run the filter on the response headers.  Return the changes it made to them.
+    pub fn on_response_headers(
+        &mut self,
+        status: u16,
+        headers: &HeaderMap,
+    ) -> Result<Vec<HeaderEdit>, String> {
+        let mut pairs = vec![(":status".to_string(), status.to_string().into_bytes())];
+        pairs.extend(header_pairs(headers));
+
+        let id = self.id;
+        self.run(|vm, fuel| {
+            let num_headers = pairs.len() as i32;
+            vm.store.data_mut().view = Some(HeaderView::new(RESPONSE_HEADERS, pairs));
+            let action = vm.call::<(i32, i32, i32), i32>(
+                fuel,
+                "proxy_on_response_headers",
+                (id, num_headers, 0),
+            )?;
+            let edits = vm
+                .store
+                .data_mut()
+                .view
+                .take()
+                .map(|view| view.edits)
+                .unwrap_or_default();
+            if action.is_some_and(|action| action != ACTION_CONTINUE) {
+                return Err("The filter paused the response, which isn't supported".to_string());
+            }
+            Ok(edits)
+        })
+    }
+
+    /// Run a call on the instance, and drop the instance if it fails.
+    fn run<T>(
+        &mut self,
+        call: impl FnOnce(&mut Vm, u64) -> Result<T, String>,
+    ) -> Result<T, String> {
+        let vm = self
+            .vm
+            .as_mut()
+            .ok_or_else(|| "The filter failed earlier".to_string())?;
+        let result = call(vm, self.filter.fuel);
+        if result.is_err() {
+            self.vm = None;
+        }
+        result
+    }
+}
+
+impl Drop for WasmContext {
+    /// Let the filter finish with the request, and make the instance available to other requests.
+    fn drop(&mut self) {
+        let Some(mut vm) = self.vm.take() else {
+            return;
+        };
+        let fuel = self.filter.fuel;
+        let host = vm.store.data_mut();
+        host.view = None;
+        host.properties.clear();
+        let finished = vm
+            .call::<i32, i32>(fuel, "proxy_on_done", self.id)
+            .and_then(|_| vm.call::<i32, ()>(fuel, "proxy_on_log", self.id))
+            .and_then(|_| vm.call::<i32, ()>(fuel, "proxy_on_delete", self.id));
+        if let Err(e) = finished {
+            debug!("WASM filter '{}' failed to finish: {e}", &self.filter.name);
+            return;
+        }
+        let mut idle = self.filter.idle.lock().unwrap();
+        let instances = idle.entry(std::mem::take(&mut self.config)).or_default();
+        if instances.len() < MAX_IDLE_INSTANCES {
+            instances.push(vm);
+        }
+    }
+}
+
+impl fmt::Debug for WasmContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WasmContext")
+            .field("filter", &self.filter.name)
+            .field("id", &self.id)
+            .finish()
+    }
+}
+
+fn path_and_query(req: &WasmRequest) -> String {
+    match req.query {
+        "" => req.path.to_string(),
+        query => format!("{}?{query}", req.path),
+    }
+}
+
+fn header_pairs(headers: &HeaderMap) -> impl Iterator<Item = (String, Vec<u8>)> + '_ {
+    headers
+        .iter()
+        .map(|(name, value)| (name.as_str().to_string(), value.as_bytes().to_vec()))
+}
+
+/// The state of an instance that the host functions work with.
+#[derive(Default)]
+struct Host {
+    filter: String,
+    limits: StoreLimits,
+    plugin_config: Vec<u8>,
+    /// The header map of the phase being run (if any).
+    view: Option<HeaderView>,
+    properties: HashMap<String, Vec<u8>>,
+    local_response: Option<LocalResponse>,
+}
+
+/// A header map as seen by a filter, and the changes it made to it.
+struct HeaderView {
+    map_type: i32,
+    headers: Vec<(String, Vec<u8>)>,
+    edits: Vec<HeaderEdit>,
+}
+
+impl HeaderView {
+    fn new(map_type: i32, headers: Vec<(String, Vec<u8>)>) -> Self {
+        HeaderView {
+            map_type,
+            headers,
+            edits: Vec::new(),
+        }
+    }
+
+    fn get(&self, name: &str) -> Option<&[u8]> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_slice())
+    }
+
+    fn append(&mut self, name: HeaderName, value: HeaderValue) {
+        self.headers
+            .push((name.as_str().to_string(), value.as_bytes().to_vec()));
+        self.edits.push(HeaderEdit::Append(name, value));
+    }
+
+    fn replace(&mut self, name: HeaderName, value: HeaderValue) {
+        self.headers.retain(|(n, _)| n != name.as_str());
+        self.headers
+            .push((name.as_str().to_string(), value.as_bytes().to_vec()));
+        self.edits.push(HeaderEdit::Set(name, value));
+    }
+
+    fn remove(&mut self, name: HeaderName) {
+        self.headers.retain(|(n, _)| n != name.as_str());
+        self.edits.push(HeaderEdit::Remove(name));
+    }
+}
+
+impl Host {
+    fn view(&mut self, map_type: i32) -> Option<&mut HeaderView> {
+        self.view.as_mut().filter(|view| view.map_type == map_type)
+    }
+}
+
+/// Encode header pairs the way proxy-wasm passes them: the number of pairs, the sizes of each name
+/// and value, and then the null-terminated names and values.
+fn serialize_pairs(pairs: &[(String, Vec<u8>)]) -> Vec<u8> {
+    let mut bytes = Vec::new();
+    bytes.extend((pairs.len() as u32).to_le_bytes());
+    for (name, value) in pairs {
+        bytes.extend((name.len() as u32).to_le_bytes());
+        bytes.extend((value.len() as u32).to_le_bytes());
+    }
+    for (name, value) in pairs {
+        bytes.extend(name.as_bytes());
+        bytes.push(0);
+        bytes.extend(value);
+        bytes.push(0);
+    }
+    bytes
+}
+
+fn deserialize_pairs(bytes: &[u8]) -> Option<Vec<(Vec<u8>, Vec<u8>)>> {
+    let read_u32 = |at: usize| -> Option<usize> {
+        let word = bytes.get(at..at + 4)?;
+        Some(u32::from_le_bytes(word.try_into().ok()?) as usize)
+    };
+    if bytes.is_empty() {
+        return Some(Vec::new());
+    }
+    let count = read_u32(0)?;
+    let mut sizes = Vec::new();
+    for i in 0..count {
+        sizes.push((read_u32(4 + i * 8)?, read_u32(8 + i * 8)?));
+    }
+    let mut at = 4 + count * 8;
+    let mut pairs = Vec::new();
+    for (name_len, value_len) in sizes {
+        let name = bytes.get(at..at + name_len)?.to_vec();
+        at += name_len + 1;
+        let value = bytes.get(at..at + value_len)?.to_vec();
+        at += value_len + 1;
+        pairs.push((name, value));
+    }
+    Some(pairs)
+}
+
+fn parse_header(name: &[u8], value: &[u8]) -> Option<(HeaderName, HeaderValue)> {
+    Some((
+        HeaderName::from_bytes(name).ok()?,
+        HeaderValue::from_bytes(value).ok()?,
+    ))
+}
+
+fn memory(caller: &Caller<'_, Host>) -> Result<Memory, Error> {
+    caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| Error::new("The module doesn't export its memory"))
+}
+
+fn read_bytes(caller: &Caller<'_, Host>, ptr: i32, len: i32) -> Result<Vec<u8>, Error> {
+    let memory = memory(caller)?;
+    let len = len as u32 as usize;
+    if len > memory.data(caller).len() {
+        return Err(Error::new("Out of bounds memory access"));
+    }
+    let mut bytes = vec![0; len];
+    memory.read(caller, ptr as u32 as usize, &mut bytes)?;
+    Ok(bytes)
+}
+
+fn write_bytes(caller: &mut Caller<'_, Host>, ptr: i32, bytes: &[u8]) -> Result<(), Error> {
+    let memory = memory(caller)?;
+    memory.write(caller.as_context_mut(), ptr as u32 as usize, bytes)?;
+    Ok(())
+}
+
+/// Copy data into memory allocated by the filter, and return its address and size at
+/// `return_ptr` and `return_size`.
+fn copy_out(
+    caller: &mut Caller<'_, Host>,
+    data: &[u8],
+    return_ptr: i32,
+    return_size: i32,
+) -> Result<(), Error> {
+    let allocate = caller
+        .get_export("proxy_on_memory_allocate")
+        .or_else(|| caller.get_export("malloc"))
+        .and_then(Extern::into_func)
+        .ok_or_else(|| Error::new("The module exports no memory allocator"))?
+        .typed::<i32, i32>(&*caller)?;
+    let ptr = allocate.call(caller.as_context_mut(), data.len() as i32)?;
+    write_bytes(caller, ptr, data)?;
+    write_bytes(caller, return_ptr, &ptr.to_le_bytes())?;
+    write_bytes(caller, return_size, &(data.len() as u32).to_le_bytes())
+}
+
+/// Define the host functions the filter imports: the supported proxy-wasm calls, enough of WASI
+/// for modules built for it, and stubs reporting the rest as unsupported.
+fn new_linker(module: &Module) -> Result<Linker<Host>, Error> {
+    let mut linker = Linker::new(&ENGINE);
+
+    linker.func_wrap(
+        "env",
+        "proxy_log",
+        |caller: Caller<'_, Host>, level: i32, ptr: i32, len: i32| -> Result<i32, Error> {
+            let message = read_bytes(&caller, ptr, len)?;
+            let level = match level {
+                0 => Level::Trace,
+                1 => Level::Debug,
+                2 => Level::Info,
+                3 => Level::Warn,
+                _ => Level::Error,
+            };
+            log!(
+                level,
+                "WASM filter '{}': {}",
+                &caller.data().filter,
+                String::from_utf8_lossy(&message)
+            );
+            Ok(OK)
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "proxy_get_header_map_value",
+        |mut caller: Caller<'_, Host>,
+         map_type: i32,
+         name_ptr: i32,
+         name_len: i32,
+         return_ptr: i32,
+         return_size: i32|
+         -> Result<i32, Error> {
+            let name = read_bytes(&caller, name_ptr, name_len)?;
+            let name = String::from_utf8_lossy(&name);
+            let value = caller
+                .data_mut()
+                .view(map_type)
+                .and_then(|view| view.get(&name))
+                .map(<[u8]>::to_vec);
+            let Some(value) = value else {
+                return Ok(NOT_FOUND);
+            };
+            copy_out(&mut caller, &value, return_ptr, return_size)?;
+            Ok(OK)
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "proxy_get_header_map_pairs",
+        |mut caller: Caller<'_, Host>,
+         map_type: i32,
+         return_ptr: i32,
+         return_size: i32|
+         -> Result<i32, Error> {
+            let Some(view) = caller.data_mut().view(map_type) else {
+                return Ok(NOT_FOUND);
+            };
+            let pairs = serialize_pairs(&view.headers);
+            copy_out(&mut caller, &pairs, return_ptr, return_size)?;
+            Ok(OK)
+        },
+    )?;
+    for (name, replace) in [
+        ("proxy_add_header_map_value", false),
+        ("proxy_replace_header_map_value", true),
+    ] {
+        linker.func_wrap(
+            "env",
+            name,
+            move |mut caller: Caller<'_, Host>,
+                  map_type: i32,
+                  name_ptr: i32,
+                  name_len: i32,
+                  value_ptr: i32,
+                  value_len: i32|
+                  -> Result<i32, Error> {
+                let name = read_bytes(&caller, name_ptr, name_len)?;
+                let value = read_bytes(&caller, value_ptr, value_len)?;
+                // Pseudo-headers aren't valid header names, so they can't be changed.
+                let Some((name, value)) = parse_header(&name, &value) else {
+                    return Ok(BAD_ARGUMENT);
+                };
+                let Some(view) = caller.data_mut().view(map_type) else {
+                    return Ok(NOT_FOUND);
+                };
+                if replace {
+                    view.replace(name, value);
+                } else {
+                    view.append(name, value);
+                }
+                Ok(OK)
+            },
+        )?;
+    }
+    linker.func_wrap(
+        "env",
+        "proxy_remove_header_map_value",
+        |mut caller: Caller<'_, Host>,
+         map_type: i32,
+         name_ptr: i32,
+         name_len: i32|
+         -> Result<i32, Error> {
+            let name = read_bytes(&caller, name_ptr, name_len)?;
+            let Ok(name) = HeaderName::from_bytes(&name) else {
+                return Ok(BAD_ARGUMENT);
+            };
+            let Some(view) = caller.data_mut().view(map_type) else {
+                return Ok(NOT_FOUND);
+            };
+            view.remove(name);
+            Ok(OK)
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "proxy_send_local_response",
+        |mut caller: Caller<'_, Host>,
+         status: i32,
+         _details_ptr: i32,
+         _details_len: i32,
+         body_ptr: i32,
+         body_len: i32,
+         headers_ptr: i32,
+         headers_len: i32,
+         _grpc_status: i32|
+         -> Result<i32, Error> {
+            if caller.data_mut().view(REQUEST_HEADERS).is_none() {
+                return Ok(UNIMPLEMENTED);
+            }
+            let Ok(status @ 100..=599) = u16::try_from(status) else {
+                return Ok(BAD_ARGUMENT);
+            };
+            let body = read_bytes(&caller, body_ptr, body_len)?;
+            let headers = read_bytes(&caller, headers_ptr, headers_len)?;
+            let headers = deserialize_pairs(&headers).and_then(|pairs| {
+                pairs
+                    .iter()
+                    .map(|(name, value)| parse_header(name, value))
+                    .collect::<Option<Vec<_>>>()
+            });
+            let Some(headers) = headers else {
+                return Ok(BAD_ARGUMENT);
+            };
+            caller.data_mut().local_response = Some(LocalResponse {
+                status,
+                headers,
+                body,
+            });
+            Ok(OK)
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "proxy_get_buffer_bytes",
+        |mut caller: Caller<'_, Host>,
+         buffer_type: i32,
+         start: i32,
+         max_size: i32,
+         return_ptr: i32,
+         return_size: i32|
+         -> Result<i32, Error> {
+            if buffer_type != PLUGIN_CONFIGURATION {
+                return Ok(NOT_FOUND);
+            }
+            let config = &caller.data().plugin_config;
+            let start = start as u32 as usize;
+            if start >= config.len() {
+                return Ok(NOT_FOUND);
+            }
+            let end = config
+                .len()
+                .min(start.saturating_add(max_size as u32 as usize));
+            let data = config[start..end].to_vec();
+            copy_out(&mut caller, &data, return_ptr, return_size)?;
+            Ok(OK)
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "proxy_get_property",
+        |mut caller: Caller<'_, Host>,
+         path_ptr: i32,
+         path_len: i32,
+         return_ptr: i32,
+         return_size: i32|
+         -> Result<i32, Error> {
+            let path = read_bytes(&caller, path_ptr, path_len)?;
+            let path: Vec<_> = path
+                .split(|&b| b == 0)
+                .filter(|segment| !segment.is_empty())
+                .map(String::from_utf8_lossy)
+                .collect();
+            let Some(value) = caller.data().properties.get(&path.join(".")).cloned() else {
+                return Ok(NOT_FOUND);
+            };
+            copy_out(&mut caller, &value, return_ptr, return_size)?;
+            Ok(OK)
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "proxy_get_current_time_nanoseconds",
+        |mut caller: Caller<'_, Host>, return_time: i32| -> Result<i32, Error> {
+            write_bytes(&mut caller, return_time, &now_nanos().to_le_bytes())?;
+            Ok(OK)
+        },
+    )?;
+    linker.func_wrap(
+        "env",
+        "proxy_set_effective_context",
+        |_: Caller<'_, Host>, _context_id: i32| -> i32 { OK },
+    )?;
+
+    let wasi = "wasi_snapshot_preview1";
+    linker.func_wrap(
+        wasi,
+        "fd_write",
+        |mut caller: Caller<'_, Host>,
+         _fd: i32,
+         iovs: i32,
+         iovs_len: i32,
+         written_ptr: i32|
+         -> Result<i32, Error> {
+            let iovs = read_bytes(&caller, iovs, iovs_len.saturating_mul(8))?;
+            let mut output = Vec::new();
+            for iov in iovs.chunks_exact(8) {
+                let ptr = i32::from_le_bytes(iov[..4].try_into().unwrap());
+                let len = i32::from_le_bytes(iov[4..].try_into().unwrap());
+                output.extend(read_bytes(&caller, ptr, len)?);
+            }
+            debug!(
+                "WASM filter '{}': {}",
+                &caller.data().filter,
+                String::from_utf8_lossy(&output).trim_end()
+            );
+            write_bytes(
+                &mut caller,
+                written_ptr,
+                &(output.len() as u32).to_le_bytes(),
+            )?;
+            Ok(0)
+        },
+    )?;
+    linker.func_wrap(
+        wasi,
+        "random_get",
+        |mut caller: Caller<'_, Host>, ptr: i32, len: i32| -> Result<i32, Error> {
+            let mut bytes = read_bytes(&caller, ptr, len)?;
+            rand::Rng::fill(&mut rand::thread_rng(), &mut bytes[..]);
+            write_bytes(&mut caller, ptr, &bytes)?;
+            Ok(0)
+        },
+    )?;
+    linker.func_wrap(
+        wasi,
+        "clock_time_get",
+        |mut caller: Caller<'_, Host>,
+         _clock_id: i32,
+         _precision: i64,
+         time_ptr: i32|
+         -> Result<i32, Error> {
+            write_bytes(&mut caller, time_ptr, &now_nanos().to_le_bytes())?;
+            Ok(0)
+        },
+    )?;
+    for name in ["environ_sizes_get", "args_sizes_get"] {
+        linker.func_wrap(
+            wasi,
+            name,
+            |mut caller: Caller<'_, Host>, count_ptr: i32, size_ptr: i32| -> Result<i32, Error> {
+                write_bytes(&mut caller, count_ptr, &0u32.to_le_bytes())?;
+                write_bytes(&mut caller, size_ptr, &0u32.to_le_bytes())?;
+                Ok(0)
+            },
+        )?;
+    }
+    for name in ["environ_get", "args_get"] {
+        linker.func_wrap(wasi, name, |_: Caller<'_, Host>, _: i32, _: i32| -> i32 {
+            0
+        })?;
+    }
+    linker.func_wrap(
+        wasi,
+        "proc_exit",
+        |_: Caller<'_, Host>, code: i32| -> Result<(), Error> { Err(Error::i32_exit(code)) },
+    )?;
+
+    // Whatever else the module imports is unsupported.  (Defining a function again fails, which
+    // leaves the definitions above in place.)
+    for import in module.imports() {
+        let Some(ty) = import.ty().func() else {
+            continue;
+        };
+        let status = if import.module() == wasi {
+            WASI_ENOSYS
+        } else {
+            UNIMPLEMENTED
+        };
+        let results: Vec<ValType> = ty.results().to_vec();
+        let _ = linker.func_new(
+            import.module(),
+            import.name(),
+            ty.clone(),
+            move |_, _, outputs| {
+                for (output, ty) in outputs.iter_mut().zip(&results) {
+                    *output = Val::default(*ty);
+                }
+                if let Some(Val::I32(code)) = outputs.first_mut() {
+                    *code = status;
+                }
+                Ok(())
+            },
+        );
+    }
+    Ok(linker)
+}
+
+fn now_nanos() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos() as u64
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A filter that rejects requests with an `x-deny` header, adds its configuration to the others
+    /// in an `x-wasm` header, and loops forever on the response.
+    const FILTER: &str = r#"
+        (module
+          (import "env" "proxy_get_header_map_value"
+            (func $get (param i32 i32 i32 i32 i32) (result i32)))
+          (import "env" "proxy_add_header_map_value"
+            (func $add (param i32 i32 i32 i32 i32) (result i32)))
+          (import "env" "proxy_send_local_response"
+            (func $respond (param i32 i32 i32 i32 i32 i32 i32 i32) (result i32)))
+          (import "env" "proxy_get_buffer_bytes"
+            (func $buffer (param i32 i32 i32 i32 i32) (result i32)))
+          (import "env" "proxy_http_call"
+            (func $http_call (param i32 i32 i32 i32 i32 i32 i32 i32 i32 i32) (result i32)))
+          (memory (export "memory") 1)
+          (global $heap (mut i32) (i32.const 1024))
+          (data (i32.const 0) "x-deny")
+          (data (i32.const 16) "x-wasm")
+          (data (i32.const 32) "denied")
+          (func (export "proxy_abi_version_0_2_1"))
+          (func (export "proxy_on_memory_allocate") (param $size i32) (result i32)
+            (global.get $heap)
+            (global.set $heap (i32.add (global.get $heap) (local.get $size))))
+          (func (export "proxy_on_context_create") (param i32 i32))
+          (func (export "proxy_on_request_headers") (param i32 i32 i32) (result i32)
+            (if (i32.eqz (call $get (i32.const 0) (i32.const 0) (i32.const 6)
+                                    (i32.const 64) (i32.const 68)))
+              (then
+                (drop (call $respond (i32.const 403) (i32.const 0) (i32.const 0)
+                                     (i32.const 32) (i32.const 6) (i32.const 0) (i32.const 0)
+                                     (i32.const -1)))
+                (return (i32.const 1))))
+            (drop (call $buffer (i32.const 7) (i32.const 0) (i32.const 1024)
+                                (i32.const 72) (i32.const 76)))
+            (drop (call $add (i32.const 0) (i32.const 16) (i32.const 6)
+                             (i32.load (i32.const 72)) (i32.load (i32.const 76))))
+            (i32.const 0))
+          (func (export "proxy_on_response_headers") (param i32 i32 i32) (result i32)
+            (loop $forever (br $forever))
+            (i32.const 0)))
+    "#;
+
+    fn request(headers: &HeaderMap) -> WasmRequest<'_> {
+        WasmRequest {
+            route: "r",
+            method: "GET",
+            path: "/",
+            query: "",
+            authority: "example.com",
+            scheme: "http",
+            client_ip: "192.0.2.1".to_string(),
+            headers,
+            end_of_stream: true,
+        }
+    }
+
+    #[test]
+    fn filter_requests() {
+        let store = WasmStore::new();
+        let module = WasmModule {
+            name: "f".to_string(),
+            module: BASE64.encode(wat::parse_str(FILTER).unwrap()),
+            fuel: 100_000,
+            max_memory: default_max_memory(),
+        };
+        store.add_module(&module).unwrap();
+        let filter = store.get("f").unwrap();
+        let config = serde_json::json!("on");
+
+        let mut ctx = filter.start(&config).unwrap();
+        let outcome = ctx.on_request_headers(&request(&HeaderMap::new())).unwrap();
+        assert_eq!(
+            outcome,
+            RequestOutcome {
+                edits: vec![HeaderEdit::Append(
+                    HeaderName::from_static("x-wasm"),
+                    HeaderValue::from_static("on")
+                )],
+                response: None,
+            }
+        );
+        // The filter runs out of fuel.
+        assert!(ctx.on_response_headers(200, &HeaderMap::new()).is_err());
+        drop(ctx);
+
+        let mut headers = HeaderMap::new();
+        headers.insert("x-deny", HeaderValue::from_static("1"));
+        let mut ctx = filter.start(&config).unwrap();
+        let outcome = ctx.on_request_headers(&request(&headers)).unwrap();
+        assert_eq!(
+            outcome.response,
+            Some(LocalResponse {
+                status: 403,
+                headers: Vec::new(),
+                body: b"denied".to_vec(),
+            })
+        );
+    }
+
+    #[test]
+    fn reject_invalid_modules() {
+        let module = |binary: &[u8]| WasmModule {
+            name: "f".to_string(),
+            module: BASE64.encode(binary),
+            fuel: default_fuel(),
+            max_memory: default_max_memory(),
+        };
+        assert!(WasmFilter::new(&module(b"not wasm")).is_err());
+        let not_proxy_wasm = wat::parse_str("(module)").unwrap();
+        assert!(WasmFilter::new(&module(&not_proxy_wasm)).is_err());
+    }
+}